@@ -2,9 +2,10 @@ use std::net::IpAddr;
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
+use telio::telio_model::{event::Event, mesh::PeerStats};
 use telio::telio_task::io::chan;
-use tokio::sync::oneshot;
-use tracing::{error, trace};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{debug, error, trace, warn};
 
 use crate::{
     comms::{DaemonConnection, DaemonSocket},
@@ -20,12 +21,32 @@ pub(crate) const TIMEOUT_SEC: u64 = 10;
 pub enum ClientCmd {
     #[clap(name = "status", about = "Retrieve the status report")]
     GetStatus,
+    #[clap(
+        name = "stats",
+        about = "Retrieve runtime statistics of the VPN connection"
+    )]
+    GetStats,
+    #[clap(name = "watch", about = "Print daemon events as they happen")]
+    Watch,
     #[clap(hide = true)]
     IsAlive,
     #[clap(name = "stop", about = "Stop daemon execution")]
     QuitDaemon,
 }
 
+impl ClientCmd {
+    /// Whether the command only inspects the daemon and is therefore allowed on the
+    /// observer socket
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            ClientCmd::GetStatus | ClientCmd::GetStats | ClientCmd::Watch | ClientCmd::IsAlive => {
+                true
+            }
+            ClientCmd::QuitDaemon => false,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ExitNodeConfig {
     pub endpoint: Endpoint,
@@ -36,6 +57,8 @@ pub struct ExitNodeConfig {
 pub enum TelioTaskCmd {
     // Get telio status
     GetStatus(oneshot::Sender<TelioStatusReport>),
+    // Get runtime statistics of the peers
+    GetStats(oneshot::Sender<Vec<PeerStats>>),
     // Connect to exit node with endpoint and optional hostname
     ConnectToExitNode(ExitNodeConfig),
     // Break the receive loop to quit the daemon and exit gracefully
@@ -89,6 +112,7 @@ pub enum Cmd {
 pub enum CommandResponse {
     Ok,
     StatusReport(TelioStatusReport),
+    Stats(Vec<PeerStats>),
     DaemonInitializing,
     Err(String),
 }
@@ -125,19 +149,41 @@ pub struct CommandListener {
     socket: DaemonSocket,
     /// Channel to send commands to telio task
     telio_task_tx: chan::Tx<TelioTaskCmd>,
+    /// Libtelio events, forwarded to clients running `watch`
+    events: broadcast::Sender<Box<Event>>,
+    /// Reject commands which would change the daemon state
+    read_only: bool,
 }
 
 impl CommandListener {
-    pub fn new(socket: DaemonSocket, telio_task_tx: chan::Tx<TelioTaskCmd>) -> CommandListener {
+    pub fn new(
+        socket: DaemonSocket,
+        telio_task_tx: chan::Tx<TelioTaskCmd>,
+        events: broadcast::Sender<Box<Event>>,
+    ) -> CommandListener {
         CommandListener {
             socket,
             telio_task_tx,
+            events,
+            read_only: false,
+        }
+    }
+
+    /// Listener for the observer socket, accepting only read-only commands
+    pub fn new_observer(
+        socket: DaemonSocket,
+        telio_task_tx: chan::Tx<TelioTaskCmd>,
+        events: broadcast::Sender<Box<Event>>,
+    ) -> CommandListener {
+        CommandListener {
+            read_only: true,
+            ..Self::new(socket, telio_task_tx, events)
         }
     }
 
     // Main command handling communicating with telio task
     async fn process_command(
-        &mut self,
+        &self,
         command: &ClientCmd,
     ) -> Result<CommandResponse, NordVpnLiteError> {
         match command {
@@ -158,6 +204,19 @@ impl CommandListener {
                 })
                 .await
             }
+            ClientCmd::GetStats => {
+                trace!("Reporting peer statistics");
+                let (response_tx, response_rx) = oneshot::channel();
+                #[allow(mpsc_blocking_send)]
+                self.telio_task_tx
+                    .send(TelioTaskCmd::GetStats(response_tx))
+                    .await
+                    .map_err(|e| {
+                        error!("Error sending command: {}", e);
+                        NordVpnLiteError::CommandFailed(ClientCmd::GetStats)
+                    })?;
+                handle_response(response_rx, |stats| Ok(CommandResponse::Stats(stats))).await
+            }
             ClientCmd::QuitDaemon => {
                 trace!("Quitting telio task");
                 let (response_tx, response_rx) = oneshot::channel();
@@ -174,17 +233,47 @@ impl CommandListener {
                 // cleanup
                 handle_response(response_rx, |_| Ok(CommandResponse::Ok)).await
             }
-            ClientCmd::IsAlive => Ok(CommandResponse::Ok),
+            // Watch is handled by `handle_client_command`, as it takes over the connection
+            ClientCmd::IsAlive | ClientCmd::Watch => Ok(CommandResponse::Ok),
         }
     }
 
+    /// Forward libtelio events to the client, one JSON object per line,
+    /// until the client disconnects.
+    fn watch_events(&self, mut connection: DaemonConnection) {
+        let mut events = self.events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Event watcher lagging behind, skipped {skipped} events");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let line = match event.to_json() {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("Failed to serialize event: {e}");
+                        continue;
+                    }
+                };
+                if connection.respond(format!("{line}\n")).await.is_err() {
+                    debug!("Event watcher disconnected");
+                    break;
+                }
+            }
+        });
+    }
+
     /// Accept a new incoming client connection.
     ///
     /// This wraps `UnixListener::accept()` and is cancel-safe.
     /// Important when used inside a `tokio::select!` loop,
     /// if any other branch completes first, this future may be cancelled.
     /// Once accepted, the connection must be handled by `handle_client_command()`.
-    pub async fn accept_client_connection(&mut self) -> Result<DaemonConnection, NordVpnLiteError> {
+    pub async fn accept_client_connection(&self) -> Result<DaemonConnection, NordVpnLiteError> {
         Ok(self.socket.accept().await?)
     }
 
@@ -195,13 +284,28 @@ impl CommandListener {
     /// `is_ready = false` - Ensures that early client commands during daemon startup are accepted.
     /// Note: Inside an already matched `select!` branch, `handle_client_command()` is safe from being cancelled.
     pub async fn handle_client_command(
-        &mut self,
+        &self,
         is_ready: bool,
         mut connection: DaemonConnection,
     ) -> Result<ClientCmd, NordVpnLiteError> {
         let command_str = connection.read_command().await?;
 
         match serde_json::from_str::<ClientCmd>(&command_str) {
+            Ok(command) if self.read_only && !command.is_read_only() => {
+                connection
+                    .respond(
+                        CommandResponse::Err(format!(
+                            "Command not permitted in read-only mode: {command_str}"
+                        ))
+                        .serialize(),
+                    )
+                    .await?;
+                Err(NordVpnLiteError::CommandNotPermitted(command))
+            }
+            Ok(ClientCmd::Watch) => {
+                self.watch_events(connection);
+                Ok(ClientCmd::Watch)
+            }
             Ok(command) => {
                 let response = if is_ready {
                     self.process_command(&command).await?
@@ -210,7 +314,10 @@ impl CommandListener {
                     match &command {
                         ClientCmd::QuitDaemon => CommandResponse::Ok,
                         ClientCmd::IsAlive => CommandResponse::Ok,
-                        ClientCmd::GetStatus => CommandResponse::DaemonInitializing,
+                        ClientCmd::GetStatus | ClientCmd::GetStats => {
+                            CommandResponse::DaemonInitializing
+                        }
+                        ClientCmd::Watch => CommandResponse::Ok,
                     }
                 };
                 connection.respond(response.serialize()).await?;
@@ -260,6 +367,9 @@ mod tests {
                     let status_report = TelioStatusReport::default();
                     response_tx_channel.send(status_report).unwrap();
                 }
+                TelioTaskCmd::GetStats(response_tx_channel) => {
+                    response_tx_channel.send(Vec::new()).unwrap();
+                }
                 TelioTaskCmd::Quit(response_tx_channel) => {
                     response_tx_channel.send(()).unwrap();
                 }
@@ -268,8 +378,9 @@ mod tests {
         });
 
         let socket = DaemonSocket::new(Path::new(path)).unwrap();
+        let (events, _) = broadcast::channel(1);
 
-        CommandListener::new(socket, tx)
+        CommandListener::new(socket, tx, events)
     }
 
     // Simulate client sending command and waiting for response
@@ -304,8 +415,20 @@ mod tests {
         Result<ClientCmd, NordVpnLiteError>,
     ) {
         let path = make_socket_path();
-        let mut listener = make_command_listener(&path);
+        let listener = make_command_listener(&path);
+        test_listener_command_helper(listener, &path, command, is_ready, broken_client).await
+    }
 
+    async fn test_listener_command_helper(
+        listener: CommandListener,
+        path: &str,
+        command: ClientCmd,
+        is_ready: bool,
+        broken_client: bool,
+    ) -> (
+        Result<CommandResponse, std::io::Error>,
+        Result<ClientCmd, NordVpnLiteError>,
+    ) {
         let command = serde_json::to_string(&command).unwrap();
         let daemon = tokio::spawn(async move {
             let connection = listener.accept_client_connection().await.unwrap();
@@ -314,10 +437,10 @@ mod tests {
         });
 
         let daemon_response = if broken_client {
-            broken_client_send_command(&path, &command).await.unwrap();
+            broken_client_send_command(path, &command).await.unwrap();
             Err(std::io::Error::other("broken client, no response"))
         } else {
-            client_send_command(&path, &command).await
+            client_send_command(path, &command).await
         };
         let client_command = daemon.await.unwrap();
 
@@ -360,10 +483,60 @@ mod tests {
         assert_eq!(cmd.unwrap(), ClientCmd::GetStatus);
     }
 
+    #[tokio::test]
+    async fn test_command_stats() {
+        let (response, cmd) = test_command_helper(ClientCmd::GetStats, true, false).await;
+
+        assert_eq!(response.unwrap(), CommandResponse::Stats(Vec::new()));
+        assert_eq!(cmd.unwrap(), ClientCmd::GetStats);
+    }
+
+    #[tokio::test]
+    async fn test_observer_allows_status() {
+        let path = make_socket_path();
+        let CommandListener {
+            socket,
+            telio_task_tx,
+            events,
+            ..
+        } = make_command_listener(&path);
+        let listener = CommandListener::new_observer(socket, telio_task_tx, events);
+
+        let (response, cmd) =
+            test_listener_command_helper(listener, &path, ClientCmd::GetStatus, true, false).await;
+
+        assert_eq!(
+            response.unwrap(),
+            CommandResponse::StatusReport(TelioStatusReport::default())
+        );
+        assert_eq!(cmd.unwrap(), ClientCmd::GetStatus);
+    }
+
+    #[tokio::test]
+    async fn test_observer_rejects_quit() {
+        let path = make_socket_path();
+        let CommandListener {
+            socket,
+            telio_task_tx,
+            events,
+            ..
+        } = make_command_listener(&path);
+        let listener = CommandListener::new_observer(socket, telio_task_tx, events);
+
+        let (response, cmd) =
+            test_listener_command_helper(listener, &path, ClientCmd::QuitDaemon, true, false).await;
+
+        assert_matches!(response, Ok(CommandResponse::Err(_)));
+        assert_matches!(
+            cmd,
+            Err(NordVpnLiteError::CommandNotPermitted(ClientCmd::QuitDaemon))
+        );
+    }
+
     #[tokio::test]
     async fn test_command_invalid() {
         let path = make_socket_path();
-        let mut listener = make_command_listener(&path);
+        let listener = make_command_listener(&path);
 
         let command = "garbage";
         let daemon = tokio::spawn(async move {
@@ -380,7 +553,7 @@ mod tests {
     #[tokio::test]
     async fn test_command_invalid_broken() {
         let path = make_socket_path();
-        let mut listener = make_command_listener(&path);
+        let listener = make_command_listener(&path);
 
         let command = "garbage";
         let daemon = tokio::spawn(async move {
@@ -4,9 +4,13 @@ use std::{
     fs,
     io::{Error, ErrorKind, Result, Write},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    time::timeout,
+};
 
 #[cfg(not(test))]
 use interprocess::os::unix::local_socket::ListenerOptionsExt;
@@ -19,10 +23,21 @@ use interprocess::{
     os::unix::local_socket::FilesystemUdSocket,
 };
 
+/// File name of the read-only observer socket, placed next to the main IPC socket.
+const OBSERVER_SOCKET_NAME: &str = "nordvpnlited-observer.sock";
+
+/// Maximum length of a single command, including the trailing newline.
+const MAX_COMMAND_LENGTH: u64 = 4096;
+
+/// Time given to a client to send its command after connecting.
+const COMMAND_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Struct for handling connections of the daemon's side of the IPC communication with the API.
 pub struct DaemonSocket {
     /// The inner socket over which the actual communication is happening.
     socket: LocalSocketListener,
+    /// Path of the socket file, removed on drop.
+    path: PathBuf,
 }
 
 impl DaemonSocket {
@@ -45,6 +60,16 @@ impl DaemonSocket {
         }
     }
 
+    /// Returns the path to the read-only observer socket, which lives in the same directory
+    /// as the main IPC socket.
+    ///
+    /// # Returns
+    ///
+    /// A path to the observer socket wrapped inside result
+    pub fn get_observer_socket_path() -> Result<PathBuf> {
+        Self::get_ipc_socket_path().map(|path| path.with_file_name(OBSERVER_SOCKET_NAME))
+    }
+
     /// Binds the IPC socket to the specified address and returns a handle to the struct
     /// containing the IPC socket.
     ///
@@ -56,6 +81,27 @@ impl DaemonSocket {
     ///
     /// An instance of Self wrapped inside of a Result.
     pub fn new(ipc_socket_path: &Path) -> Result<Self> {
+        // Mode for unix socket rw-------
+        Self::bind(ipc_socket_path, 0o600)
+    }
+
+    /// Binds the read-only observer socket. Unlike the main IPC socket it is also accessible
+    /// by the owning group, so that monitoring agents do not need to run as root.
+    ///
+    /// # Arguments
+    ///
+    /// * `observer_socket_path` - Path of the observer socket.
+    ///
+    /// # Returns
+    ///
+    /// An instance of Self wrapped inside of a Result.
+    pub fn new_observer(observer_socket_path: &Path) -> Result<Self> {
+        // Mode for unix socket rw-rw----
+        Self::bind(observer_socket_path, 0o660)
+    }
+
+    #[cfg_attr(test, allow(unused_variables))]
+    fn bind(ipc_socket_path: &Path, mode: u32) -> Result<Self> {
         // Delete the socket file if it already exists
         let _ = fs::remove_file(ipc_socket_path);
 
@@ -67,12 +113,14 @@ impl DaemonSocket {
         // `umask()` C API call is operating on a process level, and therfore is not thread-safe.
         // As multiple cargo tests are executing in parallel this causes a panic.
         #[cfg(not(test))]
-        // Mode for unix socket rw-------
-        let options = options.mode(0o600);
+        let options = options.mode(mode);
 
         let socket = options.create_tokio()?;
 
-        Ok(Self { socket })
+        Ok(Self {
+            socket,
+            path: ipc_socket_path.to_owned(),
+        })
     }
 
     /// Opens a connection on the IPC socket for listening to commands (requests) from the API and
@@ -122,16 +170,43 @@ impl DaemonSocket {
 
         Ok(response_buffer)
     }
+
+    /// Function for subscribing to a stream of messages from the server.
+    /// Unlike `send_command` the response is not awaited as a whole, instead `on_line` is
+    /// called for every newline terminated message until the server closes the connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Address (path) of the IPC socket.
+    /// * `cmd` - Command to be sent to the daemon.
+    /// * `on_line` - Callback receiving each message.
+    pub async fn subscribe<F: FnMut(&str)>(addr: &Path, cmd: &str, mut on_line: F) -> Result<()> {
+        if cmd.contains('\n') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Command cannot contain newline characters",
+            ));
+        }
+
+        let mut stream =
+            LocalSocketStream::connect(addr.to_fs_name::<FilesystemUdSocket>()?).await?;
+        let mut buffer = Vec::<u8>::new();
+        writeln!(buffer, "{cmd}")?;
+        stream.write_all(&buffer).await?;
+
+        let mut lines = BufReader::new(stream).lines();
+        while let Some(line) = lines.next_line().await? {
+            on_line(&line);
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for DaemonSocket {
     fn drop(&mut self) {
         // Normally this should be cleaned up, but in some cases it is not
-        // The ipc path here should be valid, as the daemon socket is created
-        // based on whether the path is valid or not.
-        if let Ok(socket_path) = DaemonSocket::get_ipc_socket_path() {
-            let _ = fs::remove_file(socket_path);
-        }
+        let _ = fs::remove_file(&self.path);
     }
 }
 
@@ -145,14 +220,21 @@ pub struct DaemonConnection {
 impl DaemonConnection {
     /// Function for reading from the IPC socket while also abstracting dependencies
     /// and annoying implementation details like new line handling.
+    /// Fails when the client does not send a complete command within `COMMAND_READ_TIMEOUT`
+    /// or when the command is longer than `MAX_COMMAND_LENGTH`.
     ///
     /// # Returns
     ///
     /// String containing the received command.
     pub async fn read_command(&mut self) -> Result<String> {
         let mut command_buffer = String::new();
-        let mut reader = BufReader::new(&mut self.stream);
-        reader.read_line(&mut command_buffer).await?;
+        let mut reader = BufReader::new((&mut self.stream).take(MAX_COMMAND_LENGTH));
+        let read = timeout(COMMAND_READ_TIMEOUT, reader.read_line(&mut command_buffer))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Timed out reading command"))??;
+        if read as u64 == MAX_COMMAND_LENGTH && !command_buffer.ends_with('\n') {
+            return Err(Error::new(ErrorKind::InvalidData, "Command too long"));
+        }
         // Removing the trailing newline used as a message ending according to platform.
         command_buffer.pop();
 
@@ -11,9 +11,9 @@ use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::fs;
 use tracing::{level_filters::LevelFilter, Level};
 
-use telio::{crypto::SecretKey, device::AdapterType, telio_utils::Hidden};
+use telio::{crypto::SecretKey, device::AdapterType, telio_sockets, telio_utils::Hidden};
 
-use crate::{interface::InterfaceConfig, NordVpnLiteError};
+use crate::{interface::InterfaceConfig, webhook::WebhookConfig, NordVpnLiteError};
 use std::net::IpAddr;
 use telio::crypto::PublicKey;
 
@@ -135,6 +135,20 @@ pub struct NordVpnLiteConfig {
 
     /// Path to a http pem certificate to be used when connecting to CoreApi
     pub http_certificate_file_path: Option<PathBuf>,
+
+    /// Expose a read-only observer socket, accessible to the daemon's group, which
+    /// monitoring agents can use to query status, statistics and watch events
+    #[serde(default)]
+    pub observer_socket: bool,
+
+    /// HTTP endpoints notified about peer, exit and relay state changes
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// Proxy the Core API requests, the webhooks and the control connections of libtelio go
+    /// through, for the networks which reach the internet only through one
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
 }
 
 impl NordVpnLiteConfig {
@@ -147,6 +161,23 @@ impl NordVpnLiteConfig {
             Ok(file) => {
                 let mut config: NordVpnLiteConfig = serde_json::from_reader(file)?;
                 config.log_file_path = Self::resolve_log_path(&config.log_file_path)?;
+                if let Some(proxy) = &config.proxy {
+                    proxy
+                        .to_reqwest()
+                        .map_err(|e| NordVpnLiteError::InvalidConfigOption {
+                            key: "proxy".to_owned(),
+                            msg: e.to_string(),
+                            value: format!("{}:{}", proxy.host, proxy.port),
+                        })?;
+                }
+                #[cfg(feature = "strict_privacy")]
+                if !config.webhooks.is_empty() {
+                    return Err(NordVpnLiteError::InvalidConfigOption {
+                        key: "webhooks".to_owned(),
+                        msg: "webhooks are compiled out of the strict privacy builds".to_owned(),
+                        value: format!("{} webhooks", config.webhooks.len()),
+                    });
+                }
                 Ok(config)
             }
             // Create a default config if it does not exist
@@ -261,6 +292,9 @@ impl Default for NordVpnLiteConfig {
             override_default_wg_port: None,
             authentication_token: Default::default(),
             http_certificate_file_path: None,
+            observer_socket: false,
+            webhooks: Vec::new(),
+            proxy: None,
         }
     }
 }
@@ -345,6 +379,69 @@ pub enum VpnConfig {
     Recommended,
 }
 
+/// Protocol spoken to the proxy
+#[derive(PartialEq, Eq, Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// Proxy of the outbound connections
+#[derive(PartialEq, Eq, Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// Hostname or IP address of the proxy
+    pub host: String,
+    pub port: u16,
+    /// Username, when the proxy requires authentication
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Hidden<String>>,
+}
+
+impl ProxyConfig {
+    /// Proxy of the control connections of libtelio
+    pub fn to_telio(&self) -> telio_sockets::Proxy {
+        telio_sockets::Proxy {
+            kind: match self.kind {
+                ProxyKind::Socks5 => telio_sockets::ProxyKind::Socks5,
+                ProxyKind::HttpConnect => telio_sockets::ProxyKind::HttpConnect,
+            },
+            host: self.host.clone(),
+            port: self.port,
+            username: self.username.clone(),
+            password: self.password.clone(),
+        }
+    }
+
+    /// Proxy of the http clients. The hostnames of the requests are resolved by a SOCKS5 proxy
+    /// too, not to depend on a resolver reachable without it.
+    pub fn to_reqwest(&self) -> Result<reqwest::Proxy, reqwest::Error> {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5h",
+            ProxyKind::HttpConnect => "http",
+        };
+        let host = if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let proxy = reqwest::Proxy::all(format!("{scheme}://{host}:{}", self.port))?;
+        Ok(match &self.username {
+            Some(username) => proxy.basic_auth(
+                username,
+                self.password
+                    .as_ref()
+                    .map_or("", |password| password.as_str()),
+            ),
+            None => proxy,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::interface::InterfaceConfigurationProvider;
@@ -396,6 +493,9 @@ mod tests {
             override_default_wg_port: None,
             authentication_token: Default::default(),
             http_certificate_file_path: None,
+            observer_socket: false,
+            webhooks: Vec::new(),
+            proxy: None,
         };
         {
             let json = r#"{
@@ -682,6 +782,63 @@ mod tests {
         matches!(err, NordVpnLiteError::InvalidConfigOption { .. });
     }
 
+    #[cfg(feature = "strict_privacy")]
+    #[test]
+    fn test_config_proxy() {
+        let json = r#"{
+            "log_level": "Info",
+            "log_file_path": "test.log",
+            "adapter_type": "linux-native",
+            "interface": {
+                "name": "nlx",
+                "config_provider": "manual"
+            },
+            "authentication_token": "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+            "proxy": {
+                "kind": "socks5",
+                "host": "proxy.example",
+                "port": 1080,
+                "username": "user",
+                "password": "pass"
+            }
+        }"#;
+
+        let config: NordVpnLiteConfig = serde_json::from_str(json).unwrap();
+        let proxy = config.proxy.unwrap();
+        assert_eq!(
+            proxy.to_telio(),
+            telio_sockets::Proxy {
+                kind: telio_sockets::ProxyKind::Socks5,
+                host: "proxy.example".to_owned(),
+                port: 1080,
+                username: Some("user".to_owned()),
+                password: Some(Hidden("pass".to_owned())),
+            }
+        );
+        assert!(proxy.to_reqwest().is_ok());
+
+        let config = temp_config(&json.replace("proxy.example", "proxy example"));
+        assert!(matches!(
+            NordVpnLiteConfig::from_file(config.path()),
+            Err(NordVpnLiteError::InvalidConfigOption { key, .. }) if key == "proxy"
+        ));
+    }
+
+    #[test]
+    fn test_webhooks_are_refused_with_strict_privacy() {
+        let config_json = config_json_for_log("test.log").replace(
+            r#""log_level""#,
+            r#""webhooks": [{"url": "https://example.com/hook"}], "log_level""#,
+        );
+        let file = temp_config(&config_json);
+        let err = NordVpnLiteConfig::from_file(file.path().to_str().unwrap()).unwrap_err();
+
+        assert!(matches!(
+            err,
+            NordVpnLiteError::InvalidConfigOption { ref key, .. } if key == "webhooks"
+        ));
+    }
+
     #[test]
     #[serial]
     fn test_token_override_from_env() {
@@ -17,7 +17,9 @@ use telio::telio_utils::exponential_backoff::{
     Backoff, Error as BackoffError, ExponentialBackoff, ExponentialBackoffBounds,
 };
 
-use crate::config::{Endpoint, NordToken, NordVpnLiteConfig, NordlynxKeyResponse, VpnConfig};
+use crate::config::{
+    Endpoint, NordToken, NordVpnLiteConfig, NordlynxKeyResponse, ProxyConfig, VpnConfig,
+};
 use crate::NordVpnLiteError;
 
 const API_BASE: &str = "https://api.nordvpn.com/v1";
@@ -204,13 +206,14 @@ macro_rules! handle_api_error {
 pub(crate) async fn request_nordlynx_key(
     auth_token: &NordToken,
     cert_path: Option<&Path>,
+    proxy: Option<&ProxyConfig>,
 ) -> Result<SecretKey, Error> {
     info!("Requesting Nordlynx secret..");
     let mut backoff = build_backoff()?;
     let mut retries = 0;
 
     loop {
-        match send_request_nordlynx_key(auth_token, cert_path).await {
+        match send_request_nordlynx_key(auth_token, cert_path, proxy).await {
             Ok(id) => return Ok(id),
             Err(e) => {
                 warn!("Failed to request Nordlynx secret due to {e:?}");
@@ -225,8 +228,9 @@ pub(crate) async fn request_nordlynx_key(
 async fn send_request_nordlynx_key(
     auth_token: &NordToken,
     cert_path: Option<&Path>,
+    proxy: Option<&ProxyConfig>,
 ) -> Result<SecretKey, Error> {
-    let client = http_client(cert_path);
+    let client = http_client(cert_path, proxy)?;
     let response = client
         .get(format!("{API_BASE}/users/services/credentials"))
         .basic_auth("token", Some(auth_token as &str))
@@ -274,8 +278,13 @@ async fn wait_with_backoff_delay(
     Ok(())
 }
 
-fn http_client(cert_path: Option<&Path>) -> Client {
-    cert_path
+/// Client of the Core API, through the `proxy` when one is set
+fn http_client(cert_path: Option<&Path>, proxy: Option<&ProxyConfig>) -> Result<Client, Error> {
+    let builder = match proxy {
+        Some(proxy) => Client::builder().proxy(proxy.to_reqwest()?),
+        None => Client::builder(),
+    };
+    let cert = cert_path
         .and_then(|cert_path| {
             File::open(cert_path)
                 .inspect_err(|err| warn!("Custom certificate file could not be opened: {err:?}"))
@@ -294,31 +303,29 @@ fn http_client(cert_path: Option<&Path>) -> Client {
                         })
                         .ok()
                 })
-        })
-        .and_then(|cert| {
-            warn!("Using a self-signed certificate is unsafe and should generally be avoided");
-            Client::builder()
-                .add_root_certificate(cert)
-                .use_rustls_tls()
-                .danger_accept_invalid_certs(true)
-                .build()
-                .inspect_err(|err| {
-                    warn!("Could not build http client with custom certificate: {err:?}")
-                })
-                .ok()
-        })
-        .unwrap_or_default()
+        });
+    let Some(cert) = cert else {
+        return Ok(builder.build()?);
+    };
+
+    warn!("Using a self-signed certificate is unsafe and should generally be avoided");
+    Ok(builder
+        .add_root_certificate(cert)
+        .use_rustls_tls()
+        .danger_accept_invalid_certs(true)
+        .build()?)
 }
 
 /// Get the list of countries with VPN servers from core API.
 pub async fn get_countries_with_exp_backoff(
     cert_path: Option<&Path>,
+    proxy: Option<&ProxyConfig>,
 ) -> Result<Vec<Country>, Error> {
     let mut backoff = build_backoff()?;
     let mut retries = 0;
 
     loop {
-        match get_countries(cert_path).await {
+        match get_countries(cert_path, proxy).await {
             Ok(countries) => return Ok(countries),
             Err(Error::Reqwest(ref e)) if e.is_timeout() => {
                 warn!(
@@ -335,9 +342,12 @@ pub async fn get_countries_with_exp_backoff(
 }
 
 /// Get the list of countries with VPN servers from core API.
-async fn get_countries(cert_path: Option<&Path>) -> Result<Vec<Country>, Error> {
+async fn get_countries(
+    cert_path: Option<&Path>,
+    proxy: Option<&ProxyConfig>,
+) -> Result<Vec<Country>, Error> {
     debug!("Getting countries list");
-    let response = http_client(cert_path)
+    let response = http_client(cert_path, proxy)?
         .get(format!("{}/servers/countries", API_BASE))
         .header(header::ACCEPT, "application/json")
         .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
@@ -367,12 +377,13 @@ pub async fn get_recommended_servers_with_exp_backoff(
     country_id_filter: Option<u64>,
     limit_filter: Option<u64>,
     cert_path: Option<&Path>,
+    proxy: Option<&ProxyConfig>,
 ) -> Result<Vec<Server>, Error> {
     let mut backoff = build_backoff()?;
     let mut retries = 0;
 
     loop {
-        match get_recommended_servers(country_id_filter, limit_filter, cert_path).await {
+        match get_recommended_servers(country_id_filter, limit_filter, cert_path, proxy).await {
             Ok(servers) => return Ok(servers),
             Err(Error::Reqwest(ref e)) if e.is_timeout() => {
                 warn!(
@@ -393,6 +404,7 @@ async fn get_recommended_servers(
     country_id_filter: Option<u64>,
     limit_filter: Option<u64>,
     cert_path: Option<&Path>,
+    proxy: Option<&ProxyConfig>,
 ) -> Result<Vec<Server>, Error> {
     debug!(
         "Getting server recommendations list: country_id:{:?}, limit:{:?}",
@@ -416,7 +428,7 @@ async fn get_recommended_servers(
         filter.push(("filters[country_id]".into(), country_id.to_string()));
     };
 
-    let client = http_client(cert_path);
+    let client = http_client(cert_path, proxy)?;
     let response = client
         .get(format!("{}/servers/recommendations", API_BASE))
         .header(header::ACCEPT, "application/json")
@@ -449,8 +461,11 @@ pub async fn get_server_endpoints_list(config: &NordVpnLiteConfig) -> Result<Vec
                 warn!("Invalid country format: '{target_country}', non-ascii characters used");
                 None
             } else {
-                match get_countries_with_exp_backoff(config.http_certificate_file_path.as_deref())
-                    .await
+                match get_countries_with_exp_backoff(
+                    config.http_certificate_file_path.as_deref(),
+                    config.proxy.as_ref(),
+                )
+                .await
                 {
                     Ok(countries_list) => countries_list
                         .iter()
@@ -485,6 +500,7 @@ pub async fn get_server_endpoints_list(config: &NordVpnLiteConfig) -> Result<Vec
         country_id,
         None,
         config.http_certificate_file_path.as_deref(),
+        config.proxy.as_ref(),
     )
     .await?
     .iter()
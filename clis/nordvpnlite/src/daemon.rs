@@ -12,7 +12,7 @@ use telio::telio_model::mesh::{ExitNode, Node};
 use thiserror::Error as ThisError;
 use tokio::task::JoinError;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     time::Duration,
 };
 use tracing::{debug, error, info, trace, warn};
@@ -31,14 +31,19 @@ use telio::{
 
 use crate::command_listener::{ClientCmd, ExitNodeConfig, TelioTaskCmd, TIMEOUT_SEC};
 use crate::core_api::get_server_endpoints_list;
+#[cfg(not(feature = "strict_privacy"))]
+use crate::webhook::run_webhook_notifier;
 use crate::{
     command_listener::CommandListener,
-    comms::DaemonSocket,
-    config::{NordVpnLiteConfig, LOCAL_IP},
+    comms::{DaemonConnection, DaemonSocket},
+    config::{NordVpnLiteConfig, ProxyConfig, LOCAL_IP},
     core_api::{request_nordlynx_key, Error as ApiError, DEFAULT_WIREGUARD_PORT},
     interface::ConfigureInterface,
 };
 
+/// Number of libtelio events buffered for slow `watch` clients
+const EVENT_QUEUE_SIZE: usize = 64;
+
 #[derive(Debug, ThisError)]
 pub enum NordVpnLiteError {
     #[error(transparent)]
@@ -57,6 +62,8 @@ pub enum NordVpnLiteError {
     ParsingError(#[from] SerdeJsonError),
     #[error("Command failed to execute: {0:?}")]
     CommandFailed(ClientCmd),
+    #[error("Command not permitted in read-only mode: {0:?}")]
+    CommandNotPermitted(ClientCmd),
     #[error("Failed executing system command: {0:?}")]
     SystemCommandFailed(String),
     #[error("Daemon is not running")]
@@ -144,6 +151,7 @@ impl TelioContext {
     fn new(
         config: NordVpnLiteConfig,
         nordlynx_private_key: SecretKey,
+        events: broadcast::Sender<Box<Event>>,
     ) -> Result<Self, NordVpnLiteError> {
         debug!("Initializing telio device");
 
@@ -153,7 +161,15 @@ impl TelioContext {
             .enable_direct()
             .build();
 
-        let mut telio = Device::new(features, handle_telio_event, None)?;
+        let mut telio = Device::new(
+            features,
+            move |event: Box<Event>| {
+                // No receivers is the usual case, when nobody is watching
+                let _ = events.send(event.clone());
+                handle_telio_event(event);
+            },
+            None,
+        )?;
         Self::start_telio(&mut telio, &config, nordlynx_private_key)?;
 
         let mut interface_config_provider = config.interface.get_config_provider();
@@ -195,6 +211,7 @@ impl TelioContext {
             adapter: config.adapter_type.to_owned(),
             ..Default::default()
         })?;
+        telio.set_proxy(config.proxy.as_ref().map(ProxyConfig::to_telio))?;
 
         #[cfg(target_os = "linux")]
         telio.set_fwmark(LIBTELIO_FWMARK)?;
@@ -251,6 +268,13 @@ impl TelioTaskCmd {
                 }
                 Ok(TelioTaskOutcome::Continue)
             }
+            TelioTaskCmd::GetStats(response_tx_channel) => {
+                let stats = ctx.telio.peer_stats()?;
+                if response_tx_channel.send(stats).is_err() {
+                    error!("Telio task failed sending peer stats: receiver dropped")
+                }
+                Ok(TelioTaskOutcome::Continue)
+            }
             TelioTaskCmd::ConnectToExitNode(exit_node) => {
                 ctx.interface_config_provider
                     .set_exit_routes(&exit_node.endpoint.address, &exit_node.dns)
@@ -355,13 +379,36 @@ pub async fn daemon_event_loop(config: NordVpnLiteConfig) -> Result<(), NordVpnL
 
     let (telio_tx, telio_rx) = mpsc::channel(10);
 
+    let (events_tx, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+
+    #[cfg(not(feature = "strict_privacy"))]
+    if !config.webhooks.is_empty() {
+        tokio::spawn(run_webhook_notifier(
+            config.webhooks.clone(),
+            config.proxy.clone(),
+            events_tx.subscribe(),
+        ));
+    }
+
     let socket = DaemonSocket::new(&DaemonSocket::get_ipc_socket_path()?)?;
-    let mut cmd_listener = CommandListener::new(socket, telio_tx.clone());
+    let cmd_listener = CommandListener::new(socket, telio_tx.clone(), events_tx.clone());
+
+    let observer_listener = if config.observer_socket {
+        let socket = DaemonSocket::new_observer(&DaemonSocket::get_observer_socket_path()?)?;
+        Some(Arc::new(CommandListener::new_observer(
+            socket,
+            telio_tx.clone(),
+            events_tx.clone(),
+        )))
+    } else {
+        None
+    };
 
     let nordlynx_private_key = {
         let api_request_future = request_nordlynx_key(
             &config.authentication_token,
             config.http_certificate_file_path.as_deref(),
+            config.proxy.as_ref(),
         );
         pin_mut!(api_request_future);
         loop {
@@ -388,6 +435,9 @@ pub async fn daemon_event_loop(config: NordVpnLiteConfig) -> Result<(), NordVpnL
                         }
                     }
                 },
+                connection_result = accept_observer_connection(&observer_listener) => {
+                    handle_observer_connection(&observer_listener, false, connection_result);
+                },
                 _ = signals.next() => {
                     warn!("Interrupted while obtaining service credentials - stopping");
                     return Ok(());
@@ -398,7 +448,7 @@ pub async fn daemon_event_loop(config: NordVpnLiteConfig) -> Result<(), NordVpnL
 
     let config_clone = config.clone();
     let mut telio_task_handle = tokio::task::spawn_blocking(move || {
-        let mut context = TelioContext::new(config_clone, nordlynx_private_key)?;
+        let mut context = TelioContext::new(config_clone, nordlynx_private_key, events_tx)?;
         context.start_listening_commands(telio_rx)
     });
 
@@ -449,6 +499,10 @@ pub async fn daemon_event_loop(config: NordVpnLiteConfig) -> Result<(), NordVpnL
                     }
                 }
             },
+            // Handle read-only commands from monitoring agents
+            connection_result = accept_observer_connection(&observer_listener) => {
+                handle_observer_connection(&observer_listener, true, connection_result);
+            },
             // Handle interrupt signals for clean shutdown
             signal = signals.next() => {
                 match signal {
@@ -476,6 +530,46 @@ pub async fn daemon_event_loop(config: NordVpnLiteConfig) -> Result<(), NordVpnL
     }
 }
 
+/// Wait for a connection on the observer socket, never completes when it is disabled
+async fn accept_observer_connection(
+    observer_listener: &Option<Arc<CommandListener>>,
+) -> Result<DaemonConnection, NordVpnLiteError> {
+    match observer_listener {
+        Some(listener) => listener.accept_client_connection().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Serve an observer connection in its own task, so that a slow or unresponsive
+/// observer cannot stall the daemon event loop
+fn handle_observer_connection(
+    observer_listener: &Option<Arc<CommandListener>>,
+    is_ready: bool,
+    connection_result: Result<DaemonConnection, NordVpnLiteError>,
+) {
+    let Some(listener) = observer_listener else {
+        return;
+    };
+    match connection_result {
+        Ok(connection) => {
+            let listener = listener.clone();
+            tokio::spawn(async move {
+                match listener.handle_client_command(is_ready, connection).await {
+                    Ok(command) => {
+                        debug!("Observer command {:?} executed successfully", command);
+                    }
+                    Err(err) => {
+                        warn!("Rejected observer command: {}", err);
+                    }
+                }
+            });
+        }
+        Err(err) => {
+            error!("Failed accepting observer connection: {err}");
+        }
+    }
+}
+
 /// Handle events from telio::device
 fn handle_telio_event(event: Box<Event>) {
     match event.as_ref() {
@@ -537,5 +631,45 @@ fn handle_telio_event(event: Box<Event>) {
         Event::Relay { body } => {
             warn!("Received unsupported relay event: {:?}", body);
         }
+        Event::RelayRehoming { body } => {
+            info!(
+                "Relay rehoming from {} to {}",
+                body.from.hostname, body.to.hostname
+            );
+        }
+        Event::KeyRotation { body } => {
+            info!("Key rotation {:?}: {}", body.state, body.public_key);
+        }
+        Event::Connectivity { body } => {
+            info!("Connectivity change: {:?}", body);
+        }
+        Event::RelayQuality { body } => {
+            debug!(
+                "Relay {} rtt: {}ms, jitter: {}ms, reconnects: {}",
+                body.hostname, body.rtt_ms, body.jitter_ms, body.reconnects
+            );
+        }
+        Event::PqRekey { body } => match &body.error {
+            Some(error) => warn!(
+                "PQ rekey v{} with {} failed: {}",
+                body.version, body.public_key, error
+            ),
+            None => debug!(
+                "PQ rekey v{} with {} {:?}",
+                body.version, body.public_key, body.state
+            ),
+        },
+        Event::InboundApproval { body } => {
+            info!(
+                "Peer {} asks for approval to connect to {}",
+                body.public_key, body.destination
+            );
+        }
+        Event::Readiness { body } => {
+            debug!(
+                "Ready: {:?}, mesh formed: {:?}%",
+                body.ready, body.mesh_formed_percent
+            );
+        }
     }
 }
@@ -2,7 +2,7 @@
 
 use clap::Parser;
 use daemonize::{Daemonize, Outcome};
-use std::fs::OpenOptions;
+use std::{fs::OpenOptions, path::PathBuf};
 use tokio::time::{timeout, Duration};
 
 mod command_listener;
@@ -12,6 +12,7 @@ mod core_api;
 mod daemon;
 mod interface;
 mod logging;
+mod webhook;
 
 use crate::{
     command_listener::{ClientCmd, Cmd, CommandResponse, TIMEOUT_SEC},
@@ -104,8 +105,16 @@ fn main() -> Result<(), NordVpnLiteError> {
 async fn client_main(cmd: Cmd) -> Result<(), NordVpnLiteError> {
     match cmd {
         Cmd::Client(cmd) => {
-            let socket_path = DaemonSocket::get_ipc_socket_path()?;
+            let socket_path = client_socket_path(&cmd)?;
             if socket_path.exists() {
+                if cmd == ClientCmd::Watch {
+                    DaemonSocket::subscribe(&socket_path, &serde_json::to_string(&cmd)?, |line| {
+                        println!("{line}")
+                    })
+                    .await?;
+                    return Ok(());
+                }
+
                 let response = timeout(
                     Duration::from_secs(TIMEOUT_SEC),
                     DaemonSocket::send_command(&socket_path, &serde_json::to_string(&cmd)?),
@@ -122,6 +131,10 @@ async fn client_main(cmd: Cmd) -> Result<(), NordVpnLiteError> {
                         println!("{}", serde_json::to_string_pretty(&status)?);
                         Ok(())
                     }
+                    CommandResponse::Stats(stats) => {
+                        println!("{}", serde_json::to_string_pretty(&stats)?);
+                        Ok(())
+                    }
                     CommandResponse::DaemonInitializing => {
                         println!("Daemon is not ready, ignoring");
                         Err(NordVpnLiteError::CommandFailed(cmd))
@@ -143,7 +156,7 @@ async fn client_main(cmd: Cmd) -> Result<(), NordVpnLiteError> {
         }
         // Display list of available countries with VPN servers
         Cmd::Countries => {
-            for country in get_countries_with_exp_backoff(None).await? {
+            for country in get_countries_with_exp_backoff(None, None).await? {
                 println!("{}: {}", country.name, country.code);
             }
             Ok(())
@@ -153,3 +166,15 @@ async fn client_main(cmd: Cmd) -> Result<(), NordVpnLiteError> {
         _ => Err(NordVpnLiteError::InvalidCommand(format!("{cmd:?}"))),
     }
 }
+
+/// Read-only commands go through the observer socket when it is enabled, so that they
+/// also work for group members without access to the main socket
+fn client_socket_path(cmd: &ClientCmd) -> Result<PathBuf, NordVpnLiteError> {
+    if cmd.is_read_only() {
+        let observer_path = DaemonSocket::get_observer_socket_path()?;
+        if observer_path.exists() {
+            return Ok(observer_path);
+        }
+    }
+    Ok(DaemonSocket::get_ipc_socket_path()?)
+}
@@ -0,0 +1,444 @@
+//! Notifications of the meshnet state changes to user configured HTTP endpoints.
+//!
+//! Each selected event is POSTed as JSON to the webhooks subscribed to it. Requests are signed
+//! with HMAC-SHA256 when a secret is configured, and retried with an exponential backoff when
+//! the endpoint is unreachable or responds with an error.
+//!
+//! The notifications are compiled out of the `strict_privacy` builds, where only the config is
+//! kept so that a config with webhooks can be refused.
+
+#[cfg(not(feature = "strict_privacy"))]
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(not(feature = "strict_privacy"))]
+use hmac::{Hmac, Mac};
+#[cfg(not(feature = "strict_privacy"))]
+use reqwest::Client;
+use reqwest::Url;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(not(feature = "strict_privacy"))]
+use serde_json::{json, Value};
+#[cfg(not(feature = "strict_privacy"))]
+use sha2::Sha256;
+#[cfg(not(feature = "strict_privacy"))]
+use tokio::{sync::broadcast, time::Duration};
+#[cfg(not(feature = "strict_privacy"))]
+use tracing::{debug, warn};
+
+#[cfg(not(feature = "strict_privacy"))]
+use crate::config::ProxyConfig;
+use telio::telio_utils::Hidden;
+#[cfg(not(feature = "strict_privacy"))]
+use telio::{
+    crypto::PublicKey,
+    telio_model::config::RelayState,
+    telio_model::event::Event,
+    telio_model::mesh::NodeState,
+    telio_utils::exponential_backoff::{Backoff, ExponentialBackoff, ExponentialBackoffBounds},
+};
+
+/// Header carrying the hex encoded HMAC-SHA256 of `<timestamp>.<body>`
+#[cfg(not(feature = "strict_privacy"))]
+const SIGNATURE_HEADER: &str = "X-NordVPNLite-Signature";
+/// Header carrying the unix timestamp the signature was made at
+#[cfg(not(feature = "strict_privacy"))]
+const TIMESTAMP_HEADER: &str = "X-NordVPNLite-Timestamp";
+/// Deliveries failing more times than this are dropped
+#[cfg(not(feature = "strict_privacy"))]
+const MAX_RETRIES: usize = 5;
+#[cfg(not(feature = "strict_privacy"))]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events webhooks can be subscribed to
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// Meshnet peer disconnected
+    PeerOffline,
+    /// Connection to the VPN server is lost or being re-established
+    ExitDegraded,
+    /// Active relay server was switched to another one
+    RelaySwitched,
+}
+
+impl WebhookEvent {
+    fn all() -> Vec<Self> {
+        vec![Self::PeerOffline, Self::ExitDegraded, Self::RelaySwitched]
+    }
+}
+
+/// Turns the libtelio events into webhook events on the state transitions only, so repeated
+/// and initial states are not notified about
+#[cfg(not(feature = "strict_privacy"))]
+#[derive(Default)]
+struct Transitions {
+    /// Last known state of the nodes which are not disconnected
+    nodes: HashMap<PublicKey, NodeState>,
+    /// Relay server connected last
+    relay: Option<PublicKey>,
+}
+
+#[cfg(not(feature = "strict_privacy"))]
+impl Transitions {
+    /// Webhook event caused by the libtelio `event`, if any
+    fn classify(&mut self, event: &Event) -> Option<WebhookEvent> {
+        match event {
+            Event::Node { body } => {
+                let previous = if body.state == NodeState::Disconnected {
+                    self.nodes.remove(&body.public_key)
+                } else {
+                    self.nodes.insert(body.public_key, body.state)
+                };
+                match (previous, body.state) {
+                    (
+                        Some(NodeState::Connected),
+                        NodeState::Connecting | NodeState::Disconnected,
+                    ) if body.is_exit => Some(WebhookEvent::ExitDegraded),
+                    (Some(_), NodeState::Disconnected) if !body.is_exit => {
+                        Some(WebhookEvent::PeerOffline)
+                    }
+                    _ => None,
+                }
+            }
+            Event::Relay { body } if body.conn_state == RelayState::Connected => {
+                match self.relay.replace(body.public_key) {
+                    Some(previous) if previous != body.public_key => {
+                        Some(WebhookEvent::RelaySwitched)
+                    }
+                    _ => None,
+                }
+            }
+            Event::RelayRehoming { body } => {
+                // The connected event of the new server follows, it must not be notified again
+                self.relay = Some(body.to.public_key);
+                Some(WebhookEvent::RelaySwitched)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// HTTP endpoint notified about the selected events
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// http or https URL the events are POSTed to
+    #[serde(
+        deserialize_with = "deserialize_webhook_url",
+        serialize_with = "serialize_webhook_url"
+    )]
+    pub url: Url,
+    /// Events to notify about, all of them by default
+    #[serde(default = "WebhookEvent::all")]
+    pub events: Vec<WebhookEvent>,
+    /// Key of the HMAC-SHA256 request signatures, requests are not signed without it
+    #[serde(default)]
+    pub secret: Option<Hidden<String>>,
+}
+
+fn deserialize_webhook_url<'de, D>(deserializer: D) -> Result<Url, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let url: String = Deserialize::deserialize(deserializer)?;
+    let url = Url::parse(&url).map_err(de::Error::custom)?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(de::Error::custom(format!(
+            "unsupported webhook url scheme '{scheme}'"
+        ))),
+    }
+}
+
+fn serialize_webhook_url<S>(url: &Url, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(url.as_str())
+}
+
+/// Forward the libtelio `events` to the `webhooks`, through the `proxy` when one is set, until
+/// the events channel is closed
+#[cfg(not(feature = "strict_privacy"))]
+pub async fn run_webhook_notifier(
+    webhooks: Vec<WebhookConfig>,
+    proxy: Option<ProxyConfig>,
+    mut events: broadcast::Receiver<Box<Event>>,
+) {
+    let mut builder = Client::builder().timeout(REQUEST_TIMEOUT);
+    if let Some(proxy) = &proxy {
+        match proxy.to_reqwest() {
+            Ok(proxy) => builder = builder.proxy(proxy),
+            Err(e) => {
+                warn!("Failed to set up the webhook proxy: {e}");
+                return;
+            }
+        }
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to build the webhook http client: {e}");
+            return;
+        }
+    };
+
+    let mut transitions = Transitions::default();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Webhook notifier lagged, {skipped} events were not delivered");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let Some(kind) = transitions.classify(&event) else {
+            continue;
+        };
+        let body = json!({
+            "event": kind,
+            "timestamp": unix_timestamp(),
+            "data": event_data(&event),
+        })
+        .to_string();
+
+        for webhook in webhooks.iter().filter(|w| w.events.contains(&kind)) {
+            // Deliveries are independent, a slow endpoint does not hold back the others
+            tokio::spawn(deliver(client.clone(), webhook.clone(), body.clone()));
+        }
+    }
+    debug!("Webhook notifier stopped");
+}
+
+/// Body of the libtelio event, without its type tag
+#[cfg(not(feature = "strict_privacy"))]
+fn event_data(event: &Event) -> Value {
+    match serde_json::to_value(event) {
+        Ok(Value::Object(mut event)) => event.remove("body").unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(not(feature = "strict_privacy"))]
+async fn deliver(client: Client, webhook: WebhookConfig, body: String) {
+    let mut backoff = match ExponentialBackoff::new(ExponentialBackoffBounds {
+        initial: Duration::from_secs(1),
+        maximal: Some(Duration::from_secs(60)),
+    }) {
+        Ok(backoff) => backoff,
+        Err(e) => {
+            warn!("Invalid webhook backoff: {e}");
+            return;
+        }
+    };
+
+    for attempt in 0..=MAX_RETRIES {
+        let timestamp = unix_timestamp().to_string();
+        let mut request = client
+            .post(webhook.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .body(body.clone());
+        if let Some(secret) = &webhook.secret {
+            let message = format!("{timestamp}.{body}");
+            request = request.header(SIGNATURE_HEADER, sign(secret, message.as_bytes()));
+        }
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("Webhook {} notified", webhook.url);
+                return;
+            }
+            Ok(response) => warn!(
+                "Webhook {} responded with {} (attempt {})",
+                webhook.url,
+                response.status(),
+                attempt + 1
+            ),
+            Err(e) => warn!(
+                "Webhook {} failed: {e} (attempt {})",
+                webhook.url,
+                attempt + 1
+            ),
+        }
+        if attempt < MAX_RETRIES {
+            tokio::time::sleep(backoff.get_backoff()).await;
+            backoff.next_backoff();
+        }
+    }
+    warn!("Webhook {} notification dropped", webhook.url);
+}
+
+/// Hex encoded HMAC-SHA256 of the `message`
+#[cfg(not(feature = "strict_privacy"))]
+fn sign(secret: &str, message: &[u8]) -> String {
+    // HMAC accepts keys of any length
+    #[allow(clippy::expect_used)]
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes key of any size");
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(not(feature = "strict_privacy"))]
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "strict_privacy"))]
+    use telio::{
+        crypto::SecretKey,
+        telio_model::{config::Server, event::RelayRehoming, mesh::Node},
+    };
+
+    #[cfg(not(feature = "strict_privacy"))]
+    #[test]
+    fn signature_is_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[cfg(not(feature = "strict_privacy"))]
+    #[test]
+    fn node_transitions_are_classified() {
+        let peer = SecretKey::gen().public();
+        let exit = SecretKey::gen().public();
+        let node = |public_key, is_exit, state| Event::Node {
+            body: Node {
+                public_key,
+                is_exit,
+                state,
+                ..Default::default()
+            },
+        };
+        let mut transitions = Transitions::default();
+
+        // Initial states are not transitions
+        assert_eq!(
+            transitions.classify(&node(exit, true, NodeState::Connecting)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&node(peer, false, NodeState::Connecting)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&node(exit, true, NodeState::Connected)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&node(peer, false, NodeState::Connected)),
+            None
+        );
+
+        assert_eq!(
+            transitions.classify(&node(exit, true, NodeState::Connecting)),
+            Some(WebhookEvent::ExitDegraded)
+        );
+        // Repeats are not notified about
+        assert_eq!(
+            transitions.classify(&node(exit, true, NodeState::Connecting)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&node(exit, true, NodeState::Disconnected)),
+            None
+        );
+
+        assert_eq!(
+            transitions.classify(&node(peer, false, NodeState::Disconnected)),
+            Some(WebhookEvent::PeerOffline)
+        );
+        assert_eq!(
+            transitions.classify(&node(peer, false, NodeState::Disconnected)),
+            None
+        );
+    }
+
+    #[cfg(not(feature = "strict_privacy"))]
+    #[test]
+    fn relay_transitions_are_classified() {
+        let server = |public_key, conn_state| Server {
+            public_key,
+            conn_state,
+            ..Default::default()
+        };
+        let relay = |public_key, conn_state| Event::Relay {
+            body: server(public_key, conn_state),
+        };
+        let first = SecretKey::gen().public();
+        let second = SecretKey::gen().public();
+        let third = SecretKey::gen().public();
+        let mut transitions = Transitions::default();
+
+        assert_eq!(
+            transitions.classify(&relay(first, RelayState::Connected)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&relay(first, RelayState::Disconnected)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&relay(first, RelayState::Connected)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&relay(second, RelayState::Connecting)),
+            None
+        );
+        assert_eq!(
+            transitions.classify(&relay(second, RelayState::Connected)),
+            Some(WebhookEvent::RelaySwitched)
+        );
+
+        let rehoming = Event::RelayRehoming {
+            body: RelayRehoming {
+                from: server(second, RelayState::Connected),
+                to: server(third, RelayState::Connected),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            transitions.classify(&rehoming),
+            Some(WebhookEvent::RelaySwitched)
+        );
+        assert_eq!(
+            transitions.classify(&relay(third, RelayState::Connected)),
+            None
+        );
+        assert!(event_data(&rehoming).get("from_latency_ms").is_some());
+    }
+
+    #[test]
+    fn webhook_config_json() {
+        let webhook: WebhookConfig = serde_json::from_str(
+            r#"{"url": "https://example.com/hook", "events": ["peer_offline"], "secret": "s"}"#,
+        )
+        .unwrap();
+        assert_eq!(webhook.url.as_str(), "https://example.com/hook");
+        assert_eq!(webhook.events, vec![WebhookEvent::PeerOffline]);
+        assert_eq!(webhook.secret, Some(Hidden("s".to_owned())));
+
+        let webhook: WebhookConfig =
+            serde_json::from_str(r#"{"url": "http://10.0.0.1:8123/api/webhook/x"}"#).unwrap();
+        assert_eq!(webhook.events, WebhookEvent::all());
+        assert_eq!(webhook.secret, None);
+
+        assert!(serde_json::from_str::<WebhookConfig>(r#"{"url": "ftp://example.com"}"#).is_err());
+        assert!(serde_json::from_str::<WebhookConfig>(r#"{"url": "not a url"}"#).is_err());
+    }
+}
@@ -21,8 +21,20 @@ struct Args {
     features: Option<String>,
     #[clap(long)]
     less_spam: bool,
+    /// Print a json report for every command instead of text, for scripts
+    #[clap(long)]
+    json: bool,
+    /// Command to execute instead of reading them from the input, the exit code is 0 when it
+    /// succeeds, 1 when it fails and 2 when it is malformed
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
 }
 
+/// Exit code of a command which failed
+const EXIT_FAILURE: i32 = 1;
+/// Exit code of a command which could not be parsed
+const EXIT_USAGE: i32 = 2;
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
@@ -50,9 +62,24 @@ fn main() -> Result<()> {
     let derp_server = Arc::new(Mutex::<Option<Server>>::new(None));
 
     let mut cli = cli::Cli::new(features, token, derp_server)?;
+    cli.set_json(args.json);
     let mut stdout = std::io::stdout();
 
-    let less_spam = args.less_spam;
+    if !args.command.is_empty() {
+        let resps = cli.exec_args(args.command);
+        let code = exit_code(&resps);
+        if args.json {
+            println!("{}", json_report(resps)?);
+        } else {
+            for resp in resps {
+                println!("{}", resp);
+            }
+        }
+        stdout.flush()?;
+        std::process::exit(code);
+    }
+
+    let less_spam = args.less_spam || args.json;
     if !less_spam {
         println!("telio dev cli");
         println!();
@@ -67,7 +94,7 @@ fn main() -> Result<()> {
     let history_file_path = home_dir().map(|hp| hp.join(".tcli_history.txt"));
     if let Some(path) = history_file_path.as_ref() {
         let _ = rl.load_history(path).is_err();
-    } else {
+    } else if !args.json {
         println!("Home directory could not be found - the history won't be loaded and saved.\n");
     }
 
@@ -94,6 +121,22 @@ fn main() -> Result<()> {
                 .to_string();
         }
 
+        if args.json {
+            let resps = cli.exec(&cmd);
+            let quit = resps.iter().any(|resp| matches!(resp, cli::Resp::Quit));
+            println!("{}", json_report(resps)?);
+            if let Some(idx) = message_idx {
+                println!("MESSAGE_DONE={}", idx);
+            }
+            if quit {
+                if let Some(path) = history_file_path.as_ref() {
+                    let _ = rl.save_history(path);
+                }
+                return Ok(());
+            }
+            continue;
+        }
+
         for resp in cli.exec(&cmd) {
             use cli::Resp::*;
             match resp {
@@ -102,10 +145,20 @@ fn main() -> Result<()> {
                     DevEvent::Node { body: b } => print_event(ts, "node", &b)?,
                     DevEvent::Relay { body: b } => print_event(ts, "relay", &b)?,
                     DevEvent::Error { body: b } => print_event(ts, "error", &b)?,
+                    DevEvent::RelayRehoming { body: b } => print_event(ts, "relay_rehoming", &b)?,
+                    DevEvent::KeyRotation { body: b } => print_event(ts, "key_rotation", &b)?,
+                    DevEvent::Connectivity { body: b } => print_event(ts, "connectivity", &b)?,
+                    DevEvent::RelayQuality { body: b } => print_event(ts, "relay_quality", &b)?,
+                    DevEvent::PqRekey { body: b } => print_event(ts, "pq_rekey", &b)?,
+                    DevEvent::InboundApproval { body: b } => {
+                        print_event(ts, "inbound_approval", &b)?
+                    }
+                    DevEvent::Readiness { body: b } => print_event(ts, "readiness", &b)?,
                 },
                 Error(e) => {
                     println!("error: {e:#?}")
                 }
+                Data(data) => println!("- {}", data),
                 Quit => {
                     if let Some(idx) = message_idx {
                         println!("MESSAGE_DONE={}", idx);
@@ -128,6 +181,49 @@ fn main() -> Result<()> {
     }
 }
 
+/// Exit code of the command which gave the `resps`
+fn exit_code(resps: &[cli::Resp]) -> i32 {
+    resps
+        .iter()
+        .find_map(|resp| match resp {
+            cli::Resp::Error(err) if matches!(**err, cli::Error::Parser(_)) => Some(EXIT_USAGE),
+            cli::Resp::Error(_) => Some(EXIT_FAILURE),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+/// Single line json report of the command which gave the `resps`
+fn json_report(resps: Vec<cli::Resp>) -> Result<String> {
+    let code = exit_code(&resps);
+    let mut info = Vec::new();
+    let mut data = Vec::new();
+    let mut events = Vec::new();
+    let mut error = None;
+    for resp in resps {
+        match resp {
+            cli::Resp::Info(msg) => info.push(msg),
+            cli::Resp::Data(value) => data.push(value),
+            cli::Resp::Event { ts, event } => events.push(serde_json::json!({
+                "ts": time::OffsetDateTime::from(ts)
+                    .format(&time::format_description::well_known::Rfc3339)?,
+                "event": event,
+            })),
+            cli::Resp::Error(err) => error = Some(err.to_string()),
+            cli::Resp::Quit => (),
+        }
+    }
+
+    Ok(serde_json::to_string(&serde_json::json!({
+        "ok": code == 0,
+        "exit_code": code,
+        "info": info,
+        "data": data,
+        "events": events,
+        "error": error,
+    }))?)
+}
+
 fn print_event(
     ts: SystemTime,
     ty: impl std::fmt::Display,
@@ -3,8 +3,10 @@ use clap::Parser;
 use ipnet::IpNet;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use telio::crypto::{PublicKey, SecretKey};
 use telio::device::{Device, DeviceConfig};
+use telio::telio_firewall::firewall::Permissions;
 use telio_model::config::{RelayState, Server};
 use telio_model::features::Features;
 use telio_model::{config::Config as MeshMap, event::Event as DevEvent, mesh::ExitNode};
@@ -106,6 +108,8 @@ pub struct Cli {
     meshmap: Option<MeshMap>,
     derp_client: DerpClient,
     derp_server: Arc<Mutex<Option<Server>>>,
+    /// Whether the results are given as [Resp::Data] for scripts instead of text
+    json: bool,
 }
 
 #[derive(Debug)]
@@ -116,6 +120,8 @@ pub enum Resp {
         event: Box<DevEvent>,
     },
     Error(Box<Error>),
+    /// Machine-readable result, given instead of text in json mode
+    Data(serde_json::Value),
     Quit,
 }
 
@@ -128,6 +134,9 @@ impl Display for Resp {
             Resp::Error(e) => {
                 write!(f, "ERROR: {:?}", e)
             }
+            Resp::Data(data) => {
+                write!(f, "DATA: {}", data)
+            }
             Resp::Quit => {
                 write!(f, "QUIT")
             }
@@ -166,6 +175,8 @@ enum Cmd {
     Dns(DnsCmd),
     #[clap(subcommand)]
     Derp(DerpClientCmd),
+    #[clap(subcommand)]
+    Fw(FwCmd),
     Quit,
 }
 
@@ -222,6 +233,8 @@ enum DevCmd {
     Analytics,
     #[clap(about = "Trigger qos collection")]
     Qos,
+    #[clap(about = "Classify the NAT the device is behind")]
+    Nat,
 }
 
 #[derive(Parser)]
@@ -281,6 +294,17 @@ enum DnsCmd {
     Off,
 }
 
+#[derive(Parser)]
+#[clap(about = "Firewall inspection")]
+enum FwCmd {
+    /// Show permissions of the peers
+    Rules,
+    /// Show connections tracked by the firewall
+    Conntrack,
+    /// Show number of the packets accepted, dropped and rejected
+    Stats,
+}
+
 #[macro_export]
 macro_rules! cli_res {
     [
@@ -387,12 +411,31 @@ impl Cli {
             meshmap: None,
             derp_client: DerpClient::new(),
             derp_server,
+            json: false,
         })
     }
 
+    /// Give the results of the commands as [Resp::Data], for scripts
+    pub fn set_json(&mut self, json: bool) {
+        self.json = json;
+        self.derp_client.set_json(json);
+    }
+
+    /// Give the `data` as the result of the command in json mode
+    fn data(&self, res: &mut Vec<Resp>, data: serde_json::Value) {
+        if self.json {
+            res.push(Resp::Data(data));
+        }
+    }
+
     pub fn exec(&mut self, cmd: &str) -> Vec<Resp> {
+        let args = cli_try![shellwords::split(cmd)];
+        self.exec_args(args)
+    }
+
+    /// Execute a command already split into arguments
+    pub fn exec_args(&mut self, mut args: Vec<String>) -> Vec<Resp> {
         let mut res = Vec::new();
-        let mut args = cli_try![shellwords::split(cmd)];
         args.insert(0, "tcli".to_owned());
 
         let parse_try_result = Cmd::try_parse_from(&args);
@@ -400,6 +443,10 @@ impl Cli {
             Ok(cmd) => cli_res!(res; (j self.exec_cmd(cmd))),
             Err(err) => {
                 cli_res!(res; (i "{}", err));
+                // Help and version requests are not errors
+                if err.use_stderr() {
+                    cli_res!(res; (e Error::Parser(err.kind())));
+                }
             }
         }
         res
@@ -417,6 +464,7 @@ impl Cli {
             Cmd::Mesh(cmd) => cli_res!(res; (j self.exec_mesh(cmd))),
             Cmd::Dns(cmd) => cli_res!(res; (j self.exec_dns(cmd))),
             Cmd::Derp(cmd) => cli_res!(res; (j self.derp_client.exec_cmd(cmd))),
+            Cmd::Fw(cmd) => cli_res!(res; (j self.exec_fw(cmd))),
             Cmd::Quit => cli_res!(res; q),
         }
         res
@@ -436,7 +484,9 @@ impl Cli {
                         (i "go to: {}", &auth.redirect_uri),
                         (i "after rerun the same command again")
                     );
+                    self.data(&mut res, json!({ "redirect_uri": auth.redirect_uri }));
                     self.auth = Some(auth);
+                    return res;
                 }
             }
             Password { username, password } => {
@@ -446,6 +496,10 @@ impl Cli {
                 self.nord = Some(cli_try!(res; Nord::token_login(&token)));
             }
         }
+        self.data(
+            &mut res,
+            json!({ "user": self.nord.as_ref().map(|nord| &nord.user) }),
+        );
         res
     }
 
@@ -462,8 +516,13 @@ impl Cli {
 
                 let private_key = cli_try!(res; private_key
                     .or_else(|| self.nord.as_ref().and_then(|n| n.get_private_key().ok())).ok_or(Error::NeedsLogin));
+                let public_key = private_key.public();
 
                 cli_try!(res; self.start_telio(name, private_key, adapter, &mut res));
+                self.data(
+                    &mut res,
+                    json!({ "running": self.telio.is_running(), "public_key": public_key }),
+                );
             }
             Con {
                 public_key,
@@ -492,6 +551,7 @@ impl Cli {
                     cli_res!(res; (i "connecting to node:\n{:#?}", node));
                     cli_try!(res; self.telio.connect_exit_node(&node));
                 }
+                self.data(&mut res, json!({ "exit_node": node }));
             }
             Dis { public_key } => {
                 if !self.telio.is_running() {
@@ -500,6 +560,7 @@ impl Cli {
 
                 cli_res!(res; (i "stopping peer {}", public_key));
                 cli_try!(self.telio.disconnect_exit_node(&public_key));
+                self.data(&mut res, json!({ "disconnected": [public_key] }));
             }
             Disall => {
                 if !self.telio.is_running() {
@@ -508,6 +569,7 @@ impl Cli {
 
                 cli_res!(res; (i "stopping all peers"));
                 cli_try!(self.telio.disconnect_exit_nodes());
+                self.data(&mut res, json!({ "exit_node": null }));
             }
             NotifyNetChange => {
                 if !self.telio.is_running() {
@@ -516,18 +578,35 @@ impl Cli {
 
                 cli_res!(res; (i "notify net change"));
                 cli_try!(self.telio.notify_network_change());
+                self.data(&mut res, json!({ "notified": "network_change" }));
             }
             Stop => {
                 self.telio.stop();
                 cli_res!(res; (i "stopped telio."));
+                self.data(&mut res, json!({ "running": false }));
             }
             Analytics => {
                 cli_res!(res; (i "Trigger analytics event."));
                 cli_try!(self.telio.trigger_analytics_event());
+                self.data(&mut res, json!({ "triggered": "analytics" }));
             }
             Qos => {
                 cli_res!(res; (i "Trigger qos collection."));
                 cli_try!(self.telio.trigger_qos_collection());
+                self.data(&mut res, json!({ "triggered": "qos" }));
+            }
+            Nat => {
+                let report = cli_try!(res; self.telio.detect_nat());
+                cli_res!(res; (i "{:#?}", report));
+                self.data(
+                    &mut res,
+                    json!({
+                        "nat_type": format!("{:?}", report.nat_type),
+                        "public_address": report.public_address,
+                        "hairpinning": report.hairpinning,
+                        "binding_lifetime_s": report.binding_lifetime_s,
+                    }),
+                );
             }
         }
         res
@@ -558,12 +637,16 @@ impl Cli {
                 } else {
                     cli_try!(self.telio.connect_exit_node(&server));
                 }
+                self.data(&mut res, json!({ "exit_node": server }));
             }
             SetIp { name } => {
-                cli_try!(self.set_ip(&name, "10.5.0.2/16"));
+                let address = "10.5.0.2/16";
+                cli_try!(self.set_ip(&name, address));
+                self.data(&mut res, json!({ "interface": name, "address": address }));
             }
             Off => {
                 cli_try!(self.telio.disconnect_exit_nodes());
+                self.data(&mut res, json!({ "exit_node": null }));
             }
         }
         res
@@ -592,6 +675,7 @@ impl Cli {
                 cli_try!(res; self.start_telio(name, private_key, adapter_type, &mut res));
                 cli_try!(res; self.telio.set_config(&self.meshmap));
                 cli_res!(res; (i "started meshnet"));
+                self.data(&mut res, json!({ "meshnet": true, "config": self.meshmap }));
             }
             SetIp { mut name } => {
                 let meshmap = cli_try!(self
@@ -614,6 +698,7 @@ impl Cli {
                     ip_addr[0].to_string() + if ip_addr[0].is_ipv4() { "/10" } else { "/64" };
 
                 cli_try!(self.set_ip(&name, &ip_addr));
+                self.data(&mut res, json!({ "interface": name, "address": ip_addr }));
             }
             Register { name } => {
                 let nord = cli_try!(res; self.nord.as_ref().ok_or(Error::NeedsLogin));
@@ -643,27 +728,42 @@ impl Cli {
                     cli_res!(res; (i "registered new device."));
                     conf
                 };
+                self.data(
+                    &mut res,
+                    json!({ "name": conf.name, "public_key": conf.pk, "id": conf.id }),
+                );
                 self.conf = Some(conf);
             }
-            Ping {} => cli_res!(res; (i "receive ping - {:?}", self.telio.receive_ping())),
+            Ping {} => {
+                if self.json {
+                    let ping = cli_try!(res; self.telio.receive_ping());
+                    self.data(&mut res, json!({ "ping": ping }));
+                } else {
+                    cli_res!(res; (i "receive ping - {:?}", self.telio.receive_ping()))
+                }
+            }
             Config { mesh_config } => {
                 if mesh_config.is_empty() {
                     let meshmap =
                         cli_try!(self.meshmap.as_ref().cloned().ok_or(Error::EmptyConfig));
+                    self.data(&mut res, json!({ "config": meshmap }));
                     let meshmap = cli_try!(serde_json::to_string_pretty(&meshmap));
                     cli_res!(res; (i "Current meshnet config:\n{}", meshmap));
                 } else {
                     let meshmap: MeshMap = cli_try!(serde_json::from_str(&mesh_config));
+                    self.data(&mut res, json!({ "config": meshmap }));
                     cli_try!(self.telio.set_config(&Some(meshmap)));
                 }
             }
             FileConfig { filename } => {
                 let file_contents = cli_try!(fs::read_to_string(filename));
                 let meshmap: MeshMap = cli_try!(serde_json::from_str(&file_contents));
+                self.data(&mut res, json!({ "config": meshmap }));
                 cli_try!(self.telio.set_config(&Some(meshmap)));
             }
             Off => {
                 cli_try!(res; self.telio.set_config(&None));
+                self.data(&mut res, json!({ "meshnet": false }));
             }
         }
 
@@ -686,9 +786,103 @@ impl Cli {
 
                 cli_res!(res; (i "starting magic dns with forward servers: {:?}...", forward_servers));
                 cli_try!(res; self.telio.enable_magic_dns(&forward_servers));
+                self.data(
+                    &mut res,
+                    json!({ "magic_dns": true, "forward_servers": forward_servers }),
+                );
             }
             DnsCmd::Off => {
                 cli_try!(res; self.telio.disable_magic_dns());
+                self.data(&mut res, json!({ "magic_dns": false }));
+            }
+        }
+
+        res
+    }
+
+    fn exec_fw(&mut self, cmd: FwCmd) -> Vec<Resp> {
+        let mut res = Vec::new();
+        if !self.telio.is_running() {
+            cli_res!(res; (e Error::NotStarted));
+        }
+
+        match cmd {
+            FwCmd::Rules => {
+                let state = cli_try!(res; self.telio.firewall_state());
+                let whitelist = state.whitelist;
+                if self.json {
+                    let permissions: serde_json::Map<_, _> = Permissions::VALUES
+                        .iter()
+                        .map(|permission| {
+                            #[allow(clippy::indexing_slicing)]
+                            let peers = &whitelist.peer_whitelists[*permission];
+                            (permission.to_string(), json!(peers))
+                        })
+                        .collect();
+                    let ports: serde_json::Map<_, _> = whitelist
+                        .port_whitelist
+                        .iter()
+                        .map(|(peer, port)| (peer.to_string(), json!(port)))
+                        .collect();
+                    res.push(Resp::Data(json!({
+                        "ip_addresses": state.ip_addresses,
+                        "vpn_peer": whitelist.vpn_peer,
+                        "permissions": permissions,
+                        "port_whitelist": ports,
+                    })));
+                    return res;
+                }
+                cli_res!(res; (i "local addresses: {:?}", state.ip_addresses));
+                cli_res!(res; (i "vpn peer: {:?}", whitelist.vpn_peer));
+                for permission in Permissions::VALUES {
+                    #[allow(clippy::indexing_slicing)]
+                    let peers = &whitelist.peer_whitelists[permission];
+                    cli_res!(res; (i "{}: {} peer(s)", permission, peers.len()));
+                    for peer in peers {
+                        cli_res!(res; (i "  {}", peer));
+                    }
+                }
+                cli_res!(res; (i "whitelisted ports: {} peer(s)", whitelist.port_whitelist.len()));
+                for (peer, port) in &whitelist.port_whitelist {
+                    cli_res!(res; (i "  {}: {}", peer, port));
+                }
+            }
+            FwCmd::Conntrack => {
+                let connections = cli_try!(res; self.telio.firewall_connections());
+                if self.json {
+                    res.push(Resp::Data(json!(connections)));
+                    return res;
+                }
+                cli_res!(res; (i "{} connection(s)", connections.len()));
+                for connection in connections {
+                    let peer = connection
+                        .peer
+                        .map_or_else(|| "-".to_owned(), |peer| peer.to_string());
+                    cli_res!(res; (i "{} {} -> {} {:?} peer: {}",
+                        connection.protocol,
+                        connection.source,
+                        connection.destination,
+                        connection.state,
+                        peer
+                    ));
+                }
+            }
+            FwCmd::Stats => {
+                let stats = cli_try!(res; self.telio.firewall_stats());
+                if self.json {
+                    res.push(Resp::Data(json!(stats)));
+                    return res;
+                }
+                for (direction, counts) in
+                    [("inbound", stats.inbound), ("outbound", stats.outbound)]
+                {
+                    cli_res!(res; (i "{}: accepted {}, dropped {}, rejected {}",
+                        direction,
+                        counts.accepted,
+                        counts.dropped,
+                        counts.rejected
+                    ));
+                }
             }
         }
 
@@ -798,6 +992,21 @@ impl Cli {
     fn report(&self, cmd: StatusCmd) -> Vec<Resp> {
         use StatusCmd::*;
         let mut res = Vec::new();
+        if self.json {
+            let telio_nodes = if self.telio.is_running() {
+                cli_try!(res; self.telio.external_nodes())
+            } else {
+                Vec::new()
+            };
+            res.push(Resp::Data(json!({
+                "user": self.nord.as_ref().map(|nord| &nord.user),
+                "running": self.telio.is_running(),
+                "nodes": telio_nodes,
+                "derp": *self.derp_server.lock(),
+            })));
+            return res;
+        }
+
         if let Some(ref nord) = self.nord {
             cli_res!(res; (i "logged in as {}.", nord.user))
         } else {
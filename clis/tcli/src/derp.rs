@@ -3,6 +3,7 @@ use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 use clap::Parser;
 use parking_lot::Mutex;
 use serde::Deserialize;
+use serde_json::json;
 use telio::crypto::SecretKey;
 use telio_model::{config::Server, PublicKey};
 use telio_proto::{Codec, PacketRelayed};
@@ -37,6 +38,8 @@ pub enum DerpClientCmd {
 
 pub struct DerpClient {
     inst: Option<Instance>,
+    /// Whether the results are given as [Resp::Data] for scripts
+    json: bool,
 }
 
 #[derive(Deserialize)]
@@ -58,7 +61,15 @@ struct Instance {
 
 impl DerpClient {
     pub fn new() -> Self {
-        Self { inst: None }
+        Self {
+            inst: None,
+            json: false,
+        }
+    }
+
+    /// Give the results of the commands as [Resp::Data], for scripts
+    pub fn set_json(&mut self, json: bool) {
+        self.json = json;
     }
 
     pub fn exec_cmd(&mut self, cmd: DerpClientCmd) -> Vec<Resp> {
@@ -155,31 +166,46 @@ impl DerpClient {
                                 relay,
                             });
                         }
-                        Err(e) => res.push(Resp::Error(Box::new(e.into()))),
+                        Err(e) => cli_res!(res; (e e)),
                     };
                 }
+                self.data(&mut res, json!({ "running": true }));
             }
             Send { public_key, bytes } => {
                 if let Some(inst) = &mut self.inst {
                     let packet = cli_try!(PacketRelayed::decode(&bytes));
                     let _ = inst.send.blocking_send((public_key, packet));
                 }
+                self.data(&mut res, json!({ "sent": self.inst.is_some() }));
             }
             Recv => {
+                let mut received = Vec::new();
                 if let Some(inst) = &mut self.inst {
                     for (pk, packet) in inst.packets.lock().drain(..) {
-                        // TODO: Improve printing form personal needs.
-                        cli_res!(res; (i "{}: {:?}", pk, packet))
+                        if self.json {
+                            let bytes = cli_try!(res; packet.encode());
+                            received.push(json!({ "public_key": pk, "packet": bytes }));
+                        } else {
+                            // TODO: Improve printing form personal needs.
+                            cli_res!(res; (i "{}: {:?}", pk, packet))
+                        }
                     }
                 }
+                self.data(&mut res, json!(received));
             }
             Events => {
+                let mut events = Vec::new();
                 if let Some(inst) = &mut self.inst {
                     for event in inst.events.lock().drain(..) {
-                        // TODO: Improve printing form personal needs. json could be used
-                        cli_res!(res; (i "{:?}", event))
+                        if self.json {
+                            events.push(event);
+                        } else {
+                            // TODO: Improve printing form personal needs. json could be used
+                            cli_res!(res; (i "{:?}", event))
+                        }
                     }
                 }
+                self.data(&mut res, json!(events));
             }
             Off => {
                 if let Some(inst) = self.inst.take() {
@@ -187,9 +213,17 @@ impl DerpClient {
                     inst.collect.abort();
                     inst.rt.shutdown_timeout(Duration::from_secs(1));
                 }
+                self.data(&mut res, json!({ "running": false }));
             }
         }
 
         res
     }
+
+    /// Give the `data` as the result of the command in json mode
+    fn data(&self, res: &mut Vec<Resp>, data: serde_json::Value) {
+        if self.json {
+            res.push(Resp::Data(data));
+        }
+    }
 }
@@ -9,7 +9,7 @@ use curve25519_dalek::{scalar::clamp_integer, MontgomeryPoint, Scalar};
 use rand::{CryptoRng, RngCore};
 use zeroize::Zeroizing;
 
-use crate::{PublicKey, SecretKey};
+use crate::{PublicKey, SecretKey, SharedSecret};
 
 /// Public-key encryption scheme based on the X25519 Elliptic Curve Diffie-Hellman function and
 /// the [XChaCha20Poly1305] authenticated encryption cipher.
@@ -38,6 +38,14 @@ impl ChaChaBox {
         )))
     }
 
+    /// Create a ChaCha20 crypto box for the X25519 shared secret of the local and remote keys
+    pub fn from_shared_secret(shared_secret: &SharedSecret) -> Self {
+        Self(XChaCha20Poly1305::new(&hchacha::<U10>(
+            GenericArray::from_slice(&shared_secret.0 .0),
+            &GenericArray::default(),
+        )))
+    }
+
     /// Generate nonce bytes
     pub fn generate_nonce(rng: impl CryptoRng + RngCore) -> Nonce {
         XChaCha20Poly1305::generate_nonce(rng)
@@ -5,7 +5,7 @@ use std::{array::TryFromSliceError, convert::TryInto};
 use rand::{CryptoRng, RngCore};
 use telio_utils::telio_err_with_log;
 
-use crate::{chachabox::ChaChaBox, PublicKey, SecretKey, KEY_SIZE};
+use crate::{chachabox::ChaChaBox, KeyProvider, KeyProviderError, PublicKey, SecretKey, KEY_SIZE};
 
 const NONCE_SIZE: usize = 24;
 
@@ -24,15 +24,18 @@ pub enum Error {
     /// Failure when converting to array
     #[error(transparent)]
     ArrayConversionError(#[from] TryFromSliceError),
+    /// Local private key could not be used
+    #[error(transparent)]
+    KeyProvider(#[from] KeyProviderError),
 }
 
 /// Encrypt request stored in `msg`.
 ///
-/// This function encrypts message from `msg` using `local_sk` as the secret key and `remote_pk` as
+/// This function encrypts message from `msg` using `local_key` as the secret key and `remote_pk` as
 /// public key. It uses two layers of encryption so that the recipient can learn public key of sender
 /// but at the same time public key of sender is not leaked in the output bytes. The `msg` is first
-/// encrypted using `local_sk`, `remote_pk` and random `nonce1`. Next we prepend both public key
-/// matching `local_sk` and `nonce1` to the encrypted `msg`. The result of prepend is used as input
+/// encrypted using `local_key`, `remote_pk` and random `nonce1`. Next we prepend both public key
+/// matching `local_key` and `nonce1` to the encrypted `msg`. The result of prepend is used as input
 /// message for outer/second layer of encryption. Outer layer uses randomly generated public/private key
 /// pair and again `remote_pk`.
 /// This ensures that owner of secret key matching `remote_pk` can decode outer layer and check attached
@@ -40,17 +43,17 @@ pub enum Error {
 pub fn encrypt_request(
     msg: &[u8],
     mut rng: &mut (impl RngCore + CryptoRng),
-    local_sk: &SecretKey,
+    local_key: &(impl KeyProvider + ?Sized),
     remote_pk: &PublicKey,
 ) -> Result<Vec<u8>, Error> {
     let inner_nonce = ChaChaBox::generate_nonce(&mut rng);
-    let inner_secret_box = ChaChaBox::new(remote_pk, local_sk);
+    let inner_secret_box = ChaChaBox::from_shared_secret(&local_key.ecdh(remote_pk)?);
     let inner_encrypted_payload = match inner_secret_box.encrypt(&inner_nonce, msg) {
         Ok(inner_encrypted_payload) => inner_encrypted_payload,
         Err(e) => telio_err_with_log!(e)?,
     };
 
-    let local_pk = &local_sk.public();
+    let local_pk = &local_key.public_key();
 
     let mut outer_msg =
         Vec::with_capacity(local_pk.len() + inner_nonce.len() + inner_encrypted_payload.len());
@@ -83,7 +86,7 @@ pub fn encrypt_request(
 /// This function should be used with payload created using `encrypt_request`.
 pub fn decrypt_request(
     msg: &[u8],
-    local_sk: &SecretKey,
+    local_key: &(impl KeyProvider + ?Sized),
     is_allowed: impl Fn(&PublicKey) -> bool,
 ) -> Result<(Vec<u8>, PublicKey), Error> {
     let ephemeral_pk: [u8; KEY_SIZE] = msg
@@ -98,7 +101,8 @@ pub fn decrypt_request(
         .get(KEY_SIZE + NONCE_SIZE..)
         .ok_or(Error::InvalidLength)?;
 
-    let outer_secret_box = ChaChaBox::new(&PublicKey(ephemeral_pk), local_sk);
+    let outer_secret_box =
+        ChaChaBox::from_shared_secret(&local_key.ecdh(&PublicKey(ephemeral_pk))?);
     let outer_msg = match outer_secret_box.decrypt(&outer_nonce.into(), outer_encrypted_payload) {
         Ok(outer_msg) => outer_msg,
         Err(e) => telio_err_with_log!(e)?,
@@ -122,7 +126,7 @@ pub fn decrypt_request(
         .get(KEY_SIZE + NONCE_SIZE..)
         .ok_or(Error::InvalidLength)?;
 
-    let inner_secret_box = ChaChaBox::new(&remote_pk, local_sk);
+    let inner_secret_box = ChaChaBox::from_shared_secret(&local_key.ecdh(&remote_pk)?);
     let inner_msg = match inner_secret_box.decrypt(&inner_nonce.into(), inner_encrypted_payload) {
         Ok(inner_msg) => inner_msg,
         Err(e) => telio_err_with_log!(e)?,
@@ -131,17 +135,17 @@ pub fn decrypt_request(
     Ok((inner_msg, remote_pk))
 }
 
-/// Encrypt message stored in `msg` using `local_sk` as secret key and `remote_pk` as public key.
+/// Encrypt message stored in `msg` using `local_key` as secret key and `remote_pk` as public key.
 ///
 /// Resulting payload contains in order: random nonce and encrypted message.
 pub fn encrypt_response(
     msg: &[u8],
     rng: &mut (impl RngCore + CryptoRng),
-    local_sk: &SecretKey,
+    local_key: &(impl KeyProvider + ?Sized),
     remote_pk: &PublicKey,
 ) -> Result<Vec<u8>, Error> {
     let nonce = ChaChaBox::generate_nonce(rng);
-    let secret_box = ChaChaBox::new(remote_pk, local_sk);
+    let secret_box = ChaChaBox::from_shared_secret(&local_key.ecdh(remote_pk)?);
     let encrypted_msg = match secret_box.encrypt(&nonce, msg) {
         Ok(encrypted_msg) => encrypted_msg,
         Err(e) => telio_err_with_log!(e)?,
@@ -157,7 +161,7 @@ pub fn encrypt_response(
 /// This function should be used with payload created using `encrypt_response`.
 pub fn decrypt_response(
     msg: &[u8],
-    local_sk: &SecretKey,
+    local_key: &(impl KeyProvider + ?Sized),
     remote_pk: &PublicKey,
 ) -> Result<Vec<u8>, Error> {
     let nonce: [u8; NONCE_SIZE] = msg
@@ -165,7 +169,7 @@ pub fn decrypt_response(
         .ok_or(Error::InvalidLength)?
         .try_into()?;
     let message = msg.get(NONCE_SIZE..).ok_or(Error::InvalidLength)?;
-    let secret_box = ChaChaBox::new(remote_pk, local_sk);
+    let secret_box = ChaChaBox::from_shared_secret(&local_key.ecdh(remote_pk)?);
     let msg = match secret_box.decrypt(&nonce.into(), message) {
         Ok(msg) => msg,
         Err(e) => telio_err_with_log!(e)?,
@@ -280,4 +284,36 @@ mod tests {
         assert_eq!(MSG, decrypted_response);
         Ok(())
     }
+    /// Provider which cannot reveal its key, like the hardware backed ones
+    #[derive(Debug)]
+    struct OpaqueKeyProvider(SecretKey);
+
+    impl KeyProvider for OpaqueKeyProvider {
+        fn public_key(&self) -> PublicKey {
+            self.0.public()
+        }
+
+        fn ecdh(&self, peer: &PublicKey) -> Result<crate::SharedSecret, KeyProviderError> {
+            Ok(self.0.ecdh(peer))
+        }
+    }
+
+    #[test]
+    fn request_roundtrip_with_key_provider() -> Result<(), Error> {
+        let mut rng = rand::thread_rng();
+        let local_sk = SecretKey::gen();
+        let local_pk = local_sk.public();
+        let remote: Box<dyn KeyProvider> = Box::new(OpaqueKeyProvider(SecretKey::gen()));
+        let encrypted_request = encrypt_request(MSG, &mut rng, &local_sk, &remote.public_key())?;
+
+        let (decrypted_request, key) = decrypt_request(&encrypted_request, &*remote, |_| true)?;
+        assert_eq!(local_pk, key);
+        assert_eq!(MSG, decrypted_request);
+
+        let encrypted_response = encrypt_response(MSG, &mut rng, &*remote, &local_pk)?;
+        let decrypted_response =
+            decrypt_response(&encrypted_response, &local_sk, &remote.public_key())?;
+        assert_eq!(MSG, decrypted_response);
+        Ok(())
+    }
 }
@@ -23,6 +23,8 @@
 
 pub mod chachabox;
 pub mod encryption;
+pub mod provider;
+pub mod rotation;
 
 use std::{cmp::Ordering, convert::TryInto, fmt};
 
@@ -34,6 +36,8 @@ use telio_utils::Hidden;
 use x25519_dalek::x25519;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
+pub use provider::{KeyProvider, KeyProviderError};
+
 /// Secret, Public and Wireguard Preshared key size in bytes
 pub const KEY_SIZE: usize = 32;
 
@@ -127,7 +131,19 @@ pub fn smaller_key_in_meshnet_canonical_order<'a>(
 }
 
 /// Preshared key type
-#[derive(Default, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Clone, ZeroizeOnDrop)]
+#[derive(
+    Default,
+    Debug,
+    PartialOrd,
+    Ord,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    Serialize,
+    Deserialize,
+    ZeroizeOnDrop,
+)]
 pub struct PresharedKey(pub Hidden<[u8; KEY_SIZE]>);
 
 /// Error returned when parsing fails for SecretKey or PublicKey.
@@ -0,0 +1,60 @@
+//! Private key operations delegated to the key storage.
+//!
+//! Platforms keeping the device key in Android Keystore, iOS Secure Enclave or a TPM implement
+//! [KeyProvider], so that the protocols of libtelio can use the key without its bytes ever
+//! being loaded into memory. [SecretKey] implements it as the software fallback.
+//!
+//! WireGuard backends still need the raw private key, so only the operations done by libtelio
+//! itself, like the encryption of the meshnet pinger messages, go through the provider.
+
+use std::fmt;
+
+use crate::{PublicKey, SecretKey, SharedSecret};
+
+/// Error returned by the [KeyProvider] operations
+#[derive(Debug, thiserror::Error)]
+pub enum KeyProviderError {
+    /// Key storage is locked or the key was removed from it
+    #[error("Private key is not available: {0}")]
+    Unavailable(String),
+    /// Key storage failed to perform the operation
+    #[error("Private key operation failed: {0}")]
+    OperationFailed(String),
+}
+
+/// Holder of a X25519 private key performing the operations which require it
+pub trait KeyProvider: Send + Sync + fmt::Debug {
+    /// Public key of the held private key
+    fn public_key(&self) -> PublicKey;
+
+    /// X25519 Diffie-Hellman of the held private key with the `peer` public key
+    fn ecdh(&self, peer: &PublicKey) -> Result<SharedSecret, KeyProviderError>;
+}
+
+impl KeyProvider for SecretKey {
+    fn public_key(&self) -> PublicKey {
+        self.public()
+    }
+
+    fn ecdh(&self, peer: &PublicKey) -> Result<SharedSecret, KeyProviderError> {
+        Ok(SecretKey::ecdh(self, peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn software_provider_agrees_with_peer() {
+        let local = SecretKey::gen();
+        let remote = SecretKey::gen();
+        let provider: &dyn KeyProvider = &local;
+
+        assert_eq!(provider.public_key(), local.public());
+        assert_eq!(
+            provider.ecdh(&remote.public()).unwrap(),
+            remote.ecdh(&local.public())
+        );
+    }
+}
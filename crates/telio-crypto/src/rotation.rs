@@ -0,0 +1,178 @@
+//! Periodic device key rotation.
+//!
+//! Rotation is split into two phases. First a new keypair is generated and its public
+//! key is announced, so that the backend can distribute it to the peers. Once the
+//! announcement is confirmed, the old key stays in use for a grace window, after
+//! which the device is switched over to the new key.
+
+use std::time::Duration;
+
+use telio_utils::Instant;
+
+use crate::{PublicKey, SecretKey};
+
+/// Action which has to be taken by the owner of [KeyRotation]
+#[derive(Debug, PartialEq, Eq)]
+pub enum RotationAction {
+    /// New key was generated, its public key should be announced to the backend
+    Announce(PublicKey),
+    /// Grace window has passed, device should start using the new key
+    Switch(SecretKey),
+}
+
+#[derive(Debug)]
+struct PendingKey {
+    secret_key: SecretKey,
+    confirmed_at: Option<Instant>,
+}
+
+/// Key rotation state machine
+#[derive(Debug)]
+pub struct KeyRotation {
+    interval: Duration,
+    grace_period: Duration,
+    current: PublicKey,
+    pending: Option<PendingKey>,
+    next_rotation: Instant,
+}
+
+impl KeyRotation {
+    /// Create rotation schedule for the currently used key
+    pub fn new(
+        current: PublicKey,
+        interval: Duration,
+        grace_period: Duration,
+        now: Instant,
+    ) -> Self {
+        Self {
+            interval,
+            grace_period,
+            current,
+            pending: None,
+            next_rotation: now + interval,
+        }
+    }
+
+    /// Public key which is currently used by the device
+    pub fn current(&self) -> PublicKey {
+        self.current
+    }
+
+    /// Public key waiting to be confirmed or switched to
+    pub fn pending(&self) -> Option<PublicKey> {
+        self.pending.as_ref().map(|p| p.secret_key.public())
+    }
+
+    /// Confirm that `public_key` was accepted by the backend.
+    ///
+    /// Returns false if `public_key` is not the pending key.
+    pub fn confirm(&mut self, public_key: &PublicKey, now: Instant) -> bool {
+        match self.pending.as_mut() {
+            Some(pending) if pending.secret_key.public() == *public_key => {
+                pending.confirmed_at.get_or_insert(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Restart the schedule after the key was changed externally,
+    /// any pending key is discarded
+    pub fn reset(&mut self, current: PublicKey, now: Instant) {
+        self.current = current;
+        self.pending = None;
+        self.next_rotation = now + self.interval;
+    }
+
+    /// Advance the state machine. The pending key is kept until the owner resets the schedule
+    /// with it after the switch succeeded, so that a failed switch is retried on the next poll
+    pub fn poll(&mut self, now: Instant) -> Option<RotationAction> {
+        match self.pending.take() {
+            None if now >= self.next_rotation => {
+                let secret_key = SecretKey::gen();
+                let public_key = secret_key.public();
+                self.pending = Some(PendingKey {
+                    secret_key,
+                    confirmed_at: None,
+                });
+                Some(RotationAction::Announce(public_key))
+            }
+            Some(PendingKey {
+                secret_key,
+                confirmed_at: Some(confirmed_at),
+            }) if now >= confirmed_at + self.grace_period => {
+                self.pending = Some(PendingKey {
+                    secret_key: secret_key.clone(),
+                    confirmed_at: Some(confirmed_at),
+                });
+                Some(RotationAction::Switch(secret_key))
+            }
+            pending => {
+                self.pending = pending;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(100);
+    const GRACE: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn rotation_announces_and_switches_after_grace_period() {
+        let start = Instant::now();
+        let current = SecretKey::gen().public();
+        let mut rotation = KeyRotation::new(current, INTERVAL, GRACE, start);
+
+        assert_eq!(rotation.poll(start + INTERVAL / 2), None);
+
+        let announced = match rotation.poll(start + INTERVAL) {
+            Some(RotationAction::Announce(pk)) => pk,
+            other => panic!("Unexpected action {other:?}"),
+        };
+        assert_eq!(rotation.pending(), Some(announced));
+
+        // Not confirmed, so the old key stays
+        assert_eq!(rotation.poll(start + INTERVAL * 2), None);
+        assert_eq!(rotation.current(), current);
+
+        assert!(!rotation.confirm(&current, start + INTERVAL * 2));
+        assert!(rotation.confirm(&announced, start + INTERVAL * 2));
+
+        assert_eq!(rotation.poll(start + INTERVAL * 2 + GRACE / 2), None);
+        match rotation.poll(start + INTERVAL * 2 + GRACE) {
+            Some(RotationAction::Switch(sk)) => assert_eq!(sk.public(), announced),
+            other => panic!("Unexpected action {other:?}"),
+        }
+        // The switch is repeated until it succeeds
+        assert_eq!(rotation.current(), current);
+        assert!(matches!(
+            rotation.poll(start + INTERVAL * 2 + GRACE * 2),
+            Some(RotationAction::Switch(_))
+        ));
+
+        rotation.reset(announced, start + INTERVAL * 2 + GRACE * 2);
+        assert_eq!(rotation.current(), announced);
+        assert_eq!(rotation.pending(), None);
+    }
+
+    #[test]
+    fn reset_discards_pending_key() {
+        let start = Instant::now();
+        let mut rotation = KeyRotation::new(SecretKey::gen().public(), INTERVAL, GRACE, start);
+        assert!(rotation.poll(start + INTERVAL).is_some());
+
+        let new = SecretKey::gen().public();
+        rotation.reset(new, start + INTERVAL);
+        assert_eq!(rotation.pending(), None);
+        assert_eq!(rotation.current(), new);
+        assert_eq!(
+            rotation.poll(start + INTERVAL * 2 - Duration::from_secs(1)),
+            None
+        );
+    }
+}
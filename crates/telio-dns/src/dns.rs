@@ -1,4 +1,4 @@
-use crate::{bind_tun, LocalNameServer, NameServer, Records};
+use crate::{bind_tun, LocalNameServer, NameServer, QueryObserver, Records, UpstreamFailover};
 use async_trait::async_trait;
 use ipnet::IpNet;
 use neptun::noise::Tunn;
@@ -11,7 +11,7 @@ use tokio::net::UdpSocket;
 use tokio::sync::{Mutex, RwLock};
 use x25519_dalek::{PublicKey as PublicKeyDalek, StaticSecret};
 
-use telio_model::features::{FeatureExitDns, TtlValue};
+use telio_model::features::{FeatureDnsStubListener, FeatureExitDns, TtlValue};
 
 //debug tools
 use telio_utils::{telio_log_debug, telio_log_error};
@@ -114,6 +114,36 @@ impl LocalDnsResolver {
             auto_switch_ips,
         })
     }
+
+    /// Start plain UDP listeners serving the same zones as the in-tunnel resolver.
+    ///
+    /// Listeners are stopped together with the resolver.
+    pub async fn start_stub_listeners(
+        &self,
+        listeners: &[FeatureDnsStubListener],
+    ) -> Result<(), String> {
+        for listener in listeners {
+            let addr = SocketAddr::new(listener.address, listener.port);
+            let socket = UdpSocket::bind(addr).await.map_err(|e| {
+                telio_log_error!("Failed to bind dns stub listener on {}: {:?}", addr, e);
+                format!("Failed to bind dns stub listener on {addr}: {e:?}")
+            })?;
+            telio_log_debug!("Dns - stub listener on {}", addr);
+            self.nameserver.start_stub_listener(Arc::new(socket)).await;
+        }
+        Ok(())
+    }
+
+    /// Notify `observer` about the name of every query served, or stop notifying with `None`.
+    pub async fn set_query_observer(&self, observer: Option<QueryObserver>) {
+        self.nameserver.set_query_observer(observer).await;
+    }
+
+    /// Configure how the queries are forwarded to multiple upstream servers, and who is notified
+    /// when a failing server gets quarantined.
+    pub async fn set_upstream_failover(&self, failover: UpstreamFailover) -> Result<(), String> {
+        self.nameserver.set_upstream_failover(failover).await
+    }
 }
 
 #[async_trait]
@@ -2,10 +2,13 @@
 //! Needed to change behaviour of [tokio::net::UdpSocket]
 
 use std::{
+    collections::HashMap,
     future::Future,
     io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
+    sync::Arc,
+    time::Duration,
 };
 
 use async_trait::async_trait;
@@ -19,8 +22,8 @@ use hickory_server::{
         udp::{DnsUdpSocket, UdpSocket as ProtoUdpSocket},
     },
     resolver::{
-        config::ResolverConfig,
-        error::ResolveErrorKind,
+        config::{NameServerConfigGroup, ResolverConfig},
+        error::{ResolveError, ResolveErrorKind},
         lookup::Lookup as ResolverLookup,
         name_server::{GenericConnector, RuntimeProvider, TokioRuntimeProvider},
         AsyncResolver,
@@ -28,10 +31,12 @@ use hickory_server::{
     server::RequestInfo,
     store::forwarder::ForwardConfig,
 };
+use telio_model::features::DnsUpstreamStrategy;
 use telio_utils::{telio_log_debug, telio_log_info, telio_log_trace, telio_log_warn};
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, sync::Mutex, task::JoinSet};
 
 use crate::bind_tun;
+use crate::upstream::{FailoverObserver, UpstreamFailover, Upstreams};
 
 #[derive(Default, Clone)]
 pub struct TelioRuntimeProvider(TokioRuntimeProvider);
@@ -129,10 +134,14 @@ impl ProtoUdpSocket for TelioUdpSocket {
 
 /// An authority that will forward resolutions to upstream resolvers.
 ///
-/// This uses the trust-dns-resolver for resolving requests.
+/// This uses the trust-dns-resolver for resolving requests, with a resolver per upstream server,
+/// so that the servers are picked according to the configured strategy and their health.
 pub struct ForwardAuthority {
     origin: LowerName,
-    resolver: TelioAsyncResolver,
+    resolvers: HashMap<IpAddr, TelioAsyncResolver>,
+    /// Shared with the authorities forwarding to the servers before, to keep their health
+    upstreams: Arc<Mutex<Upstreams>>,
+    failover_observer: Option<FailoverObserver>,
 }
 
 impl ForwardAuthority {
@@ -141,6 +150,8 @@ impl ForwardAuthority {
         origin: Name,
         _zone_type: ZoneType,
         config: ForwardConfig,
+        failover: UpstreamFailover,
+        upstreams: Arc<Mutex<Upstreams>>,
     ) -> Result<Self, String> {
         telio_log_info!("loading forwarder config: {}", origin);
 
@@ -166,18 +177,120 @@ impl ForwardAuthority {
             options.preserve_intermediates = true;
         }
 
-        let config = ResolverConfig::from_parts(None, vec![], name_servers);
+        options.timeout = Duration::from_millis(failover.config.timeout_ms.into());
 
-        let resolver = TelioAsyncResolver::new(config, options, GenericConnector::default());
+        let mut servers: Vec<IpAddr> = Vec::new();
+        for name_server in name_servers.iter() {
+            let ip = name_server.socket_addr.ip();
+            if !servers.contains(&ip) {
+                servers.push(ip);
+            }
+        }
+        let resolvers = servers
+            .iter()
+            .map(|ip| {
+                let group = NameServerConfigGroup::from(
+                    name_servers
+                        .iter()
+                        .filter(|name_server| name_server.socket_addr.ip() == *ip)
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                );
+                let config = ResolverConfig::from_parts(None, vec![], group);
+                (
+                    *ip,
+                    TelioAsyncResolver::new(config, options.clone(), GenericConnector::default()),
+                )
+            })
+            .collect();
 
-        telio_log_info!("forward resolver configured: {}: ", origin);
+        telio_log_info!(
+            "forward resolver configured: {}: {:?} {:?}",
+            origin,
+            servers,
+            failover.config.strategy
+        );
+
+        upstreams.lock().await.update(&servers, failover.config);
 
         // TODO: this might be infallible?
         Ok(Self {
             origin: origin.into(),
-            resolver,
+            resolvers,
+            upstreams,
+            failover_observer: failover.observer,
         })
     }
+
+    /// Record the outcome of a query to the `server`, returns whether the server answered
+    async fn record(&self, server: IpAddr, result: &Result<ResolverLookup, ResolveError>) -> bool {
+        let answered = is_answer(result);
+        let takeover = {
+            let mut upstreams = self.upstreams.lock().await;
+            if answered {
+                upstreams.succeeded(server);
+                None
+            } else {
+                upstreams.failed(server)
+            }
+        };
+        if let (Some(to), Some(observer)) = (takeover, self.failover_observer.as_ref()) {
+            observer(server, to);
+        }
+        answered
+    }
+
+    /// Query the servers one by one until one of them answers
+    async fn lookup_sequential(
+        &self,
+        servers: Vec<IpAddr>,
+        name: &LowerName,
+        rtype: RecordType,
+    ) -> Option<Result<ResolverLookup, ResolveError>> {
+        let mut last = None;
+        for server in servers {
+            let Some(resolver) = self.resolvers.get(&server) else {
+                continue;
+            };
+            let result = resolver.lookup(name.clone(), rtype).await;
+            let answered = self.record(server, &result).await;
+            last = Some(result);
+            if answered {
+                break;
+            }
+        }
+        last
+    }
+
+    /// Query all of the servers at once, the first answer wins
+    async fn lookup_parallel(
+        &self,
+        servers: Vec<IpAddr>,
+        name: &LowerName,
+        rtype: RecordType,
+    ) -> Option<Result<ResolverLookup, ResolveError>> {
+        let mut queries = JoinSet::new();
+        for server in servers {
+            if let Some(resolver) = self.resolvers.get(&server).cloned() {
+                let name = name.clone();
+                queries.spawn(async move { (server, resolver.lookup(name, rtype).await) });
+            }
+        }
+
+        // Dropping the set aborts the queries still running
+        let mut last = None;
+        while let Some(joined) = queries.join_next().await {
+            let Ok((server, result)) = joined else {
+                continue;
+            };
+            let answered = self.record(server, &result).await;
+            last = Some(result);
+            if answered {
+                break;
+            }
+        }
+        last
+    }
 }
 
 #[async_trait::async_trait]
@@ -218,7 +331,20 @@ impl Authority for ForwardAuthority {
         debug_assert!(self.origin.zone_of(name));
 
         telio_log_debug!("forwarding lookup: {} {}", name, rtype);
-        let resolve = self.resolver.lookup(name.clone(), rtype).await;
+        let (servers, strategy) = {
+            let mut upstreams = self.upstreams.lock().await;
+            (upstreams.candidates(), upstreams.strategy())
+        };
+        let resolve = match strategy {
+            DnsUpstreamStrategy::Parallel if servers.len() > 1 => {
+                self.lookup_parallel(servers, name, rtype).await
+            }
+            _ => self.lookup_sequential(servers, name, rtype).await,
+        };
+        let Some(resolve) = resolve else {
+            telio_log_warn!("No upstream DNS server to forward {} to", name);
+            return Err(LookupError::from(ResponseCode::ServFail));
+        };
 
         // Log DNS failures
         match resolve {
@@ -308,3 +434,42 @@ impl LookupObject for ForwardLookup {
         None
     }
 }
+
+/// Whether the `result` of a query is an answer of the server. Negative answers come from a
+/// working server too, unless it failed to resolve the name or refused to.
+fn is_answer(result: &Result<ResolverLookup, ResolveError>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(e) => matches!(
+            e.kind(),
+            ResolveErrorKind::NoRecordsFound { response_code, .. }
+                if !matches!(response_code, ResponseCode::ServFail | ResponseCode::Refused)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::op::Query;
+
+    fn no_records(response_code: ResponseCode) -> Result<ResolverLookup, ResolveError> {
+        Err(ResolveErrorKind::NoRecordsFound {
+            query: Box::new(Query::new()),
+            soa: None,
+            negative_ttl: None,
+            response_code,
+            trusted: true,
+        }
+        .into())
+    }
+
+    #[test]
+    fn server_failures_are_not_answers() {
+        assert!(is_answer(&no_records(ResponseCode::NXDomain)));
+        assert!(is_answer(&no_records(ResponseCode::NoError)));
+        assert!(!is_answer(&no_records(ResponseCode::ServFail)));
+        assert!(!is_answer(&no_records(ResponseCode::Refused)));
+        assert!(!is_answer(&Err(ResolveErrorKind::Timeout.into())));
+    }
+}
@@ -7,6 +7,7 @@
 mod dns;
 mod nameserver;
 mod resolver;
+mod upstream;
 mod zone;
 
 pub mod bind_tun;
@@ -14,8 +15,9 @@ pub mod bind_tun;
 pub(crate) mod forward;
 
 pub use crate::dns::{DnsResolver, LocalDnsResolver};
-pub use nameserver::{LocalNameServer, NameServer};
+pub use nameserver::{LocalNameServer, NameServer, QueryObserver};
 pub use resolver::Resolver;
+pub use upstream::{FailoverObserver, UpstreamFailover};
 pub use zone::Records;
 
 #[cfg(feature = "mockall")]
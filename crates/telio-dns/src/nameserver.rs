@@ -1,5 +1,6 @@
 use crate::{
     resolver::Resolver,
+    upstream::{UpstreamFailover, Upstreams},
     zone::{AuthoritativeZone, ClonableZones, ForwardZone, Records},
 };
 use async_trait::async_trait;
@@ -28,7 +29,9 @@ use tokio::task::JoinHandle;
 use tokio::time::Duration;
 use tokio::{net::UdpSocket, sync::Mutex};
 
-use telio_utils::{format_hex, telio_log_debug, telio_log_error, telio_log_trace, telio_log_warn};
+use telio_utils::{
+    format_hex, telio_log_debug, telio_log_error, telio_log_trace, telio_log_warn, PacketPool,
+};
 
 const IPV4_HEADER: usize = 20; // bytes
 const IPV6_HEADER: usize = 40; // bytes
@@ -36,6 +39,8 @@ const MAX_PACKET: usize = 2048;
 const UDP_HEADER: usize = 8;
 const TCP_MIN_HEADER: usize = 20;
 const MAX_CONCURRENT_QUERIES: usize = 256;
+/// Number of the packet buffers kept for reuse
+const POOLED_PACKETS: usize = 64;
 const IDLE_TIME: Duration = Duration::from_secs(1);
 
 /// NameServer is a server that stores the DNS records.
@@ -46,10 +51,16 @@ pub trait NameServer {
     /// Server will listen on `socket`, expect connections from `peer` and will
     /// reply to `dst_address`.
     async fn start(&self, peer: Arc<Mutex<Tunn>>, socket: Arc<UdpSocket>);
+    /// Start serving plain (not tunneled) DNS queries received on `socket`.
+    async fn start_stub_listener(&self, socket: Arc<UdpSocket>);
+    /// Notify `observer` about the name of every query served, or stop notifying with `None`.
+    async fn set_query_observer(&self, observer: Option<QueryObserver>);
     /// Stop the server.
     async fn stop(&self);
     /// Configure list of forward DNS servers for zone '.'.
     async fn forward(&self, to: &[IpAddr]) -> Result<(), String>;
+    /// Configure how the forward DNS servers are queried, applies to the current ones too.
+    async fn set_upstream_failover(&self, failover: UpstreamFailover) -> Result<(), String>;
     /// Insert or update zone records used by the server.
     async fn upsert(
         &self,
@@ -61,11 +72,12 @@ pub trait NameServer {
 
 /// Helper to update wg timers
 async fn update_wg_timers(
+    pool: &PacketPool,
     peer: &Arc<Mutex<Tunn>>,
     socket: &Arc<UdpSocket>,
     sender_addr: SocketAddr,
 ) {
-    let mut sending_buffer = vec![0u8; MAX_PACKET];
+    let mut sending_buffer = pool.take();
     loop {
         // Drain update_timers until Done or Error is returned
         let res = peer.lock().await.update_timers(&mut sending_buffer);
@@ -84,11 +96,20 @@ async fn update_wg_timers(
     }
 }
 
+/// Callback notified about the name of every query served
+pub type QueryObserver = Arc<dyn Fn(&str) + Send + Sync>;
+
 /// Local name server.
 #[derive(Default)]
 pub struct LocalNameServer {
     zones: Arc<ClonableZones>,
     task_handle: Option<JoinHandle<()>>,
+    stub_handles: Vec<JoinHandle<()>>,
+    query_observer: Option<QueryObserver>,
+    upstream_failover: UpstreamFailover,
+    /// Health of the forward servers, kept when they are configured anew
+    upstreams: Arc<Mutex<Upstreams>>,
+    forward_ips: Vec<IpAddr>,
 }
 
 impl LocalNameServer {
@@ -98,6 +119,11 @@ impl LocalNameServer {
         let ns = Arc::new(RwLock::new(LocalNameServer {
             zones: Arc::new(ClonableZones::new()),
             task_handle: None,
+            stub_handles: Vec::new(),
+            query_observer: None,
+            upstream_failover: UpstreamFailover::default(),
+            upstreams: Default::default(),
+            forward_ips: Vec::new(),
         }));
         ns.forward(forward_ips).await?;
         Ok(ns)
@@ -108,7 +134,8 @@ impl LocalNameServer {
         nameserver: Arc<RwLock<LocalNameServer>>,
         socket: Arc<UdpSocket>,
     ) {
-        let mut receiving_buffer = vec![0u8; MAX_PACKET];
+        let pool = PacketPool::new("dns", MAX_PACKET, POOLED_PACKETS);
+        let mut receiving_buffer = pool.take();
         let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES));
 
         // Remember where to send timer packets when idle.
@@ -137,17 +164,21 @@ impl LocalNameServer {
 
                     last_sender = Some(sender_addr);
                     idle_timer.reset();
-                    update_wg_timers(&peer, &socket, sender_addr).await;
+                    update_wg_timers(&pool, &peer, &socket, sender_addr).await;
 
                     let peer = peer.clone();
                     let socket = socket.clone();
                     let nameserver = nameserver.clone();
                     let semaphore = semaphore.clone();
-                    let in_bytes = Vec::from(receiving_buffer.get(..bytes_read).unwrap_or_default());
+                    let pool = pool.clone();
+                    // The query is handed over to the task as it is, and the next one is received
+                    // into a fresh buffer.
+                    let mut in_bytes = std::mem::replace(&mut receiving_buffer, pool.take());
+                    in_bytes.truncate(bytes_read);
 
                     tokio::spawn(async move {
-                        let mut sending_buffer = vec![0u8; MAX_PACKET];
-                        let mut task_receiving_buffer = vec![0u8; MAX_PACKET];
+                        let mut sending_buffer = pool.take();
+                        let mut task_receiving_buffer = pool.take();
 
                         let res = peer.lock().await.decapsulate(
                             None,
@@ -161,7 +192,7 @@ impl LocalNameServer {
                                     telio_log_warn!("[DNS] Failed to send handshake packet: {:?}", e);
                                     return;
                                 };
-                                let mut temporary_buffer = vec![0u8; MAX_PACKET];
+                                let mut temporary_buffer = pool.take();
 
                                 while let TunnResult::WriteToNetwork(empty) =
                                 peer.lock()
@@ -232,7 +263,7 @@ impl LocalNameServer {
                 _ = idle_timer.tick() => {
                     if let Some(last_sender) = last_sender {
                         telio_log_trace!("[DNS] Peer was idle, updating WG timers");
-                        update_wg_timers(&peer, &socket, last_sender).await;
+                        update_wg_timers(&pool, &peer, &socket, last_sender).await;
                     }
                 }
                 else => {
@@ -243,15 +274,88 @@ impl LocalNameServer {
         }
     }
 
-    async fn resolve_dns_request(
+    async fn stub_service(nameserver: Arc<RwLock<LocalNameServer>>, socket: Arc<UdpSocket>) {
+        let pool = PacketPool::new("dns-stub", MAX_PACKET, POOLED_PACKETS);
+        let mut receiving_buffer = pool.take();
+        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES));
+
+        loop {
+            let (bytes_read, sender_addr) = match socket.recv_from(&mut receiving_buffer).await {
+                Ok((bytes, sender_addr)) => (bytes, sender_addr),
+                Err(e) => {
+                    telio_log_error!("[DNS] Failed to read bytes on stub listener: {:?}", e);
+                    continue;
+                }
+            };
+
+            let lease = match semaphore.clone().acquire_owned().await {
+                Ok(lease) => lease,
+                Err(_) => {
+                    telio_log_debug!("[DNS] Error semaphore.acquire()");
+                    continue;
+                }
+            };
+
+            let socket = socket.clone();
+            let nameserver = nameserver.clone();
+            let mut in_bytes = std::mem::replace(&mut receiving_buffer, pool.take());
+            in_bytes.truncate(bytes_read);
+
+            tokio::spawn(async move {
+                let _lease = lease;
+                let dns_request = match MessageRequest::from_bytes(&in_bytes) {
+                    Ok(dns_request) => Request::new(dns_request, sender_addr, Protocol::Udp),
+                    Err(_) => {
+                        telio_log_debug!(
+                            "[DNS] Invalid stub request from {:?}: [{:?}]",
+                            sender_addr,
+                            format_hex(&in_bytes)
+                        );
+                        return;
+                    }
+                };
+
+                match LocalNameServer::lookup(nameserver, &dns_request).await {
+                    Ok(response) => {
+                        if let Err(e) = socket.send_to(&response, sender_addr).await {
+                            telio_log_warn!("[DNS] Failed to send stub query response: {:?}", e)
+                        }
+                    }
+                    Err(e) => telio_log_debug!("[DNS] {}", e),
+                }
+            });
+        }
+    }
+
+    async fn lookup(
         nameserver: Arc<RwLock<LocalNameServer>>,
-        request_info: &mut RequestInfo,
+        dns_request: &Request,
     ) -> Result<Vec<u8>, String> {
-        telio_log_debug!("Resolving dns");
         let resolver = Resolver::new();
         telio_log_debug!("Getting DNS zones");
-        let zones = nameserver.zones().await;
+        let (zones, query_observer) = {
+            let nameserver = nameserver.read().await;
+            (nameserver.zones.clone(), nameserver.query_observer.clone())
+        };
+        if let Some(observer) = query_observer {
+            observer(&dns_request.query().name().to_string());
+        }
 
+        zones
+            .lookup(dns_request, resolver.clone())
+            .await
+            .map_err(|e| format!("Lookup failed {e}"))?;
+
+        let dns_response = resolver.0.lock().await;
+        telio_log_debug!("Nameserver response: {:?}", &dns_response);
+        Ok(dns_response.to_vec())
+    }
+
+    async fn resolve_dns_request(
+        nameserver: Arc<RwLock<LocalNameServer>>,
+        request_info: &mut RequestInfo,
+    ) -> Result<Vec<u8>, String> {
+        telio_log_debug!("Resolving dns");
         telio_log_debug!("Preparing DNS request");
         let dns_request = match &mut request_info.payload {
             PayloadRequestInfo::Udp {
@@ -265,14 +369,7 @@ impl LocalNameServer {
         let dns_request = Request::new(dns_request, request_info.dns_source(), Protocol::Udp);
         telio_log_debug!("DNS request: {:?}", &dns_request);
 
-        zones
-            .lookup(&dns_request, resolver.clone())
-            .await
-            .map_err(|e| format!("Lookup failed {e}"))?;
-
-        let dns_response = resolver.0.lock().await;
-        telio_log_debug!("Nameserver response: {:?}", &dns_response);
-        Ok(dns_response.to_vec())
+        LocalNameServer::lookup(nameserver, &dns_request).await
     }
 
     async fn process_packet(
@@ -702,16 +799,36 @@ impl NameServer for Arc<RwLock<LocalNameServer>> {
     }
 
     async fn forward(&self, to: &[IpAddr]) -> Result<(), String> {
+        let (failover, upstreams) = {
+            let this = self.read().await;
+            (this.upstream_failover.clone(), this.upstreams.clone())
+        };
         self.zones_mut().await.upsert(
             LowerName::from_str(".")?,
-            Box::new(Arc::new(ForwardZone::new(".", to).await?)),
+            Box::new(Arc::new(
+                ForwardZone::new(".", to, failover, upstreams).await?,
+            )),
         );
+        self.write().await.forward_ips = to.to_vec();
         Ok(())
     }
 
+    async fn set_upstream_failover(&self, failover: UpstreamFailover) -> Result<(), String> {
+        let forward_ips = {
+            let mut this = self.write().await;
+            this.upstream_failover = failover;
+            this.forward_ips.clone()
+        };
+        self.forward(&forward_ips).await
+    }
+
     // TODO: maybe report or recover in case of thread panic
     async fn stop(&self) {
-        if let Some(handle) = &self.read().await.task_handle {
+        let mut this = self.write().await;
+        if let Some(handle) = &this.task_handle {
+            handle.abort();
+        }
+        for handle in this.stub_handles.drain(..) {
             handle.abort();
         }
     }
@@ -724,6 +841,20 @@ impl NameServer for Arc<RwLock<LocalNameServer>> {
         )));
         telio_log_trace!("start_sucessfull");
     }
+
+    async fn set_query_observer(&self, observer: Option<QueryObserver>) {
+        self.write().await.query_observer = observer;
+    }
+
+    async fn start_stub_listener(&self, socket: Arc<UdpSocket>) {
+        let nameserver = self.clone();
+        self.write()
+            .await
+            .stub_handles
+            .push(tokio::spawn(LocalNameServer::stub_service(
+                nameserver, socket,
+            )));
+    }
 }
 
 #[cfg(test)]
@@ -791,6 +922,47 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn stub_listener_answers_plain_queries() {
+        let entry_name = String::from("pashka.nord.");
+        let mut records = Records::new();
+        records.insert(
+            entry_name.clone(),
+            vec![IpAddr::V4(Ipv4Addr::new(100, 69, 69, 69))],
+        );
+        let nameserver = LocalNameServer::new(&[IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))])
+            .await
+            .unwrap();
+        nameserver
+            .upsert("nord", &records, TtlValue(60))
+            .await
+            .unwrap();
+
+        let stub = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+        let stub_addr = stub.local_addr().unwrap();
+        nameserver.start_stub_listener(stub).await;
+
+        let mut question = Message::new();
+        let mut query = Query::new();
+        query.set_name(Name::from_str(&entry_name).unwrap());
+        question.add_query(query);
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client
+            .send_to(&question.to_bytes().unwrap(), stub_addr)
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; MAX_PACKET];
+        let len = client.recv(&mut buf).await.unwrap();
+        let mut decoder = BinDecoder::new(&buf[..len]);
+        let answers = Message::read(&mut decoder).unwrap().take_answers();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].name().to_string(), entry_name);
+
+        nameserver.stop().await;
+        assert!(nameserver.read().await.stub_handles.is_empty());
+    }
+
     #[tokio::test]
     async fn zones_are_lazily_copied_on_write_access() {
         let name1 = "test.nord.".to_owned();
@@ -0,0 +1,233 @@
+//! Selection of the upstream servers the queries are forwarded to.
+//!
+//! Every server failing to answer [FeatureDnsUpstreams::max_failures] times in a row is
+//! quarantined for [FeatureDnsUpstreams::quarantine_s]. Quarantined servers are still tried, but
+//! only after all of the healthy ones, so that queries are answered even when all of them fail.
+//! The health is kept across the changes of the servers, for the servers still in use.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use telio_model::features::{DnsUpstreamStrategy, FeatureDnsUpstreams};
+use telio_utils::{telio_log_info, Instant};
+
+/// Callback notified when queries move from a quarantined upstream server to another one
+pub type FailoverObserver = Arc<dyn Fn(IpAddr, IpAddr) + Send + Sync>;
+
+/// Upstream servers configuration, together with the observer of the failovers
+#[derive(Clone, Default)]
+pub struct UpstreamFailover {
+    /// How the servers are queried and quarantined
+    pub config: FeatureDnsUpstreams,
+    /// Notified when a server is quarantined
+    pub observer: Option<FailoverObserver>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Failures since the last answer
+    failures: u32,
+    quarantined_until: Option<Instant>,
+    /// Current weight of the smooth weighted round robin
+    current_weight: i64,
+}
+
+/// Upstream servers with their health
+#[derive(Default)]
+pub(crate) struct Upstreams {
+    config: FeatureDnsUpstreams,
+    servers: Vec<IpAddr>,
+    health: HashMap<IpAddr, Health>,
+}
+
+impl Upstreams {
+    /// Use the `servers` with the `config` from now on, keeping the health of the servers which
+    /// were used before
+    pub(crate) fn update(&mut self, servers: &[IpAddr], config: FeatureDnsUpstreams) {
+        self.health.retain(|server, _| servers.contains(server));
+        self.servers = servers.to_vec();
+        self.config = config;
+    }
+
+    pub(crate) fn strategy(&self) -> DnsUpstreamStrategy {
+        self.config.strategy
+    }
+
+    fn weight(&self, server: IpAddr) -> i64 {
+        self.config
+            .weights
+            .iter()
+            .find(|weight| weight.address == server)
+            .map_or(1, |weight| weight.weight.into())
+    }
+
+    fn is_quarantined(&self, server: IpAddr, now: Instant) -> bool {
+        self.health
+            .get(&server)
+            .and_then(|health| health.quarantined_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// Servers to send the next query to, in order. With the parallel strategy all of the healthy
+    /// servers are queried at once.
+    pub(crate) fn candidates(&mut self) -> Vec<IpAddr> {
+        let now = Instant::now();
+        let (mut healthy, quarantined): (Vec<IpAddr>, Vec<IpAddr>) = self
+            .servers
+            .iter()
+            .partition(|server| !self.is_quarantined(**server, now));
+
+        match self.config.strategy {
+            DnsUpstreamStrategy::Parallel if !healthy.is_empty() => return healthy,
+            DnsUpstreamStrategy::Weighted => {
+                if let Some(first) = self.pick_weighted(&healthy) {
+                    healthy.retain(|server| *server != first);
+                    healthy.sort_by_key(|server| std::cmp::Reverse(self.weight(*server)));
+                    healthy.insert(0, first);
+                }
+            }
+            _ => (),
+        }
+        healthy.extend(quarantined);
+        healthy
+    }
+
+    /// Smooth weighted round robin, spreads the picks evenly in proportion to the weights
+    fn pick_weighted(&mut self, servers: &[IpAddr]) -> Option<IpAddr> {
+        let total: i64 = servers.iter().map(|server| self.weight(*server)).sum();
+        for server in servers {
+            let weight = self.weight(*server);
+            self.health.entry(*server).or_default().current_weight += weight;
+        }
+        let picked = servers.iter().copied().max_by_key(|server| {
+            (
+                self.health
+                    .get(server)
+                    .map_or(0, |health| health.current_weight),
+                // The first server wins ties
+                std::cmp::Reverse(self.servers.iter().position(|s| s == server)),
+            )
+        })?;
+        self.health.entry(picked).or_default().current_weight -= total;
+        Some(picked)
+    }
+
+    /// Record an answer of the `server`
+    pub(crate) fn succeeded(&mut self, server: IpAddr) {
+        if let Some(health) = self.health.get_mut(&server) {
+            if health.quarantined_until.take().is_some() {
+                telio_log_info!("DNS upstream {server} answers again");
+            }
+            health.failures = 0;
+        }
+    }
+
+    /// Record a failure of the `server`. Returns the server taking over if the failure got the
+    /// `server` quarantined.
+    pub(crate) fn failed(&mut self, server: IpAddr) -> Option<IpAddr> {
+        let now = Instant::now();
+        let max_failures = self.config.max_failures;
+        let quarantine = Duration::from_secs(self.config.quarantine_s.into());
+
+        let health = self.health.entry(server).or_default();
+        health.failures = health.failures.saturating_add(1);
+        if health.failures < max_failures || health.quarantined_until.is_some_and(|u| u > now) {
+            return None;
+        }
+        // Failing again after the quarantine is over is not reported for the second time
+        let reported = health.quarantined_until.is_none();
+        health.quarantined_until = Some(now + quarantine);
+        telio_log_info!("DNS upstream {server} is quarantined for {quarantine:?}");
+
+        let takeover = self
+            .servers
+            .iter()
+            .copied()
+            .find(|other| *other != server && !self.is_quarantined(*other, now));
+        takeover.filter(|_| reported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_model::features::FeatureDnsUpstreamWeight;
+
+    const A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(1, 1, 1, 1));
+    const B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(8, 8, 8, 8));
+
+    fn upstreams(strategy: DnsUpstreamStrategy) -> Upstreams {
+        let mut upstreams = Upstreams::default();
+        upstreams.update(
+            &[A, B],
+            FeatureDnsUpstreams {
+                strategy,
+                max_failures: 2,
+                quarantine_s: 60,
+                weights: vec![FeatureDnsUpstreamWeight {
+                    address: B,
+                    weight: 2,
+                }],
+                ..Default::default()
+            },
+        );
+        upstreams
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_server_is_quarantined() {
+        let mut upstreams = upstreams(DnsUpstreamStrategy::Sequential);
+        assert_eq!(upstreams.candidates(), vec![A, B]);
+
+        assert_eq!(upstreams.failed(A), None);
+        assert_eq!(upstreams.failed(A), Some(B));
+        assert_eq!(upstreams.candidates(), vec![B, A]);
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(upstreams.candidates(), vec![A, B]);
+        // Still failing after the quarantine
+        assert_eq!(upstreams.failed(A), None);
+        assert_eq!(upstreams.candidates(), vec![B, A]);
+
+        upstreams.succeeded(A);
+        assert_eq!(upstreams.candidates(), vec![A, B]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_is_kept_across_updates() {
+        let mut upstreams = upstreams(DnsUpstreamStrategy::Sequential);
+        upstreams.failed(A);
+        upstreams.failed(A);
+
+        let config = upstreams.config.clone();
+        upstreams.update(&[A, B], config.clone());
+        assert_eq!(upstreams.candidates(), vec![B, A]);
+
+        // Forgotten once not used anymore
+        upstreams.update(&[B], config.clone());
+        upstreams.update(&[A, B], config);
+        assert_eq!(upstreams.candidates(), vec![A, B]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn parallel_strategy_queries_healthy_servers() {
+        let mut upstreams = upstreams(DnsUpstreamStrategy::Parallel);
+        assert_eq!(upstreams.candidates(), vec![A, B]);
+        upstreams.failed(B);
+        upstreams.failed(B);
+        assert_eq!(upstreams.candidates(), vec![A]);
+        upstreams.failed(A);
+        upstreams.failed(A);
+        assert_eq!(upstreams.candidates(), vec![A, B]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn weighted_strategy_follows_weights() {
+        let mut upstreams = upstreams(DnsUpstreamStrategy::Weighted);
+        let firsts: Vec<IpAddr> = (0..6).map(|_| upstreams.candidates()[0]).collect();
+        assert_eq!(firsts, vec![B, A, B, B, A, B]);
+        assert_eq!(upstreams.candidates().len(), 2);
+    }
+}
@@ -14,11 +14,14 @@ use std::{
     convert::TryInto,
     net::IpAddr,
     str::FromStr,
+    sync::Arc,
 };
 use telio_model::features::TtlValue;
 use telio_utils::telio_log_warn;
+use tokio::sync::Mutex;
 
 use crate::forward::ForwardAuthority;
+use crate::upstream::{UpstreamFailover, Upstreams};
 
 /// Zone is a portion of the DNS namespace that is managed by a specific
 /// organization or administrator.
@@ -174,7 +177,12 @@ pub(crate) struct ForwardZone {
 }
 
 impl ForwardZone {
-    pub(crate) async fn new(name: &str, ips: &[IpAddr]) -> Result<Self, String> {
+    pub(crate) async fn new(
+        name: &str,
+        ips: &[IpAddr],
+        failover: UpstreamFailover,
+        upstreams: Arc<Mutex<Upstreams>>,
+    ) -> Result<Self, String> {
         let mut options = ResolverOpts::default();
         // Some tools and browsers do not accept responses without intermediates preserved
         options.preserve_intermediates = true;
@@ -193,6 +201,8 @@ impl ForwardZone {
                 options: Some(options),
                 name_servers: NameServerConfigGroup::from_ips_clear(ips, 53, true),
             },
+            failover,
+            upstreams,
         )
         .await?;
         Ok(ForwardZone { zone })
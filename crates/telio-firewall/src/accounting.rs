@@ -0,0 +1,97 @@
+//! Packet counters of the peers.
+//!
+//! WireGuard only counts the bytes exchanged with the peers, so the packets are counted as they
+//! pass through the firewall. A packet received from a peer counts whatever the verdict, as it was
+//! transferred anyway, while a packet to a peer only counts when it is let through.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::RwLock;
+use telio_crypto::PublicKey;
+
+use crate::firewall::{HashMap, PacketDirection};
+
+/// Packets exchanged with a peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketCount {
+    /// Packets received from the peer
+    pub received: u64,
+    /// Packets sent to the peer
+    pub sent: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Counters {
+    fn of(&self, direction: PacketDirection) -> &AtomicU64 {
+        match direction {
+            PacketDirection::Inbound => &self.received,
+            PacketDirection::Outbound => &self.sent,
+        }
+    }
+}
+
+/// Packet counters of the peers, taken by the traffic accounting
+#[derive(Default)]
+pub(crate) struct PeerPacketCounters {
+    peers: RwLock<HashMap<PublicKey, Counters>>,
+}
+
+impl PeerPacketCounters {
+    pub(crate) fn count(&self, peer: &PublicKey, direction: PacketDirection) {
+        if let Some(counters) = self.peers.read().get(peer) {
+            counters.of(direction).fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.peers
+            .write()
+            .entry(*peer)
+            .or_default()
+            .of(direction)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Packets counted since the previous call
+    pub(crate) fn take(&self) -> HashMap<PublicKey, PacketCount> {
+        std::mem::take(&mut *self.peers.write())
+            .into_iter()
+            .map(|(peer, counters)| {
+                (
+                    peer,
+                    PacketCount {
+                        received: counters.received.into_inner(),
+                        sent: counters.sent.into_inner(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_are_counted_until_taken() {
+        let counters = PeerPacketCounters::default();
+        let peer = PublicKey([1; 32]);
+
+        counters.count(&peer, PacketDirection::Inbound);
+        counters.count(&peer, PacketDirection::Inbound);
+        counters.count(&peer, PacketDirection::Outbound);
+
+        assert_eq!(
+            counters.take().get(&peer),
+            Some(&PacketCount {
+                received: 2,
+                sent: 1,
+            })
+        );
+        assert!(counters.take().is_empty());
+    }
+}
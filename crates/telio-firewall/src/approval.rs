@@ -0,0 +1,227 @@
+//! Interactive approval of the inbound connections.
+//!
+//! When [FeatureInboundApproval] is enabled, the first inbound connection of a peer without the
+//! permission to make one is reported to the [ApprovalObserver], while the connections of the
+//! peer keep being dropped until the app decides. Allowed peers are accepted in addition to the
+//! ones whitelisted in the firewall state, so the decisions survive the state updates. Denied
+//! peers are not asked about again.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use parking_lot::{Mutex, RwLock};
+use telio_crypto::PublicKey;
+use telio_model::features::FeatureInboundApproval;
+use telio_utils::{telio_log_debug, telio_log_info, Instant};
+
+use crate::firewall::HashMap;
+
+/// Callback notified when a peer without the permission connects to the local address
+pub type ApprovalObserver = Arc<dyn Fn(PublicKey, SocketAddr) + Send + Sync>;
+
+/// Decision of the app about the inbound connections of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InboundDecision {
+    /// Accept the connections of the peer for [FeatureInboundApproval::allow_once_s]
+    AllowOnce,
+    /// Accept the connections of the peer for the lifetime of the firewall
+    AllowAlways,
+    /// Drop the connections of the peer without asking again
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Approval {
+    /// App was asked at the given time and did not answer yet
+    Pending(Instant),
+    /// Connections are accepted until the given time
+    AllowedUntil(Instant),
+    Allowed,
+    Denied,
+}
+
+impl Approval {
+    fn is_allowed(&self) -> bool {
+        matches!(self, Approval::Allowed | Approval::AllowedUntil(_))
+    }
+}
+
+#[derive(Default)]
+struct Approvals {
+    peers: HashMap<PublicKey, Approval>,
+    /// Earliest end of the allow once decisions
+    next_expiry: Option<Instant>,
+}
+
+/// Approvals of the inbound connections, asked for and decided per peer
+pub(crate) struct InboundApprovals {
+    config: FeatureInboundApproval,
+    observer: RwLock<Option<ApprovalObserver>>,
+    approvals: Mutex<Approvals>,
+}
+
+impl InboundApprovals {
+    pub(crate) fn new(config: FeatureInboundApproval) -> Self {
+        Self {
+            config,
+            observer: RwLock::new(None),
+            approvals: Mutex::new(Approvals::default()),
+        }
+    }
+
+    pub(crate) fn set_observer(&self, observer: Option<ApprovalObserver>) {
+        *self.observer.write() = observer;
+    }
+
+    /// Report a dropped inbound connection of the `peer` to the local `destination`. Returns
+    /// whether the app was asked for the approval.
+    pub(crate) fn dropped(&self, peer: PublicKey, destination: SocketAddr, now: Instant) -> bool {
+        let prompt_timeout = Duration::from_secs(self.config.prompt_timeout_s.into());
+        {
+            let mut approvals = self.approvals.lock();
+            match approvals.peers.get(&peer) {
+                Some(Approval::Pending(asked))
+                    if now.saturating_duration_since(*asked) < prompt_timeout =>
+                {
+                    return false
+                }
+                Some(Approval::Pending(_)) | None => (),
+                Some(_) => return false,
+            }
+            approvals.peers.insert(peer, Approval::Pending(now));
+        }
+
+        // Called without the lock, the observer may decide right away
+        let Some(observer) = self.observer.read().clone() else {
+            telio_log_debug!("Inbound connection of {peer:?} awaits approval, but nobody asks");
+            return false;
+        };
+        telio_log_info!("Asking for approval of {peer:?} connecting to {destination}");
+        observer(peer, destination);
+        true
+    }
+
+    /// Record the `decision` about the `peer`. Returns whether the accepted peers changed.
+    pub(crate) fn decide(&self, peer: PublicKey, decision: InboundDecision, now: Instant) -> bool {
+        let approval = match decision {
+            InboundDecision::AllowOnce => {
+                Approval::AllowedUntil(now + Duration::from_secs(self.config.allow_once_s.into()))
+            }
+            InboundDecision::AllowAlways => Approval::Allowed,
+            InboundDecision::Deny => Approval::Denied,
+        };
+        telio_log_info!("Inbound connections of {peer:?}: {decision:?}");
+
+        let mut approvals = self.approvals.lock();
+        let was_allowed = approvals
+            .peers
+            .insert(peer, approval)
+            .is_some_and(|previous| previous.is_allowed());
+        if let Approval::AllowedUntil(until) = approval {
+            approvals.next_expiry =
+                Some(approvals.next_expiry.map_or(until, |next| next.min(until)));
+        }
+        was_allowed || approval.is_allowed()
+    }
+
+    /// Forget the allow once decisions which ended. Returns whether any of them did.
+    pub(crate) fn expire(&self, now: Instant) -> bool {
+        let mut approvals = self.approvals.lock();
+        if !approvals.next_expiry.is_some_and(|next| next <= now) {
+            return false;
+        }
+        approvals.peers.retain(
+            |_, approval| !matches!(approval, Approval::AllowedUntil(until) if *until <= now),
+        );
+        approvals.next_expiry = approvals
+            .peers
+            .values()
+            .filter_map(|approval| match approval {
+                Approval::AllowedUntil(until) => Some(*until),
+                _ => None,
+            })
+            .min();
+        true
+    }
+
+    /// Peers whose inbound connections are accepted
+    pub(crate) fn allowed_peers(&self) -> Vec<PublicKey> {
+        self.approvals
+            .lock()
+            .peers
+            .iter()
+            .filter(|(_, approval)| approval.is_allowed())
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PEER: PublicKey = PublicKey([1; 32]);
+
+    fn approvals() -> (InboundApprovals, Arc<AtomicUsize>) {
+        let approvals = InboundApprovals::new(FeatureInboundApproval {
+            prompt_timeout_s: 60,
+            allow_once_s: 600,
+        });
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        approvals.set_observer(Some(Arc::new(move |peer, destination| {
+            assert_eq!(peer, PEER);
+            assert_eq!(destination.port(), 22);
+            counter.fetch_add(1, Ordering::Relaxed);
+        })));
+        (approvals, asked)
+    }
+
+    fn ssh() -> SocketAddr {
+        SocketAddr::from(([100, 64, 0, 1], 22))
+    }
+
+    #[test]
+    fn peer_is_asked_once_until_prompt_times_out() {
+        let (approvals, asked) = approvals();
+        let now = Instant::now();
+
+        assert!(approvals.dropped(PEER, ssh(), now));
+        assert!(!approvals.dropped(PEER, ssh(), now + Duration::from_secs(59)));
+        assert!(approvals.dropped(PEER, ssh(), now + Duration::from_secs(60)));
+        assert_eq!(asked.load(Ordering::Relaxed), 2);
+        assert!(approvals.allowed_peers().is_empty());
+    }
+
+    #[test]
+    fn allow_once_expires() {
+        let (approvals, _) = approvals();
+        let now = Instant::now();
+
+        assert!(approvals.decide(PEER, InboundDecision::AllowOnce, now));
+        assert_eq!(approvals.allowed_peers(), vec![PEER]);
+        assert!(!approvals.dropped(PEER, ssh(), now));
+
+        assert!(!approvals.expire(now + Duration::from_secs(599)));
+        assert!(approvals.expire(now + Duration::from_secs(600)));
+        assert!(approvals.allowed_peers().is_empty());
+        assert!(approvals.dropped(PEER, ssh(), now + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn allow_always_and_deny_are_kept() {
+        let (approvals, asked) = approvals();
+        let now = Instant::now();
+
+        assert!(approvals.decide(PEER, InboundDecision::AllowAlways, now));
+        assert!(!approvals.expire(now + Duration::from_secs(3600)));
+        assert_eq!(approvals.allowed_peers(), vec![PEER]);
+
+        assert!(approvals.decide(PEER, InboundDecision::Deny, now));
+        assert!(approvals.allowed_peers().is_empty());
+        assert!(!approvals.dropped(PEER, ssh(), now + Duration::from_secs(3600)));
+        assert_eq!(asked.load(Ordering::Relaxed), 0);
+
+        assert!(!approvals.decide(PEER, InboundDecision::Deny, now));
+    }
+}
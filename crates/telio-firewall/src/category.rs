@@ -0,0 +1,285 @@
+//! Local classification of observed domains.
+//!
+//! Upstream only provides categories for a fixed set of domains. The embedding app can register
+//! a [CategoryProvider], for example backed by an offline category database, which is then
+//! consulted for every domain seen in DNS queries. Answers are cached, so that the provider is
+//! asked at most once per domain within [CATEGORY_CACHE_TTL].
+//!
+//! The provider is asked on a thread of its own, off the DNS resolution: a slow provider, or one
+//! calling back into libtelio, holds up only the classification, never the queries. Domains
+//! observed while the thread is busy are queued up to [CATEGORY_QUEUE_CAPACITY], and dropped
+//! beyond it.
+
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use telio_utils::{telio_log_debug, telio_log_warn, LruCache};
+
+/// How long a domain classification is remembered
+pub const CATEGORY_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Maximal number of remembered domain classifications
+pub const CATEGORY_CACHE_CAPACITY: usize = 4096;
+
+/// Maximal number of domains waiting for the provider
+pub const CATEGORY_QUEUE_CAPACITY: usize = 256;
+
+/// Source of categories for domains, implemented by the embedding app
+pub trait CategoryProvider: Send + Sync + Debug {
+    /// Categories of the `domain`, empty if the domain is unknown to the provider
+    fn categorize(&self, domain: &str) -> Vec<String>;
+}
+
+/// Classifications shared with the thread asking the provider
+#[derive(Debug)]
+struct Classified {
+    cache: Mutex<LruCache<String, Vec<String>>>,
+    /// Domains queued for the provider
+    pending: Mutex<HashSet<String>>,
+    /// Bumped with every provider change, answers of the previous ones are discarded
+    generation: AtomicU64,
+}
+
+/// Caching front of the registered [CategoryProvider]
+#[derive(Debug)]
+pub struct DomainClassifier {
+    classified: Arc<Classified>,
+    /// Queue of the thread asking the current provider, none without a provider
+    queue: Mutex<Option<SyncSender<String>>>,
+}
+
+impl Default for DomainClassifier {
+    fn default() -> Self {
+        Self::new(CATEGORY_CACHE_TTL, CATEGORY_CACHE_CAPACITY)
+    }
+}
+
+impl DomainClassifier {
+    /// Create a classifier without a provider
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            classified: Arc::new(Classified {
+                cache: Mutex::new(LruCache::new(ttl, capacity)),
+                pending: Mutex::new(HashSet::new()),
+                generation: AtomicU64::new(0),
+            }),
+            queue: Mutex::new(None),
+        }
+    }
+
+    /// Register the provider, or remove it with `None`.
+    ///
+    /// Classifications made by the previous provider are forgotten.
+    pub fn set_provider(&self, provider: Option<Arc<dyn CategoryProvider>>) {
+        let mut queue = self.queue.lock();
+        // Dropping the queue stops the thread of the previous provider
+        *queue = None;
+        let generation = {
+            let mut cache = self.classified.cache.lock();
+            cache.clear();
+            self.classified.generation.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        self.classified.pending.lock().clear();
+
+        let Some(provider) = provider else {
+            return;
+        };
+        let (tx, rx) = sync_channel(CATEGORY_QUEUE_CAPACITY);
+        let classified = self.classified.clone();
+        match std::thread::Builder::new()
+            .name("telio-categories".to_owned())
+            .spawn(move || categorize(provider, rx, &classified, generation))
+        {
+            Ok(_) => *queue = Some(tx),
+            Err(e) => telio_log_warn!("Failed to start the domain classification: {e}"),
+        }
+    }
+
+    /// Queue the `domain` for classification by the provider, unless it is remembered already.
+    /// Returns right away, the classification shows up in [Self::classified_domains] once done.
+    pub fn observe(&self, domain: &str) {
+        let domain = normalize(domain);
+        if self.classified.cache.lock().get(&domain).is_some() {
+            return;
+        }
+        let queue = self.queue.lock();
+        let Some(queue) = queue.as_ref() else {
+            return;
+        };
+        if !self.classified.pending.lock().insert(domain.clone()) {
+            return;
+        }
+        if let Err(TrySendError::Full(domain) | TrySendError::Disconnected(domain)) =
+            queue.try_send(domain)
+        {
+            telio_log_debug!("Domain {domain} left unclassified, the provider is busy");
+            self.classified.pending.lock().remove(&domain);
+        }
+    }
+
+    /// All classified domains which are still remembered, with their categories
+    pub fn classified_domains(&self) -> Vec<(String, Vec<String>)> {
+        let mut cache = self.classified.cache.lock();
+        // Dropping the expired entries first
+        cache.len();
+        cache
+            .iter()
+            .map(|(domain, categories)| (domain.clone(), categories.clone()))
+            .collect()
+    }
+}
+
+/// Ask the `provider` about the queued domains until the queue is dropped
+fn categorize(
+    provider: Arc<dyn CategoryProvider>,
+    queue: Receiver<String>,
+    classified: &Classified,
+    generation: u64,
+) {
+    while let Ok(domain) = queue.recv() {
+        let categories = provider.categorize(&domain);
+        // Checked under the lock of the cache, which is cleared with the provider change
+        let mut cache = classified.cache.lock();
+        if classified.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        telio_log_debug!("Domain {domain} classified as {categories:?}");
+        cache.insert(domain.clone(), categories);
+        drop(cache);
+        classified.pending.lock().remove(&domain);
+    }
+}
+
+/// Domains in DNS queries are case insensitive and may be fully qualified
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Counting {
+        calls: AtomicUsize,
+    }
+
+    impl CategoryProvider for Counting {
+        fn categorize(&self, domain: &str) -> Vec<String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if domain.ends_with("example.com") {
+                vec!["test".to_owned()]
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    /// Wait for the provider to have been asked `calls` times and the answers to be cached
+    fn wait_for_calls(classifier: &DomainClassifier, provider: &Counting, calls: usize) {
+        for _ in 0..500 {
+            if provider.calls.load(Ordering::SeqCst) >= calls
+                && classifier.classified.pending.lock().is_empty()
+            {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        panic!("provider was not asked {calls} times");
+    }
+
+    #[test]
+    fn without_provider_nothing_is_classified() {
+        let classifier = DomainClassifier::default();
+        classifier.observe("example.com");
+        assert!(classifier.classified_domains().is_empty());
+    }
+
+    #[test]
+    fn provider_is_asked_once_per_domain() {
+        let provider = Arc::new(Counting::default());
+        let classifier = DomainClassifier::default();
+        classifier.set_provider(Some(provider.clone()));
+
+        classifier.observe("www.example.com.");
+        classifier.observe("nordvpn.com");
+        wait_for_calls(&classifier, &provider, 2);
+        classifier.observe("WWW.Example.com");
+        classifier.observe("nordvpn.com");
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 2);
+
+        let mut domains = classifier.classified_domains();
+        domains.sort();
+        assert_eq!(
+            domains,
+            vec![
+                ("nordvpn.com".to_owned(), vec![]),
+                ("www.example.com".to_owned(), vec!["test".to_owned()])
+            ]
+        );
+    }
+
+    #[derive(Debug)]
+    struct Blocked(Mutex<()>);
+
+    impl CategoryProvider for Blocked {
+        fn categorize(&self, _domain: &str) -> Vec<String> {
+            drop(self.0.lock());
+            vec!["late".to_owned()]
+        }
+    }
+
+    #[test]
+    fn slow_provider_does_not_hold_up_the_queries() {
+        let provider = Arc::new(Blocked(Mutex::new(())));
+        let classifier = DomainClassifier::default();
+        classifier.set_provider(Some(provider.clone()));
+
+        let blocked = provider.0.lock();
+        for i in 0..CATEGORY_QUEUE_CAPACITY * 2 {
+            classifier.observe(&format!("{i}.example.com"));
+        }
+        assert!(classifier.classified_domains().is_empty());
+        drop(blocked);
+    }
+
+    #[test]
+    fn classification_expires() {
+        let provider = Arc::new(Counting::default());
+        let classifier = DomainClassifier::new(Duration::from_secs(10), 16);
+        classifier.set_provider(Some(provider.clone()));
+
+        classifier.observe("example.com");
+        wait_for_calls(&classifier, &provider, 1);
+        sn_fake_clock::FakeClock::advance_time(11_000);
+        assert!(classifier.classified_domains().is_empty());
+        classifier.observe("example.com");
+        wait_for_calls(&classifier, &provider, 2);
+    }
+
+    #[test]
+    fn replacing_provider_clears_cache() {
+        let provider = Arc::new(Counting::default());
+        let classifier = DomainClassifier::default();
+        classifier.set_provider(Some(provider.clone()));
+        classifier.observe("example.com");
+        wait_for_calls(&classifier, &provider, 1);
+
+        classifier.set_provider(None);
+        assert!(classifier.classified_domains().is_empty());
+        classifier.observe("example.com");
+        assert!(classifier.classified_domains().is_empty());
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 1);
+    }
+}
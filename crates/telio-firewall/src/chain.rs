@@ -474,12 +474,28 @@ impl Chain {
         assoc_data: Option<&[u8]>,
         direction: Direction,
     ) -> LibfwVerdict {
-        self.rules
-            .iter()
-            .find(|rule| rule.is_matching(conn_state, packet, assoc_data, direction))
+        self.matching_rule(conn_state, packet, assoc_data, direction)
+            .and_then(|index| self.rules.get(index))
             .map(|rule| rule.action)
             .unwrap_or(LibfwVerdict::LibfwVerdictDrop)
     }
+
+    ///
+    /// Finds the rule deciding the packet, with the same parameters as `process_packet`.
+    ///
+    /// @return             Index of the first matching rule, None when there is no rule matching
+    ///
+    pub(crate) fn matching_rule<'a>(
+        &self,
+        conn_state: ConnectionState,
+        packet: &impl IpPacket<'a>,
+        assoc_data: Option<&[u8]>,
+        direction: Direction,
+    ) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|rule| rule.is_matching(conn_state, packet, assoc_data, direction))
+    }
 }
 
 impl TryFrom<&LibfwChain> for Chain {
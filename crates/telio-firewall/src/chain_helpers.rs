@@ -228,7 +228,7 @@ impl From<&Filter> for (LibfwFilter, Option<PinnedAssocData>) {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct Rule {
     pub(crate) filters: Vec<Filter>,
     pub(crate) action: LibfwVerdict,
@@ -1,7 +1,7 @@
 use std::{
     convert::TryInto,
     fmt::{Debug, Formatter},
-    net::IpAddr as StdIpAddr,
+    net::{IpAddr as StdIpAddr, SocketAddr as StdSocketAddr},
     time::Duration,
 };
 
@@ -27,7 +27,9 @@ use pnet_packet::{
     udp::UdpPacket,
     Packet,
 };
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use smallvec::{SmallVec, ToSmallVec};
+use telio_crypto::PublicKey;
 use telio_utils::{Entry, LruCache};
 
 macro_rules! unwrap_option_or_return {
@@ -51,7 +53,7 @@ pub(crate) const LRU_CACHE_TCP_TTL: Duration = Duration::from_secs(7440); // 2 h
 pub(crate) const LRU_CACHE_UDP_TTL: Duration = Duration::from_secs(120); // 2 minutes as per REQ-5 of RFC4787 section-12
 pub(crate) const LRU_CACHE_ICMP_TTL: Duration = Duration::from_secs(60); // 1 minute as per REQ-2 of RFC5508 section-9
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub(crate) struct IpConnWithPort {
     pub(crate) remote_addr: IpAddr,
     pub(crate) remote_port: u16,
@@ -72,16 +74,35 @@ impl Debug for IpConnWithPort {
 
 pub(crate) type AssociatedData = Option<SmallVec<[u8; KEY_SIZE]>>;
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+/// Serde support for [AssociatedData], which is serialized as plain bytes
+mod associated_data {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(
+        data: &AssociatedData,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        data.as_deref().serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<AssociatedData, D::Error> {
+        Ok(Option::<Vec<u8>>::deserialize(deserializer)?.map(SmallVec::from_vec))
+    }
+}
+
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub(crate) struct Connection {
     pub(crate) link: IpConnWithPort,
     // Data associated with the packets, usually the public keys refering
     // to source peer for inbound connections and destination peer for outbound
     // connections, which may be used for tracking and filtering packets
+    #[serde(with = "associated_data")]
     pub(crate) associated_data: AssociatedData,
 }
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub(crate) struct IcmpConn {
     src_addr: IpAddr,
     dst_addr: IpAddr,
@@ -90,6 +111,7 @@ pub(crate) struct IcmpConn {
     // Data associated with the packets, usually the public keys refering
     // to source peer for inbound connections and destination peer for outbound
     // connections, which may be used for tracking and filtering packets
+    #[serde(with = "associated_data")]
     associated_data: AssociatedData,
 }
 
@@ -140,7 +162,7 @@ pub(crate) enum Direction {
 ///
 #[allow(clippy::enum_variant_names)]
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum ConnectionState {
     /// Connection established
     Established = LIBFW_CONTRACK_STATE_ESTABLISHED,
@@ -155,7 +177,7 @@ pub enum ConnectionState {
     Closed = LIBFW_CONTRACK_STATE_CLOSED,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UdpConnectionInfo {
     pub(crate) is_remote_initiated: bool,
     pub(crate) last_out_pkg_chunk: Option<Vec<u8>>,
@@ -172,7 +194,7 @@ impl UdpConnectionInfo {
     pub(crate) const LAST_PKG_MAX_CHUNK_LEN: usize = 60 + 8;
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct TcpConnectionInfo {
     pub(crate) tx_alive: bool,
     pub(crate) rx_alive: bool,
@@ -181,6 +203,115 @@ pub(crate) struct TcpConnectionInfo {
     pub(crate) state: ConnectionState,
 }
 
+/// Connections tracked by the firewall, detached from it so that they can be restored in another
+/// firewall instance, possibly in another process
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConntrackSnapshot {
+    udp: Vec<(Connection, UdpConnectionInfo)>,
+    tcp: Vec<(Connection, TcpConnectionInfo)>,
+    icmp: Vec<(IcmpConn, ConnectionState)>,
+}
+
+impl ConntrackSnapshot {
+    /// Number of the tracked connections
+    pub fn len(&self) -> usize {
+        self.udp.len() + self.tcp.len() + self.icmp.len()
+    }
+
+    /// Whether there are no tracked connections
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The tracked connections, TCP first, then UDP and ICMP
+    pub fn entries(&self) -> Vec<ConntrackEntry> {
+        let tcp = self
+            .tcp
+            .iter()
+            .map(|(conn, info)| link_entry("tcp", conn, info.state, info.conn_remote_initiated));
+        let udp = self
+            .udp
+            .iter()
+            .map(|(conn, info)| link_entry("udp", conn, info.state, info.is_remote_initiated));
+        let icmp = self
+            .icmp
+            .iter()
+            .map(|(conn, state)| icmp_entry(conn, *state));
+        tcp.chain(udp).chain(icmp).collect()
+    }
+
+    /// Number of the tracked connections by protocol
+    pub fn summary(&self) -> ConntrackSummary {
+        ConntrackSummary {
+            udp: self.udp.len(),
+            tcp: self.tcp.len(),
+            icmp: self.icmp.len(),
+        }
+    }
+}
+
+fn link_entry(
+    protocol: &'static str,
+    conn: &Connection,
+    state: ConnectionState,
+    remote_initiated: bool,
+) -> ConntrackEntry {
+    let local = StdSocketAddr::new(conn.link.local_addr.into(), conn.link.local_port);
+    let remote = StdSocketAddr::new(conn.link.remote_addr.into(), conn.link.remote_port);
+    let (source, destination) = match remote_initiated {
+        true => (remote, local),
+        false => (local, remote),
+    };
+    ConntrackEntry {
+        protocol,
+        source,
+        destination,
+        peer: peer_of(&conn.associated_data),
+        state,
+    }
+}
+
+fn icmp_entry(conn: &IcmpConn, state: ConnectionState) -> ConntrackEntry {
+    ConntrackEntry {
+        protocol: "icmp",
+        source: StdSocketAddr::new(conn.src_addr.into(), 0),
+        destination: StdSocketAddr::new(conn.dst_addr.into(), 0),
+        peer: peer_of(&conn.associated_data),
+        state,
+    }
+}
+
+fn peer_of(associated_data: &AssociatedData) -> Option<PublicKey> {
+    let key: [u8; KEY_SIZE] = associated_data.as_deref()?.try_into().ok()?;
+    Some(PublicKey(key))
+}
+
+/// Connection tracked by the firewall
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConntrackEntry {
+    /// Protocol of the connection, "tcp", "udp" or "icmp"
+    pub protocol: &'static str,
+    /// Address of the side which initiated the connection, the port is zero for ICMP
+    pub source: StdSocketAddr,
+    /// Address of the other side, the port is zero for ICMP
+    pub destination: StdSocketAddr,
+    /// Peer the packets of the connection are exchanged with
+    pub peer: Option<PublicKey>,
+    /// State of the connection
+    pub state: ConnectionState,
+}
+
+/// Number of the connections tracked by the firewall, by protocol
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConntrackSummary {
+    /// Tracked UDP connections
+    pub udp: usize,
+    /// Tracked TCP connections
+    pub tcp: usize,
+    /// Tracked ICMP exchanges
+    pub icmp: usize,
+}
+
 pub(crate) struct Conntrack {
     /// Recent udp connections
     pub(crate) udp: Mutex<LruCache<Connection, UdpConnectionInfo>>,
@@ -206,6 +337,71 @@ impl Conntrack {
         (self.tcp.lock().len(), self.udp.lock().len())
     }
 
+    /// Copy of the connections which have not expired yet
+    pub(crate) fn snapshot(&self) -> ConntrackSnapshot {
+        fn entries<K: Clone + Eq + std::hash::Hash, V: Clone>(
+            cache: &Mutex<LruCache<K, V>>,
+        ) -> Vec<(K, V)> {
+            let mut cache = cache.lock();
+            // Dropping the expired entries first
+            cache.len();
+            cache
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }
+
+        ConntrackSnapshot {
+            udp: entries(&self.udp),
+            tcp: entries(&self.tcp),
+            icmp: entries(&self.icmp),
+        }
+    }
+
+    /// Connections which are neither closed nor expired, without copying their state
+    pub(crate) fn open_entries(&self) -> Vec<ConntrackEntry> {
+        fn entries<K: Clone + Eq + std::hash::Hash, V>(
+            cache: &Mutex<LruCache<K, V>>,
+            entry: impl Fn(&K, &V) -> ConntrackEntry,
+        ) -> Vec<ConntrackEntry> {
+            let mut cache = cache.lock();
+            // Dropping the expired entries first
+            cache.len();
+            cache
+                .iter()
+                .map(|(key, value)| entry(key, value))
+                .filter(|entry| entry.state != ConnectionState::Closed)
+                .collect()
+        }
+
+        let mut open = entries(&self.tcp, |conn, info: &TcpConnectionInfo| {
+            link_entry("tcp", conn, info.state, info.conn_remote_initiated)
+        });
+        open.extend(entries(&self.udp, |conn, info: &UdpConnectionInfo| {
+            link_entry("udp", conn, info.state, info.is_remote_initiated)
+        }));
+        open.extend(entries(&self.icmp, |conn, state| icmp_entry(conn, *state)));
+        open
+    }
+
+    /// Track the connections from the `snapshot`, their lifetime starts anew.
+    /// Connections tracked already are updated.
+    pub(crate) fn restore(&self, snapshot: ConntrackSnapshot) {
+        fn insert<K: Clone + Eq + std::hash::Hash, V>(
+            cache: &Mutex<LruCache<K, V>>,
+            entries: Vec<(K, V)>,
+        ) {
+            let mut cache = cache.lock();
+            for (key, value) in entries {
+                cache.insert(key, value);
+            }
+        }
+
+        insert(&self.udp, snapshot.udp);
+        insert(&self.tcp, snapshot.tcp);
+        insert(&self.icmp, snapshot.icmp);
+    }
+
     /// Constructs a new conntracker
     pub(crate) fn new_with_capacity_and_ttl(
         capacity: usize,
@@ -1276,7 +1472,8 @@ mod tests {
 
     use crate::{
         conntrack::{
-            ConnectionState, Conntrack, LRU_CACHE_ICMP_TTL, LRU_CACHE_TCP_TTL, LRU_CACHE_UDP_TTL,
+            ConnectionState, Conntrack, ConntrackSnapshot, LRU_CACHE_ICMP_TTL, LRU_CACHE_TCP_TTL,
+            LRU_CACHE_UDP_TTL,
         },
         packet::IpPacket,
     };
@@ -1725,6 +1922,92 @@ mod tests {
         assert_eq!(conntrack.tcp.lock().len(), 1);
     }
 
+    #[test]
+    fn conntrack_snapshot_is_restored() {
+        let conntrack = Conntrack::new();
+        let peer = [7u8; 32];
+
+        let src = "127.0.0.1:1111";
+        let dst = "8.8.8.8:8888";
+
+        conntrack
+            .track_outbound_ip_packet::<Ipv4Packet>(Some(&peer), &make_tcp(src, dst, TcpFlags::SYN))
+            .expect("Unexpected conntrack error");
+        conntrack
+            .track_inbound_ip_packet::<Ipv4Packet>(
+                Some(&peer),
+                &make_tcp(dst, src, TcpFlags::SYN | TcpFlags::ACK),
+            )
+            .expect("Unexpected conntrack error");
+        conntrack
+            .track_outbound_ip_packet::<Ipv4Packet>(Some(&peer), &make_udp(src, dst))
+            .expect("Unexpected conntrack error");
+
+        let snapshot = conntrack.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        let serialized = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: ConntrackSnapshot = serde_json::from_str(&serialized).unwrap();
+
+        let restored = Conntrack::new();
+        restored.restore(snapshot);
+        assert_eq!(restored.get_connections_count(), (1, 1));
+
+        let conn_state = restored
+            .track_inbound_ip_packet::<Ipv4Packet>(Some(&peer), &make_tcp(dst, src, TcpFlags::ACK))
+            .expect("Unexpected conntrack error");
+        assert_eq!(conn_state, ConnectionState::Established);
+        let conn_state = restored
+            .track_inbound_ip_packet::<Ipv4Packet>(Some(&peer), &make_udp(dst, src))
+            .expect("Unexpected conntrack error");
+        assert_eq!(conn_state, ConnectionState::Established);
+    }
+
+    #[test]
+    fn conntrack_snapshot_lists_entries() {
+        let conntrack = Conntrack::new();
+        let peer = [7u8; 32];
+
+        let src = "127.0.0.1:1111";
+        let dst = "8.8.8.8:8888";
+
+        conntrack
+            .track_outbound_ip_packet::<Ipv4Packet>(Some(&peer), &make_tcp(src, dst, TcpFlags::SYN))
+            .expect("Unexpected conntrack error");
+        conntrack
+            .track_outbound_ip_packet::<Ipv4Packet>(None, &make_udp(src, dst))
+            .expect("Unexpected conntrack error");
+
+        let snapshot = conntrack.snapshot();
+        assert_eq!(
+            snapshot.summary(),
+            ConntrackSummary {
+                udp: 1,
+                tcp: 1,
+                icmp: 0,
+            }
+        );
+
+        let entries = snapshot.entries();
+        let described: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.protocol,
+                    entry.source.to_string(),
+                    entry.destination.to_string(),
+                    entry.peer,
+                )
+            })
+            .collect();
+        assert_eq!(
+            described,
+            vec![
+                ("tcp", src.to_owned(), dst.to_owned(), Some(PublicKey(peer))),
+                ("udp", src.to_owned(), dst.to_owned(), None),
+            ]
+        );
+    }
+
     #[test]
     fn conntrack_establishing_inbound_tcp_connection() {
         let conntrack = Conntrack::new();
@@ -0,0 +1,414 @@
+//! Forwarding and masquerade rules needed when the device acts as an exit node on Linux.
+//!
+//! nftables is used when available, with a fallback to iptables. Every rule is owned by
+//! libtelio: nftables rules live in a dedicated table, while iptables rules are tagged
+//! with a comment. This allows rules left behind by a crashed run to be found and removed.
+//!
+//! The rules are installed by running the firewall binaries, which blocks, so the async callers
+//! run the methods of [ExitNodeRules] on a blocking thread.
+
+#![cfg(target_os = "linux")]
+
+use std::{net::IpAddr, process::Command};
+
+use parking_lot::Mutex;
+use telio_model::features::{ExitNodeRulesBackend, FeatureExitNodeRules};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn};
+
+/// nftables table holding all of the rules
+const NFT_TABLE: &str = "libtelio_exit_node";
+
+/// Comment used to tag iptables rules
+const IPT_COMMENT: &str = "libtelio-exit-node";
+
+/// Path of the IPv4 forwarding sysctl
+const IPV4_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Path of the IPv6 forwarding sysctl
+const IPV6_FORWARD: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
+
+/// Exit node rules error
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Neither nft nor iptables could be executed
+    #[error("No supported firewall backend found")]
+    NoBackend,
+    /// Firewall command failed
+    #[error("Command {0} failed: {1}")]
+    Command(String, String),
+    /// Failed to toggle IP forwarding
+    #[error("Failed to enable forwarding: {0}")]
+    Forwarding(#[from] std::io::Error),
+}
+
+/// Backend used to install the rules
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Backend {
+    Nftables,
+    Iptables,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Interface the traffic is forwarded from
+    tunnel_name: String,
+    /// Addresses whose traffic is currently forwarded
+    sources: Vec<IpAddr>,
+    /// Forwarding sysctl values from before we touched them
+    forwarding: Vec<(&'static str, String)>,
+}
+
+/// Manager of the exit node forwarding and masquerade rules
+#[derive(Debug)]
+pub struct ExitNodeRules {
+    backend: Backend,
+    state: Mutex<State>,
+}
+
+impl ExitNodeRules {
+    /// Pick the backend and remove rules left over from previous runs
+    pub fn new(config: FeatureExitNodeRules) -> Result<Self, Error> {
+        let backend = match config.backend {
+            ExitNodeRulesBackend::Auto if is_available("nft") => Backend::Nftables,
+            ExitNodeRulesBackend::Auto if is_available("iptables") => Backend::Iptables,
+            ExitNodeRulesBackend::Auto => return Err(Error::NoBackend),
+            ExitNodeRulesBackend::Nftables => Backend::Nftables,
+            ExitNodeRulesBackend::Iptables => Backend::Iptables,
+        };
+        telio_log_info!("Using {backend:?} for exit node rules");
+
+        let rules = Self {
+            backend,
+            state: Mutex::new(State::default()),
+        };
+        rules.remove_rules();
+        Ok(rules)
+    }
+
+    /// Forward and masquerade traffic of `sources` coming from the tunnel interface called
+    /// `tunnel_name`. Forwarding is enabled only for the IP versions of the `sources`.
+    ///
+    /// Passing an empty list is equivalent to [ExitNodeRules::disable].
+    pub fn enable(&self, tunnel_name: &str, sources: &[IpAddr]) -> Result<(), Error> {
+        if sources.is_empty() {
+            return self.disable();
+        }
+
+        let mut state = self.state.lock();
+        if state.tunnel_name == tunnel_name && state.sources == sources {
+            return Ok(());
+        }
+
+        self.remove_rules();
+        enable_forwarding(sources, &mut state.forwarding)?;
+        state.tunnel_name = tunnel_name.to_owned();
+        state.sources = sources.to_vec();
+
+        let res = match self.backend {
+            Backend::Nftables => Self::install_nftables(tunnel_name, sources),
+            Backend::Iptables => Self::install_iptables(tunnel_name, sources),
+        };
+        if res.is_err() {
+            self.remove_rules();
+            state.sources.clear();
+        }
+        res
+    }
+
+    /// Remove all of the rules and restore forwarding settings
+    pub fn disable(&self) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        if state.sources.is_empty() && state.forwarding.is_empty() {
+            return Ok(());
+        }
+
+        self.remove_rules();
+        state.sources.clear();
+        for (path, value) in state.forwarding.drain(..) {
+            std::fs::write(path, value)?;
+        }
+        Ok(())
+    }
+
+    fn install_nftables(tun: &str, sources: &[IpAddr]) -> Result<(), Error> {
+        nft(&["add", "table", "inet", NFT_TABLE])?;
+        nft(&[
+            "add", "chain", "inet", NFT_TABLE, "forward", "{", "type", "filter", "hook", "forward",
+            "priority", "0", ";", "}",
+        ])?;
+        nft(&[
+            "add",
+            "chain",
+            "inet",
+            NFT_TABLE,
+            "postrouting",
+            "{",
+            "type",
+            "nat",
+            "hook",
+            "postrouting",
+            "priority",
+            "100",
+            ";",
+            "}",
+        ])?;
+
+        for source in sources {
+            let (family, addr) = (nft_family(source), source.to_string());
+            nft(&[
+                "add", "rule", "inet", NFT_TABLE, "forward", "iifname", tun, family, "saddr",
+                &addr, "accept",
+            ])?;
+            nft(&[
+                "add",
+                "rule",
+                "inet",
+                NFT_TABLE,
+                "forward",
+                "oifname",
+                tun,
+                family,
+                "daddr",
+                &addr,
+                "ct",
+                "state",
+                "established,related",
+                "accept",
+            ])?;
+            nft(&[
+                "add",
+                "rule",
+                "inet",
+                NFT_TABLE,
+                "postrouting",
+                "oifname",
+                "!=",
+                tun,
+                family,
+                "saddr",
+                &addr,
+                "masquerade",
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn install_iptables(tun: &str, sources: &[IpAddr]) -> Result<(), Error> {
+        for source in sources {
+            let (bin, addr) = (iptables_bin(source), source.to_string());
+            iptables(
+                bin,
+                &["-A", "FORWARD", "-i", tun, "-s", &addr, "-j", "ACCEPT"],
+            )?;
+            iptables(
+                bin,
+                &[
+                    "-A",
+                    "FORWARD",
+                    "-o",
+                    tun,
+                    "-d",
+                    &addr,
+                    "-m",
+                    "conntrack",
+                    "--ctstate",
+                    "ESTABLISHED,RELATED",
+                    "-j",
+                    "ACCEPT",
+                ],
+            )?;
+            iptables(
+                bin,
+                &[
+                    "-t",
+                    "nat",
+                    "-A",
+                    "POSTROUTING",
+                    "!",
+                    "-o",
+                    tun,
+                    "-s",
+                    &addr,
+                    "-j",
+                    "MASQUERADE",
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove every rule owned by libtelio, including ones from crashed runs
+    fn remove_rules(&self) {
+        match self.backend {
+            Backend::Nftables => {
+                // Fails when the table does not exist, which is fine
+                let _ = nft(&["delete", "table", "inet", NFT_TABLE]);
+            }
+            Backend::Iptables => {
+                for bin in ["iptables", "ip6tables"] {
+                    for table in ["filter", "nat"] {
+                        remove_tagged_iptables_rules(bin, table, IPT_COMMENT);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ExitNodeRules {
+    fn drop(&mut self) {
+        if let Err(e) = self.disable() {
+            telio_log_warn!("Failed to remove exit node rules: {e}");
+        }
+    }
+}
+
+pub(crate) fn is_available(bin: &str) -> bool {
+    Command::new(bin)
+        .arg("--version")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+pub(crate) fn execute(bin: &str, args: &[&str]) -> Result<String, Error> {
+    telio_log_debug!("Executing {bin} {}", args.join(" "));
+    let output = Command::new(bin)
+        .args(args)
+        .output()
+        .map_err(|e| Error::Command(bin.to_owned(), e.to_string()))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(Error::Command(
+            format!("{bin} {}", args.join(" ")),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+fn nft(args: &[&str]) -> Result<(), Error> {
+    execute("nft", args).map(|_| ())
+}
+
+fn iptables(bin: &str, args: &[&str]) -> Result<(), Error> {
+    let mut args = args.to_vec();
+    args.extend(["-m", "comment", "--comment", IPT_COMMENT]);
+    execute(bin, &args).map(|_| ())
+}
+
+pub(crate) fn nft_family(addr: &IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    }
+}
+
+pub(crate) fn iptables_bin(addr: &IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "iptables",
+        IpAddr::V6(_) => "ip6tables",
+    }
+}
+
+/// Turn `-A ...` rules tagged with `comment` listed by `iptables -S` into their `-D ...`
+/// counterparts
+fn tagged_rule_deletions<'a>(listing: &'a str, comment: &str) -> Vec<Vec<&'a str>> {
+    listing
+        .lines()
+        .filter(|line| line.starts_with("-A ") && line.contains(comment))
+        .map(|line| {
+            std::iter::once("-D")
+                .chain(line.split_whitespace().skip(1))
+                .collect()
+        })
+        .collect()
+}
+
+/// Remove the rules tagged with `comment` from the `table`
+pub(crate) fn remove_tagged_iptables_rules(bin: &str, table: &str, comment: &str) {
+    let listing = match execute(bin, &["-t", table, "-S"]) {
+        Ok(listing) => listing,
+        Err(e) => {
+            telio_log_debug!("Could not list {bin} {table} rules: {e}");
+            return;
+        }
+    };
+
+    for rule in tagged_rule_deletions(&listing, comment) {
+        let mut args = vec!["-t", table];
+        args.extend(rule);
+        if let Err(e) = execute(bin, &args) {
+            telio_log_warn!("Failed to remove stale rule: {e}");
+        }
+    }
+}
+
+/// Enable forwarding of the IP versions of the `sources`, recording the values it had before
+/// into `previous`
+fn enable_forwarding(
+    sources: &[IpAddr],
+    previous: &mut Vec<(&'static str, String)>,
+) -> Result<(), Error> {
+    let paths = [
+        (IPV4_FORWARD, sources.iter().any(IpAddr::is_ipv4)),
+        (IPV6_FORWARD, sources.iter().any(IpAddr::is_ipv6)),
+    ];
+    for (path, _) in paths.iter().filter(|(_, needed)| *needed) {
+        let value = std::fs::read_to_string(path)?;
+        if value.trim() != "1" {
+            std::fs::write(path, "1")?;
+            previous.push((*path, value));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_tagged_rules_are_deleted() {
+        let listing = "-P FORWARD ACCEPT\n\
+            -A FORWARD -i eth0 -j ACCEPT\n\
+            -A FORWARD -s 100.64.0.2/32 -i nlx0 -m comment --comment libtelio-exit-node -j ACCEPT\n\
+            -A POSTROUTING -s 100.64.0.2/32 ! -o nlx0 -m comment --comment libtelio-exit-node -j MASQUERADE\n";
+
+        assert_eq!(
+            tagged_rule_deletions(listing, IPT_COMMENT),
+            vec![
+                vec![
+                    "-D",
+                    "FORWARD",
+                    "-s",
+                    "100.64.0.2/32",
+                    "-i",
+                    "nlx0",
+                    "-m",
+                    "comment",
+                    "--comment",
+                    "libtelio-exit-node",
+                    "-j",
+                    "ACCEPT"
+                ],
+                vec![
+                    "-D",
+                    "POSTROUTING",
+                    "-s",
+                    "100.64.0.2/32",
+                    "!",
+                    "-o",
+                    "nlx0",
+                    "-m",
+                    "comment",
+                    "--comment",
+                    "libtelio-exit-node",
+                    "-j",
+                    "MASQUERADE"
+                ],
+            ]
+        );
+    }
+}
@@ -6,16 +6,26 @@ use enum_map::{Enum, EnumMap};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use parking_lot::RwLock;
 use pnet_packet::{
-    icmp::{IcmpType, IcmpTypes},
-    icmpv6::{Icmpv6Type, Icmpv6Types},
-    tcp::TcpFlags,
+    icmp::{IcmpPacket, IcmpType, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{Icmpv6Packet, Icmpv6Type, Icmpv6Types, MutableIcmpv6Packet},
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
+    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    tcp::{MutableTcpPacket, TcpFlags, TcpPacket},
+    udp::{MutableUdpPacket, UdpPacket},
 };
+use serde::Serialize;
 use smallvec::ToSmallVec;
 use std::{
+    convert::TryFrom,
     ffi::c_void,
     fmt::Debug,
     io::{self},
-    net::{IpAddr as StdIpAddr, Ipv4Addr as StdIpv4Addr, Ipv6Addr as StdIpv6Addr},
+    net::{
+        IpAddr as StdIpAddr, Ipv4Addr as StdIpv4Addr, Ipv6Addr as StdIpv6Addr,
+        SocketAddr as StdSocketAddr,
+    },
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
 
 use telio_model::features::{FeatureFirewall, IpProtocol};
@@ -23,23 +33,32 @@ use telio_network_monitors::monitor::{LocalInterfacesObserver, LOCAL_ADDRS_CACHE
 
 use telio_crypto::PublicKey;
 use telio_utils::{
-    telio_log_debug, telio_log_error, telio_log_info, telio_log_trace, telio_log_warn,
+    telio_log_debug, telio_log_error, telio_log_info, telio_log_trace, telio_log_warn, Instant,
 };
 
 use crate::{
+    accounting::{PacketCount, PeerPacketCounters},
+    approval::{ApprovalObserver, InboundApprovals, InboundDecision},
+    category::DomainClassifier,
+    chain::Chain,
     chain_helpers::{
         ConnectionState, Direction, FfiChainGuard, Filter, FilterData, NetworkFilterData,
         NextLevelProtocol, Rule,
     },
+    conntrack,
     ffi_chain::{LibfwChain, LibfwVerdict},
+    flow::{FlowExporter, Summary},
     libfirewall_api::{
         libfw_configure_chain, libfw_deinit, libfw_init, libfw_process_inbound_packet,
         libfw_process_outbound_packet, libfw_set_log_callback,
         libfw_trigger_stale_connection_close, LibfwFirewall,
     },
     log::LibfwLogLevel,
+    shaping::{BandwidthClassStats, Shaper},
 };
 
+pub use crate::conntrack::{ConntrackEntry, ConntrackSnapshot, ConntrackSummary};
+
 /// HashSet type used internally by firewall and returned by get_peer_whitelist
 pub type HashSet<V> = rustc_hash::FxHashSet<V>;
 /// HashMap type used internally by firewall and returned by get_port_whitelist
@@ -103,6 +122,10 @@ pub trait Firewall {
     /// Creates packets that are supposed to kill the existing connections.
     /// The end goal here is to force the client app sockets to reconnect.
     fn reset_connections(&self, pubkey: &PublicKey, sink: &mut dyn io::Write);
+
+    /// Evaluates a hypothetical packet against the current policy without processing it,
+    /// so it neither reaches the peer nor creates a tracked connection
+    fn evaluate(&self, packet: &PacketDescription) -> Verdict;
 }
 
 /// Possible permissions of the peer
@@ -156,7 +179,8 @@ pub struct FirewallState {
 }
 
 /// Configuration for firewall initialization.
-/// These parameters are set once during firewall creation and cannot be changed.
+/// Only the filtering rules of the feature can be changed afterwards, see
+/// [StatefullFirewall::update_rules].
 #[derive(Clone, Debug)]
 pub struct FirewallConfig {
     /// Whether IPv6 traffic is allowed
@@ -165,15 +189,149 @@ pub struct FirewallConfig {
     pub feature: FeatureFirewall,
 }
 
+/// Direction of a packet, relative to this node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// Packet received from a peer
+    Inbound,
+    /// Packet sent to a peer
+    Outbound,
+}
+
+/// Transport protocol of a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PacketProtocol {
+    /// TCP
+    Tcp,
+    /// UDP
+    Udp,
+    /// ICMP echo request
+    Icmp,
+    /// ICMPv6 echo request
+    Icmpv6,
+}
+
+/// Hypothetical packet evaluated against the current policy.
+/// It is treated as the first packet of a new connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketDescription {
+    /// Peer which sends the packet for inbound, or receives it for outbound packets
+    pub peer: PublicKey,
+    /// Direction of the packet
+    pub direction: PacketDirection,
+    /// Transport protocol of the packet
+    pub protocol: PacketProtocol,
+    /// Source address, the port is ignored for ICMP
+    pub source: StdSocketAddr,
+    /// Destination address, the port is ignored for ICMP
+    pub destination: StdSocketAddr,
+}
+
+/// Action the firewall takes on a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerdictAction {
+    /// Packet is let through
+    Accept,
+    /// Packet is silently discarded
+    Drop,
+    /// Packet is discarded and the sender is notified
+    Reject,
+}
+
+impl From<LibfwVerdict> for VerdictAction {
+    fn from(verdict: LibfwVerdict) -> Self {
+        match verdict {
+            LibfwVerdict::LibfwVerdictAccept => VerdictAction::Accept,
+            LibfwVerdict::LibfwVerdictDrop => VerdictAction::Drop,
+            LibfwVerdict::LibfwVerdictReject => VerdictAction::Reject,
+        }
+    }
+}
+
+/// Outcome of evaluating a packet with [Firewall::evaluate]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verdict {
+    /// What would happen to the packet
+    pub action: VerdictAction,
+    /// Index of the rule in the chain which decided, none when no rule matched
+    pub matched_rule: Option<u32>,
+    /// Human readable explanation of the decision
+    pub reason: String,
+}
+
+/// Number of the packets of a direction processed by the firewall, by verdict
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct VerdictCounts {
+    /// Packets let through
+    pub accepted: u64,
+    /// Packets silently discarded
+    pub dropped: u64,
+    /// Packets discarded with the sender notified
+    pub rejected: u64,
+}
+
+/// Number of the packets processed by the firewall since it was created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FirewallStats {
+    /// Packets received from the peers
+    pub inbound: VerdictCounts,
+    /// Packets sent to the peers
+    pub outbound: VerdictCounts,
+}
+
+#[derive(Debug, Default)]
+struct VerdictCounters {
+    accepted: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl VerdictCounters {
+    fn count(&self, action: VerdictAction) {
+        let counter = match action {
+            VerdictAction::Accept => &self.accepted,
+            VerdictAction::Drop => &self.dropped,
+            VerdictAction::Reject => &self.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> VerdictCounts {
+        VerdictCounts {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Statefull packet-filter firewall.
 pub struct StatefullFirewall {
     /// Libfirewall instance
     firewall: *mut LibfwFirewall,
     /// Firewall configuration set at initialization
-    config: FirewallConfig,
+    config: RwLock<FirewallConfig>,
     /// Current firewall state
     state: RwLock<FirewallState>,
     local_ifs_addrs: RwLock<Vec<StdIpAddr>>,
+    /// Categories of the domains observed in DNS queries
+    classifier: DomainClassifier,
+    /// Accounting of the flows seen in processed packets
+    flows: FlowExporter,
+    /// Decisions about the inbound connections of the peers, when they are asked for
+    approvals: Option<InboundApprovals>,
+    /// Bandwidth limits of the traffic to the peers, when configured
+    shaper: Option<Shaper>,
+    /// Packets exchanged with the peers, when counted for the traffic accounting
+    packet_counters: Option<PeerPacketCounters>,
+    /// Whether new connections are refused while the established ones drain
+    draining: AtomicBool,
+    /// Packets let through since the draining started
+    drained_packets: AtomicU64,
+    /// Verdicts on the packets received from the peers
+    inbound_verdicts: VerdictCounters,
+    /// Verdicts on the packets sent to the peers
+    outbound_verdicts: VerdictCounters,
 }
 
 // Access to internal firewall structs is guarded by locks, so that should be fine
@@ -190,6 +348,7 @@ impl LocalInterfacesObserver for StatefullFirewall {
 
 impl Drop for StatefullFirewall {
     fn drop(&mut self) {
+        self.flows.flush();
         unsafe {
             libfw_deinit(self.firewall);
         }
@@ -221,11 +380,24 @@ impl StatefullFirewall {
             .map(|iface| iface.addr.ip())
             .collect();
 
+        let flows = FlowExporter::new(config.feature.flow_export.as_ref());
+        let approvals = config.feature.inbound_approval.map(InboundApprovals::new);
+        let shaper = config.feature.shaping.as_ref().map(Shaper::new);
+
         let result = Self {
             firewall,
-            config,
+            config: RwLock::new(config),
             local_ifs_addrs: RwLock::new(initial_local_ifs_addrs),
             state: RwLock::new(state),
+            classifier: DomainClassifier::default(),
+            flows,
+            approvals,
+            shaper,
+            packet_counters: None,
+            draining: AtomicBool::new(false),
+            drained_packets: AtomicU64::new(0),
+            inbound_verdicts: VerdictCounters::default(),
+            outbound_verdicts: VerdictCounters::default(),
         };
 
         result.refresh_chain();
@@ -233,10 +405,198 @@ impl StatefullFirewall {
         result
     }
 
-    fn refresh_chain(&self) {
-        let state = self.state.read().clone();
+    /// Count the packets exchanged with each peer, see [Self::take_packet_counts]
+    pub fn with_packet_counters(mut self) -> Self {
+        self.packet_counters = Some(PeerPacketCounters::default());
+        self
+    }
+
+    /// Packets exchanged with each peer since the previous call, empty when they are not counted
+    pub fn take_packet_counts(&self) -> HashMap<PublicKey, PacketCount> {
+        self.packet_counters
+            .as_ref()
+            .map(|counters| counters.take())
+            .unwrap_or_default()
+    }
+
+    /// Classifier assigning categories to the domains observed in DNS queries
+    pub fn domain_classifier(&self) -> &DomainClassifier {
+        &self.classifier
+    }
+
+    /// Exporter of the flows seen in processed packets
+    pub fn flow_exporter(&self) -> &FlowExporter {
+        &self.flows
+    }
+
+    /// Packets processed since the firewall was created, by direction and verdict
+    pub fn stats(&self) -> FirewallStats {
+        FirewallStats {
+            inbound: self.inbound_verdicts.load(),
+            outbound: self.outbound_verdicts.load(),
+        }
+    }
+
+    /// Traffic of the bandwidth classes, empty when the shaping is not configured
+    pub fn shaping_stats(&self) -> Vec<BandwidthClassStats> {
+        self.shaper
+            .as_ref()
+            .map(|shaper| shaper.stats())
+            .unwrap_or_default()
+    }
+
+    /// Connections currently tracked, which can be passed to [Self::import_connections] of
+    /// another instance
+    pub fn export_connections(&self) -> ConntrackSnapshot {
+        // The pointer comes from libfw_init and stays valid until drop
+        unsafe { self.firewall.as_ref() }
+            .map(|firewall| firewall.conntrack.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Account for the packet in its flow, ending the flows of the connections closed meanwhile
+    fn observe_flow(
+        &self,
+        public_key: &[u8; 32],
+        direction: PacketDirection,
+        buffer: &[u8],
+        verdict: VerdictAction,
+    ) {
+        self.flows.observe(public_key, direction, buffer, verdict);
+        // The pointer comes from libfw_init and stays valid until drop
+        if let Some(firewall) = unsafe { self.firewall.as_ref() } {
+            self.flows.sweep(&firewall.conntrack);
+        }
+    }
+
+    /// Track the connections exported by another instance, so that they are not treated as new
+    pub fn import_connections(&self, snapshot: ConntrackSnapshot) {
+        telio_log_debug!("Importing {} tracked connections", snapshot.len());
+        if let Some(firewall) = unsafe { self.firewall.as_ref() } {
+            firewall.conntrack.restore(snapshot);
+        }
+    }
+
+    /// Number of the tracked connections which are not closed yet
+    pub fn active_connections(&self) -> usize {
+        self.export_connections()
+            .entries()
+            .iter()
+            .filter(|entry| entry.state != crate::conntrack::ConnectionState::Closed)
+            .count()
+    }
+
+    /// Refuse the new connections in both directions, while the established ones are let
+    /// through until they finish, see [Self::active_connections]
+    pub fn set_draining(&self, draining: bool) {
+        telio_log_info!("Draining connections: {draining}");
+        self.drained_packets.store(0, Ordering::Relaxed);
+        self.draining.store(draining, Ordering::Relaxed);
+        self.refresh_chain();
+    }
+
+    /// Packets of the established connections let through since the draining started. Idle
+    /// connections, e.g. UDP ones which are never closed, stop adding to it.
+    pub fn drained_packets(&self) -> u64 {
+        self.drained_packets.load(Ordering::Relaxed)
+    }
+
+    /// Count the packet let through while draining
+    fn count_drained(&self) {
+        if self.draining.load(Ordering::Relaxed) {
+            self.drained_packets.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Replace the excluded private range and the outgoing blacklist with the ones of the
+    /// `feature`. Other options are bound to the firewall instance and stay as they were.
+    pub fn update_rules(&self, feature: &FeatureFirewall) {
+        {
+            let mut config = self.config.write();
+            config.feature.exclude_private_ip_range = feature.exclude_private_ip_range;
+            config.feature.outgoing_blacklist = feature.outgoing_blacklist.clone();
+        }
+        self.refresh_chain();
+    }
+
+    /// Register the observer asked for the approval of the inbound connections, or remove it
+    /// with `None`. It is called on the packet processing path, so it must not block.
+    pub fn set_approval_observer(&self, observer: Option<ApprovalObserver>) {
+        if let Some(approvals) = &self.approvals {
+            approvals.set_observer(observer);
+        }
+    }
+
+    /// Apply the `decision` about the inbound connections of the `peer`. Returns false when the
+    /// inbound approval is not enabled.
+    pub fn decide_inbound(&self, peer: PublicKey, decision: InboundDecision) -> bool {
+        let Some(approvals) = &self.approvals else {
+            return false;
+        };
+        if approvals.decide(peer, decision, Instant::now()) {
+            self.refresh_chain();
+        }
+        true
+    }
+
+    /// Ask for the approval of a dropped packet opening a connection to this node
+    fn ask_for_approval(&self, public_key: &[u8; 32], buffer: &[u8]) {
+        let Some(approvals) = &self.approvals else {
+            return;
+        };
+        let Some(packet) = Summary::parse(buffer) else {
+            return;
+        };
+        let opens_connection = match packet.protocol {
+            PacketProtocol::Tcp => {
+                packet.tcp_flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN
+            }
+            PacketProtocol::Udp => true,
+            // Pings are not worth asking about
+            PacketProtocol::Icmp | PacketProtocol::Icmpv6 => false,
+        };
+        let peer = PublicKey(*public_key);
+        {
+            let state = self.state.read();
+            if !opens_connection
+                || state.whitelist.vpn_peer == Some(peer)
+                || !state.ip_addresses.contains(&packet.destination.ip())
+            {
+                return;
+            }
+        }
+        approvals.dropped(peer, packet.destination, Instant::now());
+    }
+
+    /// Firewall state in effect, with the peers allowed by the inbound approvals whitelisted
+    pub fn effective_state(&self) -> FirewallState {
+        let mut state = self.state.read().clone();
+        if let Some(approvals) = &self.approvals {
+            #[allow(clippy::indexing_slicing)]
+            state.whitelist.peer_whitelists[Permissions::IncomingConnections]
+                .extend(approvals.allowed_peers());
+        }
+        state
+    }
+
+    fn policy_rules(&self) -> Vec<(&'static str, Rule)> {
+        let state = self.effective_state();
         let local_ifs_addrs = self.local_ifs_addrs.read().clone();
-        let ffi_chain = configure_chain(&self.config, &state, &local_ifs_addrs);
+        let mut rules = match self.draining.load(Ordering::Relaxed) {
+            true => draining_rules(),
+            false => Vec::new(),
+        };
+        rules.extend(policy_rules(&self.config.read(), &state, &local_ifs_addrs));
+        rules
+    }
+
+    fn refresh_chain(&self) {
+        let rules: Vec<Rule> = self
+            .policy_rules()
+            .into_iter()
+            .map(|(_, rule)| rule)
+            .collect();
+        let ffi_chain: FfiChainGuard = rules.as_slice().into();
         unsafe {
             libfw_configure_chain(self.firewall, (&ffi_chain.ffi_chain) as *const LibfwChain);
         }
@@ -311,21 +671,54 @@ fn get_local_area_networks_filters(
     result
 }
 
-/// Configures the firewall chain based on the provided configuration.
-pub(crate) fn configure_chain(
+/// Rules refusing the new connections, local apps are told so right away
+fn draining_rules() -> Vec<(&'static str, Rule)> {
+    let new_connection = Filter {
+        filter_data: FilterData::ConntrackState(ConnectionState::New),
+        inverted: false,
+    };
+    vec![
+        (
+            "node is draining its connections",
+            Rule {
+                filters: vec![
+                    new_connection.clone(),
+                    Filter {
+                        filter_data: FilterData::Direction(Direction::Outbound),
+                        inverted: false,
+                    },
+                ],
+                action: LibfwVerdict::LibfwVerdictReject,
+            },
+        ),
+        (
+            "node is draining its connections",
+            Rule {
+                filters: vec![new_connection],
+                action: LibfwVerdict::LibfwVerdictDrop,
+            },
+        ),
+    ]
+}
+
+/// Rules of the chain in order, each with the reason it is there
+fn policy_rules(
     config: &FirewallConfig,
     state: &FirewallState,
     local_ifs_addrs: &[StdIpAddr],
-) -> FfiChainGuard {
+) -> Vec<(&'static str, Rule)> {
     let mut rules = vec![];
 
     // Drop all IPv6 packets when we don't allow ipv6 traffic
     const ALL_IP_V6_ADDRS: IpNet = IpNet::V6(Ipv6Net::new_assert(StdIpv6Addr::UNSPECIFIED, 0));
     if !config.allow_ipv6 {
-        rules.push(Rule {
-            filters: vec![dst_net_all_ports_filter(ALL_IP_V6_ADDRS, false)],
-            action: LibfwVerdict::LibfwVerdictDrop,
-        });
+        rules.push((
+            "IPv6 traffic is not allowed",
+            Rule {
+                filters: vec![dst_net_all_ports_filter(ALL_IP_V6_ADDRS, false)],
+                action: LibfwVerdict::LibfwVerdictDrop,
+            },
+        ));
     }
 
     // Reject packets from blacklist
@@ -334,39 +727,48 @@ pub(crate) fn configure_chain(
             IpProtocol::UDP => NextLevelProtocol::Udp,
             IpProtocol::TCP => NextLevelProtocol::Tcp,
         };
-        rules.push(Rule {
-            filters: vec![
-                Filter {
-                    filter_data: FilterData::Direction(Direction::Outbound),
-                    inverted: false,
-                },
-                Filter {
-                    filter_data: FilterData::NextLevelProtocol(next_level_protocol),
-                    inverted: false,
-                },
-                dst_net_all_ports_filter(IpNet::from(blacklist_entry.ip), false),
-            ],
-            action: LibfwVerdict::LibfwVerdictReject,
-        });
+        rules.push((
+            "destination is in the outgoing blacklist",
+            Rule {
+                filters: vec![
+                    Filter {
+                        filter_data: FilterData::Direction(Direction::Outbound),
+                        inverted: false,
+                    },
+                    Filter {
+                        filter_data: FilterData::NextLevelProtocol(next_level_protocol),
+                        inverted: false,
+                    },
+                    dst_net_all_ports_filter(IpNet::from(blacklist_entry.ip), false),
+                ],
+                action: LibfwVerdict::LibfwVerdictReject,
+            },
+        ));
     }
 
-    rules.push(Rule {
-        filters: vec![Filter {
-            filter_data: FilterData::Direction(Direction::Outbound),
-            inverted: false,
-        }],
-        action: LibfwVerdict::LibfwVerdictAccept,
-    });
-
-    // Add VPN rule
-    if let Some(vpn_pk) = state.whitelist.vpn_peer {
-        rules.push(Rule {
+    rules.push((
+        "outbound traffic is allowed",
+        Rule {
             filters: vec![Filter {
-                filter_data: FilterData::AssociatedData(Some(vpn_pk.to_smallvec())),
+                filter_data: FilterData::Direction(Direction::Outbound),
                 inverted: false,
             }],
             action: LibfwVerdict::LibfwVerdictAccept,
-        });
+        },
+    ));
+
+    // Add VPN rule
+    if let Some(vpn_pk) = state.whitelist.vpn_peer {
+        rules.push((
+            "traffic from the VPN server is allowed",
+            Rule {
+                filters: vec![Filter {
+                    filter_data: FilterData::AssociatedData(Some(vpn_pk.to_smallvec())),
+                    inverted: false,
+                }],
+                action: LibfwVerdict::LibfwVerdictAccept,
+            },
+        ));
     }
 
     let local_network_filters = get_local_area_networks_filters(
@@ -382,19 +784,25 @@ pub(crate) fn configure_chain(
                 inverted: false,
             }];
             filters.extend_from_slice(local_net);
-            rules.push(Rule {
-                filters,
-                action: LibfwVerdict::LibfwVerdictAccept,
-            });
+            rules.push((
+                "peer is allowed to access the local network",
+                Rule {
+                    filters,
+                    action: LibfwVerdict::LibfwVerdictAccept,
+                },
+            ));
         }
     }
 
     // For nodes not included in the whitelist this packet should be dropped
     for filters in local_network_filters {
-        rules.push(Rule {
-            filters,
-            action: LibfwVerdict::LibfwVerdictDrop,
-        });
+        rules.push((
+            "local network access is not permitted for the peer",
+            Rule {
+                filters,
+                action: LibfwVerdict::LibfwVerdictDrop,
+            },
+        ));
     }
 
     // Rules for incoming connections
@@ -402,93 +810,224 @@ pub(crate) fn configure_chain(
         // Accept packets for whitelisted peers
         #[allow(clippy::indexing_slicing)]
         for peer in state.whitelist.peer_whitelists[Permissions::IncomingConnections].iter() {
-            rules.push(Rule {
+            rules.push((
+                "peer is allowed to make incoming connections",
+                Rule {
+                    filters: vec![
+                        Filter {
+                            filter_data: FilterData::AssociatedData(Some(peer.to_smallvec())),
+                            inverted: false,
+                        },
+                        dst_net_all_ports_filter(IpNet::from(*ip), false),
+                    ],
+                    action: LibfwVerdict::LibfwVerdictAccept,
+                },
+            ));
+        }
+
+        // Accept packets for locally initiated connections
+        rules.push((
+            "packet belongs to a locally initiated connection",
+            Rule {
                 filters: vec![
                     Filter {
-                        filter_data: FilterData::AssociatedData(Some(peer.to_smallvec())),
+                        filter_data: FilterData::ConntrackState(ConnectionState::Established),
                         inverted: false,
                     },
                     dst_net_all_ports_filter(IpNet::from(*ip), false),
                 ],
                 action: LibfwVerdict::LibfwVerdictAccept,
-            });
-        }
-
-        // Accept packets for locally initiated connections
-        rules.push(Rule {
-            filters: vec![
-                Filter {
-                    filter_data: FilterData::ConntrackState(ConnectionState::Established),
-                    inverted: false,
-                },
-                dst_net_all_ports_filter(IpNet::from(*ip), false),
-            ],
-            action: LibfwVerdict::LibfwVerdictAccept,
-        });
+            },
+        ));
 
         // Accept certain TCP packets for finished connections
-        rules.push(Rule {
-            filters: vec![
-                Filter {
-                    filter_data: FilterData::ConntrackState(ConnectionState::Closed),
-                    inverted: false,
-                },
-                Filter {
-                    filter_data: FilterData::TcpFlags(
-                        TcpFlags::ACK | TcpFlags::FIN | TcpFlags::RST,
-                    ),
-                    inverted: false,
-                },
-                dst_net_all_ports_filter(IpNet::from(*ip), false),
-            ],
-            action: LibfwVerdict::LibfwVerdictAccept,
-        });
+        rules.push((
+            "packet finishes a closed TCP connection",
+            Rule {
+                filters: vec![
+                    Filter {
+                        filter_data: FilterData::ConntrackState(ConnectionState::Closed),
+                        inverted: false,
+                    },
+                    Filter {
+                        filter_data: FilterData::TcpFlags(
+                            TcpFlags::ACK | TcpFlags::FIN | TcpFlags::RST,
+                        ),
+                        inverted: false,
+                    },
+                    dst_net_all_ports_filter(IpNet::from(*ip), false),
+                ],
+                action: LibfwVerdict::LibfwVerdictAccept,
+            },
+        ));
 
         // Accept packets for whitelisted ports
         for proto in [NextLevelProtocol::Tcp, NextLevelProtocol::Udp] {
             for (peer, &port) in &state.whitelist.port_whitelist {
-                rules.push(Rule {
-                    filters: vec![
-                        Filter {
-                            filter_data: FilterData::AssociatedData(Some(peer.to_smallvec())),
-                            inverted: false,
-                        },
-                        Filter {
-                            filter_data: FilterData::NextLevelProtocol(proto),
-                            inverted: false,
-                        },
-                        Filter {
-                            filter_data: FilterData::DstNetwork(NetworkFilterData {
-                                network: IpNet::from(*ip),
-                                port_range: (port, port),
-                            }),
-                            inverted: false,
-                        },
-                    ],
-                    action: LibfwVerdict::LibfwVerdictAccept,
-                });
+                rules.push((
+                    "port is whitelisted for the peer",
+                    Rule {
+                        filters: vec![
+                            Filter {
+                                filter_data: FilterData::AssociatedData(Some(peer.to_smallvec())),
+                                inverted: false,
+                            },
+                            Filter {
+                                filter_data: FilterData::NextLevelProtocol(proto),
+                                inverted: false,
+                            },
+                            Filter {
+                                filter_data: FilterData::DstNetwork(NetworkFilterData {
+                                    network: IpNet::from(*ip),
+                                    port_range: (port, port),
+                                }),
+                                inverted: false,
+                            },
+                        ],
+                        action: LibfwVerdict::LibfwVerdictAccept,
+                    },
+                ));
             }
         }
 
         // Drop rest of the packets going to local interfaces
-        rules.push(Rule {
-            filters: vec![dst_net_all_ports_filter(IpNet::from(*ip), false)],
-            action: LibfwVerdict::LibfwVerdictDrop,
-        });
+        rules.push((
+            "incoming connections are not permitted for the peer",
+            Rule {
+                filters: vec![dst_net_all_ports_filter(IpNet::from(*ip), false)],
+                action: LibfwVerdict::LibfwVerdictDrop,
+            },
+        ));
     }
 
     #[allow(clippy::indexing_slicing)]
     for peer in state.whitelist.peer_whitelists[Permissions::RoutingConnections].iter() {
-        rules.push(Rule {
-            filters: vec![Filter {
-                filter_data: FilterData::AssociatedData(Some(peer.to_smallvec())),
-                inverted: false,
-            }],
-            action: LibfwVerdict::LibfwVerdictAccept,
-        });
+        rules.push((
+            "peer is allowed to route traffic",
+            Rule {
+                filters: vec![Filter {
+                    filter_data: FilterData::AssociatedData(Some(peer.to_smallvec())),
+                    inverted: false,
+                }],
+                action: LibfwVerdict::LibfwVerdictAccept,
+            },
+        ));
     }
 
-    (rules.as_slice()).into()
+    rules
+}
+
+/// Builds the first packet of the connection the description stands for, a TCP SYN, a UDP
+/// datagram or an ICMP echo request. None when the addresses are of different families.
+fn synthesize_packet(packet: &PacketDescription) -> Option<Vec<u8>> {
+    let (next_level_protocol, transport_len) = match packet.protocol {
+        PacketProtocol::Tcp => (IpNextHeaderProtocols::Tcp, TcpPacket::minimum_packet_size()),
+        PacketProtocol::Udp => (IpNextHeaderProtocols::Udp, UdpPacket::minimum_packet_size()),
+        PacketProtocol::Icmp => (
+            IpNextHeaderProtocols::Icmp,
+            IcmpPacket::minimum_packet_size(),
+        ),
+        PacketProtocol::Icmpv6 => (
+            IpNextHeaderProtocols::Icmpv6,
+            Icmpv6Packet::minimum_packet_size(),
+        ),
+    };
+
+    let (mut raw, ip_len) = match (packet.source.ip(), packet.destination.ip()) {
+        (StdIpAddr::V4(source), StdIpAddr::V4(destination)) => {
+            let ip_len = Ipv4Packet::minimum_packet_size();
+            let mut raw = vec![0u8; ip_len + transport_len];
+            let mut ip = MutableIpv4Packet::new(&mut raw)?;
+            ip.set_version(4);
+            ip.set_header_length((ip_len / 4) as u8);
+            ip.set_total_length((ip_len + transport_len) as u16);
+            ip.set_ttl(64);
+            ip.set_next_level_protocol(next_level_protocol);
+            ip.set_source(source);
+            ip.set_destination(destination);
+            (raw, ip_len)
+        }
+        (StdIpAddr::V6(source), StdIpAddr::V6(destination)) => {
+            let ip_len = Ipv6Packet::minimum_packet_size();
+            let mut raw = vec![0u8; ip_len + transport_len];
+            let mut ip = MutableIpv6Packet::new(&mut raw)?;
+            ip.set_version(6);
+            ip.set_payload_length(transport_len as u16);
+            ip.set_hop_limit(64);
+            ip.set_next_header(next_level_protocol);
+            ip.set_source(source);
+            ip.set_destination(destination);
+            (raw, ip_len)
+        }
+        _ => return None,
+    };
+
+    let transport = raw.get_mut(ip_len..)?;
+    match packet.protocol {
+        PacketProtocol::Tcp => {
+            let mut tcp = MutableTcpPacket::new(transport)?;
+            tcp.set_source(packet.source.port());
+            tcp.set_destination(packet.destination.port());
+            tcp.set_data_offset((transport_len / 4) as u8);
+            tcp.set_flags(TcpFlags::SYN);
+        }
+        PacketProtocol::Udp => {
+            let mut udp = MutableUdpPacket::new(transport)?;
+            udp.set_source(packet.source.port());
+            udp.set_destination(packet.destination.port());
+            udp.set_length(transport_len as u16);
+        }
+        PacketProtocol::Icmp => {
+            MutableIcmpPacket::new(transport)?.set_icmp_type(IcmpTypes::EchoRequest);
+        }
+        PacketProtocol::Icmpv6 => {
+            MutableIcmpv6Packet::new(transport)?.set_icmpv6_type(Icmpv6Types::EchoRequest);
+        }
+    }
+    Some(raw)
+}
+
+/// Runs the packet through the chain the rules are configured as, as the first packet of
+/// a new connection, dropping packets which match no rule
+fn evaluate_rules(rules: &[(&'static str, Rule)], packet: &PacketDescription) -> Verdict {
+    let dropped = |reason: String| Verdict {
+        action: VerdictAction::Drop,
+        matched_rule: None,
+        reason,
+    };
+
+    let (reasons, rules): (Vec<&'static str>, Vec<Rule>) = rules.iter().cloned().unzip();
+    let ffi_chain: FfiChainGuard = rules.as_slice().into();
+    let chain = match Chain::try_from(&ffi_chain.ffi_chain) {
+        Ok(chain) => chain,
+        Err(err) => return dropped(format!("chain is malformed: {err:?}")),
+    };
+    let Some(raw) = synthesize_packet(packet) else {
+        return dropped("source and destination addresses are of different families".to_owned());
+    };
+
+    let direction = match packet.direction {
+        PacketDirection::Inbound => conntrack::Direction::Inbound,
+        PacketDirection::Outbound => conntrack::Direction::Outbound,
+    };
+    let peer = Some(packet.peer.as_slice());
+    let matched = match packet.destination {
+        StdSocketAddr::V4(_) => Ipv4Packet::new(&raw).and_then(|ip| {
+            chain.matching_rule(conntrack::ConnectionState::New, &ip, peer, direction)
+        }),
+        StdSocketAddr::V6(_) => Ipv6Packet::new(&raw).and_then(|ip| {
+            chain.matching_rule(conntrack::ConnectionState::New, &ip, peer, direction)
+        }),
+    };
+
+    match matched.and_then(|index| Some((index, chain.rules.get(index)?, reasons.get(index)?))) {
+        Some((index, rule, reason)) => Verdict {
+            action: rule.action.into(),
+            matched_rule: u32::try_from(index).ok(),
+            reason: (*reason).to_owned(),
+        },
+        None => dropped("no rule matched the packet".to_owned()),
+    }
 }
 
 extern "C" fn write_to_sink(
@@ -528,6 +1067,9 @@ impl Firewall for StatefullFirewall {
         if *self.state.read() == new_state {
             return;
         }
+        if let Some(shaper) = &self.shaper {
+            shaper.set_exit_node(new_state.whitelist.vpn_peer);
+        }
         *self.state.write() = new_state;
 
         self.refresh_chain();
@@ -544,18 +1086,35 @@ impl Firewall for StatefullFirewall {
         sink: &mut dyn io::Write,
     ) -> bool {
         let sink_ptr = &sink as *const &mut dyn io::Write;
-        LibfwVerdict::LibfwVerdictAccept
-            == unsafe {
-                libfw_process_outbound_packet(
-                    self.firewall,
-                    buffer.as_ptr(),
-                    buffer.len(),
-                    public_key as *const u8,
-                    public_key.len(),
-                    sink_ptr as *mut c_void,
-                    Some(write_to_sink),
-                )
-            }
+        let verdict = unsafe {
+            libfw_process_outbound_packet(
+                self.firewall,
+                buffer.as_ptr(),
+                buffer.len(),
+                public_key as *const u8,
+                public_key.len(),
+                sink_ptr as *mut c_void,
+                Some(write_to_sink),
+            )
+        };
+        self.outbound_verdicts.count(verdict.into());
+        let sent = LibfwVerdict::LibfwVerdictAccept == verdict
+            && self.shaper.as_ref().is_none_or(|shaper| {
+                shaper.admit(&PublicKey(*public_key), buffer.len(), Instant::now())
+            });
+        // Packets over the rate of their peer are recorded as dropped by the flows
+        let action = match verdict {
+            LibfwVerdict::LibfwVerdictAccept if !sent => VerdictAction::Drop,
+            verdict => verdict.into(),
+        };
+        self.observe_flow(public_key, PacketDirection::Outbound, buffer, action);
+        if sent {
+            self.count_drained();
+        }
+        if let (true, Some(counters)) = (sent, &self.packet_counters) {
+            counters.count(&PublicKey(*public_key), PacketDirection::Outbound);
+        }
+        sent
     }
 
     /// Checks if incoming packet should be accepted.
@@ -563,18 +1122,33 @@ impl Firewall for StatefullFirewall {
     /// Adds new connection to cache only if ip is whitelisted
     /// Allows all icmp packets except for request types
     fn process_inbound_packet(&self, public_key: &[u8; 32], buffer: &[u8]) -> bool {
-        LibfwVerdict::LibfwVerdictAccept
-            == unsafe {
-                libfw_process_inbound_packet(
-                    self.firewall,
-                    buffer.as_ptr(),
-                    buffer.len(),
-                    public_key as *const u8,
-                    public_key.len(),
-                    std::ptr::null_mut(),
-                    None,
-                )
+        if let Some(approvals) = &self.approvals {
+            if approvals.expire(Instant::now()) {
+                self.refresh_chain();
             }
+        }
+        let verdict = unsafe {
+            libfw_process_inbound_packet(
+                self.firewall,
+                buffer.as_ptr(),
+                buffer.len(),
+                public_key as *const u8,
+                public_key.len(),
+                std::ptr::null_mut(),
+                None,
+            )
+        };
+        self.inbound_verdicts.count(verdict.into());
+        if let Some(counters) = &self.packet_counters {
+            counters.count(&PublicKey(*public_key), PacketDirection::Inbound);
+        }
+        self.observe_flow(public_key, PacketDirection::Inbound, buffer, verdict.into());
+        if LibfwVerdict::LibfwVerdictAccept == verdict {
+            self.count_drained();
+            return true;
+        }
+        self.ask_for_approval(public_key, buffer);
+        false
     }
 
     fn reset_connections(&self, pubkey: &PublicKey, sink: &mut dyn io::Write) {
@@ -591,6 +1165,11 @@ impl Firewall for StatefullFirewall {
             );
         }
     }
+    fn evaluate(&self, packet: &PacketDescription) -> Verdict {
+        let verdict = evaluate_rules(&self.policy_rules(), packet);
+        telio_log_debug!("Evaluated {:?}: {:?}", packet, verdict);
+        verdict
+    }
 }
 
 /// The default initialization of Firewall object
@@ -599,3 +1178,151 @@ impl Default for StatefullFirewall {
         Self::new(true, FeatureFirewall::default())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL_IP: StdIpv4Addr = StdIpv4Addr::new(100, 64, 0, 1);
+    const PEER_IP: StdIpv4Addr = StdIpv4Addr::new(100, 64, 0, 2);
+
+    fn config() -> FirewallConfig {
+        FirewallConfig {
+            allow_ipv6: false,
+            feature: FeatureFirewall::default(),
+        }
+    }
+
+    fn state(peer: PublicKey) -> FirewallState {
+        let mut state = FirewallState {
+            ip_addresses: vec![LOCAL_IP.into()],
+            ..Default::default()
+        };
+        state.whitelist.port_whitelist.insert(peer, FILE_SEND_PORT);
+        state
+    }
+
+    fn ssh_from(peer: PublicKey) -> PacketDescription {
+        PacketDescription {
+            peer,
+            direction: PacketDirection::Inbound,
+            protocol: PacketProtocol::Tcp,
+            source: (PEER_IP, 51234).into(),
+            destination: (LOCAL_IP, 22).into(),
+        }
+    }
+
+    #[test]
+    fn inbound_connection_needs_permission() {
+        let peer = PublicKey([1; 32]);
+        let mut state = state(peer);
+
+        let verdict = evaluate_rules(&policy_rules(&config(), &state, &[]), &ssh_from(peer));
+        assert_eq!(verdict.action, VerdictAction::Drop);
+        assert_eq!(
+            verdict.reason,
+            "incoming connections are not permitted for the peer"
+        );
+
+        state.whitelist.peer_whitelists[Permissions::IncomingConnections].insert(peer);
+        let rules = policy_rules(&config(), &state, &[]);
+        let verdict = evaluate_rules(&rules, &ssh_from(peer));
+        assert_eq!(verdict.action, VerdictAction::Accept);
+        assert_eq!(
+            verdict.reason,
+            "peer is allowed to make incoming connections"
+        );
+        let index = verdict.matched_rule.unwrap() as usize;
+        assert_eq!(rules[index].1.action, LibfwVerdict::LibfwVerdictAccept);
+
+        let other = evaluate_rules(&rules, &ssh_from(PublicKey([2; 32])));
+        assert_eq!(other.action, VerdictAction::Drop);
+    }
+
+    #[test]
+    fn whitelisted_port_is_accepted() {
+        let peer = PublicKey([1; 32]);
+        let rules = policy_rules(&config(), &state(peer), &[]);
+
+        let mut packet = ssh_from(peer);
+        packet.destination.set_port(FILE_SEND_PORT);
+        let verdict = evaluate_rules(&rules, &packet);
+        assert_eq!(verdict.action, VerdictAction::Accept);
+        assert_eq!(verdict.reason, "port is whitelisted for the peer");
+
+        packet.protocol = PacketProtocol::Icmp;
+        assert_eq!(evaluate_rules(&rules, &packet).action, VerdictAction::Drop);
+    }
+
+    #[test]
+    fn outbound_and_ipv6_packets() {
+        let peer = PublicKey([1; 32]);
+        let rules = policy_rules(&config(), &state(peer), &[]);
+
+        let mut packet = ssh_from(peer);
+        packet.direction = PacketDirection::Outbound;
+        std::mem::swap(&mut packet.source, &mut packet.destination);
+        let verdict = evaluate_rules(&rules, &packet);
+        assert_eq!(verdict.action, VerdictAction::Accept);
+        assert_eq!(verdict.matched_rule, Some(1));
+
+        packet.source = (StdIpv6Addr::LOCALHOST, 51234).into();
+        packet.destination = (StdIpv6Addr::LOCALHOST, 22).into();
+        let verdict = evaluate_rules(&rules, &packet);
+        assert_eq!(verdict.action, VerdictAction::Drop);
+        assert_eq!(verdict.matched_rule, Some(0));
+
+        packet.source = (PEER_IP, 51234).into();
+        assert_eq!(evaluate_rules(&rules, &packet).matched_rule, None);
+    }
+
+    #[test]
+    fn new_connections_are_refused_while_draining() {
+        let peer = PublicKey([1; 32]);
+        let mut rules = draining_rules();
+        rules.extend(policy_rules(&config(), &state(peer), &[]));
+
+        let mut packet = ssh_from(peer);
+        packet.destination.set_port(FILE_SEND_PORT);
+        let verdict = evaluate_rules(&rules, &packet);
+        assert_eq!(verdict.action, VerdictAction::Drop);
+        assert_eq!(verdict.reason, "node is draining its connections");
+
+        packet.direction = PacketDirection::Outbound;
+        std::mem::swap(&mut packet.source, &mut packet.destination);
+        assert_eq!(
+            evaluate_rules(&rules, &packet).action,
+            VerdictAction::Reject
+        );
+    }
+
+    #[test]
+    fn verdicts_are_counted() {
+        let counters = VerdictCounters::default();
+        counters.count(VerdictAction::Accept);
+        counters.count(VerdictAction::Drop);
+        counters.count(VerdictAction::Drop);
+
+        assert_eq!(
+            counters.load(),
+            VerdictCounts {
+                accepted: 1,
+                dropped: 2,
+                rejected: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn unmatched_packet_is_dropped() {
+        let verdict = evaluate_rules(&[], &ssh_from(PublicKey([1; 32])));
+        assert_eq!(
+            verdict,
+            Verdict {
+                action: VerdictAction::Drop,
+                matched_rule: None,
+                reason: "no rule matched the packet".to_owned(),
+            }
+        );
+    }
+}
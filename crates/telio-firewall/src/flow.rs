@@ -0,0 +1,834 @@
+//! Export of flow records.
+//!
+//! The firewall sees every packet exchanged with the peers, which makes it the natural place to
+//! account for traffic without capturing it. Packets are counted in flows identified by the
+//! peer, the transport protocol and both endpoints. A flow lasts as long as the connection
+//! tracker of the firewall keeps its connection open: once the connection is closed or expires,
+//! a [FlowRecord] is handed to every registered [FlowSink]. Collectors are sent the records as
+//! IPFIX messages, encoded by [IpfixEncoder].
+
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::{Mutex, RwLock};
+use pnet_packet::{
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
+    tcp::TcpPacket,
+    udp::UdpPacket,
+    Packet,
+};
+use rustc_hash::FxHasher;
+use telio_crypto::PublicKey;
+use telio_model::features::FeatureFlowExport;
+use telio_utils::Instant;
+
+use crate::{
+    conntrack::Conntrack,
+    firewall::{HashMap, HashSet, PacketDirection, PacketProtocol, VerdictAction},
+};
+
+/// Flows of the closed connections are looked for at most this often
+const FLOW_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Flows are spread over this many tables, so that the packets of different flows rarely wait
+/// for each other
+const FLOW_SHARDS: usize = 16;
+
+/// Version number of IPFIX in the message header
+const IPFIX_VERSION: u16 = 10;
+/// Set id of the template sets
+const IPFIX_TEMPLATE_SET_ID: u16 = 2;
+/// Templates of the records with IPv4 and IPv6 addresses
+const IPFIX_TEMPLATE_IPV4: u16 = 256;
+const IPFIX_TEMPLATE_IPV6: u16 = 257;
+
+/// IANA information elements of the records
+mod ie {
+    pub(super) const PROTOCOL_IDENTIFIER: u16 = 4;
+    pub(super) const SOURCE_TRANSPORT_PORT: u16 = 7;
+    pub(super) const SOURCE_IPV4_ADDRESS: u16 = 8;
+    pub(super) const DESTINATION_TRANSPORT_PORT: u16 = 11;
+    pub(super) const DESTINATION_IPV4_ADDRESS: u16 = 12;
+    pub(super) const SOURCE_IPV6_ADDRESS: u16 = 27;
+    pub(super) const DESTINATION_IPV6_ADDRESS: u16 = 28;
+    pub(super) const FLOW_DIRECTION: u16 = 61;
+    pub(super) const FLOW_START_MILLISECONDS: u16 = 152;
+    pub(super) const FLOW_END_MILLISECONDS: u16 = 153;
+    pub(super) const FIREWALL_EVENT: u16 = 233;
+    pub(super) const INITIATOR_OCTETS: u16 = 231;
+    pub(super) const RESPONDER_OCTETS: u16 = 232;
+    pub(super) const INITIATOR_PACKETS: u16 = 298;
+    pub(super) const RESPONDER_PACKETS: u16 = 299;
+}
+
+/// Summary of a finished flow
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlowRecord {
+    /// Peer on the other side of the flow
+    pub peer: PublicKey,
+    /// Transport protocol of the flow, ports are zero for ICMP
+    pub protocol: PacketProtocol,
+    /// Address of the side which sent the first packet
+    pub source: SocketAddr,
+    /// Address of the other side
+    pub destination: SocketAddr,
+    /// Direction of the first packet, relative to this node
+    pub direction: PacketDirection,
+    /// Number of packets sent by the source
+    pub source_packets: u64,
+    /// Number of IP bytes sent by the source
+    pub source_bytes: u64,
+    /// Number of packets sent by the destination
+    pub destination_packets: u64,
+    /// Number of IP bytes sent by the destination
+    pub destination_bytes: u64,
+    /// When the first packet was seen
+    pub start: SystemTime,
+    /// Time between the first and the last packet
+    pub duration: Duration,
+    /// [VerdictAction::Accept] when any packet of the flow was let through, otherwise the
+    /// verdict on the first packet
+    pub verdict: VerdictAction,
+}
+
+/// Receiver of finished flows.
+///
+/// Records are exported on the packet processing path, so implementations must not block.
+pub trait FlowSink: Send + Sync + Debug {
+    /// Handle a finished flow
+    fn export(&self, record: &FlowRecord);
+}
+
+/// Encoder of [FlowRecord]s into IPFIX messages (RFC 7011).
+///
+/// Every message carries a single record along with its template, so that a collector decodes
+/// it regardless of the messages lost before. Flows are reported as biflows, with the initiator
+/// and responder counters of RFC 5103, and the firewall event telling whether they were let
+/// through. There is no information element for the peer, which is left out.
+#[derive(Debug, Default)]
+pub struct IpfixEncoder {
+    /// Number of the data records encoded so far
+    sequence: AtomicU32,
+}
+
+impl IpfixEncoder {
+    /// Encode the `record` into a message exported at `export_time`, none when the addresses
+    /// of the record are of different families
+    pub fn encode(&self, record: &FlowRecord, export_time: SystemTime) -> Option<Vec<u8>> {
+        let (template_id, source, destination) = match (record.source.ip(), record.destination.ip())
+        {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => (
+                IPFIX_TEMPLATE_IPV4,
+                (ie::SOURCE_IPV4_ADDRESS, source.octets().to_vec()),
+                (ie::DESTINATION_IPV4_ADDRESS, destination.octets().to_vec()),
+            ),
+            (IpAddr::V6(source), IpAddr::V6(destination)) => (
+                IPFIX_TEMPLATE_IPV6,
+                (ie::SOURCE_IPV6_ADDRESS, source.octets().to_vec()),
+                (ie::DESTINATION_IPV6_ADDRESS, destination.octets().to_vec()),
+            ),
+            _ => return None,
+        };
+        let protocol: u8 = match record.protocol {
+            PacketProtocol::Tcp => 6,
+            PacketProtocol::Udp => 17,
+            PacketProtocol::Icmp => 1,
+            PacketProtocol::Icmpv6 => 58,
+        };
+        let direction: u8 = match record.direction {
+            PacketDirection::Inbound => 0,
+            PacketDirection::Outbound => 1,
+        };
+        // Flow deleted, or flow denied
+        let event: u8 = match record.verdict {
+            VerdictAction::Accept => 2,
+            VerdictAction::Drop | VerdictAction::Reject => 3,
+        };
+        let start = unix_millis(record.start);
+        let end = start.saturating_add(record.duration.as_millis() as u64);
+
+        let fields = [
+            source,
+            destination,
+            (
+                ie::SOURCE_TRANSPORT_PORT,
+                record.source.port().to_be_bytes().to_vec(),
+            ),
+            (
+                ie::DESTINATION_TRANSPORT_PORT,
+                record.destination.port().to_be_bytes().to_vec(),
+            ),
+            (ie::PROTOCOL_IDENTIFIER, vec![protocol]),
+            (ie::FLOW_DIRECTION, vec![direction]),
+            (ie::FLOW_START_MILLISECONDS, start.to_be_bytes().to_vec()),
+            (ie::FLOW_END_MILLISECONDS, end.to_be_bytes().to_vec()),
+            (
+                ie::INITIATOR_PACKETS,
+                record.source_packets.to_be_bytes().to_vec(),
+            ),
+            (
+                ie::RESPONDER_PACKETS,
+                record.destination_packets.to_be_bytes().to_vec(),
+            ),
+            (
+                ie::INITIATOR_OCTETS,
+                record.source_bytes.to_be_bytes().to_vec(),
+            ),
+            (
+                ie::RESPONDER_OCTETS,
+                record.destination_bytes.to_be_bytes().to_vec(),
+            ),
+            (ie::FIREWALL_EVENT, vec![event]),
+        ];
+
+        // Set headers and the template record header are 4 bytes each
+        let template_set_len = 8 + 4 * fields.len();
+        let data_set_len = 4 + fields.iter().map(|(_, value)| value.len()).sum::<usize>();
+        let length = 16 + template_set_len + data_set_len;
+
+        let mut message = Vec::with_capacity(length);
+        message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+        message.extend_from_slice(&(length as u16).to_be_bytes());
+        message.extend_from_slice(&((unix_millis(export_time) / 1000) as u32).to_be_bytes());
+        message.extend_from_slice(&self.sequence.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        // Observation domain
+        message.extend_from_slice(&0u32.to_be_bytes());
+
+        message.extend_from_slice(&IPFIX_TEMPLATE_SET_ID.to_be_bytes());
+        message.extend_from_slice(&(template_set_len as u16).to_be_bytes());
+        message.extend_from_slice(&template_id.to_be_bytes());
+        message.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (id, value) in &fields {
+            message.extend_from_slice(&id.to_be_bytes());
+            message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        }
+
+        message.extend_from_slice(&template_id.to_be_bytes());
+        message.extend_from_slice(&(data_set_len as u16).to_be_bytes());
+        for (_, value) in &fields {
+            message.extend_from_slice(value);
+        }
+        Some(message)
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Flow tracking in front of the registered sinks
+#[derive(Debug)]
+pub struct FlowExporter {
+    shards: Vec<Mutex<FlowTable>>,
+    last_sweep: Mutex<Option<Instant>>,
+    sinks: RwLock<Vec<Arc<dyn FlowSink>>>,
+}
+
+impl FlowExporter {
+    /// Create an exporter tracking at most the flows allowed by the `feature`
+    pub fn new(feature: Option<&FeatureFlowExport>) -> Self {
+        let max_flows = feature.cloned().unwrap_or_default().max_flows as usize;
+        let capacity = max_flows.div_ceil(FLOW_SHARDS);
+        Self {
+            shards: (0..FLOW_SHARDS)
+                .map(|_| Mutex::new(FlowTable::new(capacity)))
+                .collect(),
+            last_sweep: Mutex::new(None),
+            sinks: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Register another sink. Flows are tracked only while there is at least one sink.
+    pub fn add_sink(&self, sink: Arc<dyn FlowSink>) {
+        self.sinks.write().push(sink);
+    }
+
+    /// Account for a packet exchanged with the `peer`
+    pub(crate) fn observe(
+        &self,
+        peer: &[u8; 32],
+        direction: PacketDirection,
+        buffer: &[u8],
+        verdict: VerdictAction,
+    ) {
+        if self.sinks.read().is_empty() {
+            return;
+        }
+        let Some(packet) = Summary::parse(buffer) else {
+            return;
+        };
+        let key = FlowKey::new(PublicKey(*peer), direction, &packet);
+        let evicted = self.shard(&key).and_then(|shard| {
+            shard
+                .lock()
+                .observe(key, direction, &packet, verdict, Instant::now())
+        });
+        if let Some(record) = evicted {
+            self.export(&[record]);
+        }
+    }
+
+    /// End the flows of the connections the `conntrack` no longer keeps open, at most once
+    /// per [FLOW_SWEEP_INTERVAL]
+    pub(crate) fn sweep(&self, conntrack: &Conntrack) {
+        let now = Instant::now();
+        {
+            // Another packet is sweeping already
+            let Some(mut last_sweep) = self.last_sweep.try_lock() else {
+                return;
+            };
+            if last_sweep
+                .is_some_and(|last| now.saturating_duration_since(last) < FLOW_SWEEP_INTERVAL)
+            {
+                return;
+            }
+            *last_sweep = Some(now);
+        }
+        if self.sinks.read().is_empty() {
+            return;
+        }
+
+        let mut open = HashSet::default();
+        for entry in conntrack.open_entries() {
+            if let Some(peer) = entry.peer {
+                open.insert((peer, entry.protocol, entry.source, entry.destination));
+                open.insert((peer, entry.protocol, entry.destination, entry.source));
+            }
+        }
+        let finished: Vec<FlowRecord> = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.lock().finish_closed(&open))
+            .collect();
+        self.export(&finished);
+    }
+
+    /// Export all the flows, as if they have just ended
+    pub fn flush(&self) {
+        let finished: Vec<FlowRecord> = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.lock().flush())
+            .collect();
+        self.export(&finished);
+    }
+
+    fn shard(&self, key: &FlowKey) -> Option<&Mutex<FlowTable>> {
+        let mut hasher = FxHasher::default();
+        key.hash(&mut hasher);
+        self.shards
+            .get(hasher.finish() as usize % self.shards.len().max(1))
+    }
+
+    fn export(&self, records: &[FlowRecord]) {
+        if records.is_empty() {
+            return;
+        }
+        let sinks = self.sinks.read().clone();
+        for record in records {
+            for sink in &sinks {
+                sink.export(record);
+            }
+        }
+    }
+}
+
+/// Connection tracked by the conntrack: the peer, the protocol name and the endpoints
+type OpenConnection = (PublicKey, &'static str, SocketAddr, SocketAddr);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FlowKey {
+    peer: PublicKey,
+    protocol: PacketProtocol,
+    local: SocketAddr,
+    remote: SocketAddr,
+}
+
+impl FlowKey {
+    fn new(peer: PublicKey, direction: PacketDirection, packet: &Summary) -> Self {
+        let (local, remote) = match direction {
+            PacketDirection::Outbound => (packet.source, packet.destination),
+            PacketDirection::Inbound => (packet.destination, packet.source),
+        };
+        Self {
+            peer,
+            protocol: packet.protocol,
+            local,
+            remote,
+        }
+    }
+
+    /// The connection of the flow, as listed by the conntrack
+    fn connection(&self) -> OpenConnection {
+        let protocol = match self.protocol {
+            PacketProtocol::Tcp => "tcp",
+            PacketProtocol::Udp => "udp",
+            PacketProtocol::Icmp | PacketProtocol::Icmpv6 => "icmp",
+        };
+        (self.peer, protocol, self.local, self.remote)
+    }
+}
+
+#[derive(Debug)]
+struct Flow {
+    record: FlowRecord,
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+impl Flow {
+    fn finish(mut self) -> FlowRecord {
+        self.record.duration = self.last_seen.saturating_duration_since(self.first_seen);
+        self.record
+    }
+}
+
+/// Transport level summary of a packet
+pub(crate) struct Summary {
+    pub(crate) protocol: PacketProtocol,
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+    pub(crate) length: u64,
+    pub(crate) tcp_flags: u8,
+}
+
+impl Summary {
+    pub(crate) fn parse(buffer: &[u8]) -> Option<Self> {
+        match buffer.first().map(|b| b >> 4) {
+            Some(4) => {
+                let ip = Ipv4Packet::new(buffer)?;
+                Self::parse_transport(
+                    ip.get_source().into(),
+                    ip.get_destination().into(),
+                    ip.get_next_level_protocol(),
+                    ip.payload(),
+                    buffer.len(),
+                )
+            }
+            Some(6) => {
+                let ip = Ipv6Packet::new(buffer)?;
+                Self::parse_transport(
+                    ip.get_source().into(),
+                    ip.get_destination().into(),
+                    ip.get_next_header(),
+                    ip.payload(),
+                    buffer.len(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_transport(
+        source: IpAddr,
+        destination: IpAddr,
+        protocol: IpNextHeaderProtocol,
+        payload: &[u8],
+        length: usize,
+    ) -> Option<Self> {
+        let (protocol, source_port, destination_port, tcp_flags) = match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                let tcp = TcpPacket::new(payload)?;
+                (
+                    PacketProtocol::Tcp,
+                    tcp.get_source(),
+                    tcp.get_destination(),
+                    tcp.get_flags(),
+                )
+            }
+            IpNextHeaderProtocols::Udp => {
+                let udp = UdpPacket::new(payload)?;
+                (
+                    PacketProtocol::Udp,
+                    udp.get_source(),
+                    udp.get_destination(),
+                    0,
+                )
+            }
+            IpNextHeaderProtocols::Icmp => (PacketProtocol::Icmp, 0, 0, 0),
+            IpNextHeaderProtocols::Icmpv6 => (PacketProtocol::Icmpv6, 0, 0, 0),
+            _ => return None,
+        };
+        Some(Self {
+            protocol,
+            source: SocketAddr::new(source, source_port),
+            destination: SocketAddr::new(destination, destination_port),
+            length: length as u64,
+            tcp_flags,
+        })
+    }
+}
+
+/// Bounded set of active flows
+#[derive(Debug)]
+struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    capacity: usize,
+}
+
+impl FlowTable {
+    fn new(capacity: usize) -> Self {
+        Self {
+            flows: HashMap::default(),
+            capacity,
+        }
+    }
+
+    /// Account for a packet of the flow, returning the flow evicted to make room for it
+    fn observe(
+        &mut self,
+        key: FlowKey,
+        direction: PacketDirection,
+        packet: &Summary,
+        verdict: VerdictAction,
+        now: Instant,
+    ) -> Option<FlowRecord> {
+        let mut evicted = None;
+        if !self.flows.contains_key(&key) {
+            if self.capacity == 0 {
+                return None;
+            }
+            if self.flows.len() >= self.capacity {
+                evicted = self.evict_oldest();
+            }
+            self.flows.insert(
+                key,
+                Flow {
+                    record: FlowRecord {
+                        peer: key.peer,
+                        protocol: packet.protocol,
+                        source: packet.source,
+                        destination: packet.destination,
+                        direction,
+                        source_packets: 0,
+                        source_bytes: 0,
+                        destination_packets: 0,
+                        destination_bytes: 0,
+                        start: SystemTime::now(),
+                        duration: Duration::ZERO,
+                        verdict,
+                    },
+                    first_seen: now,
+                    last_seen: now,
+                },
+            );
+        }
+
+        if let Some(flow) = self.flows.get_mut(&key) {
+            flow.last_seen = now;
+            if verdict == VerdictAction::Accept {
+                flow.record.verdict = VerdictAction::Accept;
+            }
+            if flow.record.direction == direction {
+                flow.record.source_packets += 1;
+                flow.record.source_bytes += packet.length;
+            } else {
+                flow.record.destination_packets += 1;
+                flow.record.destination_bytes += packet.length;
+            }
+        }
+        evicted
+    }
+
+    /// Remove the flows whose connections are not `open` anymore
+    fn finish_closed(&mut self, open: &HashSet<OpenConnection>) -> Vec<FlowRecord> {
+        let closed: Vec<FlowKey> = self
+            .flows
+            .keys()
+            .filter(|key| !open.contains(&key.connection()))
+            .copied()
+            .collect();
+        closed
+            .iter()
+            .filter_map(|key| self.flows.remove(key))
+            .map(Flow::finish)
+            .collect()
+    }
+
+    /// Remove all the flows
+    fn flush(&mut self) -> Vec<FlowRecord> {
+        self.flows.drain().map(|(_, flow)| flow.finish()).collect()
+    }
+
+    /// Make room for a new flow by ending the least recently active one
+    fn evict_oldest(&mut self) -> Option<FlowRecord> {
+        let oldest = self
+            .flows
+            .iter()
+            .min_by_key(|(_, flow)| flow.last_seen)
+            .map(|(key, _)| *key)?;
+        self.flows.remove(&oldest).map(Flow::finish)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet_packet::{
+        ipv4::MutableIpv4Packet,
+        tcp::{MutableTcpPacket, TcpFlags},
+        udp::MutableUdpPacket,
+        MutablePacket,
+    };
+    use std::{convert::TryInto, net::SocketAddrV4};
+
+    use super::*;
+
+    const PEER: PublicKey = PublicKey([1; 32]);
+    const LOCAL: &str = "100.64.0.1:4000";
+    const REMOTE: &str = "100.64.0.2:80";
+
+    fn ipv4(protocol: IpNextHeaderProtocol, src: &str, dst: &str, payload: usize) -> Vec<u8> {
+        let src: SocketAddrV4 = src.parse().unwrap();
+        let dst: SocketAddrV4 = dst.parse().unwrap();
+        let mut raw = vec![0u8; 20 + payload];
+        let mut ip = MutableIpv4Packet::new(&mut raw).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((20 + payload) as u16);
+        ip.set_next_level_protocol(protocol);
+        ip.set_source(*src.ip());
+        ip.set_destination(*dst.ip());
+        raw
+    }
+
+    fn tcp(src: &str, dst: &str, flags: u8) -> Vec<u8> {
+        let mut raw = ipv4(IpNextHeaderProtocols::Tcp, src, dst, 20);
+        let mut ip = MutableIpv4Packet::new(&mut raw).unwrap();
+        let mut tcp = MutableTcpPacket::new(ip.payload_mut()).unwrap();
+        tcp.set_source(src.parse::<SocketAddrV4>().unwrap().port());
+        tcp.set_destination(dst.parse::<SocketAddrV4>().unwrap().port());
+        tcp.set_data_offset(5);
+        tcp.set_flags(flags);
+        raw
+    }
+
+    fn udp(src: &str, dst: &str) -> Vec<u8> {
+        let mut raw = ipv4(IpNextHeaderProtocols::Udp, src, dst, 8);
+        let mut ip = MutableIpv4Packet::new(&mut raw).unwrap();
+        let mut udp = MutableUdpPacket::new(ip.payload_mut()).unwrap();
+        udp.set_source(src.parse::<SocketAddrV4>().unwrap().port());
+        udp.set_destination(dst.parse::<SocketAddrV4>().unwrap().port());
+        udp.set_length(8);
+        raw
+    }
+
+    fn observe(
+        table: &mut FlowTable,
+        direction: PacketDirection,
+        buffer: &[u8],
+        verdict: VerdictAction,
+        now: Instant,
+    ) -> Option<FlowRecord> {
+        let packet = Summary::parse(buffer).unwrap();
+        let key = FlowKey::new(PEER, direction, &packet);
+        table.observe(key, direction, &packet, verdict, now)
+    }
+
+    fn open(source: &str, destination: &str, protocol: &'static str) -> HashSet<OpenConnection> {
+        let (source, destination) = (source.parse().unwrap(), destination.parse().unwrap());
+        IntoIterator::into_iter([
+            (PEER, protocol, source, destination),
+            (PEER, protocol, destination, source),
+        ])
+        .collect()
+    }
+
+    #[test]
+    fn flow_lasts_while_its_connection_is_open() {
+        let mut table = FlowTable::new(16);
+        let start = Instant::now();
+        let out = PacketDirection::Outbound;
+        let inb = PacketDirection::Inbound;
+        let accept = VerdictAction::Accept;
+
+        for (direction, packet, at) in [
+            (out, tcp(LOCAL, REMOTE, TcpFlags::SYN), 0),
+            (inb, tcp(REMOTE, LOCAL, TcpFlags::SYN | TcpFlags::ACK), 10),
+            (out, tcp(LOCAL, REMOTE, TcpFlags::ACK), 20),
+            (out, tcp(LOCAL, REMOTE, TcpFlags::FIN), 30),
+            (inb, tcp(REMOTE, LOCAL, TcpFlags::FIN), 40),
+        ] {
+            let now = start + Duration::from_millis(at);
+            assert!(observe(&mut table, direction, &packet, accept, now).is_none());
+        }
+
+        assert!(table.finish_closed(&open(LOCAL, REMOTE, "tcp")).is_empty());
+        assert_eq!(table.finish_closed(&open(LOCAL, REMOTE, "udp")).len(), 1);
+        assert!(table.flows.is_empty());
+    }
+
+    #[test]
+    fn flow_is_summarized_when_finished() {
+        let mut table = FlowTable::new(16);
+        let start = Instant::now();
+        let out = PacketDirection::Outbound;
+        let inb = PacketDirection::Inbound;
+
+        for (direction, packet, verdict, at) in [
+            (
+                inb,
+                tcp(REMOTE, LOCAL, TcpFlags::SYN),
+                VerdictAction::Drop,
+                0,
+            ),
+            (
+                inb,
+                tcp(REMOTE, LOCAL, TcpFlags::SYN),
+                VerdictAction::Accept,
+                10,
+            ),
+            (
+                out,
+                tcp(LOCAL, REMOTE, TcpFlags::SYN | TcpFlags::ACK),
+                VerdictAction::Accept,
+                20,
+            ),
+        ] {
+            let now = start + Duration::from_millis(at);
+            observe(&mut table, direction, &packet, verdict, now);
+        }
+
+        let finished = table.finish_closed(&HashSet::default());
+        assert_eq!(finished.len(), 1);
+        let record = &finished[0];
+        assert_eq!(record.protocol, PacketProtocol::Tcp);
+        assert_eq!(record.source, REMOTE.parse().unwrap());
+        assert_eq!(record.destination, LOCAL.parse().unwrap());
+        assert_eq!(record.direction, PacketDirection::Inbound);
+        assert_eq!((record.source_packets, record.source_bytes), (2, 80));
+        assert_eq!(
+            (record.destination_packets, record.destination_bytes),
+            (1, 40)
+        );
+        assert_eq!(record.duration, Duration::from_millis(20));
+        assert_eq!(record.verdict, VerdictAction::Accept);
+    }
+
+    #[test]
+    fn icmp_flow_matches_the_icmp_connection() {
+        let mut table = FlowTable::new(16);
+        let packet = ipv4(IpNextHeaderProtocols::Icmp, LOCAL, REMOTE, 8);
+        observe(
+            &mut table,
+            PacketDirection::Outbound,
+            &packet,
+            VerdictAction::Accept,
+            Instant::now(),
+        );
+
+        assert!(table
+            .finish_closed(&open("100.64.0.1:0", "100.64.0.2:0", "icmp"))
+            .is_empty());
+        assert_eq!(table.flush().len(), 1);
+    }
+
+    #[test]
+    fn oldest_flow_is_evicted_when_full() {
+        let mut table = FlowTable::new(1);
+        let start = Instant::now();
+        let out = PacketDirection::Outbound;
+        let accept = VerdictAction::Accept;
+        assert!(observe(&mut table, out, &udp(LOCAL, REMOTE), accept, start).is_none());
+
+        let evicted = observe(&mut table, out, &udp(LOCAL, "100.64.0.3:53"), accept, start);
+        assert_eq!(evicted.unwrap().destination, REMOTE.parse().unwrap());
+        assert_eq!(table.flows.len(), 1);
+    }
+
+    #[test]
+    fn unknown_packets_are_ignored() {
+        let exporter = FlowExporter::new(None);
+        #[derive(Debug)]
+        struct Sink;
+        impl FlowSink for Sink {
+            fn export(&self, _record: &FlowRecord) {}
+        }
+        exporter.add_sink(Arc::new(Sink));
+
+        let packet = ipv4(IpNextHeaderProtocols::Gre, LOCAL, REMOTE, 4);
+        for buffer in [&packet[..], &[0xff; 3][..]] {
+            exporter.observe(
+                &PEER.0,
+                PacketDirection::Outbound,
+                buffer,
+                VerdictAction::Accept,
+            );
+        }
+        assert!(exporter
+            .shards
+            .iter()
+            .all(|shard| shard.lock().flows.is_empty()));
+    }
+
+    #[test]
+    fn record_is_encoded_as_ipfix() {
+        let record = FlowRecord {
+            peer: PEER,
+            protocol: PacketProtocol::Udp,
+            source: LOCAL.parse().unwrap(),
+            destination: REMOTE.parse().unwrap(),
+            direction: PacketDirection::Outbound,
+            source_packets: 2,
+            source_bytes: 56,
+            destination_packets: 1,
+            destination_bytes: 28,
+            start: UNIX_EPOCH + Duration::from_secs(100),
+            duration: Duration::from_millis(1500),
+            verdict: VerdictAction::Accept,
+        };
+        let encoder = IpfixEncoder::default();
+        let export_time = UNIX_EPOCH + Duration::from_secs(200);
+        let message = encoder.encode(&record, export_time).unwrap();
+
+        let u16_at = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(message[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_be_bytes(message[at..at + 8].try_into().unwrap());
+
+        // Header
+        assert_eq!(u16_at(0), IPFIX_VERSION);
+        assert_eq!(u16_at(2) as usize, message.len());
+        assert_eq!(u32_at(4), 200);
+        assert_eq!(u32_at(8), 0);
+
+        // Template set of 13 fields
+        assert_eq!(u16_at(16), IPFIX_TEMPLATE_SET_ID);
+        assert_eq!(u16_at(18), 60);
+        assert_eq!(u16_at(20), IPFIX_TEMPLATE_IPV4);
+        assert_eq!(u16_at(22), 13);
+        assert_eq!((u16_at(24), u16_at(26)), (ie::SOURCE_IPV4_ADDRESS, 4));
+
+        // Data set
+        let data = 16 + 60;
+        assert_eq!(u16_at(data), IPFIX_TEMPLATE_IPV4);
+        assert_eq!(u16_at(data + 2) as usize, message.len() - data);
+        assert_eq!(message[data + 4..data + 8], [100, 64, 0, 1]);
+        assert_eq!(message[data + 8..data + 12], [100, 64, 0, 2]);
+        assert_eq!((u16_at(data + 12), u16_at(data + 14)), (4000, 80));
+        assert_eq!(message[data + 16..data + 18], [17, 1]);
+        assert_eq!(u64_at(data + 18), 100_000);
+        assert_eq!(u64_at(data + 26), 101_500);
+        assert_eq!((u64_at(data + 34), u64_at(data + 42)), (2, 1));
+        assert_eq!((u64_at(data + 50), u64_at(data + 58)), (56, 28));
+        assert_eq!(message[data + 66..], [2]);
+
+        // Sequence counts the records
+        let message = encoder.encode(&record, export_time).unwrap();
+        assert_eq!(u32::from_be_bytes(message[8..12].try_into().unwrap()), 1);
+
+        let mut mixed = record;
+        mixed.destination = "[::1]:80".parse().unwrap();
+        assert!(encoder.encode(&mixed, export_time).is_none());
+    }
+}
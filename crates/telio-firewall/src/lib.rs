@@ -3,12 +3,25 @@
 //! Implements stateful firewall to keep track of
 //! initiated connections, and deny inbound packet
 //! from an unrecognized source
+pub mod accounting;
+pub mod approval;
+pub mod category;
 pub(crate) mod chain;
 pub(crate) mod chain_helpers;
 pub(crate) mod conntrack;
 pub(crate) mod error;
+#[cfg(target_os = "linux")]
+pub mod exit_node;
 pub(crate) mod ffi_chain;
 pub mod firewall;
+pub mod flow;
 pub(crate) mod libfirewall_api;
 pub(crate) mod log;
 pub(crate) mod packet;
+pub mod shaping;
+#[cfg(target_os = "linux")]
+pub mod split_tunnel;
+#[cfg(target_os = "linux")]
+pub mod steering;
+#[cfg(windows)]
+pub mod wfp;
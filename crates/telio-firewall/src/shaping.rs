@@ -0,0 +1,298 @@
+//! Egress bandwidth shaping of the traffic to the peers.
+//!
+//! Peers are assigned to the bandwidth classes of [FeatureShaping]. Every class is a token bucket
+//! filled at the rate of the class up to its burst. A packet sent to a peer of the class takes as
+//! many tokens as it is long, and is dropped when there are not enough of them, so that the
+//! sender backs off. When the total limit is set, the packets also take the tokens of the total
+//! bucket, where the bursts of the classes with a higher priority are kept in reserve: a class
+//! may only take the tokens above the reserve, so a bulk transfer cannot starve the classes with
+//! a higher priority. The traffic to the peers in no class is not shaped.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use telio_crypto::PublicKey;
+use telio_model::features::{FeatureBandwidthLimit, FeatureShaping};
+use telio_utils::{telio_log_debug, Instant};
+
+use crate::firewall::HashMap;
+
+/// Tokens are kept in byte nanoseconds, so that no fraction of a byte is lost between packets
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Traffic of a bandwidth class since the firewall was created
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BandwidthClassStats {
+    /// Name of the class
+    pub name: String,
+    /// Bytes sent to the peers of the class
+    pub sent_bytes: u64,
+    /// Packets dropped over the limits
+    pub dropped_packets: u64,
+}
+
+struct TokenBucket {
+    /// Bytes per second
+    rate: u128,
+    capacity: u128,
+    tokens: u128,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &FeatureBandwidthLimit, now: Instant) -> Self {
+        let capacity = u128::from(limit.burst_bytes) * NANOS_PER_SEC;
+        Self {
+            // 1 kbps is 125 bytes per second
+            rate: u128::from(limit.rate_kbps) * 125,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_nanos();
+        self.tokens = self
+            .tokens
+            .saturating_add(elapsed.saturating_mul(self.rate))
+            .min(self.capacity);
+        self.updated = self.updated.max(now);
+    }
+
+    /// Whether `cost` tokens can be taken leaving at least `reserve` of them
+    fn has(&self, cost: u128, reserve: u128) -> bool {
+        self.tokens >= cost.saturating_add(reserve)
+    }
+
+    fn take(&mut self, cost: u128) {
+        self.tokens = self.tokens.saturating_sub(cost);
+    }
+}
+
+struct Class {
+    stats: BandwidthClassStats,
+    bucket: TokenBucket,
+    /// Tokens of the total bucket kept for the classes with a higher priority
+    reserve: u128,
+}
+
+struct Shaping {
+    classes: Vec<Class>,
+    total: Option<TokenBucket>,
+    /// Classes of the listed peers, by index
+    peers: HashMap<PublicKey, usize>,
+    /// Class of the exit node, by index
+    exit_node_class: Option<usize>,
+    exit_node: Option<PublicKey>,
+}
+
+impl Shaping {
+    fn class_of(&self, peer: &PublicKey) -> Option<usize> {
+        self.peers.get(peer).copied().or_else(|| {
+            self.exit_node_class
+                .filter(|_| self.exit_node.as_ref() == Some(peer))
+        })
+    }
+}
+
+/// Bandwidth limits of the traffic to the peers
+pub(crate) struct Shaper {
+    shaping: Mutex<Shaping>,
+}
+
+impl Shaper {
+    pub(crate) fn new(config: &FeatureShaping) -> Self {
+        let now = Instant::now();
+        let classes = config
+            .classes
+            .iter()
+            .map(|class| Class {
+                stats: BandwidthClassStats {
+                    name: class.name.clone(),
+                    sent_bytes: 0,
+                    dropped_packets: 0,
+                },
+                bucket: TokenBucket::new(&class.limit, now),
+                reserve: config
+                    .classes
+                    .iter()
+                    .filter(|other| other.priority > class.priority)
+                    .map(|other| u128::from(other.limit.burst_bytes) * NANOS_PER_SEC)
+                    .sum(),
+            })
+            .collect();
+        // The first class listing a peer wins, the validation rejects peers in several classes
+        let mut peers = HashMap::default();
+        for (index, class) in config.classes.iter().enumerate() {
+            for peer in &class.peers {
+                peers.entry(*peer).or_insert(index);
+            }
+        }
+
+        Self {
+            shaping: Mutex::new(Shaping {
+                classes,
+                total: config
+                    .total
+                    .as_ref()
+                    .map(|limit| TokenBucket::new(limit, now)),
+                peers,
+                exit_node_class: config.classes.iter().position(|class| class.exit_node),
+                exit_node: None,
+            }),
+        }
+    }
+
+    /// Shape the traffic to the `exit_node` by the class of the exit node
+    pub(crate) fn set_exit_node(&self, exit_node: Option<PublicKey>) {
+        self.shaping.lock().exit_node = exit_node;
+    }
+
+    /// Whether a packet of `len` bytes can be sent to the `peer` now
+    pub(crate) fn admit(&self, peer: &PublicKey, len: usize, now: Instant) -> bool {
+        let mut shaping = self.shaping.lock();
+        let Some(index) = shaping.class_of(peer) else {
+            return true;
+        };
+        let Shaping { classes, total, .. } = &mut *shaping;
+        let Some(class) = classes.get_mut(index) else {
+            return true;
+        };
+
+        let cost = len as u128 * NANOS_PER_SEC;
+        class.bucket.refill(now);
+        if let Some(total) = total.as_mut() {
+            total.refill(now);
+        }
+        let admitted = class.bucket.has(cost, 0)
+            && total
+                .as_ref()
+                .is_none_or(|total| total.has(cost, class.reserve));
+
+        if admitted {
+            class.bucket.take(cost);
+            if let Some(total) = total.as_mut() {
+                total.take(cost);
+            }
+            class.stats.sent_bytes += len as u64;
+        } else {
+            if class.stats.dropped_packets == 0 {
+                telio_log_debug!("Bandwidth class {} is over its limit", class.stats.name);
+            }
+            class.stats.dropped_packets += 1;
+        }
+        admitted
+    }
+
+    pub(crate) fn stats(&self) -> Vec<BandwidthClassStats> {
+        self.shaping
+            .lock()
+            .classes
+            .iter()
+            .map(|class| class.stats.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use telio_model::features::FeatureBandwidthClass;
+
+    const BACKUP: PublicKey = PublicKey([1; 32]);
+    const CALLS: PublicKey = PublicKey([2; 32]);
+    const OTHER: PublicKey = PublicKey([3; 32]);
+
+    fn class(name: &str, rate_kbps: u32, burst_bytes: u32, priority: u8) -> FeatureBandwidthClass {
+        FeatureBandwidthClass {
+            name: name.to_owned(),
+            limit: FeatureBandwidthLimit {
+                rate_kbps,
+                burst_bytes,
+            },
+            priority,
+            peers: vec![],
+            exit_node: false,
+        }
+    }
+
+    #[test]
+    fn class_is_limited_to_its_rate() {
+        // 8 kbps is 1000 bytes per second
+        let shaper = Shaper::new(&FeatureShaping {
+            total: None,
+            classes: vec![FeatureBandwidthClass {
+                peers: vec![BACKUP],
+                ..class("backup", 8, 1500, 0)
+            }],
+        });
+        let now = Instant::now();
+
+        assert!(shaper.admit(&BACKUP, 1000, now));
+        assert!(!shaper.admit(&BACKUP, 1000, now));
+        assert!(shaper.admit(&OTHER, 1000, now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(shaper.admit(&BACKUP, 1000, later));
+        assert!(!shaper.admit(&BACKUP, 1, later));
+
+        assert_eq!(
+            shaper.stats(),
+            vec![BandwidthClassStats {
+                name: "backup".to_owned(),
+                sent_bytes: 2000,
+                dropped_packets: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn higher_priority_keeps_its_burst_in_reserve() {
+        let shaper = Shaper::new(&FeatureShaping {
+            total: Some(FeatureBandwidthLimit {
+                rate_kbps: 8,
+                burst_bytes: 3000,
+            }),
+            classes: vec![
+                FeatureBandwidthClass {
+                    peers: vec![BACKUP],
+                    ..class("backup", 100_000, 3000, 0)
+                },
+                FeatureBandwidthClass {
+                    peers: vec![CALLS],
+                    ..class("calls", 100_000, 1500, 1)
+                },
+            ],
+        });
+        let now = Instant::now();
+
+        // Backup may only use the total bucket above the burst of the calls
+        assert!(shaper.admit(&BACKUP, 1500, now));
+        assert!(!shaper.admit(&BACKUP, 100, now));
+        assert!(shaper.admit(&CALLS, 1500, now));
+        assert!(!shaper.admit(&CALLS, 100, now));
+    }
+
+    #[test]
+    fn exit_node_is_shaped_by_its_class() {
+        let shaper = Shaper::new(&FeatureShaping {
+            total: None,
+            classes: vec![FeatureBandwidthClass {
+                exit_node: true,
+                ..class("vpn", 8, 1500, 0)
+            }],
+        });
+        let now = Instant::now();
+
+        assert!(shaper.admit(&OTHER, 1500, now));
+        assert!(shaper.admit(&OTHER, 1500, now));
+
+        shaper.set_exit_node(Some(OTHER));
+        assert!(shaper.admit(&OTHER, 1500, now));
+        assert!(!shaper.admit(&OTHER, 1500, now));
+
+        shaper.set_exit_node(None);
+        assert!(shaper.admit(&OTHER, 1500, now));
+    }
+}
@@ -0,0 +1,286 @@
+//! Marks routing the split tunneled traffic around the tunnel on Linux.
+//!
+//! Packets of the excluded apps and to the excluded destinations get the fwmark of the
+//! encapsulated packets, so they follow the routing rule taking the encapsulated packets around
+//! the tunnel. The marks are set before the output routing decision, nftables in a route chain and
+//! iptables in the mangle table, so the kernel routes the marked packets again.
+//!
+//! The source address was picked by the first routing decision though, so the rerouted packets
+//! would still leave with the address of the tunnel. Their connections are therefore marked too,
+//! and masqueraded on the way out. The encapsulated packets get the fwmark from their socket and
+//! never the connection mark, so they are left alone. As with the exit node rules, nftables rules
+//! live in a dedicated table, while iptables rules are tagged with a comment.
+
+#![cfg(target_os = "linux")]
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use telio_utils::telio_log_info;
+
+pub use crate::exit_node::Error;
+use crate::exit_node::{
+    execute, iptables_bin, is_available, nft_family, remove_tagged_iptables_rules, Backend,
+};
+
+/// nftables table holding all of the rules
+const NFT_TABLE: &str = "libtelio_split_tunnel";
+
+/// Comment used to tag iptables rules
+const IPT_COMMENT: &str = "libtelio-split-tunnel";
+
+/// Traffic routed around the tunnel
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bypass {
+    /// Mark of the encapsulated packets
+    pub fwmark: u32,
+    /// Destination networks
+    pub routes: Vec<IpNet>,
+    /// UIDs of the apps
+    pub uids: Vec<u32>,
+}
+
+impl Bypass {
+    /// Whether no traffic is routed around the tunnel
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty() && self.uids.is_empty()
+    }
+}
+
+/// Manager of the split tunnel marking rules
+#[derive(Debug)]
+pub struct SplitTunnelRules {
+    backend: Backend,
+    /// Bypass currently installed
+    installed: Mutex<Option<Bypass>>,
+}
+
+impl SplitTunnelRules {
+    /// Pick the backend and remove rules left over from previous runs
+    pub fn new() -> Result<Self, Error> {
+        let backend = if is_available("nft") {
+            Backend::Nftables
+        } else if is_available("iptables") {
+            Backend::Iptables
+        } else {
+            return Err(Error::NoBackend);
+        };
+        telio_log_info!("Using {backend:?} for split tunnel rules");
+
+        let rules = Self {
+            backend,
+            installed: Mutex::new(None),
+        };
+        rules.remove_rules();
+        Ok(rules)
+    }
+
+    /// Route the traffic of the `bypass` around the tunnel.
+    ///
+    /// Passing an empty bypass is equivalent to [SplitTunnelRules::disable].
+    pub fn enable(&self, bypass: &Bypass) -> Result<(), Error> {
+        if bypass.is_empty() {
+            self.disable();
+            return Ok(());
+        }
+
+        let mut installed = self.installed.lock();
+        if installed.as_ref() == Some(bypass) {
+            return Ok(());
+        }
+
+        self.remove_rules();
+        *installed = None;
+        let res = match self.backend {
+            Backend::Nftables => install_nftables(bypass),
+            Backend::Iptables => install_iptables(bypass),
+        };
+        match res {
+            Ok(()) => *installed = Some(bypass.clone()),
+            Err(_) => self.remove_rules(),
+        }
+        res
+    }
+
+    /// Remove all of the rules
+    pub fn disable(&self) {
+        if self.installed.lock().take().is_some() {
+            self.remove_rules();
+        }
+    }
+
+    /// Remove every rule owned by libtelio, including ones from crashed runs
+    fn remove_rules(&self) {
+        match self.backend {
+            Backend::Nftables => {
+                // Fails when the table does not exist, which is fine
+                let _ = nft(&["delete", "table", "inet", NFT_TABLE]);
+            }
+            Backend::Iptables => {
+                for bin in ["iptables", "ip6tables"] {
+                    for table in ["mangle", "nat"] {
+                        remove_tagged_iptables_rules(bin, table, IPT_COMMENT);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SplitTunnelRules {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+fn install_nftables(bypass: &Bypass) -> Result<(), Error> {
+    let mark = bypass.fwmark.to_string();
+    nft(&["add", "table", "inet", NFT_TABLE])?;
+    nft(&[
+        "add", "chain", "inet", NFT_TABLE, "output", "{", "type", "route", "hook", "output",
+        "priority", "-150", ";", "}",
+    ])?;
+    nft(&[
+        "add",
+        "chain",
+        "inet",
+        NFT_TABLE,
+        "postrouting",
+        "{",
+        "type",
+        "nat",
+        "hook",
+        "postrouting",
+        "priority",
+        "100",
+        ";",
+        "}",
+    ])?;
+
+    for route in &bypass.routes {
+        let (family, route) = (nft_family(&route.addr()), route.to_string());
+        nft(&[
+            "add", "rule", "inet", NFT_TABLE, "output", family, "daddr", &route, "meta", "mark",
+            "set", &mark, "ct", "mark", "set", &mark,
+        ])?;
+    }
+    for uid in &bypass.uids {
+        nft(&[
+            "add",
+            "rule",
+            "inet",
+            NFT_TABLE,
+            "output",
+            "meta",
+            "skuid",
+            &uid.to_string(),
+            "meta",
+            "mark",
+            "set",
+            &mark,
+            "ct",
+            "mark",
+            "set",
+            &mark,
+        ])?;
+    }
+    nft(&[
+        "add",
+        "rule",
+        "inet",
+        NFT_TABLE,
+        "postrouting",
+        "ct",
+        "mark",
+        &mark,
+        "masquerade",
+    ])?;
+    Ok(())
+}
+
+fn install_iptables(bypass: &Bypass) -> Result<(), Error> {
+    let mark = bypass.fwmark.to_string();
+    for route in &bypass.routes {
+        iptables(
+            iptables_bin(&route.addr()),
+            &[
+                "-A",
+                "OUTPUT",
+                "-d",
+                &route.to_string(),
+                "-j",
+                "CONNMARK",
+                "--set-mark",
+                &mark,
+            ],
+        )?;
+    }
+    for uid in &bypass.uids {
+        for bin in ["iptables", "ip6tables"] {
+            iptables(
+                bin,
+                &[
+                    "-A",
+                    "OUTPUT",
+                    "-m",
+                    "owner",
+                    "--uid-owner",
+                    &uid.to_string(),
+                    "-j",
+                    "CONNMARK",
+                    "--set-mark",
+                    &mark,
+                ],
+            )?;
+        }
+    }
+    // A single target per rule, so the packets get the mark of their connection afterwards
+    for bin in ["iptables", "ip6tables"] {
+        iptables(
+            bin,
+            &[
+                "-A",
+                "OUTPUT",
+                "-m",
+                "connmark",
+                "--mark",
+                &mark,
+                "-j",
+                "MARK",
+                "--set-mark",
+                &mark,
+            ],
+        )?;
+        iptables(
+            bin,
+            &[
+                "-t",
+                "nat",
+                "-A",
+                "POSTROUTING",
+                "-m",
+                "connmark",
+                "--mark",
+                &mark,
+                "-j",
+                "MASQUERADE",
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn nft(args: &[&str]) -> Result<(), Error> {
+    execute("nft", args).map(|_| ())
+}
+
+/// Rule in the mangle table, unless the `args` name another one
+fn iptables(bin: &str, args: &[&str]) -> Result<(), Error> {
+    let table: &[&str] = if args.first() == Some(&"-t") {
+        &[]
+    } else {
+        &["-t", "mangle"]
+    };
+    let mut args = [table, args].concat();
+    args.extend(["-m", "comment", "--comment", IPT_COMMENT]);
+    execute(bin, &args).map(|_| ())
+}
@@ -0,0 +1,424 @@
+//! Marks and routes steering traffic classes to the interfaces of their exits on Linux.
+//!
+//! WireGuard picks the peer of a packet by its destination only, so the exits of the classes
+//! selecting their traffic by the source ports or the fwmark get interfaces of their own. Packets
+//! of those classes are marked before the output routing decision, nftables in a route chain and
+//! iptables in the mangle table, and a routing rule looks the marked packets up in a table routing
+//! everything to the interface of their exit. The source address picked by the first routing
+//! decision is the address of the tunnel, which the exits expect anyway. Encapsulated packets
+//! carry the fwmark of the tunnels and are never steered. The steering runs before the split
+//! tunnel marking, so the bypassed traffic stays off the tunnels.
+
+#![cfg(target_os = "linux")]
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use telio_model::config::PortRange;
+use telio_utils::{telio_log_info, telio_log_warn};
+
+pub use crate::exit_node::Error;
+use crate::exit_node::{
+    execute, iptables_bin, is_available, nft_family, remove_tagged_iptables_rules, Backend,
+};
+
+/// nftables table holding all of the marking rules
+const NFT_TABLE: &str = "libtelio_steering";
+
+/// Comment used to tag iptables rules
+const IPT_COMMENT: &str = "libtelio-steering";
+
+/// Priority of the routing rules, ahead of the rules routing the traffic into the tunnel
+const RULE_PRIORITY: &str = "1000";
+
+/// Class of traffic steered to an exit, matching all of its selectors
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SteeredClass {
+    /// Destination networks, all destinations when empty
+    pub networks: Vec<IpNet>,
+    /// Source ports of the TCP and UDP traffic
+    pub source_ports: Option<PortRange>,
+    /// Mark set on the traffic by the apps
+    pub fwmark: Option<u32>,
+}
+
+/// Exit with an interface of its own
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SteeredExit {
+    /// Interface of the exit
+    pub interface: String,
+    /// Mark of the steered packets, also the routing table of the interface
+    pub mark: u32,
+    /// Classes steered to the exit
+    pub classes: Vec<SteeredClass>,
+}
+
+/// Traffic steered to the interfaces of the exits
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Steering {
+    /// Mark of the encapsulated packets
+    pub fwmark: u32,
+    /// Whether the IPv6 traffic is routed as well
+    pub ipv6: bool,
+    /// Exits and their classes
+    pub exits: Vec<SteeredExit>,
+}
+
+/// Manager of the steering rules
+#[derive(Debug)]
+pub struct SteeringRules {
+    backend: Backend,
+    /// Steering currently installed
+    installed: Mutex<Option<Steering>>,
+}
+
+impl SteeringRules {
+    /// Pick the backend and remove rules left over from previous runs
+    pub fn new() -> Result<Self, Error> {
+        let backend = if is_available("nft") {
+            Backend::Nftables
+        } else if is_available("iptables") {
+            Backend::Iptables
+        } else {
+            return Err(Error::NoBackend);
+        };
+        telio_log_info!("Using {backend:?} for steering rules");
+
+        let rules = Self {
+            backend,
+            installed: Mutex::new(None),
+        };
+        rules.remove_rules();
+        Ok(rules)
+    }
+
+    /// Steer the traffic of the `steering`, the interfaces of the exits must exist already.
+    ///
+    /// Passing no exits is equivalent to [SteeringRules::disable].
+    pub fn enable(&self, steering: &Steering) -> Result<(), Error> {
+        if steering.exits.is_empty() {
+            self.disable();
+            return Ok(());
+        }
+
+        let mut installed = self.installed.lock();
+        if installed.as_ref() == Some(steering) {
+            return Ok(());
+        }
+
+        self.remove_rules();
+        *installed = None;
+        let res = self.install(steering);
+        match res {
+            Ok(()) => *installed = Some(steering.clone()),
+            Err(_) => self.remove_rules(),
+        }
+        res
+    }
+
+    /// Remove all of the rules
+    pub fn disable(&self) {
+        if self.installed.lock().take().is_some() {
+            self.remove_rules();
+        }
+    }
+
+    fn install(&self, steering: &Steering) -> Result<(), Error> {
+        for exit in &steering.exits {
+            install_routes(exit, steering.ipv6)?;
+        }
+        match self.backend {
+            Backend::Nftables => nftables_rules(steering)
+                .iter()
+                .try_for_each(|args| execute("nft", &as_strs(args)).map(|_| ())),
+            Backend::Iptables => iptables_rules(steering)
+                .iter()
+                .try_for_each(|(bin, args)| iptables(bin, &as_strs(args))),
+        }
+    }
+
+    /// Remove every rule owned by libtelio, including ones from crashed runs. Routes go away
+    /// together with the interfaces of the exits.
+    fn remove_rules(&self) {
+        match self.backend {
+            Backend::Nftables => {
+                // Fails when the table does not exist, which is fine
+                let _ = execute("nft", &["delete", "table", "inet", NFT_TABLE]);
+            }
+            Backend::Iptables => {
+                for bin in ["iptables", "ip6tables"] {
+                    remove_tagged_iptables_rules(bin, "mangle", IPT_COMMENT);
+                }
+            }
+        }
+        for family in ["-4", "-6"] {
+            // Fails once no rule of the priority is left
+            while execute("ip", &[family, "rule", "del", "priority", RULE_PRIORITY]).is_ok() {}
+        }
+    }
+}
+
+impl Drop for SteeringRules {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+/// Route everything looked up in the table of the `exit` to its interface
+fn install_routes(exit: &SteeredExit, ipv6: bool) -> Result<(), Error> {
+    let mark = exit.mark.to_string();
+    let families: &[&str] = if ipv6 { &["-4", "-6"] } else { &["-4"] };
+    for &family in families {
+        execute(
+            "ip",
+            &[
+                family,
+                "route",
+                "replace",
+                "default",
+                "dev",
+                &exit.interface,
+                "table",
+                &mark,
+            ],
+        )?;
+        execute(
+            "ip",
+            &[
+                family,
+                "rule",
+                "add",
+                "fwmark",
+                &mark,
+                "lookup",
+                &mark,
+                "priority",
+                RULE_PRIORITY,
+            ],
+        )?;
+    }
+
+    // Replies arrive on the interface of the exit, while the tunnel holds the route back
+    let rp_filter = format!("/proc/sys/net/ipv4/conf/{}/rp_filter", exit.interface);
+    if let Err(e) = std::fs::write(&rp_filter, "2") {
+        telio_log_warn!("Failed to loosen {rp_filter}: {e}");
+    }
+    Ok(())
+}
+
+/// Matches of the packets of the `class`, one list per rule. `port_range` formats the
+/// source ports for the backend.
+fn class_matches(
+    class: &SteeredClass,
+    port_range: impl Fn(PortRange) -> String,
+) -> Vec<(Option<IpNet>, Option<(&'static str, String)>)> {
+    let networks: Vec<Option<IpNet>> = if class.networks.is_empty() {
+        vec![None]
+    } else {
+        class.networks.iter().copied().map(Some).collect()
+    };
+    let ports: Vec<Option<(&'static str, String)>> = match class.source_ports {
+        Some(ports) => ["tcp", "udp"]
+            .iter()
+            .map(|proto| Some((*proto, port_range(ports))))
+            .collect(),
+        None => vec![None],
+    };
+    networks
+        .iter()
+        .flat_map(|network| ports.iter().map(move |ports| (*network, ports.clone())))
+        .collect()
+}
+
+fn nftables_rules(steering: &Steering) -> Vec<Vec<String>> {
+    let mut rules: Vec<Vec<String>> = vec![
+        as_strings(&["add", "table", "inet", NFT_TABLE]),
+        as_strings(&[
+            "add", "chain", "inet", NFT_TABLE, "output", "{", "type", "route", "hook", "output",
+            "priority", "-160", ";", "}",
+        ]),
+        as_strings(&[
+            "add",
+            "rule",
+            "inet",
+            NFT_TABLE,
+            "output",
+            "meta",
+            "mark",
+            &steering.fwmark.to_string(),
+            "return",
+        ]),
+    ];
+
+    for exit in &steering.exits {
+        for class in &exit.classes {
+            let port_range = |ports: PortRange| {
+                if ports.first == ports.last {
+                    ports.first.to_string()
+                } else {
+                    format!("{}-{}", ports.first, ports.last)
+                }
+            };
+            for (network, ports) in class_matches(class, port_range) {
+                let mut rule = as_strings(&["add", "rule", "inet", NFT_TABLE, "output"]);
+                if let Some(network) = network {
+                    rule.extend(as_strings(&[
+                        nft_family(&network.addr()),
+                        "daddr",
+                        &network.to_string(),
+                    ]));
+                }
+                if let Some((proto, range)) = ports {
+                    rule.extend(as_strings(&[proto, "sport", &range]));
+                }
+                if let Some(fwmark) = class.fwmark {
+                    rule.extend(as_strings(&["meta", "mark", &fwmark.to_string()]));
+                }
+                rule.extend(as_strings(&["meta", "mark", "set", &exit.mark.to_string()]));
+                rules.push(rule);
+            }
+        }
+    }
+    rules
+}
+
+/// Rules inserted at the top of the mangle OUTPUT chain in order, ahead of the split tunnel
+fn iptables_rules(steering: &Steering) -> Vec<(&'static str, Vec<String>)> {
+    let mut rules: Vec<(&'static str, Vec<String>)> = ["iptables", "ip6tables"]
+        .iter()
+        .map(|bin| {
+            (
+                *bin,
+                as_strings(&[
+                    "-m",
+                    "mark",
+                    "--mark",
+                    &steering.fwmark.to_string(),
+                    "-j",
+                    "RETURN",
+                ]),
+            )
+        })
+        .collect();
+
+    for exit in &steering.exits {
+        for class in &exit.classes {
+            let port_range = |ports: PortRange| format!("{}:{}", ports.first, ports.last);
+            for (network, ports) in class_matches(class, port_range) {
+                let mut rule = Vec::new();
+                if let Some(network) = network {
+                    rule.extend(as_strings(&["-d", &network.to_string()]));
+                }
+                if let Some((proto, range)) = ports {
+                    rule.extend(as_strings(&["-p", proto, "--sport", &range]));
+                }
+                if let Some(fwmark) = class.fwmark {
+                    rule.extend(as_strings(&["-m", "mark", "--mark", &fwmark.to_string()]));
+                }
+                rule.extend(as_strings(&[
+                    "-j",
+                    "MARK",
+                    "--set-mark",
+                    &exit.mark.to_string(),
+                ]));
+                match network {
+                    Some(network) => rules.push((iptables_bin(&network.addr()), rule)),
+                    None => rules.extend([("iptables", rule.clone()), ("ip6tables", rule)]),
+                }
+            }
+        }
+    }
+
+    // Position of each rule within the chain of its binary
+    let (mut v4, mut v6) = (0usize, 0usize);
+    rules
+        .into_iter()
+        .map(|(bin, rule)| {
+            let position = if bin == "ip6tables" { &mut v6 } else { &mut v4 };
+            *position += 1;
+            let mut args = as_strings(&["-I", "OUTPUT", &position.to_string()]);
+            args.extend(rule);
+            (bin, args)
+        })
+        .collect()
+}
+
+fn iptables(bin: &str, args: &[&str]) -> Result<(), Error> {
+    let mut args = [&["-t", "mangle"], args].concat();
+    args.extend(["-m", "comment", "--comment", IPT_COMMENT]);
+    execute(bin, &args).map(|_| ())
+}
+
+fn as_strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| (*arg).to_owned()).collect()
+}
+
+fn as_strs(args: &[String]) -> Vec<&str> {
+    args.iter().map(String::as_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steering() -> Steering {
+        Steering {
+            fwmark: 11673110,
+            ipv6: true,
+            exits: vec![SteeredExit {
+                interface: "nlxc0".to_owned(),
+                mark: 11673120,
+                classes: vec![
+                    SteeredClass {
+                        networks: vec!["198.51.100.0/24".parse().unwrap()],
+                        source_ports: Some(PortRange {
+                            first: 6881,
+                            last: 6889,
+                        }),
+                        fwmark: None,
+                    },
+                    SteeredClass {
+                        networks: Vec::new(),
+                        source_ports: None,
+                        fwmark: Some(42),
+                    },
+                ],
+            }],
+        }
+    }
+
+    fn joined(rules: impl IntoIterator<Item = Vec<String>>) -> Vec<String> {
+        rules.into_iter().map(|rule| rule.join(" ")).collect()
+    }
+
+    #[test]
+    fn nftables_rules_mark_the_selected_packets() {
+        assert_eq!(
+            joined(nftables_rules(&steering()))[2..],
+            [
+                "add rule inet libtelio_steering output meta mark 11673110 return",
+                "add rule inet libtelio_steering output ip daddr 198.51.100.0/24 tcp sport 6881-6889 meta mark set 11673120",
+                "add rule inet libtelio_steering output ip daddr 198.51.100.0/24 udp sport 6881-6889 meta mark set 11673120",
+                "add rule inet libtelio_steering output meta mark 42 meta mark set 11673120",
+            ]
+        );
+    }
+
+    #[test]
+    fn iptables_rules_keep_their_order_per_family() {
+        let rules: Vec<String> = iptables_rules(&steering())
+            .into_iter()
+            .map(|(bin, rule)| format!("{bin} {}", rule.join(" ")))
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                "iptables -I OUTPUT 1 -m mark --mark 11673110 -j RETURN",
+                "ip6tables -I OUTPUT 1 -m mark --mark 11673110 -j RETURN",
+                "iptables -I OUTPUT 2 -d 198.51.100.0/24 -p tcp --sport 6881:6889 -j MARK --set-mark 11673120",
+                "iptables -I OUTPUT 3 -d 198.51.100.0/24 -p udp --sport 6881:6889 -j MARK --set-mark 11673120",
+                "iptables -I OUTPUT 4 -m mark --mark 42 -j MARK --set-mark 11673120",
+                "ip6tables -I OUTPUT 2 -m mark --mark 42 -j MARK --set-mark 11673120",
+            ]
+        );
+    }
+}
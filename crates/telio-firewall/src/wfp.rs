@@ -0,0 +1,405 @@
+//! Killswitch and DNS leak protection implemented on top of
+//! the Windows Filtering Platform (WFP).
+//!
+//! All filters are installed into a dedicated sublayer owned by libtelio,
+//! so they can be identified and removed even after a crashed run.
+
+#![cfg(windows)]
+
+use std::net::{IpAddr, SocketAddr};
+
+use parking_lot::Mutex;
+use telio_model::features::FeatureKillswitch;
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn};
+use windows::{
+    core::{GUID, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::HANDLE,
+        NetworkManagement::WindowsFilteringPlatform::{
+            FwpmEngineClose0, FwpmEngineOpen0, FwpmFilterAdd0, FwpmFilterDeleteByKey0,
+            FwpmSubLayerAdd0, FwpmSubLayerDeleteByKey0, FwpmTransactionAbort0,
+            FwpmTransactionBegin0, FwpmTransactionCommit0, FWPM_ACTION0, FWPM_CONDITION_FLAGS,
+            FWPM_CONDITION_IP_LOCAL_INTERFACE, FWPM_CONDITION_IP_PROTOCOL,
+            FWPM_CONDITION_IP_REMOTE_ADDRESS, FWPM_CONDITION_IP_REMOTE_PORT, FWPM_DISPLAY_DATA0,
+            FWPM_FILTER0, FWPM_FILTER_CONDITION0, FWPM_FILTER_FLAGS, FWPM_FILTER_FLAG_BOOTTIME,
+            FWPM_FILTER_FLAG_PERSISTENT, FWPM_LAYER_ALE_AUTH_CONNECT_V4,
+            FWPM_LAYER_ALE_AUTH_CONNECT_V6, FWPM_SESSION0, FWPM_SUBLAYER0,
+            FWPM_SUBLAYER_FLAG_PERSISTENT, FWP_ACTION_BLOCK, FWP_ACTION_PERMIT, FWP_BYTE_ARRAY16,
+            FWP_BYTE_ARRAY16_TYPE, FWP_CONDITION_FLAG_IS_LOOPBACK, FWP_CONDITION_VALUE0,
+            FWP_MATCH_EQUAL, FWP_MATCH_FLAGS_ALL_SET, FWP_MATCH_TYPE, FWP_UINT16, FWP_UINT32,
+            FWP_UINT64, FWP_UINT8, FWP_VALUE0, FWP_VALUE0_0,
+        },
+        System::Rpc::RPC_C_AUTHN_WINNT,
+    },
+};
+
+/// Sublayer owning every filter installed by libtelio
+const SUBLAYER_KEY: GUID = GUID::from_u128(0x6c3b2f8e_1d4a_4b7e_9f21_5a0c7d3e9b10);
+
+/// Base key of the filters, n-th filter uses `FILTER_KEY_BASE + n`
+const FILTER_KEY_BASE: u128 = 0x6c3b2f8e_1d4a_4b7e_9f21_5a0c7d3e0000;
+
+/// Upper bound on the number of filters we can own, used for stale filter cleanup
+const MAX_FILTERS: u128 = 256;
+
+/// Returned by WFP when the object being deleted does not exist
+const FWP_E_NOT_FOUND: u32 = 0x8032_0008;
+const FWP_E_FILTER_NOT_FOUND: u32 = 0x8032_0003;
+const FWP_E_SUBLAYER_NOT_FOUND: u32 = 0x8032_0007;
+const FWP_E_ALREADY_EXISTS: u32 = 0x8032_0009;
+
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_TCP: u8 = 6;
+const DNS_PORT: u16 = 53;
+
+// Loopback goes above the DNS block, so the local resolvers keep answering
+const WEIGHT_PERMIT_TUNNEL: u8 = 15;
+const WEIGHT_PERMIT_LOOPBACK: u8 = 12;
+const WEIGHT_BLOCK_DNS: u8 = 10;
+const WEIGHT_PERMIT: u8 = 5;
+const WEIGHT_BLOCK_ALL: u8 = 0;
+
+/// WFP error
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// WFP API call returned an error code
+    #[error("{0} failed with error code {1:#x}")]
+    Wfp(&'static str, u32),
+    /// Too many endpoints were requested to be allowed outside of the tunnel
+    #[error("Too many filters requested")]
+    TooManyFilters,
+}
+
+/// Result type of the WFP killswitch
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+fn check(call: &'static str, code: u32) -> Result {
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(Error::Wfp(call, code))
+    }
+}
+
+/// Killswitch blocking any traffic outside of the tunnel interface
+pub struct WfpKillswitch {
+    engine: HANDLE,
+    config: FeatureKillswitch,
+    installed: Mutex<u128>,
+}
+
+// WFP engine handle can be used from any thread
+unsafe impl Send for WfpKillswitch {}
+unsafe impl Sync for WfpKillswitch {}
+
+impl WfpKillswitch {
+    /// Open WFP engine session and register libtelio sublayer.
+    ///
+    /// Filters left over from a previous (crashed) run are removed.
+    pub fn new(config: FeatureKillswitch) -> Result<Self> {
+        let mut engine = HANDLE::default();
+        let session = FWPM_SESSION0::default();
+        check("FwpmEngineOpen0", unsafe {
+            FwpmEngineOpen0(
+                PCWSTR::null(),
+                RPC_C_AUTHN_WINNT,
+                None,
+                Some(&session),
+                &mut engine,
+            )
+        })?;
+
+        let killswitch = Self {
+            engine,
+            config,
+            installed: Mutex::new(MAX_FILTERS),
+        };
+        killswitch.disable()?;
+        killswitch.add_sublayer()?;
+
+        Ok(killswitch)
+    }
+
+    /// Block all of the traffic except the one going through the tunnel interface
+    /// (identified by `tunnel_luid`), loopback and `allowed_endpoints`
+    /// (e.g. VPN server and relay servers which are reached outside of the tunnel).
+    pub fn enable(&self, tunnel_luid: u64, allowed_endpoints: &[SocketAddr]) -> Result {
+        self.transaction(|| {
+            self.delete_filters()?;
+
+            let mut luid = tunnel_luid;
+            let mut count = 0;
+            for layer in [
+                FWPM_LAYER_ALE_AUTH_CONNECT_V4,
+                FWPM_LAYER_ALE_AUTH_CONNECT_V6,
+            ] {
+                let mut tunnel = [condition(
+                    FWPM_CONDITION_IP_LOCAL_INTERFACE,
+                    FWP_MATCH_EQUAL,
+                    u64_value(&mut luid),
+                )];
+                self.add_filter(&mut count, layer, WEIGHT_PERMIT_TUNNEL, true, &mut tunnel)?;
+
+                let mut loopback = [condition(
+                    FWPM_CONDITION_FLAGS,
+                    FWP_MATCH_FLAGS_ALL_SET,
+                    u32_value(FWP_CONDITION_FLAG_IS_LOOPBACK),
+                )];
+                self.add_filter(
+                    &mut count,
+                    layer,
+                    WEIGHT_PERMIT_LOOPBACK,
+                    true,
+                    &mut loopback,
+                )?;
+
+                if self.config.block_dns_leaks {
+                    for proto in [IPPROTO_UDP, IPPROTO_TCP] {
+                        let mut dns = [
+                            condition(FWPM_CONDITION_IP_PROTOCOL, FWP_MATCH_EQUAL, u8_value(proto)),
+                            condition(
+                                FWPM_CONDITION_IP_REMOTE_PORT,
+                                FWP_MATCH_EQUAL,
+                                u16_value(DNS_PORT),
+                            ),
+                        ];
+                        self.add_filter(&mut count, layer, WEIGHT_BLOCK_DNS, false, &mut dns)?;
+                    }
+                }
+
+                self.add_filter(&mut count, layer, WEIGHT_BLOCK_ALL, false, &mut [])?;
+            }
+
+            for endpoint in allowed_endpoints {
+                let mut v6 = FWP_BYTE_ARRAY16::default();
+                let (layer, address) = match endpoint.ip() {
+                    IpAddr::V4(ip) => (FWPM_LAYER_ALE_AUTH_CONNECT_V4, u32_value(u32::from(ip))),
+                    IpAddr::V6(ip) => {
+                        v6.byteArray16 = ip.octets();
+                        (FWPM_LAYER_ALE_AUTH_CONNECT_V6, byte_array16_value(&mut v6))
+                    }
+                };
+                let mut conditions = [
+                    condition(FWPM_CONDITION_IP_REMOTE_ADDRESS, FWP_MATCH_EQUAL, address),
+                    condition(
+                        FWPM_CONDITION_IP_REMOTE_PORT,
+                        FWP_MATCH_EQUAL,
+                        u16_value(endpoint.port()),
+                    ),
+                ];
+                self.add_filter(&mut count, layer, WEIGHT_PERMIT, true, &mut conditions)?;
+            }
+
+            *self.installed.lock() = count;
+            telio_log_info!("WFP killswitch enabled with {} filters", count);
+            Ok(())
+        })
+    }
+
+    /// Remove all of the filters installed by the killswitch
+    pub fn disable(&self) -> Result {
+        self.transaction(|| self.delete_filters())?;
+        telio_log_info!("WFP killswitch disabled");
+        Ok(())
+    }
+
+    fn transaction(&self, f: impl FnOnce() -> Result) -> Result {
+        check("FwpmTransactionBegin0", unsafe {
+            FwpmTransactionBegin0(self.engine, 0)
+        })?;
+        match f() {
+            Ok(()) => check("FwpmTransactionCommit0", unsafe {
+                FwpmTransactionCommit0(self.engine)
+            }),
+            Err(e) => {
+                unsafe { FwpmTransactionAbort0(self.engine) };
+                Err(e)
+            }
+        }
+    }
+
+    fn add_sublayer(&self) -> Result {
+        let mut name = wide("libtelio");
+        let mut sublayer = FWPM_SUBLAYER0 {
+            subLayerKey: SUBLAYER_KEY,
+            displayData: FWPM_DISPLAY_DATA0 {
+                name: PWSTR(name.as_mut_ptr()),
+                description: PWSTR::null(),
+            },
+            weight: u16::MAX,
+            ..Default::default()
+        };
+        if self.config.persistent {
+            sublayer.flags = FWPM_SUBLAYER_FLAG_PERSISTENT;
+        }
+
+        match unsafe { FwpmSubLayerAdd0(self.engine, &sublayer, None) } {
+            FWP_E_ALREADY_EXISTS => Ok(()),
+            code => check("FwpmSubLayerAdd0", code),
+        }
+    }
+
+    fn add_filter(
+        &self,
+        count: &mut u128,
+        layer: GUID,
+        weight: u8,
+        permit: bool,
+        conditions: &mut [FWPM_FILTER_CONDITION0],
+    ) -> Result {
+        // WFP refuses filters both persistent and boot time, so each gets its own copy
+        let mut flags = Vec::new();
+        if self.config.persistent {
+            flags.push(FWPM_FILTER_FLAG_PERSISTENT);
+        }
+        if self.config.boot_time {
+            flags.push(FWPM_FILTER_FLAG_BOOTTIME);
+        }
+        if flags.is_empty() {
+            flags.push(FWPM_FILTER_FLAGS::default());
+        }
+
+        for flags in flags {
+            self.add_filter_with(count, layer, weight, permit, flags, conditions)?;
+        }
+        Ok(())
+    }
+
+    fn add_filter_with(
+        &self,
+        count: &mut u128,
+        layer: GUID,
+        weight: u8,
+        permit: bool,
+        flags: FWPM_FILTER_FLAGS,
+        conditions: &mut [FWPM_FILTER_CONDITION0],
+    ) -> Result {
+        if *count >= MAX_FILTERS {
+            return Err(Error::TooManyFilters);
+        }
+
+        let mut name = wide("libtelio killswitch");
+        let filter = FWPM_FILTER0 {
+            filterKey: GUID::from_u128(FILTER_KEY_BASE + *count),
+            displayData: FWPM_DISPLAY_DATA0 {
+                name: PWSTR(name.as_mut_ptr()),
+                description: PWSTR::null(),
+            },
+            layerKey: layer,
+            subLayerKey: SUBLAYER_KEY,
+            weight: FWP_VALUE0 {
+                r#type: FWP_UINT8,
+                Anonymous: FWP_VALUE0_0 { uint8: weight },
+            },
+            numFilterConditions: conditions.len() as u32,
+            filterCondition: conditions.as_mut_ptr(),
+            action: FWPM_ACTION0 {
+                r#type: if permit {
+                    FWP_ACTION_PERMIT
+                } else {
+                    FWP_ACTION_BLOCK
+                },
+                ..Default::default()
+            },
+            flags,
+            ..Default::default()
+        };
+
+        check("FwpmFilterAdd0", unsafe {
+            FwpmFilterAdd0(self.engine, &filter, None, None)
+        })?;
+        *count += 1;
+        Ok(())
+    }
+
+    fn delete_filters(&self) -> Result {
+        let mut installed = self.installed.lock();
+        for n in 0..*installed {
+            let key = GUID::from_u128(FILTER_KEY_BASE + n);
+            match unsafe { FwpmFilterDeleteByKey0(self.engine, &key) } {
+                FWP_E_FILTER_NOT_FOUND | FWP_E_NOT_FOUND => (),
+                code => check("FwpmFilterDeleteByKey0", code)?,
+            }
+        }
+        *installed = 0;
+        Ok(())
+    }
+}
+
+impl Drop for WfpKillswitch {
+    fn drop(&mut self) {
+        if !self.config.persistent {
+            if let Err(e) = self.disable() {
+                telio_log_warn!("Failed to remove WFP killswitch filters: {e}");
+            }
+            match unsafe { FwpmSubLayerDeleteByKey0(self.engine, &SUBLAYER_KEY) } {
+                0 | FWP_E_SUBLAYER_NOT_FOUND => (),
+                code => telio_log_warn!("Failed to remove WFP sublayer: {code:#x}"),
+            }
+        } else {
+            telio_log_debug!("Keeping persistent WFP killswitch filters");
+        }
+        unsafe { FwpmEngineClose0(self.engine) };
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn condition(
+    field: GUID,
+    match_type: FWP_MATCH_TYPE,
+    value: FWP_CONDITION_VALUE0,
+) -> FWPM_FILTER_CONDITION0 {
+    FWPM_FILTER_CONDITION0 {
+        fieldKey: field,
+        matchType: match_type,
+        conditionValue: value,
+    }
+}
+
+fn u8_value(value: u8) -> FWP_CONDITION_VALUE0 {
+    let mut v = FWP_CONDITION_VALUE0 {
+        r#type: FWP_UINT8,
+        ..Default::default()
+    };
+    v.Anonymous.uint8 = value;
+    v
+}
+
+fn u16_value(value: u16) -> FWP_CONDITION_VALUE0 {
+    let mut v = FWP_CONDITION_VALUE0 {
+        r#type: FWP_UINT16,
+        ..Default::default()
+    };
+    v.Anonymous.uint16 = value;
+    v
+}
+
+fn u32_value(value: u32) -> FWP_CONDITION_VALUE0 {
+    let mut v = FWP_CONDITION_VALUE0 {
+        r#type: FWP_UINT32,
+        ..Default::default()
+    };
+    v.Anonymous.uint32 = value;
+    v
+}
+
+fn u64_value(value: &mut u64) -> FWP_CONDITION_VALUE0 {
+    let mut v = FWP_CONDITION_VALUE0 {
+        r#type: FWP_UINT64,
+        ..Default::default()
+    };
+    v.Anonymous.uint64 = value;
+    v
+}
+
+fn byte_array16_value(value: &mut FWP_BYTE_ARRAY16) -> FWP_CONDITION_VALUE0 {
+    let mut v = FWP_CONDITION_VALUE0 {
+        r#type: FWP_BYTE_ARRAY16_TYPE,
+        ..Default::default()
+    };
+    v.Anonymous.byteArray16 = value;
+    v
+}
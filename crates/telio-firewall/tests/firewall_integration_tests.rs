@@ -12,13 +12,15 @@ use std::{
     convert::TryInto,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr as StdSocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex},
 };
 use telio_crypto::SecretKey;
+use telio_firewall::approval::InboundDecision;
 use telio_firewall::firewall::{
     Firewall, FirewallState, Permissions, StatefullFirewall, FILE_SEND_PORT,
 };
 use telio_model::{
-    features::{FeatureFirewall, FirewallBlacklistTuple, IpProtocol},
+    features::{FeatureFirewall, FeatureInboundApproval, FirewallBlacklistTuple, IpProtocol},
     PublicKey,
 };
 
@@ -1023,3 +1025,70 @@ fn firewall_whitelist_port() {
         assert_eq!(fw.process_inbound_packet(&make_peer(), &make_tcp(&test_input.src_socket(11111), &test_input.dst_socket(FILE_SEND_PORT), TcpFlags::PSH)), false);
     }
 }
+
+#[test]
+fn firewall_inbound_approval() {
+    let feature = FeatureFirewall {
+        inbound_approval: Some(FeatureInboundApproval::default()),
+        ..Default::default()
+    };
+    let fw = StatefullFirewall::new(true, feature);
+    fw.apply_state(FirewallState {
+        ip_addresses: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+        ..Default::default()
+    });
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let requests = asked.clone();
+    fw.set_approval_observer(Some(Arc::new(move |peer, destination| {
+        requests.lock().unwrap().push((peer, destination));
+    })));
+
+    let src = "100.100.100.100:1234";
+    let dst = "127.0.0.1:22";
+    assert!(!fw.process_inbound_packet(&make_peer(), &make_tcp(src, dst, TcpFlags::SYN)));
+    assert!(!fw.process_inbound_packet(&make_peer(), &make_tcp(src, dst, TcpFlags::SYN)));
+    // Packets which do not open a connection are not asked about
+    assert!(!fw.process_inbound_packet(&make_random_peer().0, &make_tcp(src, dst, TcpFlags::PSH)));
+    assert_eq!(*asked.lock().unwrap(), vec![(PublicKey(make_peer()), dst.parse().unwrap())]);
+
+    assert!(fw.decide_inbound(PublicKey(make_peer()), InboundDecision::AllowAlways));
+    assert!(fw.process_inbound_packet(&make_peer(), &make_tcp(src, dst, TcpFlags::SYN)));
+    // Decisions are kept when the state is replaced
+    fw.apply_state(FirewallState {
+        ip_addresses: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))],
+        ..Default::default()
+    });
+    assert!(fw.process_inbound_packet(&make_peer(), &make_udp(src, dst)));
+    assert!(fw.get_state().whitelist.peer_whitelists[Permissions::IncomingConnections].is_empty());
+
+    assert!(fw.decide_inbound(PublicKey(make_peer()), InboundDecision::Deny));
+    assert!(!fw.process_inbound_packet(&make_peer(), &make_udp("100.100.100.100:1235", dst)));
+    assert_eq!(asked.lock().unwrap().len(), 1);
+
+    let fw = StatefullFirewall::new(true, FeatureFirewall::default());
+    assert!(!fw.decide_inbound(PublicKey(make_peer()), InboundDecision::AllowOnce));
+}
+
+#[test]
+fn firewall_counts_drained_packets() {
+    let fw = StatefullFirewall::new(true, FeatureFirewall::default());
+    fw.apply_state(FirewallState {
+        ip_addresses: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+        ..Default::default()
+    });
+    let us = "127.0.0.1:1111";
+    let them = "8.8.8.8:8888";
+
+    assert!(fw.process_outbound_packet_sink(&make_peer(), &make_udp(us, them)));
+    assert_eq!(fw.drained_packets(), 0);
+
+    fw.set_draining(true);
+    assert!(fw.process_inbound_packet(&make_peer(), &make_udp(them, us)));
+    assert!(fw.process_outbound_packet_sink(&make_peer(), &make_udp(us, them)));
+    // Refused connections do not count as traffic of the draining ones
+    assert!(!fw.process_outbound_packet_sink(&make_peer(), &make_udp(us, "8.8.4.4:8888")));
+    assert_eq!(fw.drained_packets(), 2);
+
+    fw.set_draining(false);
+    assert_eq!(fw.drained_packets(), 0);
+}
@@ -1,6 +1,8 @@
 //! This module is a mock for libmoose API, it logs moose function calls to file.
 //!
+#[cfg(not(feature = "strict_privacy"))]
 use std::{fs::File, io::Write};
+#[cfg(not(feature = "strict_privacy"))]
 use telio_utils::telio_log_error;
 
 pub use telio_utils::telio_log_warn;
@@ -15,6 +17,7 @@ const LOGFILE_PATH: &str = "events-moose.log";
 ///
 /// Returns:
 /// * The number of bytes written into file or moose::Error otherwise
+#[cfg(not(feature = "strict_privacy"))]
 fn event_log(
     func_name: &str,
     arg_set: Option<Vec<&str>>,
@@ -51,6 +54,16 @@ fn event_log(
     }
 }
 
+/// Nothing is written in the strict privacy builds, the events are refused
+#[cfg(feature = "strict_privacy")]
+fn event_log(
+    _func_name: &str,
+    _arg_set: Option<Vec<&str>>,
+) -> std::result::Result<usize, moose::Error> {
+    Err(moose::Error::NotInitiatedError)
+}
+
+#[cfg(not(feature = "strict_privacy"))]
 fn get_logfile() -> std::io::Result<File> {
     if std::path::Path::new(&LOGFILE_PATH).exists() {
         File::options().append(true).open(LOGFILE_PATH)
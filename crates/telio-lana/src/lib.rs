@@ -1,6 +1,15 @@
 #![deny(unsafe_code)]
 #![deny(missing_docs)]
 //! Contains macros to simplify using Moose functions from within libtelio
+//!
+//! With the `strict_privacy` feature lana can never be initialized, the analytics backend is not
+//! compiled in and none of the events leave the device.
+
+#[cfg(all(feature = "strict_privacy", feature = "moose"))]
+compile_error!("The `strict_privacy` feature excludes the `moose` analytics backend");
+
+#[cfg(all(feature = "strict_privacy", feature = "otlp"))]
+compile_error!("The `strict_privacy` feature excludes the `otlp` exporter");
 
 use std::sync::atomic::{AtomicBool, Ordering};
 
@@ -14,6 +23,10 @@ pub mod event_log;
 /// Module containing moose callbacks
 pub mod moose_callbacks;
 
+/// Module exporting metrics and spans to an OpenTelemetry collector
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
 pub use event_log::*;
 
 /// App name used to initialize moose with
@@ -119,6 +132,7 @@ pub fn is_lana_initialized() -> bool {
 /// * app_version - Indicates the semantic version of the application.
 /// * prod - whether the events should be sent to production or not
 /// * tx - channel tx half for the InitCallback instance
+#[cfg(not(feature = "strict_privacy"))]
 pub fn init_moose(
     event_path: String,
     app_version: String,
@@ -145,6 +159,16 @@ pub fn init_moose(
     moose::set_context_application_libtelioapp_name(LANA_APP_NAME.to_string())
 }
 
+/// Initialization of moose is compiled out in the strict privacy builds, it always fails
+#[cfg(feature = "strict_privacy")]
+pub fn init_moose(
+    _event_path: String,
+    _app_version: String,
+    _prod: bool,
+) -> std::result::Result<moose::Result, moose::Error> {
+    Err(moose::Error::NotInitiatedError)
+}
+
 /// Fetches context string from moose
 pub fn fetch_context_string(path: String) -> Option<String> {
     if is_lana_initialized() {
@@ -211,6 +235,7 @@ mod test {
         }
 
         #[allow(clippy::too_many_arguments)]
+        #[cfg_attr(feature = "strict_privacy", allow(dead_code))]
         pub fn init(
             &mut self,
             _event_path: String,
@@ -270,6 +295,7 @@ mod test {
 
     #[test]
     #[serial]
+    #[cfg_attr(feature = "strict_privacy", ignore)]
     fn test_init_lana() {
         let result = init_lana("/event.db".to_string(), "tests".to_string(), false);
         match result {
@@ -284,6 +310,7 @@ mod test {
 
     #[test]
     #[serial]
+    #[cfg_attr(feature = "strict_privacy", ignore)]
     fn test_init_lana_initialized() {
         let result = init_lana("/event.db".to_string(), "tests".to_string(), false);
         match result {
@@ -326,6 +353,7 @@ mod test {
 
     #[test]
     #[serial]
+    #[cfg_attr(feature = "strict_privacy", ignore)]
     fn test_init_lana_with_failing_moose_init_cb() {
         let init_cb_barrier = Arc::new(Barrier::new(2));
         let _wrapper = MooseStubWrapper::new(MooseStub::new(
@@ -350,6 +378,7 @@ mod test {
 
     #[test]
     #[serial]
+    #[cfg_attr(feature = "strict_privacy", ignore)]
     fn test_deinit_lana() {
         let result = init_lana("/event.db".to_string(), "tests".to_string(), false);
         match result {
@@ -384,4 +413,28 @@ mod test {
 
         teardown();
     }
+    #[test]
+    #[serial]
+    #[cfg(feature = "strict_privacy")]
+    fn test_init_lana_strict_privacy() {
+        let _wrapper = MooseStubWrapper::new(MooseStub::default());
+
+        let result = init_lana("/event.db".to_string(), "tests".to_string(), false);
+        match result {
+            Ok(res) => panic!("{:?}", res),
+            Err(error) => assert_eq!("NotInitiatedError", format!("{:?}", error)),
+        };
+
+        assert!(!is_lana_initialized());
+
+        teardown();
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "strict_privacy")]
+    fn test_events_are_not_logged_strict_privacy() {
+        assert!(moose::set_context_application_libtelioapp_version("tests".to_string()).is_err());
+        assert!(moose::moose_deinit().is_err());
+    }
 }
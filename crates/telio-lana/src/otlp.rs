@@ -0,0 +1,457 @@
+//! Export of metrics and spans to an OpenTelemetry collector.
+//!
+//! Producers record [OtlpExporter::gauge] values, [OtlpExporter::add] to cumulative counters and
+//! queue finished spans through [OtlpExporter::span]. None of these block, the collected data is
+//! sent by a background thread every export interval, and once more when the exporter is dropped,
+//! as OTLP/HTTP requests with JSON payloads. Remote collectors are reached over TLS, verifying
+//! their certificate with the platform verifier.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+use rustls_platform_verifier::ConfigVerifierExt;
+use serde_json::{json, Value};
+use telio_utils::{telio_log_debug, telio_log_warn};
+
+/// Spans queued above this are dropped, oldest first
+const MAX_PENDING_SPANS: usize = 512;
+
+/// Counters kept above this evict the least recently updated one, so that the series of gone
+/// peers do not accumulate
+const MAX_SUM_SERIES: usize = 1024;
+
+/// Timeout of every step of the requests to the collector
+const COLLECTOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name of the instrumentation scope of every metric and span
+const SCOPE_NAME: &str = "libtelio";
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE` of the OTLP protocol
+const CUMULATIVE: u32 = 2;
+
+/// `SPAN_KIND_INTERNAL` of the OTLP protocol
+const SPAN_KIND_INTERNAL: u32 = 1;
+
+/// Attributes of a data point or a span, as key and string value pairs
+pub type Attributes = Vec<(&'static str, String)>;
+
+/// Identity of a time series
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Series {
+    name: &'static str,
+    unit: &'static str,
+    attributes: Attributes,
+}
+
+struct Span {
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Attributes,
+}
+
+/// Cumulative value of a counter
+struct Sum {
+    value: u64,
+    /// Start of the counter, a counter evicted and added again starts anew
+    start: SystemTime,
+    /// Order of the last update, the lowest is evicted first
+    updated: u64,
+}
+
+#[derive(Default)]
+struct Batch {
+    /// Last values, cleared on every export so that the series of gone peers disappear
+    gauges: HashMap<Series, f64>,
+    /// Cumulative values, at most [MAX_SUM_SERIES] of them
+    sums: HashMap<Series, Sum>,
+    /// Updates of the counters so far
+    updates: u64,
+    spans: VecDeque<Span>,
+}
+
+impl Batch {
+    fn add(&mut self, series: Series, value: u64, now: SystemTime) {
+        self.updates += 1;
+        if !self.sums.contains_key(&series) && self.sums.len() >= MAX_SUM_SERIES {
+            let stale = self
+                .sums
+                .iter()
+                .min_by_key(|(_, sum)| sum.updated)
+                .map(|(series, _)| series.clone());
+            if let Some(stale) = stale {
+                self.sums.remove(&stale);
+            }
+        }
+        let sum = self.sums.entry(series).or_insert(Sum {
+            value: 0,
+            start: now,
+            updated: 0,
+        });
+        sum.value = sum.value.saturating_add(value);
+        sum.updated = self.updates;
+    }
+}
+
+/// TLS client of a remote collector
+struct Tls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+/// Collects metrics and spans and periodically exports them to an OTLP/HTTP collector
+pub struct OtlpExporter {
+    batch: Arc<Mutex<Batch>>,
+    /// Dropping it stops the export thread
+    _stop: mpsc::Sender<()>,
+}
+
+impl OtlpExporter {
+    /// Start exporting to the `collector` every `interval`, reporting as `service_name`. The
+    /// exports go over TLS when the `tls_server_name` of the collector is given.
+    pub fn start(
+        collector: SocketAddr,
+        tls_server_name: Option<String>,
+        service_name: String,
+        interval: Duration,
+    ) -> io::Result<Self> {
+        let host = tls_server_name
+            .clone()
+            .unwrap_or_else(|| collector.to_string());
+        let tls = tls_server_name
+            .map(|name| {
+                let server_name = ServerName::try_from(name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Ok::<_, io::Error>(Tls {
+                    config: Arc::new(ClientConfig::with_platform_verifier()),
+                    server_name,
+                })
+            })
+            .transpose()?;
+        let batch = Arc::new(Mutex::new(Batch::default()));
+        let (stop, stopped) = mpsc::channel();
+        let export = Export {
+            collector,
+            host,
+            tls,
+            service_name,
+            batch: batch.clone(),
+        };
+        thread::Builder::new()
+            .name("telio-otlp".to_owned())
+            .spawn(move || loop {
+                let last = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                );
+                export.run();
+                if last {
+                    break;
+                }
+            })?;
+        Ok(Self { batch, _stop: stop })
+    }
+
+    /// Set the current `value` of a gauge
+    pub fn gauge(
+        &self,
+        name: &'static str,
+        unit: &'static str,
+        value: f64,
+        attributes: Attributes,
+    ) {
+        let series = Series {
+            name,
+            unit,
+            attributes,
+        };
+        self.batch.lock().gauges.insert(series, value);
+    }
+
+    /// Add `value` to a monotonic counter
+    pub fn add(&self, name: &'static str, unit: &'static str, value: u64, attributes: Attributes) {
+        let series = Series {
+            name,
+            unit,
+            attributes,
+        };
+        self.batch.lock().add(series, value, SystemTime::now());
+    }
+
+    /// Queue a finished span
+    pub fn span(
+        &self,
+        name: &'static str,
+        start: SystemTime,
+        end: SystemTime,
+        attributes: Attributes,
+    ) {
+        let mut batch = self.batch.lock();
+        if batch.spans.len() >= MAX_PENDING_SPANS {
+            batch.spans.pop_front();
+        }
+        batch.spans.push_back(Span {
+            name,
+            start,
+            end,
+            attributes,
+        });
+    }
+}
+
+/// State of the export thread
+struct Export {
+    collector: SocketAddr,
+    /// Host header of the requests
+    host: String,
+    tls: Option<Tls>,
+    service_name: String,
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl Export {
+    fn run(&self) {
+        let now = SystemTime::now();
+        let (metrics, spans) = {
+            let mut batch = self.batch.lock();
+            let mut metrics: Vec<Value> = batch
+                .gauges
+                .drain()
+                .map(|(series, value)| {
+                    gauge_json(
+                        &series,
+                        json!({ "timeUnixNano": nanos(now), "asDouble": value }),
+                    )
+                })
+                .collect();
+            metrics.extend(batch.sums.iter().map(|(series, sum)| {
+                sum_json(
+                    series,
+                    json!({
+                        "startTimeUnixNano": nanos(sum.start),
+                        "timeUnixNano": nanos(now),
+                        "asInt": sum.value.to_string(),
+                    }),
+                )
+            }));
+            let spans: Vec<Value> = batch.spans.drain(..).map(|span| span_json(&span)).collect();
+            (metrics, spans)
+        };
+
+        let resource = json!({
+            "attributes": attributes_json(&[("service.name", self.service_name.clone())]),
+        });
+        let scope = json!({ "name": SCOPE_NAME });
+        if !metrics.is_empty() {
+            self.post(
+                "/v1/metrics",
+                &json!({
+                    "resourceMetrics": [{
+                        "resource": resource,
+                        "scopeMetrics": [{ "scope": scope, "metrics": metrics }],
+                    }],
+                }),
+            );
+        }
+        if !spans.is_empty() {
+            self.post(
+                "/v1/traces",
+                &json!({
+                    "resourceSpans": [{
+                        "resource": resource,
+                        "scopeSpans": [{ "scope": scope, "spans": spans }],
+                    }],
+                }),
+            );
+        }
+    }
+
+    fn post(&self, path: &str, payload: &Value) {
+        match self.request(path, payload) {
+            Ok(()) => telio_log_debug!("Exported {path} to the OTLP collector"),
+            // The data is dropped, the collector is expected to be local and reliable
+            Err(e) => telio_log_warn!("Failed to export {path} to the OTLP collector: {e}"),
+        }
+    }
+
+    fn request(&self, path: &str, payload: &Value) -> io::Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let stream = TcpStream::connect_timeout(&self.collector, COLLECTOR_TIMEOUT)?;
+        stream.set_read_timeout(Some(COLLECTOR_TIMEOUT))?;
+        stream.set_write_timeout(Some(COLLECTOR_TIMEOUT))?;
+        match &self.tls {
+            Some(tls) => {
+                let connection = ClientConnection::new(tls.config.clone(), tls.server_name.clone())
+                    .map_err(io::Error::other)?;
+                send_request(
+                    StreamOwned::new(connection, stream),
+                    &self.host,
+                    path,
+                    &body,
+                )
+            }
+            None => send_request(stream, &self.host, path, &body),
+        }
+    }
+}
+
+/// Send the request with the `body` over the `stream` and check the status of the response
+fn send_request<S: Read + Write>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    match status.get(9) {
+        Some(b'2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "unexpected response {:?}",
+            String::from_utf8_lossy(&status)
+        ))),
+    }
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attributes_json(attributes: &[(&'static str, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+fn gauge_json(series: &Series, mut point: Value) -> Value {
+    point["attributes"] = attributes_json(&series.attributes);
+    json!({
+        "name": series.name,
+        "unit": series.unit,
+        "gauge": { "dataPoints": [point] },
+    })
+}
+
+fn sum_json(series: &Series, mut point: Value) -> Value {
+    point["attributes"] = attributes_json(&series.attributes);
+    json!({
+        "name": series.name,
+        "unit": series.unit,
+        "sum": {
+            "aggregationTemporality": CUMULATIVE,
+            "isMonotonic": true,
+            "dataPoints": [point],
+        },
+    })
+}
+
+fn span_json(span: &Span) -> Value {
+    let mut rng = rand::thread_rng();
+    json!({
+        "traceId": format!("{:032x}", rng.gen::<u128>()),
+        "spanId": format!("{:016x}", rng.gen::<u64>()),
+        "name": span.name,
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end),
+        "attributes": attributes_json(&span.attributes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn data_is_exported_on_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let collector = listener.local_addr().unwrap();
+        let exporter = OtlpExporter::start(
+            collector,
+            None,
+            "test".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+
+        exporter.gauge("telio.gauge", "ms", 1.5, vec![("peer", "a".to_owned())]);
+        exporter.add("telio.sum", "1", 2, vec![]);
+        exporter.add("telio.sum", "1", 3, vec![]);
+        exporter.span("telio.span", SystemTime::now(), SystemTime::now(), vec![]);
+        drop(exporter);
+
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            requests.push(String::from_utf8(request).unwrap());
+        }
+
+        assert!(requests[0].starts_with("POST /v1/metrics "));
+        assert!(requests[0].contains(r#""asDouble":1.5"#));
+        assert!(requests[0].contains(r#""asInt":"5""#));
+        assert!(requests[0].contains(r#""stringValue":"test""#));
+        assert!(requests[1].starts_with("POST /v1/traces "));
+        assert!(requests[1].contains(r#""name":"telio.span""#));
+    }
+
+    #[test]
+    fn least_recently_updated_counters_are_evicted() {
+        let series = |peer: usize| Series {
+            name: "telio.sum",
+            unit: "1",
+            attributes: vec![("peer", peer.to_string())],
+        };
+        let mut batch = Batch::default();
+        let now = SystemTime::now();
+        for peer in 0..MAX_SUM_SERIES {
+            batch.add(series(peer), 1, now);
+        }
+        batch.add(series(0), 1, now);
+        batch.add(series(MAX_SUM_SERIES), 1, now);
+
+        assert_eq!(batch.sums.len(), MAX_SUM_SERIES);
+        assert_eq!(batch.sums.get(&series(0)).map(|sum| sum.value), Some(2));
+        assert!(!batch.sums.contains_key(&series(1)));
+        assert!(batch.sums.contains_key(&series(MAX_SUM_SERIES)));
+    }
+
+    #[test]
+    fn invalid_tls_server_name_is_refused() {
+        assert!(OtlpExporter::start(
+            SocketAddr::from(([127, 0, 0, 1], 4318)),
+            Some("not a name".to_owned()),
+            "test".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .is_err());
+    }
+}
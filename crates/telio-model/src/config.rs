@@ -12,6 +12,7 @@ use std::{
     ops::Deref,
 };
 
+use ipnet::IpNet;
 use telio_crypto::PublicKey;
 
 const MAX_CONFIG_LENGTH: usize = 16 * 1024 * 1024;
@@ -143,6 +144,113 @@ impl PartialEq for Server {
     }
 }
 
+/// Restriction of the relay servers the device may connect to, e.g. for compliance
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RelayPinning {
+    /// Region codes of the allowed servers
+    #[serde(default)]
+    pub regions: Vec<String>,
+    /// Hostnames of the allowed servers, in addition to the ones in the allowed regions
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    /// What to do when none of the allowed servers is reachable
+    #[serde(default)]
+    pub fallback: RelayPinningFallback,
+}
+
+impl RelayPinning {
+    /// Whether the device may connect to the `server`
+    pub fn allows(&self, server: &Server) -> bool {
+        self.regions.contains(&server.region_code) || self.hostnames.contains(&server.hostname)
+    }
+}
+
+/// Behavior of the relay pinning when none of the allowed servers is reachable
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayPinningFallback {
+    /// Stay without a relay connection until an allowed server is reachable again
+    #[default]
+    Block,
+    /// Connect to any of the servers until an allowed one is reachable again
+    AnyServer,
+}
+
+/// Exit of the exit failover
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FailoverExit {
+    /// Public key of the exit
+    pub public_key: PublicKey,
+    /// Networks routed to the exit, all of them when not set
+    #[serde(default)]
+    pub allowed_ips: Option<Vec<IpNet>>,
+    /// Endpoint of the VPN server, none for a meshnet peer
+    #[serde(default)]
+    pub endpoint: Option<SocketAddr>,
+}
+
+/// Ordered exits connected one after another whenever the active one degrades
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, SmartDefault)]
+#[serde(default)]
+pub struct ExitFailover {
+    /// Exits in the order of preference, the first one is connected right away
+    pub exits: Vec<FailoverExit>,
+    /// Longest time without a handshake before the active exit is degraded [default 180s]
+    #[default(180)]
+    pub max_handshake_age_s: u32,
+    /// Ping loss of the active exit above which it is degraded, needs the QoS analytics
+    pub max_ping_loss_percent: Option<u32>,
+}
+
+/// Traffic to some destinations routed to another exit than the rest of the traffic, e.g. the
+/// streaming services through an exit in another country
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TrafficClass {
+    /// Name of the class, used only in the logs
+    pub name: String,
+    /// Destination networks of the class, all destinations when empty and the class selects its
+    /// traffic by the source ports or the fwmark
+    pub networks: Vec<IpNet>,
+    /// Source ports of the TCP and UDP traffic of the class, supported only on Linux
+    #[serde(default)]
+    pub source_ports: Option<PortRange>,
+    /// Firewall mark set on the traffic of the class by the apps, supported only on Linux
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+    /// Public key of the exit the class is routed to, either a meshnet peer or the VPN server
+    /// connected with libtelio.connect_to_exit_node(...). Classes selecting their traffic by the
+    /// source ports or the fwmark are routed only to the additional exits.
+    pub exit_public_key: PublicKey,
+}
+
+impl TrafficClass {
+    /// Whether the class selects its traffic by more than the destination
+    pub fn has_selectors(&self) -> bool {
+        self.source_ports.is_some() || self.fwmark.is_some()
+    }
+}
+
+/// Range of ports, both ends included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PortRange {
+    /// First port of the range
+    pub first: u16,
+    /// Last port of the range
+    pub last: u16,
+}
+
+/// Traffic kept off the tunnel, e.g. of the banking apps
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SplitTunnel {
+    /// Destination networks routed through the exit, all of them when not set
+    pub included_routes: Option<Vec<IpNet>>,
+    /// Destination networks never routed through the tunnel
+    pub excluded_routes: Vec<IpNet>,
+    /// Apps never routed through the tunnel, identified by their UIDs, supported only on Linux
+    pub excluded_apps: Vec<String>,
+}
+
 /// Possible relay server connectivity change reasons
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum RelayConnectionChangeReason {
@@ -515,4 +623,22 @@ mod tests {
         assert_eq!(peer_deserialization_failure_count, 3);
         assert_eq!(full_config, expected_config);
     }
+
+    #[test]
+    fn test_relay_pinning() {
+        let server = |region_code: &str, hostname: &str| Server {
+            region_code: region_code.to_owned(),
+            hostname: hostname.to_owned(),
+            ..Default::default()
+        };
+        let pinning: RelayPinning = serde_json::from_str(
+            r#"{"regions": ["de"], "hostnames": ["nl1.example.com"], "fallback": "any_server"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(pinning.fallback, RelayPinningFallback::AnyServer);
+        assert!(pinning.allows(&server("de", "de1.example.com")));
+        assert!(pinning.allows(&server("nl", "nl1.example.com")));
+        assert!(!pinning.allows(&server("nl", "nl2.example.com")));
+    }
 }
@@ -14,6 +14,13 @@ pub const IPV4_MULTICAST_NETWORK: ConstIpv4Net = ConstIpv4Net::new(Ipv4Addr::new
 /// Ipv6 multicast range
 pub const IPV6_MULTICAST_NETWORK: ConstIpv6Net =
     ConstIpv6Net::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb), 128);
+/// Ipv4 meshnet network
+pub const IPV4_MESHNET_NETWORK: ConstIpv4Net = ConstIpv4Net::new(Ipv4Addr::new(100, 64, 0, 0), 10);
+/// Ipv6 meshnet network
+pub const IPV6_MESHNET_NETWORK: ConstIpv6Net =
+    ConstIpv6Net::new(Ipv6Addr::new(0xfd74, 0x656c, 0x696f, 0, 0, 0, 0, 0), 48);
+/// Directed broadcast address of the IPv4 meshnet network
+pub const IPV4_MESHNET_BROADCAST: Ipv4Addr = Ipv4Addr::new(100, 127, 255, 255);
 /// Ipv4 starcast's virtual peer address
 pub const IPV4_STARCAST_ADDRESS: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 5);
 /// Ipv6 starcast's virtual peer address
@@ -2,9 +2,11 @@
 
 use super::mesh::Node;
 use modifier::Modifier;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::config::Server as Relay;
+use crate::config::{RelayPinningFallback, Server as Relay};
+use std::net::{IpAddr, SocketAddr};
+use telio_crypto::PublicKey;
 
 pub use modifier::Set;
 
@@ -61,6 +63,223 @@ pub struct Error {
     pub msg: EventMsg,
 }
 
+/// State of the device key rotation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyRotationState {
+    /// New keypair was generated. Its public key should be registered with the
+    /// backend and the rotation confirmed afterwards
+    #[default]
+    Announced,
+    /// Device switched to the new keypair
+    Switched,
+}
+
+/// Key rotation event. Used to coordinate the device key switch with the upper layers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct KeyRotation {
+    /// Public key of the new keypair
+    pub public_key: PublicKey,
+    /// The state of the rotation
+    pub state: KeyRotationState,
+}
+
+/// State of a post-quantum rekey
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PqRekeyState {
+    /// Rekey request is sent to the VPN server
+    #[default]
+    Started,
+    /// New preshared key was agreed on with the VPN server
+    Succeeded,
+    /// Rekey failed, it is retried after the handshake retry interval
+    Failed,
+}
+
+/// Post-quantum rekey event. Sent on every periodic rekey of the post-quantum VPN connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PqRekey {
+    /// Public key of the VPN server
+    pub public_key: PublicKey,
+    /// Post-quantum protocol version used for the rekey
+    pub version: u32,
+    /// The state of the rekey
+    pub state: PqRekeyState,
+    /// Cause of the failure, only set in the failed state
+    pub error: Option<String>,
+}
+
+/// Inbound approval event. Sent when a peer without the permission to make inbound connections
+/// tries to make one, the app answers it with the decision for the peer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct InboundApproval {
+    /// Public key of the peer
+    pub public_key: PublicKey,
+    /// Local address the peer connects to
+    pub destination: SocketAddr,
+}
+
+/// Part of the device reported by the readiness events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessComponent {
+    /// Userspace firewall filtering tunnel traffic
+    Firewall,
+    /// OS level policy, like the killswitch
+    PolicyRules,
+    /// Tunnel adapter with its private key set
+    Adapter,
+    /// Addresses and routes of the tunnel interface
+    AdapterAddresses,
+    /// Local DNS resolver
+    DnsStub,
+    /// DNS peer reachable through the tunnel
+    DnsConfig,
+    /// Connection to a relay server
+    Relay,
+}
+
+/// Readiness event. Sent whenever a component of the device becomes ready or stops being
+/// ready, starting during libtelio.start(...), and whenever more of the meshnet is formed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Readiness {
+    /// Components ready so far, in the startup order
+    pub ready: Vec<ReadinessComponent>,
+    /// Connected meshnet peers in percent of the configured ones, unset without meshnet
+    pub mesh_formed_percent: Option<u8>,
+}
+
+/// Relay rehoming event. Sent when the active DERP server is left for a faster one.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RelayRehoming {
+    /// Server which is left
+    pub from: Relay,
+    /// Server which is connected instead
+    pub to: Relay,
+    /// Latency to the left server measured by the last check, in milliseconds
+    pub from_latency_ms: u64,
+    /// Latency to the new server measured by the last check, in milliseconds
+    pub to_latency_ms: u64,
+}
+
+/// Relay quality event. Sent periodically with the health of the active DERP server.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RelayQuality {
+    /// Hostname of the active server
+    pub hostname: String,
+    /// Round trip time measured by the last health check, in milliseconds
+    pub rtt_ms: u64,
+    /// Smoothed variation of the round trip time, in milliseconds
+    pub jitter_ms: u64,
+    /// Number of reconnections to the relay since the device was started
+    pub reconnects: u32,
+}
+
+/// Reason of a direct path being abandoned in favour of the relay
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DowngradeReason {
+    /// No traffic passed over a freshly punched direct path within the upgrade window
+    HolePunchTimeout,
+    /// Keepalives stopped arriving over an established direct path
+    KeepaliveLoss,
+    /// The direct endpoint was invalidated, e.g. after the local network changed
+    EndpointChange,
+}
+
+/// Reason of the active exit being failed over to the next one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitDegradation {
+    /// No handshake completed with the exit for longer than allowed
+    StaleHandshake,
+    /// More of the pings to the exit got no reply than allowed
+    PingLoss,
+}
+
+/// Typed connectivity change of the device
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Connectivity {
+    /// Connection to the relay server is established
+    RelayConnected {
+        /// The relay server
+        server: Relay,
+    },
+    /// Established connection to the relay server is lost
+    RelayDisconnected {
+        /// The relay server
+        server: Relay,
+    },
+    /// Traffic to the peer moved from the relay to a direct path
+    PathUpgraded {
+        /// Public key of the peer
+        public_key: PublicKey,
+        /// Direct endpoint of the peer
+        endpoint: SocketAddr,
+    },
+    /// Traffic to the peer moved from a direct path back to the relay
+    PathDowngraded {
+        /// Public key of the peer
+        public_key: PublicKey,
+        /// Why the direct path was abandoned
+        reason: DowngradeReason,
+    },
+    /// DNS queries are forwarded to another upstream server
+    DnsFailover {
+        /// Upstream server which stopped answering
+        from: IpAddr,
+        /// Upstream server used instead
+        to: IpAddr,
+    },
+    /// Killswitch started or stopped blocking the traffic outside of the tunnel
+    Killswitch {
+        /// Whether the killswitch is engaged
+        engaged: bool,
+    },
+    /// None of the relay servers allowed by the relay pinning is reachable
+    NoAllowedRelay {
+        /// Behavior applied until an allowed server is reachable again
+        fallback: RelayPinningFallback,
+    },
+    /// Active exit of the exit failover degraded and the next one was connected
+    ExitFailover {
+        /// Public key of the degraded exit
+        from: PublicKey,
+        /// Public key of the exit connected instead
+        to: PublicKey,
+        /// How the exit degraded
+        reason: ExitDegradation,
+    },
+    /// Fast reconnect after the network change finished
+    NetworkReconnect {
+        /// Time until the last of the reconnected peers was heard from, the whole budget when
+        /// some of them were not
+        duration_ms: u64,
+        /// Peers connected before the change which were heard from again
+        reconnected_peers: u32,
+        /// Peers connected before the change which were not heard from within the budget
+        unreachable_peers: u32,
+    },
+    /// Flags of the network path reported by the platform monitor of the app changed
+    NetworkPathChanged {
+        /// Whether the traffic over the path is metered
+        expensive: bool,
+        /// Whether the user asked to save the data on the path
+        constrained: bool,
+        /// Whether the path goes through another VPN
+        over_vpn: bool,
+    },
+    /// Peer announced that it is shutting down
+    PeerLeaving {
+        /// Public key of the peer
+        public_key: PublicKey,
+        /// Time the peer keeps the established connections for, in milliseconds
+        drain_timeout_ms: u64,
+    },
+}
+
 /// Used for the constructing `Event` object.
 /// Adding another `Event` type, that type should implement this trait,
 /// for the ability to be constructed, but not used outside of this module.
@@ -87,6 +306,48 @@ impl MakeEvent for Node {
     }
 }
 
+impl MakeEvent for KeyRotation {
+    fn make() -> EventBuilder {
+        EventBuilder::KeyRotation { body: None }
+    }
+}
+
+impl MakeEvent for RelayRehoming {
+    fn make() -> EventBuilder {
+        EventBuilder::RelayRehoming { body: None }
+    }
+}
+
+impl MakeEvent for Connectivity {
+    fn make() -> EventBuilder {
+        EventBuilder::Connectivity { body: None }
+    }
+}
+
+impl MakeEvent for RelayQuality {
+    fn make() -> EventBuilder {
+        EventBuilder::RelayQuality { body: None }
+    }
+}
+
+impl MakeEvent for PqRekey {
+    fn make() -> EventBuilder {
+        EventBuilder::PqRekey { body: None }
+    }
+}
+
+impl MakeEvent for InboundApproval {
+    fn make() -> EventBuilder {
+        EventBuilder::InboundApproval { body: None }
+    }
+}
+
+impl MakeEvent for Readiness {
+    fn make() -> EventBuilder {
+        EventBuilder::Readiness { body: None }
+    }
+}
+
 /// Main object of `Event`. See `Event::new()` for init options.
 #[derive(Clone, Debug, Serialize)]
 #[serde(tag = "type")]
@@ -108,6 +369,41 @@ pub enum Event {
         /// Error type event
         body: Error,
     },
+    /// Used to report the progress of the device key rotation
+    KeyRotation {
+        /// KeyRotation type event
+        body: KeyRotation,
+    },
+    /// Used to report switching of the active relay to a faster one
+    RelayRehoming {
+        /// RelayRehoming type event
+        body: RelayRehoming,
+    },
+    /// Used to report typed connectivity changes, like path upgrades and relay disconnects
+    Connectivity {
+        /// Connectivity type event
+        body: Connectivity,
+    },
+    /// Used to report the quality of the connection to the active relay
+    RelayQuality {
+        /// RelayQuality type event
+        body: RelayQuality,
+    },
+    /// Used to report the progress of the post-quantum rekeys
+    PqRekey {
+        /// PqRekey type event
+        body: PqRekey,
+    },
+    /// Used to ask for the approval of the inbound connections of a peer
+    InboundApproval {
+        /// InboundApproval type event
+        body: InboundApproval,
+    },
+    /// Used to report the progress of the device startup
+    Readiness {
+        /// Readiness type event
+        body: Readiness,
+    },
 }
 
 impl Event {
@@ -147,6 +443,13 @@ pub enum EventBuilder {
     Relay { body: Option<Relay> },
     Node { body: Option<Node> },
     Error { body: Option<Error> },
+    KeyRotation { body: Option<KeyRotation> },
+    RelayRehoming { body: Option<RelayRehoming> },
+    Connectivity { body: Option<Connectivity> },
+    RelayQuality { body: Option<RelayQuality> },
+    PqRekey { body: Option<PqRekey> },
+    InboundApproval { body: Option<InboundApproval> },
+    Readiness { body: Option<Readiness> },
 }
 
 impl EventBuilder {
@@ -157,6 +460,15 @@ impl EventBuilder {
             EventBuilder::Relay { body: Some(body) } => Some(Event::Relay { body }),
             EventBuilder::Node { body: Some(body) } => Some(Event::Node { body }),
             EventBuilder::Error { body: Some(body) } => Some(Event::Error { body }),
+            EventBuilder::KeyRotation { body: Some(body) } => Some(Event::KeyRotation { body }),
+            EventBuilder::RelayRehoming { body: Some(body) } => Some(Event::RelayRehoming { body }),
+            EventBuilder::Connectivity { body: Some(body) } => Some(Event::Connectivity { body }),
+            EventBuilder::RelayQuality { body: Some(body) } => Some(Event::RelayQuality { body }),
+            EventBuilder::PqRekey { body: Some(body) } => Some(Event::PqRekey { body }),
+            EventBuilder::InboundApproval { body: Some(body) } => {
+                Some(Event::InboundApproval { body })
+            }
+            EventBuilder::Readiness { body: Some(body) } => Some(Event::Readiness { body }),
             _ => None,
         }
     }
@@ -178,6 +490,62 @@ impl Modifier<EventBuilder> for Node {
     }
 }
 
+impl Modifier<EventBuilder> for KeyRotation {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::KeyRotation { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
+impl Modifier<EventBuilder> for RelayRehoming {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::RelayRehoming { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
+impl Modifier<EventBuilder> for Connectivity {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::Connectivity { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
+impl Modifier<EventBuilder> for RelayQuality {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::RelayQuality { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
+impl Modifier<EventBuilder> for PqRekey {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::PqRekey { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
+impl Modifier<EventBuilder> for InboundApproval {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::InboundApproval { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
+impl Modifier<EventBuilder> for Readiness {
+    fn modify(self, res: &mut EventBuilder) {
+        if let EventBuilder::Readiness { body } = res {
+            *body = Some(self);
+        }
+    }
+}
+
 impl Modifier<EventBuilder> for ErrorLevel {
     fn modify(self, res: &mut EventBuilder) {
         if let EventBuilder::Error { body } = res {
@@ -323,4 +691,143 @@ mod tests {
         assert_eq!(conn_json, conn_event.to_json().unwrap());
         assert_eq!(node_json, node_event.to_json().unwrap());
     }
+
+    #[test]
+    fn connectivity_to_json() {
+        let downgrade = Event::builder::<Connectivity>()
+            .set(Connectivity::PathDowngraded {
+                public_key: PublicKey([1_u8; KEY_SIZE]),
+                reason: DowngradeReason::HolePunchTimeout,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"connectivity","body":{"kind":"path_downgraded","#,
+                r#""public_key":"AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=","#,
+                r#""reason":"hole_punch_timeout"}}"#
+            ),
+            downgrade.to_json().unwrap()
+        );
+
+        let killswitch = Event::builder::<Connectivity>()
+            .set(Connectivity::Killswitch { engaged: true })
+            .build()
+            .unwrap();
+        assert_eq!(
+            r#"{"type":"connectivity","body":{"kind":"killswitch","engaged":true}}"#,
+            killswitch.to_json().unwrap()
+        );
+
+        let failover = Event::builder::<Connectivity>()
+            .set(Connectivity::ExitFailover {
+                from: PublicKey([1_u8; KEY_SIZE]),
+                to: PublicKey([2_u8; KEY_SIZE]),
+                reason: ExitDegradation::PingLoss,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"connectivity","body":{"kind":"exit_failover","#,
+                r#""from":"AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=","#,
+                r#""to":"AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=","#,
+                r#""reason":"ping_loss"}}"#
+            ),
+            failover.to_json().unwrap()
+        );
+    }
+
+    #[test]
+    fn network_reconnect_to_json() {
+        let reconnect = Event::builder::<Connectivity>()
+            .set(Connectivity::NetworkReconnect {
+                duration_ms: 1200,
+                reconnected_peers: 2,
+                unreachable_peers: 1,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"connectivity","body":{"kind":"network_reconnect","#,
+                r#""duration_ms":1200,"reconnected_peers":2,"unreachable_peers":1}}"#
+            ),
+            reconnect.to_json().unwrap()
+        );
+
+        let path = Event::builder::<Connectivity>()
+            .set(Connectivity::NetworkPathChanged {
+                expensive: true,
+                constrained: false,
+                over_vpn: true,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"connectivity","body":{"kind":"network_path_changed","#,
+                r#""expensive":true,"constrained":false,"over_vpn":true}}"#
+            ),
+            path.to_json().unwrap()
+        );
+    }
+
+    #[test]
+    fn pq_rekey_to_json() {
+        let failed = Event::builder::<PqRekey>()
+            .set(PqRekey {
+                public_key: PublicKey([1_u8; KEY_SIZE]),
+                version: 2,
+                state: PqRekeyState::Failed,
+                error: Some("TIMEOUT(8s)".to_owned()),
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"pqrekey","body":{"#,
+                r#""public_key":"AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=","#,
+                r#""version":2,"state":"failed","error":"TIMEOUT(8s)"}}"#
+            ),
+            failed.to_json().unwrap()
+        );
+    }
+
+    #[test]
+    fn inbound_approval_to_json() {
+        let approval = Event::builder::<InboundApproval>()
+            .set(InboundApproval {
+                public_key: PublicKey([1_u8; KEY_SIZE]),
+                destination: SocketAddr::from(([100, 64, 0, 1], 22)),
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"inboundapproval","body":{"#,
+                r#""public_key":"AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=","#,
+                r#""destination":"100.64.0.1:22"}}"#
+            ),
+            approval.to_json().unwrap()
+        );
+    }
+
+    #[test]
+    fn readiness_to_json() {
+        let readiness = Event::builder::<Readiness>()
+            .set(Readiness {
+                ready: vec![ReadinessComponent::Firewall, ReadinessComponent::Relay],
+                mesh_formed_percent: Some(50),
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"readiness","body":{"ready":["firewall","relay"],"#,
+                r#""mesh_formed_percent":50}}"#
+            ),
+            readiness.to_json().unwrap()
+        );
+    }
 }
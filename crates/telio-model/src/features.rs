@@ -1,13 +1,23 @@
 //! Object descriptions of various
 //! telio configurable features via API
 
-use std::{collections::HashSet, fmt, net::IpAddr, str::FromStr, time::Duration};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
 
 use base64::{prelude::BASE64_STANDARD, Engine};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+use serde::{
+    de::{self, IntoDeserializer},
+    Deserialize, Deserializer, Serialize,
+};
 use smart_default::SmartDefault;
 use strum_macros::EnumCount;
+use telio_crypto::PublicKey;
 use telio_utils::{exponential_backoff::ExponentialBackoffBounds, telio_log_warn};
 
 /// Type alias for UniFFI
@@ -52,6 +62,10 @@ pub struct Features {
     pub firewall: FeatureFirewall,
     /// If and for how long to flush events when stopping telio. Setting to Some(0) means waiting until all events have been flushed, regardless of how long it takes
     pub flush_events_on_stop_timeout_seconds: Option<u64>,
+    /// Bound of the time telio start may take, past it the start fails with the components ready
+    /// so far reported by the readiness events, and is rolled back once it completes in the
+    /// background. Unbounded when not set.
+    pub startup_timeout_seconds: Option<u64>,
     /// Post quantum VPN tunnel configuration
     pub post_quantum_vpn: FeaturePostQuantumVPN,
     /// No link detection mechanism
@@ -60,10 +74,27 @@ pub struct Features {
     pub dns: FeatureDns,
     /// Multicast support
     pub multicast: bool,
+    /// Groups and broadcasts relayed by the multicast support, the whole multicast range when not
+    /// set
+    pub starcast: Option<FeatureStarcast>,
     /// Batching feature configuration, disabled by default, used for batching keep-alives
     pub batching: Option<FeatureBatching>,
     /// Configuration for the Error Notification Service
     pub error_notification_service: Option<FeatureErrorNotificationService>,
+    /// Periodic device key rotation
+    pub key_rotation: Option<FeatureKeyRotation>,
+    /// Export of metrics and spans to an OpenTelemetry collector
+    pub otlp: Option<FeatureOtlp>,
+    /// Fast path of the reconnect after the network changed
+    pub fast_reconnect: Option<FeatureFastReconnect>,
+    /// Supervision of the internal tasks, restarting the dead and stalled ones
+    pub supervision: Option<FeatureSupervision>,
+    /// Accounting of the traffic exchanged with each peer
+    pub accounting: Option<FeatureAccounting>,
+    /// Local HTTP endpoint probed for the health and status of the device
+    pub health_endpoint: Option<FeatureHealthEndpoint>,
+    /// Most peers accepted in a meshnet config, unlimited when not set
+    pub max_meshnet_peers: Option<u32>,
 }
 
 impl Features {
@@ -114,6 +145,27 @@ pub struct FeatureWireguard {
     /// Configurable socket buffer size for NepTUN
     #[serde(default)]
     pub max_inter_thread_batched_pkts: Option<u32>,
+    /// Number of the NepTUN worker threads, each running the crypto of its own share of the
+    /// packets. Defaults to the number of CPUs, four on Android and one on Apple platforms
+    #[serde(default, deserialize_with = "deserialize_worker_threads")]
+    #[cfg_attr(test, proptest(strategy = "worker_threads_strategy()"))]
+    pub worker_threads: Option<u32>,
+    /// Open a queue of the NepTUN TUN interface per worker thread on Linux, so that the workers do
+    /// not contend on a single queue [default true]
+    #[serde(default)]
+    pub multi_queue_tun: Option<bool>,
+    /// Stage insertion of new peers, so that large meshmaps do not handshake all at once
+    #[serde(default)]
+    pub handshake_throttling: Option<FeatureHandshakeThrottling>,
+    /// Tune keepalive of direct peers to the NAT binding lifetime of the current network
+    #[serde(default)]
+    pub adaptive_keepalive: Option<FeatureAdaptiveKeepalive>,
+    /// Handshake obfuscation parameters, used by exit nodes connected with obfuscation
+    #[serde(default)]
+    pub obfuscation: Option<FeatureObfuscation>,
+    /// Fallback of the exit node connection to TLS and WebSocket streams when UDP is blocked
+    #[serde(default)]
+    pub stream_transport: Option<FeatureStreamTransport>,
 }
 
 impl FeatureWireguard {
@@ -126,6 +178,107 @@ impl FeatureWireguard {
     }
 }
 
+/// Configurable staging of handshakes with newly added peers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureHandshakeThrottling {
+    /// Maximum number of meshnet peers added (and so handshaked with) per WireGuard
+    /// consolidation round, at least one peer is always added [default 32]
+    #[default = 32]
+    pub max_handshakes_per_round: u32,
+}
+
+/// Configurable adaptive keepalive of direct peers.
+///
+/// Starting from `persistent_keepalive.direct`, the interval is increased while direct peers
+/// stay reachable, and reduced back once a NAT binding is lost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureAdaptiveKeepalive {
+    /// Upper bound of the keepalive interval in seconds [default 120s]
+    #[default = 120]
+    pub max_interval_s: u32,
+    /// Measure the NAT binding lifetime of each network through the STUN server of a relay
+    /// and start from just under it, instead of slowly growing the interval [default false]
+    pub probe_binding_lifetime: bool,
+}
+
+/// Configurable AmneziaWG compatible obfuscation of WireGuard packets.
+///
+/// Values have to match the configuration of the server, otherwise no handshake completes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureObfuscation {
+    /// Number of junk packets sent before every handshake initiation (Jc) [default 4]
+    #[default = 4]
+    pub junk_packet_count: u32,
+    /// Minimum size of a junk packet in bytes (Jmin) [default 40]
+    #[default = 40]
+    pub junk_packet_min_size: u32,
+    /// Maximum size of a junk packet in bytes (Jmax) [default 70]
+    #[default = 70]
+    pub junk_packet_max_size: u32,
+    /// Random bytes prepended to handshake initiations (S1) [default 0]
+    pub init_packet_junk_size: u32,
+    /// Random bytes prepended to handshake responses (S2) [default 0]
+    pub response_packet_junk_size: u32,
+    /// Message type of handshake initiations (H1) [default 1]
+    #[default = 1]
+    pub init_packet_magic_header: u32,
+    /// Message type of handshake responses (H2) [default 2]
+    #[default = 2]
+    pub response_packet_magic_header: u32,
+    /// Message type of cookie replies (H3) [default 3]
+    #[default = 3]
+    pub cookie_packet_magic_header: u32,
+    /// Message type of transport data (H4) [default 4]
+    #[default = 4]
+    pub transport_packet_magic_header: u32,
+}
+
+/// Transports wrapping WireGuard traffic of the exit node into a TCP stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub enum StreamTransport {
+    /// Length prefixed packets inside a TLS connection
+    Tls,
+    /// Binary WebSocket messages inside a TLS connection
+    Websocket,
+}
+
+/// Configurable fallback of the exit node connection to stream transports.
+///
+/// Plain UDP is tried first, then every transport of `fallback_order` in turn, each one given
+/// `handshake_timeout_s` to complete a handshake, before starting over with UDP. A transport
+/// failing to connect is skipped right away. Meshnet peers are not affected, as they already
+/// fall back to DERP.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureStreamTransport {
+    /// Transports tried after plain UDP, in order [default ["tls", "websocket"]]
+    #[default(vec![StreamTransport::Tls, StreamTransport::Websocket])]
+    pub fallback_order: Vec<StreamTransport>,
+    /// Seconds without a handshake after which the next transport is tried [default 15s]
+    #[default = 15]
+    pub handshake_timeout_s: u32,
+    /// TCP port of the stream transports on the exit node [default 443]
+    #[default = 443]
+    pub port: u16,
+    /// Request path of the WebSocket upgrade [default "/wireguard"]
+    #[default(String::from("/wireguard"))]
+    pub websocket_path: String,
+    /// Name used to verify the TLS certificate of the exit node, its IP address when missing
+    /// [default None]
+    pub server_name: Option<String>,
+    /// Use Mozilla's root certificates instead of OS ones [default false]
+    pub use_built_in_root_certificates: bool,
+}
+
 /// Configurable persistent keepalive periods for different types of peers
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
 #[serde(default)]
@@ -295,6 +448,31 @@ pub struct FeatureDirect {
     /// Configurable features for UPNP endpoint provider
     #[default(Some(Default::default()))]
     pub upnp_features: Option<FeatureUpnp>,
+    /// Configurable features for NAT-PMP / PCP endpoint provider
+    #[default(Some(Default::default()))]
+    pub port_mapping_features: Option<FeaturePortMapping>,
+    /// Gather and exchange IPv6 endpoint candidates of local interfaces [default false]
+    pub ipv6_candidates: bool,
+    /// Attempt TCP hole punching when UDP pings to a peer time out [default false]
+    pub tcp_hole_punching: bool,
+    /// Discover the peers on the same LAN by multicast announcements [default disabled]
+    pub lan_discovery: Option<FeatureLanDiscovery>,
+}
+
+/// Configurable features for the discovery of the peers on the same LAN
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureLanDiscovery {
+    /// Multicast group the announcements are sent to [default 239.255.76.84]
+    #[default(Ipv4Addr::new(239, 255, 76, 84))]
+    pub multicast_group: Ipv4Addr,
+    /// UDP port of the announcements [default 51900]
+    #[default = 51900]
+    pub port: u16,
+    /// Period of the announcements, in seconds [default 5]
+    #[default = 5]
+    pub announce_interval_s: u32,
 }
 
 fn deserialize_providers<'de, D>(de: D) -> Result<Option<EndpointProviders>, D::Error>
@@ -320,6 +498,23 @@ where
     Ok(Some(eps))
 }
 
+fn deserialize_worker_threads<'de, D>(de: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u32>::deserialize(de)? {
+        Some(0) => Err(de::Error::custom(
+            "wireguard.worker_threads must be at least 1",
+        )),
+        worker_threads => Ok(worker_threads),
+    }
+}
+
+#[cfg(test)]
+fn worker_threads_strategy() -> impl proptest::strategy::Strategy<Value = Option<u32>> {
+    proptest::option::of(1u32..=256)
+}
+
 /// Available Endpoint Providers for meshnet direct connections
 #[derive(
     Clone,
@@ -344,6 +539,8 @@ pub enum EndpointProvider {
     Stun = 2,
     /// Use IGD and upnp to generate endpoints
     Upnp = 3,
+    /// Use NAT-PMP or PCP port mappings to generate endpoints
+    PortMapping = 4,
 }
 
 /// Avoid sending periodic messages to peers with no traffic reported by wireguard
@@ -386,6 +583,75 @@ pub struct FeatureDerp {
     /// Use Mozilla's root certificates instead of OS ones [default false]
     #[serde(default)]
     pub use_built_in_root_certificates: bool,
+    /// Transport used to reach DERP servers [default tcp]
+    #[serde(default)]
+    pub transport: DerpTransport,
+    /// Periodic health checks of the servers, preferring the fastest healthy one [default disabled]
+    #[serde(default)]
+    pub health_check: Option<FeatureRelayHealthCheck>,
+    /// Tunneling over HTTPS long-polling when neither QUIC nor TCP reach the servers [default disabled]
+    #[serde(default)]
+    pub long_poll: Option<FeatureDerpLongPoll>,
+}
+
+/// DERP over HTTPS long-polling, for networks allowing only plain HTTPS requests. The servers have
+/// to serve the endpoint described in docs/derp_long_poll.md
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureDerpLongPoll {
+    /// Consecutive failed connection attempts to a server after which long-polling is used
+    /// instead [default 3]
+    #[default = 3]
+    pub probe_failures: u32,
+}
+
+/// Health monitoring of DERP servers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureRelayHealthCheck {
+    /// Interval between checks of all the servers [default 30s]
+    #[default = 30]
+    pub interval_s: u32,
+    /// Timeout for reaching a single server [default 5s]
+    #[default = 5]
+    pub timeout_s: u32,
+    /// Consecutive failed checks after which a server is unhealthy [default 3]
+    #[default = 3]
+    pub max_failures: u32,
+    /// Switching to a faster server while the active one is still healthy [default disabled]
+    pub rehoming: Option<FeatureRelayRehoming>,
+    /// Interval of the relay quality events, rounded up to the 5s state polling [default disabled]
+    pub quality_report_interval_s: Option<u32>,
+}
+
+/// Switching of the active DERP server to a faster one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureRelayRehoming {
+    /// How much slower than the fastest server the active one has to be [default 50ms]
+    #[default = 50]
+    pub latency_threshold_ms: u32,
+    /// Number of checks in a row in which the active server has to be slower [default 3]
+    #[default = 3]
+    pub consecutive_checks: u32,
+    /// Minimal time between two switches [default 600s]
+    #[default = 600]
+    pub cooldown_s: u32,
+}
+
+/// Transport used to reach DERP servers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub enum DerpTransport {
+    /// TLS over TCP
+    #[default]
+    Tcp,
+    /// QUIC on the same UDP port, falling back to TCP when it cannot be established
+    Quic,
 }
 
 /// Whether to validate keys
@@ -477,6 +743,24 @@ pub struct FeatureFirewall {
     /// Blackist for outgoing connections
     #[serde(default)]
     pub outgoing_blacklist: Vec<FirewallBlacklistTuple>,
+    /// [Windows only] Killswitch and DNS leak protection using Windows Filtering Platform
+    #[serde(default)]
+    pub killswitch: Option<FeatureKillswitch>,
+    /// [Linux only] Forwarding and masquerade rules for meshnet peers routing through this device
+    #[serde(default)]
+    pub exit_node_rules: Option<FeatureExitNodeRules>,
+    /// Export of flow records for the traffic passing the firewall
+    #[serde(default)]
+    pub flow_export: Option<FeatureFlowExport>,
+    /// Keep the tracked connections across restarts of the device
+    #[serde(default)]
+    pub conntrack_persistence: Option<FeatureConntrackPersistence>,
+    /// Ask the app to approve the first inbound connection of the peers without the permission
+    #[serde(default)]
+    pub inbound_approval: Option<FeatureInboundApproval>,
+    /// Egress bandwidth limits of the traffic to the peers, by bandwidth class
+    #[serde(default)]
+    pub shaping: Option<FeatureShaping>,
 }
 
 impl FeatureFirewall {
@@ -489,6 +773,134 @@ impl FeatureFirewall {
     }
 }
 
+/// Configurable exit node rules, installed while meshnet peers are allowed to route through the device
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureExitNodeRules {
+    /// Firewall used to install the rules [default auto]
+    pub backend: ExitNodeRulesBackend,
+}
+
+/// Firewall used to install the exit node rules
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub enum ExitNodeRulesBackend {
+    /// Use nftables when available, fall back to iptables otherwise
+    #[default]
+    Auto,
+    /// Use nftables
+    Nftables,
+    /// Use iptables
+    Iptables,
+}
+
+/// Configurable export of flow records, emitted when a connection ends
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureFlowExport {
+    /// IPFIX collector the finished flows are sent to over UDP [default none]
+    pub collector: Option<SocketAddr>,
+    /// Maximal number of simultaneously tracked flows [default 8192]
+    #[default = 8192]
+    pub max_flows: u32,
+}
+
+/// Configurable persistence of the tracked connections, so that the connections established
+/// through the tunnel are not reset after a brief restart
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureConntrackPersistence {
+    /// File the connections are saved to on stop, kept in memory only if not set [default none]
+    pub path: Option<String>,
+    /// Saved connections older than this are not restored [default 30]
+    #[default = 30]
+    pub max_age_s: u32,
+}
+
+/// Configurable approval of the inbound connections of the peers which are not allowed to make
+/// them, a meshnet equivalent of a personal firewall prompt
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureInboundApproval {
+    /// Unanswered approval requests are repeated after this [default 60]
+    #[default = 60]
+    pub prompt_timeout_s: u32,
+    /// How long the connections of a peer allowed once are accepted [default 3600]
+    #[default = 3600]
+    pub allow_once_s: u32,
+}
+
+/// Configurable egress bandwidth shaping, so that bulk transfers to some of the peers do not
+/// starve the interactive traffic to the others. Enforced by the userspace datapath, so with the
+/// NepTUN adapter only.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureShaping {
+    /// Limit of the traffic of all the classes together, shared by their priority. Unlimited when
+    /// not set [default none]
+    pub total: Option<FeatureBandwidthLimit>,
+    /// Bandwidth classes, the traffic to the peers in none of them is not shaped [default empty]
+    pub classes: Vec<FeatureBandwidthClass>,
+}
+
+/// Sustained rate with a burst allowance
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureBandwidthLimit {
+    /// Sustained rate, in kilobits per second
+    pub rate_kbps: u32,
+    /// Bytes which may be sent at once after an idle period, at least a full packet
+    pub burst_bytes: u32,
+}
+
+/// Peers whose traffic shares a bandwidth limit
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureBandwidthClass {
+    /// Name of the class, e.g. "backup"
+    pub name: String,
+    /// Limit of the traffic to all the peers of the class together
+    pub limit: FeatureBandwidthLimit,
+    /// Within the total limit, the bursts of the classes with a higher priority are kept in
+    /// reserve for them [default 0]
+    #[serde(default)]
+    pub priority: u8,
+    /// Peers of the class [default empty]
+    #[serde(default)]
+    #[cfg_attr(test, proptest(strategy = "public_keys_strategy()"))]
+    pub peers: Vec<PublicKey>,
+    /// Whether the VPN server in use belongs to the class, meshnet exit nodes are listed in the
+    /// peers [default false]
+    #[serde(default)]
+    pub exit_node: bool,
+}
+
+#[cfg(test)]
+fn public_keys_strategy() -> impl proptest::strategy::Strategy<Value = Vec<PublicKey>> {
+    use proptest::prelude::*;
+    proptest::collection::vec(any::<[u8; 32]>().prop_map(PublicKey), 0..4)
+}
+
+/// Configurable killswitch, enforced while connected to a VPN exit node
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureKillswitch {
+    /// Block DNS requests which are not sent through the tunnel [default true]
+    #[default = true]
+    pub block_dns_leaks: bool,
+    /// Keep the filters installed when libtelio stops or crashes [default false]
+    pub persistent: bool,
+    /// Enforce the filters during boot, before the base filtering engine starts [default false]
+    pub boot_time: bool,
+}
+
 /// Turns on post quantum VPN tunnel
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
 #[serde(default)]
@@ -502,9 +914,19 @@ pub struct FeaturePostQuantumVPN {
     #[default = 90]
     pub rekey_interval_s: u32,
 
-    /// Post-quantum protocol version, currently supported versions: 1, 2
+    /// Upper bound of the random delay added to every rekey interval, in seconds. Spreads the
+    /// rekeys of many clients over time [default 0]
+    #[default = 0]
+    pub rekey_jitter_s: u32,
+
+    /// Post-quantum protocol version, currently supported versions: 1, 2 and 3, the hybrid
+    /// X25519 + ML-KEM-768 exchange
     #[default = 1]
     pub version: u32,
+
+    /// Lowest version to fall back to when the server does not support `version`. Versions
+    /// already agreed with a server are never fallen back from [default none]
+    pub min_version: Option<u32>,
 }
 
 /// Turns on the no link detection mechanism
@@ -531,6 +953,75 @@ pub struct FeatureDns {
     /// Configure options for exit dns
     #[serde(default)]
     pub exit_dns: Option<FeatureExitDns>,
+    /// Additional plain UDP listeners of the stub resolver
+    #[serde(default)]
+    pub stub_listeners: Vec<FeatureDnsStubListener>,
+    /// Forwarding of the queries to multiple upstream servers
+    #[serde(default)]
+    pub upstreams: FeatureDnsUpstreams,
+}
+
+/// Forwarding of the DNS queries to multiple upstream servers, with the failing ones quarantined
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureDnsUpstreams {
+    /// How the upstream servers are queried [default parallel]
+    pub strategy: DnsUpstreamStrategy,
+    /// Time to wait for the answer of a single upstream server, in milliseconds [default 5000]
+    #[default = 5000]
+    pub timeout_ms: u32,
+    /// Consecutive failures after which an upstream server is quarantined [default 3]
+    #[default = 3]
+    pub max_failures: u32,
+    /// How long a quarantined upstream server is used only as the last resort, in seconds [default 60]
+    #[default = 60]
+    pub quarantine_s: u32,
+    /// Weights of the upstream servers for the weighted strategy, unlisted ones weigh 1 [default empty]
+    pub weights: Vec<FeatureDnsUpstreamWeight>,
+}
+
+/// How the DNS queries are sent to the upstream servers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub enum DnsUpstreamStrategy {
+    /// One by one in the configured order, moving to the next one on failure or timeout
+    Sequential,
+    /// To all of the healthy ones at once, the first answer wins, so that no query waits for
+    /// the timeout of a dead server
+    #[default]
+    Parallel,
+    /// One by one, the first one picked in proportion to the weights
+    Weighted,
+}
+
+/// Weight of an upstream DNS server for the weighted strategy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureDnsUpstreamWeight {
+    /// Address of the server
+    pub address: IpAddr,
+    /// Relative share of the queries sent to the server first
+    pub weight: u32,
+}
+
+/// Plain UDP listener of the stub resolver, serving the same zones as the in-tunnel resolver.
+///
+/// Binding to `127.0.0.1` makes it reachable only from the local host, while binding to the
+/// address of the tunnel interface serves only queries arriving through the tunnel. Other
+/// addresses are refused when the resolver starts. A port other than 53 allows it to coexist
+/// with systemd-resolved and other local resolvers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureDnsStubListener {
+    /// Address to bind the listener to, a loopback or a tunnel address [default 127.0.0.1]
+    #[default(IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub address: IpAddr,
+    /// UDP port to bind the listener to [default 53]
+    #[default = 53]
+    pub port: u16,
 }
 
 /// Newtype for TTL value to ensure that the default function returns the actual default value and not 0.
@@ -564,6 +1055,32 @@ pub struct FeatureUpnp {
     pub lease_duration_s: u32,
 }
 
+/// Protocol used to request port mappings from the gateway
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub enum PortMappingProtocol {
+    /// Port Control Protocol, RFC 6887
+    Pcp,
+    /// NAT Port Mapping Protocol, RFC 6886
+    NatPmp,
+}
+
+/// Configurable features for NAT-PMP / PCP endpoint provider
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeaturePortMapping {
+    /// Protocols to try, in order of preference [default pcp, nat-pmp]
+    #[default(vec![PortMappingProtocol::Pcp, PortMappingProtocol::NatPmp])]
+    pub protocols: Vec<PortMappingProtocol>,
+    /// Requested lifetime of the mappings, in seconds [default 3600]
+    #[default = 3600]
+    pub lease_duration_s: u32,
+    /// Gateway to request mappings from, the default route gateway when not set
+    pub gateway: Option<IpAddr>,
+}
+
 /// Configuration for the Error Notification Service
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
 #[serde(default)]
@@ -636,6 +1153,143 @@ impl From<Backoff> for ExponentialBackoffBounds {
     }
 }
 
+/// Configurable features for periodic device key rotation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureKeyRotation {
+    /// How often a new device keypair is generated, in seconds [default 86400s]
+    #[default = 86400]
+    pub interval_s: u64,
+    /// How long the old key stays in use after the new key was confirmed, in seconds [default 60s]
+    #[default = 60]
+    pub grace_period_s: u64,
+}
+
+/// Configurable export of metrics and spans to an OpenTelemetry collector. Takes effect only when
+/// libtelio is built with the `otlp` feature
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureOtlp {
+    /// OTLP/HTTP collector receiving the exports [default 127.0.0.1:4318]
+    #[default(SocketAddr::from(([127, 0, 0, 1], 4318)))]
+    pub collector: SocketAddr,
+    /// Name the certificate of the collector is verified against. The exports are sent over
+    /// HTTPS when set, and over plain HTTP to a local collector otherwise [default none]
+    pub tls_server_name: Option<String>,
+    /// Service name reported with every export [default "libtelio"]
+    #[default("libtelio".to_owned())]
+    pub service_name: String,
+    /// How often the collected data is exported, in seconds [default 60s]
+    #[default = 60]
+    pub export_interval_s: u32,
+}
+
+/// Configurable fast path of the reconnect after the network changed. Sockets are rebound, the
+/// endpoints discovered again and the peers connected before the change are made to handshake
+/// right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureFastReconnect {
+    /// Time the peers are given to be heard from again, in milliseconds. The handshakes are
+    /// retried until then and the reconnect is reported after it at the latest [default 5000ms]
+    #[default = 5000]
+    pub budget_ms: u32,
+    /// Reconnect when libtelio detects a change of the local interfaces, without waiting for
+    /// libtelio.notify_network_change(...) [default true]
+    #[default = true]
+    pub follow_interface_changes: bool,
+}
+
+/// Configurable supervision of the internal tasks. Every task is sent a heartbeat periodically,
+/// the tasks which stopped on their own are dead and the ones not answering in time are stalled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureSupervision {
+    /// Period of the heartbeats, in seconds [default 10s]
+    #[default = 10]
+    pub check_interval_s: u32,
+    /// Time the tasks have to answer a heartbeat, in seconds [default 30s]
+    #[default = 30]
+    pub stall_timeout_s: u32,
+    /// Policy of the tasks without one of their own [default escalate]
+    pub default_policy: RestartPolicy,
+    /// Policies of the individual tasks [default empty]
+    pub policies: Vec<FeatureSupervisionPolicy>,
+}
+
+/// What is done once a task is found dead or stalled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub enum RestartPolicy {
+    /// Restart the component the task belongs to, the meshnet for its tasks
+    RestartComponent,
+    /// Stop every task of the device and start them anew, with the same meshnet config and
+    /// exit node
+    RestartDevice,
+    /// Only report the task in an error event
+    #[default]
+    Escalate,
+}
+
+/// Restart policy of a single task
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureSupervisionPolicy {
+    /// Name of the task, e.g. "DerpRelay"
+    pub task: String,
+    /// Policy applied to the task
+    pub policy: RestartPolicy,
+}
+
+/// Configurable accounting of the traffic exchanged with each peer, including the exit node. The
+/// traffic is rolled up into the minutes of the last hour and the hours of the last day.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureAccounting {
+    /// File the rollups are saved to every minute and restored from on start, kept in memory
+    /// only if not set [default none]
+    pub path: Option<String>,
+}
+
+/// Configurable HTTP endpoint serving `/healthz` and `/status` on the loopback, for orchestrators
+/// probing headless deployments. Takes effect only when libtelio is built with the
+/// `health_endpoint` feature
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureHealthEndpoint {
+    /// Port listened on at 127.0.0.1 [default 9180]
+    #[default = 9180]
+    pub port: u16,
+    /// Time without the device polling its state after which it is reported unhealthy, in
+    /// seconds [default 30s]
+    #[default = 30]
+    pub stall_timeout_s: u32,
+}
+
+/// Configurable relay of the multicast groups and broadcasts between the meshnet peers allowing
+/// multicast. The meshnet counts as a single link, so link-local groups pass unchanged.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct FeatureStarcast {
+    /// IPv4 multicast groups relayed, e.g. 239.255.255.250/32 for SSDP and WS-Discovery or
+    /// 224.0.0.251/32 for mDNS used by Chromecast. All of 224.0.0.0/4 when not set [default None]
+    pub groups: Option<Vec<Ipv4Net>>,
+    /// Relay the directed broadcasts of the meshnet network [default false]
+    pub directed_broadcast: bool,
+    /// Count the relay as a router hop for the groups beyond the link-local scope: the TTL of
+    /// their packets is decremented and the packets which exhausted it are dropped
+    /// [default false]
+    pub decrement_ttl: bool,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -701,7 +1355,35 @@ mod tests {
                 "enable_dynamic_wg_nt_control": true,
                 "skt_buffer_size": 123456,
                 "inter_thread_channel_size": 123456,
-                "max_inter_thread_batched_pkts": 123456
+                "max_inter_thread_batched_pkts": 123456,
+                "worker_threads": 8,
+                "multi_queue_tun": false,
+                "handshake_throttling": {
+                    "max_handshakes_per_round": 16
+                },
+                "adaptive_keepalive": {
+                    "max_interval_s": 90,
+                    "probe_binding_lifetime": true
+                },
+                "obfuscation": {
+                    "junk_packet_count": 3,
+                    "junk_packet_min_size": 10,
+                    "junk_packet_max_size": 50,
+                    "init_packet_junk_size": 15,
+                    "response_packet_junk_size": 18,
+                    "init_packet_magic_header": 1020325451,
+                    "response_packet_magic_header": 3288052141,
+                    "cookie_packet_magic_header": 1766607858,
+                    "transport_packet_magic_header": 2528465083
+                },
+                "stream_transport": {
+                    "fallback_order": ["websocket"],
+                    "handshake_timeout_s": 10,
+                    "port": 8443,
+                    "websocket_path": "/wg",
+                    "server_name": "vpn.example.com",
+                    "use_built_in_root_certificates": true
+                }
             },
             "nurse": {
                 "fingerprint": "test_fingerprint",
@@ -737,6 +1419,18 @@ mod tests {
                 },
                 "upnp_features": {
                     "lease_duration_s": 60
+                },
+                "port_mapping_features": {
+                    "protocols": ["nat-pmp"],
+                    "lease_duration_s": 120,
+                    "gateway": "192.168.1.1"
+                },
+                "ipv6_candidates": true,
+                "tcp_hole_punching": true,
+                "lan_discovery": {
+                    "multicast_group": "239.255.0.1",
+                    "port": 50000,
+                    "announce_interval_s": 3
                 }
             },
             "is_test_env": true,
@@ -747,7 +1441,22 @@ mod tests {
                 "derp_keepalive": 14,
                 "poll_keepalive": true,
                 "enable_polling": true,
-                "use_built_in_root_certificates": true
+                "use_built_in_root_certificates": true,
+                "transport": "quic",
+                "health_check": {
+                    "interval_s": 20,
+                    "timeout_s": 2,
+                    "max_failures": 4,
+                    "rehoming": {
+                        "latency_threshold_ms": 30,
+                        "consecutive_checks": 5,
+                        "cooldown_s": 120
+                    },
+                    "quality_report_interval_s": 60
+                },
+                "long_poll": {
+                    "probe_failures": 2
+                }
             },
             "validate_keys": false,
             "ipv6": true,
@@ -760,13 +1469,52 @@ mod tests {
                     "protocol": "UDP",
                     "ip": "8.8.4.4",
                     "port": 30
-                }]
+                }],
+                "killswitch": {
+                    "block_dns_leaks": false,
+                    "persistent": true,
+                    "boot_time": true
+                },
+                "exit_node_rules": {
+                    "backend": "iptables"
+                },
+                "flow_export": {
+                    "collector": "127.0.0.1:2055",
+                    "max_flows": 100
+                },
+                "conntrack_persistence": {
+                    "path": "/var/lib/telio/conntrack.json",
+                    "max_age_s": 10
+                },
+                "inbound_approval": {
+                    "prompt_timeout_s": 30,
+                    "allow_once_s": 600
+                },
+                "shaping": {
+                    "total": {
+                        "rate_kbps": 100000,
+                        "burst_bytes": 262144
+                    },
+                    "classes": [{
+                        "name": "backup",
+                        "limit": {
+                            "rate_kbps": 20000,
+                            "burst_bytes": 65536
+                        },
+                        "priority": 1,
+                        "peers": ["AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="],
+                        "exit_node": true
+                    }]
+                }
             },
             "flush_events_on_stop_timeout_seconds": 15,
+            "startup_timeout_seconds": 20,
             "post_quantum_vpn": {
                 "handshake_retry_interval_s": 15,
                 "rekey_interval_s": 16,
-                "version": 1
+                "rekey_jitter_s": 5,
+                "version": 3,
+                "min_version": 2
             },
             "link_detection": {
                 "rtt_seconds": 17,
@@ -776,6 +1524,19 @@ mod tests {
                 "ttl_value": 19,
                 "exit_dns": {
                     "auto_switch_dns_ips": true
+                },
+                "stub_listeners": [
+                    {
+                        "address": "127.0.0.1",
+                        "port": 5353
+                    }
+                ],
+                "upstreams": {
+                    "strategy": "weighted",
+                    "timeout_ms": 1500,
+                    "max_failures": 2,
+                    "quarantine_s": 30,
+                    "weights": [{"address": "1.1.1.1", "weight": 3}]
                 }
             },
             "multicast": true,
@@ -786,6 +1547,35 @@ mod tests {
             },
             "error_notification_service": {
                 "buffer_size": 42
+            },
+            "otlp": {
+                "collector": "10.0.0.1:4318",
+                "tls_server_name": "otel.example.com",
+                "service_name": "telio-fleet",
+                "export_interval_s": 30
+            },
+            "fast_reconnect": {
+                "budget_ms": 3000,
+                "follow_interface_changes": false
+            },
+            "supervision": {
+                "check_interval_s": 5,
+                "stall_timeout_s": 20,
+                "default_policy": "restart-device",
+                "policies": [{"task": "DerpRelay", "policy": "restart-component"}]
+            },
+            "accounting": {
+                "path": "/var/lib/telio/accounting.json"
+            },
+            "health_endpoint": {
+                "port": 8080,
+                "stall_timeout_s": 60
+            },
+            "max_meshnet_peers": 500,
+            "starcast": {
+                "groups": ["239.255.255.250/32", "224.0.0.251/32"],
+                "directed_broadcast": true,
+                "decrement_ttl": true
             }
         }
         "#,
@@ -805,6 +1595,34 @@ mod tests {
                         skt_buffer_size: Some(123456),
                         inter_thread_channel_size: Some(123456),
                         max_inter_thread_batched_pkts: Some(123456),
+                        worker_threads: Some(8),
+                        multi_queue_tun: Some(false),
+                        handshake_throttling: Some(FeatureHandshakeThrottling {
+                            max_handshakes_per_round: 16,
+                        }),
+                        adaptive_keepalive: Some(FeatureAdaptiveKeepalive {
+                            max_interval_s: 90,
+                            probe_binding_lifetime: true,
+                        }),
+                        obfuscation: Some(FeatureObfuscation {
+                            junk_packet_count: 3,
+                            junk_packet_min_size: 10,
+                            junk_packet_max_size: 50,
+                            init_packet_junk_size: 15,
+                            response_packet_junk_size: 18,
+                            init_packet_magic_header: 1020325451,
+                            response_packet_magic_header: 3288052141,
+                            cookie_packet_magic_header: 1766607858,
+                            transport_packet_magic_header: 2528465083,
+                        }),
+                        stream_transport: Some(FeatureStreamTransport {
+                            fallback_order: vec![StreamTransport::Websocket],
+                            handshake_timeout_s: 10,
+                            port: 8443,
+                            websocket_path: "/wg".to_owned(),
+                            server_name: Some("vpn.example.com".to_owned()),
+                            use_built_in_root_certificates: true,
+                        }),
                     },
                     nurse: Some(FeatureNurse {
                         heartbeat_interval: 5,
@@ -842,6 +1660,18 @@ mod tests {
                         upnp_features: Some(FeatureUpnp {
                             lease_duration_s: 60
                         }),
+                        port_mapping_features: Some(FeaturePortMapping {
+                            protocols: vec![PortMappingProtocol::NatPmp],
+                            lease_duration_s: 120,
+                            gateway: Some(IpAddr::from([192, 168, 1, 1])),
+                        }),
+                        ipv6_candidates: true,
+                        tcp_hole_punching: true,
+                        lan_discovery: Some(FeatureLanDiscovery {
+                            multicast_group: Ipv4Addr::new(239, 255, 0, 1),
+                            port: 50000,
+                            announce_interval_s: 3,
+                        }),
                     }),
                     is_test_env: Some(true),
                     hide_user_data: false,
@@ -852,6 +1682,19 @@ mod tests {
                         poll_keepalive: Some(true),
                         enable_polling: Some(true),
                         use_built_in_root_certificates: true,
+                        transport: DerpTransport::Quic,
+                        health_check: Some(FeatureRelayHealthCheck {
+                            interval_s: 20,
+                            timeout_s: 2,
+                            max_failures: 4,
+                            rehoming: Some(FeatureRelayRehoming {
+                                latency_threshold_ms: 30,
+                                consecutive_checks: 5,
+                                cooldown_s: 120,
+                            }),
+                            quality_report_interval_s: Some(60),
+                        }),
+                        long_poll: Some(FeatureDerpLongPoll { probe_failures: 2 }),
                     }),
                     validate_keys: FeatureValidateKeys(false),
                     ipv6: true,
@@ -867,12 +1710,51 @@ mod tests {
                             ip: IpAddr::from_str("8.8.4.4").unwrap(),
                             port: 30,
                         }],
+                        killswitch: Some(FeatureKillswitch {
+                            block_dns_leaks: false,
+                            persistent: true,
+                            boot_time: true,
+                        }),
+                        exit_node_rules: Some(FeatureExitNodeRules {
+                            backend: ExitNodeRulesBackend::Iptables,
+                        }),
+                        flow_export: Some(FeatureFlowExport {
+                            collector: Some(SocketAddr::from(([127, 0, 0, 1], 2055))),
+                            max_flows: 100,
+                        }),
+                        conntrack_persistence: Some(FeatureConntrackPersistence {
+                            path: Some("/var/lib/telio/conntrack.json".to_owned()),
+                            max_age_s: 10,
+                        }),
+                        inbound_approval: Some(FeatureInboundApproval {
+                            prompt_timeout_s: 30,
+                            allow_once_s: 600,
+                        }),
+                        shaping: Some(FeatureShaping {
+                            total: Some(FeatureBandwidthLimit {
+                                rate_kbps: 100000,
+                                burst_bytes: 262144,
+                            }),
+                            classes: vec![FeatureBandwidthClass {
+                                name: "backup".to_owned(),
+                                limit: FeatureBandwidthLimit {
+                                    rate_kbps: 20000,
+                                    burst_bytes: 65536,
+                                },
+                                priority: 1,
+                                peers: vec![PublicKey([1; 32])],
+                                exit_node: true,
+                            }],
+                        }),
                     },
                     flush_events_on_stop_timeout_seconds: Some(15),
+                    startup_timeout_seconds: Some(20),
                     post_quantum_vpn: FeaturePostQuantumVPN {
                         handshake_retry_interval_s: 15,
                         rekey_interval_s: 16,
-                        version: 1,
+                        rekey_jitter_s: 5,
+                        version: 3,
+                        min_version: Some(2),
                     },
                     link_detection: Some(FeatureLinkDetection {
                         rtt_seconds: 17,
@@ -883,6 +1765,20 @@ mod tests {
                         exit_dns: Some(FeatureExitDns {
                             auto_switch_dns_ips: Some(true),
                         }),
+                        stub_listeners: vec![FeatureDnsStubListener {
+                            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                            port: 5353,
+                        }],
+                        upstreams: FeatureDnsUpstreams {
+                            strategy: DnsUpstreamStrategy::Weighted,
+                            timeout_ms: 1500,
+                            max_failures: 2,
+                            quarantine_s: 30,
+                            weights: vec![FeatureDnsUpstreamWeight {
+                                address: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+                                weight: 3,
+                            }],
+                        },
                     },
                     multicast: true,
                     batching: Some(FeatureBatching {
@@ -895,7 +1791,43 @@ mod tests {
                         allow_only_pq: true,
                         backoff: Default::default(),
                         root_certificate_override: None
-                    })
+                    }),
+                    key_rotation: None,
+                    otlp: Some(FeatureOtlp {
+                        collector: SocketAddr::from(([10, 0, 0, 1], 4318)),
+                        tls_server_name: Some("otel.example.com".to_owned()),
+                        service_name: "telio-fleet".to_owned(),
+                        export_interval_s: 30,
+                    }),
+                    fast_reconnect: Some(FeatureFastReconnect {
+                        budget_ms: 3000,
+                        follow_interface_changes: false,
+                    }),
+                    supervision: Some(FeatureSupervision {
+                        check_interval_s: 5,
+                        stall_timeout_s: 20,
+                        default_policy: RestartPolicy::RestartDevice,
+                        policies: vec![FeatureSupervisionPolicy {
+                            task: "DerpRelay".to_owned(),
+                            policy: RestartPolicy::RestartComponent,
+                        }],
+                    }),
+                    accounting: Some(FeatureAccounting {
+                        path: Some("/var/lib/telio/accounting.json".to_owned()),
+                    }),
+                    health_endpoint: Some(FeatureHealthEndpoint {
+                        port: 8080,
+                        stall_timeout_s: 60,
+                    }),
+                    max_meshnet_peers: Some(500),
+                    starcast: Some(FeatureStarcast {
+                        groups: Some(vec![
+                            Ipv4Net::from_str("239.255.255.250/32").unwrap(),
+                            Ipv4Net::from_str("224.0.0.251/32").unwrap(),
+                        ]),
+                        directed_broadcast: true,
+                        decrement_ttl: true,
+                    }),
                 }
             );
         }
@@ -909,6 +1841,55 @@ mod tests {
             );
         }
 
+        #[test]
+        fn test_zero_worker_threads_is_rejected() {
+            assert!(
+                serde_json::from_str::<Features>(r#"{"wireguard": {"worker_threads": 0}}"#)
+                    .is_err()
+            );
+            assert_json!(
+                r#"{"wireguard": {"worker_threads": 2}}"#,
+                Some(2),
+                wireguard.worker_threads
+            );
+        }
+
+        #[test]
+        fn test_empty_wireguard_handshake_throttling() {
+            assert_json!(
+                r#"{"wireguard": {"handshake_throttling": {}}}"#,
+                FeatureHandshakeThrottling::default(),
+                wireguard.handshake_throttling.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_wireguard_adaptive_keepalive() {
+            assert_json!(
+                r#"{"wireguard": {"adaptive_keepalive": {}}}"#,
+                FeatureAdaptiveKeepalive::default(),
+                wireguard.adaptive_keepalive.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_wireguard_obfuscation() {
+            assert_json!(
+                r#"{"wireguard": {"obfuscation": {}}}"#,
+                FeatureObfuscation::default(),
+                wireguard.obfuscation.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_wireguard_stream_transport() {
+            assert_json!(
+                r#"{"wireguard": {"stream_transport": {}}}"#,
+                FeatureStreamTransport::default(),
+                wireguard.stream_transport.unwrap()
+            );
+        }
+
         #[test]
         fn test_empty_wireguard_persistent_keepalive() {
             assert_json!(
@@ -918,6 +1899,33 @@ mod tests {
             );
         }
 
+        #[test]
+        fn test_empty_derp_health_check() {
+            assert_json!(
+                r#"{"derp": {"health_check": {}}}"#,
+                FeatureRelayHealthCheck::default(),
+                derp.unwrap().health_check.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_derp_rehoming() {
+            assert_json!(
+                r#"{"derp": {"health_check": {"rehoming": {}}}}"#,
+                FeatureRelayRehoming::default(),
+                derp.unwrap().health_check.unwrap().rehoming.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_derp_long_poll() {
+            assert_json!(
+                r#"{"derp": {"long_poll": {}}}"#,
+                FeatureDerpLongPoll::default(),
+                derp.unwrap().long_poll.unwrap()
+            );
+        }
+
         #[test]
         fn test_empty_nurse() {
             assert_json!(
@@ -981,6 +1989,15 @@ mod tests {
             );
         }
 
+        #[test]
+        fn test_empty_direct_port_mapping_features() {
+            assert_json!(
+                r#"{"direct": {"port_mapping_features": {}}}"#,
+                FeaturePortMapping::default(),
+                direct.unwrap().port_mapping_features.unwrap()
+            );
+        }
+
         #[test]
         fn test_empty_derp() {
             assert_json!(r#"{"derp": {}}"#, FeatureDerp::default(), derp.unwrap());
@@ -991,6 +2008,129 @@ mod tests {
             assert_json!(r#"{"firewall": {}}"#, FeatureFirewall::default(), firewall);
         }
 
+        #[test]
+        fn test_empty_firewall_killswitch() {
+            assert_json!(
+                r#"{"firewall": {"killswitch": {}}}"#,
+                FeatureKillswitch::default(),
+                firewall.killswitch.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_key_rotation() {
+            assert_json!(
+                r#"{"key_rotation": {}}"#,
+                FeatureKeyRotation::default(),
+                key_rotation.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_otlp() {
+            assert_json!(r#"{"otlp": {}}"#, FeatureOtlp::default(), otlp.unwrap());
+        }
+
+        #[test]
+        fn test_empty_fast_reconnect() {
+            assert_json!(
+                r#"{"fast_reconnect": {}}"#,
+                FeatureFastReconnect::default(),
+                fast_reconnect.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_supervision() {
+            assert_json!(
+                r#"{"supervision": {}}"#,
+                FeatureSupervision::default(),
+                supervision.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_accounting() {
+            assert_json!(
+                r#"{"accounting": {}}"#,
+                FeatureAccounting::default(),
+                accounting.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_health_endpoint() {
+            assert_json!(
+                r#"{"health_endpoint": {}}"#,
+                FeatureHealthEndpoint::default(),
+                health_endpoint.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_starcast() {
+            assert_json!(
+                r#"{"starcast": {}}"#,
+                FeatureStarcast::default(),
+                starcast.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_firewall_exit_node_rules() {
+            assert_json!(
+                r#"{"firewall": {"exit_node_rules": {}}}"#,
+                FeatureExitNodeRules::default(),
+                firewall.exit_node_rules.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_firewall_flow_export() {
+            assert_json!(
+                r#"{"firewall": {"flow_export": {}}}"#,
+                FeatureFlowExport::default(),
+                firewall.flow_export.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_firewall_inbound_approval() {
+            assert_json!(
+                r#"{"firewall": {"inbound_approval": {}}}"#,
+                FeatureInboundApproval {
+                    prompt_timeout_s: 60,
+                    allow_once_s: 3600,
+                },
+                firewall.inbound_approval.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_firewall_shaping() {
+            assert_json!(
+                r#"{"firewall": {"shaping": {}}}"#,
+                FeatureShaping {
+                    total: None,
+                    classes: vec![],
+                },
+                firewall.shaping.unwrap()
+            );
+        }
+
+        #[test]
+        fn test_empty_lan_discovery() {
+            assert_json!(
+                r#"{"direct": {"lan_discovery": {}}}"#,
+                FeatureLanDiscovery {
+                    multicast_group: Ipv4Addr::new(239, 255, 76, 84),
+                    port: 51900,
+                    announce_interval_s: 5,
+                },
+                direct.unwrap().lan_discovery.unwrap()
+            );
+        }
+
         #[test]
         fn test_empty_post_quantum_vpn() {
             assert_json!(
@@ -1037,6 +2177,15 @@ mod tests {
             );
         }
 
+        #[test]
+        fn test_empty_dns_stub_listener() {
+            assert_json!(
+                r#"{"dns": {"stub_listeners": [{}]}}"#,
+                vec![FeatureDnsStubListener::default()],
+                dns.stub_listeners
+            );
+        }
+
         #[test]
         fn test_json_direct_accepts_arbitrary_providers() {
             assert_json!(
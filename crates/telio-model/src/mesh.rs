@@ -2,6 +2,7 @@
 
 use super::EndpointMap as RelayEndpointMap;
 
+use crate::event::DowngradeReason;
 use crate::features::PathType;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, net::IpAddr};
@@ -79,7 +80,7 @@ pub enum VpnConnectionError {
 
 /// Description of the Exit Node
 /// It is the gateway node to the internet
-#[derive(Debug, Default, Clone, Serialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExitNode {
     /// An identifier for an exit node
     /// Makes it possible to distinguish different exit nodes in the presence of key reuse
@@ -92,6 +93,157 @@ pub struct ExitNode {
     pub endpoint: Option<SocketAddr>,
 }
 
+/// Runtime statistics of a single WireGuard peer
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerStats {
+    /// Public key of the peer
+    pub public_key: PublicKey,
+    /// Number of bytes received from the peer
+    pub rx_bytes: u64,
+    /// Number of bytes sent to the peer
+    pub tx_bytes: u64,
+    /// Seconds elapsed since the last successful handshake, if there was one
+    pub time_since_last_handshake_s: Option<u64>,
+    /// Current endpoint of the peer
+    pub endpoint: Option<SocketAddr>,
+    /// Whether the peer is reached directly or through a relay
+    pub path: PathType,
+    /// Last measured round trip time in milliseconds, available when QoS analytics are enabled
+    pub rtt_ms: Option<u64>,
+    /// Persistent keepalive interval currently applied to the peer, in seconds
+    pub persistent_keepalive_interval_s: Option<u32>,
+    /// Protocol version agreed on with the peer, none until the capabilities are exchanged and
+    /// zero for the peers which do not exchange them
+    pub protocol_version: Option<u32>,
+    /// Optional protocol features supported by both sides
+    pub capabilities: Vec<PeerCapability>,
+}
+
+/// Optional protocol feature, used with a peer only when both sides support it
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    strum_macros::EnumString,
+    strum_macros::IntoStaticStr,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum PeerCapability {
+    /// IPv6 endpoint candidates in the CallMeMaybe messages
+    Ipv6Candidates,
+    /// Post-quantum hybrid key exchange
+    PqHybrid,
+    /// Compressed CallMeMaybe messages
+    CompressedCmm,
+    /// Sequenced control messages, protected against replays
+    ReplayProtection,
+}
+
+/// Quality of the connection to a single peer, aggregated over the recent QoS measurements
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerQos {
+    /// Public key of the peer
+    pub public_key: PublicKey,
+    /// Average round trip time in milliseconds, if any ping succeeded
+    pub rtt_ms: Option<u64>,
+    /// Average difference between consecutive round trip times in milliseconds
+    pub jitter_ms: Option<u64>,
+    /// Percentage of the pings which got no reply
+    pub loss_percent: u32,
+    /// Number of measurements the values are aggregated from
+    pub samples: u32,
+}
+
+/// Current path to a peer, as drawn in the connectivity matrix
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectivityPath {
+    /// Direct connection over IPv4
+    DirectV4,
+    /// Direct connection over IPv6
+    DirectV6,
+    /// Connection through a relay server
+    Relayed,
+    /// No connection
+    #[default]
+    Down,
+}
+
+/// Connectivity between this node and a single peer
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerConnectivity {
+    /// Public key of the peer
+    pub public_key: PublicKey,
+    /// Current path to the peer
+    pub path: ConnectivityPath,
+    /// Time of the last path change, in seconds since the Unix epoch
+    pub last_change_s: u64,
+    /// Why the last direct path to the peer was abandoned, cleared when a direct path is found
+    pub failure_reason: Option<DowngradeReason>,
+}
+
+/// Resources consumed since libtelio was started, used to quantify its battery impact
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    /// Seconds elapsed since libtelio was started
+    pub period_s: u64,
+    /// CPU time consumed by the whole process in milliseconds, not available on every platform
+    pub cpu_time_ms: Option<u64>,
+    /// Number of times libtelio runtime threads were woken up
+    pub wakeups: u64,
+    /// Number of bytes received from currently configured WireGuard peers
+    pub rx_bytes: u64,
+    /// Number of bytes sent to currently configured WireGuard peers
+    pub tx_bytes: u64,
+}
+
+/// Traffic exchanged with a peer within a period
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TrafficCounters {
+    /// Number of WireGuard bytes received from the peer
+    pub rx_bytes: u64,
+    /// Number of WireGuard bytes sent to the peer
+    pub tx_bytes: u64,
+    /// Number of packets received from the peer, only counted by the NepTUN adapter
+    pub rx_packets: u64,
+    /// Number of packets sent to the peer, only counted by the NepTUN adapter
+    pub tx_packets: u64,
+}
+
+impl std::ops::AddAssign for TrafficCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.rx_bytes = self.rx_bytes.saturating_add(other.rx_bytes);
+        self.tx_bytes = self.tx_bytes.saturating_add(other.tx_bytes);
+        self.rx_packets = self.rx_packets.saturating_add(other.rx_packets);
+        self.tx_packets = self.tx_packets.saturating_add(other.tx_packets);
+    }
+}
+
+/// Traffic exchanged with a peer, as rolled up by the accounting. The periods start at the
+/// beginning of the minute or the hour the given time ago, so they cover up to one more minute or
+/// hour than their name says.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerTraffic {
+    /// Public key of the peer
+    pub public_key: PublicKey,
+    /// Whether the peer was the exit node during the last day
+    pub exit_node: bool,
+    /// Traffic of the last minute
+    pub last_minute: TrafficCounters,
+    /// Traffic of the last hour
+    pub last_hour: TrafficCounters,
+    /// Traffic of the last day
+    pub last_day: TrafficCounters,
+}
+
 /// Connection state of the node
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
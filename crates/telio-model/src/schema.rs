@@ -0,0 +1,185 @@
+//! JSON schema of the [Features] config.
+//!
+//! The schema is derived from the features themselves, so it cannot drift from them. Every
+//! optional setting is filled in with the first probe value it accepts, which also brings in the
+//! defaults of the settings nested in it. Settings accepting none of the probes, e.g. addresses,
+//! are described as values of any type.
+
+use std::collections::HashSet;
+
+use serde_json::{json, Map, Value};
+
+use crate::features::Features;
+
+/// Old names of the settings, still accepted
+const ALIASES: &[(&str, &str)] = &[("hide_ips", "hide_user_data")];
+
+/// Values tried for the optional settings, in order
+fn probes() -> [Value; 5] {
+    [json!({}), json!(true), json!(0), json!(""), json!([])]
+}
+
+/// Features with every optional setting filled in, along with the JSON pointers of the optional
+/// settings
+fn populated() -> (Value, HashSet<String>) {
+    let mut value = serde_json::to_value(Features::default()).unwrap_or_default();
+    let mut optional = HashSet::new();
+    while let Some(pointer) = find_null(&value, "", &optional) {
+        let accepted = probes().into_iter().find_map(|probe| {
+            let mut candidate = value.clone();
+            *candidate.pointer_mut(&pointer)? = probe;
+            let features: Features = serde_json::from_value(candidate).ok()?;
+            let filled = serde_json::to_value(features).ok()?;
+            (!filled.pointer(&pointer)?.is_null()).then_some(filled)
+        });
+        if let Some(filled) = accepted {
+            value = filled;
+        }
+        optional.insert(pointer);
+    }
+    (value, optional)
+}
+
+/// Pointer of the first null in the `value`, skipping the `known` ones
+fn find_null(value: &Value, pointer: &str, known: &HashSet<String>) -> Option<String> {
+    match value {
+        Value::Null if !known.contains(pointer) => Some(pointer.to_owned()),
+        Value::Object(fields) => fields
+            .iter()
+            .find_map(|(key, field)| find_null(field, &format!("{pointer}/{key}"), known)),
+        _ => None,
+    }
+}
+
+fn schema_of(value: &Value, pointer: &str, optional: &HashSet<String>) -> Value {
+    let mut schema = Map::new();
+    let type_name = match value {
+        Value::Null => None,
+        Value::Bool(_) => Some("boolean"),
+        Value::Number(number) if number.is_f64() => Some("number"),
+        Value::Number(_) => Some("integer"),
+        Value::String(_) => Some("string"),
+        Value::Array(items) => {
+            let items = items.first().map_or_else(
+                || json!({}),
+                |item| schema_of(item, &format!("{pointer}/0"), optional),
+            );
+            schema.insert("items".to_owned(), items);
+            Some("array")
+        }
+        Value::Object(fields) => {
+            let properties = fields
+                .iter()
+                .map(|(key, field)| {
+                    let schema = schema_of(field, &format!("{pointer}/{key}"), optional);
+                    (key.clone(), schema)
+                })
+                .collect();
+            schema.insert("properties".to_owned(), Value::Object(properties));
+            schema.insert("additionalProperties".to_owned(), json!(false));
+            Some("object")
+        }
+    };
+
+    if let Some(type_name) = type_name {
+        let type_names = match optional.contains(pointer) {
+            true => json!([type_name, "null"]),
+            false => json!(type_name),
+        };
+        schema.insert("type".to_owned(), type_names);
+    }
+    Value::Object(schema)
+}
+
+/// JSON schema of the [Features], as accepted by [crate::validation::parse_features_strict]
+pub fn features_json_schema() -> Value {
+    let (value, optional) = populated();
+    let mut schema = schema_of(&value, "", &optional);
+    if let Some(properties) = schema.get_mut("properties").and_then(Value::as_object_mut) {
+        for (alias, name) in ALIASES {
+            if let Some(property) = properties.get(*name).cloned() {
+                properties.insert((*alias).to_owned(), property);
+            }
+        }
+    }
+    if let Some(schema) = schema.as_object_mut() {
+        schema.insert(
+            "$schema".to_owned(),
+            json!("https://json-schema.org/draft/2020-12/schema"),
+        );
+        schema.insert("title".to_owned(), json!("Features"));
+    }
+    schema
+}
+
+/// Paths of the settings in the features `json` which are not known, and so would be ignored
+pub fn unknown_feature_settings(json: &Value) -> Vec<String> {
+    let (value, _) = populated();
+    let mut unknown = Vec::new();
+    collect_unknown(json, &value, "", &mut unknown);
+    unknown
+        .into_iter()
+        .filter(|path| !ALIASES.iter().any(|(alias, _)| path == alias))
+        .collect()
+}
+
+fn collect_unknown(json: &Value, known: &Value, path: &str, unknown: &mut Vec<String>) {
+    let (Value::Object(fields), Value::Object(known_fields)) = (json, known) else {
+        return;
+    };
+    for (key, field) in fields {
+        let field_path = match path.is_empty() {
+            true => key.clone(),
+            false => format!("{path}.{key}"),
+        };
+        match known_fields.get(key) {
+            Some(known_field) => collect_unknown(field, known_field, &field_path, unknown),
+            None => unknown.push(field_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_settings_are_described() {
+        let schema = features_json_schema();
+
+        assert_eq!(schema["type"], json!("object"));
+        assert_eq!(
+            schema["properties"]["derp"]["type"],
+            json!(["object", "null"])
+        );
+        assert_eq!(
+            schema["properties"]["derp"]["properties"]["tcp_keepalive"]["type"],
+            json!(["integer", "null"])
+        );
+        assert_eq!(
+            schema["properties"]["wireguard"]["properties"]["polling"]["properties"]
+                ["wireguard_polling_period"]["type"],
+            json!("integer")
+        );
+        assert_eq!(schema["properties"]["ipv6"]["type"], json!("boolean"));
+        assert_eq!(
+            schema["properties"]["hide_ips"],
+            schema["properties"]["hide_user_data"]
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_found() {
+        let json = json!({
+            "hide_ips": true,
+            "ipv_6": true,
+            "wireguard": {"polling": {"wireguard_polling_period": 100, "period": 1}},
+            "derp": {"tcp_keepalive": 10},
+        });
+
+        assert_eq!(
+            unknown_feature_settings(&json),
+            vec!["ipv_6", "wireguard.polling.period"]
+        );
+    }
+}
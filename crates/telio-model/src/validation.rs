@@ -1,7 +1,17 @@
 //! Config validation checks
 
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    net::IpAddr,
+};
+
+use serde::{Deserialize, Serialize};
+use telio_crypto::PublicKey;
 use telio_utils::telio_log_debug;
 
+use crate::{config::Config, features::Features, schema::unknown_feature_settings};
+
 /// Nickname validation checks (RFC #009)
 pub fn validate_nickname(name: &str) -> bool {
     if name.len() > 25 {
@@ -38,3 +48,689 @@ pub fn validate_nickname(name: &str) -> bool {
     }
     true
 }
+
+/// Problem found in a meshnet config by [validate_meshnet_config]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "issue", rename_all = "snake_case")]
+pub enum ConfigIssue {
+    /// The IP address is assigned to more than one node
+    OverlappingIp {
+        /// Address assigned more than once
+        ip_address: IpAddr,
+        /// Nodes the address is assigned to
+        public_keys: Vec<PublicKey>,
+    },
+    /// The public key belongs to more than one node
+    DuplicateKey {
+        /// Key used more than once
+        public_key: PublicKey,
+    },
+    /// None of the IP addresses of the node is of an enabled address family, e.g. the node has
+    /// IPv6 addresses only, while IPv6 is disabled
+    UnsupportedAddressFamily {
+        /// Node the addresses are assigned to
+        public_key: PublicKey,
+        /// Addresses of the node
+        ip_addresses: Vec<IpAddr>,
+    },
+    /// The config has more peers than the `max_meshnet_peers` feature allows
+    TooManyPeers {
+        /// Peers in the config
+        count: u64,
+        /// Most peers allowed
+        max: u64,
+    },
+}
+
+/// Outcome of [validate_meshnet_config]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// Problems found, none when the config can be applied
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl ValidationReport {
+    /// Whether the config can be applied
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check the meshnet `config` without applying it. IPv6 addresses are usable only with `ipv6`
+/// enabled, otherwise they are left out when the config is applied. The peers are not limited
+/// without `max_peers`.
+pub fn validate_meshnet_config(
+    config: &Config,
+    ipv6: bool,
+    max_peers: Option<u32>,
+) -> ValidationReport {
+    let peers = config.peers.as_deref().unwrap_or_default();
+    let nodes = || std::iter::once(&config.this).chain(peers.iter().map(|peer| &peer.base));
+    let mut issues = Vec::new();
+
+    if let Some(max) = max_peers.filter(|max| peers.len() > *max as usize) {
+        issues.push(ConfigIssue::TooManyPeers {
+            count: peers.len() as u64,
+            max: max.into(),
+        });
+    }
+
+    let mut seen_keys = HashSet::new();
+    let mut duplicate_keys = Vec::new();
+    for node in nodes() {
+        if !seen_keys.insert(node.public_key) && !duplicate_keys.contains(&node.public_key) {
+            duplicate_keys.push(node.public_key);
+        }
+    }
+    issues.extend(
+        duplicate_keys
+            .into_iter()
+            .map(|public_key| ConfigIssue::DuplicateKey { public_key }),
+    );
+
+    let mut owners: BTreeMap<IpAddr, Vec<PublicKey>> = BTreeMap::new();
+    for node in nodes() {
+        let ip_addresses = node.ip_addresses.as_deref().unwrap_or_default();
+        for ip_address in ip_addresses {
+            let public_keys = owners.entry(*ip_address).or_default();
+            if !public_keys.contains(&node.public_key) {
+                public_keys.push(node.public_key);
+            }
+        }
+        let usable = |ip: &IpAddr| ip.is_ipv4() || ipv6;
+        if !ip_addresses.is_empty() && !ip_addresses.iter().any(usable) {
+            issues.push(ConfigIssue::UnsupportedAddressFamily {
+                public_key: node.public_key,
+                ip_addresses: ip_addresses.to_vec(),
+            });
+        }
+    }
+    issues.extend(
+        owners
+            .into_iter()
+            .filter(|(_, public_keys)| public_keys.len() > 1)
+            .map(|(ip_address, public_keys)| ConfigIssue::OverlappingIp {
+                ip_address,
+                public_keys,
+            }),
+    );
+
+    if !issues.is_empty() {
+        telio_log_debug!("Meshnet config has issues: {issues:?}");
+    }
+    ValidationReport { issues }
+}
+
+/// Problem found in a features config by [validate_features]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FeatureIssue {
+    /// The JSON is not a features config
+    Malformed {
+        /// Why the JSON could not be parsed
+        reason: String,
+    },
+    /// The setting is not known, it would be ignored
+    UnknownSetting {
+        /// Path of the setting, e.g. `wireguard.polling`
+        path: String,
+    },
+    /// The value of the setting is outside of the supported range
+    OutOfRange {
+        /// Path of the setting
+        path: String,
+        /// Value of the setting
+        value: u64,
+        /// Smallest supported value
+        min: u64,
+        /// Largest supported value
+        max: u64,
+    },
+    /// The settings cannot be used together
+    Conflict {
+        /// Paths of the settings
+        paths: Vec<String>,
+        /// Why they cannot be used together
+        reason: String,
+    },
+}
+
+impl fmt::Display for FeatureIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeatureIssue::Malformed { reason } => write!(f, "malformed features: {reason}"),
+            FeatureIssue::UnknownSetting { path } => write!(f, "{path} is not known"),
+            FeatureIssue::OutOfRange {
+                path,
+                value,
+                min,
+                max,
+            } => write!(f, "{path} is {value}, it has to be in {min}..={max}"),
+            FeatureIssue::Conflict { paths, reason } => {
+                write!(f, "{} conflict: {reason}", paths.join(", "))
+            }
+        }
+    }
+}
+
+/// Checks of the settings of a features config
+#[derive(Default)]
+struct FeatureChecks {
+    issues: Vec<FeatureIssue>,
+}
+
+impl FeatureChecks {
+    fn range(&mut self, path: &str, value: impl Into<u64>, min: u64, max: u64) {
+        let value = value.into();
+        if !(min..=max).contains(&value) {
+            self.issues.push(FeatureIssue::OutOfRange {
+                path: path.to_owned(),
+                value,
+                min,
+                max,
+            });
+        }
+    }
+
+    fn conflict(&mut self, conflicting: bool, paths: &[&str], reason: &str) {
+        if conflicting {
+            self.issues.push(FeatureIssue::Conflict {
+                paths: paths.iter().map(|path| (*path).to_owned()).collect(),
+                reason: reason.to_owned(),
+            });
+        }
+    }
+}
+
+/// Check the ranges and the combinations of the settings of the `features`
+pub fn validate_features(features: &Features) -> Vec<FeatureIssue> {
+    // WireGuard keepalive intervals are 16 bit
+    const MAX_KEEPALIVE: u64 = u16::MAX as u64;
+    const MAX_POLLING_PERIOD_MS: u64 = 60 * 1000;
+    const MAX_WORKER_THREADS: u64 = 256;
+    // A burst has to fit at least a full packet, or the packets would never pass
+    const MIN_BURST_BYTES: u64 = 1500;
+    // Peers discovered on the LAN are forgotten a minute after their last announcement
+    const MAX_LAN_ANNOUNCE_INTERVAL_S: u64 = 30;
+
+    let mut checks = FeatureChecks::default();
+    let wireguard = &features.wireguard;
+
+    let keepalive = &wireguard.persistent_keepalive;
+    let optional_keepalives = [
+        ("vpn", keepalive.vpn),
+        ("proxying", keepalive.proxying),
+        ("stun", keepalive.stun),
+    ];
+    for (name, interval) in optional_keepalives {
+        if let Some(interval) = interval {
+            let path = format!("wireguard.persistent_keepalive.{name}");
+            checks.range(&path, interval, 0, MAX_KEEPALIVE);
+        }
+    }
+    checks.range(
+        "wireguard.persistent_keepalive.direct",
+        keepalive.direct,
+        0,
+        MAX_KEEPALIVE,
+    );
+    checks.range(
+        "wireguard.polling.wireguard_polling_period",
+        wireguard.polling.wireguard_polling_period,
+        1,
+        MAX_POLLING_PERIOD_MS,
+    );
+    checks.range(
+        "wireguard.polling.wireguard_polling_period_after_state_change",
+        wireguard
+            .polling
+            .wireguard_polling_period_after_state_change,
+        1,
+        MAX_POLLING_PERIOD_MS,
+    );
+
+    // Buffers of NepTUN
+    let buffers = [
+        ("wireguard.skt_buffer_size", wireguard.skt_buffer_size),
+        (
+            "wireguard.inter_thread_channel_size",
+            wireguard.inter_thread_channel_size,
+        ),
+        (
+            "wireguard.max_inter_thread_batched_pkts",
+            wireguard.max_inter_thread_batched_pkts,
+        ),
+    ];
+    for (path, size) in buffers {
+        if let Some(size) = size {
+            checks.range(path, size, 1, u32::MAX.into());
+        }
+    }
+    if let (Some(channel_size), Some(batched_pkts)) = (
+        wireguard.inter_thread_channel_size,
+        wireguard.max_inter_thread_batched_pkts,
+    ) {
+        checks.conflict(
+            batched_pkts > channel_size,
+            &[
+                "wireguard.inter_thread_channel_size",
+                "wireguard.max_inter_thread_batched_pkts",
+            ],
+            "batches do not fit into the channel",
+        );
+    }
+
+    if let Some(worker_threads) = wireguard.worker_threads {
+        checks.range(
+            "wireguard.worker_threads",
+            worker_threads,
+            1,
+            MAX_WORKER_THREADS,
+        );
+    }
+
+    if let Some(throttling) = &wireguard.handshake_throttling {
+        checks.range(
+            "wireguard.handshake_throttling.max_handshakes_per_round",
+            throttling.max_handshakes_per_round,
+            1,
+            u32::MAX.into(),
+        );
+    }
+    if let Some(adaptive_keepalive) = &wireguard.adaptive_keepalive {
+        checks.range(
+            "wireguard.adaptive_keepalive.max_interval_s",
+            adaptive_keepalive.max_interval_s,
+            keepalive.direct.into(),
+            MAX_KEEPALIVE,
+        );
+    }
+    if let Some(obfuscation) = &wireguard.obfuscation {
+        checks.conflict(
+            obfuscation.junk_packet_min_size > obfuscation.junk_packet_max_size,
+            &[
+                "wireguard.obfuscation.junk_packet_min_size",
+                "wireguard.obfuscation.junk_packet_max_size",
+            ],
+            "the minimum is above the maximum",
+        );
+        let headers: HashSet<u32> = [
+            obfuscation.init_packet_magic_header,
+            obfuscation.response_packet_magic_header,
+            obfuscation.cookie_packet_magic_header,
+            obfuscation.transport_packet_magic_header,
+        ]
+        .into();
+        checks.conflict(
+            headers.len() < 4,
+            &[
+                "wireguard.obfuscation.init_packet_magic_header",
+                "wireguard.obfuscation.response_packet_magic_header",
+                "wireguard.obfuscation.cookie_packet_magic_header",
+                "wireguard.obfuscation.transport_packet_magic_header",
+            ],
+            "the message types are not distinct",
+        );
+    }
+
+    if let Some(nurse) = &features.nurse {
+        checks.range(
+            "nurse.heartbeat_interval",
+            nurse.heartbeat_interval,
+            1,
+            u64::MAX,
+        );
+        checks.range(
+            "nurse.initial_heartbeat_interval",
+            nurse.initial_heartbeat_interval,
+            1,
+            u64::MAX,
+        );
+        if let Some(qos) = &nurse.qos {
+            checks.range("nurse.qos.rtt_interval", qos.rtt_interval, 1, u64::MAX);
+            checks.range("nurse.qos.rtt_tries", qos.rtt_tries, 1, u32::MAX.into());
+            checks.range("nurse.qos.buckets", qos.buckets, 1, u32::MAX.into());
+        }
+    }
+
+    if let Some(direct) = &features.direct {
+        checks.range(
+            "direct.endpoint_interval_secs",
+            direct.endpoint_interval_secs,
+            1,
+            u64::MAX,
+        );
+        if let Some(lan_discovery) = &direct.lan_discovery {
+            checks.range(
+                "direct.lan_discovery.announce_interval_s",
+                lan_discovery.announce_interval_s,
+                1,
+                MAX_LAN_ANNOUNCE_INTERVAL_S,
+            );
+            checks.range(
+                "direct.lan_discovery.port",
+                lan_discovery.port,
+                1,
+                u16::MAX.into(),
+            );
+            checks.conflict(
+                !lan_discovery.multicast_group.is_multicast(),
+                &["direct.lan_discovery.multicast_group"],
+                "not a multicast address",
+            );
+        }
+    }
+
+    if let Some(long_poll) = features
+        .derp
+        .as_ref()
+        .and_then(|derp| derp.long_poll.as_ref())
+    {
+        checks.range(
+            "derp.long_poll.probe_failures",
+            long_poll.probe_failures,
+            1,
+            u32::MAX.into(),
+        );
+    }
+
+    if let Some(supervision) = &features.supervision {
+        checks.range(
+            "supervision.check_interval_s",
+            supervision.check_interval_s,
+            1,
+            u32::MAX.into(),
+        );
+        checks.range(
+            "supervision.stall_timeout_s",
+            supervision.stall_timeout_s,
+            1,
+            u32::MAX.into(),
+        );
+    }
+
+    if let Some(shaping) = &features.firewall.shaping {
+        let total = shaping
+            .total
+            .iter()
+            .map(|limit| ("firewall.shaping.total".to_owned(), limit));
+        let classes = shaping.classes.iter().enumerate().map(|(index, class)| {
+            (
+                format!("firewall.shaping.classes.{index}.limit"),
+                &class.limit,
+            )
+        });
+        for (path, limit) in total.chain(classes) {
+            checks.range(
+                &format!("{path}.rate_kbps"),
+                limit.rate_kbps,
+                1,
+                u32::MAX.into(),
+            );
+            checks.range(
+                &format!("{path}.burst_bytes"),
+                limit.burst_bytes,
+                MIN_BURST_BYTES,
+                u32::MAX.into(),
+            );
+        }
+        let mut peers = HashSet::new();
+        checks.conflict(
+            shaping
+                .classes
+                .iter()
+                .flat_map(|class| &class.peers)
+                .any(|peer| !peers.insert(*peer)),
+            &["firewall.shaping.classes"],
+            "a peer is in more than one class",
+        );
+    }
+
+    checks.issues
+}
+
+/// Parse the `json` of a features config. Unlike the plain deserialization, the unknown settings
+/// fail the parsing instead of being ignored, and so do the settings failing [validate_features].
+pub fn parse_features_strict(json: &str) -> Result<Features, Vec<FeatureIssue>> {
+    let malformed = |error: serde_json::Error| {
+        vec![FeatureIssue::Malformed {
+            reason: error.to_string(),
+        }]
+    };
+    let value: serde_json::Value = serde_json::from_str(json).map_err(malformed)?;
+    let mut issues: Vec<FeatureIssue> = unknown_feature_settings(&value)
+        .into_iter()
+        .map(|path| FeatureIssue::UnknownSetting { path })
+        .collect();
+    let features: Features = serde_json::from_value(value).map_err(malformed)?;
+    issues.extend(validate_features(&features));
+
+    match issues.is_empty() {
+        true => Ok(features),
+        false => {
+            telio_log_debug!("Features have issues: {issues:?}");
+            Err(issues)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Peer, PeerBase};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn node(byte: u8, ip_addresses: &[IpAddr]) -> PeerBase {
+        PeerBase {
+            public_key: PublicKey([byte; 32]),
+            ip_addresses: Some(ip_addresses.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn config(this: PeerBase, peers: Vec<PeerBase>) -> Config {
+        Config {
+            this,
+            peers: Some(
+                peers
+                    .into_iter()
+                    .map(|base| Peer {
+                        base,
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn valid_config_has_no_issues() {
+        let v4 = |last| IpAddr::V4(Ipv4Addr::new(100, 64, 0, last));
+        let config = config(node(1, &[v4(1)]), vec![node(2, &[v4(2)]), node(3, &[])]);
+
+        assert!(validate_meshnet_config(&config, false, None).is_valid());
+    }
+
+    #[test]
+    fn config_issues_are_reported() {
+        let shared = IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1));
+        let v6 = IpAddr::V6(Ipv6Addr::new(0xfd74, 0x656c, 0x696f, 0, 0, 0, 0, 3));
+        let config = config(
+            node(1, &[shared]),
+            vec![node(2, &[shared, shared]), node(1, &[]), node(3, &[v6])],
+        );
+
+        assert_eq!(
+            validate_meshnet_config(&config, false, None).issues,
+            vec![
+                ConfigIssue::DuplicateKey {
+                    public_key: PublicKey([1; 32]),
+                },
+                ConfigIssue::UnsupportedAddressFamily {
+                    public_key: PublicKey([3; 32]),
+                    ip_addresses: vec![v6],
+                },
+                ConfigIssue::OverlappingIp {
+                    ip_address: shared,
+                    public_keys: vec![PublicKey([1; 32]), PublicKey([2; 32])],
+                },
+            ]
+        );
+        assert_eq!(validate_meshnet_config(&config, true, None).issues.len(), 2);
+    }
+
+    #[test]
+    fn default_features_are_valid() {
+        assert_eq!(validate_features(&Features::default()), Vec::new());
+        assert_eq!(parse_features_strict("{}"), Ok(Features::default()));
+    }
+
+    #[test]
+    fn invalid_features_are_rejected() {
+        let json = r#"{
+            "ipv6": true,
+            "wireguard": {
+                "polling": {"wireguard_polling_period": 0},
+                "persistent_keepalive": {"direct": 70000},
+                "inter_thread_channel_size": 16,
+                "max_inter_thread_batched_pkts": 32
+            },
+            "nurse": {"qos": {"rtt_tries": 0}, "heartbeat": 10}
+        }"#;
+
+        assert_eq!(
+            parse_features_strict(json),
+            Err(vec![
+                FeatureIssue::UnknownSetting {
+                    path: "nurse.heartbeat".to_owned()
+                },
+                FeatureIssue::OutOfRange {
+                    path: "wireguard.persistent_keepalive.direct".to_owned(),
+                    value: 70000,
+                    min: 0,
+                    max: 65535,
+                },
+                FeatureIssue::OutOfRange {
+                    path: "wireguard.polling.wireguard_polling_period".to_owned(),
+                    value: 0,
+                    min: 1,
+                    max: 60000,
+                },
+                FeatureIssue::Conflict {
+                    paths: vec![
+                        "wireguard.inter_thread_channel_size".to_owned(),
+                        "wireguard.max_inter_thread_batched_pkts".to_owned(),
+                    ],
+                    reason: "batches do not fit into the channel".to_owned(),
+                },
+                FeatureIssue::OutOfRange {
+                    path: "nurse.qos.rtt_tries".to_owned(),
+                    value: 0,
+                    min: 1,
+                    max: u32::MAX.into(),
+                },
+            ])
+        );
+        assert!(matches!(
+            parse_features_strict("{"),
+            Err(issues) if matches!(issues[..], [FeatureIssue::Malformed { .. }])
+        ));
+    }
+
+    #[test]
+    fn shaping_limits_are_checked() {
+        let json = r#"{
+            "firewall": {"shaping": {"classes": [
+                {"name": "backup", "limit": {"rate_kbps": 0, "burst_bytes": 65536}, "peers": [
+                    "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+                ]},
+                {"name": "calls", "limit": {"rate_kbps": 1000, "burst_bytes": 100}, "peers": [
+                    "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+                ]}
+            ]}}
+        }"#;
+
+        assert_eq!(
+            parse_features_strict(json),
+            Err(vec![
+                FeatureIssue::OutOfRange {
+                    path: "firewall.shaping.classes.0.limit.rate_kbps".to_owned(),
+                    value: 0,
+                    min: 1,
+                    max: u32::MAX.into(),
+                },
+                FeatureIssue::OutOfRange {
+                    path: "firewall.shaping.classes.1.limit.burst_bytes".to_owned(),
+                    value: 100,
+                    min: 1500,
+                    max: u32::MAX.into(),
+                },
+                FeatureIssue::Conflict {
+                    paths: vec!["firewall.shaping.classes".to_owned()],
+                    reason: "a peer is in more than one class".to_owned(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn lan_discovery_is_checked() {
+        let json = r#"{
+            "direct": {"lan_discovery": {"multicast_group": "192.168.1.255", "announce_interval_s": 60}}
+        }"#;
+
+        assert_eq!(
+            parse_features_strict(json),
+            Err(vec![
+                FeatureIssue::OutOfRange {
+                    path: "direct.lan_discovery.announce_interval_s".to_owned(),
+                    value: 60,
+                    min: 1,
+                    max: 30,
+                },
+                FeatureIssue::Conflict {
+                    paths: vec!["direct.lan_discovery.multicast_group".to_owned()],
+                    reason: "not a multicast address".to_owned(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn long_poll_needs_a_failed_probe() {
+        let json = r#"{"derp": {"long_poll": {"probe_failures": 0}}}"#;
+
+        assert_eq!(
+            parse_features_strict(json),
+            Err(vec![FeatureIssue::OutOfRange {
+                path: "derp.long_poll.probe_failures".to_owned(),
+                value: 0,
+                min: 1,
+                max: u32::MAX.into(),
+            }])
+        );
+    }
+
+    #[test]
+    fn oversized_configs_are_reported() {
+        let peers: Vec<_> = (0..=4)
+            .map(|i| {
+                let mut public_key = [1; 32];
+                public_key[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                PeerBase {
+                    public_key: PublicKey(public_key),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let config = config(node(0, &[]), peers);
+
+        assert_eq!(
+            validate_meshnet_config(&config, true, Some(4)).issues,
+            vec![ConfigIssue::TooManyPeers { count: 5, max: 4 }]
+        );
+        assert!(validate_meshnet_config(&config, true, Some(5)).is_valid());
+        assert!(validate_meshnet_config(&config, true, None).is_valid());
+    }
+}
@@ -17,3 +17,5 @@ pub mod windows;
 
 /// Get local interfaces
 pub mod local_interfaces;
+
+pub mod path;
@@ -1,6 +1,6 @@
-use ipnet::Ipv4Net;
+use ipnet::{Ipv4Net, Ipv6Net};
 use mockall::automock;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// A trait that provides an interface for retrieving network interface addresses.
 #[automock]
@@ -25,8 +25,19 @@ impl GetIfAddrs for SystemGetIfAddrs {
 /// Filtering out meshnet and loopback IP
 pub fn gather_local_interfaces<G: GetIfAddrs>(
     get_if_addr: &G,
+) -> std::io::Result<Vec<if_addrs::Interface>> {
+    gather_local_interfaces_with(get_if_addr, false)
+}
+
+/// Same as [gather_local_interfaces], but with `ipv6` also keeps the IPv6 addresses
+/// which are reachable from outside of the link
+pub fn gather_local_interfaces_with<G: GetIfAddrs>(
+    get_if_addr: &G,
+    ipv6: bool,
 ) -> std::io::Result<Vec<if_addrs::Interface>> {
     let shared_range: Ipv4Net = Ipv4Net::new(Ipv4Addr::new(100, 64, 0, 0), 10).unwrap_or_default();
+    let meshnet_range_v6: Ipv6Net =
+        Ipv6Net::new(Ipv6Addr::new(0xfd74, 0x656c, 0x696f, 0, 0, 0, 0, 0), 48).unwrap_or_default();
     Ok((*get_if_addr)
         .get()?
         .into_iter()
@@ -35,8 +46,14 @@ pub fn gather_local_interfaces<G: GetIfAddrs>(
                 match x.addr.ip() {
                     // Filter 100.64/10 libtelio's meshnet network.
                     IpAddr::V4(v4) => !shared_range.contains(&v4),
-                    // Filter IPv6
-                    _ => false,
+                    // Filter libtelio's meshnet network and link scoped addresses,
+                    // which are useless without the scope id
+                    IpAddr::V6(v6) => {
+                        ipv6 && !meshnet_range_v6.contains(&v6)
+                            && !v6.is_unicast_link_local()
+                            && !v6.is_multicast()
+                            && !v6.is_unspecified()
+                    }
                 }
             }
         })
@@ -113,4 +130,47 @@ mod tests {
         assert!(interfaces.len() == 1);
         assert!(interfaces[0].name == "correct");
     }
+
+    #[tokio::test]
+    async fn gather_local_interfaces_with_ipv6_filtering() {
+        fn interface(name: &str, ip: Ipv6Addr) -> if_addrs::Interface {
+            if_addrs::Interface {
+                name: name.to_owned(),
+                addr: if_addrs::IfAddr::V6(if_addrs::Ifv6Addr {
+                    ip,
+                    netmask: Ipv6Addr::new(0xffff, 0xffff, 0xffff, 0xffff, 0, 0, 0, 0),
+                    prefixlen: 64,
+                    broadcast: None,
+                }),
+                index: None,
+                oper_status: IfOperStatus::Testing,
+                #[cfg(windows)]
+                adapter_name: "{78f73923-a518-4936-ba87-2a30427b1f63}".to_string(),
+            }
+        }
+
+        let mut get_if_addrs_mock = MockGetIfAddrs::new();
+        get_if_addrs_mock.expect_get().returning(|| {
+            Ok(vec![
+                interface("localhost", Ipv6Addr::LOCALHOST),
+                interface("link", Ipv6Addr::new(0xfe80, 0, 0, 0, 1, 2, 3, 4)),
+                interface(
+                    "internal",
+                    Ipv6Addr::new(0xfd74, 0x656c, 0x696f, 0, 0x12, 0x34, 0x56, 0),
+                ),
+                interface("global", Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 1, 2, 3, 4)),
+                interface("lan", Ipv6Addr::new(0xfd00, 0, 0, 0, 1, 2, 3, 4)),
+            ])
+        });
+
+        assert!(gather_local_interfaces(&get_if_addrs_mock)
+            .unwrap()
+            .is_empty());
+        let names: Vec<_> = gather_local_interfaces_with(&get_if_addrs_mock, true)
+            .unwrap()
+            .into_iter()
+            .map(|itf| itf.name)
+            .collect();
+        assert_eq!(names, vec!["global", "lan"]);
+    }
 }
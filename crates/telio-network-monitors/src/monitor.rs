@@ -10,7 +10,10 @@ use std::{
     sync::{Arc, Weak},
 };
 use telio_utils::{telio_log_debug, telio_log_trace, telio_log_warn};
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use tokio::{
+    sync::{broadcast::Sender, mpsc},
+    task::JoinHandle,
+};
 /// Sender to notify if there is a change in OS interface order
 pub static PATH_CHANGE_BROADCAST: Lazy<Sender<()>> = Lazy::new(|| Sender::new(10));
 /// Vector containing all local interfaces
@@ -33,6 +36,14 @@ pub trait LocalInterfacesObserver: Sync + Send {
     fn notify(&self, new_addresses: Vec<IpAddr>);
 }
 
+/// Forwards the changes to a task reacting to them. Changes arriving while the previous one was
+/// not received yet are coalesced into it.
+impl LocalInterfacesObserver for mpsc::Sender<()> {
+    fn notify(&self, _new_addresses: Vec<IpAddr>) {
+        let _ = self.try_send(());
+    }
+}
+
 #[derive(Debug)]
 /// Struct to monitor network
 pub struct NetworkMonitor {
@@ -282,6 +293,17 @@ mod tests {
         assert_eq!(CALL_COUNT.load(Ordering::SeqCst), 1);
     }
 
+    #[tokio::test]
+    async fn test_channel_observer_coalesces_changes() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let observer: &dyn LocalInterfacesObserver = &tx;
+        observer.notify(vec![]);
+        observer.notify(vec![]);
+
+        assert_eq!(rx.try_recv(), Ok(()));
+        assert!(rx.try_recv().is_err());
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_gather_if_error() {
@@ -0,0 +1,136 @@
+//! Network paths reported by the platform monitors of the app.
+//!
+//! Android and iOS sandbox the network extension, so the changes of the network are observed by
+//! the app, with `ConnectivityManager.NetworkCallback` and `NWPathMonitor` respectively, and passed
+//! in through [report_network_path]. A change of the interfaces is fed to the [NetworkMonitor] as
+//! any other path change, while every change of the path is broadcast on
+//! [NETWORK_PATH_BROADCAST] for the flags libtelio has no other source of.
+//!
+//! [NetworkMonitor]: crate::monitor::NetworkMonitor
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use telio_utils::{telio_log_debug, telio_log_info};
+use tokio::sync::broadcast::Sender;
+
+use crate::monitor::PATH_CHANGE_BROADCAST;
+
+/// Sender of the network paths reported by the app
+pub static NETWORK_PATH_BROADCAST: Lazy<Sender<NetworkPath>> = Lazy::new(|| Sender::new(10));
+
+/// Last network path reported by the app
+static CURRENT_PATH: Mutex<Option<NetworkPath>> = Mutex::new(None);
+
+/// Network path as seen by the platform monitor
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetworkPath {
+    /// Whether the path can be used to reach the internet
+    pub satisfied: bool,
+    /// Names of the interfaces the path goes through, in the order of preference
+    pub interfaces: Vec<String>,
+    /// Whether the traffic over the path is metered, e.g. cellular or a personal hotspot
+    pub expensive: bool,
+    /// Whether the user asked to save the data on the path, e.g. Low Data Mode or Data Saver
+    pub constrained: bool,
+    /// Whether the path goes through another VPN
+    pub over_vpn: bool,
+}
+
+impl NetworkPath {
+    /// Whether moving to the `new` path changes how the traffic leaves the device, which
+    /// invalidates the sockets and the endpoints bound to the old one
+    pub fn rebinds(&self, new: &NetworkPath) -> bool {
+        self.satisfied != new.satisfied || self.interfaces != new.interfaces
+    }
+
+    /// Whether optional traffic should be avoided on the path
+    pub fn saves_data(&self) -> bool {
+        self.expensive || self.constrained
+    }
+}
+
+/// Last network path reported by the app, if any
+pub fn current_network_path() -> Option<NetworkPath> {
+    CURRENT_PATH.lock().clone()
+}
+
+/// Take the network `path` reported by the platform monitor of the app into account. Reports
+/// of the same path are ignored.
+pub fn report_network_path(path: NetworkPath) {
+    let rebinds = {
+        let mut current = CURRENT_PATH.lock();
+        if current.as_ref() == Some(&path) {
+            telio_log_debug!("Network path did not change: {path:?}");
+            return;
+        }
+        let rebinds = current
+            .as_ref()
+            .is_none_or(|current| current.rebinds(&path));
+        *current = Some(path.clone());
+        rebinds
+    };
+
+    telio_log_info!("Network path changed: {path:?}");
+    if rebinds {
+        let _ = PATH_CHANGE_BROADCAST.send(());
+    }
+    let _ = NETWORK_PATH_BROADCAST.send(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    fn wifi() -> NetworkPath {
+        NetworkPath {
+            satisfied: true,
+            interfaces: vec!["wlan0".to_owned()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_interface_changes_rebind() {
+        let cellular = NetworkPath {
+            interfaces: vec!["rmnet0".to_owned()],
+            expensive: true,
+            ..wifi()
+        };
+        let low_data = NetworkPath {
+            constrained: true,
+            ..wifi()
+        };
+        let offline = NetworkPath {
+            satisfied: false,
+            ..wifi()
+        };
+
+        assert!(wifi().rebinds(&cellular));
+        assert!(wifi().rebinds(&offline));
+        assert!(!wifi().rebinds(&low_data));
+    }
+
+    #[test]
+    #[serial]
+    fn reported_changes_are_broadcast() {
+        *CURRENT_PATH.lock() = None;
+        let mut path_changes = PATH_CHANGE_BROADCAST.subscribe();
+        let mut paths = NETWORK_PATH_BROADCAST.subscribe();
+
+        report_network_path(wifi());
+        report_network_path(wifi());
+        let low_data = NetworkPath {
+            constrained: true,
+            ..wifi()
+        };
+        report_network_path(low_data.clone());
+
+        assert_eq!(path_changes.try_recv(), Ok(()));
+        assert!(path_changes.try_recv().is_err());
+        assert_eq!(paths.try_recv(), Ok(wifi()));
+        assert_eq!(paths.try_recv(), Ok(low_data.clone()));
+        assert!(paths.try_recv().is_err());
+        assert_eq!(current_network_path(), Some(low_data));
+    }
+}
@@ -47,6 +47,7 @@ pub(crate) enum EndpointType {
     Local = 1,
     Stun = 2,
     UPnP = 3,
+    PortMapping = 4,
 }
 
 impl From<EndpointProvider> for EndpointType {
@@ -55,6 +56,7 @@ impl From<EndpointProvider> for EndpointType {
             EndpointProvider::Local => EndpointType::Local,
             EndpointProvider::Stun => EndpointType::Stun,
             EndpointProvider::Upnp => EndpointType::UPnP,
+            EndpointProvider::PortMapping => EndpointType::PortMapping,
         }
     }
 }
@@ -170,12 +172,22 @@ impl PeerConnDataSegment {
     }
 }
 
+/// Control messages of the peers dropped by the relay client
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DroppedRelayedMessages {
+    /// Messages which could not be authenticated, or were not sequenced while they had to be
+    pub rejected: u64,
+    /// Authentic messages seen before, replayed by the relay
+    pub replayed: u64,
+}
+
 struct AggregatorData {
     current_relay_event: Option<DerpAnalyticsEvent>,
     current_peer_events: HashMap<PublicKey, (AnalyticsEvent, PeerEndpointTypes)>,
     peer_segments: Vec<PeerConnDataSegment>,
     relay_segments: Vec<RelayConnDataSegment>,
     local_key: PublicKey,
+    dropped_relayed_messages: DroppedRelayedMessages,
 }
 
 /// A container to store the connectivity data for the Meshnet peers used by Nurse
@@ -212,6 +224,7 @@ impl ConnectivityDataAggregator {
                 peer_segments: Vec::new(),
                 relay_segments: Vec::new(),
                 local_key,
+                dropped_relayed_messages: Default::default(),
             }),
             config,
             wg_interface,
@@ -466,6 +479,21 @@ impl ConnectivityDataAggregator {
     pub async fn set_local_key(&self, public_key: PublicKey) {
         self.data.lock().await.local_key = public_key;
     }
+
+    /// Count a control message of a peer dropped by the relay client, either as `replayed` or
+    /// as rejected
+    pub async fn report_dropped_relayed_message(&self, replayed: bool) {
+        let dropped = &mut self.data.lock().await.dropped_relayed_messages;
+        match replayed {
+            true => dropped.replayed += 1,
+            false => dropped.rejected += 1,
+        }
+    }
+
+    /// Control messages dropped since the previous call
+    pub async fn take_dropped_relayed_messages(&self) -> DroppedRelayedMessages {
+        mem::take(&mut self.data.lock().await.dropped_relayed_messages)
+    }
 }
 
 #[cfg(test)]
@@ -748,6 +776,28 @@ mod tests {
         assert_eq!(segments.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_dropped_relayed_messages_are_counted() {
+        let env = setup(false, false, None).await;
+        let aggregator = &env.connectivity_data_aggregator;
+
+        aggregator.report_dropped_relayed_message(true).await;
+        aggregator.report_dropped_relayed_message(true).await;
+        aggregator.report_dropped_relayed_message(false).await;
+
+        assert_eq!(
+            aggregator.take_dropped_relayed_messages().await,
+            DroppedRelayedMessages {
+                rejected: 1,
+                replayed: 2,
+            }
+        );
+        assert_eq!(
+            aggregator.take_dropped_relayed_messages().await,
+            Default::default()
+        );
+    }
+
     #[tokio::test]
     async fn test_aggregator_disabled() {
         let mut wg_interface = MockWireGuard::new();
@@ -245,6 +245,9 @@ pub struct Analytics {
 
     /// Connectivity data aggregator
     aggregator: Arc<ConnectivityDataAggregator>,
+
+    /// No collections are started while paused
+    paused: bool,
 }
 
 #[async_trait]
@@ -282,7 +285,7 @@ impl Runtime for Analytics {
                 ),
 
             // A collection event has been triggered from the outside, only in the `Monitoring` state
-            Ok(_) = self.io.collection_trigger_channel.recv(), if self.state == RuntimeState::Monitoring =>
+            Ok(_) = self.io.collection_trigger_channel.recv(), if !self.paused && self.state == RuntimeState::Monitoring =>
                 Self::guard(
                     async move {
                         self.handle_collection().await;
@@ -292,7 +295,7 @@ impl Runtime for Analytics {
                 ),
 
             // Time to send a data request to other nodes, only update in the `Monitoring` state
-            _ = self.task_interval.tick(), if self.state == RuntimeState::Monitoring =>
+            _ = self.task_interval.tick(), if !self.paused && self.state == RuntimeState::Monitoring =>
                 Self::guard(
                     async move {
                         self.handle_collection().await;
@@ -384,6 +387,26 @@ impl Analytics {
             ip_stack: None,
             nat_type: NatType::Unknown,
             aggregator,
+            paused: false,
+        }
+    }
+
+    /// Stop or restart the periodic collections
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Collect with the intervals of the `config` from now on
+    pub fn set_intervals(&mut self, config: &HeartbeatConfig) {
+        self.config.initial_collect_interval = config.initial_collect_interval;
+        if self.config.collect_interval != config.collect_interval {
+            self.config.collect_interval = config.collect_interval;
+            // Nothing is collected until meshnet is started
+            let offset = match self.io.chan {
+                Some(_) => config.collect_interval,
+                None => FAR_FUTURE,
+            };
+            self.task_interval = interval_after(offset, config.collect_interval);
         }
     }
 
@@ -20,14 +20,36 @@ pub mod data;
 pub mod error;
 
 /// Main nurse module
+#[cfg(not(feature = "strict_privacy"))]
+mod nurse;
+
+/// Nurse compiled out of the strict privacy builds
+#[cfg(feature = "strict_privacy")]
+#[path = "strict_privacy.rs"]
 mod nurse;
 
 /// Connectivity data aggregator module
 pub mod aggregator;
 
+/// Analytics consumers module
+pub mod sink;
+
+/// Analytics export to an OpenTelemetry collector
+#[cfg(feature = "otlp")]
+mod otlp;
+
+#[cfg(not(feature = "strict_privacy"))]
 mod heartbeat;
+#[cfg(not(feature = "strict_privacy"))]
 mod qos;
 
+#[cfg(not(feature = "strict_privacy"))]
 pub use heartbeat::MeshnetEntities;
+#[cfg(feature = "strict_privacy")]
+pub use nurse::MeshnetEntities;
 pub use nurse::Nurse;
+#[cfg(not(feature = "strict_privacy"))]
 pub use nurse::NurseIo;
+#[cfg(not(feature = "strict_privacy"))]
+pub use sink::LanaSink;
+pub use sink::{AnalyticsSink, ServiceQualityRecord};
@@ -1,17 +1,20 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use serde::Serialize;
 use std::sync::Arc;
 use telio_crypto::{PublicKey, SecretKey};
-use telio_lana::*;
 use telio_model::event::Event;
+use telio_model::mesh::PeerQos;
 use telio_sockets::SocketPool;
 use telio_task::{
     io::{chan, mc_chan, Chan, McChan},
     task_exec, ExecError, Runtime, RuntimeExt, Task, WaitResponse,
 };
 use telio_utils::{
-    telio_log_debug, telio_log_error, telio_log_info, telio_log_trace, telio_log_warn,
+    telio_log_debug, telio_log_error, telio_log_info, telio_log_trace, telio_log_warn, Instant,
+    ProcessUsage,
 };
 use telio_wg::uapi::AnalyticsEvent;
 use tokio::task::spawn_blocking;
@@ -19,8 +22,10 @@ use uuid::Uuid;
 
 #[mockall_double::double]
 use crate::aggregator::ConnectivityDataAggregator;
+use crate::aggregator::DroppedRelayedMessages;
 
 use crate::error::Error;
+use crate::sink::{AnalyticsSink, ServiceQualityRecord};
 use crate::{config::Config, data::MeshConfigUpdateEvent};
 use crate::{
     data::{AnalyticsMessage, HeartbeatInfo},
@@ -46,6 +51,8 @@ pub struct NurseIo<'a> {
     pub collection_trigger_channel: Option<mc_chan::Tx<()>>,
     /// Event channel to manual trigger a qos
     pub qos_trigger_channel: Option<mc_chan::Tx<()>>,
+    /// Receivers of the collected analytics
+    pub analytics_sinks: Vec<Arc<dyn AnalyticsSink>>,
 }
 
 /// Nurse entity
@@ -96,6 +103,42 @@ impl Nurse {
         .await;
     }
 
+    /// Stop or restart the heartbeat collections and the QoS pings, e.g. while the device is
+    /// paused
+    pub async fn set_paused(&self, paused: bool) {
+        let _ = task_exec!(&self.task, async move |state| {
+            state.set_paused(paused).await;
+            Ok(())
+        })
+        .await;
+    }
+
+    /// Take over the heartbeat and QoS intervals of the `config`, the rest of it is only used
+    /// on start
+    pub async fn set_intervals(&self, config: Config) {
+        let _ = task_exec!(&self.task, async move |state| {
+            state.set_intervals(config).await;
+            Ok(())
+        })
+        .await;
+    }
+
+    /// Most recent RTT measured by QoS analytics for each of the nodes
+    pub async fn get_last_rtts(&self) -> HashMap<PublicKey, Duration> {
+        task_exec!(&self.task, async move |state| Ok(state
+            .get_last_rtts()
+            .await))
+        .await
+        .unwrap_or_default()
+    }
+
+    /// RTT, jitter and loss measured by QoS analytics for each of the nodes
+    pub async fn qos_report(&self) -> Vec<PeerQos> {
+        task_exec!(&self.task, async move |state| Ok(state.qos_report().await))
+            .await
+            .unwrap_or_default()
+    }
+
     /// Send disconnect data
     pub async fn send_disconnect_data(&self) {
         let _ = task_exec!(&self.task, async move |state| {
@@ -112,6 +155,37 @@ impl Nurse {
     }
 }
 
+/// Resources consumed between two service quality events, sent along as debug data
+#[derive(Debug, Serialize)]
+struct ResourceReport {
+    period_s: u64,
+    cpu_time_ms: Option<u64>,
+    wakeups: u64,
+}
+
+/// Connection quality of a peer sent along as debug data, the peer identified by the hash of its
+/// public key
+#[derive(Debug, Serialize)]
+struct PeerQosReport {
+    peer: String,
+    rtt_ms: Option<u64>,
+    jitter_ms: Option<u64>,
+    loss_percent: u32,
+    samples: u32,
+}
+
+impl From<&PeerQos> for PeerQosReport {
+    fn from(qos: &PeerQos) -> Self {
+        Self {
+            peer: format!("{:x}", md5::compute(qos.public_key.0)),
+            rtt_ms: qos.rtt_ms,
+            jitter_ms: qos.jitter_ms,
+            loss_percent: qos.loss_percent,
+            samples: qos.samples,
+        }
+    }
+}
+
 /// Nurse struct, combines meshnet health data from different sources
 /// --
 /// State contains:
@@ -122,6 +196,12 @@ pub struct State {
     analytics_channel: chan::Rx<AnalyticsMessage>,
     heartbeat: Task<HeartbeatAnalytics>,
     qos: Option<Task<QoSAnalytics>>,
+    /// Resource usage at the time of the previous service quality event
+    last_usage: (Instant, ProcessUsage),
+    /// Source of the counters of the dropped relayed messages
+    aggregator: Arc<ConnectivityDataAggregator>,
+    /// Receivers of the collected analytics
+    sinks: Vec<Arc<dyn AnalyticsSink>>,
 }
 
 impl State {
@@ -175,7 +255,7 @@ impl State {
             None,
             config.heartbeat_config,
             heartbeat_io,
-            aggregator,
+            aggregator.clone(),
         );
 
         // Qos component
@@ -203,6 +283,9 @@ impl State {
             analytics_channel: analytics_channel.rx,
             heartbeat: Task::start(heartbeat),
             qos,
+            last_usage: (Instant::now(), ProcessUsage::current()),
+            aggregator,
+            sinks: io.analytics_sinks,
         }
     }
 
@@ -238,132 +321,154 @@ impl State {
         .await;
     }
 
-    async fn handle_service_quality_event(&self, info: &HeartbeatInfo, disconnect: bool) {
-        let internal_sorted_public_keys = info.internal_sorted_public_keys.clone();
-        let external_sorted_public_keys = info.external_sorted_public_keys.clone();
-        let (internal_qos_data, external_qos_data) = if let Some(qos) = self.qos.as_ref() {
-            task_exec!(qos, async move |state| {
-                let result = (
-                    state.get_data(&internal_sorted_public_keys),
-                    state.get_data(&external_sorted_public_keys),
-                );
-                let every_node_in_config: HashSet<PublicKey> = internal_sorted_public_keys
-                    .into_iter()
-                    .chain(external_sorted_public_keys.into_iter())
-                    .collect();
-
-                state.clean_nodes_list(&every_node_in_config);
-                state.reset_cached_data();
+    async fn set_paused(&self, paused: bool) {
+        let _ = task_exec!(&self.heartbeat, async move |state| {
+            state.set_paused(paused);
+            Ok(())
+        })
+        .await;
+        if let Some(qos) = self.qos.as_ref() {
+            let _ = task_exec!(qos, async move |state| {
+                state.set_paused(paused);
+                Ok(())
+            })
+            .await;
+        }
+        telio_log_debug!("Nurse paused: {paused}");
+    }
 
-                Ok(result)
+    async fn set_intervals(&self, config: Config) {
+        let _ = task_exec!(&self.heartbeat, async move |state| {
+            state.set_intervals(&config.heartbeat_config);
+            Ok(())
+        })
+        .await;
+        if let (Some(qos), Some(qos_config)) = (self.qos.as_ref(), config.qos_config) {
+            let _ = task_exec!(qos, async move |state| {
+                state.set_rtt_interval(qos_config.rtt_interval);
+                Ok(())
             })
-            .await
-            .unwrap_or_default()
-        } else {
-            (QoSData::default(), QoSData::default())
-        };
+            .await;
+        }
+    }
 
-        telio_log_info!(
-            "Attempting to send moose {} event ...",
-            if disconnect {
-                "disconnect"
-            } else {
-                "heartbeat"
-            },
-        );
+    async fn get_last_rtts(&self) -> HashMap<PublicKey, Duration> {
+        match self.qos.as_ref() {
+            Some(qos) => task_exec!(qos, async move |state| Ok(state.get_last_rtts()))
+                .await
+                .unwrap_or_default(),
+            None => HashMap::new(),
+        }
+    }
 
-        let qos_data = QoSData::merge(internal_qos_data, external_qos_data);
+    async fn qos_report(&self) -> Vec<PeerQos> {
+        match self.qos.as_ref() {
+            Some(qos) => task_exec!(qos, async move |state| Ok(state.qos_report()))
+                .await
+                .unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
 
-        let r = if disconnect {
-            lana!(
-                send_serviceQuality_node_disconnect,
-                qos_data.connection_duration,
-                qos_data.rtt,
-                qos_data.rtt_loss,
-                qos_data.rtt6,
-                qos_data.rtt6_loss,
-                qos_data.tx,
-                qos_data.rx,
-                info.heartbeat_interval,
-                0, // TODO Derp Connection Duration
-                info.nat_traversal_conn_info.clone(),
-                info.derp_conn_info.clone(),
-                None
-            )
-        } else {
-            lana!(
-                send_serviceQuality_node_heartbeat,
-                qos_data.connection_duration,
-                qos_data.rtt,
-                qos_data.rtt_loss,
-                qos_data.rtt6,
-                qos_data.rtt6_loss,
-                qos_data.tx,
-                qos_data.rx,
-                info.heartbeat_interval,
-                0, // TODO Derp Connection Duration
-                info.nat_traversal_conn_info.clone(),
-                info.derp_conn_info.clone(),
-                None
-            )
+    /// Serialized resource usage since the previous call, together with the `qos` report and the
+    /// `dropped` relayed messages
+    fn take_debug_report(
+        &mut self,
+        qos: &[PeerQos],
+        dropped: DroppedRelayedMessages,
+    ) -> Option<String> {
+        let (now, usage) = (Instant::now(), ProcessUsage::current());
+        let (since, baseline) = std::mem::replace(&mut self.last_usage, (now, usage));
+        let delta = usage.since(&baseline);
+
+        let report = ResourceReport {
+            period_s: now.saturating_duration_since(since).as_secs(),
+            cpu_time_ms: delta.cpu_time.map(|cpu_time| cpu_time.as_millis() as u64),
+            wakeups: delta.wakeups,
         };
+        let qos: Vec<PeerQosReport> = qos.iter().map(PeerQosReport::from).collect();
+        let data = serde_json::json!({
+            "resource_usage": report,
+            "qos": qos,
+            "dropped_relayed_messages": dropped,
+        });
+        serde_json::to_string(&data)
+            .map_err(|e| telio_log_warn!("Failed to serialize debug data: {e}"))
+            .ok()
+    }
+
+    async fn handle_service_quality_event(&mut self, info: &HeartbeatInfo, disconnect: bool) {
+        let internal_sorted_public_keys = info.internal_sorted_public_keys.clone();
+        let external_sorted_public_keys = info.external_sorted_public_keys.clone();
+        let (internal_qos_data, external_qos_data, qos_report) =
+            if let Some(qos) = self.qos.as_ref() {
+                task_exec!(qos, async move |state| {
+                    let result = (
+                        state.get_data(&internal_sorted_public_keys),
+                        state.get_data(&external_sorted_public_keys),
+                        state.qos_report(),
+                    );
+                    let every_node_in_config: HashSet<PublicKey> = internal_sorted_public_keys
+                        .into_iter()
+                        .chain(external_sorted_public_keys.into_iter())
+                        .collect();
+
+                    state.clean_nodes_list(&every_node_in_config);
+                    state.reset_cached_data();
+
+                    Ok(result)
+                })
+                .await
+                .unwrap_or_default()
+            } else {
+                (QoSData::default(), QoSData::default(), Vec::new())
+            };
 
         telio_log_info!(
-            "Moose {} event result: {:?}",
+            "Attempting to send {} event ...",
             if disconnect {
                 "disconnect"
             } else {
                 "heartbeat"
             },
-            r
-        );
-    }
-
-    async fn handle_heartbeat_event(&self, info: HeartbeatInfo) {
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_meshnetEnabled,
-            info.meshnet_enabled
-        );
-
-        // Send off nominated fingerprint to moose
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_internalMeshnet_fp,
-            info.meshnet_id.to_string()
-        );
-
-        // We pray that nothing goes wrong here
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_internalMeshnet_members,
-            info.fingerprints.clone()
         );
 
-        // And send this off to moose
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_internalMeshnet_connectivityMatrix,
-            info.connectivity_matrix.clone()
-        );
-
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_externalLinks,
-            info.external_links.clone()
-        );
-
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_internalMeshnet_fpNat,
-            info.nat_type.clone()
-        );
+        let qos_data = QoSData::merge(internal_qos_data, external_qos_data);
+        let dropped = self.aggregator.take_dropped_relayed_messages().await;
+        let debug_data = self.take_debug_report(&qos_report, dropped);
+
+        let record = ServiceQualityRecord {
+            disconnect,
+            connection_duration: qos_data.connection_duration,
+            rtt: qos_data.rtt,
+            rtt_loss: qos_data.rtt_loss,
+            rtt6: qos_data.rtt6,
+            rtt6_loss: qos_data.rtt6_loss,
+            tx: qos_data.tx,
+            rx: qos_data.rx,
+            heartbeat_interval: info.heartbeat_interval,
+            nat_traversal_conn_info: info.nat_traversal_conn_info.clone(),
+            derp_conn_info: info.derp_conn_info.clone(),
+            qos: qos_report,
+            debug_data,
+        };
+        for sink in &self.sinks {
+            sink.service_quality(&record);
+        }
+    }
 
-        let _ = lana!(
-            set_context_application_libtelioapp_config_currentState_internalMeshnet_membersNat,
-            info.peer_nat_types.join(",")
-        );
+    async fn handle_heartbeat_event(&mut self, info: HeartbeatInfo) {
+        for sink in &self.sinks {
+            sink.heartbeat(&info);
+        }
 
         self.handle_service_quality_event(&info, false).await;
 
+        let sinks = self.sinks.clone();
         let _ = spawn_blocking(move || {
-            telio_log_info!("Attempting to flush moose changes");
-            let r = lana!(flush_changes);
-            telio_log_info!("Flushing moose changes result: {:?}", r);
+            for sink in &sinks {
+                sink.flush();
+            }
         })
         .await;
     }
@@ -387,15 +492,16 @@ impl State {
         })
     }
 
-    async fn send_disconnect_data(&self) {
+    async fn send_disconnect_data(&mut self) {
         if let Ok(Ok(hb_info)) = task_exec!(&self.heartbeat, async move |state| {
             Ok(state.get_disconnect_data().await.map_err(|_| ExecError))
         })
         .await
         {
             self.handle_service_quality_event(&hb_info, true).await;
-            let r = lana!(flush_changes);
-            telio_log_info!("Flushing moose changes result: {:?}", r);
+            for sink in &self.sinks {
+                sink.flush();
+            }
         }
     }
 }
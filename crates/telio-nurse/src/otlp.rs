@@ -0,0 +1,43 @@
+//! [AnalyticsSink] turning the nurse records into OpenTelemetry gauges.
+
+use telio_lana::otlp::OtlpExporter;
+
+use crate::data::HeartbeatInfo;
+use crate::sink::{AnalyticsSink, ServiceQualityRecord};
+
+impl AnalyticsSink for OtlpExporter {
+    fn heartbeat(&self, info: &HeartbeatInfo) {
+        let meshnet = vec![("meshnet.id", info.meshnet_id.clone())];
+        self.gauge(
+            "telio.meshnet.enabled",
+            "1",
+            f64::from(u8::from(info.meshnet_enabled)),
+            meshnet.clone(),
+        );
+        self.gauge(
+            "telio.meshnet.internal_nodes",
+            "1",
+            info.internal_sorted_public_keys.len() as f64,
+            meshnet.clone(),
+        );
+        self.gauge(
+            "telio.meshnet.external_nodes",
+            "1",
+            info.external_sorted_public_keys.len() as f64,
+            meshnet,
+        );
+    }
+
+    fn service_quality(&self, record: &ServiceQualityRecord) {
+        for qos in &record.qos {
+            let peer = vec![("peer", qos.public_key.to_string())];
+            if let Some(rtt) = qos.rtt_ms {
+                self.gauge("telio.peer.rtt", "ms", rtt as f64, peer.clone());
+            }
+            if let Some(jitter) = qos.jitter_ms {
+                self.gauge("telio.peer.jitter", "ms", jitter as f64, peer.clone());
+            }
+            self.gauge("telio.peer.loss", "%", f64::from(qos.loss_percent), peer);
+        }
+    }
+}
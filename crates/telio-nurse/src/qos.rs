@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use histogram::Histogram;
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::net::IpAddr;
 use std::sync::Arc;
 use telio_model::constants::{VPN_INTERNAL_IPV4, VPN_INTERNAL_IPV6};
@@ -10,12 +10,15 @@ use tokio::time::{Duration, Interval};
 
 use telio_crypto::PublicKey;
 use telio_model::features::RttType;
+use telio_model::mesh::PeerQos;
 use telio_task::{io::mc_chan, Runtime, RuntimeExt, WaitResponse};
 use telio_wg::uapi::{AnalyticsEvent, PeerState};
 
-use telio_pinger::{DualPingResults, Pinger};
+use telio_pinger::{DualPingResults, PingResults, Pinger};
 use telio_sockets::SocketPool;
-use telio_utils::{interval, telio_log_debug, telio_log_trace, DualTarget, Instant, IpStack};
+use telio_utils::{
+    interval, interval_after, telio_log_debug, telio_log_trace, DualTarget, Instant, IpStack,
+};
 
 use crate::{config::QoSConfig, data::MeshConfigUpdateEvent};
 
@@ -35,6 +38,8 @@ pub struct NodeInfo {
     pub rtt_loss_histogram: Histogram,
     pub rtt6_histogram: Histogram,
     pub rtt6_loss_histogram: Histogram,
+    pub last_rtt: Option<Duration>,
+    pub qos_window: QosWindow,
 
     // Throughput
     pub last_tx_bytes: u64,
@@ -89,6 +94,8 @@ impl From<AnalyticsEvent> for NodeInfo {
             rtt_loss_histogram: Histogram::new(),
             rtt6_histogram: Histogram::new(),
             rtt6_loss_histogram: Histogram::new(),
+            last_rtt: None,
+            qos_window: QosWindow::default(),
             last_tx_bytes: 0,
             last_rx_bytes: 0,
             tx_histogram: Histogram::new(),
@@ -97,6 +104,78 @@ impl From<AnalyticsEvent> for NodeInfo {
     }
 }
 
+/// Number of the most recent ping rounds aggregated in [QosWindow]
+const QOS_WINDOW_SIZE: usize = 10;
+
+/// One round of pings to a node
+#[derive(Clone, Copy, Debug)]
+struct PingSample {
+    rtt: Option<Duration>,
+    sent: u32,
+    lost: u32,
+}
+
+/// Rolling RTT, jitter and loss of a node, unlike the histograms not reset by heartbeats
+#[derive(Clone, Debug, Default)]
+pub struct QosWindow {
+    samples: VecDeque<PingSample>,
+}
+
+impl QosWindow {
+    fn push(&mut self, results: &PingResults) {
+        if self.samples.len() >= QOS_WINDOW_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(PingSample {
+            rtt: results.avg_rtt,
+            sent: results.successful_pings + results.unsuccessful_pings,
+            lost: results.unsuccessful_pings,
+        });
+    }
+
+    /// Aggregates of the samples in the window
+    pub fn report(&self, public_key: PublicKey) -> PeerQos {
+        let rtts: Vec<u64> = self
+            .samples
+            .iter()
+            .filter_map(|sample| sample.rtt)
+            .map(|rtt| rtt.as_millis() as u64)
+            .collect();
+        let average = |values: &[u64]| {
+            (!values.is_empty()).then(|| values.iter().sum::<u64>() / values.len() as u64)
+        };
+        let deltas: Vec<u64> = rtts
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [a, b] => Some(a.abs_diff(*b)),
+                _ => None,
+            })
+            .collect();
+
+        let (sent, lost) = self
+            .samples
+            .iter()
+            .fold((0u32, 0u32), |(sent, lost), sample| {
+                (
+                    sent.saturating_add(sample.sent),
+                    lost.saturating_add(sample.lost),
+                )
+            });
+
+        PeerQos {
+            public_key,
+            rtt_ms: average(&rtts),
+            jitter_ms: average(&deltas),
+            loss_percent: if sent > 0 {
+                (100 * u64::from(lost) / u64::from(sent)) as u32
+            } else {
+                0
+            },
+            samples: self.samples.len() as u32,
+        }
+    }
+}
+
 /// QoS data.
 #[derive(Debug, Default, PartialEq)]
 pub struct OutputData {
@@ -182,6 +261,8 @@ pub struct Analytics {
     ping_channel_tx: mpsc::WeakSender<(PublicKey, DualPingResults)>,
     buckets: u32,
     ip_stack: Option<IpStack>,
+    /// No pings are sent while paused
+    paused: bool,
     #[cfg(test)]
     ping_cnt: u32,
 }
@@ -211,13 +292,13 @@ impl Runtime for Analytics {
             },
 
             // Manual trigger
-            Ok(_) = self.io.manual_trigger_channel.recv(), if self.ping_channel_tx.upgrade().is_none() => {
+            Ok(_) = self.io.manual_trigger_channel.recv(), if !self.paused && self.ping_channel_tx.upgrade().is_none() => {
                 self.rtt_interval.reset();
                 self.perform_ping();
                 Self::next()
             },
 
-            _ = self.rtt_interval.tick(), if self.ping_channel_tx.upgrade().is_none() => {
+            _ = self.rtt_interval.tick(), if !self.paused && self.ping_channel_tx.upgrade().is_none() => {
                 telio_log_debug!("Starting periodic ping");
                 self.perform_ping();
                 Self::next()
@@ -270,12 +351,41 @@ impl Analytics {
             ping_channel_tx: ping_channel_tx.downgrade(),
             buckets: config.buckets,
             ip_stack: None,
+            paused: false,
             // TODO: introduce mocked `ping_backend` for testing
             #[cfg(test)]
             ping_cnt: 0,
         }
     }
 
+    /// Stop or restart the periodic pings
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Ping every `rtt_interval` from now on
+    pub fn set_rtt_interval(&mut self, rtt_interval: Duration) {
+        if self.rtt_interval.period() != rtt_interval {
+            self.rtt_interval = interval_after(rtt_interval, rtt_interval);
+        }
+    }
+
+    /// Most recent RTT measured for each of the nodes
+    pub fn get_last_rtts(&self) -> HashMap<PublicKey, Duration> {
+        self.nodes
+            .iter()
+            .filter_map(|(pk, node)| node.last_rtt.map(|rtt| (*pk, rtt)))
+            .collect()
+    }
+
+    /// Rolling RTT, jitter and loss of each of the nodes
+    pub fn qos_report(&self) -> Vec<PeerQos> {
+        self.nodes
+            .iter()
+            .map(|(pk, node)| node.qos_window.report(*pk))
+            .collect()
+    }
+
     /// Wrapper function around `Analytics::get_data_from_nodes_hashmap`.
     pub fn get_data(&mut self, sorted_public_keys_set: &BTreeSet<PublicKey>) -> OutputData {
         Analytics::get_data_from_nodes_hashmap(&self.nodes, self.buckets, sorted_public_keys_set)
@@ -500,8 +610,17 @@ impl Analytics {
     fn process_node_ping_results(&mut self, dpr: (PublicKey, DualPingResults)) {
         if let Some(pinger) = &*self.ping_backend {
             self.nodes.entry(dpr.0).and_modify(|node| {
+                // The window follows a single measurement per round, preferring IPv4
+                if let Some(results) = dpr.1.v4.as_ref().or(dpr.1.v6.as_ref()) {
+                    node.qos_window.push(results);
+                }
+
+                // The last RTT prefers IPv4 too, falling back to IPv6 from the same round
+                let has_rtt_v4 = dpr.1.v4.as_ref().and_then(|r| r.avg_rtt).is_some();
+
                 if let Some(results_v4) = dpr.1.v4 {
                     if let Some(avg_rtt) = results_v4.avg_rtt {
+                        node.last_rtt = Some(avg_rtt);
                         let avg_v4 = avg_rtt.as_millis() as u64;
                         let _ = node.rtt_histogram.increment(avg_v4);
                         let _ = node.rtt_loss_histogram.increment(
@@ -517,6 +636,9 @@ impl Analytics {
 
                 if let Some(results_v6) = dpr.1.v6 {
                     if let Some(avg_rtt) = results_v6.avg_rtt {
+                        if !has_rtt_v4 {
+                            node.last_rtt = Some(avg_rtt);
+                        }
                         let avg_v6 = avg_rtt.as_millis() as u64;
                         let _ = node.rtt6_histogram.increment(avg_v6);
                         let _ = node.rtt6_loss_histogram.increment(
@@ -634,6 +756,55 @@ mod tests {
         assert_eq!(output_empty_histogram, String::from("0:0:0:0:0"));
     }
 
+    #[test]
+    fn test_qos_window() {
+        let public_key = PublicKey(*b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+        let results = |rtt_ms: Option<u64>, lost| PingResults {
+            host: None,
+            successful_pings: 2 - lost,
+            unsuccessful_pings: lost,
+            avg_rtt: rtt_ms.map(Duration::from_millis),
+        };
+
+        let mut window = QosWindow::default();
+        assert_eq!(
+            window.report(public_key),
+            PeerQos {
+                public_key,
+                ..Default::default()
+            }
+        );
+
+        window.push(&results(Some(10), 0));
+        window.push(&results(Some(30), 1));
+        window.push(&results(None, 2));
+        window.push(&results(Some(20), 1));
+        assert_eq!(
+            window.report(public_key),
+            PeerQos {
+                public_key,
+                rtt_ms: Some(20),
+                jitter_ms: Some(15),
+                loss_percent: 50,
+                samples: 4,
+            }
+        );
+
+        for _ in 0..QOS_WINDOW_SIZE {
+            window.push(&results(Some(40), 0));
+        }
+        assert_eq!(
+            window.report(public_key),
+            PeerQos {
+                public_key,
+                rtt_ms: Some(40),
+                jitter_ms: Some(0),
+                loss_percent: 0,
+                samples: QOS_WINDOW_SIZE as u32,
+            }
+        );
+    }
+
     #[test]
     fn test_data_format() {
         let a_public_key = PublicKey(*b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
@@ -801,6 +972,45 @@ mod tests {
         assert_eq!(output, expected_output);
     }
 
+    #[tokio::test]
+    #[cfg(not(target_os = "macos"))]
+    async fn test_last_rtt_falls_back_to_ipv6() {
+        let (mut analytics, _, _) = setup();
+        let event = generate_event();
+        analytics.handle_wg_event(&event).await;
+
+        let results = |avg_rtt: Option<u64>| PingResults {
+            successful_pings: avg_rtt.map_or(0, |_| 1),
+            unsuccessful_pings: avg_rtt.map_or(1, |_| 0),
+            avg_rtt: avg_rtt.map(Duration::from_millis),
+            ..Default::default()
+        };
+
+        analytics.process_node_ping_results((
+            event.public_key,
+            DualPingResults {
+                v4: Some(results(Some(10))),
+                v6: Some(results(Some(20))),
+            },
+        ));
+        assert_eq!(
+            analytics.get_last_rtts().get(&event.public_key),
+            Some(&Duration::from_millis(10))
+        );
+
+        analytics.process_node_ping_results((
+            event.public_key,
+            DualPingResults {
+                v4: Some(results(None)),
+                v6: Some(results(Some(30))),
+            },
+        ));
+        assert_eq!(
+            analytics.get_last_rtts().get(&event.public_key),
+            Some(&Duration::from_millis(30))
+        );
+    }
+
     #[tokio::test(start_paused = true)]
     #[cfg(not(target_os = "macos"))]
     async fn test_manual_and_rtt_interval() {
@@ -994,6 +1204,8 @@ mod tests {
             rtt_loss_histogram: histogram.clone(),
             rtt6_histogram: histogram.clone(),
             rtt6_loss_histogram: histogram.clone(),
+            last_rtt: None,
+            qos_window: QosWindow::default(),
             last_rx_bytes: 0,
             last_tx_bytes: 0,
             tx_histogram: histogram.clone(),
@@ -0,0 +1,160 @@
+//! Consumers of the analytics collected by nurse.
+//!
+//! By default the records go to the built-in analytics backend through [LanaSink]. Deployments
+//! running their own telemetry pipeline register an [AnalyticsSink] instead, or in addition, and
+//! receive the same records as plain Rust types.
+
+#[cfg(not(feature = "strict_privacy"))]
+use telio_lana::*;
+use telio_model::mesh::PeerQos;
+#[cfg(not(feature = "strict_privacy"))]
+use telio_utils::{telio_log_info, telio_log_warn};
+
+use crate::data::HeartbeatInfo;
+
+/// Quality of service data, sent with every heartbeat and when the device disconnects.
+///
+/// Histograms are serialized the same way the built-in backend expects them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServiceQualityRecord {
+    /// Whether the record is the last one, sent on disconnect
+    pub disconnect: bool,
+    /// Connection durations of the nodes
+    pub connection_duration: String,
+    /// RTT histograms of the nodes
+    pub rtt: String,
+    /// RTT loss histograms of the nodes
+    pub rtt_loss: String,
+    /// IPv6 RTT histograms of the nodes
+    pub rtt6: String,
+    /// IPv6 RTT loss histograms of the nodes
+    pub rtt6_loss: String,
+    /// Sent bytes histograms of the nodes
+    pub tx: String,
+    /// Received bytes histograms of the nodes
+    pub rx: String,
+    /// How often heartbeats are sent, in seconds
+    pub heartbeat_interval: i32,
+    /// Nat traversal connection info
+    pub nat_traversal_conn_info: String,
+    /// Derp connection info
+    pub derp_conn_info: String,
+    /// Rolling RTT, jitter and loss aggregates of the nodes
+    pub qos: Vec<PeerQos>,
+    /// Resources consumed and relayed messages dropped since the previous record, and the QoS
+    /// aggregates, serialized as JSON
+    pub debug_data: Option<String>,
+}
+
+/// Receiver of the analytics records collected by nurse
+pub trait AnalyticsSink: Send + Sync {
+    /// Meshnet state collected by the heartbeat, including the connectivity matrix
+    fn heartbeat(&self, info: &HeartbeatInfo);
+
+    /// Quality of service data of the nodes
+    fn service_quality(&self, record: &ServiceQualityRecord);
+
+    /// Called after each round of records, may block
+    fn flush(&self) {}
+}
+
+/// Sink forwarding the records to the built-in analytics backend, compiled out of the strict
+/// privacy builds
+#[cfg(not(feature = "strict_privacy"))]
+#[derive(Debug, Default)]
+pub struct LanaSink;
+
+#[cfg(not(feature = "strict_privacy"))]
+impl AnalyticsSink for LanaSink {
+    fn heartbeat(&self, info: &HeartbeatInfo) {
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_meshnetEnabled,
+            info.meshnet_enabled
+        );
+
+        // Send off nominated fingerprint to moose
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_internalMeshnet_fp,
+            info.meshnet_id.to_string()
+        );
+
+        // We pray that nothing goes wrong here
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_internalMeshnet_members,
+            info.fingerprints.clone()
+        );
+
+        // And send this off to moose
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_internalMeshnet_connectivityMatrix,
+            info.connectivity_matrix.clone()
+        );
+
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_externalLinks,
+            info.external_links.clone()
+        );
+
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_internalMeshnet_fpNat,
+            info.nat_type.clone()
+        );
+
+        let _ = lana!(
+            set_context_application_libtelioapp_config_currentState_internalMeshnet_membersNat,
+            info.peer_nat_types.join(",")
+        );
+    }
+
+    fn service_quality(&self, record: &ServiceQualityRecord) {
+        let r = if record.disconnect {
+            lana!(
+                send_serviceQuality_node_disconnect,
+                record.connection_duration.clone(),
+                record.rtt.clone(),
+                record.rtt_loss.clone(),
+                record.rtt6.clone(),
+                record.rtt6_loss.clone(),
+                record.tx.clone(),
+                record.rx.clone(),
+                record.heartbeat_interval,
+                0, // TODO Derp Connection Duration
+                record.nat_traversal_conn_info.clone(),
+                record.derp_conn_info.clone(),
+                record.debug_data.clone()
+            )
+        } else {
+            lana!(
+                send_serviceQuality_node_heartbeat,
+                record.connection_duration.clone(),
+                record.rtt.clone(),
+                record.rtt_loss.clone(),
+                record.rtt6.clone(),
+                record.rtt6_loss.clone(),
+                record.tx.clone(),
+                record.rx.clone(),
+                record.heartbeat_interval,
+                0, // TODO Derp Connection Duration
+                record.nat_traversal_conn_info.clone(),
+                record.derp_conn_info.clone(),
+                record.debug_data.clone()
+            )
+        };
+
+        telio_log_info!(
+            "Moose {} event result: {:?}",
+            if record.disconnect {
+                "disconnect"
+            } else {
+                "heartbeat"
+            },
+            r
+        );
+    }
+
+    fn flush(&self) {
+        telio_log_info!("Attempting to flush moose changes");
+        let r = lana!(flush_changes);
+        telio_log_info!("Flushing moose changes result: {:?}", r);
+    }
+}
@@ -0,0 +1,54 @@
+//! Nurse compiled out by the `strict_privacy` feature.
+//!
+//! The heartbeats and the QoS pings are never sent to the peers and nothing is collected for the
+//! analytics sinks. [Nurse] has no values, so the code holding an `Option<Nurse>` keeps compiling
+//! while every use of it is unreachable.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use telio_crypto::{PublicKey, SecretKey};
+use telio_model::mesh::PeerQos;
+use telio_proto::HeartbeatMessage;
+use telio_task::io::Chan;
+
+/// Additional configuration for Nurse provided when meshnet is configured
+pub struct MeshnetEntities {
+    /// Multiplexer channel
+    pub multiplexer_channel: Chan<(PublicKey, HeartbeatMessage)>,
+}
+
+/// Nurse entity, uninhabited in the strict privacy builds
+pub enum Nurse {}
+
+impl Nurse {
+    /// Run when you change meshnet configuration
+    pub async fn configure_meshnet(&self, _meshnet_entities: Option<MeshnetEntities>) {
+        match *self {}
+    }
+
+    /// Update private key
+    pub async fn set_private_key(&self, _private_key: SecretKey) {
+        match *self {}
+    }
+
+    /// Most recent RTT measured by QoS analytics for each of the nodes
+    pub async fn get_last_rtts(&self) -> HashMap<PublicKey, Duration> {
+        match *self {}
+    }
+
+    /// RTT, jitter and loss measured by QoS analytics for each of the nodes
+    pub async fn qos_report(&self) -> Vec<PeerQos> {
+        match *self {}
+    }
+
+    /// Send disconnect data
+    pub async fn send_disconnect_data(&self) {
+        match *self {}
+    }
+
+    /// Stop nurse
+    pub async fn stop(self) {
+        match self {}
+    }
+}
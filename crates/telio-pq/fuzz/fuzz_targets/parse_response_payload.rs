@@ -5,4 +5,5 @@ use libfuzzer_sys::fuzz_target;
 fuzz_target!(|data: &[u8]| {
     let _result = telio_pq::fuzz::parse_response_payload(data, 1);
     let _result = telio_pq::fuzz::parse_response_payload(data, 2);
+    let _result = telio_pq::fuzz::parse_hybrid_response_payload(data);
 });
@@ -1,13 +1,21 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 
-use tokio::task::JoinHandle;
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::{sync::watch, task::JoinHandle};
 
-use telio_model::features::FeaturePostQuantumVPN;
+use telio_model::{
+    event::{PqRekey, PqRekeyState},
+    features::FeaturePostQuantumVPN,
+};
 use telio_task::io::chan;
 use telio_utils::{reset_after, telio_log_debug, telio_log_warn};
 
 use crate::proto;
 
+/// Highest protocol version agreed with each server, which is never fallen back below
+pub type NegotiatedVersions = Arc<Mutex<HashMap<telio_crypto::PublicKey, u32>>>;
+
 pub struct ConnKeyRotation {
     task: JoinHandle<()>,
 }
@@ -20,12 +28,18 @@ impl ConnKeyRotation {
         wg_secret: telio_crypto::SecretKey,
         peer: telio_crypto::PublicKey,
         features: &FeaturePostQuantumVPN,
+        negotiated_versions: NegotiatedVersions,
+        mut paused: watch::Receiver<bool>,
     ) -> Self {
         telio_log_debug!("Starting PQ task");
 
         let rekey_interval = Duration::from_secs(features.rekey_interval_s as _);
+        let rekey_jitter = Duration::from_secs(features.rekey_jitter_s as _);
         let request_retry = Duration::from_secs(features.handshake_retry_interval_s as _);
-        let pq_version = features.version;
+        let mut pq_version = features.version;
+        let min_version = features
+            .min_version
+            .map_or(pq_version, |min_version| min_version.min(pq_version));
 
         let task = async move {
             let mut retry_interval = telio_utils::interval(request_retry);
@@ -35,6 +49,15 @@ impl ConnKeyRotation {
                 pq_secret,
             } = loop {
                 retry_interval.tick().await;
+                // Nothing is sent to the server while paused
+                let _ = paused.wait_for(|paused| !paused).await;
+
+                // Falling back below a version the server already agreed on could only be caused
+                // by someone tampering with the exchange
+                let lowest_version = negotiated_versions
+                    .lock()
+                    .get(&peer)
+                    .map_or(min_version, |negotiated| (*negotiated).max(min_version));
 
                 // Dylint is unhappy about the `fetch_keys` future size
                 // and asks for using `Box::pin` to move it on the heap
@@ -48,10 +71,21 @@ impl ConnKeyRotation {
 
                 match tokio::time::timeout(request_retry, fetch_keys).await {
                     Ok(Ok(keys)) => {
-                        telio_log_debug!("PQ keys fetched");
+                        telio_log_debug!("PQ keys fetched with version {pq_version}");
+                        let mut negotiated_versions = negotiated_versions.lock();
+                        let negotiated = negotiated_versions.entry(peer).or_insert(pq_version);
+                        *negotiated = (*negotiated).max(pq_version);
                         break keys;
                     }
-                    Ok(Err(err)) => telio_log_warn!("Failed to fetch PQ keys: {err}"),
+                    Ok(Err(err)) => match fallback_version(&err, pq_version, lowest_version) {
+                        Some(lower) => {
+                            telio_log_warn!(
+                                "PQ version {pq_version} rejected by the server, falling back to {lower}"
+                            );
+                            pq_version = lower;
+                        }
+                        None => telio_log_warn!("Failed to fetch PQ keys: {err}"),
+                    },
                     Err(_timeout) => telio_log_warn!(
                         "Failed to fetch PQ keys: TIMEOUT({}s)",
                         request_retry.as_secs()
@@ -67,56 +101,59 @@ impl ConnKeyRotation {
                 .send(super::Event::KeyFetch(addr, wg_keys.clone()))
                 .await;
 
-            telio_log_debug!("Rekey interval: {}s", rekey_interval.as_secs());
-            let mut interval = telio_utils::interval(rekey_interval);
+            telio_log_debug!(
+                "Rekey interval: {}s, jitter: {}s",
+                rekey_interval.as_secs(),
+                rekey_jitter.as_secs()
+            );
+            let mut interval =
+                telio_utils::interval_after(jittered(rekey_interval, rekey_jitter), rekey_interval);
 
-            interval.tick().await; // This call returns immedietly
             loop {
                 interval.tick().await;
+                let _ = paused.wait_for(|paused| !paused).await;
 
                 // Dylint is unhappy about the `rekey` future size
                 // and asks for using `Box::pin` to move it on the heap
-                let rekey = if pq_version == 2 {
-                    Box::pin(super::proto::rekey(
-                        &socket_pool,
-                        &pq_secret,
-                        pq_version,
-                        Some(super::proto::RekeyV2Auth {
-                            pre_shared_key: wg_keys.pq_shared.clone(),
-                            wg_client_public: wg_keys.wg_secret.public(),
-                            wg_server_public: peer,
-                        }),
-                    ))
-                } else {
-                    Box::pin(super::proto::rekey(
-                        &socket_pool,
-                        &pq_secret,
-                        pq_version,
-                        None,
-                    ))
-                };
+                // Rekeys stay at the version of the initial exchange
+                let auth = (pq_version >= 2).then(|| super::proto::RekeyAuth {
+                    pre_shared_key: wg_keys.pq_shared.clone(),
+                    wg_client_public: wg_keys.wg_secret.public(),
+                    wg_server_public: peer,
+                });
+                let rekey = Box::pin(super::proto::rekey(
+                    &socket_pool,
+                    &pq_secret,
+                    pq_version,
+                    auth,
+                ));
 
+                report_rekey(&chan, peer, pq_version, PqRekeyState::Started, None).await;
                 match tokio::time::timeout(request_retry, rekey).await {
                     Ok(Ok(key)) => {
                         telio_log_debug!("Successful PQ REKEY");
                         wg_keys.pq_shared = key.clone();
+                        reset_after(&mut interval, jittered(rekey_interval, rekey_jitter));
 
                         // The channel is allways open during the library operation.
                         // It can only be closed on library shutdown, in that case we
                         // may not care since the task itself will be killed soon
                         #[allow(mpsc_blocking_send)]
                         let _ = chan.send(super::Event::Rekey(wg_keys.clone())).await;
+                        report_rekey(&chan, peer, pq_version, PqRekeyState::Succeeded, None).await;
                     }
                     Ok(Err(err)) => {
                         telio_log_warn!("Failed to perform PQ rekey: {err}");
                         reset_after(&mut interval, request_retry);
+                        let error = Some(err.to_string());
+                        report_rekey(&chan, peer, pq_version, PqRekeyState::Failed, error).await;
                     }
                     Err(_timeout) => {
-                        telio_log_warn!(
-                            "Failed to perform PQ rekey: TIMEOUT({}s)",
-                            request_retry.as_secs()
-                        );
+                        let error = format!("TIMEOUT({}s)", request_retry.as_secs());
+                        telio_log_warn!("Failed to perform PQ rekey: {error}");
                         interval.reset_immediately();
+                        report_rekey(&chan, peer, pq_version, PqRekeyState::Failed, Some(error))
+                            .await;
                     }
                 }
             }
@@ -128,9 +165,84 @@ impl ConnKeyRotation {
     }
 }
 
+/// The rekey `interval` extended by a random delay of up to `jitter`
+fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        interval
+    } else {
+        interval + rand::thread_rng().gen_range(Duration::ZERO..=jitter)
+    }
+}
+
+async fn report_rekey(
+    chan: &chan::Tx<super::Event>,
+    public_key: telio_crypto::PublicKey,
+    version: u32,
+    state: PqRekeyState,
+    error: Option<String>,
+) {
+    // The channel is allways open during the library operation
+    #[allow(mpsc_blocking_send)]
+    let _ = chan
+        .send(super::Event::RekeyProgress(PqRekey {
+            public_key,
+            version,
+            state,
+            error,
+        }))
+        .await;
+}
+
 impl Drop for ConnKeyRotation {
     fn drop(&mut self) {
         self.task.abort();
         telio_log_debug!("PQ rekey task aborted");
     }
 }
+
+/// Version to fetch the keys with after the `err` of a fetch with the `pq_version`, when the
+/// server rejected it and it is above the `lowest_version`
+fn fallback_version(err: &super::Error, pq_version: u32, lowest_version: u32) -> Option<u32> {
+    (err.is_version_rejection() && pq_version > lowest_version).then(|| pq_version - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::{self, PqProtoV2Status},
+        Error,
+    };
+
+    #[test]
+    fn rejected_version_falls_back() {
+        // A server not supporting the hybrid exchange answers with its v2 error
+        let err = proto::parse_hybrid_response_payload(b"\x02\x00\x00\x00\x05").unwrap_err();
+        assert_eq!(fallback_version(&err, 3, 1), Some(2));
+        assert_eq!(
+            fallback_version(&Error::ServerV2(PqProtoV2Status::CouldNotReadVersion), 2, 1),
+            Some(1)
+        );
+
+        // Not below the version already negotiated with the server
+        assert_eq!(fallback_version(&err, 3, 3), None);
+        assert_eq!(
+            fallback_version(
+                &Error::ServerV3(PqProtoV2Status::AuthenticationFailed),
+                3,
+                1
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn jitter_extends_rekey_interval() {
+        let interval = Duration::from_secs(90);
+        assert_eq!(jittered(interval, Duration::ZERO), interval);
+        for _ in 0..100 {
+            let delay = jittered(interval, Duration::from_secs(10));
+            assert!(delay >= interval && delay <= Duration::from_secs(100));
+        }
+    }
+}
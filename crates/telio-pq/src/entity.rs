@@ -1,12 +1,13 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 use parking_lot::Mutex;
+use tokio::sync::watch;
 
 use telio_model::features::FeaturePostQuantumVPN;
 use telio_task::io::chan;
-use telio_utils::telio_log_debug;
+use telio_utils::{telio_log_debug, Instant};
 
 // This constant is based on section 6.1 of the wireguard whitepaper
 // It is the amount of time that has to happen since the last handshake before a connection is abandoned
@@ -19,7 +20,7 @@ struct Peer {
     /// This is a key rotation task guard, its `Drop` implementation aborts the task
     _rotation_task: super::conn::ConnKeyRotation,
     keys: Option<super::Keys>,
-    last_key_fetch_ts: Option<SystemTime>,
+    last_key_fetch_ts: Option<Instant>,
 }
 
 pub struct Entity {
@@ -27,6 +28,9 @@ pub struct Entity {
     sockets: Arc<telio_sockets::SocketPool>,
     chan: chan::Tx<super::Event>,
     peer: Mutex<Option<Peer>>,
+    negotiated_versions: super::conn::NegotiatedVersions,
+    /// Key exchanges wait while it is set
+    paused: watch::Sender<bool>,
 }
 
 impl crate::PostQuantum for Entity {
@@ -50,16 +54,24 @@ impl Entity {
             sockets,
             chan,
             peer: Mutex::new(None),
+            negotiated_versions: Default::default(),
+            paused: watch::channel(false).0,
         }
     }
 
+    /// Hold the key fetches and rekeys, e.g. while the device is paused. The overdue ones are
+    /// made right after resuming.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
     pub fn on_event(&self, event: super::Event) {
         if let Some(peer) = self.peer.lock().as_mut() {
             match event {
                 super::Event::KeyFetch(addr, keys) => {
                     if peer.addr == addr {
                         peer.keys = Some(keys);
-                        peer.last_key_fetch_ts = Some(SystemTime::now());
+                        peer.last_key_fetch_ts = Some(Instant::now());
                     }
                 }
                 super::Event::Rekey(super::Keys {
@@ -73,7 +85,7 @@ impl Entity {
                             // otherwise we're connecting to different node already
                             keys.pq_shared = pq_shared;
                             // Keys are also fetched during rekey
-                            peer.last_key_fetch_ts = Some(SystemTime::now());
+                            peer.last_key_fetch_ts = Some(Instant::now());
                         } else {
                             telio_log_debug!(
                                 "PQ secret key does not match, ignoring shared secret rotation"
@@ -114,6 +126,23 @@ impl Entity {
         self.start_impl(addr, wg_secret, peer).await;
     }
 
+    /// Start with the `keys` of the previous session with the `peer`, e.g. one restored after a
+    /// restart, so that the tunnel is usable before the new keys are fetched
+    pub async fn resume(
+        &self,
+        addr: SocketAddr,
+        wg_secret: telio_crypto::SecretKey,
+        peer: telio_crypto::PublicKey,
+        keys: super::Keys,
+    ) {
+        self.stop().await;
+        self.start_impl(addr, wg_secret, peer).await;
+        if let Some(peer) = self.peer.lock().as_mut() {
+            peer.keys = Some(keys);
+            peer.last_key_fetch_ts = Some(Instant::now());
+        }
+    }
+
     // Postquantum has a quirk that can cause VPN connections to fail due to the client having a preshared key when the server doesn't.
     // This can happen if there was a handshake established with a preshared key, then the connection is broken for more than 180s
     // (the time after which wireguard will abandon an inactive connection), and then the client tries to do a new handshake with the same
@@ -122,23 +151,15 @@ impl Entity {
     //
     // To solve this we can restart the postquantum entity if the time since the last handshake exceeds 180s, which is what this function does.
     // The postquantum peer stores a timestamp of the last handshake, which is used in this function to check whether we should restart.
-    // We store the timestamp of the last handshake as a SystemTime instead of an Instant because Instant does not guarantee
-    // whether time spent while the system is asleep is counted or not.
-    // Since we use SystemTime, it is possible that changes to the system clock by the user may affect our measurements.
-    // Because of this, we must also restart when ts.elapsed() returns an error, which happens when the elapsed() call
-    // would return a negative value. The error returned contains the absolute value of the time difference.
-    // We ignore values smaller than 5 seconds (chosen arbitrarily) because SystemTime does not guarantee monotonicity,
-    // so it is possible that ts.elapsed() returns a small negative value when called shortly after ts was saved.
+    // The timestamp is a suspend-aware Instant, so time spent while the system is asleep is counted, while changes to
+    // the system clock by the user do not affect the measurement.
     pub async fn maybe_restart(&self) {
         let peer = {
             let mut peer = self.peer.lock();
             let should_restart = peer
                 .as_ref()
                 .and_then(|peer| peer.last_key_fetch_ts)
-                .is_some_and(|ts| match ts.elapsed() {
-                    Ok(dur) => dur > REJECT_AFTER_TIME,
-                    Err(err) => err.duration() > Duration::from_secs(5),
-                });
+                .is_some_and(|ts| ts.elapsed() > REJECT_AFTER_TIME);
 
             if should_restart {
                 peer.take()
@@ -170,6 +191,8 @@ impl Entity {
                 wg_secret,
                 peer,
                 &self.features,
+                self.negotiated_versions.clone(),
+                self.paused.subscribe(),
             ),
             keys: None,
             last_key_fetch_ts: None,
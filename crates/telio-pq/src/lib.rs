@@ -30,6 +30,8 @@ pub enum Event {
     Disconnected(telio_crypto::PublicKey),
     KeyFetch(SocketAddr, Keys),
     Rekey(Keys),
+    /// Progress of the periodic rekey, to be reported to the integrators
+    RekeyProgress(telio_model::event::PqRekey),
 }
 
 /// The PQ module error type
@@ -44,11 +46,34 @@ pub enum Error {
     /// Server errors (version 2)
     #[error("Server error v2: {0:?}")]
     ServerV2(PqProtoV2Status),
+    /// Server errors (version 3, with the version 2 status codes)
+    #[error("Server error v3: {0:?}")]
+    ServerV3(PqProtoV2Status),
+    /// The server responded with another protocol version than the requested one
+    #[error("Server responded with version {responded} to version {requested}")]
+    UnexpectedVersion { requested: u32, responded: u32 },
     /// Generic unrecoverable error
     #[error("Generic: {0}")]
     Generic(String),
 }
 
+impl Error {
+    /// Whether the server does not support the requested protocol version, either telling so
+    /// or responding with a lower version
+    pub fn is_version_rejection(&self) -> bool {
+        match self {
+            Error::ServerV1(PqProtoV1Status::CouldNotReadVersion)
+            | Error::ServerV2(PqProtoV2Status::CouldNotReadVersion)
+            | Error::ServerV3(PqProtoV2Status::CouldNotReadVersion) => true,
+            Error::UnexpectedVersion {
+                requested,
+                responded,
+            } => responded < requested,
+            _ => false,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl From<String> for Error {
@@ -66,5 +91,7 @@ impl From<&str> for Error {
 /// Public functions exposed for fuzzing framework
 #[cfg(feature = "fuzzing")]
 pub mod fuzz {
-    pub use super::proto::{parse_get_response, parse_response_payload};
+    pub use super::proto::{
+        parse_get_response, parse_hybrid_response_payload, parse_response_payload,
+    };
 }
@@ -17,6 +17,7 @@ use pnet_packet::{
     Packet,
 };
 use pqcrypto_kyber::{ffi::PQCLEAN_KYBER768_CLEAN_CRYPTO_SECRETKEYBYTES, kyber768};
+use pqcrypto_mlkem::{ffi::PQCLEAN_MLKEM768_CLEAN_CRYPTO_SECRETKEYBYTES, mlkem768};
 use pqcrypto_traits::kem::{Ciphertext, PublicKey, SecretKey, SharedSecret};
 use rand::{prelude::Distribution, rngs::OsRng};
 use telio_utils::{telio_log_debug, telio_log_error, telio_log_warn, Hidden};
@@ -27,8 +28,15 @@ const LOCAL_PORT_RANGE: RangeInclusive<u16> = 49152..=u16::MAX; // dynamic port
 const LOCAL_IP: Ipv4Addr = Ipv4Addr::new(10, 5, 0, 2);
 const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 5, 0, 1);
 const CIPHERTEXT_LEN: u32 = kyber768::ciphertext_bytes() as _;
+const MLKEM_CIPHERTEXT_LEN: u32 = mlkem768::ciphertext_bytes() as _;
+const X25519_KEY_LEN: u32 = 32;
 const REKEY_METHOD_ID: u32 = 1;
 
+/// Protocol version of the hybrid X25519 + ML-KEM-768 exchange
+pub const HYBRID_VERSION: u32 = 3;
+/// Context of the key derivation combining the hybrid shared secrets
+const HYBRID_KDF_CONTEXT: &str = "telio-pq hybrid x25519 ml-kem-768";
+
 const IPV4_HEADER_LEN: usize = 20;
 const UDP_HEADER_LEN: usize = 8;
 
@@ -96,9 +104,25 @@ struct TunnelSock {
     sock: telio_sockets::External<UdpSocket>,
 }
 
+/// Client KEM secret, kept for the rekeys
+pub enum KemSecret {
+    /// Kyber-768, used by versions 1 and 2
+    Kyber(Hidden<[u8; PQCLEAN_KYBER768_CLEAN_CRYPTO_SECRETKEYBYTES]>),
+    /// ML-KEM-768, combined with X25519 by version 3
+    MlKem(Hidden<[u8; PQCLEAN_MLKEM768_CLEAN_CRYPTO_SECRETKEYBYTES]>),
+}
+
 pub struct KeySet {
     pub wg_keys: super::Keys,
-    pub pq_secret: Hidden<[u8; PQCLEAN_KYBER768_CLEAN_CRYPTO_SECRETKEYBYTES]>,
+    pub pq_secret: KemSecret,
+}
+
+/// Server part of the hybrid exchange
+pub struct HybridResponse {
+    /// Ephemeral X25519 public key of the server
+    pub server_ephemeral: [u8; 32],
+    /// ML-KEM-768 ciphertext
+    pub ciphertext: mlkem768::Ciphertext,
 }
 
 /// Get PQ keys from the VPN server
@@ -110,7 +134,10 @@ pub struct KeySet {
 /// 4) S -> C (in tunnel) : Server responds with UDP packet containing the ciphertext
 ///
 /// The shared key is established which should be used as,
-/// a WG preshared key for subsequent connection
+/// a WG preshared key for subsequent connection.
+///
+/// With [HYBRID_VERSION] the client sends an ephemeral X25519 key along with the ML-KEM key, and
+/// the preshared key combines both shared secrets, so it stays secure as long as either is.
 pub async fn fetch_keys(
     sock_pool: &telio_sockets::SocketPool,
     endpoint: impl ToSocketAddrs,
@@ -127,8 +154,10 @@ pub async fn fetch_keys(
 
     // Generate keys
     let wg_secret = telio_crypto::SecretKey::gen_with(&mut rng);
-    let (pq_public, pq_secret) = kyber768::keypair();
+    let (pq_public, pq_secret) = kem_keypair(pq_version)?;
     let wg_public = wg_secret.public();
+    let ephemeral =
+        (pq_version == HYBRID_VERSION).then(|| telio_crypto::SecretKey::gen_with(&mut rng));
 
     let local_port = random_port(&mut rng);
 
@@ -137,6 +166,10 @@ pub async fn fetch_keys(
         peers_pubkey,
         &wg_secret,
         &wg_public,
+        ephemeral
+            .as_ref()
+            .map(|ephemeral| ephemeral.public())
+            .as_ref(),
         &pq_public,
         local_port,
         pq_version,
@@ -157,7 +190,7 @@ pub async fn fetch_keys(
     let mut msgbuf = [0u8; 2048]; // 2 KiB buffer should suffice
 
     // Receive response
-    let ciphertext = loop {
+    let pq_shared = loop {
         let len = sock.recv(&mut recvbuf).await?;
         #[allow(clippy::indexing_slicing)]
         let pkg = &recvbuf[..len];
@@ -169,8 +202,12 @@ pub async fn fetch_keys(
                 return Err(format!("Failed to decapsulate PQ keys message: {err:?}").into())
             }
             noise::TunnResult::WriteToTunnel(buf, _) => {
-                match parse_get_response(buf, local_port, pq_version) {
-                    Ok(cipehrtext) => break cipehrtext,
+                match get_response_payload(buf, local_port).and_then(|payload| {
+                    decapsulate(&payload, pq_version, &pq_secret, ephemeral.as_ref())
+                }) {
+                    Ok(pq_shared) => break pq_shared,
+                    // Retrying will not help, the caller may fall back to a lower version
+                    Err(err) if err.is_version_rejection() => return Err(err),
                     Err(err) => telio_log_warn!("Invalid PQ keys response: {err:?}"),
                 }
             }
@@ -178,31 +215,107 @@ pub async fn fetch_keys(
         };
     };
 
-    // Extract the shared secret
-    let pq_shared = kyber768::decapsulate(&ciphertext, &pq_secret);
-    let pq_shared = telio_crypto::PresharedKey::new(
-        pq_shared
-            .as_bytes()
-            .try_into()
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
-    );
-
     Ok(KeySet {
         wg_keys: super::Keys {
             pq_shared,
             wg_secret,
         },
-        pq_secret: Hidden(
-            pq_secret
-                .as_bytes()
-                .try_into()
-                .map_err(|e: TryFromSliceError| super::Error::Generic(e.to_string()))?,
-        ),
+        pq_secret,
     })
 }
 
-/// Authentication parameters required for PQ protocol version 2
-pub struct RekeyV2Auth {
+/// Generate the KEM keypair of the `pq_version`, returns the serialized public key
+fn kem_keypair(pq_version: u32) -> super::Result<(Vec<u8>, KemSecret)> {
+    match pq_version {
+        1 | 2 => {
+            let (public, secret) = kyber768::keypair();
+            let secret = Hidden(
+                secret
+                    .as_bytes()
+                    .try_into()
+                    .map_err(|e: TryFromSliceError| super::Error::Generic(e.to_string()))?,
+            );
+            Ok((public.as_bytes().to_vec(), KemSecret::Kyber(secret)))
+        }
+        HYBRID_VERSION => {
+            let (public, secret) = mlkem768::keypair();
+            let secret = Hidden(
+                secret
+                    .as_bytes()
+                    .try_into()
+                    .map_err(|e: TryFromSliceError| super::Error::Generic(e.to_string()))?,
+            );
+            Ok((public.as_bytes().to_vec(), KemSecret::MlKem(secret)))
+        }
+        _ => Err(super::Error::Generic(format!(
+            "Unsupported PQ version: {pq_version}",
+        ))),
+    }
+}
+
+/// Extract the preshared key from the response `payload` of the server
+fn decapsulate(
+    payload: &[u8],
+    pq_version: u32,
+    pq_secret: &KemSecret,
+    ephemeral: Option<&telio_crypto::SecretKey>,
+) -> super::Result<telio_crypto::PresharedKey> {
+    match (pq_secret, ephemeral) {
+        (KemSecret::Kyber(secret), _) => {
+            let ciphertext = parse_response_payload(payload, pq_version)?;
+            let secret = kyber768::SecretKey::from_bytes(&**secret)
+                .map_err(|e| super::Error::Generic(e.to_string()))?;
+            let pq_shared = kyber768::decapsulate(&ciphertext, &secret);
+            Ok(telio_crypto::PresharedKey::new(
+                pq_shared
+                    .as_bytes()
+                    .try_into()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            ))
+        }
+        (KemSecret::MlKem(secret), Some(ephemeral)) => {
+            let response = parse_hybrid_response_payload(payload)?;
+            let secret = mlkem768::SecretKey::from_bytes(&**secret)
+                .map_err(|e| super::Error::Generic(e.to_string()))?;
+            let pq_shared = mlkem768::decapsulate(&response.ciphertext, &secret);
+            combine_hybrid_secrets(ephemeral, &response, pq_shared.as_bytes())
+        }
+        (KemSecret::MlKem(_), None) => {
+            Err("Ephemeral X25519 key required for the hybrid exchange".into())
+        }
+    }
+}
+
+/// Combine the shared secrets of the hybrid exchange
+/// key = derive_key(context: HYBRID_KDF_CONTEXT, key_material: ml_kem_shared || x25519_shared || ciphertext || x25519_public_key_server || x25519_public_key_client)
+///
+/// Binding the transcript makes the key depend on both exchanges even if one of them is broken.
+fn combine_hybrid_secrets(
+    ephemeral: &telio_crypto::SecretKey,
+    response: &HybridResponse,
+    pq_shared: &[u8],
+) -> super::Result<telio_crypto::PresharedKey> {
+    let x25519_shared = x25519_dalek::x25519(*ephemeral.as_bytes(), response.server_ephemeral);
+    if x25519_shared == [0u8; 32] {
+        return Err(super::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Server responded with a low order X25519 key",
+        )));
+    }
+
+    let mut hasher = blake3::Hasher::new_derive_key(HYBRID_KDF_CONTEXT);
+    hasher.update(pq_shared);
+    hasher.update(&x25519_shared);
+    hasher.update(response.ciphertext.as_bytes());
+    hasher.update(&response.server_ephemeral);
+    hasher.update(&ephemeral.public().0);
+    Ok(telio_crypto::PresharedKey::new(
+        *hasher.finalize().as_bytes(),
+    ))
+}
+
+/// Authentication parameters required for PQ protocol versions 2 and up
+pub struct RekeyAuth {
     pub pre_shared_key: telio_crypto::PresharedKey,
     pub wg_client_public: telio_crypto::PublicKey,
     pub wg_server_public: telio_crypto::PublicKey,
@@ -211,28 +324,41 @@ pub struct RekeyV2Auth {
 /// Establsh new PQ preshared key with the VPN server
 pub async fn rekey(
     sock_pool: &telio_sockets::SocketPool,
-    pq_secret: &Hidden<[u8; PQCLEAN_KYBER768_CLEAN_CRYPTO_SECRETKEYBYTES]>,
+    pq_secret: &KemSecret,
     pq_version: u32,
-    v2_auth: Option<RekeyV2Auth>,
+    auth: Option<RekeyAuth>,
 ) -> super::Result<telio_crypto::PresharedKey> {
     telio_log_debug!("Rekeying with version {}", pq_version);
     let mut pkgbuf = Vec::with_capacity(1024 * 4); // 4 KiB
 
+    // Every hybrid rekey is a new X25519 exchange, for forward secrecy
+    let ephemeral =
+        (pq_version == HYBRID_VERSION).then(|| telio_crypto::SecretKey::gen_with(&mut OsRng));
+
     match pq_version {
         1 => push_rekey_method_udp_payload_v1(&mut pkgbuf),
-        2 => {
-            let auth = v2_auth.ok_or_else(|| {
-                super::Error::Generic(
-                    "Authentication parameters required for version 2".to_string(),
-                )
+        2 | HYBRID_VERSION => {
+            let auth = auth.ok_or_else(|| {
+                super::Error::Generic(format!(
+                    "Authentication parameters required for version {pq_version}"
+                ))
             })?;
 
-            push_rekey_method_udp_payload_v2(
-                &mut pkgbuf,
-                &auth.pre_shared_key,
-                &auth.wg_client_public,
-                &auth.wg_server_public,
-            );
+            match &ephemeral {
+                Some(ephemeral) => push_rekey_method_udp_payload_v3(
+                    &mut pkgbuf,
+                    &ephemeral.public(),
+                    &auth.pre_shared_key,
+                    &auth.wg_client_public,
+                    &auth.wg_server_public,
+                ),
+                None => push_rekey_method_udp_payload_v2(
+                    &mut pkgbuf,
+                    &auth.pre_shared_key,
+                    &auth.wg_client_public,
+                    &auth.wg_server_public,
+                ),
+            }
         }
         _ => {
             return Err(super::Error::Generic(format!(
@@ -257,22 +383,7 @@ pub async fn rekey(
 
     telio_log_debug!("Received packet of size {}", pkg.len());
 
-    #[allow(clippy::indexing_slicing)]
-    let ciphertext = parse_response_payload(pkg, pq_version)?;
-
-    // Extract the shared secret
-    let pq_shared = kyber768::decapsulate(
-        &ciphertext,
-        &SecretKey::from_bytes(&**pq_secret).map_err(|e| super::Error::Generic(e.to_string()))?,
-    );
-    let pq_shared = telio_crypto::PresharedKey::new(
-        pq_shared
-            .as_bytes()
-            .try_into()
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
-    );
-
-    Ok(pq_shared)
+    decapsulate(pkg, pq_version, pq_secret, ephemeral.as_ref())
 }
 
 async fn handshake(
@@ -335,6 +446,11 @@ pub fn parse_get_response(
     local_port: u16,
     expected_version: u32,
 ) -> super::Result<kyber768::Ciphertext> {
+    parse_response_payload(&get_response_payload(pkgbuf, local_port)?, expected_version)
+}
+
+/// Validate the IP and UDP headers of the GET response and return its payload
+fn get_response_payload(pkgbuf: &[u8], local_port: u16) -> super::Result<Vec<u8>> {
     let ip = Ipv4Packet::new(pkgbuf).ok_or(io::Error::new(
         io::ErrorKind::InvalidData,
         "Invalid PQ keys IP packet received",
@@ -353,7 +469,7 @@ pub fn parse_get_response(
         |reason| io::Error::new(io::ErrorKind::InvalidData, format!("PQ keys UDP: {reason}")),
     )?;
 
-    parse_response_payload(udp.payload(), expected_version)
+    Ok(udp.payload().to_vec())
 }
 
 fn validate_get_response_ip(ip: &Ipv4Packet) -> Result<(), String> {
@@ -490,11 +606,94 @@ pub fn parse_response_payload(
     Ok(ct)
 }
 
+/// The hybrid (v3) response looks as follows:
+///
+/// ---------------------------------
+///  version           , u32le, = 3
+/// ---------------------------------
+///  X25519 pubkey len , u32le, = 32
+/// ---------------------------------
+///  X25519 pubkey     , [u8]
+/// ---------------------------------
+///  Ciphertext len    , u32le, = 1088
+/// ---------------------------------
+///  Ciphertext bytes  , [u8]
+/// ---------------------------------
+///
+/// A response of any other version is rejected, so that the exchange cannot be downgraded. The
+/// servers which do not support the hybrid exchange respond with a lower version, the caller may
+/// then start another exchange with it.
+pub fn parse_hybrid_response_payload(payload: &[u8]) -> super::Result<HybridResponse> {
+    let mut data = io::Cursor::new(payload);
+
+    let mut version = [0u8; 4];
+    data.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != HYBRID_VERSION {
+        return Err(super::Error::UnexpectedVersion {
+            requested: HYBRID_VERSION,
+            responded: version,
+        });
+    }
+
+    // v3 reports error as a single(5th) byte with no further payload, using the v2 codes.
+    #[allow(clippy::comparison_chain)]
+    if payload.len() < 5 {
+        return Err(super::Error::ServerV3(PqProtoV2Status::NoData));
+    } else if payload.len() == 5 {
+        #[allow(clippy::indexing_slicing)]
+        let error_code = payload[4];
+        let status = PqProtoV2Status::from(error_code);
+        telio_log_error!(
+            "PQ upgrader v3 responded with: {}: {:?}",
+            error_code,
+            status
+        );
+        return Err(super::Error::ServerV3(status));
+    }
+
+    let mut key_len = [0u8; 4];
+    data.read_exact(&mut key_len)?;
+    if u32::from_le_bytes(key_len) != X25519_KEY_LEN {
+        return Err(super::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Server responded with invalid X25519 key length",
+        )));
+    }
+    let mut server_ephemeral = [0u8; 32];
+    data.read_exact(&mut server_ephemeral)?;
+
+    let mut ciphertext_len = [0u8; 4];
+    data.read_exact(&mut ciphertext_len)?;
+    if u32::from_le_bytes(ciphertext_len) != MLKEM_CIPHERTEXT_LEN {
+        return Err(super::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Server responded with invalid PQ handshake ciphertext length",
+        )));
+    }
+
+    let mut cipherbuf = [0; MLKEM_CIPHERTEXT_LEN as usize];
+    data.read_exact(&mut cipherbuf)?;
+
+    let ciphertext = mlkem768::Ciphertext::from_bytes(&cipherbuf).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid PQ ciphertext received: {err:?}"),
+        )
+    })?;
+
+    Ok(HybridResponse {
+        server_ephemeral,
+        ciphertext,
+    })
+}
+
 fn create_get_packet(
     wg_server_public: &telio_crypto::PublicKey,
     wg_client_secret: &telio_crypto::SecretKey,
     wg_client_public: &telio_crypto::PublicKey,
-    pq_public: &kyber768::PublicKey,
+    ephemeral_public: Option<&telio_crypto::PublicKey>,
+    pq_public: &[u8],
     local_port: u16,
     pq_version: u32,
 ) -> Vec<u8> {
@@ -504,6 +703,7 @@ fn create_get_packet(
     push_get_method_udp_payload_without_auth_tag(
         &mut pkgbuf,
         wg_client_public,
+        ephemeral_public,
         pq_public,
         pq_version,
     );
@@ -542,18 +742,24 @@ fn create_get_packet(
 /// ---------------------------------
 ///  WG pubkey bytes   , [u8]
 /// ---------------------------------
-///  Kyber pubkey len  , u32le, = 1184
+///  X25519 pubkey len , u32le, = 32   (v3 only)
+/// ---------------------------------
+///  X25519 pubkey     , [u8]          (v3 only)
 /// ---------------------------------
-///  Kyber pubkey bytes, [u8]
+///  KEM pubkey len    , u32le, = 1184
+/// ---------------------------------
+///  KEM pubkey bytes  , [u8]
 /// ---------------------------------
 ///  Authentication tag, [u8; 32]
 /// ---------------------------------
 ///
+/// The KEM is Kyber-768 up to v2 and ML-KEM-768 in v3.
 /// But this function does not include `Authentication tag`
 fn push_get_method_udp_payload_without_auth_tag(
     pkgbuf: &mut Vec<u8>,
     wg_public: &telio_crypto::PublicKey,
-    pq_public: &kyber768::PublicKey,
+    ephemeral_public: Option<&telio_crypto::PublicKey>,
+    pq_public: &[u8],
     pq_version: u32,
 ) {
     let method = 0u32; // get
@@ -565,8 +771,12 @@ fn push_get_method_udp_payload_without_auth_tag(
     pkgbuf.extend_from_slice(&timestamp.to_le_bytes());
     pkgbuf.extend_from_slice(&(wg_public.len() as u32).to_le_bytes());
     pkgbuf.extend_from_slice(wg_public);
-    pkgbuf.extend_from_slice(&(pq_public.as_bytes().len() as u32).to_le_bytes());
-    pkgbuf.extend_from_slice(pq_public.as_bytes());
+    if let Some(ephemeral_public) = ephemeral_public {
+        pkgbuf.extend_from_slice(&(ephemeral_public.len() as u32).to_le_bytes());
+        pkgbuf.extend_from_slice(ephemeral_public);
+    }
+    pkgbuf.extend_from_slice(&(pq_public.len() as u32).to_le_bytes());
+    pkgbuf.extend_from_slice(pq_public);
 }
 
 /// Derive authentication key for PQ protocol version 2
@@ -627,6 +837,66 @@ fn push_rekey_method_udp_payload_v2_with_timestamp(
     pkgbuf.extend_from_slice(&REKEY_METHOD_ID.to_le_bytes());
     pkgbuf.extend_from_slice(&timestamp.to_le_bytes());
 
+    push_rekey_auth_tag(pkgbuf, pre_shared_key, wg_client_public, wg_server_public);
+}
+
+/// The REKEY payload (v3) looks as follows:
+///
+/// --------------------------------
+///  version           , u32le, = 3
+/// --------------------------------
+///  method            , u32le, = 1
+/// --------------------------------
+///  timestamp         , u64le
+/// --------------------------------
+///  X25519 pubkey len , u32le, = 32
+/// --------------------------------
+///  X25519 pubkey     , [u8]
+/// --------------------------------
+///  auth_tag          , [u8; 32]
+/// ---------------------------------
+fn push_rekey_method_udp_payload_v3_with_timestamp(
+    pkgbuf: &mut Vec<u8>,
+    ephemeral_public: &telio_crypto::PublicKey,
+    pre_shared_key: &telio_crypto::PresharedKey,
+    wg_client_public: &telio_crypto::PublicKey,
+    wg_server_public: &telio_crypto::PublicKey,
+    timestamp: u64,
+) {
+    // UDP packet payload
+    pkgbuf.extend_from_slice(&HYBRID_VERSION.to_le_bytes());
+    pkgbuf.extend_from_slice(&REKEY_METHOD_ID.to_le_bytes());
+    pkgbuf.extend_from_slice(&timestamp.to_le_bytes());
+    pkgbuf.extend_from_slice(&(ephemeral_public.len() as u32).to_le_bytes());
+    pkgbuf.extend_from_slice(ephemeral_public);
+
+    push_rekey_auth_tag(pkgbuf, pre_shared_key, wg_client_public, wg_server_public);
+}
+
+fn push_rekey_method_udp_payload_v3(
+    pkgbuf: &mut Vec<u8>,
+    ephemeral_public: &telio_crypto::PublicKey,
+    pre_shared_key: &telio_crypto::PresharedKey,
+    wg_client_public: &telio_crypto::PublicKey,
+    wg_server_public: &telio_crypto::PublicKey,
+) {
+    push_rekey_method_udp_payload_v3_with_timestamp(
+        pkgbuf,
+        ephemeral_public,
+        pre_shared_key,
+        wg_client_public,
+        wg_server_public,
+        timestamp(),
+    );
+}
+
+/// Authenticate the REKEY payload in `pkgbuf` with the current preshared key
+fn push_rekey_auth_tag(
+    pkgbuf: &mut Vec<u8>,
+    pre_shared_key: &telio_crypto::PresharedKey,
+    wg_client_public: &telio_crypto::PublicKey,
+    wg_server_public: &telio_crypto::PublicKey,
+) {
     // Derive authentication key
     let auth_key = derive_pq_auth_key(pre_shared_key, wg_client_public, wg_server_public);
 
@@ -958,6 +1228,130 @@ mod tests {
         ));
     }
 
+    fn hybrid_response(server_ephemeral: &[u8; 32], ciphertext: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&3u32.to_le_bytes());
+        payload.extend_from_slice(&32u32.to_le_bytes());
+        payload.extend_from_slice(server_ephemeral);
+        payload.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        payload.extend_from_slice(ciphertext);
+        payload
+    }
+
+    #[test]
+    fn hybrid_exchange_agrees_on_key() {
+        use pqcrypto_mlkem::mlkem768;
+        use pqcrypto_traits::kem::PublicKey as _;
+
+        let client_ephemeral = telio_crypto::SecretKey::gen();
+        let (pq_public, pq_secret) = super::kem_keypair(super::HYBRID_VERSION).unwrap();
+        assert!(matches!(pq_secret, super::KemSecret::MlKem(_)));
+
+        // Server side of the exchange
+        let server_ephemeral = telio_crypto::SecretKey::gen();
+        let pq_public = mlkem768::PublicKey::from_bytes(&pq_public).unwrap();
+        let (pq_shared, ciphertext) = mlkem768::encapsulate(&pq_public);
+        let x25519_shared =
+            x25519_dalek::x25519(*server_ephemeral.as_bytes(), client_ephemeral.public().0);
+        let mut hasher = blake3::Hasher::new_derive_key(super::HYBRID_KDF_CONTEXT);
+        hasher.update(pq_shared.as_bytes());
+        hasher.update(&x25519_shared);
+        hasher.update(ciphertext.as_bytes());
+        hasher.update(&server_ephemeral.public().0);
+        hasher.update(&client_ephemeral.public().0);
+        let expected = telio_crypto::PresharedKey::new(*hasher.finalize().as_bytes());
+
+        let payload = hybrid_response(&server_ephemeral.public().0, ciphertext.as_bytes());
+        let key = super::decapsulate(
+            &payload,
+            super::HYBRID_VERSION,
+            &pq_secret,
+            Some(&client_ephemeral),
+        )
+        .unwrap();
+        assert_eq!(key, expected);
+
+        // The ephemeral key is mandatory
+        assert!(super::decapsulate(&payload, super::HYBRID_VERSION, &pq_secret, None).is_err());
+    }
+
+    #[test]
+    fn hybrid_response_rejects_downgrade() {
+        let ciphertext = [0u8; super::MLKEM_CIPHERTEXT_LEN as usize];
+        let mut payload = hybrid_response(&[9u8; 32], &ciphertext);
+        assert!(super::parse_hybrid_response_payload(&payload).is_ok());
+
+        payload[0] = 2;
+        assert!(matches!(
+            super::parse_hybrid_response_payload(&payload),
+            Err(Error::UnexpectedVersion {
+                requested: 3,
+                responded: 2
+            })
+        ));
+
+        // Responses to a v3 request never parse as Kyber ciphertexts
+        payload[0] = 3;
+        assert!(super::parse_response_payload(&payload, 3).is_err());
+    }
+
+    #[test]
+    fn parse_response_error_v3() {
+        assert!(matches!(
+            super::parse_hybrid_response_payload(b"\x03\x00\x00\x00\x05"),
+            Err(Error::ServerV3(PqProtoV2Status::CouldNotReadVersion))
+        ));
+        assert!(matches!(
+            super::parse_hybrid_response_payload(b"\x03\x00\x00\x00\x08"),
+            Err(Error::ServerV3(PqProtoV2Status::AuthenticationFailed))
+        ));
+        assert!(matches!(
+            super::parse_hybrid_response_payload(b"\x03\x00\x00\x00"),
+            Err(Error::ServerV3(PqProtoV2Status::NoData))
+        ));
+        assert!(
+            super::parse_hybrid_response_payload(b"\x03\x00\x00\x00\x05")
+                .unwrap_err()
+                .is_version_rejection()
+        );
+        // Servers of the older versions answer with their own version
+        assert!(
+            super::parse_hybrid_response_payload(b"\x02\x00\x00\x00\x05")
+                .unwrap_err()
+                .is_version_rejection()
+        );
+        assert!(
+            !super::parse_hybrid_response_payload(b"\x04\x00\x00\x00\x05")
+                .unwrap_err()
+                .is_version_rejection()
+        );
+    }
+
+    #[test]
+    fn test_push_rekey_method_udp_payload_v3() {
+        let ephemeral_public = telio_crypto::PublicKey::from(&[4u8; 32]);
+        let pre_shared_key = telio_crypto::PresharedKey::new([1u8; 32]);
+        let wg_client_public = telio_crypto::PublicKey::from(&[2u8; 32]);
+        let wg_server_public = telio_crypto::PublicKey::from(&[3u8; 32]);
+
+        let mut pkgbuf = Vec::new();
+        super::push_rekey_method_udp_payload_v3_with_timestamp(
+            &mut pkgbuf,
+            &ephemeral_public,
+            &pre_shared_key,
+            &wg_client_public,
+            &wg_server_public,
+            0x11_22_33_44_55_66_77_88,
+        );
+
+        // version + method + timestamp + key len + key + auth_tag
+        assert_eq!(pkgbuf.len(), 4 + 4 + 8 + 4 + 32 + 32);
+        assert_eq!(&pkgbuf[0..4], &3u32.to_le_bytes());
+        assert_eq!(&pkgbuf[4..8], &1u32.to_le_bytes());
+        assert_eq!(&pkgbuf[16..20], &32u32.to_le_bytes());
+        assert_eq!(&pkgbuf[20..52], &[4u8; 32]);
+    }
+
     use proptest::prelude::*;
 
     fn extract_auth_tag_from_payload(payload: &[u8]) -> [u8; 32] {
@@ -25,6 +25,8 @@ fn main() -> Result<()> {
             "protos/pinger.proto",
             "protos/upgrade.proto",
             "protos/derppoll.proto",
+            "protos/capabilities.proto",
+            "protos/goodbye.proto",
         ])
         .out_dir(out_dir)
         .run()?;
@@ -367,12 +367,11 @@ async fn create_external_channel(
                 }
             };
 
-            let socket = pool.new_external_tcp_v4(None)?;
             let domain = tokio_rustls::rustls::pki_types::ServerName::try_from(host.to_owned())
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
             if let Some(resolved) = ((host, port).to_socket_addrs()?).next() {
-                let tcp_stream = socket.connect(resolved).await?;
+                let tcp_stream = pool.connect_external_tcp_v4(resolved, None).await?;
                 let tls_connector = make_tls_connector(allow_only_mlkem, &root_certificate)?;
                 let tls_stream = tls_connector.connect(domain, tcp_stream).await?;
                 return Ok::<_, std::io::Error>(TokioIo::new(tls_stream));
@@ -14,6 +14,8 @@ pub(crate) mod messages {
 
 mod packet;
 
+mod sequence;
+
 mod ens;
 
 pub use ens::{
@@ -23,6 +25,7 @@ pub use ens::{
 
 pub use codec::{Codec, Error as CodecError, Result as CodecResult};
 pub use packet::*;
+pub use sequence::{ControlSequence, ReplayGuard, SequencedNonce, NONCE_LEN};
 
 pub use messages::nurse::heartbeat::NatType as HeartbeatNatType;
 pub use messages::nurse::heartbeat::Status as HeartbeatStatus;
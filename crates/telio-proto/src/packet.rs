@@ -5,8 +5,10 @@ use crate::{Codec, CodecError, CodecResult};
 use telio_crypto::PublicKey;
 
 pub use relayed::{
+    capabilities::{CapabilitiesMsg, NegotiatedCapabilities, PROTOCOL_VERSION},
     data::DataMsg,
     generation::Generation,
+    goodbye::GoodbyeMsg,
     natter::CallMeMaybeMsg,
     natter::CallMeMaybeMsgDeprecated,
     nurse::HeartbeatMessage,
@@ -80,6 +82,12 @@ pub enum PacketTypeRelayed {
 
     /// Message with a reply for the Upgrade message
     UpgradeDecision = 0x0a,
+    /// Protocol version and optional features supported by the sender
+    Capabilities = 0x0b,
+    /// Encrypted package which is not Data type, with a sequenced nonce
+    SequencedEncrypted = 0x0c,
+    /// Sender is going away
+    Goodbye = 0x0d,
 
     /// Reserved for future, in case we use all byte values for types.
     Reserved = 0xfe,
@@ -113,6 +121,10 @@ pub enum PacketRelayed {
     Upgrade(UpgradeMsg),
     /// Upgrading connection result
     UpgradeDecision(UpgradeDecisionMsg),
+    /// Negotiating the protocol version and optional features
+    Capabilities(CapabilitiesMsg),
+    /// Announcing that the sender is going away
+    Goodbye(GoodbyeMsg),
 }
 
 impl PacketRelayed {
@@ -153,8 +165,12 @@ impl PacketRelayed {
                 }
                 Upgrade => Self::Upgrade(UpgradeMsg::decode(bytes)?),
                 UpgradeDecision => Self::UpgradeDecision(UpgradeDecisionMsg::decode(bytes)?),
+                Capabilities => Self::Capabilities(CapabilitiesMsg::decode(bytes)?),
+                Goodbye => Self::Goodbye(GoodbyeMsg::decode(bytes)?),
                 // At this point a package already should be decrypted if is not Data
-                Reserved | Invalid | Encrypted => return Err(CodecError::DecodeFailed),
+                Reserved | Invalid | Encrypted | SequencedEncrypted => {
+                    return Err(CodecError::DecodeFailed)
+                }
             },
             None,
         ))
@@ -172,6 +188,8 @@ impl Codec<PacketTypeRelayed> for PacketRelayed {
         PacketTypeRelayed::Upgrade,
         PacketTypeRelayed::Ponger,
         PacketTypeRelayed::UpgradeDecision,
+        PacketTypeRelayed::Capabilities,
+        PacketTypeRelayed::Goodbye,
     ];
 
     fn decode(bytes: &[u8]) -> CodecResult<Self>
@@ -195,8 +213,10 @@ impl Codec<PacketTypeRelayed> for PacketRelayed {
             )),
             Upgrade => Ok(Self::Upgrade(UpgradeMsg::decode(bytes)?)),
             UpgradeDecision => Ok(Self::UpgradeDecision(UpgradeDecisionMsg::decode(bytes)?)),
+            Capabilities => Ok(Self::Capabilities(CapabilitiesMsg::decode(bytes)?)),
+            Goodbye => Ok(Self::Goodbye(GoodbyeMsg::decode(bytes)?)),
             // At this point a package already should be decrypted if is not Data
-            Reserved | Invalid | Encrypted => Err(CodecError::DecodeFailed),
+            Reserved | Invalid | Encrypted | SequencedEncrypted => Err(CodecError::DecodeFailed),
         }
     }
     // can be done with enum_dispatch
@@ -210,6 +230,8 @@ impl Codec<PacketTypeRelayed> for PacketRelayed {
             Self::CallMeMaybeDeprecated(msg) => msg.encode(),
             Self::Upgrade(msg) => msg.encode(),
             Self::UpgradeDecision(msg) => msg.encode(),
+            Self::Capabilities(msg) => msg.encode(),
+            Self::Goodbye(msg) => msg.encode(),
         }
     }
 
@@ -224,6 +246,8 @@ impl Codec<PacketTypeRelayed> for PacketRelayed {
             Self::CallMeMaybeDeprecated(msg) => msg.packet_type(),
             Self::Upgrade(msg) => msg.packet_type(),
             Self::UpgradeDecision(msg) => msg.packet_type(),
+            Self::Capabilities(msg) => msg.packet_type(),
+            Self::Goodbye(msg) => msg.packet_type(),
         }
     }
 }
@@ -373,6 +397,18 @@ impl From<UpgradeDecisionMsg> for PacketRelayed {
     }
 }
 
+impl From<CapabilitiesMsg> for PacketRelayed {
+    fn from(other: CapabilitiesMsg) -> Self {
+        Self::Capabilities(other)
+    }
+}
+
+impl From<GoodbyeMsg> for PacketRelayed {
+    fn from(other: GoodbyeMsg) -> Self {
+        Self::Goodbye(other)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -383,6 +419,7 @@ mod test {
         let skip = [
             PacketTypeRelayed::Reserved,
             PacketTypeRelayed::Encrypted,
+            PacketTypeRelayed::SequencedEncrypted,
             PacketTypeRelayed::Invalid,
         ];
         assert_eq!(
@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    messages::capabilities::*, Codec, CodecError, CodecResult, DowncastPacket, PacketRelayed,
+    PacketTypeRelayed, MAX_PACKET_SIZE,
+};
+
+use bytes::BufMut;
+use protobuf::Message;
+use telio_model::mesh::PeerCapability;
+
+/// Version of the Node <-> Node protocol spoken by this node.
+///
+/// Bumped on changes which cannot be expressed as an optional capability. Peers which never
+/// exchanged their capabilities are considered to speak version 0.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Packet announcing the protocol version and the optional features supported by the sender
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CapabilitiesMsg {
+    /// Protocol version of the sender
+    pub version: u32,
+    /// Optional features supported by the sender
+    pub capabilities: BTreeSet<PeerCapability>,
+    /// Whether the receiver should answer with its own capabilities
+    pub reply_requested: bool,
+}
+
+/// Outcome of the capability exchange with a peer
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct NegotiatedCapabilities {
+    /// Highest protocol version spoken by both sides
+    pub version: u32,
+    /// Optional features supported by both sides
+    pub capabilities: BTreeSet<PeerCapability>,
+}
+
+impl CapabilitiesMsg {
+    /// Capabilities of this node supporting the `capabilities`
+    pub fn local(capabilities: impl IntoIterator<Item = PeerCapability>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities.into_iter().collect(),
+            reply_requested: false,
+        }
+    }
+
+    /// What can be used with the peer which announced the `remote` capabilities
+    pub fn negotiate(&self, remote: &Self) -> NegotiatedCapabilities {
+        NegotiatedCapabilities {
+            version: self.version.min(remote.version),
+            capabilities: self
+                .capabilities
+                .intersection(&remote.capabilities)
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+impl Codec<PacketTypeRelayed> for CapabilitiesMsg {
+    const TYPES: &'static [PacketTypeRelayed] = &[PacketTypeRelayed::Capabilities];
+
+    fn decode(bytes: &[u8]) -> CodecResult<Self>
+    where
+        Self: Sized,
+    {
+        let Some((first, rest)) = bytes.split_first() else {
+            return Err(CodecError::InvalidLength);
+        };
+        match PacketTypeRelayed::from(*first) {
+            PacketTypeRelayed::Capabilities => {
+                let proto_capabilities =
+                    Capabilities::parse_from_bytes(rest).map_err(|_| CodecError::DecodeFailed)?;
+
+                Ok(Self {
+                    version: proto_capabilities.version,
+                    // Capabilities added by the newer peers are not known here
+                    capabilities: proto_capabilities
+                        .capabilities
+                        .iter()
+                        .filter_map(|capability| capability.parse().ok())
+                        .collect(),
+                    reply_requested: proto_capabilities.reply_requested,
+                })
+            }
+            _ => Err(CodecError::DecodeFailed),
+        }
+    }
+
+    fn encode(self) -> CodecResult<Vec<u8>>
+    where
+        Self: Sized,
+    {
+        let mut bytes = Vec::with_capacity(MAX_PACKET_SIZE);
+        let mut msg = Capabilities::new();
+        msg.version = self.version;
+        msg.capabilities = self
+            .capabilities
+            .into_iter()
+            .map(|capability| <&'static str>::from(capability).to_owned())
+            .collect();
+        msg.reply_requested = self.reply_requested;
+
+        bytes.put_u8(PacketTypeRelayed::Capabilities as u8);
+        msg.write_to_vec(&mut bytes)
+            .map_err(|_| CodecError::Encode)?;
+
+        Ok(bytes)
+    }
+
+    fn packet_type(&self) -> PacketTypeRelayed {
+        PacketTypeRelayed::Capabilities
+    }
+}
+
+impl DowncastPacket<PacketRelayed> for CapabilitiesMsg {
+    fn downcast(packet: PacketRelayed) -> Result<Self, PacketRelayed>
+    where
+        Self: Sized,
+    {
+        match packet {
+            PacketRelayed::Capabilities(capabilities) => Ok(capabilities),
+            packet => Err(packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_capabilities_packet() {
+        let msg = CapabilitiesMsg {
+            reply_requested: true,
+            ..CapabilitiesMsg::local([PeerCapability::Ipv6Candidates, PeerCapability::PqHybrid])
+        };
+        let expected_bytes: &[u8] = &[
+            11, 8, 1, 18, 15, 105, 112, 118, 54, 45, 99, 97, 110, 100, 105, 100, 97, 116, 101, 115,
+            18, 9, 112, 113, 45, 104, 121, 98, 114, 105, 100, 24, 1,
+        ];
+
+        let bytes = msg.clone().encode().unwrap();
+        assert_eq!(expected_bytes, bytes);
+        assert_eq!(msg, CapabilitiesMsg::decode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let mut msg = Capabilities::new();
+        msg.version = 7;
+        msg.capabilities = vec!["compressed-cmm".to_owned(), "teleportation".to_owned()];
+        let mut bytes = vec![PacketTypeRelayed::Capabilities as u8];
+        msg.write_to_vec(&mut bytes).unwrap();
+
+        assert_eq!(
+            CapabilitiesMsg::decode(&bytes),
+            Ok(CapabilitiesMsg {
+                version: 7,
+                capabilities: [PeerCapability::CompressedCmm].into(),
+                reply_requested: false,
+            })
+        );
+    }
+
+    #[test]
+    fn fail_to_decode_capabilities_packet_of_wrong_type() {
+        let bytes = &[PacketTypeRelayed::Invalid as u8];
+        assert_eq!(
+            CapabilitiesMsg::decode(bytes),
+            Err(CodecError::DecodeFailed)
+        );
+        assert_eq!(CapabilitiesMsg::decode(&[]), Err(CodecError::InvalidLength));
+    }
+
+    #[test]
+    fn common_capabilities_are_negotiated() {
+        let local = CapabilitiesMsg::local([
+            PeerCapability::Ipv6Candidates,
+            PeerCapability::CompressedCmm,
+        ]);
+        let remote = CapabilitiesMsg {
+            version: 3,
+            capabilities: [PeerCapability::CompressedCmm, PeerCapability::PqHybrid].into(),
+            reply_requested: true,
+        };
+
+        assert_eq!(
+            local.negotiate(&remote),
+            NegotiatedCapabilities {
+                version: PROTOCOL_VERSION,
+                capabilities: [PeerCapability::CompressedCmm].into(),
+            }
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::{
+    messages::goodbye::*, Codec, CodecError, CodecResult, DowncastPacket, PacketRelayed,
+    PacketTypeRelayed, MAX_PACKET_SIZE,
+};
+
+use bytes::BufMut;
+use protobuf::Message;
+
+/// Packet announcing that the sender is going away
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct GoodbyeMsg {
+    /// Time the sender keeps the established connections before going away
+    pub drain_timeout: Duration,
+}
+
+impl Codec<PacketTypeRelayed> for GoodbyeMsg {
+    const TYPES: &'static [PacketTypeRelayed] = &[PacketTypeRelayed::Goodbye];
+
+    fn decode(bytes: &[u8]) -> CodecResult<Self>
+    where
+        Self: Sized,
+    {
+        let Some((first, rest)) = bytes.split_first() else {
+            return Err(CodecError::InvalidLength);
+        };
+        match PacketTypeRelayed::from(*first) {
+            PacketTypeRelayed::Goodbye => {
+                let proto_goodbye =
+                    Goodbye::parse_from_bytes(rest).map_err(|_| CodecError::DecodeFailed)?;
+
+                Ok(Self {
+                    drain_timeout: Duration::from_millis(proto_goodbye.drain_timeout_ms),
+                })
+            }
+            _ => Err(CodecError::DecodeFailed),
+        }
+    }
+
+    fn encode(self) -> CodecResult<Vec<u8>>
+    where
+        Self: Sized,
+    {
+        let mut bytes = Vec::with_capacity(MAX_PACKET_SIZE);
+        let mut msg = Goodbye::new();
+        msg.drain_timeout_ms = u64::try_from(self.drain_timeout.as_millis()).unwrap_or(u64::MAX);
+
+        bytes.put_u8(PacketTypeRelayed::Goodbye as u8);
+        msg.write_to_vec(&mut bytes)
+            .map_err(|_| CodecError::Encode)?;
+
+        Ok(bytes)
+    }
+
+    fn packet_type(&self) -> PacketTypeRelayed {
+        PacketTypeRelayed::Goodbye
+    }
+}
+
+impl DowncastPacket<PacketRelayed> for GoodbyeMsg {
+    fn downcast(packet: PacketRelayed) -> Result<Self, PacketRelayed>
+    where
+        Self: Sized,
+    {
+        match packet {
+            PacketRelayed::Goodbye(goodbye) => Ok(goodbye),
+            packet => Err(packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_goodbye_packet() {
+        let msg = GoodbyeMsg {
+            drain_timeout: Duration::from_secs(30),
+        };
+        let expected_bytes: &[u8] = &[13, 8, 176, 234, 1];
+
+        let bytes = msg.clone().encode().unwrap();
+        assert_eq!(expected_bytes, bytes);
+        assert_eq!(msg, GoodbyeMsg::decode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn fail_to_decode_goodbye_packet_of_wrong_type() {
+        let bytes = &[PacketTypeRelayed::Invalid as u8];
+        assert_eq!(GoodbyeMsg::decode(bytes), Err(CodecError::DecodeFailed));
+        assert_eq!(GoodbyeMsg::decode(&[]), Err(CodecError::InvalidLength));
+    }
+}
@@ -1,6 +1,8 @@
 //! Implementation for Node <-> Node packets
+pub mod capabilities;
 pub mod data;
 pub mod generation;
+pub mod goodbye;
 pub mod natter;
 pub mod nurse;
 pub mod pinger;
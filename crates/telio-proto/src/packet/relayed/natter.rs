@@ -55,6 +55,21 @@ impl CallMeMaybeMsg {
             .collect()
     }
 
+    /// Attach the endpoints which can be used for TCP hole punching
+    pub fn with_tcp_addrs<T: Iterator<Item = SocketAddr>>(mut self, addrs: T) -> Self {
+        self.0.my_tcp_addresses = addrs.map(|addr| addr.to_string()).collect();
+        self
+    }
+
+    /// Get list of endpoints for TCP hole punching, empty if the sender does not want it
+    pub fn get_tcp_addrs(&self) -> Vec<SocketAddr> {
+        self.0
+            .my_tcp_addresses
+            .iter()
+            .flat_map(|s| s.parse())
+            .collect()
+    }
+
     /// Returns [`CallMeMaybe_Type`] of the message
     pub fn get_message_type(&self) -> CodecResult<CallMeMaybeType> {
         self.0
@@ -133,7 +148,11 @@ impl std::fmt::Display for CallMeMaybeMsg {
             },
             self.0.my_addresses,
             self.0.session,
-        )
+        )?;
+        if !self.0.my_tcp_addresses.is_empty() {
+            write!(f, ", tcp: {:?}", self.0.my_tcp_addresses)?;
+        }
+        Ok(())
     }
 }
 
@@ -294,6 +313,18 @@ mod tests {
         assert_eq!(packet.encode().unwrap(), bytes)
     }
 
+    #[test]
+    fn tcp_addrs_roundtrip() {
+        let addr: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        let packet = CallMeMaybeMsg::new(true, std::iter::empty(), 1);
+        assert!(packet.get_tcp_addrs().is_empty());
+
+        let packet = packet.with_tcp_addrs(std::iter::once(addr));
+        let data = CallMeMaybeMsg::decode(&packet.encode().unwrap()).unwrap();
+        assert_eq!(data.get_tcp_addrs(), vec![addr]);
+        assert!(data.get_addrs().is_empty());
+    }
+
     #[test]
     fn deprecated_decode_packet() {
         let bytes = &[
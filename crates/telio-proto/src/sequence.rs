@@ -0,0 +1,247 @@
+//! Sequence numbers and replay protection of the control messages relayed between the nodes.
+//!
+//! Control messages are encrypted for the receiving node, with a nonce chosen by the sender.
+//! Sequenced messages carry the epoch of the sender and the sequence number of the message in
+//! the nonce, which is authenticated by the encryption. The receiver keeps the latest epoch of
+//! every sender and a [ReplayGuard] of the sequence numbers seen within it, so that a relay
+//! replaying the messages, e.g. old endpoint updates, is noticed. Senders pick a new, later,
+//! epoch each time they start, so the messages of the previous runs are refused too.
+//!
+//! A receiver which knows no epoch of the sender yet, e.g. after it restarted, cannot tell a
+//! replayed epoch from a new one, so it only takes up epochs started within [MAX_EPOCH_AGE_MS].
+//! Senders start a new epoch every [EPOCH_LIFETIME_MS] to stay within it, which leaves room for
+//! clocks of the nodes differing by up to the same time.
+
+/// Length of the nonce of the encrypted control messages
+pub const NONCE_LEN: usize = 24;
+
+/// Number of the latest sequence numbers remembered, older messages are refused
+const WINDOW_LEN: u64 = u64::BITS as u64;
+
+/// Time after which senders start a new epoch, in milliseconds
+pub const EPOCH_LIFETIME_MS: u64 = 60 * 60 * 1000;
+
+/// Age of the oldest epoch a receiver takes up, in milliseconds
+pub const MAX_EPOCH_AGE_MS: u64 = 2 * EPOCH_LIFETIME_MS;
+
+/// Nonce of a sequenced control message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SequencedNonce {
+    /// When the sender started the epoch, in milliseconds since the UNIX epoch
+    pub epoch: u64,
+    /// Random part keeping the nonces of the senders started at the same time apart
+    pub salt: [u8; 8],
+    /// Position of the message within the epoch
+    pub sequence: u64,
+}
+
+impl SequencedNonce {
+    /// Nonce encoded as epoch, salt and sequence number, in the network byte order
+    pub fn to_bytes(self) -> [u8; NONCE_LEN] {
+        let mut bytes = [0; NONCE_LEN];
+        let (epoch, rest) = bytes.split_at_mut(8);
+        let (salt, sequence) = rest.split_at_mut(8);
+        epoch.copy_from_slice(&self.epoch.to_be_bytes());
+        salt.copy_from_slice(&self.salt);
+        sequence.copy_from_slice(&self.sequence.to_be_bytes());
+        bytes
+    }
+
+    /// Nonce encoded in the `bytes`, none when they are not exactly [NONCE_LEN] long
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != NONCE_LEN {
+            return None;
+        }
+        Some(Self {
+            epoch: u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?),
+            salt: bytes.get(8..16)?.try_into().ok()?,
+            sequence: u64::from_be_bytes(bytes.get(16..)?.try_into().ok()?),
+        })
+    }
+}
+
+/// Nonces of the control messages sent by this node
+#[derive(Debug)]
+pub struct ControlSequence {
+    epoch: u64,
+    salt: [u8; 8],
+    next: u64,
+}
+
+impl ControlSequence {
+    /// Sequence of the node started at `epoch`, in milliseconds since the UNIX epoch
+    pub fn new(epoch: u64, salt: [u8; 8]) -> Self {
+        Self {
+            epoch,
+            salt,
+            next: 0,
+        }
+    }
+
+    /// Nonce of the next message sent at `now`, in milliseconds since the UNIX epoch. Nonces are
+    /// never repeated within the epoch, and a new epoch is started once it gets too old to be
+    /// taken up by the receivers.
+    pub fn next_nonce(&mut self, now: u64) -> SequencedNonce {
+        if now >= self.epoch.saturating_add(EPOCH_LIFETIME_MS) {
+            self.epoch = now;
+            self.next = 0;
+        }
+        let nonce = SequencedNonce {
+            epoch: self.epoch,
+            salt: self.salt,
+            sequence: self.next,
+        };
+        self.next = self.next.wrapping_add(1);
+        nonce
+    }
+}
+
+/// Messages of a single sender seen so far
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    /// Epoch and salt of the sender
+    sender: Option<(u64, [u8; 8])>,
+    /// Highest sequence number seen
+    highest: u64,
+    /// Sequence numbers seen, bit `n` standing for `highest - n`
+    seen: u64,
+}
+
+impl ReplayGuard {
+    /// Whether the message with the `nonce`, received at `now` in milliseconds since the UNIX
+    /// epoch, is seen for the first time, in which case it is remembered. Only the nonces of
+    /// authenticated messages may be passed in, or the guard can be made to refuse the genuine
+    /// ones.
+    pub fn accept(&mut self, nonce: &SequencedNonce, now: u64) -> bool {
+        match self.sender {
+            Some((epoch, _)) if nonce.epoch < epoch => return false,
+            Some((epoch, salt)) if nonce.epoch == epoch => {
+                if nonce.salt != salt {
+                    return false;
+                }
+            }
+            _ => {
+                // First message of the sender or of its new epoch, which might be a replay
+                // unless it is recent
+                if nonce.epoch.saturating_add(MAX_EPOCH_AGE_MS) < now {
+                    return false;
+                }
+                self.sender = Some((nonce.epoch, nonce.salt));
+                self.highest = nonce.sequence;
+                self.seen = 1;
+                return true;
+            }
+        }
+
+        if nonce.sequence > self.highest {
+            let shift = nonce.sequence - self.highest;
+            self.seen = match shift < WINDOW_LEN {
+                true => (self.seen << shift) | 1,
+                false => 1,
+            };
+            self.highest = nonce.sequence;
+            return true;
+        }
+
+        let age = self.highest - nonce.sequence;
+        if age >= WINDOW_LEN || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; 8] = [7; 8];
+
+    /// Later than all of the epochs of the tests, but not by much
+    const NOW: u64 = 100;
+
+    fn nonce(epoch: u64, sequence: u64) -> SequencedNonce {
+        SequencedNonce {
+            epoch,
+            salt: SALT,
+            sequence,
+        }
+    }
+
+    #[test]
+    fn nonce_is_encoded_in_network_byte_order() {
+        let mut sequence = ControlSequence::new(0x0102, SALT);
+        sequence.next_nonce(0x0102);
+        let nonce = sequence.next_nonce(0x0102);
+
+        let bytes = nonce.to_bytes();
+        assert_eq!(
+            bytes,
+            [0, 0, 0, 0, 0, 0, 1, 2, 7, 7, 7, 7, 7, 7, 7, 7, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(SequencedNonce::from_bytes(&bytes), Some(nonce));
+        assert_eq!(SequencedNonce::from_bytes(&bytes[1..]), None);
+    }
+
+    #[test]
+    fn replayed_messages_are_refused() {
+        let mut guard = ReplayGuard::default();
+
+        assert!(guard.accept(&nonce(10, 0), NOW));
+        assert!(!guard.accept(&nonce(10, 0), NOW));
+        assert!(guard.accept(&nonce(10, 5), NOW));
+        // Reordered messages are accepted once
+        assert!(guard.accept(&nonce(10, 3), NOW));
+        assert!(!guard.accept(&nonce(10, 3), NOW));
+        assert!(!guard.accept(&nonce(10, 5), NOW));
+
+        // Messages fallen out of the window cannot be told apart from the replayed ones
+        assert!(guard.accept(&nonce(10, 5 + WINDOW_LEN), NOW));
+        assert!(!guard.accept(&nonce(10, 4), NOW));
+        assert!(guard.accept(&nonce(10, 6 + WINDOW_LEN), NOW));
+    }
+
+    #[test]
+    fn later_epochs_replace_earlier_ones() {
+        let mut guard = ReplayGuard::default();
+
+        assert!(guard.accept(&nonce(10, 100), NOW));
+        assert!(guard.accept(&nonce(11, 0), NOW));
+        assert!(!guard.accept(&nonce(10, 101), NOW));
+        assert!(guard.accept(&nonce(11, 1), NOW));
+
+        let other_sender = SequencedNonce {
+            salt: [8; 8],
+            ..nonce(11, 2)
+        };
+        assert!(!guard.accept(&other_sender, NOW));
+    }
+
+    #[test]
+    fn epochs_are_taken_up_only_while_recent() {
+        let now = 10 * MAX_EPOCH_AGE_MS;
+        let mut guard = ReplayGuard::default();
+
+        // Messages of a sender which could have been recorded long ago
+        assert!(!guard.accept(&nonce(now - MAX_EPOCH_AGE_MS - 1, 0), now));
+        assert!(guard.accept(&nonce(now - MAX_EPOCH_AGE_MS, 0), now));
+        // Known epochs stay accepted
+        assert!(guard.accept(&nonce(now - MAX_EPOCH_AGE_MS, 1), now + 1));
+    }
+
+    #[test]
+    fn senders_start_new_epochs_in_time() {
+        let mut sequence = ControlSequence::new(1000, SALT);
+        assert_eq!(sequence.next_nonce(1000), nonce(1000, 0));
+        assert_eq!(
+            sequence.next_nonce(1000 + EPOCH_LIFETIME_MS - 1),
+            nonce(1000, 1)
+        );
+
+        let renewed = sequence.next_nonce(1000 + EPOCH_LIFETIME_MS);
+        assert_eq!(renewed, nonce(1000 + EPOCH_LIFETIME_MS, 0));
+        let mut guard = ReplayGuard::default();
+        assert!(guard.accept(&renewed, renewed.epoch + MAX_EPOCH_AGE_MS));
+    }
+}
@@ -1,6 +1,8 @@
 #![deny(unsafe_code)]
 use async_trait::async_trait;
 use futures::future::{pending, select_all, FutureExt};
+#[cfg(target_os = "linux")]
+use std::io::IoSliceMut;
 use std::{
     collections::{HashMap, HashSet},
     io::ErrorKind,
@@ -11,6 +13,8 @@ use std::{
 use telio_crypto::PublicKey;
 use telio_model::EndpointMap;
 use telio_proto::{DataMsg, MAX_PACKET_SIZE};
+#[cfg(target_os = "linux")]
+use telio_sockets::batch::{self, RecvMeta};
 use telio_sockets::{SocketBufSizes, SocketPool, UdpParams};
 use telio_task::{
     io::{
@@ -31,6 +35,12 @@ type SocketMuteMap = HashMap<PublicKey, (Arc<UdpSocket>, Option<PinnedSleep<Publ
 
 const SOCK_BUF_SZ: usize = 212992;
 
+/// Most datagrams read from a WG socket at once, Linux reads them with a single syscall
+#[cfg(target_os = "linux")]
+const READ_BATCH: usize = 8;
+#[cfg(not(target_os = "linux"))]
+const READ_BATCH: usize = 1;
+
 #[derive(Debug, thiserror::Error)]
 /// Custom `UdpProxy` error
 pub enum Error {
@@ -130,7 +140,8 @@ pub struct Config {
 struct StateIngress {
     sockets: SocketMap,
     output: Tx<(PublicKey, DataMsg)>,
-    read_buf: Box<[u8; MAX_PACKET_SIZE]>,
+    /// [READ_BATCH] buffers of [MAX_PACKET_SIZE] back to back
+    read_buf: Box<[u8]>,
     updated_socket: Rx<(PublicKey, Arc<UdpSocket>)>,
 }
 
@@ -156,7 +167,7 @@ impl UdpProxy {
             task_ingress: Task::start(StateIngress {
                 sockets: HashMap::new(),
                 output: io.relay.tx,
-                read_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+                read_buf: vec![0u8; MAX_PACKET_SIZE * READ_BATCH].into_boxed_slice(),
                 updated_socket,
             }),
             task_egress: Task::start(StateEgress {
@@ -274,6 +285,42 @@ impl StateIngress {
 
         Ok(self.sockets.clone())
     }
+
+    /// Read at most `max` of the datagrams queued on the `socket` through the `read_buf`
+    #[cfg(target_os = "linux")]
+    fn recv(read_buf: &mut [u8], socket: &UdpSocket, max: usize) -> std::io::Result<Vec<DataMsg>> {
+        let mut meta = [RecvMeta {
+            len: 0,
+            source: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            segment_size: 0,
+        }; READ_BATCH];
+        let mut bufs: Vec<_> = read_buf
+            .chunks_mut(MAX_PACKET_SIZE)
+            .take(max)
+            .map(IoSliceMut::new)
+            .collect();
+        let received = batch::try_recv(socket, &mut bufs, &mut meta)?;
+        drop(bufs);
+
+        Ok(read_buf
+            .chunks(MAX_PACKET_SIZE)
+            .zip(meta.iter())
+            .take(received)
+            .filter_map(|(buf, meta)| buf.get(..meta.len))
+            .map(DataMsg::new)
+            .collect())
+    }
+
+    /// Read at most `max` of the datagrams queued on the `socket` through the `read_buf`
+    #[cfg(not(target_os = "linux"))]
+    fn recv(read_buf: &mut [u8], socket: &UdpSocket, _max: usize) -> std::io::Result<Vec<DataMsg>> {
+        let received = socket.try_recv(read_buf)?;
+        Ok(read_buf
+            .get(..received)
+            .map(DataMsg::new)
+            .into_iter()
+            .collect())
+    }
 }
 
 impl StateEgress {
@@ -473,14 +520,17 @@ impl Runtime for StateIngress {
             Some((permit, ((pk, socket), _, _))) =
                 wait_for_tx(&self.output, select_all(futures)) =>
             {
-                match socket.try_recv(self.read_buf.as_mut_slice()) {
-                    Ok(n) => {
-                        let msg = DataMsg::new(if let Some(buf) = self.read_buf.get(..n) {
-                            buf
-                        } else {
-                            return Self::error(());
-                        });
-                        let _ = permit.send((pk, msg));
+                // No more is read than the relay has room for, the rest waits in the socket
+                let max = self.output.capacity() + 1;
+                match Self::recv(&mut self.read_buf, &socket, max) {
+                    Ok(msgs) => {
+                        let mut msgs = msgs.into_iter();
+                        if let Some(msg) = msgs.next() {
+                            let _ = permit.send((pk, msg));
+                        }
+                        for msg in msgs {
+                            let _ = self.output.try_send((pk, msg));
+                        }
                     }
                     Err(e) if e.kind() == ErrorKind::WouldBlock => {
                         // Blocking error is not an issue here
@@ -673,6 +723,23 @@ mod tests {
         ts.stop().await;
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_queued_packets_are_forwarded_in_order() {
+        let mut ts = TestSystem::start().await;
+        let pk = ts.create_peers(1).await[0];
+
+        let packets: Vec<_> = (0..20u8).map(|i| vec![i; usize::from(i) + 1]).collect();
+        for packet in &packets {
+            ts.wg.send(pk, packet).await;
+        }
+
+        for packet in &packets {
+            ts.relay.expect_next((pk, DataMsg::new(packet))).await;
+        }
+
+        ts.stop().await;
+    }
+
     /// Helper utils for tests
     mod helper {
         use futures::future::join_all;
@@ -1058,6 +1125,14 @@ mod tests {
                     }
                 }
             }
+
+            pub async fn expect_next(&self, expected: (PublicKey, DataMsg)) {
+                let got = timeout(Duration::from_millis(1000), self.rx.lock().await.recv())
+                    .await
+                    .expect("relay recv timeout")
+                    .expect("failed to recv from relay");
+                assert_eq!(got, expected);
+            }
         }
     }
 }
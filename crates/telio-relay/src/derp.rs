@@ -7,26 +7,29 @@
 //! config contains sorted list of servers, the module will try to connect to them one at a time
 //! until first connection is made. For other configuration values, see `Config` description
 
+pub mod health;
 pub mod http;
+pub mod longpoll;
 pub mod proto;
+pub mod quic;
 
 use async_trait::async_trait;
 use futures::{future::select_all, Future};
 use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use telio_crypto::{PublicKey, SecretKey};
 use telio_model::config::{DerpAnalyticsEvent, RelayConnectionChangeReason};
 use telio_model::{
     config::{RelayState, Server},
-    features::FeatureDerp,
+    features::{DerpTransport, FeatureDerp},
 };
 #[mockall_double::double]
 use telio_nurse::aggregator::ConnectivityDataAggregator;
 use telio_proto::{
-    Codec, DerpPollRequestMsg, PacketControl, PacketRelayed, PacketTypeRelayed, PeersStatesMap,
-    Session,
+    Codec, ControlSequence, DerpPollRequestMsg, PacketControl, PacketRelayed, PacketTypeRelayed,
+    PeersStatesMap, ReplayGuard, SequencedNonce, Session,
 };
 use telio_sockets::SocketPool;
 use telio_task::io::{wait_for_tx, Chan};
@@ -44,10 +47,10 @@ use crypto_box::aead::{
 };
 use telio_crypto::chachabox::ChaChaBox;
 
-use rand::{rngs::StdRng, SeedableRng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use smart_default::SmartDefault;
 
-use self::{http::connect_http_and_start, http::DerpConnection};
+use self::http::{connect_http_and_start, connect_long_poll_and_start, DerpConnection};
 
 pub use self::proto::Error as DerpError;
 
@@ -68,6 +71,27 @@ impl SortedServers {
         }
     }
 
+    /// Create SortedServers keeping the given order, e.g. the one from [health::order_by_health]
+    pub fn with_order(servers: Vec<Server>) -> Self {
+        Self {
+            servers,
+            current_server_num: 0,
+        }
+    }
+
+    /// Try the server with the `public_key` first, e.g. the one connected to before a restart
+    pub fn prefer(&mut self, public_key: &PublicKey) {
+        if let Some(index) = self
+            .servers
+            .iter()
+            .position(|server| &server.public_key == public_key)
+        {
+            if let Some(servers) = self.servers.get_mut(..=index) {
+                servers.rotate_right(1);
+            }
+        }
+    }
+
     fn get_next(&mut self) -> Option<Server> {
         if self.current_server_num < self.servers.len() {
             let result = self.servers.get(self.current_server_num).cloned();
@@ -93,6 +117,22 @@ impl PartialEq for SortedServers {
     }
 }
 
+/// Address of the relay port of the server, resolving its hostname when no IP address is given
+pub(crate) async fn server_addr(server: &Server) -> std::io::Result<SocketAddr> {
+    if !server.ipv4.is_unspecified() {
+        return Ok(SocketAddr::new(IpAddr::V4(server.ipv4), server.relay_port));
+    }
+    tokio::net::lookup_host((server.hostname.as_str(), server.relay_port))
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no IPv4 address for {}", server.hostname),
+            )
+        })
+}
+
 /// Derp task exposed to other crates
 pub struct DerpRelay {
     task: Task<State>,
@@ -119,12 +159,52 @@ struct State {
     remote_peers_states: PeersStatesMap,
     /// Connectivity data aggregator
     aggregator: Option<Arc<ConnectivityDataAggregator>>,
+    /// Replay protection of the control messages
+    replay_protection: ReplayProtection,
 
     last_disconnection_reason: RelayConnectionChangeReason,
 
     connecting: Option<JoinHandle<(Server, DerpConnection)>>,
 }
 
+/// Replay protection of the control messages exchanged with the peers, see [ReplayGuard]
+struct ReplayProtection {
+    /// Nonces of the sequenced messages sent
+    sequence: ControlSequence,
+    /// Peers which negotiated the replay protection, control messages are sent to them sequenced
+    /// and have to come from them sequenced
+    peers: HashSet<PublicKey>,
+    /// Sequenced messages received from each peer
+    guards: HashMap<PublicKey, ReplayGuard>,
+}
+
+impl ReplayProtection {
+    fn new(rng: &mut StdRng) -> Self {
+        // Later runs get later epochs, unless the clock is turned back
+        Self {
+            sequence: ControlSequence::new(unix_millis(), rng.gen()),
+            peers: HashSet::new(),
+            guards: HashMap::new(),
+        }
+    }
+}
+
+/// Current time in milliseconds since the UNIX epoch, as used by the sequenced nonces
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Why a relayed message of a peer was dropped
+#[derive(Debug, PartialEq, Eq)]
+enum Dropped {
+    /// Not authentic, or not sequenced while it had to be
+    Rejected,
+    /// Seen before
+    Replayed,
+}
+
 /// Keepalive values that help keeping Derp connection in conntrack alive,
 /// so server can send traffic after being silent for a while
 /// *derp_keepalive* is also used as an interval for retrieving remote peer states.
@@ -186,6 +266,11 @@ pub struct Config {
     pub enable_polling: bool,
     /// Use Mozilla's root certificates instead of OS ones [default false]
     pub use_built_in_root_certificates: bool,
+    /// Transport used to reach the servers [default Tcp]
+    pub transport: DerpTransport,
+    /// Consecutive failed connection attempts after which HTTPS long-polling is used instead
+    /// [default disabled]
+    pub long_poll_after_failures: Option<u32>,
 }
 
 impl State {
@@ -217,6 +302,9 @@ impl State {
 
         let connection = async move {
             let mut sleep_time = 1f64;
+            // Failed attempts of each server, once they are enough long-polling is used for the
+            // rest of the attempts to it, the streams are tried again after the next disconnection
+            let mut failures: HashMap<String, u32> = HashMap::new();
             loop {
                 let mut server = match config.servers.get_next() {
                     Some(server) => {
@@ -252,14 +340,33 @@ impl State {
                 }
 
                 // Try to establish connection
-                match Box::pin(connect_http_and_start(
-                    socket_pool.clone(),
-                    &server.get_address(),
-                    SocketAddr::new(IpAddr::V4(server.ipv4), server.relay_port),
-                    config.clone(),
-                ))
-                .await
-                {
+                let server_failures = failures.entry(server.get_address()).or_default();
+                let long_poll = config
+                    .long_poll_after_failures
+                    .is_some_and(|probe_failures| *server_failures >= probe_failures);
+                let connection = match server_addr(&server).await {
+                    Ok(addr) if long_poll => {
+                        telio_log_debug!("({}) Trying to connect over long-polling", Self::NAME);
+                        Box::pin(connect_long_poll_and_start(
+                            socket_pool.clone(),
+                            &server.get_address(),
+                            addr,
+                            config.clone(),
+                        ))
+                        .await
+                    }
+                    Ok(addr) => {
+                        Box::pin(connect_http_and_start(
+                            socket_pool.clone(),
+                            &server.get_address(),
+                            addr,
+                            config.clone(),
+                        ))
+                        .await
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                match connection {
                     Ok(conn) => {
                         telio_log_info!("({}) Connected to {}", Self::NAME, server.get_address());
                         server.conn_state = RelayState::Connected;
@@ -282,6 +389,7 @@ impl State {
                                 err
                             );
                         }
+                        *server_failures = server_failures.saturating_add(1);
                         last_disconnection_reason = err.into();
                         continue;
                     }
@@ -301,7 +409,8 @@ impl DerpRelay {
         aggregator: Option<Arc<ConnectivityDataAggregator>>,
     ) -> Self {
         // generate random number used to encrypt control messages
-        let rng = StdRng::from_entropy();
+        let mut rng = StdRng::from_entropy();
+        let replay_protection = ReplayProtection::new(&mut rng);
 
         Self {
             task: Task::start(State {
@@ -317,6 +426,7 @@ impl DerpRelay {
                 connecting: None,
                 last_disconnection_reason: RelayConnectionChangeReason::ConfigurationChange,
                 aggregator,
+                replay_protection,
             }),
         }
     }
@@ -333,6 +443,9 @@ impl DerpRelay {
 
             // Prepare new config
             if let Some(config) = s.config.as_mut() {
+                // Guards of the removed peers are kept, so that their messages cannot be
+                // replayed once they are added back
+
                 // TODO: This logic should most likely linked with wg_stun_controll
                 // Restart connection
                 match s.server.as_ref() {
@@ -356,6 +469,16 @@ impl DerpRelay {
         .await;
     }
 
+    /// Exchange only sequenced control messages with the `peers`, which negotiated the
+    /// protection against replays
+    pub async fn set_replay_protected_peers(&self, peers: HashSet<PublicKey>) {
+        let _ = task_exec!(&self.task, async move |s| {
+            s.replay_protection.peers = peers;
+            Ok(())
+        })
+        .await;
+    }
+
     /// Get current Derp configuration
     pub async fn get_config(&self) -> Option<Config> {
         task_exec!(&self.task, async move |s| Ok(s.config.as_ref().cloned()))
@@ -537,6 +660,63 @@ impl DerpRelay {
             }
         }
     }
+
+    /// Used to encrypt control messages to the peers protected against
+    /// replays
+    ///
+    /// The nonce is the next one of the `sequence`, the message type is
+    /// authenticated along, so it cannot be passed for an unsequenced one
+    ///
+    /// return message including: type (0x0c) + Nonce + payload
+    fn encrypt_sequenced(
+        secret_key: SecretKey,
+        public_key: PublicKey,
+        sequence: &mut ControlSequence,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let nonce = sequence.next_nonce(unix_millis()).to_bytes();
+        let secret_box = ChaChaBox::new(&public_key, &secret_key);
+        let mut cipher_text = secret_box.encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: data,
+                aad: &[PacketTypeRelayed::SequencedEncrypted as u8],
+            },
+        )?;
+
+        let mut message = vec![PacketTypeRelayed::SequencedEncrypted as u8];
+        message.extend_from_slice(&nonce);
+        message.append(&mut cipher_text);
+        Ok(message)
+    }
+
+    /// Associated function used to decrypt control messages encrypted
+    /// with [DerpRelay::encrypt_sequenced]
+    ///
+    /// return the nonce, to be checked for replays, and the plain text
+    fn decrypt_sequenced(
+        secret_key: SecretKey,
+        public_key: PublicKey,
+        data: &[u8],
+    ) -> Result<(SequencedNonce, Vec<u8>), Error> {
+        let nonce = data
+            .get(DerpRelay::NONCE_BEGIN_POS..DerpRelay::NONCE_END_POS)
+            .ok_or(crypto_box::aead::Error)?;
+        let cipher_text = data
+            .get(DerpRelay::NONCE_END_POS..)
+            .ok_or(crypto_box::aead::Error)?;
+
+        let secret_box = ChaChaBox::new(&public_key, &secret_key);
+        let plain_text = secret_box.decrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                msg: cipher_text,
+                aad: &[PacketTypeRelayed::SequencedEncrypted as u8],
+            },
+        )?;
+        let nonce = SequencedNonce::from_bytes(nonce).ok_or(crypto_box::aead::Error)?;
+        Ok((nonce, plain_text))
+    }
 }
 
 impl State {
@@ -547,6 +727,7 @@ impl State {
         msg: PacketRelayed,
         config: &Config,
         rng: &mut StdRng,
+        replay_protection: &mut ReplayProtection,
     ) {
         // TODO add custom task's log format macro
         telio_log_trace!(
@@ -556,15 +737,30 @@ impl State {
             msg.packet_type()
         );
         match msg.encode() {
-            Ok(buf) => match DerpRelay::encrypt_if_needed(config.secret_key.clone(), pk, rng, &buf)
-            {
-                Ok(cipher_text) => {
-                    let _ = permit.send((pk, cipher_text));
-                }
-                Err(error) => {
-                    telio_log_debug!("({}) Encryption failed: {}", Self::NAME, error);
+            Ok(buf) => {
+                // Capabilities are always sequenced, so that the peers which restarted can
+                // renegotiate with the ones still sequencing their messages
+                let sequenced = buf.first() == Some(&(PacketTypeRelayed::Capabilities as u8))
+                    || (replay_protection.peers.contains(&pk)
+                        && buf.first() != Some(&(PacketTypeRelayed::Data as u8)));
+                let cipher_text = match sequenced {
+                    true => DerpRelay::encrypt_sequenced(
+                        config.secret_key.clone(),
+                        pk,
+                        &mut replay_protection.sequence,
+                        &buf,
+                    ),
+                    false => DerpRelay::encrypt_if_needed(config.secret_key.clone(), pk, rng, &buf),
+                };
+                match cipher_text {
+                    Ok(cipher_text) => {
+                        let _ = permit.send((pk, cipher_text));
+                    }
+                    Err(error) => {
+                        telio_log_debug!("({}) Encryption failed: {}", Self::NAME, error);
+                    }
                 }
-            },
+            }
             Err(e) => {
                 telio_log_debug!("({}) Failed to encode packet: {}", Self::NAME, e);
             }
@@ -594,9 +790,11 @@ impl State {
         pk: PublicKey,
         buf: Vec<u8>,
         config: &Config,
+        replay_protection: &mut ReplayProtection,
+        aggregator: Option<&Arc<ConnectivityDataAggregator>>,
     ) {
         if config.meshnet_peers.contains(&pk) {
-            match DerpRelay::decrypt_if_needed(config.secret_key.clone(), pk, &buf) {
+            match Self::open_relayed_payload(pk, &buf, config, replay_protection) {
                 Ok(plain_text) => match PacketRelayed::decode(&plain_text) {
                     Ok(msg) => {
                         telio_log_trace!(
@@ -616,8 +814,18 @@ impl State {
                         );
                     }
                 },
-                Err(error) => {
-                    telio_log_debug!("Decryption failed: {}", error);
+                Err(dropped) => {
+                    telio_log_debug!(
+                        "({}) DERP --> Rx, dropped packet of {:?}: {:?}",
+                        Self::NAME,
+                        pk,
+                        dropped
+                    );
+                    if let Some(aggregator) = aggregator {
+                        aggregator
+                            .report_dropped_relayed_message(dropped == Dropped::Replayed)
+                            .await;
+                    }
                 }
             }
         } else {
@@ -629,6 +837,40 @@ impl State {
         }
     }
 
+    /// Decrypt the relayed `buf` of the peer `pk`, the sequenced messages are checked for replays
+    fn open_relayed_payload(
+        pk: PublicKey,
+        buf: &[u8],
+        config: &Config,
+        replay_protection: &mut ReplayProtection,
+    ) -> Result<Vec<u8>, Dropped> {
+        if buf.first() == Some(&(PacketTypeRelayed::SequencedEncrypted as u8)) {
+            let (nonce, plain_text) =
+                DerpRelay::decrypt_sequenced(config.secret_key.clone(), pk, buf)
+                    .map_err(|_| Dropped::Rejected)?;
+            if !replay_protection
+                .guards
+                .entry(pk)
+                .or_default()
+                .accept(&nonce, unix_millis())
+            {
+                return Err(Dropped::Replayed);
+            }
+            return Ok(plain_text);
+        }
+
+        let plain_text = DerpRelay::decrypt_if_needed(config.secret_key.clone(), pk, buf)
+            .map_err(|_| Dropped::Rejected)?;
+        // Peers keep sending unsequenced messages until they learn of the negotiation
+        let unsequenced = buf.first() == Some(&(PacketTypeRelayed::Encrypted as u8));
+        let sequencing =
+            replay_protection.peers.contains(&pk) && replay_protection.guards.contains_key(&pk);
+        if unsequenced && sequencing {
+            return Err(Dropped::Rejected);
+        }
+        Ok(plain_text)
+    }
+
     /// handle traffic for |Derp -> LocalNode|
     async fn handle_incoming_payload_direct(
         expected_session: Session,
@@ -734,7 +976,7 @@ impl Runtime for State {
                     // Received payload from upper relay, forward it to DERP stream
                     res = wait_for_tx(&c.comms_relayed.tx, upper_read) => match res {
                         Some((permit, Some((pk, msg)))) => {
-                            Self::handle_outcoming_payload_relayed(permit, pk, msg, config, &mut self.rng).await;
+                            Self::handle_outcoming_payload_relayed(permit, pk, msg, config, &mut self.rng, &mut self.replay_protection).await;
                         },
                         Some((_, None)) => {
                             telio_log_debug!("Disconnecting from DERP server due to closed rx channel");
@@ -761,7 +1003,7 @@ impl Runtime for State {
                     }
                     // Received payload from DERP stream, forward it to upper relay
                     Some((permit, Some((pk, buf)))) = wait_for_tx(chan_tx, derp_relayed_read) => {
-                        Self::handle_incoming_payload_relayed(permit, pk, buf, config).await;
+                        Self::handle_incoming_payload_relayed(permit, pk, buf, config, &mut self.replay_protection, self.aggregator.as_ref()).await;
                     },
                     Some((_, Some(buf))) = wait_for_tx(chan_tx, derp_direct_read) => {
                         self.remote_peers_states = Self::handle_incoming_payload_direct(self.derp_poll_session, buf).await.unwrap_or_default();
@@ -897,6 +1139,28 @@ mod tests {
         assert_eq!(None, config.servers.get_next());
     }
 
+    #[test]
+    fn preferred_server_goes_first() {
+        let first = Server {
+            public_key: PublicKey([1; 32]),
+            weight: 11,
+            ..Default::default()
+        };
+        let second = Server {
+            public_key: PublicKey([2; 32]),
+            weight: 22,
+            ..Default::default()
+        };
+
+        let mut servers = SortedServers::new(vec![first.clone(), second.clone()]);
+        servers.prefer(&PublicKey([3; 32]));
+        servers.prefer(&second.public_key);
+
+        assert_eq!(Some(second), servers.get_next());
+        assert_eq!(Some(first), servers.get_next());
+        assert_eq!(None, servers.get_next());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     #[ignore = "derp cannot connect to real host"]
     async fn test_derp_fallback() {
@@ -1064,4 +1328,97 @@ mod tests {
 
         await_timeout!(test_derp.stop());
     }
+
+    #[test]
+    fn sequenced_messages_are_not_replayed() {
+        let (sender, receiver) = (SecretKey::gen(), SecretKey::gen());
+        let mut rng = StdRng::from_entropy();
+        let mut sender_protection = ReplayProtection::new(&mut rng);
+        let mut receiver_protection = ReplayProtection::new(&mut rng);
+        let config = Config {
+            secret_key: receiver.clone(),
+            meshnet_peers: [sender.public()].into(),
+            ..Default::default()
+        };
+        let mut open = |buf: &[u8]| {
+            State::open_relayed_payload(sender.public(), buf, &config, &mut receiver_protection)
+        };
+
+        let plain_text = vec![PacketTypeRelayed::Upgrade as u8, 1, 2, 3];
+        let message = DerpRelay::encrypt_sequenced(
+            sender.clone(),
+            receiver.public(),
+            &mut sender_protection.sequence,
+            &plain_text,
+        )
+        .unwrap();
+        assert_eq!(open(&message), Ok(plain_text.clone()));
+        assert_eq!(open(&message), Err(Dropped::Replayed));
+
+        // The sequenced message cannot be passed for an unsequenced one
+        let mut downgraded = message.clone();
+        downgraded[0] = PacketTypeRelayed::Encrypted as u8;
+        assert_eq!(open(&downgraded), Err(Dropped::Rejected));
+    }
+
+    #[test]
+    fn unsequenced_messages_of_protected_peers_are_rejected() {
+        let (sender, receiver) = (SecretKey::gen(), SecretKey::gen());
+        let mut rng = StdRng::from_entropy();
+        let mut sender_protection = ReplayProtection::new(&mut rng);
+        let mut protection = ReplayProtection::new(&mut rng);
+        protection.peers.insert(sender.public());
+        let config = Config {
+            secret_key: receiver.clone(),
+            meshnet_peers: [sender.public()].into(),
+            ..Default::default()
+        };
+
+        let plain_text = vec![PacketTypeRelayed::Upgrade as u8, 1, 2, 3];
+        let message =
+            DerpRelay::encrypt_if_needed(sender.clone(), receiver.public(), &mut rng, &plain_text)
+                .unwrap();
+        // Accepted until the peer sequences its messages too
+        assert_eq!(
+            State::open_relayed_payload(sender.public(), &message, &config, &mut protection),
+            Ok(plain_text.clone())
+        );
+        let sequenced = DerpRelay::encrypt_sequenced(
+            sender.clone(),
+            receiver.public(),
+            &mut sender_protection.sequence,
+            &plain_text,
+        )
+        .unwrap();
+        assert_eq!(
+            State::open_relayed_payload(sender.public(), &sequenced, &config, &mut protection),
+            Ok(plain_text.clone())
+        );
+        assert_eq!(
+            State::open_relayed_payload(sender.public(), &message, &config, &mut protection),
+            Err(Dropped::Rejected)
+        );
+
+        // Not even the capabilities, which a restarted peer renegotiates with in a new epoch
+        let plain_text = vec![PacketTypeRelayed::Capabilities as u8];
+        let message =
+            DerpRelay::encrypt_if_needed(sender.clone(), receiver.public(), &mut rng, &plain_text)
+                .unwrap();
+        assert_eq!(
+            State::open_relayed_payload(sender.public(), &message, &config, &mut protection),
+            Err(Dropped::Rejected)
+        );
+        let mut restarted = ControlSequence::new(unix_millis() + 1, [0; 8]);
+        let message = DerpRelay::encrypt_sequenced(
+            sender.clone(),
+            receiver.public(),
+            &mut restarted,
+            &plain_text,
+        )
+        .unwrap();
+        assert_eq!(
+            State::open_relayed_payload(sender.public(), &message, &config, &mut protection),
+            Ok(plain_text)
+        );
+    }
 }
@@ -0,0 +1,478 @@
+//! Health monitoring of DERP servers.
+//!
+//! Servers are checked periodically by opening a TCP connection to their relay port, which also
+//! measures the latency to them. [order_by_health] uses the results to move unreachable servers to
+//! the end of the list and to prefer the fastest ones, so that self-hosted relays can be used
+//! without knowing upfront which of them is the closest. With [Rehoming] the active server is also
+//! left for a faster one, once it has been slower for long enough.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use telio_crypto::PublicKey;
+use telio_model::{
+    config::Server,
+    features::{FeatureRelayHealthCheck, FeatureRelayRehoming},
+};
+use telio_sockets::SocketPool;
+use telio_task::{task_exec, Runtime, RuntimeExt, Task, WaitResponse};
+use telio_utils::{telio_log_debug, telio_log_info, Instant};
+use tokio::time::{interval, timeout, Interval, MissedTickBehavior};
+
+use super::server_addr;
+
+/// Results of the health checks of a single server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerHealth {
+    /// Latency measured by the last successful check
+    pub latency: Option<Duration>,
+    /// Smoothed variation of the latency between successful checks, like the interarrival
+    /// jitter of RFC 3550
+    pub jitter: Option<Duration>,
+    /// Number of checks failed in a row
+    pub failures: u32,
+    /// When the server was last checked
+    pub last_check: Option<SystemTime>,
+}
+
+impl ServerHealth {
+    /// Whether the server is reachable, according to the given threshold of failed checks
+    pub fn is_healthy(&self, max_failures: u32) -> bool {
+        self.failures < max_failures
+    }
+
+    fn update(&mut self, latency: Option<Duration>) {
+        match latency {
+            Some(latency) => {
+                if let Some(previous) = self.latency {
+                    let diff = if latency > previous {
+                        latency - previous
+                    } else {
+                        previous - latency
+                    };
+                    let jitter = self.jitter.unwrap_or_default();
+                    self.jitter = Some(if diff > jitter {
+                        jitter + (diff - jitter) / 16
+                    } else {
+                        jitter - (jitter - diff) / 16
+                    });
+                }
+                self.latency = Some(latency);
+                self.failures = 0;
+            }
+            None => self.failures = self.failures.saturating_add(1),
+        }
+        self.last_check = Some(SystemTime::now());
+    }
+}
+
+/// Order the servers by health: healthy ones first with the fastest first, then the ones not
+/// checked yet and unhealthy ones last. Otherwise the original order is kept.
+pub fn order_by_health(
+    servers: &[Server],
+    health: &HashMap<PublicKey, ServerHealth>,
+    max_failures: u32,
+) -> Vec<Server> {
+    let mut servers = servers.to_vec();
+    servers.sort_by_key(|server| match health.get(&server.public_key) {
+        Some(health) if !health.is_healthy(max_failures) => (2, Duration::ZERO),
+        Some(ServerHealth {
+            latency: Some(latency),
+            ..
+        }) => (0, *latency),
+        _ => (1, Duration::ZERO),
+    });
+    servers
+}
+
+/// Decides when to leave the healthy active server for a faster one.
+///
+/// The active server has to be slower than the fastest healthy server by more than the threshold
+/// in a number of consecutive checks, and switches are separated by a cooldown, so that servers
+/// with similar latency do not cause flapping.
+#[derive(Debug)]
+pub struct Rehoming {
+    config: FeatureRelayRehoming,
+    slower_checks: u32,
+    last_switch: Option<Instant>,
+}
+
+impl Rehoming {
+    /// Start without any history
+    pub fn new(config: FeatureRelayRehoming) -> Self {
+        Self {
+            config,
+            slower_checks: 0,
+            last_switch: None,
+        }
+    }
+
+    /// Account the results of a round of checks, returning the server to switch to if it is time
+    pub fn update(
+        &mut self,
+        active: &PublicKey,
+        health: &HashMap<PublicKey, ServerHealth>,
+        max_failures: u32,
+        now: Instant,
+    ) -> Option<PublicKey> {
+        let active_latency = health
+            .get(active)
+            .filter(|h| h.is_healthy(max_failures))
+            .and_then(|h| h.latency);
+        let fastest = health
+            .iter()
+            .filter(|(key, h)| *key != active && h.is_healthy(max_failures))
+            .filter_map(|(key, h)| h.latency.map(|latency| (*key, latency)))
+            .min_by_key(|(_, latency)| *latency);
+
+        let threshold = Duration::from_millis(self.config.latency_threshold_ms as u64);
+        let candidate = match (active_latency, fastest) {
+            (Some(active_latency), Some((key, latency)))
+                if latency + threshold < active_latency =>
+            {
+                key
+            }
+            _ => {
+                self.slower_checks = 0;
+                return None;
+            }
+        };
+
+        self.slower_checks = self.slower_checks.saturating_add(1);
+        if self.slower_checks < self.config.consecutive_checks {
+            return None;
+        }
+        let cooldown = Duration::from_secs(self.config.cooldown_s as u64);
+        if self
+            .last_switch
+            .is_some_and(|last| now.saturating_duration_since(last) < cooldown)
+        {
+            return None;
+        }
+
+        self.slower_checks = 0;
+        self.last_switch = Some(now);
+        Some(candidate)
+    }
+}
+
+/// Task periodically checking the health of the DERP servers
+pub struct RelayHealthMonitor {
+    task: Task<State>,
+}
+
+struct State {
+    servers: Vec<Server>,
+    health: HashMap<PublicKey, ServerHealth>,
+    socket_pool: Arc<SocketPool>,
+    check_timeout: Duration,
+    max_failures: u32,
+    poll_timer: Interval,
+    /// Server the DERP client is connected to
+    active: Option<PublicKey>,
+    rehoming: Option<Rehoming>,
+    /// Server to switch to, decided by the rehoming
+    rehome_to: Option<PublicKey>,
+    /// No checks are made while paused
+    paused: bool,
+}
+
+impl RelayHealthMonitor {
+    /// Start checking the servers once they are configured
+    pub fn start(socket_pool: Arc<SocketPool>, config: &FeatureRelayHealthCheck) -> Self {
+        let mut poll_timer = interval(Duration::from_secs(config.interval_s.max(1) as u64));
+        poll_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            task: Task::start(State {
+                servers: Vec::new(),
+                health: HashMap::new(),
+                socket_pool,
+                check_timeout: Duration::from_secs(config.timeout_s as u64),
+                max_failures: config.max_failures,
+                poll_timer,
+                active: None,
+                rehoming: config.rehoming.map(Rehoming::new),
+                rehome_to: None,
+                paused: false,
+            }),
+        }
+    }
+
+    /// Set the servers to check, forgetting the results for the removed ones.
+    /// New servers are checked right away.
+    pub async fn configure(&self, servers: Vec<Server>) {
+        let _ = task_exec!(&self.task, async move |s| {
+            s.health
+                .retain(|key, _| servers.iter().any(|server| server.public_key == *key));
+            if servers != s.servers {
+                s.servers = servers;
+                s.poll_timer.reset_immediately();
+            }
+            Ok(())
+        })
+        .await;
+    }
+
+    /// Results of the checks by public key of the server
+    pub async fn get_health(&self) -> HashMap<PublicKey, ServerHealth> {
+        task_exec!(&self.task, async move |s| Ok(s.health.clone()))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Set the server the DERP client is connected to, which is the one considered for rehoming
+    pub async fn set_active_server(&self, active: Option<PublicKey>) {
+        let _ = task_exec!(&self.task, async move |s| {
+            if s.active != active {
+                s.active = active;
+                s.rehome_to = None;
+            }
+            Ok(())
+        })
+        .await;
+    }
+
+    /// Take the faster server the active one should be replaced with, if rehoming decided so
+    pub async fn take_rehome_target(&self) -> Option<PublicKey> {
+        task_exec!(&self.task, async move |s| Ok(s.rehome_to.take()))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Stop or restart the checks without forgetting their results. The servers are checked
+    /// right away when resumed.
+    pub async fn set_paused(&self, paused: bool) {
+        let _ = task_exec!(&self.task, async move |s| {
+            if s.paused && !paused {
+                s.poll_timer.reset_immediately();
+            }
+            s.paused = paused;
+            Ok(())
+        })
+        .await;
+    }
+
+    /// Stop the monitor
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+impl State {
+    async fn check_all(&mut self) {
+        let results = join_all(self.servers.iter().map(|server| async {
+            let latency = check(&self.socket_pool, server, self.check_timeout)
+                .await
+                .map_err(|e| {
+                    telio_log_debug!("Health check of {} failed: {e}", server.hostname);
+                })
+                .ok();
+            (server.public_key, latency)
+        }))
+        .await;
+
+        for (key, latency) in results {
+            self.health.entry(key).or_default().update(latency);
+        }
+        telio_log_debug!("Relay server health: {:?}", self.health);
+
+        if let (Some(rehoming), Some(active)) = (self.rehoming.as_mut(), self.active.as_ref()) {
+            if let Some(target) =
+                rehoming.update(active, &self.health, self.max_failures, Instant::now())
+            {
+                telio_log_info!("Relay server {target} is faster than the active {active}");
+                self.rehome_to = Some(target);
+            }
+        }
+    }
+}
+
+/// Measure the time it takes to open a TCP connection to the relay port of the server
+async fn check(
+    socket_pool: &SocketPool,
+    server: &Server,
+    check_timeout: Duration,
+) -> io::Result<Duration> {
+    let addr = server_addr(server).await?;
+    let start = Instant::now();
+    timeout(
+        check_timeout,
+        socket_pool.connect_external_tcp_v4(addr, None),
+    )
+    .await??;
+    Ok(start.elapsed())
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "RelayHealthMonitor";
+
+    type Err = ();
+
+    async fn wait(&mut self) -> WaitResponse<'_, Self::Err> {
+        self.poll_timer.tick().await;
+        if !self.paused && !self.servers.is_empty() {
+            self.check_all().await;
+        }
+        Self::next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_crypto::SecretKey;
+
+    fn server(weight: u32) -> Server {
+        Server {
+            hostname: format!("relay-{weight}.example.com"),
+            public_key: SecretKey::gen().public(),
+            weight,
+            ..Default::default()
+        }
+    }
+
+    fn health(latency_ms: Option<u64>, failures: u32) -> ServerHealth {
+        ServerHealth {
+            latency: latency_ms.map(Duration::from_millis),
+            jitter: None,
+            failures,
+            last_check: None,
+        }
+    }
+
+    #[test]
+    fn health_update() {
+        let mut h = ServerHealth::default();
+        h.update(None);
+        h.update(None);
+        assert_eq!(h.failures, 2);
+        assert!(h.is_healthy(3));
+        h.update(None);
+        assert!(!h.is_healthy(3));
+
+        h.update(Some(Duration::from_millis(20)));
+        assert_eq!(h.failures, 0);
+        assert_eq!(h.latency, Some(Duration::from_millis(20)));
+        assert_eq!(h.jitter, None);
+        assert!(h.last_check.is_some());
+
+        h.update(Some(Duration::from_millis(36)));
+        assert_eq!(h.jitter, Some(Duration::from_millis(1)));
+        h.update(None);
+        assert_eq!(h.jitter, Some(Duration::from_millis(1)));
+        h.update(Some(Duration::from_millis(36)));
+        assert_eq!(h.jitter, Some(Duration::from_micros(937_500)));
+    }
+
+    #[test]
+    fn servers_are_ordered_by_health() {
+        let servers = [server(1), server(2), server(3), server(4), server(5)];
+        let health = HashMap::from([
+            (servers[0].public_key, health(Some(10), 3)),
+            (servers[1].public_key, health(Some(80), 0)),
+            (servers[3].public_key, health(Some(25), 1)),
+            (servers[4].public_key, health(None, 1)),
+        ]);
+
+        let ordered: Vec<u32> = order_by_health(&servers, &health, 3)
+            .iter()
+            .map(|s| s.weight)
+            .collect();
+        assert_eq!(ordered, vec![4, 2, 3, 5, 1]);
+    }
+
+    #[test]
+    fn rehoming_needs_consecutive_slower_checks() {
+        let (active, fast) = (server(1), server(2));
+        let mut rehoming = Rehoming::new(FeatureRelayRehoming {
+            latency_threshold_ms: 50,
+            consecutive_checks: 3,
+            cooldown_s: 600,
+        });
+        let now = Instant::now();
+        let round = |active_ms, fast_ms| {
+            HashMap::from([
+                (active.public_key, health(Some(active_ms), 0)),
+                (fast.public_key, health(Some(fast_ms), 0)),
+            ])
+        };
+
+        assert_eq!(
+            rehoming.update(&active.public_key, &round(100, 20), 3, now),
+            None
+        );
+        assert_eq!(
+            rehoming.update(&active.public_key, &round(100, 20), 3, now),
+            None
+        );
+        // Within the threshold, the streak starts over
+        assert_eq!(
+            rehoming.update(&active.public_key, &round(60, 20), 3, now),
+            None
+        );
+        assert_eq!(
+            rehoming.update(&active.public_key, &round(100, 20), 3, now),
+            None
+        );
+        assert_eq!(
+            rehoming.update(&active.public_key, &round(100, 20), 3, now),
+            None
+        );
+        assert_eq!(
+            rehoming.update(&active.public_key, &round(100, 20), 3, now),
+            Some(fast.public_key)
+        );
+    }
+
+    #[test]
+    fn rehoming_respects_cooldown_and_health() {
+        let (active, fast) = (server(1), server(2));
+        let mut rehoming = Rehoming::new(FeatureRelayRehoming {
+            latency_threshold_ms: 10,
+            consecutive_checks: 1,
+            cooldown_s: 600,
+        });
+        let now = Instant::now();
+        let slow = HashMap::from([
+            (active.public_key, health(Some(100), 0)),
+            (fast.public_key, health(Some(20), 0)),
+        ]);
+
+        assert_eq!(
+            rehoming.update(&active.public_key, &slow, 3, now),
+            Some(fast.public_key)
+        );
+        let soon = now + Duration::from_secs(60);
+        assert_eq!(rehoming.update(&active.public_key, &slow, 3, soon), None);
+        let later = now + Duration::from_secs(601);
+        assert_eq!(
+            rehoming.update(&active.public_key, &slow, 3, later),
+            Some(fast.public_key)
+        );
+
+        let unhealthy = HashMap::from([
+            (active.public_key, health(Some(100), 0)),
+            (fast.public_key, health(Some(20), 3)),
+        ]);
+        let much_later = now + Duration::from_secs(3600);
+        assert_eq!(
+            rehoming.update(&active.public_key, &unhealthy, 3, much_later),
+            None
+        );
+    }
+
+    #[test]
+    fn order_is_kept_without_health() {
+        let servers = [server(3), server(1), server(2)];
+        assert_eq!(order_by_health(&servers, &HashMap::new(), 3), servers);
+    }
+}
@@ -1,9 +1,11 @@
 //! Connection to Derp creation and management
 
+use super::longpoll::LongPollStream;
 use super::proto::{
     exchange_keys, read_server_info, start_read, start_write, Error, PairAddr, TCP_KEEPALIVE_COUNT,
     TCP_KEEPALIVE_IDLE, TCP_KEEPALIVE_INTERVAL, TCP_USER_TIMEOUT,
 };
+use super::quic::{self, QuicStream};
 use futures::FutureExt;
 use httparse::Status;
 use std::{
@@ -22,6 +24,7 @@ use crate::{Config, DerpKeepaliveConfig};
 
 use rustls_platform_verifier::ConfigVerifierExt;
 use telio_crypto::{PublicKey, SecretKey};
+use telio_model::features::DerpTransport;
 use tokio::time::Interval;
 use tokio::{
     io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
@@ -39,6 +42,7 @@ const SOCK_BUF_SZ: usize = 212992;
 /// Max TCP packet size is 65535
 const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
 
+#[derive(Clone, Copy)]
 enum DerpVersion {
     V1,
     V2,
@@ -69,6 +73,7 @@ impl DerpConnection {
 
 /// Function determines whether to use plain TCP socket or TCP over TLS socket, initiates the connection to
 /// the server. TLS connection is default. In order to ignore tls, set url scheme to `http://`.
+/// With [DerpTransport::Quic] QUIC is tried first, falling back to TCP when it fails.
 /// The function returns sender and receiver for communicating with other DERP peers and thread handles
 /// Note that this function spawns 2 tasks
 pub async fn connect_http_and_start(
@@ -77,6 +82,18 @@ pub async fn connect_http_and_start(
     ip: SocketAddr,
     derp_config: Config,
 ) -> Result<DerpConnection, Error> {
+    // QUIC cannot go through the proxies of the TCP connections
+    if derp_config.transport == DerpTransport::Quic && socket_pool.proxy().is_none() {
+        telio_log_debug!("Trying to connect to derp over QUIC");
+        match connect_quic_and_start(&socket_pool, addr, ip, &derp_config)
+            .boxed()
+            .await
+        {
+            Ok(connection) => return Ok(connection),
+            Err(e) => telio_log_warn!("Failed to connect to derp over QUIC, using TCP: {e}"),
+        }
+    }
+
     // First try to use derp v2, with poll keepalives if the feature is enabled
     if derp_config.server_keepalives.poll_keepalive {
         telio_log_debug!("Trying to connect to derp V2");
@@ -99,38 +116,91 @@ pub async fn connect_http_and_start(
         .await
 }
 
-async fn try_connect(
-    socket_pool: Arc<SocketPool>,
-    ip: SocketAddr,
+/// Connect over a QUIC stream, skipping the servers which failed over QUIC recently
+async fn connect_quic_and_start(
+    socket_pool: &SocketPool,
     addr: &str,
+    ip: SocketAddr,
     derp_config: &Config,
-    derp_version: DerpVersion,
 ) -> Result<DerpConnection, Error> {
+    if !quic::should_try_quic(ip) {
+        return Err(IoError::other("QUIC failed recently").into());
+    }
+
     let u = Url::parse(addr)?;
-    let hostname = match u.host() {
-        None => return Err(IoError::other("addr host is empty").into()),
-        Some(hostname) => match hostname {
+    let hostname = parse_hostname(&u)?;
+    let hostport = format!("{hostname}:{}", u.port().unwrap_or(443));
+    let derp_version = if derp_config.server_keepalives.poll_keepalive {
+        DerpVersion::V2
+    } else {
+        DerpVersion::V1
+    };
+
+    let stream = timeout(
+        quic::QUIC_CONNECT_TIMEOUT.min(derp_config.timeout),
+        QuicStream::connect(
+            socket_pool,
+            ip,
+            &hostname,
+            derp_config.use_built_in_root_certificates,
+        ),
+    )
+    .await
+    .map_err(Error::from)
+    .and_then(|stream| stream);
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            quic::record_quic_result(ip, false);
+            return Err(e);
+        }
+    };
+    let addr = stream.addr();
+    let connection = Box::pin(connect_and_start(
+        stream,
+        addr,
+        derp_config.secret_key.clone(),
+        derp_config.server_keepalives,
+        &hostport,
+        derp_version,
+    ))
+    .await;
+    quic::record_quic_result(ip, connection.is_ok());
+    connection
+}
+
+fn parse_hostname(u: &Url) -> Result<String, Error> {
+    match u.host() {
+        None => Err(IoError::other("addr host is empty").into()),
+        Some(hostname) => Ok(match hostname {
             Host::Domain(hostname) => String::from(hostname),
             Host::Ipv4(hostname) => hostname.to_string(),
             Host::Ipv6(hostname) => hostname.to_string(),
-        },
-    };
+        }),
+    }
+}
 
-    let port = match u.port() {
-        None => match u.scheme() {
-            "http" => 80,
-            _ => 443,
-        },
-        Some(port) => port,
-    };
-    let hostport = format!("{hostname}:{port}");
+async fn try_connect(
+    socket_pool: Arc<SocketPool>,
+    ip: SocketAddr,
+    addr: &str,
+    derp_config: &Config,
+    derp_version: DerpVersion,
+) -> Result<DerpConnection, Error> {
+    let u = Url::parse(addr)?;
+    let hostname = parse_hostname(&u)?;
+    let hostport = format!("{hostname}:{}", parse_port(&u));
 
     let use_tcp_keepalives = matches!(derp_version, DerpVersion::V1);
-    let socket = socket_pool.new_external_tcp_v4(Some(build_tcp_parameters(use_tcp_keepalives)))?;
-    let stream = timeout(derp_config.timeout, socket.connect(ip)).await??;
+    let stream = timeout(
+        derp_config.timeout,
+        socket_pool.connect_external_tcp_v4(ip, Some(build_tcp_parameters(use_tcp_keepalives))),
+    )
+    .await??;
+    // Peer address of the stream is the proxy one, when a proxy is used
     let addr = PairAddr {
         local: stream.local_addr()?,
-        remote: stream.peer_addr()?,
+        remote: ip,
     };
 
     match u.scheme() {
@@ -146,17 +216,7 @@ async fn try_connect(
             .await
         }
         _ => {
-            let config = if derp_config.use_built_in_root_certificates {
-                let root_store: RootCertStore = TLS_SERVER_ROOTS.iter().cloned().collect();
-
-                let config = ClientConfig::builder()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth();
-
-                TlsConnector::from(Arc::new(config))
-            } else {
-                TlsConnector::from(Arc::new(ClientConfig::with_platform_verifier()))
-            };
+            let config = tls_connector(derp_config.use_built_in_root_certificates);
 
             let server_name =
                 ServerName::try_from(hostname).map_err(|_| Error::InvalidServerName)?;
@@ -174,6 +234,30 @@ async fn try_connect(
     }
 }
 
+fn parse_port(u: &Url) -> u16 {
+    match u.port() {
+        None => match u.scheme() {
+            "http" => 80,
+            _ => 443,
+        },
+        Some(port) => port,
+    }
+}
+
+fn tls_connector(use_built_in_root_certificates: bool) -> TlsConnector {
+    if use_built_in_root_certificates {
+        let root_store: RootCertStore = TLS_SERVER_ROOTS.iter().cloned().collect();
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        TlsConnector::from(Arc::new(config))
+    } else {
+        TlsConnector::from(Arc::new(ClientConfig::with_platform_verifier()))
+    }
+}
+
 async fn connect_and_start<RW: AsyncRead + AsyncWrite + Send + 'static>(
     stream: RW,
     addr: PairAddr,
@@ -193,8 +277,22 @@ async fn connect_and_start<RW: AsyncRead + AsyncWrite + Send + 'static>(
     ))
     .await?;
 
-    let mut reader = Cursor::new(leftovers).chain(reader);
+    let reader = Cursor::new(leftovers).chain(reader);
+
+    start_connection(reader, writer, addr, secret_key, server_keepalives).await
+}
 
+/// Exchange the keys over the upgraded connection and spawn the tasks reading and writing it
+async fn start_connection<
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+>(
+    mut reader: R,
+    mut writer: W,
+    addr: PairAddr,
+    secret_key: SecretKey,
+    server_keepalives: DerpKeepaliveConfig,
+) -> Result<DerpConnection, Error> {
     exchange_keys(&mut reader, &mut writer, secret_key).await?;
 
     read_server_info(&mut reader).await?;
@@ -0,0 +1,629 @@
+//! DERP over HTTPS long-polling.
+//!
+//! Some captive networks let only plain HTTPS requests and responses through, blocking UDP and
+//! the upgraded TCP stream alike. The DERP protocol itself is unchanged, its bytes are carried by
+//! the requests of a session identified by a random id. The server sends its bytes in the
+//! chunked responses to long-polling GET requests, ending a response from time to time, and the
+//! client sends its bytes in the bodies of POST requests. Each direction has a connection of its
+//! own, as HTTP/1.1 sends no request before the response to the previous one is done.
+//!
+//! The servers, or gateways in front of them, have to serve the endpoint described in
+//! `docs/derp_long_poll.md`.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use telio_sockets::{SocketPool, TcpParams};
+use telio_utils::telio_log_debug;
+use tokio::{
+    io::{
+        duplex, split, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+        AsyncWriteExt, BufReader, DuplexStream, ReadBuf, ReadHalf, WriteHalf,
+    },
+    task::JoinHandle,
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+use super::proto::{Error, PairAddr};
+
+/// Path of the long-polling requests
+const LONG_POLL_PATH: &str = "/derp/poll";
+
+/// Bytes buffered between the DERP connection and the requests, also the max size of a POST body
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Max size of the status line and the headers of a response
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+
+/// Max size of a line of a chunked body
+const MAX_LINE_LEN: u64 = 1024;
+
+const MAX_HEADERS: usize = 32;
+
+/// Connection to the server, over TLS or not
+trait HttpConnection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> HttpConnection for T {}
+
+type Connection = BufReader<Box<dyn HttpConnection>>;
+
+/// DERP byte stream carried by long-polling requests, owning the task sending them.
+///
+/// The stream ends when any of the requests fails.
+pub struct LongPollStream {
+    stream: DuplexStream,
+    addr: PairAddr,
+    task: JoinHandle<()>,
+}
+
+/// Requests of a session
+struct Session {
+    id: String,
+    host: String,
+    headers: String,
+}
+
+/// Length of a response body
+#[derive(Debug, PartialEq, Eq)]
+enum Body {
+    Chunked,
+    Length(u64),
+}
+
+impl LongPollStream {
+    /// Open the connections of a new session with the server at `addr`.
+    ///
+    /// `tls` is none for plain HTTP servers. `headers` are lines added to every request, e.g. to
+    /// tell the server the keepalives of the DERP connection.
+    pub async fn connect(
+        socket_pool: &SocketPool,
+        addr: SocketAddr,
+        tls: Option<(TlsConnector, ServerName<'static>)>,
+        tcp_params: impl Fn() -> TcpParams,
+        host: &str,
+        headers: String,
+    ) -> Result<Self, Error> {
+        let (poll_conn, local) = open(socket_pool, addr, tls.clone(), tcp_params()).await?;
+        let (send_conn, _) = open(socket_pool, addr, tls, tcp_params()).await?;
+        let session = Session {
+            id: format!("{:032x}", rand::random::<u128>()),
+            host: host.to_owned(),
+            headers,
+        };
+        telio_log_debug!("Long-polling session {} with {addr}", session.id);
+
+        let (stream, tunnel) = duplex(BUFFER_SIZE);
+        let task = tokio::spawn(async move {
+            let (tunnel_read, tunnel_write) = split(tunnel);
+            // Whichever direction fails first ends the whole stream
+            let res = tokio::select! {
+                res = receive(poll_conn, &session, tunnel_write) => res,
+                res = send(send_conn, &session, tunnel_read) => res,
+            };
+            if let Err(e) = res {
+                telio_log_debug!("Long-polling session {} ended: {e}", session.id);
+            }
+        });
+
+        Ok(Self {
+            stream,
+            addr: PairAddr {
+                local,
+                remote: addr,
+            },
+            task,
+        })
+    }
+
+    /// Local and remote addresses of the polling connection
+    pub fn addr(&self) -> PairAddr {
+        self.addr
+    }
+}
+
+impl Drop for LongPollStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl AsyncRead for LongPollStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.stream), cx, buf)
+    }
+}
+
+impl AsyncWrite for LongPollStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.stream), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.stream), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.stream), cx)
+    }
+}
+
+impl Session {
+    fn request(&self, method: &str, body_len: Option<usize>) -> String {
+        let mut request = format!(
+            "{method} {LONG_POLL_PATH}?session={} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            User-Agent: telio/{} {}\r\n\
+            {}",
+            self.id,
+            self.host,
+            telio_utils::version_tag(),
+            std::env::consts::OS,
+            self.headers,
+        );
+        if let Some(len) = body_len {
+            request.push_str(&format!(
+                "Content-Type: application/octet-stream\r\n\
+                Content-Length: {len}\r\n"
+            ));
+        }
+        request.push_str("\r\n");
+        request
+    }
+}
+
+async fn open(
+    socket_pool: &SocketPool,
+    addr: SocketAddr,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    tcp_params: TcpParams,
+) -> Result<(Connection, SocketAddr), Error> {
+    let stream = socket_pool
+        .connect_external_tcp_v4(addr, Some(tcp_params))
+        .await?;
+    let local = stream.local_addr()?;
+    let conn: Box<dyn HttpConnection> = match tls {
+        Some((connector, server_name)) => Box::new(connector.connect(server_name, stream).await?),
+        None => Box::new(stream),
+    };
+    Ok((BufReader::new(conn), local))
+}
+
+/// Poll the server for its bytes, until a request fails
+async fn receive(
+    mut conn: Connection,
+    session: &Session,
+    mut tunnel: WriteHalf<DuplexStream>,
+) -> Result<(), Error> {
+    loop {
+        conn.write_all(session.request("GET", None).as_bytes())
+            .await?;
+        conn.flush().await?;
+        let body = read_head(&mut conn).await?;
+        read_body(&mut conn, body, &mut tunnel).await?;
+    }
+}
+
+/// Post the bytes of the DERP connection to the server, until it is closed or a request fails
+async fn send(
+    mut conn: Connection,
+    session: &Session,
+    mut tunnel: ReadHalf<DuplexStream>,
+) -> Result<(), Error> {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let len = tunnel.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        let body = buf
+            .get(..len)
+            .ok_or_else(|| io::Error::other("Out of bounds index for data buffer"))?;
+        conn.write_all(session.request("POST", Some(len)).as_bytes())
+            .await?;
+        conn.write_all(body).await?;
+        conn.flush().await?;
+        let body = read_head(&mut conn).await?;
+        read_body(&mut conn, body, &mut tokio::io::sink()).await?;
+    }
+}
+
+/// Read the status line and the headers of a successful response
+async fn read_head<C: AsyncBufRead + Unpin>(conn: &mut C) -> Result<Body, Error> {
+    let mut head = Vec::new();
+    let mut limited = conn.take(MAX_HEAD_LEN);
+    while !head.ends_with(b"\r\n\r\n") {
+        if limited.read_until(b'\n', &mut head).await? == 0 {
+            return Err(
+                io::Error::new(ErrorKind::UnexpectedEof, "HTTP response head not full").into(),
+            );
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    if response.parse(&head)?.is_partial() {
+        return Err(io::Error::other("HTTP response not full").into());
+    }
+    match response.code {
+        Some(200..=299) => (),
+        code => {
+            return Err(
+                io::Error::other(format!("Long-polling request failed with {code:?}")).into(),
+            )
+        }
+    }
+
+    let mut body = Body::Length(0);
+    for header in response.headers.iter() {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("transfer-encoding")
+            && value.to_ascii_lowercase().contains("chunked")
+        {
+            return Ok(Body::Chunked);
+        }
+        if header.name.eq_ignore_ascii_case("content-length") {
+            let len = value
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::other("Malformed Content-Length"))?;
+            body = Body::Length(len);
+        }
+    }
+    Ok(body)
+}
+
+/// Copy the response `body` to `out`
+async fn read_body<C: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    conn: &mut C,
+    body: Body,
+    out: &mut W,
+) -> Result<(), Error> {
+    if let Body::Length(len) = body {
+        return copy_exact(conn, len, out).await;
+    }
+
+    loop {
+        let len = chunk_size(&read_line(conn).await?)?;
+        if len == 0 {
+            // Optional trailers, ending with an empty line
+            while !read_line(conn).await?.is_empty() {}
+            return Ok(());
+        }
+        copy_exact(conn, len, out).await?;
+        if !read_line(conn).await?.is_empty() {
+            return Err(io::Error::other("Malformed chunk").into());
+        }
+    }
+}
+
+async fn copy_exact<C: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    conn: &mut C,
+    len: u64,
+    out: &mut W,
+) -> Result<(), Error> {
+    let copied = tokio::io::copy_buf(&mut conn.take(len), out).await?;
+    if copied < len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "HTTP response body not full").into());
+    }
+    Ok(())
+}
+
+/// Read a line of a chunked body, without its line ending
+async fn read_line<C: AsyncBufRead + Unpin>(conn: &mut C) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
+    conn.take(MAX_LINE_LEN).read_until(b'\n', &mut line).await?;
+    match line.strip_suffix(&b"\r\n"[..]) {
+        Some(line) => Ok(line.to_vec()),
+        None => Err(io::Error::new(ErrorKind::UnexpectedEof, "Malformed chunked body").into()),
+    }
+}
+
+/// Size of a chunk from its size line, ignoring the extensions
+fn chunk_size(line: &[u8]) -> Result<u64, Error> {
+    let size = line.split(|b| *b == b';').next().unwrap_or_default();
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+        .ok_or_else(|| io::Error::other("Malformed chunk size").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derp::{http::connect_long_poll_and_start, Config};
+    use futures::stream;
+    use http_body_util::{Either, Full, StreamBody};
+    use hyper::{
+        body::{Bytes, Frame, Incoming},
+        server::conn::http1,
+        service::service_fn,
+        Method, Request, Response, StatusCode,
+    };
+    use hyper_util::rt::TokioIo;
+    use std::{convert::Infallible, net::Ipv4Addr, sync::Arc, time::Duration};
+    use telio_crypto::SecretKey;
+    use telio_sockets::NativeProtector;
+    use tokio::{
+        net::TcpListener,
+        sync::{mpsc, Mutex},
+    };
+
+    type TestBody = Either<
+        Full<Bytes>,
+        StreamBody<stream::Iter<std::vec::IntoIter<Result<Frame<Bytes>, Infallible>>>>,
+    >;
+
+    /// Server answering the polls with the chunks queued in `downstream`, and queuing the posted
+    /// bodies in `upstream`
+    async fn start_server(
+        downstream: mpsc::UnboundedReceiver<Vec<&'static str>>,
+        upstream: mpsc::UnboundedSender<Bytes>,
+        status: StatusCode,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let downstream = Arc::new(Mutex::new(downstream));
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let downstream = downstream.clone();
+                let upstream = upstream.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |request: Request<Incoming>| {
+                        let downstream = downstream.clone();
+                        let upstream = upstream.clone();
+                        async move {
+                            assert_eq!(request.uri().path(), LONG_POLL_PATH);
+                            assert!(request.uri().query().unwrap().starts_with("session="));
+                            assert_eq!(request.headers().get("Poll-Keepalive").unwrap(), "2");
+                            if status != StatusCode::OK {
+                                let mut response = Response::new(TestBody::Left(Full::default()));
+                                *response.status_mut() = status;
+                                return Ok::<_, Infallible>(response);
+                            }
+
+                            if request.method() == Method::POST {
+                                let body = http_body_util::BodyExt::collect(request.into_body())
+                                    .await
+                                    .unwrap()
+                                    .to_bytes();
+                                upstream.send(body).unwrap();
+                                return Ok(Response::new(TestBody::Left(Full::default())));
+                            }
+
+                            let chunks = downstream.lock().await.recv().await.unwrap_or_default();
+                            let frames: Vec<Result<Frame<Bytes>, Infallible>> = chunks
+                                .into_iter()
+                                .map(|chunk| Ok(Frame::data(Bytes::from(chunk))))
+                                .collect();
+                            Ok(Response::new(TestBody::Right(StreamBody::new(
+                                stream::iter(frames),
+                            ))))
+                        }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn socket_pool() -> SocketPool {
+        SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn bytes_are_carried_both_ways() {
+        let (downstream_tx, downstream_rx) = mpsc::unbounded_channel();
+        let (upstream_tx, mut upstream_rx) = mpsc::unbounded_channel();
+        let addr = start_server(downstream_rx, upstream_tx, StatusCode::OK).await;
+
+        let stream = LongPollStream::connect(
+            &socket_pool(),
+            addr,
+            None,
+            TcpParams::default,
+            "hostname",
+            "Poll-Keepalive: 2\r\n".to_owned(),
+        )
+        .await
+        .unwrap();
+        let (mut reader, mut writer) = split(stream);
+
+        // Bytes of consecutive responses make a single stream
+        downstream_tx.send(vec!["hello", " "]).unwrap();
+        downstream_tx.send(vec!["world"]).unwrap();
+        let mut received = [0u8; 11];
+        reader.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello world");
+
+        writer.write_all(b"ping").await.unwrap();
+        assert_eq!(upstream_rx.recv().await.unwrap(), Bytes::from("ping"));
+        writer.write_all(b"pong").await.unwrap();
+        assert_eq!(upstream_rx.recv().await.unwrap(), Bytes::from("pong"));
+    }
+
+    #[tokio::test]
+    async fn stream_ends_when_a_request_fails() {
+        let (_downstream_tx, downstream_rx) = mpsc::unbounded_channel();
+        let (upstream_tx, _upstream_rx) = mpsc::unbounded_channel();
+        let addr = start_server(downstream_rx, upstream_tx, StatusCode::NOT_FOUND).await;
+
+        let mut stream = LongPollStream::connect(
+            &socket_pool(),
+            addr,
+            None,
+            TcpParams::default,
+            "hostname",
+            "Poll-Keepalive: 2\r\n".to_owned(),
+        )
+        .await
+        .unwrap();
+
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    /// Server bridging the long-polling requests into the server end of a DERP connection
+    async fn start_bridge(derp: DuplexStream) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (derp_read, derp_write) = split(derp);
+        let derp_read = Arc::new(Mutex::new(derp_read));
+        let derp_write = Arc::new(Mutex::new(derp_write));
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let derp_read = derp_read.clone();
+                let derp_write = derp_write.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |request: Request<Incoming>| {
+                        let derp_read = derp_read.clone();
+                        let derp_write = derp_write.clone();
+                        async move {
+                            assert_eq!(request.uri().path(), LONG_POLL_PATH);
+                            if request.method() == Method::POST {
+                                let body = http_body_util::BodyExt::collect(request.into_body())
+                                    .await
+                                    .unwrap()
+                                    .to_bytes();
+                                derp_write.lock().await.write_all(&body).await.unwrap();
+                                return Ok::<_, Infallible>(
+                                    Response::new(Full::<Bytes>::default()),
+                                );
+                            }
+
+                            let mut buf = vec![0u8; BUFFER_SIZE];
+                            let len = derp_read.lock().await.read(&mut buf).await.unwrap();
+                            buf.truncate(len);
+                            Ok(Response::new(Full::new(Bytes::from(buf))))
+                        }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    async fn read_derp_frame(conn: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let frame_type = conn.read_u8().await.unwrap();
+        let mut frame = vec![0u8; conn.read_u32().await.unwrap() as usize];
+        conn.read_exact(&mut frame).await.unwrap();
+        (frame_type, frame)
+    }
+
+    async fn write_derp_frame(conn: &mut DuplexStream, frame_type: u8, frame: &[u8]) {
+        conn.write_u8(frame_type).await.unwrap();
+        conn.write_u32(frame.len() as u32).await.unwrap();
+        conn.write_all(frame).await.unwrap();
+    }
+
+    /// Server end of a DERP connection, sending the packets back to their senders
+    async fn echo_derp_server(mut conn: DuplexStream) {
+        const SERVER_KEY: u8 = 0x01;
+        const CLIENT_INFO: u8 = 0x02;
+        const SERVER_INFO: u8 = 0x03;
+        const SEND_PACKET: u8 = 0x04;
+        const RECV_PACKET: u8 = 0x05;
+
+        let server_key = [
+            b"DERP\xF0\x9F\x94\x91".as_slice(),
+            &SecretKey::gen().public().0,
+        ]
+        .concat();
+        write_derp_frame(&mut conn, SERVER_KEY, &server_key).await;
+        let (frame_type, client_info) = read_derp_frame(&mut conn).await;
+        assert_eq!(frame_type, CLIENT_INFO);
+        let sender = client_info[..32].to_vec();
+        write_derp_frame(&mut conn, SERVER_INFO, &[]).await;
+
+        loop {
+            let (frame_type, packet) = read_derp_frame(&mut conn).await;
+            assert_eq!(frame_type, SEND_PACKET);
+            let echo = [&sender[..], &packet[32..]].concat();
+            write_derp_frame(&mut conn, RECV_PACKET, &echo).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn derp_connection_is_established_over_long_polling() {
+        let (client_end, server_end) = duplex(BUFFER_SIZE);
+        tokio::spawn(echo_derp_server(server_end));
+        let addr = start_bridge(client_end).await;
+
+        let secret_key = SecretKey::gen();
+        let mut conn = connect_long_poll_and_start(
+            Arc::new(socket_pool()),
+            &format!("http://{addr}"),
+            addr,
+            Config {
+                secret_key: secret_key.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        for payload in [b"first".to_vec(), vec![7; 2000]] {
+            let peer = SecretKey::gen().public();
+            conn.comms_relayed
+                .tx
+                .send((peer, payload.clone()))
+                .await
+                .unwrap();
+            let received =
+                tokio::time::timeout(Duration::from_secs(5), conn.comms_relayed.rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(received, (secret_key.public(), payload));
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_decoded() {
+        let response = b"4;ext=1\r\nDERP\r\n6\r\n frame\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let mut conn = BufReader::new(&response[..]);
+        let mut out = Vec::new();
+
+        read_body(&mut conn, Body::Chunked, &mut out).await.unwrap();
+        assert_eq!(out, b"DERP frame");
+
+        // The next response is left untouched
+        let mut rest = Vec::new();
+        conn.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"next");
+
+        let mut conn = BufReader::new(&b"zz\r\n"[..]);
+        assert!(read_body(&mut conn, Body::Chunked, &mut Vec::new())
+            .await
+            .is_err());
+    }
+}
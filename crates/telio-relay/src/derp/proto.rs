@@ -156,6 +156,12 @@ pub enum Error {
     /// Url parse error
     #[error("Url parse error: {0}")]
     UrlParseError(#[from] url::ParseError),
+    /// Failed to start a QUIC connection
+    #[error("QUIC connect error: {0}")]
+    QuicConnectError(#[from] quinn::ConnectError),
+    /// QUIC connection failed
+    #[error("QUIC connection error: {0}")]
+    QuicConnectionError(#[from] quinn::ConnectionError),
 }
 
 impl From<Error> for RelayConnectionChangeReason {
@@ -0,0 +1,226 @@
+//! DERP over QUIC.
+//!
+//! Relayed traffic over TCP suffers from head-of-line blocking and slow loss recovery on lossy
+//! mobile links. The DERP protocol itself is unchanged, it runs over a single bidirectional
+//! QUIC stream. TLS sessions are remembered across connections, so that reconnecting after a
+//! network change resumes them. Early data is not sent, as 0-RTT data can be replayed by anyone
+//! on the path and DERP frames are not idempotent.
+//!
+//! Where UDP is blocked the handshake never completes, so it is given less time than the TCP
+//! connections and a server failing over QUIC is reached over TCP for a while before QUIC is
+//! tried again.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, LazyLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+use quinn::{
+    crypto::rustls::QuicClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream,
+    TokioRuntime,
+};
+use rustls_platform_verifier::ConfigVerifierExt;
+use telio_sockets::{ExternalGuard, SocketPool};
+use telio_utils::{telio_log_debug, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::{
+    client::{ClientSessionMemoryCache, Resumption},
+    ClientConfig, RootCertStore,
+};
+use webpki_roots::TLS_SERVER_ROOTS;
+
+use super::proto::{Error, PairAddr};
+
+/// ALPN identifying DERP over QUIC
+const DERP_ALPN: &[u8] = b"derp";
+
+/// Number of DERP servers whose TLS sessions are remembered
+const SESSION_CACHE_SIZE: usize = 32;
+
+/// Time the QUIC handshake is given before falling back to TCP
+pub const QUIC_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Time a server which failed over QUIC is reached over TCP only
+const QUIC_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// TLS sessions shared by all DERP connections of the process, used for session resumption
+static SESSIONS: LazyLock<Arc<ClientSessionMemoryCache>> =
+    LazyLock::new(|| Arc::new(ClientSessionMemoryCache::new(SESSION_CACHE_SIZE)));
+
+/// Servers which failed over QUIC, shared by all DERP connections of the process
+static FAILURES: LazyLock<Mutex<QuicFailures>> = LazyLock::new(Default::default);
+
+/// Last QUIC failure of the servers
+#[derive(Default)]
+struct QuicFailures {
+    failed: HashMap<SocketAddr, Instant>,
+}
+
+impl QuicFailures {
+    fn should_try(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        self.failed
+            .retain(|_, at| now.saturating_duration_since(*at) < QUIC_RETRY_AFTER);
+        !self.failed.contains_key(&addr)
+    }
+
+    fn record(&mut self, addr: SocketAddr, success: bool, now: Instant) {
+        if success {
+            self.failed.remove(&addr);
+        } else {
+            self.failed.insert(addr, now);
+        }
+    }
+}
+
+/// Whether the server at `addr` is to be tried over QUIC, i.e. it did not fail recently
+pub fn should_try_quic(addr: SocketAddr) -> bool {
+    FAILURES.lock().should_try(addr, Instant::now())
+}
+
+/// Remember the outcome of connecting to the server at `addr` over QUIC
+pub fn record_quic_result(addr: SocketAddr, success: bool) {
+    FAILURES.lock().record(addr, success, Instant::now());
+}
+
+/// Local address of the socket reaching `remote`, of the same family
+fn bind_addr(remote: SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Bidirectional QUIC stream carrying DERP, owning everything its connection needs
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    addr: PairAddr,
+    _connection: Connection,
+    _endpoint: Endpoint,
+    _guard: ExternalGuard,
+}
+
+impl QuicStream {
+    /// Connect to the DERP server at `addr` and open the stream
+    pub async fn connect(
+        socket_pool: &SocketPool,
+        addr: SocketAddr,
+        hostname: &str,
+        use_built_in_root_certificates: bool,
+    ) -> Result<Self, Error> {
+        let mut tls = if use_built_in_root_certificates {
+            let root_store: RootCertStore = TLS_SERVER_ROOTS.iter().cloned().collect();
+            ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth()
+        } else {
+            ClientConfig::with_platform_verifier()
+        };
+        tls.alpn_protocols = vec![DERP_ALPN.to_vec()];
+        tls.resumption = Resumption::store(SESSIONS.clone());
+        let tls = QuicClientConfig::try_from(tls).map_err(io::Error::other)?;
+
+        let socket = socket_pool.new_external_udp(bind_addr(addr), None).await?;
+        let (socket, guard) = socket.into_parts();
+        let local = socket.local_addr()?;
+        let mut endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            socket.into_std()?,
+            Arc::new(TokioRuntime),
+        )?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+
+        let connection = endpoint.connect(addr, hostname)?.await?;
+        telio_log_debug!("QUIC connection to {addr} established");
+
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self {
+            send,
+            recv,
+            addr: PairAddr {
+                local,
+                remote: connection.remote_address(),
+            },
+            _connection: connection,
+            _endpoint: endpoint,
+            _guard: guard,
+        })
+    }
+
+    /// Local and remote addresses of the connection
+    pub fn addr(&self) -> PairAddr {
+        self.addr
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_server_is_retried_after_a_while() {
+        let mut failures = QuicFailures::default();
+        let server: SocketAddr = ([10, 0, 0, 1], 8765).into();
+        let other: SocketAddr = ([10, 0, 0, 2], 8765).into();
+        let now = Instant::now();
+
+        assert!(failures.should_try(server, now));
+        failures.record(server, false, now);
+        assert!(!failures.should_try(server, now + Duration::from_secs(1)));
+        assert!(failures.should_try(other, now + Duration::from_secs(1)));
+        assert!(failures.should_try(server, now + QUIC_RETRY_AFTER));
+
+        failures.record(server, false, now);
+        failures.record(server, true, now);
+        assert!(failures.should_try(server, now));
+    }
+
+    #[test]
+    fn socket_is_bound_in_the_family_of_the_server() {
+        assert_eq!(
+            bind_addr(([10, 0, 0, 1], 443).into()),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        );
+        assert_eq!(
+            bind_addr((Ipv6Addr::LOCALHOST, 443).into()),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        );
+    }
+}
@@ -6,8 +6,10 @@
 //! It defines two tasks:
 //!     derp: for direct communication with Derp,
 //!     multiplexer: for distributing Derp traffic to other crates
+//! It also provides [stream::StreamHop], carrying WireGuard traffic over TLS when UDP is blocked
 
 pub mod derp;
 pub mod multiplexer;
+pub mod stream;
 
 pub use derp::*;
@@ -0,0 +1,540 @@
+//! Wrapping of WireGuard traffic into a TCP stream, for networks blocking UDP.
+//!
+//! The exit node peer is configured with an endpoint pointing to a loopback socket owned by
+//! [StreamHop], which carries every WireGuard packet over a TLS connection to the exit node,
+//! either prefixed with its length or as a binary WebSocket message. Both look like regular
+//! HTTPS traffic on the wire.
+
+use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::{Buf, BytesMut};
+use httparse::Status;
+use rand::Rng;
+use rustls_platform_verifier::ConfigVerifierExt;
+use std::{
+    convert::{TryFrom, TryInto},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use telio_model::features::{FeatureStreamTransport, StreamTransport};
+use telio_sockets::{External, SocketPool};
+use telio_task::{Runtime, RuntimeExt, Task, WaitResponse};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use webpki_roots::TLS_SERVER_ROOTS;
+
+/// Time to set up the connection, including TLS and WebSocket handshakes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_PACKET_SIZE: usize = u16::MAX as usize;
+
+/// Longest HTTP response accepted for the WebSocket upgrade
+const MAX_UPGRADE_RESPONSE_SIZE: usize = 16 * 1024;
+
+/// WebSocket opcodes
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+type Stream = TlsStream<External<TcpStream>>;
+
+/// Relay between the WireGuard socket and an exit node reachable over a TCP stream only
+pub struct StreamHop {
+    task: Task<State>,
+    local_addr: SocketAddr,
+}
+
+struct State {
+    transport: StreamTransport,
+    /// Loopback socket, used as the endpoint of the exit node peer
+    local: UdpSocket,
+    reader: ReadHalf<Stream>,
+    writer: WriteHalf<Stream>,
+    /// Address of the WireGuard socket, learned from the first outgoing packet
+    wg_addr: Option<SocketAddr>,
+    /// Stream was closed, nothing gets through anymore
+    closed: bool,
+    local_buf: Box<[u8; MAX_PACKET_SIZE]>,
+    /// Bytes received from the stream, which do not make up a whole frame yet
+    stream_buf: BytesMut,
+}
+
+/// Frame received from the stream
+#[derive(Debug, PartialEq, Eq)]
+enum Frame {
+    /// WireGuard packet
+    Packet(BytesMut),
+    /// WebSocket ping, which has to be answered with its payload
+    Ping(BytesMut),
+    /// WebSocket close
+    Close,
+    /// Anything else, which is ignored
+    Other,
+}
+
+impl StreamHop {
+    /// Start relaying WireGuard packets to `endpoint` over `transport`
+    pub async fn start(
+        socket_pool: &SocketPool,
+        endpoint: SocketAddr,
+        transport: StreamTransport,
+        config: &FeatureStreamTransport,
+    ) -> io::Result<Self> {
+        let local = SocketPool::new_udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None).await?;
+        let local_addr = local.local_addr()?;
+
+        let (stream, leftovers) = timeout(
+            CONNECT_TIMEOUT,
+            connect(socket_pool, endpoint, transport, config),
+        )
+        .await??;
+        let (reader, writer) = split(stream);
+
+        telio_log_info!("Starting {transport:?} stream hop to {endpoint} via {local_addr}");
+
+        Ok(Self {
+            task: Task::start(State {
+                transport,
+                local,
+                reader,
+                writer,
+                wg_addr: None,
+                closed: false,
+                local_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+                stream_buf: leftovers,
+            }),
+            local_addr,
+        })
+    }
+
+    /// Loopback address which should be used as the endpoint of the exit node peer
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the relay
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+/// Open the TLS connection and perform the WebSocket upgrade if needed, returning the bytes
+/// received after the upgrade response
+async fn connect(
+    socket_pool: &SocketPool,
+    endpoint: SocketAddr,
+    transport: StreamTransport,
+    config: &FeatureStreamTransport,
+) -> io::Result<(Stream, BytesMut)> {
+    let stream = socket_pool.connect_external_tcp(endpoint, None).await?;
+    stream.set_nodelay(true)?;
+
+    let connector = if config.use_built_in_root_certificates {
+        let root_store: RootCertStore = TLS_SERVER_ROOTS.iter().cloned().collect();
+        let config = ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    } else {
+        TlsConnector::from(Arc::new(ClientConfig::with_platform_verifier()))
+    };
+    let host = config
+        .server_name
+        .clone()
+        .unwrap_or_else(|| endpoint.ip().to_string());
+    let server_name = ServerName::try_from(host.clone())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server name"))?;
+    let mut stream = connector.connect(server_name, stream).await?;
+
+    let leftovers = match transport {
+        StreamTransport::Tls => BytesMut::new(),
+        StreamTransport::Websocket => {
+            // IPv6 literals are bracketed in the Host header
+            let host = match (&config.server_name, endpoint.ip()) {
+                (None, IpAddr::V6(ip)) => format!("[{ip}]"),
+                _ => host,
+            };
+            websocket_upgrade(&mut stream, &host, &config.websocket_path).await?
+        }
+    };
+    Ok((stream, leftovers))
+}
+
+/// Upgrade an HTTP connection to WebSocket, returning the bytes received after the response.
+///
+/// `Sec-WebSocket-Accept` is not verified, the TLS connection already guarantees that the
+/// response comes from the exit node.
+async fn websocket_upgrade<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    path: &str,
+) -> io::Result<BytesMut> {
+    let key = BASE64_STANDARD.encode(rand::thread_rng().gen::<[u8; 16]>());
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+        Host: {host}\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Key: {key}\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        User-Agent: telio/{} {}\r\n\r\n",
+        telio_utils::version_tag(),
+        std::env::consts::OS,
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&buf).map_err(io::Error::other)? {
+            Status::Complete(len) => {
+                if response.code != Some(101) {
+                    return Err(io::Error::other(format!(
+                        "WebSocket upgrade rejected with status {:?}",
+                        response.code
+                    )));
+                }
+                buf.advance(len);
+                return Ok(buf);
+            }
+            Status::Partial if buf.len() >= MAX_UPGRADE_RESPONSE_SIZE => {
+                return Err(io::Error::other("WebSocket upgrade response too long"));
+            }
+            Status::Partial => (),
+        }
+    }
+}
+
+/// Frame an outgoing packet for `transport`
+fn encode(transport: StreamTransport, packet: &[u8]) -> Vec<u8> {
+    match transport {
+        StreamTransport::Tls => {
+            let mut frame = Vec::with_capacity(2 + packet.len());
+            frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            frame.extend_from_slice(packet);
+            frame
+        }
+        StreamTransport::Websocket => websocket_frame(OPCODE_BINARY, packet),
+    }
+}
+
+/// Single masked WebSocket frame, as sent by clients
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = rand::thread_rng().gen();
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(byte, mask)| byte ^ mask),
+    );
+    frame
+}
+
+/// Take the next whole frame out of `buf`, `None` when more bytes are needed
+fn decode(transport: StreamTransport, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+    match transport {
+        StreamTransport::Tls => {
+            let Some(len) = buf
+                .get(..2)
+                .and_then(|len| len.try_into().ok())
+                .map(u16::from_be_bytes)
+            else {
+                return Ok(None);
+            };
+            if buf.len() < 2 + len as usize {
+                return Ok(None);
+            }
+            buf.advance(2);
+            Ok(Some(Frame::Packet(buf.split_to(len as usize))))
+        }
+        StreamTransport::Websocket => decode_websocket(buf),
+    }
+}
+
+fn decode_websocket(buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+    let (Some(&first), Some(&second)) = (buf.first(), buf.get(1)) else {
+        return Ok(None);
+    };
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    let masked = second & 0x80 != 0;
+
+    let (len, mut header_len) = match second & 0x7F {
+        126 => match buf.get(2..4).and_then(|len| len.try_into().ok()) {
+            Some(len) => (u16::from_be_bytes(len) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10).and_then(|len| len.try_into().ok()) {
+            Some(len) => (u64::from_be_bytes(len), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if len > MAX_PACKET_SIZE as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WebSocket frame of {len} bytes is too long"),
+        ));
+    }
+    let len = len as usize;
+
+    let mask = if masked {
+        let Some(mask) = buf
+            .get(header_len..header_len + 4)
+            .and_then(|mask| <[u8; 4]>::try_from(mask).ok())
+        else {
+            return Ok(None);
+        };
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    if buf.len() < header_len + len {
+        return Ok(None);
+    }
+    buf.advance(header_len);
+    let mut payload = buf.split_to(len);
+    if let Some(mask) = mask {
+        payload
+            .iter_mut()
+            .zip(mask.iter().cycle())
+            .for_each(|(byte, mask)| *byte ^= mask);
+    }
+
+    Ok(Some(match opcode {
+        // WireGuard packets are small enough to never be fragmented
+        OPCODE_BINARY if fin => Frame::Packet(payload),
+        OPCODE_PING => Frame::Ping(payload),
+        OPCODE_CLOSE => Frame::Close,
+        _ => Frame::Other,
+    }))
+}
+
+impl State {
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.writer.write_all(frame).await?;
+        self.writer.flush().await
+    }
+
+    /// Hand the whole frames received so far over to WireGuard
+    async fn forward_frames(&mut self) -> io::Result<()> {
+        while let Some(frame) = decode(self.transport, &mut self.stream_buf)? {
+            match frame {
+                Frame::Packet(packet) => match self.wg_addr {
+                    Some(wg_addr) => {
+                        if let Err(e) = self.local.send_to(&packet, wg_addr).await {
+                            telio_log_warn!("Failed to send to WireGuard: {e}");
+                        }
+                    }
+                    None => {
+                        telio_log_debug!("Dropping stream hop packet, WireGuard address unknown");
+                    }
+                },
+                Frame::Ping(payload) => self.send(&websocket_frame(OPCODE_PONG, &payload)).await?,
+                Frame::Close => return Err(io::ErrorKind::ConnectionAborted.into()),
+                Frame::Other => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "StreamHop";
+
+    type Err = ();
+
+    async fn wait(&mut self) -> WaitResponse<'_, Self::Err> {
+        if self.closed {
+            return Self::sleep_forever().await;
+        }
+
+        tokio::select! {
+            // Outgoing packets from WireGuard towards the exit node
+            res = self.local.recv_from(self.local_buf.as_mut_slice()) => {
+                match res {
+                    Ok((n, from)) => {
+                        if self.wg_addr != Some(from) {
+                            telio_log_debug!("Stream hop WireGuard address: {from}");
+                            self.wg_addr = Some(from);
+                        }
+                        if let Some(packet) = self.local_buf.get(..n) {
+                            let frame = encode(self.transport, packet);
+                            if let Err(e) = self.send(&frame).await {
+                                telio_log_warn!("Failed to send to the stream hop: {e}");
+                            }
+                        }
+                    }
+                    Err(e) => telio_log_warn!("Failed to receive from WireGuard: {e}"),
+                }
+            }
+            // Incoming packets from the exit node back to WireGuard
+            res = self.reader.read_buf(&mut self.stream_buf) => {
+                let res = match res {
+                    Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(_) => self.forward_frames().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    telio_log_warn!("Stream hop closed: {e}");
+                    self.closed = true;
+                }
+            }
+        }
+
+        Self::next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_frames_roundtrip() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&encode(StreamTransport::Tls, &[1, 2, 3]));
+        buf.extend_from_slice(&encode(StreamTransport::Tls, &[4, 5]));
+        let last = encode(StreamTransport::Tls, &[6, 7, 8, 9]);
+        buf.extend_from_slice(&last[..3]);
+
+        assert_eq!(
+            decode(StreamTransport::Tls, &mut buf).unwrap(),
+            Some(Frame::Packet(BytesMut::from(&[1, 2, 3][..])))
+        );
+        assert_eq!(
+            decode(StreamTransport::Tls, &mut buf).unwrap(),
+            Some(Frame::Packet(BytesMut::from(&[4, 5][..])))
+        );
+        assert_eq!(decode(StreamTransport::Tls, &mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&last[3..]);
+        assert_eq!(
+            decode(StreamTransport::Tls, &mut buf).unwrap(),
+            Some(Frame::Packet(BytesMut::from(&[6, 7, 8, 9][..])))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn websocket_frames_roundtrip() {
+        for len in [0, 125, 126, 1420, MAX_PACKET_SIZE] {
+            let packet: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = encode(StreamTransport::Websocket, &packet);
+            assert_eq!(frame[1] & 0x80, 0x80, "client frames are masked");
+
+            let mut buf = BytesMut::from(&frame[..frame.len() - 1]);
+            assert_eq!(decode(StreamTransport::Websocket, &mut buf).unwrap(), None);
+            buf.extend_from_slice(&frame[frame.len() - 1..]);
+            assert_eq!(
+                decode(StreamTransport::Websocket, &mut buf).unwrap(),
+                Some(Frame::Packet(BytesMut::from(packet.as_slice())))
+            );
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn websocket_control_frames() {
+        let mut buf = BytesMut::from(&[0x89, 0x02, 0xAB, 0xCD, 0x8A, 0x00, 0x88, 0x00][..]);
+        assert_eq!(
+            decode(StreamTransport::Websocket, &mut buf).unwrap(),
+            Some(Frame::Ping(BytesMut::from(&[0xAB, 0xCD][..])))
+        );
+        assert_eq!(
+            decode(StreamTransport::Websocket, &mut buf).unwrap(),
+            Some(Frame::Other)
+        );
+        assert_eq!(
+            decode(StreamTransport::Websocket, &mut buf).unwrap(),
+            Some(Frame::Close)
+        );
+    }
+
+    #[test]
+    fn websocket_rejects_oversized_frames() {
+        let mut buf = BytesMut::from(&[0x82, 0x7F, 0, 0, 0, 0, 0, 1, 0, 0][..]);
+        assert!(decode(StreamTransport::Websocket, &mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade_keeps_leftovers() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut request = vec![0u8; 1024];
+            let n = server.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_string();
+            server
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\n\
+                    Connection: Upgrade\r\n\r\n\x82\x01",
+                )
+                .await
+                .unwrap();
+            request
+        });
+
+        let leftovers = websocket_upgrade(&mut client, "vpn.example.com", "/wg")
+            .await
+            .unwrap();
+        assert_eq!(&leftovers[..], &[0x82, 0x01]);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /wg HTTP/1.1\r\n"));
+        assert!(request.contains("Host: vpn.example.com\r\n"));
+        assert!(request.contains("Sec-WebSocket-Version: 13\r\n"));
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut request = vec![0u8; 1024];
+            let _ = server.read(&mut request).await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        assert!(websocket_upgrade(&mut client, "vpn.example.com", "/wg")
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Compares the receive throughput of a single UDP socket against a `SO_REUSEPORT` group of one
+//! socket per CPU, each served by its own task.
+//!
+//! Run with `cargo bench -p telio-sockets --bench reuseport`.
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use telio_sockets::{protector::make_external_protector, SocketPool};
+    use tokio::net::UdpSocket;
+
+    const CLIENTS: usize = 16;
+    const PACKETS_PER_CLIENT: usize = 20_000;
+    const PACKET_SIZE: usize = 1280;
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Stands in for the per-packet crypto of a WireGuard worker
+    fn process(packet: &[u8]) -> u64 {
+        (0..8).fold(0u64, |acc, round| {
+            packet
+                .iter()
+                .fold(acc ^ round, |acc, b| acc.rotate_left(5) ^ u64::from(*b))
+        })
+    }
+
+    async fn run(sockets: usize) -> (u64, Duration) {
+        let pool = SocketPool::new(make_external_protector(Arc::new(|_| ())));
+        let group = pool
+            .new_external_udp_group(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                sockets,
+                None,
+            )
+            .expect("Failed to bind the socket group");
+        let addr = group[0]
+            .local_addr()
+            .expect("Failed to get the local address");
+
+        let received = Arc::new(AtomicU64::new(0));
+        let servers: Vec<_> = group
+            .into_iter()
+            .map(|socket| {
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; PACKET_SIZE];
+                    let mut checksum = 0;
+                    while let Ok(Ok(len)) =
+                        tokio::time::timeout(IDLE_TIMEOUT, socket.recv(&mut buf)).await
+                    {
+                        checksum ^= process(&buf[..len]);
+                        received.fetch_add(1, Ordering::Relaxed);
+                    }
+                    checksum
+                })
+            })
+            .collect();
+
+        let start = Instant::now();
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|_| {
+                tokio::spawn(async move {
+                    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+                        .await
+                        .expect("Failed to bind the client");
+                    socket.connect(addr).await.expect("Failed to connect");
+                    let packet = [0xa5u8; PACKET_SIZE];
+                    for _ in 0..PACKETS_PER_CLIENT {
+                        let _ = socket.send(&packet).await;
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.expect("Client panicked");
+        }
+        for server in servers {
+            server.await.expect("Server panicked");
+        }
+
+        (
+            received.load(Ordering::Relaxed),
+            start.elapsed().saturating_sub(IDLE_TIMEOUT),
+        )
+    }
+
+    pub fn main() {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(cpus)
+            .enable_all()
+            .build()
+            .expect("Failed to build the runtime");
+
+        for sockets in [1, cpus] {
+            let (received, elapsed) = rt.block_on(run(sockets));
+            println!(
+                "{sockets:>3} socket(s): {received:>8} of {} packets in {elapsed:?}, {:.0} packets/s",
+                CLIENTS * PACKETS_PER_CLIENT,
+                received as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    pub fn main() {}
+}
+
+fn main() {
+    platform::main();
+}
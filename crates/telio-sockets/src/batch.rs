@@ -0,0 +1,689 @@
+//! Batched UDP I/O on Linux.
+//!
+//! Datagrams are sent with `sendmmsg` and received with `recvmmsg`, a syscall per batch instead of
+//! one per datagram. With UDP GSO (`UDP_SEGMENT`) a buffer of equally sized datagrams to the same
+//! destination is handed to the kernel at once and split as late as possible, and with UDP GRO
+//! (`UDP_GRO`) the kernel coalesces the received datagrams of a flow into a single buffer. Where
+//! the kernel or the NIC lacks any of them, the plain syscalls are used instead.
+
+use std::{
+    convert::TryFrom,
+    io::{self, IoSlice, IoSliceMut},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsRawFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use telio_utils::{telio_log_debug, telio_log_warn};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Most datagrams sent or received by a single syscall
+pub const MAX_BATCH: usize = 64;
+
+/// Set once the kernel turns out to lack `sendmmsg` and `recvmmsg`
+static MMSG_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+/// Set once sending with GSO fails, e.g. because the NIC lacks checksum offloading
+static GSO_BROKEN: AtomicBool = AtomicBool::new(false);
+
+/// Datagrams to send to a single destination
+#[derive(Clone, Copy, Debug)]
+pub struct Transmit<'a> {
+    /// Where to send the datagrams
+    pub destination: SocketAddr,
+    /// Contents of the datagrams, back to back
+    pub contents: &'a [u8],
+    /// Size of the datagrams the `contents` are split into, the last one may be shorter. `None`
+    /// sends the `contents` as a single datagram
+    pub segment_size: Option<u16>,
+}
+
+/// Datagrams received into a single buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvMeta {
+    /// Number of the bytes received
+    pub len: usize,
+    /// Sender of the datagrams
+    pub source: SocketAddr,
+    /// Size of the datagrams coalesced by GRO, the last one may be shorter. Equal to `len` for a
+    /// single datagram
+    pub segment_size: usize,
+}
+
+/// Whether datagrams can be sent with GSO through the `socket`
+pub fn gso_supported(socket: &impl AsRawFd) -> bool {
+    if GSO_BROKEN.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safety: value and len outlive the call and len is the size of value
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            ptr::addr_of_mut!(value).cast(),
+            &mut len,
+        )
+    };
+    res == 0
+}
+
+/// Let the kernel coalesce the datagrams received by the `socket`, returns whether it is supported.
+/// The receiver has to split the buffers by [RecvMeta::segment_size] afterwards.
+pub fn enable_gro(socket: &impl AsRawFd) -> bool {
+    let value: libc::c_int = 1;
+    // Safety: value outlives the call and the length passed is its size
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            ptr::addr_of!(value).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    res == 0
+}
+
+/// Send the `transmits` through the `socket`, returns how many of them, from the start, were sent.
+/// At least one is sent unless an error is returned.
+pub fn send(socket: &impl AsRawFd, transmits: &[Transmit<'_>]) -> io::Result<usize> {
+    let fd = socket.as_raw_fd();
+    let gso = has_segments(transmits, MAX_BATCH) && gso_supported(socket);
+    // Transmits to be split without GSO are sent one by one
+    let batch_len = transmits
+        .iter()
+        .take(MAX_BATCH)
+        .take_while(|transmit| gso || transmit.segment_size.is_none())
+        .count();
+
+    if MMSG_UNSUPPORTED.load(Ordering::Relaxed) || batch_len <= 1 {
+        return match transmits.first() {
+            Some(transmit) => send_one(fd, transmit, gso).map(|()| 1),
+            None => Ok(0),
+        };
+    }
+
+    match send_mmsg(fd, transmits, batch_len) {
+        Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+            telio_log_warn!("sendmmsg is not supported, sending datagrams one by one");
+            MMSG_UNSUPPORTED.store(true, Ordering::Relaxed);
+            send(socket, transmits)
+        }
+        Err(err) if is_gso_error(&err) && has_segments(transmits, batch_len) => {
+            disable_gso(&err);
+            send(socket, transmits)
+        }
+        res => res,
+    }
+}
+
+/// Receive datagrams from the `socket` into the `bufs`, filling in the `meta` of each of them,
+/// returns how many buffers were filled
+pub fn recv(
+    socket: &impl AsRawFd,
+    bufs: &mut [IoSliceMut<'_>],
+    meta: &mut [RecvMeta],
+) -> io::Result<usize> {
+    let fd = socket.as_raw_fd();
+    if !MMSG_UNSUPPORTED.load(Ordering::Relaxed) {
+        match recv_mmsg(fd, bufs, meta) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+                telio_log_warn!("recvmmsg is not supported, receiving datagrams one by one");
+                MMSG_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            res => return res,
+        }
+    }
+
+    match (bufs.first_mut(), meta.first_mut()) {
+        (Some(buf), Some(meta)) => recv_one(fd, buf, meta).map(|()| 1),
+        _ => Ok(0),
+    }
+}
+
+/// [send] driven by the readiness of the tokio `socket`
+pub async fn send_async(socket: &UdpSocket, transmits: &[Transmit<'_>]) -> io::Result<usize> {
+    socket
+        .async_io(Interest::WRITABLE, || send(socket, transmits))
+        .await
+}
+
+/// [recv] from the tokio `socket` without waiting, clearing its readiness when nothing is queued
+pub fn try_recv(
+    socket: &UdpSocket,
+    bufs: &mut [IoSliceMut<'_>],
+    meta: &mut [RecvMeta],
+) -> io::Result<usize> {
+    socket.try_io(Interest::READABLE, || recv(socket, bufs, meta))
+}
+
+/// [recv] driven by the readiness of the tokio `socket`
+pub async fn recv_async(
+    socket: &UdpSocket,
+    bufs: &mut [IoSliceMut<'_>],
+    meta: &mut [RecvMeta],
+) -> io::Result<usize> {
+    socket
+        .async_io(Interest::READABLE, || recv(socket, bufs, meta))
+        .await
+}
+
+fn has_segments(transmits: &[Transmit<'_>], len: usize) -> bool {
+    transmits
+        .iter()
+        .take(len)
+        .any(|transmit| transmit.segment_size.is_some())
+}
+
+/// GSO is refused with EIO when the NIC cannot checksum the segments, and with EINVAL by kernels
+/// which do not know `UDP_SEGMENT`
+fn is_gso_error(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EIO) | Some(libc::EINVAL))
+}
+
+fn disable_gso(err: &io::Error) {
+    if !GSO_BROKEN.swap(true, Ordering::Relaxed) {
+        telio_log_warn!(
+            "Sending with GSO failed: {}, splitting datagrams instead",
+            err
+        );
+    }
+}
+
+/// Room for a single `c_int` control message
+const CMSG_BUF_LEN: usize = 32;
+
+#[repr(C, align(8))]
+struct CmsgBuf([u8; CMSG_BUF_LEN]);
+
+impl CmsgBuf {
+    fn new() -> Self {
+        Self([0; CMSG_BUF_LEN])
+    }
+}
+
+/// Fill in the `hdr` to send the `transmit`, with the GSO control message if it is segmented
+///
+/// Safety: the `hdr` points into the `transmit`, `name`, `iov` and `cmsg`, which have to outlive
+/// its use
+unsafe fn prepare_send<'a>(
+    hdr: &mut libc::msghdr,
+    transmit: &Transmit<'a>,
+    name: &mut SockAddr,
+    iov: &mut IoSlice<'a>,
+    cmsg: &mut CmsgBuf,
+) {
+    *name = SockAddr::from(transmit.destination);
+    *iov = IoSlice::new(transmit.contents);
+    hdr.msg_name = ptr::addr_of_mut!(name.storage).cast();
+    hdr.msg_namelen = name.len;
+    // IoSlice is guaranteed to be ABI compatible with iovec
+    hdr.msg_iov = (iov as *mut IoSlice<'_>).cast();
+    hdr.msg_iovlen = 1;
+
+    match transmit.segment_size {
+        Some(segment_size) if usize::from(segment_size) < transmit.contents.len() => {
+            hdr.msg_control = cmsg.0.as_mut_ptr().cast();
+            hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+            let cmsg_hdr = libc::CMSG_FIRSTHDR(hdr);
+            (*cmsg_hdr).cmsg_level = libc::SOL_UDP;
+            (*cmsg_hdr).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg_hdr).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg_hdr).cast::<u16>(), segment_size);
+        }
+        _ => {
+            hdr.msg_control = ptr::null_mut();
+            hdr.msg_controllen = 0;
+        }
+    }
+}
+
+fn send_mmsg(fd: RawFd, transmits: &[Transmit<'_>], len: usize) -> io::Result<usize> {
+    let transmits: Vec<_> = transmits.iter().take(len).collect();
+    let mut names: Vec<_> = transmits.iter().map(|_| SockAddr::unspecified()).collect();
+    let mut iovs: Vec<_> = transmits.iter().map(|_| IoSlice::new(&[])).collect();
+    let mut cmsgs: Vec<_> = transmits.iter().map(|_| CmsgBuf::new()).collect();
+    // Safety: mmsghdr is plain data, zeroed is a valid empty header
+    let mut hdrs: Vec<libc::mmsghdr> = transmits.iter().map(|_| unsafe { mem::zeroed() }).collect();
+
+    for ((((hdr, transmit), name), iov), cmsg) in hdrs
+        .iter_mut()
+        .zip(transmits)
+        .zip(&mut names)
+        .zip(&mut iovs)
+        .zip(&mut cmsgs)
+    {
+        // Safety: the buffers live until the end of the function
+        unsafe { prepare_send(&mut hdr.msg_hdr, transmit, name, iov, cmsg) };
+    }
+
+    // Safety: the headers and everything they point into live until the end of the function
+    let sent = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+fn send_one(fd: RawFd, transmit: &Transmit<'_>, gso: bool) -> io::Result<()> {
+    let segment_size = match transmit.segment_size {
+        Some(segment_size) if !gso && usize::from(segment_size) < transmit.contents.len() => {
+            segment_size
+        }
+        _ => {
+            let mut name = SockAddr::unspecified();
+            let mut iov = IoSlice::new(&[]);
+            let mut cmsg = CmsgBuf::new();
+            // Safety: msghdr is plain data, zeroed is a valid empty header
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            // Safety: the buffers live until the end of the function
+            unsafe { prepare_send(&mut hdr, transmit, &mut name, &mut iov, &mut cmsg) };
+            // Safety: the header and everything it points into live until the end of the function
+            if unsafe { libc::sendmsg(fd, &hdr, 0) } >= 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            match transmit.segment_size {
+                Some(_) if is_gso_error(&err) => {
+                    disable_gso(&err);
+                    return send_one(fd, transmit, false);
+                }
+                _ => return Err(err),
+            }
+        }
+    };
+
+    send_segments(transmit, segment_size, |segment| {
+        send_one(fd, segment, false)
+    })
+}
+
+/// Send the datagrams of a segmented `transmit` one by one with `send_datagram`. Once the first
+/// one is sent, the transmit counts as sent: the datagrams the socket has no buffer space left for
+/// are dropped, as the network would, rather than failing the transmit and having those already
+/// sent sent again on retry.
+fn send_segments(
+    transmit: &Transmit<'_>,
+    segment_size: u16,
+    mut send_datagram: impl FnMut(&Transmit<'_>) -> io::Result<()>,
+) -> io::Result<()> {
+    for (i, segment) in transmit
+        .contents
+        .chunks(usize::from(segment_size).max(1))
+        .enumerate()
+    {
+        let sent = send_datagram(&Transmit {
+            destination: transmit.destination,
+            contents: segment,
+            segment_size: None,
+        });
+        match sent {
+            Ok(()) => (),
+            Err(err) if i > 0 && err.kind() == io::ErrorKind::WouldBlock => {
+                telio_log_debug!(
+                    "Socket buffer full after {} segments to {}, dropping the rest",
+                    i,
+                    transmit.destination
+                );
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn recv_mmsg(fd: RawFd, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+    let len = bufs.len().min(meta.len()).min(MAX_BATCH);
+    if len == 0 {
+        return Ok(0);
+    }
+    let mut names: Vec<_> = (0..len).map(|_| SockAddr::unspecified()).collect();
+    let mut cmsgs: Vec<_> = (0..len).map(|_| CmsgBuf::new()).collect();
+    // Safety: mmsghdr is plain data, zeroed is a valid empty header
+    let mut hdrs: Vec<libc::mmsghdr> = (0..len).map(|_| unsafe { mem::zeroed() }).collect();
+
+    for (((hdr, buf), name), cmsg) in hdrs
+        .iter_mut()
+        .zip(bufs.iter_mut())
+        .zip(&mut names)
+        .zip(&mut cmsgs)
+    {
+        prepare_recv(&mut hdr.msg_hdr, buf, name, cmsg);
+    }
+
+    // Safety: the headers and everything they point into live until the end of the function
+    let received =
+        unsafe { libc::recvmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, 0, ptr::null_mut()) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let received = received as usize;
+
+    for ((hdr, name), meta) in hdrs.iter().zip(&mut names).take(received).zip(meta) {
+        name.len = hdr.msg_hdr.msg_namelen;
+        *meta = parse_recv(&hdr.msg_hdr, hdr.msg_len as usize, name)?;
+    }
+    Ok(received)
+}
+
+fn recv_one(fd: RawFd, buf: &mut IoSliceMut<'_>, meta: &mut RecvMeta) -> io::Result<()> {
+    let mut name = SockAddr::unspecified();
+    let mut cmsg = CmsgBuf::new();
+    // Safety: msghdr is plain data, zeroed is a valid empty header
+    let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+    prepare_recv(&mut hdr, buf, &mut name, &mut cmsg);
+
+    // Safety: the header and everything it points into live until the end of the function
+    let received = unsafe { libc::recvmsg(fd, &mut hdr, 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    name.len = hdr.msg_namelen;
+    *meta = parse_recv(&hdr, received as usize, &name)?;
+    Ok(())
+}
+
+fn prepare_recv(
+    hdr: &mut libc::msghdr,
+    buf: &mut IoSliceMut<'_>,
+    name: &mut SockAddr,
+    cmsg: &mut CmsgBuf,
+) {
+    hdr.msg_name = ptr::addr_of_mut!(name.storage).cast();
+    hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // IoSliceMut is guaranteed to be ABI compatible with iovec
+    hdr.msg_iov = (buf as *mut IoSliceMut<'_>).cast();
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = cmsg.0.as_mut_ptr().cast();
+    hdr.msg_controllen = CMSG_BUF_LEN as _;
+}
+
+fn parse_recv(hdr: &libc::msghdr, len: usize, name: &SockAddr) -> io::Result<RecvMeta> {
+    let source = name.to_socket_addr().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected source address family",
+        )
+    })?;
+
+    let mut segment_size = len;
+    // Safety: the header was filled in by the kernel, the control messages fit in its buffer
+    unsafe {
+        let mut cmsg_hdr = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg_hdr.is_null() {
+            if (*cmsg_hdr).cmsg_level == libc::SOL_UDP && (*cmsg_hdr).cmsg_type == libc::UDP_GRO {
+                let value = ptr::read_unaligned(libc::CMSG_DATA(cmsg_hdr).cast::<libc::c_int>());
+                segment_size = usize::try_from(value).unwrap_or(len);
+            }
+            cmsg_hdr = libc::CMSG_NXTHDR(hdr, cmsg_hdr);
+        }
+    }
+
+    Ok(RecvMeta {
+        len,
+        source,
+        segment_size,
+    })
+}
+
+/// Socket address in the layout of the kernel
+struct SockAddr {
+    storage: libc::sockaddr_storage,
+    len: libc::socklen_t,
+}
+
+impl SockAddr {
+    fn unspecified() -> Self {
+        Self {
+            // Safety: sockaddr_storage is plain data, zeroed is AF_UNSPEC
+            storage: unsafe { mem::zeroed() },
+            len: 0,
+        }
+    }
+
+    fn to_socket_addr(&self) -> Option<SocketAddr> {
+        match libc::c_int::from(self.storage.ss_family) {
+            libc::AF_INET => {
+                // Safety: the storage holds a sockaddr_in and is large and aligned enough for it
+                let addr = unsafe { &*(ptr::addr_of!(self.storage).cast::<libc::sockaddr_in>()) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // Safety: the storage holds a sockaddr_in6 and is large and aligned enough for it
+                let addr = unsafe { &*(ptr::addr_of!(self.storage).cast::<libc::sockaddr_in6>()) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let mut sock_addr = Self::unspecified();
+        match addr {
+            SocketAddr::V4(addr) => {
+                // Safety: the storage is large and aligned enough for a sockaddr_in
+                let sin = unsafe {
+                    &mut *(ptr::addr_of_mut!(sock_addr.storage).cast::<libc::sockaddr_in>())
+                };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                sock_addr.len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            }
+            SocketAddr::V6(addr) => {
+                // Safety: the storage is large and aligned enough for a sockaddr_in6
+                let sin6 = unsafe {
+                    &mut *(ptr::addr_of_mut!(sock_addr.storage).cast::<libc::sockaddr_in6>())
+                };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                sock_addr.len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            }
+        }
+        sock_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket as StdUdpSocket;
+
+    fn sockets() -> (StdUdpSocket, StdUdpSocket) {
+        let sender = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let receiver = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        (sender, receiver)
+    }
+
+    fn recv_all(receiver: &StdUdpSocket, count: usize) -> Vec<(Vec<u8>, RecvMeta)> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            let mut storage = vec![[0u8; 64]; 4];
+            let mut meta = vec![
+                RecvMeta {
+                    len: 0,
+                    source: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    segment_size: 0,
+                };
+                4
+            ];
+            let mut bufs: Vec<_> = storage.iter_mut().map(|b| IoSliceMut::new(b)).collect();
+            let n = recv(receiver, &mut bufs, &mut meta).unwrap();
+            for (buf, meta) in storage.iter().zip(&meta).take(n) {
+                received.push((buf[..meta.len].to_vec(), *meta));
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn datagrams_are_sent_and_received_in_batches() {
+        let (sender, receiver) = sockets();
+        let destination = receiver.local_addr().unwrap();
+        let transmits: Vec<_> = [&b"first"[..], b"second", b"third"]
+            .iter()
+            .map(|&contents| Transmit {
+                destination,
+                contents,
+                segment_size: None,
+            })
+            .collect();
+
+        let mut sent = 0;
+        while sent < transmits.len() {
+            sent += send(&sender, &transmits[sent..]).unwrap();
+        }
+
+        let received = recv_all(&receiver, 3);
+        let contents: Vec<_> = received.iter().map(|(buf, _)| buf.as_slice()).collect();
+        assert_eq!(contents, [&b"first"[..], b"second", b"third"]);
+        for (buf, meta) in &received {
+            assert_eq!(meta.source, sender.local_addr().unwrap());
+            assert_eq!(meta.segment_size, buf.len());
+        }
+    }
+
+    #[test]
+    fn segmented_datagrams_arrive_split() {
+        let (sender, receiver) = sockets();
+        let transmit = Transmit {
+            destination: receiver.local_addr().unwrap(),
+            contents: b"aaaabbbbcc",
+            segment_size: Some(4),
+        };
+
+        assert_eq!(send(&sender, &[transmit]).unwrap(), 1);
+
+        // Without GRO on the receiver the datagrams arrive one by one, with or without GSO
+        let received = recv_all(&receiver, 3);
+        let contents: Vec<_> = received.iter().map(|(buf, _)| buf.as_slice()).collect();
+        assert_eq!(contents, [&b"aaaa"[..], b"bbbb", b"cc"]);
+    }
+
+    #[test]
+    fn datagrams_are_sent_and_received_one_by_one_without_mmsg() {
+        let (sender, receiver) = sockets();
+        let destination = receiver.local_addr().unwrap();
+        for contents in [&b"first"[..], b"second"] {
+            let transmit = Transmit {
+                destination,
+                contents,
+                segment_size: None,
+            };
+            send_one(sender.as_raw_fd(), &transmit, false).unwrap();
+        }
+
+        for expected in [&b"first"[..], b"second"] {
+            let mut buf = [0u8; 64];
+            let mut meta = RecvMeta {
+                len: 0,
+                source: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                segment_size: 0,
+            };
+            recv_one(
+                receiver.as_raw_fd(),
+                &mut IoSliceMut::new(&mut buf),
+                &mut meta,
+            )
+            .unwrap();
+            assert_eq!(&buf[..meta.len], expected);
+            assert_eq!(meta.source, sender.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn segments_are_sent_one_by_one_without_gso() {
+        let (sender, receiver) = sockets();
+        let transmit = Transmit {
+            destination: receiver.local_addr().unwrap(),
+            contents: b"aaaabbbbcc",
+            segment_size: Some(4),
+        };
+
+        send_one(sender.as_raw_fd(), &transmit, false).unwrap();
+
+        let received = recv_all(&receiver, 3);
+        let contents: Vec<_> = received.iter().map(|(buf, _)| buf.as_slice()).collect();
+        assert_eq!(contents, [&b"aaaa"[..], b"bbbb", b"cc"]);
+    }
+
+    #[test]
+    fn segments_are_not_sent_again_once_the_buffer_is_full() {
+        let transmit = Transmit {
+            destination: SocketAddr::from((Ipv4Addr::LOCALHOST, 1)),
+            contents: b"aaaabbbbcc",
+            segment_size: Some(4),
+        };
+        let full = || io::Error::from(io::ErrorKind::WouldBlock);
+
+        // The buffer fills up after the first segment, the rest is dropped
+        let mut sent = Vec::new();
+        send_segments(&transmit, 4, |segment| {
+            if sent.is_empty() {
+                sent.push(segment.contents.to_vec());
+                Ok(())
+            } else {
+                Err(full())
+            }
+        })
+        .unwrap();
+        assert_eq!(sent, [b"aaaa".to_vec()]);
+
+        // Nothing is sent, the transmit is to be retried
+        let err = send_segments(&transmit, 4, |_| Err(full())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Other errors fail the transmit
+        let err = send_segments(&transmit, 4, |segment| match segment.contents {
+            b"aaaa" => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn queued_datagrams_are_received_without_waiting() {
+        let (sender, receiver) = sockets();
+        receiver.set_nonblocking(true).unwrap();
+        let destination = receiver.local_addr().unwrap();
+        let receiver = UdpSocket::from_std(receiver).unwrap();
+        let mut storage = vec![[0u8; 64]; 4];
+        let mut meta = vec![
+            RecvMeta {
+                len: 0,
+                source: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                segment_size: 0,
+            };
+            4
+        ];
+
+        let mut bufs: Vec<_> = storage.iter_mut().map(|b| IoSliceMut::new(b)).collect();
+        let err = try_recv(&receiver, &mut bufs, &mut meta).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        sender.send_to(b"queued", destination).unwrap();
+        receiver.readable().await.unwrap();
+        assert_eq!(try_recv(&receiver, &mut bufs, &mut meta).unwrap(), 1);
+        drop(bufs);
+        assert_eq!(&storage[0][..meta[0].len], b"queued");
+    }
+}
@@ -1,9 +1,13 @@
 mod socket_pool;
 
+#[cfg(target_os = "linux")]
+pub mod batch;
 pub mod native;
 pub mod protector;
+pub mod proxy;
 pub mod socket_params;
 
-pub use protector::{NativeProtector, Protect, Protector};
+pub use protector::{NativeProtector, Protect, Protector, ProtectorHooks};
+pub use proxy::{Proxy, ProxyKind};
 pub use socket_params::{SocketBufSizes, TcpParams, UdpParams};
-pub use socket_pool::{External, SocketPool};
+pub use socket_pool::{External, ExternalGuard, SocketPool};
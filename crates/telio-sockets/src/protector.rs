@@ -10,6 +10,8 @@
 
 use std::{io, panic::RefUnwindSafe, sync::Arc};
 
+use parking_lot::RwLock;
+
 use crate::native::NativeSocket;
 
 #[cfg(windows)]
@@ -115,3 +117,84 @@ pub fn make_external_protector(protect: Protect) -> Arc<(dyn Protector + 'static
     }
     Arc::new(ProtectorMakeExternalCb(protect))
 }
+
+/// Socket protection supplied by the embedding application.
+///
+/// Used on the platforms libtelio has no native [`Protector`] for, like ChromeOS, sandboxed
+/// environments and custom routers. See [`make_custom_protector`].
+#[cfg_attr(any(test, feature = "mockall"), mockall::automock)]
+pub trait ProtectorHooks: Send + Sync {
+    /// Make the socket bypass the tunnel, e.g. by binding it to the default interface.
+    fn protect(&self, socket: NativeSocket) -> io::Result<()>;
+
+    /// Bind the socket to the tunnel interface. The `interface` is the identifier passed to
+    /// [`Protector::set_tunnel_interface`], `None` when libtelio does not know it.
+    fn bind_to_interface(&self, socket: NativeSocket, interface: Option<u64>) -> io::Result<()>;
+}
+
+/// Construct a [`Protector`] instance that delegates to the hooks of the application.
+///
+/// [`Protector::make_external`] protects the socket and [`Protector::make_internal`] binds it to
+/// the tunnel interface, all other methods only remember their arguments or are no-op.
+pub fn make_custom_protector(hooks: Arc<dyn ProtectorHooks>) -> Arc<(dyn Protector + 'static)> {
+    struct CustomProtector {
+        hooks: Arc<dyn ProtectorHooks>,
+        tunnel_interface: RwLock<Option<u64>>,
+    }
+    impl Protector for CustomProtector {
+        fn make_external(&self, socket: NativeSocket) -> io::Result<()> {
+            self.hooks.protect(socket)
+        }
+
+        fn make_internal(&self, socket: NativeSocket) -> io::Result<()> {
+            self.hooks
+                .bind_to_interface(socket, *self.tunnel_interface.read())
+        }
+
+        fn clean(&self, _socket: NativeSocket) {}
+
+        fn set_fwmark(&self, _fwmark: u32) {}
+
+        fn set_tunnel_interface(&self, interface: u64) {
+            *self.tunnel_interface.write() = Some(interface);
+        }
+
+        fn set_ext_if_filter(&self, _list: &[String]) {}
+    }
+    Arc::new(CustomProtector {
+        hooks,
+        tunnel_interface: RwLock::new(None),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockall::predicate::eq;
+
+    #[test]
+    fn custom_protector_calls_hooks() {
+        let mut hooks = MockProtectorHooks::new();
+        hooks
+            .expect_protect()
+            .with(eq(7 as NativeSocket))
+            .times(1)
+            .returning(|_| Ok(()));
+        hooks
+            .expect_bind_to_interface()
+            .with(eq(8 as NativeSocket), eq(None))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        hooks
+            .expect_bind_to_interface()
+            .with(eq(9 as NativeSocket), eq(Some(42)))
+            .times(1)
+            .returning(|_, _| Err(io::Error::other("no such interface")));
+        let protector = make_custom_protector(Arc::new(hooks));
+
+        protector.make_external(7).unwrap();
+        protector.make_internal(8).unwrap();
+        protector.set_tunnel_interface(42);
+        assert!(protector.make_internal(9).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+//! Proxies of the outbound control connections.
+//!
+//! Networks which allow to reach the internet only through a proxy need the relay, API and
+//! other TCP connections of libtelio to go through it. [SocketPool::connect_external_tcp_v4]
+//! opens such connections through the proxy set with [SocketPool::set_proxy], either by a SOCKS5
+//! CONNECT or by an HTTP CONNECT request. Peer to peer traffic is never proxied.
+//!
+//! A proxy set by its hostname is resolved once, on the first connection through it, and not
+//! on every connection: the system resolver may be unreachable or routed through the tunnel
+//! the control connections are needed to set up.
+//!
+//! [SocketPool::connect_external_tcp_v4]: crate::SocketPool::connect_external_tcp_v4
+//! [SocketPool::set_proxy]: crate::SocketPool::set_proxy
+
+use std::{
+    convert::TryFrom,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use telio_utils::HiddenString;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+    time::timeout,
+};
+
+/// Time given to the resolution of the hostname of the proxy
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest accepted HTTP CONNECT response header
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USER_PASS: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_USER_PASS_VERSION: u8 = 1;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+/// Protocol spoken to the proxy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS5, with the username and password authentication when credentials are set
+    Socks5,
+    /// HTTP CONNECT, with the basic authentication when credentials are set
+    HttpConnect,
+}
+
+/// Proxy the outbound control connections go through
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    /// Protocol spoken to the proxy
+    pub kind: ProxyKind,
+    /// Hostname or IP address of the proxy
+    pub host: String,
+    /// Port of the proxy
+    pub port: u16,
+    /// Username, when the proxy requires authentication
+    pub username: Option<String>,
+    /// Password of the username
+    pub password: Option<HiddenString>,
+}
+
+impl Proxy {
+    /// Addresses of the proxy, of both families. An IP address is taken as is, a hostname is
+    /// resolved within [RESOLVE_TIMEOUT].
+    pub(crate) async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, self.port)]);
+        }
+
+        let addrs: Vec<_> = timeout(RESOLVE_TIMEOUT, lookup_host((host, self.port)))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("resolving proxy {} timed out", self.host),
+                )
+            })??
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("proxy {} has no address", self.host),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    /// Ask the proxy connected over the `stream` to connect to the `target`. Once it succeeds,
+    /// the `stream` carries the traffic of the target.
+    pub(crate) async fn handshake<S>(&self, stream: &mut S, target: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_handshake(stream, target).await,
+            ProxyKind::HttpConnect => self.http_handshake(stream, target).await,
+        }
+    }
+
+    fn credentials(&self) -> Option<(&str, &str)> {
+        let username = self.username.as_deref()?;
+        let password = self
+            .password
+            .as_ref()
+            .map_or("", |password| password.as_str());
+        Some((username, password))
+    }
+
+    /// RFC 1928 CONNECT, with the RFC 1929 authentication
+    async fn socks5_handshake<S>(&self, stream: &mut S, target: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let credentials = self.credentials();
+        let method = match credentials {
+            Some(_) => SOCKS_USER_PASS,
+            None => SOCKS_NO_AUTH,
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD] => {
+                return Err(proxy_error("no acceptable SOCKS5 authentication method"))
+            }
+            [SOCKS_VERSION, selected] if selected == method => (),
+            _ => return Err(proxy_error("unexpected SOCKS5 method selection")),
+        }
+
+        if let Some((username, password)) = credentials {
+            let mut request = vec![SOCKS_USER_PASS_VERSION];
+            for field in [username, password] {
+                let len = u8::try_from(field.len())
+                    .map_err(|_| proxy_error("SOCKS5 credentials are too long"))?;
+                request.push(len);
+                request.extend_from_slice(field.as_bytes());
+            }
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply != [SOCKS_USER_PASS_VERSION, 0] {
+                return Err(proxy_error("SOCKS5 authentication failed"));
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+        match target.ip() {
+            IpAddr::V4(ip) => {
+                request.push(SOCKS_ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(SOCKS_ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        let [version, status, _, address_type] = reply;
+        if version != SOCKS_VERSION {
+            return Err(proxy_error("unexpected SOCKS5 reply"));
+        }
+        if status != 0 {
+            return Err(proxy_error(format!(
+                "SOCKS5 proxy refused to connect to {target}: status {status}"
+            )));
+        }
+        // The bound address is of no use, but has to be consumed
+        let address_len = match address_type {
+            SOCKS_ATYP_IPV4 => 4,
+            SOCKS_ATYP_IPV6 => 16,
+            SOCKS_ATYP_DOMAIN => stream.read_u8().await? as usize,
+            _ => return Err(proxy_error("unexpected SOCKS5 address type")),
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_handshake<S>(&self, stream: &mut S, target: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((username, password)) = self.credentials() {
+            let token = base64::Engine::encode(
+                &base64::engine::general_purpose::STANDARD,
+                format!("{username}:{password}"),
+            );
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte, not to consume the data of the target following the header
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE {
+                return Err(proxy_error("HTTP CONNECT response is too long"));
+            }
+            response.push(stream.read_u8().await?);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(proxy_error(format!(
+                "HTTP proxy refused to connect to {target}: {status_line}"
+            ))),
+        }
+    }
+}
+
+fn proxy_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_utils::Hidden;
+    use tokio::io::duplex;
+
+    const TARGET: &str = "10.0.0.1:8765";
+
+    fn proxy(kind: ProxyKind, credentials: bool) -> Proxy {
+        Proxy {
+            kind,
+            host: "127.0.0.1".to_owned(),
+            port: 1080,
+            username: credentials.then(|| "user".to_owned()),
+            password: credentials.then(|| Hidden("pass".to_owned())),
+        }
+    }
+
+    #[tokio::test]
+    async fn ip_addresses_are_not_resolved() {
+        let mut proxy = proxy(ProxyKind::Socks5, false);
+        assert_eq!(
+            proxy.resolve().await.unwrap(),
+            vec!["127.0.0.1:1080".parse().unwrap()]
+        );
+
+        proxy.host = "[::1]".to_owned();
+        assert_eq!(
+            proxy.resolve().await.unwrap(),
+            vec!["[::1]:1080".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_with_authentication() {
+        let (mut client, mut server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0u8; 11];
+            server.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut connect = [0u8; 10];
+            server.read_exact(&mut connect).await.unwrap();
+            assert_eq!(connect, [5, 1, 0, 1, 10, 0, 0, 1, 0x22, 0x3d]);
+            server
+                .write_all(&[5, 0, 0, 3, 4, b'h', b'o', b's', b't', 0, 80, b'x'])
+                .await
+                .unwrap();
+        });
+
+        proxy(ProxyKind::Socks5, true)
+            .handshake(&mut client, TARGET.parse().unwrap())
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), b'x');
+    }
+
+    #[tokio::test]
+    async fn socks5_refusal_is_an_error() {
+        let (mut client, mut server) = duplex(1024);
+        tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            server.write_all(&[5, 0]).await.unwrap();
+            let mut connect = [0u8; 10];
+            server.read_exact(&mut connect).await.unwrap();
+            server
+                .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let error = proxy(ProxyKind::Socks5, false)
+            .handshake(&mut client, TARGET.parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn http_connect_with_authentication() {
+        let (mut client, mut server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(server.read_u8().await.unwrap());
+            }
+            assert_eq!(
+                String::from_utf8(request).unwrap(),
+                "CONNECT 10.0.0.1:8765 HTTP/1.1\r\nHost: 10.0.0.1:8765\r\n\
+                 Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+            );
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nx")
+                .await
+                .unwrap();
+        });
+
+        proxy(ProxyKind::HttpConnect, true)
+            .handshake(&mut client, TARGET.parse().unwrap())
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), b'x');
+    }
+
+    #[tokio::test]
+    async fn http_refusal_is_an_error() {
+        let (mut client, mut server) = duplex(1024);
+        tokio::spawn(async move {
+            server
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let error = proxy(ProxyKind::HttpConnect, false)
+            .handshake(&mut client, TARGET.parse().unwrap())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("407"));
+    }
+}
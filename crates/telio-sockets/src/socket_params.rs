@@ -11,7 +11,7 @@ use {std::os::windows::io::AsRawSocket, windows::Win32::Networking::WinSock};
 /// time after which tcp retransmissions will be stopped and the connection will be dropped
 const TCP_RXT_CONNDROPTIME: libc::c_int = 0x80;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct TcpParams {
     pub keepalive_enable: Option<bool>,
     pub keepalive_idle: Option<Duration>,
@@ -22,7 +22,7 @@ pub struct TcpParams {
     pub buf_size: SocketBufSizes,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct SocketBufSizes {
     pub tx_buf_size: Option<usize>,
     pub rx_buf_size: Option<usize>,
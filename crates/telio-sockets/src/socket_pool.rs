@@ -1,12 +1,13 @@
 use std::{
     io,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     ops::{Deref, DerefMut},
     pin::Pin,
     sync::Arc,
     task::Poll,
 };
 
+use parking_lot::RwLock;
 use socket2::{Domain, Protocol, Socket, Type};
 
 use tokio::{
@@ -20,7 +21,7 @@ use telio_utils::{telio_log_debug, telio_log_warn};
 
 use crate::{
     native::{AsNativeSocket, NativeSocket},
-    Protector, TcpParams, UdpParams,
+    Protector, Proxy, TcpParams, UdpParams,
 };
 
 struct SocketGuard {
@@ -42,10 +43,32 @@ pub struct External<T: AsNativeSocket> {
 #[derive(Clone)]
 pub struct SocketPool {
     protect: ArcProtector,
+    /// Proxy of the control connections, shared by the clones of the pool
+    proxy: Arc<RwLock<Option<ProxyState>>>,
+}
+
+/// Proxy set on the pool, with the addresses it resolved to on the first connection through it
+struct ProxyState {
+    proxy: Proxy,
+    addrs: Option<Vec<SocketAddr>>,
 }
 
 type ArcProtector = Arc<dyn Protector>;
 
+/// Keeps a socket taken out of [External] excluded from the tunnel, until dropped
+pub struct ExternalGuard {
+    _guard: SocketGuard,
+}
+
+impl<T: AsNativeSocket> External<T> {
+    /// Take the socket out, for libraries which have to own it. The socket stays external for
+    /// as long as the returned guard is alive.
+    pub fn into_parts(self) -> (T, ExternalGuard) {
+        let Self { socket, guard } = self;
+        (socket, ExternalGuard { _guard: guard })
+    }
+}
+
 impl External<TcpSocket> {
     pub async fn connect(self, addr: SocketAddr) -> io::Result<External<TcpStream>> {
         let Self { guard, socket } = self;
@@ -112,7 +135,39 @@ impl SocketPool {
     pub fn new<T: Protector + 'static>(protect: T) -> Self {
         Self {
             protect: Arc::new(protect),
+            proxy: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Route the connections opened by [Self::connect_external_tcp_v4] through the `proxy`, or
+    /// connect directly with `None`. Already open connections are not affected.
+    pub fn set_proxy(&self, proxy: Option<Proxy>) {
+        *self.proxy.write() = proxy.map(|proxy| ProxyState { proxy, addrs: None });
+    }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy.read().as_ref().map(|state| state.proxy.clone())
+    }
+
+    /// Proxy set, with its addresses, resolved on the first call only
+    async fn resolved_proxy(&self) -> io::Result<Option<(Proxy, Vec<SocketAddr>)>> {
+        let (proxy, addrs) = match self.proxy.read().as_ref() {
+            Some(state) => (state.proxy.clone(), state.addrs.clone()),
+            None => return Ok(None),
+        };
+        if let Some(addrs) = addrs {
+            return Ok(Some((proxy, addrs)));
         }
+
+        let addrs = proxy.resolve().await?;
+        telio_log_debug!("Proxy {} resolved to {addrs:?}", proxy.host);
+        if let Some(state) = self.proxy.write().as_mut() {
+            // Unless replaced in the meantime
+            if state.proxy == proxy {
+                state.addrs = Some(addrs.clone());
+            }
+        }
+        Ok(Some((proxy, addrs)))
     }
 
     #[cfg(target_os = "linux")]
@@ -132,13 +187,21 @@ impl SocketPool {
     pub fn new_external_tcp_v4(
         &self,
         params: Option<TcpParams>,
+    ) -> io::Result<External<TcpSocket>> {
+        self.new_external_tcp(Domain::IPV4, params)
+    }
+
+    fn new_external_tcp(
+        &self,
+        domain: Domain,
+        params: Option<TcpParams>,
     ) -> io::Result<External<TcpSocket>> {
         let ty = Type::STREAM;
 
         #[cfg(any(target_os = "android", target_os = "linux"))]
         let ty = ty.nonblocking();
 
-        let socket2_socket = Socket::new(Domain::IPV4, ty, Some(Protocol::TCP))?;
+        let socket2_socket = Socket::new(domain, ty, Some(Protocol::TCP))?;
 
         #[cfg(not(any(target_os = "android", target_os = "linux")))]
         socket2_socket.set_nonblocking(true)?;
@@ -148,13 +211,99 @@ impl SocketPool {
         }
 
         telio_log_debug!(
-            "Creating external tcp_v4 socket: {}",
+            "Creating external tcp socket: {}",
             socket2_socket.as_native_socket()
         );
 
         self.new_external(TcpSocket::from_std_stream(socket2_socket.into()))
     }
 
+    /// Open an external TCP connection to the `addr`, through the proxy when one is set
+    pub async fn connect_external_tcp_v4(
+        &self,
+        addr: SocketAddr,
+        params: Option<TcpParams>,
+    ) -> io::Result<External<TcpStream>> {
+        let socket = self.new_external_tcp_v4(params)?;
+        let Some((proxy, proxy_addrs)) = self.resolved_proxy().await? else {
+            return socket.connect(addr).await;
+        };
+
+        let proxy_addr = proxy_addrs
+            .into_iter()
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("proxy {} has no IPv4 address", proxy.host),
+                )
+            })?;
+        telio_log_debug!("Connecting to {addr} through proxy {proxy_addr}");
+        let mut stream = socket.connect(proxy_addr).await?;
+        proxy.handshake(&mut stream, addr).await?;
+        Ok(stream)
+    }
+
+    /// Open an external TCP connection to the `addr` of either family, through the proxy when
+    /// one is set. The socket is of the family of the address connected to, the proxy's one
+    /// when set, its addresses being tried in turn.
+    pub async fn connect_external_tcp(
+        &self,
+        addr: SocketAddr,
+        params: Option<TcpParams>,
+    ) -> io::Result<External<TcpStream>> {
+        let Some((proxy, proxy_addrs)) = self.resolved_proxy().await? else {
+            return self
+                .new_external_tcp(Domain::for_address(addr), params)?
+                .connect(addr)
+                .await;
+        };
+
+        let mut error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("proxy {} has no address", proxy.host),
+        );
+        for proxy_addr in proxy_addrs {
+            telio_log_debug!("Connecting to {addr} through proxy {proxy_addr}");
+            let connected =
+                match self.new_external_tcp(Domain::for_address(proxy_addr), params.clone()) {
+                    Ok(socket) => socket.connect(proxy_addr).await,
+                    Err(e) => Err(e),
+                };
+            match connected {
+                Ok(mut stream) => {
+                    proxy.handshake(&mut stream, addr).await?;
+                    return Ok(stream);
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Bind a UDP socket to the `port`, shared with the other sockets bound to it, and join the
+    /// multicast `group` on the default interface. The own multicast packets are not looped back.
+    pub fn new_external_multicast_udp_v4(
+        &self,
+        group: Ipv4Addr,
+        port: u16,
+    ) -> io::Result<External<UdpSocket>> {
+        let socket2_socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket2_socket.set_reuse_address(true)?;
+        socket2_socket.set_nonblocking(true)?;
+        socket2_socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+        socket2_socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        socket2_socket.set_multicast_loop_v4(false)?;
+
+        telio_log_debug!(
+            "Creating external multicast udp socket: {}",
+            socket2_socket.as_native_socket()
+        );
+
+        let std_socket: std::net::UdpSocket = socket2_socket.into();
+        self.new_external(UdpSocket::from_std(std_socket)?)
+    }
+
     pub async fn new_udp<A: ToSocketAddrs>(
         addr: A,
         params: Option<UdpParams>,
@@ -199,6 +348,42 @@ impl SocketPool {
         self.new_external(socket)
     }
 
+    /// Bind `count` UDP sockets to the same `addr` with `SO_REUSEPORT`, e.g. one per CPU. The
+    /// kernel spreads the received flows across them, so each can be served by its own thread.
+    /// A zero port is resolved by the first socket and shared by the rest.
+    #[cfg(target_os = "linux")]
+    pub fn new_external_udp_group(
+        &self,
+        addr: SocketAddr,
+        count: usize,
+        params: Option<UdpParams>,
+    ) -> io::Result<Vec<External<UdpSocket>>> {
+        use nix::sys::socket::{setsockopt, sockopt::ReusePort};
+
+        let mut addr = addr;
+        let mut group = Vec::with_capacity(count);
+        for _ in 0..count {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            setsockopt(&socket, ReusePort, &true).map_err(io::Error::from)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            if let Some(params) = &params {
+                params.apply(&socket);
+            }
+            if let Some(bound) = socket.local_addr()?.as_socket() {
+                addr = bound;
+            }
+
+            telio_log_debug!(
+                "Creating external udp socket of a group: {}",
+                socket.as_native_socket()
+            );
+            let std_socket: std::net::UdpSocket = socket.into();
+            group.push(self.new_external(UdpSocket::from_std(std_socket)?)?);
+        }
+        Ok(group)
+    }
+
     /// wraps protect() on android, fmark on linux and interface binding for others
     pub fn make_external<T: AsNativeSocket>(&self, socket: T) {
         let _ = self.protect.make_external(socket.as_native_socket());
@@ -240,10 +425,14 @@ mod tests {
     };
 
     use rstest::rstest;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
 
     use crate::{
         protector::{make_external_protector, MockProtector},
-        Protect,
+        Protect, ProxyKind,
     };
 
     use super::*;
@@ -317,6 +506,55 @@ mod tests {
         assert_eq!(socks.lock().unwrap().clone(), vec![tcp.as_native_socket()]);
     }
 
+    #[tokio::test]
+    async fn proxy_is_resolved_once() {
+        let mut protect = MockProtector::default();
+        protect.expect_make_external().returning(|_| Ok(()));
+        protect.expect_clean().return_const(());
+        let pool = SocketPool::new(protect);
+
+        // SOCKS5 proxy without authentication, connecting to any target
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                stream.write_all(&[5, 0]).await.unwrap();
+                let mut connect = [0u8; 10];
+                stream.read_exact(&mut connect).await.unwrap();
+                stream
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let proxy = Proxy {
+            kind: ProxyKind::Socks5,
+            host: "localhost".to_owned(),
+            port,
+            username: None,
+            password: None,
+        };
+        pool.set_proxy(Some(proxy.clone()));
+        let target = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 8765));
+        for _ in 0..2 {
+            pool.connect_external_tcp_v4(target, None).await.unwrap();
+            let state = pool.proxy.read();
+            let addrs = state
+                .as_ref()
+                .and_then(|state| state.addrs.clone())
+                .unwrap();
+            assert!(addrs.contains(&SocketAddr::from((Ipv4Addr::LOCALHOST, port))));
+        }
+
+        // Resolved anew once set again
+        pool.set_proxy(Some(proxy));
+        assert!(pool.proxy.read().as_ref().unwrap().addrs.is_none());
+    }
+
     #[rstest]
     #[cfg(not(any(windows, target_os = "macos")))]
     #[case(IpAddr::V4(Ipv4Addr::LOCALHOST))]
@@ -387,4 +625,26 @@ mod tests {
         assert_eq!(PACKET.len(), socket.recv_from(&mut buf).await.unwrap().0);
         assert_eq!(PACKET, buf);
     }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn external_udp_group_shares_one_port() {
+        let mut protect = MockProtector::default();
+        protect
+            .expect_make_external()
+            .times(4)
+            .returning(|_| Ok(()));
+        protect.expect_clean().times(4).return_const(());
+        let pool = SocketPool::new(protect);
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let group = pool.new_external_udp_group(addr, 4, None).unwrap();
+        assert_eq!(4, group.len());
+
+        let local_addr = group[0].local_addr().unwrap();
+        assert_ne!(0, local_addr.port());
+        for socket in &group {
+            assert_eq!(local_addr, socket.local_addr().unwrap());
+        }
+    }
 }
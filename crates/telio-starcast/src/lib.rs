@@ -5,8 +5,11 @@
 //! 1. Starcast peer responsible for intercepting multicast traffic and injecting it on the receiver side.
 //! 2. Starcast transport component responsible for multicasting intercepted traffic to the meshnet peers and handling response messages.
 //! 3. Nat is transport's utility used to SNAT the packets comming from multiple peers into one multicast peer address.
+//!
+//! Which groups and broadcasts are relayed, and how, is decided by the relay policy.
 
 pub mod nat;
+pub mod relay;
 pub mod starcast_peer;
 pub mod transport;
 pub(crate) mod utils;
@@ -0,0 +1,297 @@
+//! Policy of the multicast and broadcast relay between the meshnet peers.
+//!
+//! Only the configured multicast groups and the directed broadcast of the meshnet network are
+//! intercepted and relayed. The meshnet counts as a single link, so link-local groups and the
+//! broadcasts keep their TTL, while the packets of wider scoped groups may count the relay as a
+//! router hop. Loops, e.g. through the OS looping the injected packets back, are broken by
+//! dropping the packets which were relayed in the other direction just before. They are
+//! recognized by their addresses, ports and UDP payload as seen locally, so identical packets of
+//! different hosts, e.g. the same discovery query, are all relayed.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use ipnet::{IpNet, Ipv4Net};
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    udp::UdpPacket,
+    Packet,
+};
+use telio_model::{
+    constants::{IPV4_MESHNET_BROADCAST, IPV4_MULTICAST_NETWORK, IPV6_MULTICAST_NETWORK},
+    features::FeatureStarcast,
+};
+use telio_utils::{const_ipnet::ConstIpv4Net, LruCache};
+
+/// Link-local scope of the IPv4 multicast, RFC 5771
+const IPV4_LINK_LOCAL_MULTICAST: ConstIpv4Net = ConstIpv4Net::new(Ipv4Addr::new(224, 0, 0, 0), 24);
+
+/// How long a relayed packet is remembered to recognize it coming back
+const LOOP_WINDOW: Duration = Duration::from_secs(2);
+const LOOP_CACHE_CAPACITY: usize = 1024;
+
+/// Multicast groups and broadcasts relayed between the meshnet peers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayPolicy {
+    groups: Vec<IpNet>,
+    broadcasts: Vec<IpAddr>,
+    decrement_ttl: bool,
+}
+
+impl RelayPolicy {
+    /// Policy of the starcast `config`
+    pub fn new(config: &FeatureStarcast) -> Self {
+        let mut groups: Vec<IpNet> = match &config.groups {
+            Some(groups) => groups
+                .iter()
+                .map(|group| IpNet::V4(Ipv4Net::from(*group)))
+                .collect(),
+            None => vec![IPV4_MULTICAST_NETWORK.into()],
+        };
+        groups.push(IPV6_MULTICAST_NETWORK.into());
+        let broadcasts = match config.directed_broadcast {
+            true => vec![IPV4_MESHNET_BROADCAST.into()],
+            false => Vec::new(),
+        };
+        Self {
+            groups,
+            broadcasts,
+            decrement_ttl: config.decrement_ttl,
+        }
+    }
+
+    /// Networks the traffic of which is relayed
+    pub fn networks(&self) -> Vec<IpNet> {
+        self.groups
+            .iter()
+            .copied()
+            .chain(self.broadcasts.iter().map(|&address| IpNet::from(address)))
+            .collect()
+    }
+
+    /// Count the relay as a hop of the local `packet` leaving to the peers, returns whether the
+    /// packet may still be relayed
+    pub(crate) fn pass_hop(&self, packet: &mut [u8]) -> bool {
+        match packet.first().map(|byte| byte >> 4) {
+            Some(4) => {
+                let Some(mut ip_packet) = MutableIpv4Packet::new(packet) else {
+                    return false;
+                };
+                if !self.decrement_ttl || self.is_link_scoped(ip_packet.get_destination().into()) {
+                    return true;
+                }
+                match ip_packet.get_ttl() {
+                    0 | 1 => false,
+                    ttl => {
+                        ip_packet.set_ttl(ttl - 1);
+                        ip_packet.set_checksum(ipv4::checksum(&ip_packet.to_immutable()));
+                        true
+                    }
+                }
+            }
+            Some(6) => {
+                let Some(mut ip_packet) = MutableIpv6Packet::new(packet) else {
+                    return false;
+                };
+                if !self.decrement_ttl || self.is_link_scoped(ip_packet.get_destination().into()) {
+                    return true;
+                }
+                match ip_packet.get_hop_limit() {
+                    0 | 1 => false,
+                    hop_limit => {
+                        ip_packet.set_hop_limit(hop_limit - 1);
+                        true
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn is_link_scoped(&self, destination: IpAddr) -> bool {
+        match destination {
+            IpAddr::V4(address) => {
+                Ipv4Net::from(IPV4_LINK_LOCAL_MULTICAST).contains(&address)
+                    || self.broadcasts.contains(&destination)
+            }
+            // Interface-local and link-local scopes, RFC 4291
+            IpAddr::V6(address) => {
+                matches!(address.octets(), [0xff, flags_and_scope, ..] if flags_and_scope & 0x0f <= 2)
+            }
+        }
+    }
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        Self::new(&FeatureStarcast::default())
+    }
+}
+
+/// Packets relayed recently in either direction
+pub(crate) struct LoopGuard {
+    /// Packets of the peers injected locally
+    injected: LruCache<u64, ()>,
+    /// Local packets sent to the peers
+    sent: LruCache<u64, ()>,
+}
+
+impl LoopGuard {
+    pub(crate) fn new() -> Self {
+        Self {
+            injected: LruCache::new(LOOP_WINDOW, LOOP_CACHE_CAPACITY),
+            sent: LruCache::new(LOOP_WINDOW, LOOP_CACHE_CAPACITY),
+        }
+    }
+
+    /// Whether the local `packet` may be sent to the peers, it may not when it is a packet of
+    /// the peers which was just injected
+    pub(crate) fn admit_local(&mut self, packet: &[u8]) -> bool {
+        Self::admit(packet, &self.injected, &mut self.sent)
+    }
+
+    /// Whether the `packet` of a peer may be injected, it may not when it is a local packet
+    /// which was just sent to the peers
+    pub(crate) fn admit_remote(&mut self, packet: &[u8]) -> bool {
+        Self::admit(packet, &self.sent, &mut self.injected)
+    }
+
+    fn admit(packet: &[u8], seen: &LruCache<u64, ()>, admitted: &mut LruCache<u64, ()>) -> bool {
+        // Packets which cannot be recognized are not looped by the relay, which handles UDP only
+        let Some(fingerprint) = fingerprint(packet) else {
+            return true;
+        };
+        if seen.peek(&fingerprint).is_some() {
+            return false;
+        }
+        admitted.insert(fingerprint, ());
+        true
+    }
+}
+
+/// Hash of the addresses, ports and payload of the UDP `packet`
+fn fingerprint(packet: &[u8]) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    match packet.first()? >> 4 {
+        4 => {
+            let ip_packet = Ipv4Packet::new(packet)?;
+            if ip_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+                return None;
+            }
+            ip_packet.get_source().hash(&mut hasher);
+            ip_packet.get_destination().hash(&mut hasher);
+            hash_udp(ip_packet.payload(), &mut hasher)?;
+        }
+        6 => {
+            let ip_packet = Ipv6Packet::new(packet)?;
+            if ip_packet.get_next_header() != IpNextHeaderProtocols::Udp {
+                return None;
+            }
+            ip_packet.get_source().hash(&mut hasher);
+            ip_packet.get_destination().hash(&mut hasher);
+            hash_udp(ip_packet.payload(), &mut hasher)?;
+        }
+        _ => return None,
+    }
+    Some(hasher.finish())
+}
+
+fn hash_udp(packet: &[u8], hasher: &mut DefaultHasher) -> Option<()> {
+    let udp_packet = UdpPacket::new(packet)?;
+    udp_packet.get_source().hash(hasher);
+    udp_packet.get_destination().hash(hasher);
+    udp_packet.payload().hash(hasher);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils::*;
+    use std::str::FromStr;
+
+    fn with_ttl(mut packet: Vec<u8>, ttl: u8) -> Vec<u8> {
+        let mut ip_packet = MutableIpv4Packet::new(&mut packet).unwrap();
+        ip_packet.set_ttl(ttl);
+        ip_packet.set_checksum(ipv4::checksum(&ip_packet.to_immutable()));
+        packet
+    }
+
+    fn ttl(packet: &[u8]) -> u8 {
+        Ipv4Packet::new(packet).unwrap().get_ttl()
+    }
+
+    #[test]
+    fn configured_groups_and_broadcasts_are_relayed() {
+        let policy = RelayPolicy::new(&FeatureStarcast {
+            groups: Some(vec![telio_model::features::Ipv4Net::from_str(
+                "239.255.255.250/32",
+            )
+            .unwrap()]),
+            directed_broadcast: true,
+            decrement_ttl: true,
+        });
+        let networks: Vec<String> = policy.networks().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            networks,
+            vec!["239.255.255.250/32", "ff02::fb/128", "100.127.255.255/32"]
+        );
+
+        assert_eq!(
+            RelayPolicy::default().networks(),
+            vec![IPV4_MULTICAST_NETWORK.into(), IPV6_MULTICAST_NETWORK.into()]
+        );
+    }
+
+    #[test]
+    fn ttl_is_decremented_beyond_link_local_scope() {
+        let policy = RelayPolicy::new(&FeatureStarcast {
+            decrement_ttl: true,
+            ..Default::default()
+        });
+
+        let mut ssdp = with_ttl(make_udp_v4("100.64.0.2:1900", "239.255.255.250:1900"), 2);
+        assert!(policy.pass_hop(&mut ssdp));
+        assert_eq!(ttl(&ssdp), 1);
+        assert_eq!(
+            Ipv4Packet::new(&ssdp).unwrap().get_checksum(),
+            ipv4::checksum(&Ipv4Packet::new(&ssdp).unwrap())
+        );
+        assert!(!policy.pass_hop(&mut ssdp));
+
+        let mut mdns = with_ttl(make_udp_v4("100.64.0.2:5353", "224.0.0.251:5353"), 1);
+        assert!(policy.pass_hop(&mut mdns));
+        assert_eq!(ttl(&mdns), 1);
+
+        let mut v6_mdns = make_udp_v6("[fd74:656c:696f::2]:5353", "[ff02::fb]:5353");
+        assert!(policy.pass_hop(&mut v6_mdns));
+
+        let keep_ttl = RelayPolicy::default();
+        let mut ssdp = with_ttl(make_udp_v4("100.64.0.2:1900", "239.255.255.250:1900"), 1);
+        assert!(keep_ttl.pass_hop(&mut ssdp));
+        assert_eq!(ttl(&ssdp), 1);
+    }
+
+    #[test]
+    fn looped_packets_are_dropped() {
+        let mut guard = LoopGuard::new();
+        let query = make_udp_v4("100.64.0.3:5353", "224.0.0.251:5353");
+        // The same query of another host
+        let other_query = make_udp_v4("100.64.0.2:5353", "224.0.0.251:5353");
+
+        assert!(guard.admit_remote(&query));
+        assert!(guard.admit_remote(&query));
+        // Looped back after being injected
+        assert!(!guard.admit_local(&query));
+        assert!(guard.admit_local(&other_query));
+
+        advance_time(LOOP_WINDOW + Duration::from_millis(1));
+        assert!(guard.admit_local(&query));
+        assert!(!guard.admit_remote(&query));
+    }
+}
@@ -10,18 +10,20 @@ use telio_task::{
     io::{wait_for_tx, Chan},
     task_exec, Runtime, RuntimeExt, Task, WaitResponse,
 };
-use telio_utils::interval;
+use telio_utils::{interval, PacketPool, PooledPacket};
 use telio_utils::{telio_log_error, telio_log_info, telio_log_warn};
 use telio_wg::uapi::Peer;
 use tokio::{net::UdpSocket, sync::mpsc::error::SendTimeoutError, time::Interval};
 use x25519_dalek::{PublicKey as PublicKeyDalek, StaticSecret};
 
-use telio_model::constants::{
-    IPV4_MULTICAST_NETWORK, IPV4_STARCAST_NETWORK, IPV6_MULTICAST_NETWORK, IPV6_STARCAST_NETWORK,
-};
+use telio_model::constants::{IPV4_STARCAST_NETWORK, IPV6_STARCAST_NETWORK};
+
+use crate::relay::RelayPolicy;
 
 /// Constant for maximum packet size.
 const MAX_PACKET: usize = 2048;
+/// Number of the decapsulated packets kept for reuse
+const POOLED_PACKETS: usize = 64;
 
 #[derive(Debug, thiserror::Error)]
 /// Custom `UdpProxy` error
@@ -68,21 +70,31 @@ impl StarcastPeer {
     /// # Arguments
     ///
     /// * `channel` - Channel for communicating decapsulated packets to and from the multicaster.
+    /// * `ipv6` - Whether to intercept the IPv6 traffic as well.
+    /// * `policy` - Groups and broadcasts to intercept.
     ///
     /// # Returns
     ///
     /// A new starcast virtual peer.
-    pub async fn start(channel: Chan<Vec<u8>>, ipv6: bool) -> Result<Self, Error> {
+    pub async fn start(
+        channel: Chan<PooledPacket>,
+        ipv6: bool,
+        policy: &RelayPolicy,
+    ) -> Result<Self, Error> {
         // Port 0 means the OS will dynamically allocate port.
         const TIMER_UPDATE_PERIOD: Duration = Duration::from_millis(250);
         let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
         let wg_timer_tick_interval = interval(TIMER_UPDATE_PERIOD);
         let secret_key = SecretKey::gen();
 
-        let mut allowed_ips = vec![IPV4_MULTICAST_NETWORK.into(), IPV4_STARCAST_NETWORK.into()];
+        let mut allowed_ips: Vec<IpNet> = policy
+            .networks()
+            .into_iter()
+            .filter(|network| ipv6 || network.addr().is_ipv4())
+            .collect();
+        allowed_ips.push(IPV4_STARCAST_NETWORK.into());
 
         if ipv6 {
-            allowed_ips.push(IPV6_MULTICAST_NETWORK.into());
             allowed_ips.push(IPV6_STARCAST_NETWORK.into());
         }
 
@@ -97,6 +109,7 @@ impl StarcastPeer {
                 packets_present_in_tunnel: false,
                 sending_buffer: Box::new([0u8; MAX_PACKET]),
                 receiving_buffer: Box::new([0u8; MAX_PACKET]),
+                decapsulated_pool: PacketPool::new("starcast-peer", MAX_PACKET, POOLED_PACKETS),
                 allowed_ips,
             }),
         })
@@ -170,7 +183,7 @@ struct State {
     /// Interval at which the tunnel timers will be updated.
     wg_timer_tick_interval: Interval,
     /// Channel for sending decapsulated packets to the multicaster and receiving packets for encapsulation from it.
-    channel: Chan<Vec<u8>>,
+    channel: Chan<PooledPacket>,
     /// Local socket for sending and receiving packets from WireGuard.
     socket: UdpSocket,
     /// WireGuard socket address to communicate encapsulated packets to and from Libtelio.
@@ -184,6 +197,8 @@ struct State {
     sending_buffer: Box<[u8; MAX_PACKET]>,
     /// Buffer for inbound packets.
     receiving_buffer: Box<[u8; MAX_PACKET]>,
+    /// Buffers the inbound packets are decapsulated into, passed on to the multicaster as they are.
+    decapsulated_pool: PacketPool,
 }
 
 impl State {
@@ -305,10 +320,11 @@ impl Runtime for State {
                     wait_for_tx(tx, socket.recv(&mut *self.receiving_buffer)), if !self.packets_present_in_tunnel => {
                         match recv_res {
                             Ok(bytes_read) => {
+                                let mut decapsulated = self.decapsulated_pool.take();
                                 match peer.decapsulate(
                                     None,
                                     self.receiving_buffer.get(..bytes_read).unwrap_or(&[]),
-                                    &mut *self.sending_buffer,
+                                    &mut decapsulated,
                                 ) {
                                     // Handshake packets. See the comment above Tunn::decapsulate() for this case.
                                     TunnResult::WriteToNetwork(packet) => {
@@ -321,7 +337,10 @@ impl Runtime for State {
                                     },
                                     // Starcast packets
                                     TunnResult::WriteToTunnel(packet, _) => {
-                                        permit.send(packet.to_vec());
+                                        // The packet is decapsulated to the start of the buffer.
+                                        let len = packet.len();
+                                        decapsulated.truncate(len);
+                                        permit.send(decapsulated);
                                     },
                                     TunnResult::Done => (),
                                     TunnResult::Err(e) => {
@@ -352,8 +371,13 @@ mod tests {
         let (chan_a, chan_a_internal) = Chan::pipe();
         let (mut chan_b, chan_b_internal) = Chan::pipe();
 
-        let starcast_vpeer_a = StarcastPeer::start(chan_a_internal, false).await.unwrap();
-        let starcast_vpeer_b = StarcastPeer::start(chan_b_internal, false).await.unwrap();
+        let policy = RelayPolicy::default();
+        let starcast_vpeer_a = StarcastPeer::start(chan_a_internal, false, &policy)
+            .await
+            .unwrap();
+        let starcast_vpeer_b = StarcastPeer::start(chan_b_internal, false, &policy)
+            .await
+            .unwrap();
 
         let a_peer = starcast_vpeer_a.get_peer().await.unwrap();
         let b_peer = starcast_vpeer_b.get_peer().await.unwrap();
@@ -375,9 +399,9 @@ mod tests {
         starcast_vpeer_a.configure(config_a).await.unwrap();
         starcast_vpeer_b.configure(config_b).await.unwrap();
 
-        chan_a.tx.send(udp_packet.clone()).await.unwrap();
+        chan_a.tx.send(udp_packet.clone().into()).await.unwrap();
         let received_bytes = chan_b.rx.recv().await.unwrap();
 
-        assert_eq!(udp_packet, received_bytes)
+        assert_eq!(udp_packet, *received_bytes)
     }
 }
@@ -20,16 +20,15 @@ use telio_utils::{
     exponential_backoff::{
         Backoff, Error as ExponentialBackoffError, ExponentialBackoff, ExponentialBackoffBounds,
     },
-    PinnedSleep,
+    PacketPool, PinnedSleep, PooledPacket,
 };
 use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn};
 
-use telio_model::constants::{
-    IPV4_MULTICAST_NETWORK, IPV4_STARCAST_ADDRESS, IPV6_MULTICAST_NETWORK, IPV6_STARCAST_ADDRESS,
-};
+use telio_model::constants::{IPV4_STARCAST_ADDRESS, IPV6_STARCAST_ADDRESS};
 
 use crate::{
     nat::{self, Nat, StarcastNat},
+    relay::{LoopGuard, RelayPolicy},
     utils::MutableIpPacket,
 };
 
@@ -40,6 +39,8 @@ const MULTICAST_TRANSPORT_PORT: u16 = 0;
 #[cfg(not(feature = "test-util"))]
 const MULTICAST_TRANSPORT_PORT: u16 = 13569;
 const MAX_PACKET_SIZE: usize = u16::MAX as usize;
+/// Number of the received packets kept for reuse
+const POOLED_PACKETS: usize = 16;
 
 /// Starcast transport-specific errors
 #[derive(Debug, thiserror::Error)]
@@ -111,12 +112,14 @@ impl Transport {
     /// * meshnet_ip - The meshnet IP of the node on which this component is currently running
     /// * socket_pool - To create the transport socket
     /// * packet_chan - A channel to send packets to and receive packets from the virtual peer component
+    /// * policy - Groups and broadcasts to relay
     pub async fn start(
         meshnet_ip: IpAddr,
         socket_pool: Arc<SocketPool>,
-        packet_chan: Chan<Vec<u8>>,
+        packet_chan: Chan<PooledPacket>,
+        policy: RelayPolicy,
     ) -> Result<Self, Error> {
-        let multicast_ips = vec![IPV4_MULTICAST_NETWORK.into(), IPV6_MULTICAST_NETWORK.into()];
+        let multicast_ips = policy.networks();
         let exponential_backoff = ExponentialBackoff::new(ExponentialBackoffBounds {
             initial: Duration::from_secs(2),
             maximal: Some(Duration::from_secs(120)),
@@ -139,18 +142,22 @@ impl Transport {
                     None
                 }
             };
+        let pool = PacketPool::new("starcast-transport", MAX_PACKET_SIZE, POOLED_PACKETS);
 
         Ok(Self {
             task: Task::start(State {
                 transport_socket,
                 packet_chan,
                 multicast_ips,
-                recv_buffer: vec![0; MAX_PACKET_SIZE],
+                recv_buffer: pool.take(),
+                pool,
                 nat: StarcastNat::new(IPV4_STARCAST_ADDRESS, IPV6_STARCAST_ADDRESS),
                 peers: Vec::new(),
                 socket_pool,
                 meshnet_ip,
                 exponential_backoff,
+                policy,
+                loop_guard: LoopGuard::new(),
             }),
         })
     }
@@ -189,14 +196,18 @@ async fn open_transport_socket(
 
 struct State {
     transport_socket: Option<Arc<UdpSocket>>,
-    packet_chan: Chan<Vec<u8>>,
-    recv_buffer: Vec<u8>,
+    packet_chan: Chan<PooledPacket>,
+    /// Buffer the next packet is received into, passed on to the virtual peer as it is
+    recv_buffer: PooledPacket,
+    pool: PacketPool,
     nat: StarcastNat,
     peers: Vec<Peer>,
     multicast_ips: Vec<IpNet>,
     socket_pool: Arc<SocketPool>,
     meshnet_ip: IpAddr,
     exponential_backoff: ExponentialBackoff,
+    policy: RelayPolicy,
+    loop_guard: LoopGuard,
 }
 
 impl State {
@@ -227,10 +238,21 @@ impl State {
         };
     }
 
-    async fn handle_local_multicast_packet(&mut self, packet: Vec<u8>) -> Result<(), Error> {
+    async fn handle_local_multicast_packet(
+        &mut self,
+        mut packet: PooledPacket,
+    ) -> Result<(), Error> {
         let Some(transport_socket) = self.transport_socket.as_ref() else {
             return Err(Error::TransportSocketNotOpen);
         };
+        if !self.loop_guard.admit_local(&packet) {
+            telio_log_debug!("Dropping multicast packet injected just before");
+            return Ok(());
+        }
+        if !self.policy.pass_hop(&mut packet) {
+            telio_log_debug!("Dropping multicast packet with its TTL exhausted");
+            return Ok(());
+        }
         // If peer_allows_multicast is false for a peer, we cannot send multicast packets to that peer,
         // but we can still receive multicast packets from that peer.
         let failed_peers = join_all(self.peers.iter().filter(|p| p.peer_allows_multicast).map(
@@ -252,7 +274,10 @@ impl State {
         }
     }
 
-    async fn handle_mapped_unicast_packet(&mut self, mut packet: Vec<u8>) -> Result<(), Error> {
+    async fn handle_mapped_unicast_packet(
+        &mut self,
+        mut packet: PooledPacket,
+    ) -> Result<(), Error> {
         let peer_ip = self
             .nat
             .translate_outgoing(&mut packet)
@@ -276,13 +301,19 @@ impl State {
     /// meshnet nodes and dropping those packets if multicast isn't allowed for those nodes.
     async fn handle_incoming_packet(
         &mut self,
-        mut packet: Vec<u8>,
-        send_permit: tokio::sync::mpsc::OwnedPermit<Vec<u8>>,
+        mut packet: PooledPacket,
+        send_permit: tokio::sync::mpsc::OwnedPermit<PooledPacket>,
     ) -> Result<(), Error> {
+        let multicast = self.has_multicast_dst(&mut packet)?;
         let peer_ip = self
             .nat
             .translate_incoming(&mut packet)
             .map_err(Error::NatError)?;
+        // Recognized as translated, which is how the local packets are seen
+        if multicast && !self.loop_guard.admit_remote(&packet) {
+            telio_log_debug!("Dropping multicast packet sent to the peers just before");
+            return Ok(());
+        }
         if self
             .peers
             .iter()
@@ -358,8 +389,8 @@ impl Runtime for State {
                 }
             }
             Some((permit, Ok(bytes_read))) = wait_for_tx(&self.packet_chan.tx, transport_socket.recv(&mut self.recv_buffer)) => {
-                #[allow(clippy::expect_used)]
-                let packet = self.recv_buffer.get(..bytes_read).expect("We know bytes_read bytes should be in the buffer at this point").to_vec();
+                let mut packet = std::mem::replace(&mut self.recv_buffer, self.pool.take());
+                packet.truncate(bytes_read);
                 self.handle_incoming_packet(packet, permit).await
             }
             else => {
@@ -390,7 +421,7 @@ mod tests {
     struct Scaffold {
         task: Task<State>,
         transport_socket: Arc<UdpSocket>,
-        channel: Chan<Vec<u8>>,
+        channel: Chan<PooledPacket>,
         peers: Vec<(PublicKey, UdpSocket, bool, bool)>,
     }
 
@@ -436,16 +467,20 @@ mod tests {
                 maximal: Some(Duration::from_secs(120)),
             };
 
+            let pool = PacketPool::new("test", TEST_MAX_PACKET_SIZE, 1);
             let task = Task::start(State {
                 transport_socket: Some(transport_socket.clone()),
                 packet_chan,
-                recv_buffer: vec![0; TEST_MAX_PACKET_SIZE],
+                recv_buffer: pool.take(),
+                pool,
                 nat: StarcastNat::new(IPV4_NAT_ADDR, IPV6_NAT_ADDR),
                 peers: task_peers,
                 exponential_backoff: ExponentialBackoff::new(exponential_backoff_bounds).unwrap(),
                 multicast_ips,
                 meshnet_ip: IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
                 socket_pool: Arc::new(socket_pool),
+                policy: RelayPolicy::default(),
+                loop_guard: LoopGuard::new(),
             });
 
             Self {
@@ -470,12 +505,14 @@ mod tests {
     #[tokio::test]
     async fn test_transport_socket_wait() {
         let (packet_chan, external_packet_chan) = Chan::pipe();
+        let pool = PacketPool::new("test", TEST_MAX_PACKET_SIZE, 1);
         // The `transport_socket` field get's abstracted away and becomes inaccessible from the `Scaffold`
         // struct, so just creating the `State` and running `.wait()` manually.
         let mut state = State {
             transport_socket: None,
             packet_chan,
-            recv_buffer: vec![0; TEST_MAX_PACKET_SIZE],
+            recv_buffer: pool.take(),
+            pool,
             nat: StarcastNat::new(IPV4_NAT_ADDR, IPV6_NAT_ADDR),
             peers: vec![],
             exponential_backoff: ExponentialBackoff::new(ExponentialBackoffBounds {
@@ -493,6 +530,8 @@ mod tests {
                 )
                 .unwrap(),
             )),
+            policy: RelayPolicy::default(),
+            loop_guard: LoopGuard::new(),
         };
 
         // Dropping the packet channel here so the wait loop doesn't wait forever for a packet to arrive
@@ -509,7 +548,12 @@ mod tests {
 
         let packet = make_udp_v4("127.0.0.1:12345", "224.0.0.251:5353");
 
-        scaffold.channel.tx.send(packet.clone()).await.unwrap();
+        scaffold
+            .channel
+            .tx
+            .send(packet.clone().into())
+            .await
+            .unwrap();
 
         for (_, socket, _, peer_allows_multicast) in &scaffold.peers {
             let mut buffer = vec![0; TEST_MAX_PACKET_SIZE];
@@ -550,7 +594,7 @@ mod tests {
         assert_eq!(src_ip, IPV4_NAT_ADDR);
 
         let packet = make_udp_v4_reply(&packet);
-        scaffold.channel.tx.send(packet.clone()).await.unwrap();
+        scaffold.channel.tx.send(packet.into()).await.unwrap();
 
         tokio::task::yield_now().await;
 
@@ -626,13 +670,16 @@ mod tests {
                         .unwrap(),
                 )),
                 packet_chan: Chan::new(1),
-                recv_buffer: Vec::new(),
+                recv_buffer: Vec::new().into(),
+                pool: PacketPool::new("test", 0, 0),
                 nat: StarcastNat::new(IPV4_NAT_ADDR, IPV6_NAT_ADDR),
                 peers: Vec::new(),
                 exponential_backoff: ExponentialBackoff::new(exponential_backoff_bounds).unwrap(),
                 multicast_ips,
                 meshnet_ip: IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
                 socket_pool: Arc::new(socket_pool),
+                policy: RelayPolicy::default(),
+                loop_guard: LoopGuard::new(),
             }
         }
 
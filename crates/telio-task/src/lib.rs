@@ -16,6 +16,7 @@ mod macros;
 mod task;
 
 pub mod io;
+pub mod supervisor;
 
 pub use macros::*;
 pub use task::BoxAction;
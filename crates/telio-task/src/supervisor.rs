@@ -0,0 +1,467 @@
+//! Supervision of the running tasks.
+//!
+//! Every [Task] is registered, while it runs, in the [TaskRegistry] it was started in, so that
+//! the tasks of separate devices in one process are supervised separately. A [Supervisor]
+//! periodically sends each task of its registry a heartbeat, an empty action, which the task
+//! executes in between the events it handles. A task which does not execute it within the stall
+//! timeout is stalled, e.g. wedged on an await which never completes, and a task which stopped
+//! on its own, with an error or a panic, is dead. Once a task turns unhealthy, the policy
+//! configured for it is handed to the handler of the supervisor, applying it is up to the owner
+//! of the tasks.
+//!
+//! The checks run on a thread of their own, so they keep going while the runtime of the
+//! supervised tasks is wedged.
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use futures::future::{join_all, BoxFuture};
+use telio_utils::{interval_after, telio_log_warn};
+use tokio::{
+    sync::oneshot,
+    time::{timeout, Instant},
+};
+
+#[cfg(doc)]
+use crate::Task;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static CURRENT_REGISTRY: TaskRegistry;
+}
+
+/// Sends an empty action to the task, resolves to whether the task executed it
+pub(crate) type Heartbeat = Box<dyn Fn() -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Applies the policy of a task which turned unhealthy, on the thread of the supervisor
+pub type ActionHandler<P> = Box<dyn Fn(SupervisionAction<P>) -> BoxFuture<'static, ()> + Send>;
+
+type Monitors = Mutex<Vec<Arc<TaskMonitor>>>;
+
+/// Tasks supervised together, e.g. the ones of a device
+///
+/// A [Task] is registered in the registry of the [TaskRegistry::scope] it is started in, the
+/// tasks it starts in turn inheriting it. Tasks started outside of any scope are not supervised.
+#[derive(Clone, Default)]
+pub struct TaskRegistry(Arc<Monitors>);
+
+impl TaskRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run the `future` with the tasks it starts registered in this registry
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_REGISTRY.scope(self, future).await
+    }
+
+    /// Registry of the scope the caller runs in
+    pub(crate) fn current() -> Self {
+        CURRENT_REGISTRY.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    /// Register the task `name` for the supervision
+    pub(crate) fn register(&self, name: &'static str, heartbeat: Heartbeat) -> Arc<TaskMonitor> {
+        let monitor = Arc::new(TaskMonitor {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            exited: AtomicBool::new(false),
+            heartbeat,
+            registry: Arc::downgrade(&self.0),
+        });
+        lock_monitors(&self.0).push(monitor.clone());
+        monitor
+    }
+
+    fn monitors(&self) -> Vec<Arc<TaskMonitor>> {
+        lock_monitors(&self.0).clone()
+    }
+}
+
+/// Liveness of a running task
+pub(crate) struct TaskMonitor {
+    id: u64,
+    name: &'static str,
+    exited: AtomicBool,
+    heartbeat: Heartbeat,
+    registry: Weak<Monitors>,
+}
+
+impl TaskMonitor {
+    /// Stop supervising the task, e.g. when it is stopped on purpose
+    pub(crate) fn deregister(&self) {
+        if let Some(monitors) = self.registry.upgrade() {
+            lock_monitors(&monitors).retain(|monitor| monitor.id != self.id);
+        }
+    }
+}
+
+/// Marks the task as exited when dropped, also while unwinding from a panic
+pub(crate) struct ExitGuard(pub(crate) Arc<TaskMonitor>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.exited.store(true, Ordering::Relaxed);
+    }
+}
+
+fn lock_monitors(monitors: &Monitors) -> MutexGuard<'_, Vec<Arc<TaskMonitor>>> {
+    monitors.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Health of a task
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskHealth {
+    /// The task executed the heartbeat in time
+    Healthy,
+    /// The task did not execute the heartbeat within the stall timeout
+    Stalled,
+    /// The task stopped on its own, with an error or a panic
+    Dead,
+}
+
+/// Health of a task as of the last check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskStatus {
+    /// Name of the task, see [crate::Runtime::NAME]
+    pub name: String,
+    /// Health of the task
+    pub health: TaskHealth,
+    /// How long the task took to execute the heartbeat, in milliseconds
+    pub heartbeat_ms: Option<u64>,
+}
+
+/// Policy to apply to a task which turned unhealthy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SupervisionAction<P> {
+    /// Name of the task
+    pub task: String,
+    /// Health of the task
+    pub health: TaskHealth,
+    /// Policy configured for the task
+    pub policy: P,
+}
+
+/// Configuration of a [Supervisor]
+#[derive(Clone, Debug)]
+pub struct SupervisorConfig<P> {
+    /// Period of the heartbeats
+    pub check_interval: Duration,
+    /// How long the tasks have to execute a heartbeat
+    pub stall_timeout: Duration,
+    /// Policy of the tasks without one of their own
+    pub default_policy: P,
+    /// Policies of the tasks, by name
+    pub policies: HashMap<String, P>,
+}
+
+struct Checks<P> {
+    config: SupervisorConfig<P>,
+    statuses: Vec<TaskStatus>,
+    /// Tasks which are unhealthy already, by id
+    unhealthy: HashSet<u64>,
+}
+
+impl<P: Clone> Checks<P> {
+    /// Record the `checked` statuses, returning the policies of the tasks which turned unhealthy
+    fn update(&mut self, checked: Vec<(u64, TaskStatus)>) -> Vec<SupervisionAction<P>> {
+        let ids: HashSet<u64> = checked.iter().map(|(id, _)| *id).collect();
+        self.unhealthy.retain(|id| ids.contains(id));
+
+        let mut actions = Vec::new();
+        for (id, status) in &checked {
+            if status.health == TaskHealth::Healthy {
+                self.unhealthy.remove(id);
+                continue;
+            }
+            if self.unhealthy.insert(*id) {
+                telio_log_warn!("Task {} is {:?}", status.name, status.health);
+                let policy = self
+                    .config
+                    .policies
+                    .get(&status.name)
+                    .unwrap_or(&self.config.default_policy);
+                actions.push(SupervisionAction {
+                    task: status.name.clone(),
+                    health: status.health,
+                    policy: policy.clone(),
+                });
+            }
+        }
+
+        self.statuses = checked.into_iter().map(|(_, status)| status).collect();
+        actions
+    }
+}
+
+/// Periodic health check of the tasks
+pub struct Supervisor<P> {
+    checks: Arc<Mutex<Checks<P>>>,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<P: Clone + Send + 'static> Supervisor<P> {
+    /// Start checking the tasks of the `registry` every `config.check_interval`, on a thread of
+    /// its own. The `handler` is called with the policy of every task which turns unhealthy.
+    pub fn start(
+        config: SupervisorConfig<P>,
+        registry: TaskRegistry,
+        handler: ActionHandler<P>,
+    ) -> io::Result<Self> {
+        let (check_interval, stall_timeout) = (config.check_interval, config.stall_timeout);
+        let checks = Arc::new(Mutex::new(Checks {
+            config,
+            statuses: Vec::new(),
+            unhealthy: HashSet::new(),
+        }));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let (stop, stopped) = oneshot::channel();
+        let thread = std::thread::Builder::new()
+            .name("telio-supervisor".to_owned())
+            .spawn({
+                let checks = checks.clone();
+                move || {
+                    runtime.block_on(async move {
+                        let mut interval = interval_after(check_interval, check_interval);
+                        let checking = async {
+                            loop {
+                                interval.tick().await;
+                                let checked = check(&registry, stall_timeout).await;
+                                let actions = lock(&checks).update(checked);
+                                for action in actions {
+                                    handler(action).await;
+                                }
+                            }
+                        };
+                        tokio::select! {
+                            _ = checking => {},
+                            _ = stopped => {},
+                        }
+                    })
+                }
+            })?;
+
+        Ok(Self {
+            checks,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Health of every task as of the last check
+    pub fn report(&self) -> Vec<TaskStatus> {
+        lock(&self.checks).statuses.clone()
+    }
+
+    /// Stop checking the tasks, waiting for the policy being applied, if any, to be dropped
+    pub fn stop(mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                telio_log_warn!("Supervisor thread panicked");
+            }
+        }
+    }
+}
+
+impl<P> Drop for Supervisor<P> {
+    fn drop(&mut self) {
+        // Dropping the sender stops the thread, which is left to finish on its own
+        self.stop.take();
+    }
+}
+
+fn lock<P>(checks: &Mutex<Checks<P>>) -> MutexGuard<'_, Checks<P>> {
+    checks.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+async fn check(registry: &TaskRegistry, stall_timeout: Duration) -> Vec<(u64, TaskStatus)> {
+    join_all(registry.monitors().into_iter().map(|monitor| async move {
+        let started = Instant::now();
+        let (health, heartbeat_ms) = if monitor.exited.load(Ordering::Relaxed) {
+            (TaskHealth::Dead, None)
+        } else {
+            match timeout(stall_timeout, (monitor.heartbeat)()).await {
+                Ok(true) => (
+                    TaskHealth::Healthy,
+                    Some(started.elapsed().as_millis() as u64),
+                ),
+                Ok(false) => (TaskHealth::Dead, None),
+                Err(_) => (TaskHealth::Stalled, None),
+            }
+        };
+        let status = TaskStatus {
+            name: monitor.name.to_owned(),
+            health,
+            heartbeat_ms,
+        };
+        (monitor.id, status)
+    }))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{task_exec, Runtime, Task};
+    use async_trait::async_trait;
+    use futures::{future::pending, FutureExt};
+    use tokio::time::sleep;
+
+    const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+    struct Wedgeable;
+
+    #[async_trait]
+    impl Runtime for Wedgeable {
+        const NAME: &'static str = "Wedgeable";
+        type Err = ();
+    }
+
+    struct Doomed;
+
+    #[async_trait]
+    impl Runtime for Doomed {
+        const NAME: &'static str = "Doomed";
+        type Err = ();
+    }
+
+    type Actions = Arc<Mutex<Vec<SupervisionAction<&'static str>>>>;
+
+    fn supervisor(registry: &TaskRegistry) -> (Supervisor<&'static str>, Actions) {
+        let actions = Actions::default();
+        let supervisor = Supervisor::start(
+            SupervisorConfig {
+                check_interval: CHECK_INTERVAL,
+                stall_timeout: CHECK_INTERVAL / 2,
+                default_policy: "escalate",
+                policies: [("Wedgeable".to_owned(), "restart")].into(),
+            },
+            registry.clone(),
+            Box::new({
+                let actions = actions.clone();
+                move |action| {
+                    actions
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(action);
+                    async {}.boxed()
+                }
+            }),
+        )
+        .unwrap();
+        (supervisor, actions)
+    }
+
+    fn health_of(supervisor: &Supervisor<&'static str>, name: &str) -> Option<TaskHealth> {
+        supervisor
+            .report()
+            .into_iter()
+            .find(|status| status.name == name)
+            .map(|status| status.health)
+    }
+
+    fn take_actions(actions: &Actions) -> Vec<SupervisionAction<&'static str>> {
+        std::mem::take(&mut *actions.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Wait for the checks to report the `health` of the task `name`
+    async fn wait_for(
+        supervisor: &Supervisor<&'static str>,
+        name: &str,
+        health: Option<TaskHealth>,
+    ) {
+        let deadline = Instant::now() + CHECK_INTERVAL * 40;
+        while health_of(supervisor, name) != health {
+            assert!(Instant::now() < deadline, "{name} is not {health:?}");
+            sleep(CHECK_INTERVAL / 5).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_tasks_are_reported() {
+        let registry = TaskRegistry::new();
+        let (supervisor, actions) = supervisor(&registry);
+        let task = Arc::new(registry.scope(async { Task::start(Wedgeable) }).await);
+
+        wait_for(&supervisor, "Wedgeable", Some(TaskHealth::Healthy)).await;
+        assert!(take_actions(&actions).is_empty());
+
+        tokio::spawn({
+            let task = task.clone();
+            async move {
+                let _ = task_exec!(&task, async move |_| pending::<Result<(), ()>>().await).await;
+            }
+        });
+        wait_for(&supervisor, "Wedgeable", Some(TaskHealth::Stalled)).await;
+        assert_eq!(
+            take_actions(&actions),
+            vec![SupervisionAction {
+                task: "Wedgeable".to_owned(),
+                health: TaskHealth::Stalled,
+                policy: "restart",
+            }]
+        );
+
+        // Reported once until it recovers
+        sleep(CHECK_INTERVAL * 3).await;
+        assert!(take_actions(&actions).is_empty());
+
+        supervisor.stop();
+    }
+
+    #[tokio::test]
+    async fn dead_tasks_are_reported_until_stopped() {
+        let registry = TaskRegistry::new();
+        let (supervisor, actions) = supervisor(&registry);
+        let task = registry.scope(async { Task::start(Doomed) }).await;
+
+        let _ = task_exec!(&task, async move |_| Err::<(), ()>(())).await;
+        wait_for(&supervisor, "Doomed", Some(TaskHealth::Dead)).await;
+        assert_eq!(
+            take_actions(&actions),
+            vec![SupervisionAction {
+                task: "Doomed".to_owned(),
+                health: TaskHealth::Dead,
+                policy: "escalate",
+            }]
+        );
+
+        let _ = task.stop().await;
+        wait_for(&supervisor, "Doomed", None).await;
+
+        supervisor.stop();
+    }
+
+    #[tokio::test]
+    async fn tasks_are_supervised_by_the_registry_they_are_started_in() {
+        let (registry, other) = (TaskRegistry::new(), TaskRegistry::new());
+        let (supervisor, _) = supervisor(&registry);
+        let task = other.scope(async { Task::start(Wedgeable) }).await;
+        let unscoped = Task::start(Doomed);
+
+        sleep(CHECK_INTERVAL * 3).await;
+        assert!(supervisor.report().is_empty());
+        assert_eq!(other.monitors().len(), 1);
+
+        let _ = task.stop().await;
+        let _ = unscoped.stop().await;
+        assert!(other.monitors().is_empty());
+        supervisor.stop();
+    }
+}
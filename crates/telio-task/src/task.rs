@@ -12,9 +12,12 @@ use tokio::{
 
 use telio_utils::telio_log_warn;
 
-use crate::io::{
-    chan::{Rx, Tx},
-    Chan,
+use crate::{
+    io::{
+        chan::{Rx, Tx},
+        Chan,
+    },
+    supervisor::{ExitGuard, Heartbeat, TaskMonitor, TaskRegistry},
 };
 
 /// Runtime implementation for a [Task]'s state
@@ -93,6 +96,7 @@ pub struct Task<S: Runtime> {
     stop: Arc<Notify>,
     execute: Tx<Update<S, S::Err>>,
     join: Option<JoinHandle<Result<(), S::Err>>>,
+    monitor: Arc<TaskMonitor>,
 }
 
 /// Task was stopped during execution
@@ -152,8 +156,14 @@ where
             rx: execute_rx,
         } = Chan::<Update<S, S::Err>>::default();
 
+        // Tasks started by this one are supervised together with it
+        let registry = TaskRegistry::current();
+        let monitor = registry.register(S::NAME, Self::heartbeat(execute.clone()));
+
         let stopped = stop.clone();
-        let join = Some(tokio::spawn(async move {
+        let exit = ExitGuard(monitor.clone());
+        let join = Some(tokio::spawn(registry.scope(async move {
+            let _exit = exit;
             tokio::select! {
                 res = Self::run_loop(&mut state, execute_rx) => {
                     state.stop().await;
@@ -164,7 +174,7 @@ where
                     Ok(())
                 },
             }
-        }));
+        })));
 
         println!("task started - {}", S::NAME);
 
@@ -172,9 +182,25 @@ where
             stop,
             execute,
             join,
+            monitor,
         }
     }
 
+    /// Heartbeat of the supervision, an action doing nothing
+    #[allow(mpsc_blocking_send)]
+    fn heartbeat(execute: Tx<Update<S, S::Err>>) -> Heartbeat {
+        Box::new(move || {
+            let execute = execute.clone();
+            async move {
+                let (tx, rx) = oneshot::channel();
+                let act: BoxAction<S, Result<AnySend, S::Err>> =
+                    Box::new(|_: &mut S| ready(Ok(Box::new(()) as AnySend)).boxed());
+                execute.send((act, tx)).await.is_ok() && rx.await.is_ok()
+            }
+            .boxed()
+        })
+    }
+
     /// Execute action with exclusive access on state
     #[allow(mpsc_blocking_send)]
     pub async fn exec<A, V>(&self, action: A) -> Result<V, ExecError>
@@ -201,6 +227,7 @@ where
     /// Stop task
     pub async fn stop(mut self) -> StopResult<S::Err> {
         println!("task stopped - {}", S::NAME);
+        self.monitor.deregister();
         self.stop.notify_one();
         let join = match self.join.take() {
             Some(v) => v,
@@ -239,6 +266,7 @@ where
 
 impl<S: Runtime> Drop for Task<S> {
     fn drop(&mut self) {
+        self.monitor.deregister();
         if self.join.is_some() {
             self.stop.notify_waiters();
             telio_log_warn!("Task [{}] was not stopped.", S::NAME);
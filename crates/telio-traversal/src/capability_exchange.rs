@@ -0,0 +1,315 @@
+//! Exchange of the protocol version and the optional features with the meshnet peers.
+//!
+//! Every new peer is sent the local capabilities over the relay, asking for its own in return.
+//! The request is retried every [RETRY_PERIOD] until the peer answers or [MAX_ATTEMPTS] are
+//! made, after which the peer is considered to be a legacy one, speaking protocol version 0 and
+//! supporting no optional features. Peers announcing their capabilities later on, e.g. after an
+//! upgrade, are renegotiated with.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::Future;
+use telio_crypto::PublicKey;
+use telio_model::mesh::PeerCapability;
+use telio_proto::{CapabilitiesMsg, NegotiatedCapabilities};
+use telio_task::{io::Chan, task_exec, BoxAction, Runtime, Task};
+use telio_utils::{interval_after, telio_log_debug, telio_log_info, telio_log_warn};
+use tokio::{sync::mpsc::error::SendError, time::Interval};
+
+/// Period of the retries of the unanswered capability requests
+const RETRY_PERIOD: Duration = Duration::from_secs(5);
+/// Capability requests sent to a peer before it is considered to be a legacy one
+const MAX_ATTEMPTS: u32 = 6;
+
+/// Possible [CapabilityExchange] errors.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// Channel error
+    #[error(transparent)]
+    SendCapabilitiesMsgErr(#[from] SendError<(PublicKey, CapabilitiesMsg)>),
+    /// Task encountered an error while running
+    #[error(transparent)]
+    Task(#[from] telio_task::ExecError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Capability exchange with a single peer
+#[derive(Debug)]
+enum Exchange {
+    /// Capability requests sent so far
+    Pending(u32),
+    Negotiated(NegotiatedCapabilities),
+}
+
+pub struct CapabilityExchange {
+    task: Task<State>,
+}
+
+struct State {
+    intercoms: Chan<(PublicKey, CapabilitiesMsg)>,
+    local: CapabilitiesMsg,
+    peers: HashMap<PublicKey, Exchange>,
+    retry_timer: Interval,
+}
+
+impl CapabilityExchange {
+    /// Exchange the local `capabilities` with the peers over the `intercoms`
+    pub fn new(
+        intercoms: Chan<(PublicKey, CapabilitiesMsg)>,
+        capabilities: impl IntoIterator<Item = PeerCapability>,
+    ) -> Self {
+        telio_log_info!("Starting capability exchange module");
+        Self {
+            task: Task::start(State {
+                intercoms,
+                local: CapabilitiesMsg::local(capabilities),
+                peers: HashMap::new(),
+                retry_timer: interval_after(RETRY_PERIOD, RETRY_PERIOD),
+            }),
+        }
+    }
+
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+
+    /// Exchange the capabilities with the new ones of the meshnet `peers`, the removed ones are
+    /// forgotten
+    pub async fn set_peers(&self, peers: HashSet<PublicKey>) -> Result<()> {
+        task_exec!(&self.task, async move |s| {
+            s.set_peers(peers).await;
+            Ok(())
+        })
+        .await
+        .map_err(Error::Task)
+    }
+
+    /// Outcome of the exchanges finished so far
+    pub async fn get_negotiated(&self) -> Result<HashMap<PublicKey, NegotiatedCapabilities>> {
+        task_exec!(&self.task, async move |s| {
+            Ok(s.peers
+                .iter()
+                .filter_map(|(public_key, exchange)| match exchange {
+                    Exchange::Negotiated(negotiated) => Some((*public_key, negotiated.clone())),
+                    Exchange::Pending(_) => None,
+                })
+                .collect())
+        })
+        .await
+        .map_err(Error::Task)
+    }
+}
+
+impl State {
+    async fn set_peers(&mut self, peers: HashSet<PublicKey>) {
+        self.peers
+            .retain(|public_key, _| peers.contains(public_key));
+        for public_key in peers {
+            if self.peers.contains_key(&public_key) {
+                continue;
+            }
+            self.peers.insert(public_key, Exchange::Pending(1));
+            self.send(public_key, true).await.unwrap_or_else(|e| {
+                telio_log_warn!("Failed to request capabilities of {public_key:?}: {e:?}")
+            });
+        }
+    }
+
+    async fn send(&self, public_key: PublicKey, reply_requested: bool) -> Result<()> {
+        let msg = CapabilitiesMsg {
+            reply_requested,
+            ..self.local.clone()
+        };
+        #[allow(mpsc_blocking_send)]
+        self.intercoms
+            .tx
+            .send((public_key, msg))
+            .await
+            .map_err(Error::SendCapabilitiesMsgErr)
+    }
+
+    async fn handle_capabilities_msg(
+        &mut self,
+        public_key: PublicKey,
+        msg: CapabilitiesMsg,
+    ) -> Result<()> {
+        telio_log_debug!("Capabilities of {public_key:?} received: {msg:?}");
+        if msg.reply_requested {
+            self.send(public_key, false).await?;
+        }
+
+        let negotiated = self.local.negotiate(&msg);
+        telio_log_info!("Negotiated with {public_key:?}: {negotiated:?}");
+        self.peers
+            .insert(public_key, Exchange::Negotiated(negotiated));
+        Ok(())
+    }
+
+    async fn handle_tick(&mut self) -> Result<()> {
+        let mut retried = Vec::new();
+        for (public_key, exchange) in self.peers.iter_mut() {
+            let Exchange::Pending(attempts) = exchange else {
+                continue;
+            };
+            if *attempts < MAX_ATTEMPTS {
+                *attempts += 1;
+                retried.push(*public_key);
+            } else {
+                telio_log_info!("{public_key:?} did not announce its capabilities, using none");
+                *exchange = Exchange::Negotiated(NegotiatedCapabilities::default());
+            }
+        }
+
+        for public_key in retried {
+            self.send(public_key, true).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "CapabilityExchange";
+    type Err = ();
+
+    async fn wait_with_update<F>(&mut self, update: F) -> std::result::Result<(), Self::Err>
+    where
+        F: Future<Output = BoxAction<Self, std::result::Result<(), Self::Err>>> + Send,
+    {
+        tokio::select! {
+            Some((public_key, msg)) = self.intercoms.rx.recv() => {
+                self.handle_capabilities_msg(public_key, msg)
+                    .await
+                    .unwrap_or_else(|e| {
+                        telio_log_warn!("Failed to process capabilities: {:?}", e);
+                    });
+            }
+            _ = self.retry_timer.tick() => {
+                self.handle_tick()
+                    .await
+                    .unwrap_or_else(|e| {
+                        telio_log_warn!("Failed to retry capability requests: {:?}", e);
+                    });
+            }
+            update = update => {
+                return update(self).await;
+            }
+            else => {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_proto::PROTOCOL_VERSION;
+    use tokio::time;
+
+    const PEER: PublicKey = PublicKey([1; 32]);
+
+    fn setup() -> (CapabilityExchange, Chan<(PublicKey, CapabilitiesMsg)>) {
+        let (intercoms_them, intercoms_us) = Chan::pipe();
+        let exchange = CapabilityExchange::new(
+            intercoms_us,
+            [PeerCapability::Ipv6Candidates, PeerCapability::PqHybrid],
+        );
+        (exchange, intercoms_them)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn capabilities_are_negotiated_on_first_contact() {
+        let (exchange, mut intercoms_them) = setup();
+
+        exchange.set_peers([PEER].into()).await.unwrap();
+        let (public_key, request) = intercoms_them.rx.recv().await.unwrap();
+        assert_eq!(public_key, PEER);
+        assert!(request.reply_requested);
+        assert_eq!(request.version, PROTOCOL_VERSION);
+        assert!(exchange.get_negotiated().await.unwrap().is_empty());
+
+        let reply = CapabilitiesMsg {
+            version: PROTOCOL_VERSION,
+            capabilities: [PeerCapability::PqHybrid, PeerCapability::CompressedCmm].into(),
+            reply_requested: false,
+        };
+        intercoms_them.tx.send((PEER, reply)).await.unwrap();
+        time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(
+            exchange.get_negotiated().await.unwrap(),
+            [(
+                PEER,
+                NegotiatedCapabilities {
+                    version: PROTOCOL_VERSION,
+                    capabilities: [PeerCapability::PqHybrid].into(),
+                }
+            )]
+            .into()
+        );
+
+        // Known peers are not asked again
+        exchange.set_peers([PEER].into()).await.unwrap();
+        time::sleep(RETRY_PERIOD * 2).await;
+        assert!(intercoms_them.rx.try_recv().is_err());
+
+        exchange.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peers_are_legacy() {
+        let (exchange, mut intercoms_them) = setup();
+
+        exchange.set_peers([PEER].into()).await.unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(intercoms_them.rx.recv().await.unwrap().1.reply_requested);
+        }
+        time::sleep(RETRY_PERIOD + Duration::from_millis(1)).await;
+
+        assert_eq!(
+            exchange.get_negotiated().await.unwrap(),
+            [(PEER, NegotiatedCapabilities::default())].into()
+        );
+        assert!(intercoms_them.rx.try_recv().is_err());
+
+        exchange.set_peers(HashSet::new()).await.unwrap();
+        assert!(exchange.get_negotiated().await.unwrap().is_empty());
+
+        exchange.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_answered() {
+        let (exchange, mut intercoms_them) = setup();
+
+        let request = CapabilitiesMsg {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: [PeerCapability::Ipv6Candidates].into(),
+            reply_requested: true,
+        };
+        intercoms_them.tx.send((PEER, request)).await.unwrap();
+
+        let (public_key, reply) = intercoms_them.rx.recv().await.unwrap();
+        assert_eq!(public_key, PEER);
+        assert_eq!(
+            reply,
+            CapabilitiesMsg::local([PeerCapability::Ipv6Candidates, PeerCapability::PqHybrid])
+        );
+        assert_eq!(
+            exchange.get_negotiated().await.unwrap()[&PEER],
+            NegotiatedCapabilities {
+                version: PROTOCOL_VERSION,
+                capabilities: [PeerCapability::Ipv6Candidates].into(),
+            }
+        );
+
+        exchange.stop().await;
+    }
+}
@@ -6,13 +6,16 @@ use crate::{
         Error as EndPointError, PongEvent,
     },
     endpoint_state::{EndpointState, EndpointStateMachine, Event},
+    lan_discovery::LanPeerEvent,
     last_rx_time_provider::{is_peer_alive, TimeSinceLastRxProvider},
     ping_pong_handler::PingPongHandler,
+    tcp_punch::{TcpPunchHop, TcpPuncher},
 };
 use async_trait::async_trait;
 use enum_map::EnumMap;
 use futures::Future;
 use std::fmt::Debug;
+use std::io;
 use std::sync::Arc;
 use std::time::Duration;
 use std::{
@@ -33,6 +36,10 @@ use tokio::time::Interval;
 const CPC_TIMEOUT: Duration = Duration::from_secs(10);
 const UPGRADE_TIMEOUT: Duration = Duration::from_secs(60);
 const MAX_SESSION_CANDIDATES: usize = 512;
+const MAX_LAN_PEERS: usize = 1024;
+
+/// Peer, session of the initiator and the outcome of a TCP hole punching attempt
+type TcpPunchResult = (PublicKey, Option<Session>, io::Result<TcpPunchHop>);
 
 #[cfg_attr(any(test, feature = "mockall"), mockall::automock)]
 #[async_trait]
@@ -111,6 +118,12 @@ pub struct Io {
     /// packet Pipes for sending messages to each other. Therefore this stores the instance of
     /// those channels
     pub intercoms: Chan<(PublicKey, CallMeMaybeMsg)>,
+
+    /// Peers announcing themselves on the LAN
+    ///
+    /// Their endpoints on the LAN are taken as validated right away, see
+    /// [crate::lan_discovery]. Nothing is received without the LAN discovery.
+    pub lan_peer_subscriber: chan::Rx<LanPeerEvent>,
 }
 
 type ExponentialBackoffProvider<E> = Box<dyn Fn() -> Result<E, Error> + Send>;
@@ -161,6 +174,25 @@ pub struct State<E: Backoff> {
 
     /// Session IDs received from other nodes in CMM requests
     session_id_candidates: LruCache<Session, PublicKey>,
+
+    /// TCP hole punching, attempted once the UDP pings of the STUN session time out
+    tcp_puncher: Option<Arc<TcpPuncher>>,
+
+    /// Connections opened by TCP hole punching, at most one per node
+    tcp_hops: HashMap<PublicKey, TcpPunchHop>,
+
+    /// Results of the TCP hole punching attempts running in the background
+    tcp_punch_results: Chan<TcpPunchResult>,
+
+    /// Peers announced on the LAN and when they were first announced, forgotten when they stop
+    /// announcing themselves
+    lan_peers: LruCache<PublicKey, (LanPeerEvent, Instant)>,
+
+    /// Peers the connection over the LAN failed with, whose announcements are ignored for a while
+    failed_lan_peers: LruCache<PublicKey, ()>,
+
+    /// Endpoints validated before a restart, taken as validated again for a while
+    restored_endpoints: LruCache<PublicKey, WireGuardEndpointCandidateChangeEvent>,
 }
 
 impl<E: Backoff> CrossPingCheck<E> {
@@ -171,6 +203,7 @@ impl<E: Backoff> CrossPingCheck<E> {
         poll_period: Duration,
         ping_pong_handler: Arc<Mutex<PingPongHandler>>,
         exponential_backoff_helper_provider: ExponentialBackoffProvider<E>,
+        tcp_puncher: Option<Arc<TcpPuncher>>,
     ) -> Self {
         let poll_timer = interval(poll_period);
         Self {
@@ -185,6 +218,12 @@ impl<E: Backoff> CrossPingCheck<E> {
                 ping_pong_handler,
                 exponential_backoff_helper_provider,
                 session_id_candidates: LruCache::new(UPGRADE_TIMEOUT, MAX_SESSION_CANDIDATES),
+                tcp_puncher,
+                tcp_hops: Default::default(),
+                tcp_punch_results: Chan::default(),
+                lan_peers: LruCache::new(UPGRADE_TIMEOUT, MAX_LAN_PEERS),
+                failed_lan_peers: LruCache::new(UPGRADE_TIMEOUT, MAX_LAN_PEERS),
+                restored_endpoints: LruCache::new(UPGRADE_TIMEOUT, MAX_SESSION_CANDIDATES),
             }),
         }
     }
@@ -198,6 +237,7 @@ impl CrossPingCheck {
         poll_period: Duration,
         ping_pong_handler: Arc<Mutex<PingPongHandler>>,
         exponential_backoff_bounds: ExponentialBackoffBounds,
+        tcp_puncher: Option<Arc<TcpPuncher>>,
     ) -> Self {
         telio_log_info!("Starting cross ping check");
 
@@ -210,12 +250,37 @@ impl CrossPingCheck {
             Box::new(move || {
                 ExponentialBackoff::new(exponential_backoff_bounds).map_err(Error::from)
             }),
+            tcp_puncher,
         )
     }
 
     pub async fn stop(self) {
         let _ = self.task.stop().await.resume_unwind();
     }
+
+    /// Take the `endpoint` validated before a restart as validated again, until the connection
+    /// over it fails or it expires. Endpoints of the nodes not in the config are ignored, so this
+    /// should be called once the config is set.
+    pub async fn restore_validated_endpoint(
+        &self,
+        endpoint: WireGuardEndpointCandidateChangeEvent,
+    ) -> Result<(), Error> {
+        task_exec!(&self.task, async move |s| {
+            if !s.node_cache.contains(&endpoint.public_key) {
+                return Ok(());
+            }
+            telio_log_info!("Publishing restored WG endpoint: {endpoint:?}");
+            s.restored_endpoints
+                .insert(endpoint.public_key, endpoint.clone());
+            s.io.wg_endpoint_publisher
+                .send(endpoint)
+                .await
+                .map_err(Box::new)?;
+            Ok(())
+        })
+        .await
+        .map_err(|e| e.into())
+    }
 }
 
 #[async_trait]
@@ -226,7 +291,8 @@ impl CrossPingCheckTrait for CrossPingCheck {
         let res: Result<HashMap<PublicKey, WireGuardEndpointCandidateChangeEvent>, Error> =
             task_exec!(&self.task, async move |s| {
                 // TODO: update logic to maintain all endpoints instead of last one
-                Ok(s.endpoint_connectivity_check_state
+                let mut validated: HashMap<_, _> = s
+                    .endpoint_connectivity_check_state
                     .iter()
                     .filter_map(
                         |(session, v)| match (v.state.get(), v.last_validated_endpoint) {
@@ -246,7 +312,27 @@ impl CrossPingCheckTrait for CrossPingCheck {
                             _ => None,
                         },
                     )
-                    .collect())
+                    .collect();
+
+                // Endpoints checked by pings are preferred over the ones announced on the LAN
+                for (public_key, (lan_peer, announced_at)) in s.lan_peers.iter() {
+                    // The iteration includes the expired peers
+                    if s.lan_peers.peek(public_key).is_some() {
+                        validated
+                            .entry(*public_key)
+                            .or_insert_with(|| lan_peer.validated_endpoint(*announced_at));
+                    }
+                }
+
+                // Endpoints from before a restart are only used until fresh ones are validated
+                for (public_key, endpoint) in s.restored_endpoints.iter() {
+                    if s.restored_endpoints.peek(public_key).is_some() {
+                        validated
+                            .entry(*public_key)
+                            .or_insert_with(|| endpoint.clone());
+                    }
+                }
+                Ok(validated)
             })
             .await
             .map_err(|e| e.into());
@@ -356,6 +442,7 @@ impl<E: Backoff> State<E> {
                         exponential_backoff: (self.exponential_backoff_helper_provider)()?,
                         local_session: session_id,
                         provider_type: provider_type.into(),
+                        pings_timed_out: false,
                     };
 
                     // Store freshly created connectivity check session
@@ -420,6 +507,7 @@ impl<E: Backoff> State<E> {
                     last_rx_time_provider: self.last_rx_time_provider.clone(),
                     exponential_backoff: (self.exponential_backoff_helper_provider)()?,
                     provider_type: event.0.into(),
+                    pings_timed_out: false,
                 };
 
                 // Store freshly created connectivity check session
@@ -490,11 +578,22 @@ impl<E: Backoff> State<E> {
                     .insert(remote_session_id, public_key);
 
                 // Next format and exchange CallMeMabe response message
-                let call_me_maybe_response = CallMeMaybeMsg::new(
+                let mut call_me_maybe_response = CallMeMaybeMsg::new(
                     false,
                     self.gather_all_local_endpoints()?.iter().map(|e| e.udp),
                     remote_session_id,
                 );
+
+                // The initiator asks for TCP hole punching after its UDP pings failed
+                let remote_tcp_addrs = message.get_tcp_addrs();
+                if !remote_tcp_addrs.is_empty() {
+                    let local_tcp_addrs = self.tcp_candidates();
+                    if !local_tcp_addrs.is_empty() {
+                        call_me_maybe_response =
+                            call_me_maybe_response.with_tcp_addrs(local_tcp_addrs.into_iter());
+                        self.start_tcp_punch(public_key, None, remote_tcp_addrs);
+                    }
+                }
                 #[allow(mpsc_blocking_send)]
                 self.io
                     .intercoms
@@ -510,6 +609,11 @@ impl<E: Backoff> State<E> {
                     &mut self.endpoint_connectivity_check_state,
                     &session_id,
                 )?;
+                let remote_tcp_addrs = if session.pings_timed_out {
+                    message.get_tcp_addrs()
+                } else {
+                    Vec::new()
+                };
                 session
                     .handle_call_me_maybe_response_rxed_event(
                         session_id,
@@ -517,6 +621,10 @@ impl<E: Backoff> State<E> {
                         self.endpoint_providers.clone(),
                     )
                     .await?;
+
+                if !remote_tcp_addrs.is_empty() {
+                    self.start_tcp_punch(public_key, Some(session_id), remote_tcp_addrs);
+                }
             }
 
             Err(e) => return Err(e.into()),
@@ -525,15 +633,100 @@ impl<E: Backoff> State<E> {
     }
 
     async fn handle_tick_event(&mut self) -> Result<(), Error> {
+        // Drop the TCP connections which are closed or lead to removed nodes
+        let stale_hops: Vec<PublicKey> = self
+            .tcp_hops
+            .iter()
+            .filter(|(public_key, hop)| hop.is_closed() || !self.node_cache.contains(public_key))
+            .map(|(public_key, _)| *public_key)
+            .collect();
+        for public_key in stale_hops {
+            if let Some(hop) = self.tcp_hops.remove(&public_key) {
+                telio_log_debug!("Dropping TCP punch hop to {:?}", public_key);
+                hop.stop().await;
+            }
+        }
+
         // Tick over all currently ongoing sessions
+        let tcp_addrs = self.tcp_candidates();
         for (session, state) in self.endpoint_connectivity_check_state.iter_mut() {
             state
-                .handle_tick_event(*session, self.io.intercoms.tx.clone())
+                .handle_tick_event(*session, self.io.intercoms.tx.clone(), &tcp_addrs)
                 .await?;
         }
         Ok(())
     }
 
+    /// Addresses for TCP hole punching, empty when it is disabled
+    fn tcp_candidates(&self) -> Vec<SocketAddr> {
+        match &self.tcp_puncher {
+            Some(puncher) => puncher.candidates(
+                self.local_endpoint_cache
+                    .iter()
+                    .flat_map(|(_, per_provider)| per_provider.iter())
+                    .map(|candidate| candidate.udp.ip()),
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    /// Connect to the peer in the background, reporting back through `tcp_punch_results`
+    fn start_tcp_punch(
+        &self,
+        public_key: PublicKey,
+        session: Option<Session>,
+        remote_tcp_addrs: Vec<SocketAddr>,
+    ) {
+        let Some(puncher) = self.tcp_puncher.clone() else {
+            return;
+        };
+        telio_log_info!(
+            "Attempting TCP hole punching to {:?} at {:?}",
+            public_key,
+            remote_tcp_addrs
+        );
+        let results = self.tcp_punch_results.tx.clone();
+        tokio::spawn(async move {
+            let result = puncher.punch(public_key, remote_tcp_addrs).await;
+            #[allow(mpsc_blocking_send)]
+            let _ = results.send((public_key, session, result)).await;
+        });
+    }
+
+    async fn handle_tcp_punch_result(
+        &mut self,
+        (public_key, session, result): TcpPunchResult,
+    ) -> Result<(), Error> {
+        let hop = match result {
+            Ok(hop) => hop,
+            Err(e) => {
+                telio_log_info!("TCP hole punching to {:?} failed: {}", public_key, e);
+                return Ok(());
+            }
+        };
+
+        // The initiator publishes the connection, the responder keeps it for the upgrade request
+        if let Some(session) = session {
+            let published = match self.endpoint_connectivity_check_state.get_mut(&session) {
+                Some(state) => {
+                    state
+                        .handle_tcp_punched(&hop, self.io.wg_endpoint_publisher.clone())
+                        .await?
+                }
+                None => false,
+            };
+            if !published {
+                hop.stop().await;
+                return Ok(());
+            }
+        }
+
+        if let Some(old_hop) = self.tcp_hops.insert(public_key, hop) {
+            old_hop.stop().await;
+        }
+        Ok(())
+    }
+
     // Ping other node via all endpoint providers. Single Endpoint provider failure should not
     // prevent others from attempting the ping.
     async fn send_ping_via_all_endpoint_providers(
@@ -566,9 +759,38 @@ impl<E: Backoff> State<E> {
         // due to 'tick event' and in that case there will be no CMM with sesssion id sent before.
         match self.session_id_candidates.get(&session) {
             Some(pk) => pk == &public_key,
-            None => false,
+            None => self
+                .lan_peers
+                .peek(&public_key)
+                .is_some_and(|(lan_peer, _)| lan_peer.remote_session == session),
         }
     }
+
+    async fn handle_lan_peer_event(&mut self, lan_peer: LanPeerEvent) -> Result<(), Error> {
+        let public_key = lan_peer.public_key;
+        if self.failed_lan_peers.peek(&public_key).is_some() {
+            return Ok(());
+        }
+
+        let announced_at = match self.lan_peers.peek(&public_key) {
+            Some((known, announced_at)) if known == &lan_peer => *announced_at,
+            _ => {
+                let announced_at = Instant::now();
+                let wg_publish_event = lan_peer.validated_endpoint(announced_at);
+                telio_log_info!(
+                    "Publishing WG endpoint announced on the LAN: {wg_publish_event:?}"
+                );
+                self.io
+                    .wg_endpoint_publisher
+                    .send(wg_publish_event)
+                    .await
+                    .map_err(Box::new)?;
+                announced_at
+            }
+        };
+        self.lan_peers.insert(public_key, (lan_peer, announced_at));
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -591,6 +813,11 @@ impl UpgradeController for CrossPingCheck {
                 .endpoint_connectivity_check_state
                 .values_mut()
                 .filter(|v| v.public_key == public_key);
+            if s.lan_peers.remove(&public_key).is_some() {
+                telio_log_info!("Connection over the LAN to {public_key:?} failed");
+                s.failed_lan_peers.insert(public_key, ());
+            }
+            s.restored_endpoints.remove(&public_key);
             for session in sessions {
                 session.handle_endpoint_gone_notification().await?;
 
@@ -661,6 +888,30 @@ impl<E: Backoff> Runtime for State<E> {
                 Ok(())
             }
 
+            Some(lan_peer) = self.io.lan_peer_subscriber.recv() => {
+                self
+                    .handle_lan_peer_event(lan_peer)
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Failed to handle LAN peer event: {:?}, ignoring", e);
+                        });
+
+                Ok(())
+            }
+
+            Some(tcp_punch_result) = self.tcp_punch_results.rx.recv() => {
+                self
+                    .handle_tcp_punch_result(tcp_punch_result)
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Failed to handle TCP punch result: {:?}, ignoring", e);
+                        });
+
+                Ok(())
+            }
+
             _ = self.poll_timer.tick() => {
                 telio_log_trace!("tick event occured");
                 self
@@ -683,6 +934,12 @@ impl<E: Backoff> Runtime for State<E> {
             }
         }
     }
+
+    async fn stop(self) {
+        for (_, hop) in self.tcp_hops {
+            hop.stop().await;
+        }
+    }
 }
 
 /// The State processing of each (endpoint, remote node) pair.
@@ -696,6 +953,8 @@ pub struct EndpointConnectivityCheckState<E: Backoff> {
     last_rx_time_provider: Option<Arc<dyn TimeSinceLastRxProvider>>,
     exponential_backoff: E,
     provider_type: ApiEndpointProvider,
+    /// UDP pings of the last attempt timed out, so the next one asks for TCP hole punching
+    pings_timed_out: bool,
 }
 
 impl<E: Backoff> Debug for EndpointConnectivityCheckState<E> {
@@ -707,6 +966,7 @@ impl<E: Backoff> Debug for EndpointConnectivityCheckState<E> {
             .field("last_state_transition", &self.last_state_transition)
             .field("last_validate_endpoint", &self.last_validated_endpoint)
             .field("exponential_backoff", &self.exponential_backoff)
+            .field("pings_timed_out", &self.pings_timed_out)
             .field(
                 "last_rx_time_provider",
                 if self.last_rx_time_provider.is_some() {
@@ -724,12 +984,17 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
         &mut self,
         session: Session,
         intercoms: chan::Tx<(PublicKey, CallMeMaybeMsg)>,
+        tcp_addrs: &[SocketAddr],
     ) -> Result<(), Error> {
-        let call_me_maybe_init = CallMeMaybeMsg::new(
+        let mut call_me_maybe_init = CallMeMaybeMsg::new(
             true,
             [self.local_endpoint_candidate.udp].iter().cloned(),
             session,
         );
+        // Only the STUN session punches, so that there is a single attempt per peer
+        if self.pings_timed_out && self.provider_type == ApiEndpointProvider::Stun {
+            call_me_maybe_init = call_me_maybe_init.with_tcp_addrs(tcp_addrs.iter().cloned());
+        }
 
         telio_log_debug!(
             "Sending a CMM request to {:?}: {:?}",
@@ -806,6 +1071,7 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
                     event.msg.get_ponging_ep_provider(),
                     Ok(Some(telio_model::features::EndpointProvider::Local))
                         | Ok(Some(telio_model::features::EndpointProvider::Upnp))
+                        | Ok(Some(telio_model::features::EndpointProvider::PortMapping))
                 );
                 if let Ok(ping_source) = event.msg.get_ping_source_address() {
                     if ping_source == self.local_endpoint_candidate.udp.ip() || nice_ep_provider {
@@ -825,6 +1091,7 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
 
                         // Reset exponential backoff on succesfull endpoint verification
                         self.exponential_backoff.reset();
+                        self.pings_timed_out = false;
 
                         let wg_publish_event = WireGuardEndpointCandidateChangeEvent {
                             public_key: self.public_key,
@@ -867,6 +1134,46 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
         Ok(())
     }
 
+    /// Publish the connection opened by TCP hole punching, if the UDP pings are still ongoing
+    #[allow(mpsc_blocking_send)]
+    async fn handle_tcp_punched(
+        &mut self,
+        hop: &TcpPunchHop,
+        wg_ep_publisher: chan::Tx<WireGuardEndpointCandidateChangeEvent>,
+    ) -> Result<bool, Error> {
+        if self.state.get() != EndpointState::Ping {
+            telio_log_debug!(
+                "TCP hole punched for {:?} during non-pinging state {:?}. Ignoring",
+                self.public_key,
+                self.state
+            );
+            return Ok(false);
+        }
+
+        do_state_transition!(self, Event::Publish);
+        self.exponential_backoff.reset();
+
+        // Both WireGuards talk to their own hop, so the peer gets the address of its hop
+        let remote_endpoint = (hop.local_addr(), self.provider_type);
+        let wg_publish_event = WireGuardEndpointCandidateChangeEvent {
+            public_key: self.public_key,
+            remote_endpoint,
+            local_endpoint: (hop.peer_hop_addr(), self.provider_type),
+            session: self.local_session,
+            changed_at: self.last_state_transition,
+        };
+        telio_log_info!(
+            "Publishing WG endpoint over TCP punch hop: {:?}",
+            wg_publish_event
+        );
+        self.last_validated_endpoint = Some(remote_endpoint);
+        wg_ep_publisher
+            .send(wg_publish_event)
+            .await
+            .map_err(Box::new)?;
+        Ok(true)
+    }
+
     async fn should_resend_call_me_maybe_request(
         &self,
         duration_in_state: Duration,
@@ -902,6 +1209,7 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
         &mut self,
         session: Session,
         intercoms: chan::Tx<(PublicKey, CallMeMaybeMsg)>,
+        tcp_addrs: &[SocketAddr],
     ) -> Result<(), Error> {
         let duration_in_state = Instant::now() - self.last_state_transition;
         let timeout = CPC_TIMEOUT; // TODO: make configurable
@@ -924,11 +1232,13 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
                         "Timeout waiting for pongs, next retry after {}s",
                         self.exponential_backoff.get_backoff().as_secs_f64()
                     );
+                    self.pings_timed_out = true;
                     do_state_transition!(self, Event::Timeout);
                 }
             }
             EndpointState::Disconnected(Event::StartUp) => {
-                self.send_call_me_maybe_request(session, intercoms).await?;
+                self.send_call_me_maybe_request(session, intercoms, tcp_addrs)
+                    .await?;
                 do_state_transition!(self, Event::SendCallMeMaybeRequest);
             }
             EndpointState::Disconnected(Event::Timeout) => {
@@ -938,7 +1248,8 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
                 {
                     ShouldSendCMMResult::Yes => {
                         self.exponential_backoff.next_backoff();
-                        self.send_call_me_maybe_request(session, intercoms).await?;
+                        self.send_call_me_maybe_request(session, intercoms, tcp_addrs)
+                            .await?;
                         do_state_transition!(self, Event::SendCallMeMaybeRequest);
                     }
                     reason => {
@@ -956,7 +1267,8 @@ impl<E: Backoff> EndpointConnectivityCheckState<E> {
                 {
                     ShouldSendCMMResult::Yes => {
                         self.exponential_backoff.next_backoff();
-                        self.send_call_me_maybe_request(session, intercoms).await?;
+                        self.send_call_me_maybe_request(session, intercoms, tcp_addrs)
+                            .await?;
                         do_state_transition!(self, Event::SendCallMeMaybeRequest);
                     }
                     reason => {
@@ -1019,6 +1331,7 @@ mod tests {
         pong_rx_events: Sender<PongEvent>,
         wg_endpoint_publish_events: Receiver<WireGuardEndpointCandidateChangeEvent>,
         intercoms: Chan<(PublicKey, CallMeMaybeMsg)>,
+        lan_peers: Sender<LanPeerEvent>,
     }
     const SESSION_ID: u64 = 0;
 
@@ -1037,6 +1350,7 @@ mod tests {
         let pong_rx_events = Chan::default();
         let wg_endpoint_publish_events = Chan::default();
         let (checker_intercoms, intercoms) = Chan::pipe();
+        let lan_peers = Chan::default();
 
         let checker = CrossPingCheck::start(
             Io {
@@ -1044,12 +1358,14 @@ mod tests {
                 pong_rx_subscriber: pong_rx_events.rx,
                 wg_endpoint_publisher: wg_endpoint_publish_events.tx,
                 intercoms: checker_intercoms,
+                lan_peer_subscriber: lan_peers.rx,
             },
             vec![Arc::new(endpoint_provider_mock)],
             Some(Arc::new(MockTimeSinceLastRxProvider::new())),
             Duration::from_secs(2),
             Arc::new(Mutex::new(PingPongHandler::new(SecretKey::gen()))),
             ExponentialBackoffBounds::default(),
+            None,
         );
 
         let channels = TestChannels {
@@ -1057,6 +1373,7 @@ mod tests {
             pong_rx_events: pong_rx_events.tx,
             wg_endpoint_publish_events: wg_endpoint_publish_events.rx,
             intercoms,
+            lan_peers: lan_peers.tx,
         };
 
         Ok((checker, channels))
@@ -1127,6 +1444,7 @@ mod tests {
             last_rx_time_provider: Some(last_rx_time_provider),
             exponential_backoff: MockBackoff::default(),
             provider_type: telio_model::features::EndpointProvider::Stun,
+            pings_timed_out: false,
         }
     }
 
@@ -1193,6 +1511,109 @@ mod tests {
         assert!(checker.get_validated_endpoints().await.unwrap().is_empty());
     }
 
+    #[tokio::test]
+    async fn lan_peers_are_validated_until_the_connection_fails() {
+        let (checker, mut channels) = prepare_checker_test().unwrap();
+        let public_key = PublicKey(*b"ABBBBBBBBBBBBBBBBBBBAAAAAAAAAAAA");
+        let lan_peer = LanPeerEvent {
+            public_key,
+            remote_endpoint: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), 51820),
+            local_endpoint: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 51820),
+            remote_session: 1,
+            local_session: 2,
+        };
+
+        channels.lan_peers.send(lan_peer.clone()).await.unwrap();
+        let published = channels.wg_endpoint_publish_events.recv().await.unwrap();
+        assert_eq!(
+            published.remote_endpoint,
+            (lan_peer.remote_endpoint, ApiEndpointProvider::Local)
+        );
+        assert_eq!(published.session, lan_peer.local_session);
+        assert_eq!(
+            checker.get_validated_endpoints().await.unwrap()[&public_key],
+            published
+        );
+
+        // The peer upgrades with the session it announced
+        assert!(checker
+            .check_if_upgrade_is_allowed(lan_peer.remote_session, public_key)
+            .await
+            .unwrap());
+        assert!(!checker
+            .check_if_upgrade_is_allowed(lan_peer.local_session, public_key)
+            .await
+            .unwrap());
+
+        // Announcing again publishes nothing new
+        channels.lan_peers.send(lan_peer.clone()).await.unwrap();
+        wait_for_tick().await;
+        assert!(channels.wg_endpoint_publish_events.try_recv().is_err());
+
+        checker
+            .notify_failed_wg_connection(public_key)
+            .await
+            .unwrap();
+        channels.lan_peers.send(lan_peer).await.unwrap();
+        wait_for_tick().await;
+        assert!(checker.get_validated_endpoints().await.unwrap().is_empty());
+        assert!(channels.wg_endpoint_publish_events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn restored_endpoints_are_validated_until_the_connection_fails() {
+        let (checker, mut channels) = prepare_checker_test().unwrap();
+        let mut peer = Peer::default();
+        let public_key = PublicKey(*b"ABBBBBBBBBBBBBBBBBBBAAAAAAAAAAAA");
+        peer.base.public_key = public_key;
+        let restored = WireGuardEndpointCandidateChangeEvent {
+            public_key,
+            remote_endpoint: (
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 51820),
+                ApiEndpointProvider::Stun,
+            ),
+            local_endpoint: (
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 51820),
+                ApiEndpointProvider::Stun,
+            ),
+            session: 7,
+            changed_at: Instant::now(),
+        };
+        let unknown = WireGuardEndpointCandidateChangeEvent {
+            public_key: PublicKey(*b"CBBBBBBBBBBBBBBBBBBBAAAAAAAAAAAA"),
+            ..restored.clone()
+        };
+
+        checker
+            .configure(Some(Config {
+                this: PeerBase::default(),
+                peers: Some(vec![peer]),
+                derp_servers: None,
+                dns: None,
+            }))
+            .await
+            .unwrap();
+        checker
+            .restore_validated_endpoint(restored.clone())
+            .await
+            .unwrap();
+        checker.restore_validated_endpoint(unknown).await.unwrap();
+
+        assert_eq!(
+            channels.wg_endpoint_publish_events.recv().await.unwrap(),
+            restored
+        );
+        let validated = checker.get_validated_endpoints().await.unwrap();
+        assert_eq!(validated.len(), 1);
+        assert_eq!(validated[&public_key], restored);
+
+        checker
+            .notify_failed_wg_connection(public_key)
+            .await
+            .unwrap();
+        assert!(checker.get_validated_endpoints().await.unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn endpoint_connectivity_check_state_send_cmm_request() {
         let last_rx_time_provider_mock = Arc::new(Mutex::new(MockTimeSinceLastRxProvider::new()));
@@ -1204,7 +1625,7 @@ mod tests {
         let intercoms = Chan::default();
 
         endpoint_connectivity_check_state
-            .handle_tick_event(0, intercoms.tx)
+            .handle_tick_event(0, intercoms.tx, &[])
             .await
             .unwrap();
 
@@ -1313,7 +1734,7 @@ mod tests {
 
         time::advance(Duration::from_secs(11)).await;
         endpoint_connectivity_check_state
-            .handle_tick_event(0, Chan::default().tx)
+            .handle_tick_event(0, Chan::default().tx, &[])
             .await
             .unwrap();
 
@@ -1334,7 +1755,7 @@ mod tests {
 
         time::advance(Duration::from_secs(11)).await;
         endpoint_connectivity_check_state
-            .handle_tick_event(0, Chan::default().tx)
+            .handle_tick_event(0, Chan::default().tx, &[])
             .await
             .unwrap();
 
@@ -1372,7 +1793,7 @@ mod tests {
 
         // Let's send the initial CMM message
         endpoint_connectivity_check_state
-            .handle_tick_event(0, intercoms_tx.clone())
+            .handle_tick_event(0, intercoms_tx.clone(), &[])
             .await
             .unwrap();
         intercoms_rx.try_recv().unwrap();
@@ -1393,7 +1814,7 @@ mod tests {
 
             // Enter Disconnected state
             endpoint_connectivity_check_state
-                .handle_tick_event(0, intercoms_tx.clone())
+                .handle_tick_event(0, intercoms_tx.clone(), &[])
                 .await
                 .unwrap();
 
@@ -1403,7 +1824,7 @@ mod tests {
 
             // Nothing should happen here
             endpoint_connectivity_check_state
-                .handle_tick_event(0, intercoms_tx.clone())
+                .handle_tick_event(0, intercoms_tx.clone(), &[])
                 .await
                 .unwrap();
             intercoms_rx
@@ -1416,7 +1837,7 @@ mod tests {
 
             // Here another CMM message should be sent
             endpoint_connectivity_check_state
-                .handle_tick_event(0, intercoms_tx.clone())
+                .handle_tick_event(0, intercoms_tx.clone(), &[])
                 .await
                 .unwrap();
             intercoms_rx
@@ -1429,6 +1850,86 @@ mod tests {
         }
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn tcp_punching_is_requested_after_pings_time_out() {
+        let last_rx_time_provider_mock = Arc::new(Mutex::new(MockTimeSinceLastRxProvider::new()));
+        last_rx_time_provider_mock
+            .lock()
+            .await
+            .expect_max_no_rx_time()
+            .return_const(Duration::from_secs(180));
+        last_rx_time_provider_mock
+            .lock()
+            .await
+            .expect_last_rx_time()
+            .returning(|_| Ok(Some(Duration::from_secs(1))));
+        let mut endpoint_connectivity_check_state = prepare_test_session_in_state(
+            EndpointStateMachine::new(EndpointState::Disconnected(Event::StartUp)),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)), 8080),
+            last_rx_time_provider_mock,
+        );
+        endpoint_connectivity_check_state
+            .exponential_backoff
+            .expect_get_backoff()
+            .return_const(Duration::from_secs(1));
+        endpoint_connectivity_check_state
+            .exponential_backoff
+            .expect_next_backoff()
+            .return_const(());
+
+        let tcp_addrs = [SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)),
+            40000,
+        )];
+        let Chan {
+            rx: mut intercoms_rx,
+            tx: intercoms_tx,
+        } = Chan::default();
+
+        // UDP gets the first chance
+        endpoint_connectivity_check_state
+            .handle_tick_event(0, intercoms_tx.clone(), &tcp_addrs)
+            .await
+            .unwrap();
+        let (_, cmm) = intercoms_rx.try_recv().unwrap();
+        assert!(cmm.get_tcp_addrs().is_empty());
+
+        // The CMM response arrives, but the pings do not get through
+        endpoint_connectivity_check_state
+            .handle_call_me_maybe_response_rxed_event(
+                0,
+                (
+                    PublicKey::default(),
+                    CallMeMaybeMsg::new(false, std::iter::empty(), 0),
+                ),
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        time::advance(CPC_TIMEOUT + Duration::from_millis(1)).await;
+        endpoint_connectivity_check_state
+            .handle_tick_event(0, intercoms_tx.clone(), &tcp_addrs)
+            .await
+            .unwrap();
+        assert!(endpoint_connectivity_check_state.pings_timed_out);
+
+        // So the next attempt asks for TCP hole punching as well
+        time::advance(Duration::from_secs(2)).await;
+        endpoint_connectivity_check_state
+            .handle_tick_event(0, intercoms_tx.clone(), &tcp_addrs)
+            .await
+            .unwrap();
+        let (_, cmm) = intercoms_rx.try_recv().unwrap();
+        assert_eq!(cmm.get_tcp_addrs(), tcp_addrs);
+        assert_eq!(
+            cmm.get_addrs(),
+            vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)),
+                8080,
+            )]
+        );
+    }
+
     #[tokio::test]
     async fn endpoint_connectivity_check_send_ping_to_all_providers_even_if_one_fails() {
         let last_rx_time_provider_mock = Arc::new(Mutex::new(MockTimeSinceLastRxProvider::new()));
@@ -1499,7 +2000,7 @@ mod tests {
 
         // Let's send the initial CMM message
         endpoint_connectivity_check_state
-            .handle_tick_event(SESSION_ID, intercoms_tx.clone())
+            .handle_tick_event(SESSION_ID, intercoms_tx.clone(), &[])
             .await
             .unwrap();
         intercoms_rx.try_recv().unwrap();
@@ -1520,7 +2021,7 @@ mod tests {
 
         // Enter Disconnected state
         endpoint_connectivity_check_state
-            .handle_tick_event(SESSION_ID, intercoms_tx.clone())
+            .handle_tick_event(SESSION_ID, intercoms_tx.clone(), &[])
             .await
             .unwrap();
 
@@ -1535,7 +2036,7 @@ mod tests {
 
         // Nothing should happen here
         endpoint_connectivity_check_state
-            .handle_tick_event(0, intercoms_tx.clone())
+            .handle_tick_event(0, intercoms_tx.clone(), &[])
             .await
             .unwrap();
         intercoms_rx
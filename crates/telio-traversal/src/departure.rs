@@ -0,0 +1,173 @@
+//! Announcements of the meshnet peers going away.
+//!
+//! A node shutting down gracefully sends a goodbye to each of its peers over the relay, telling
+//! for how long it still keeps the established connections. The goodbyes received from the peers
+//! are passed to the [DepartureObserver], so that the traffic can be moved away from them before
+//! they disappear.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::Future;
+use telio_crypto::PublicKey;
+use telio_proto::GoodbyeMsg;
+use telio_task::{io::Chan, task_exec, BoxAction, Runtime, Task};
+use telio_utils::{telio_log_info, telio_log_warn};
+use tokio::sync::mpsc::error::SendError;
+
+/// Called with the public key of the peer going away and the time it keeps the established
+/// connections for
+pub type DepartureObserver = Arc<dyn Fn(PublicKey, Duration) + Send + Sync>;
+
+/// Possible [Departures] errors.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// Channel error
+    #[error(transparent)]
+    SendGoodbyeMsgErr(#[from] SendError<(PublicKey, GoodbyeMsg)>),
+    /// Task encountered an error while running
+    #[error(transparent)]
+    Task(#[from] telio_task::ExecError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Departures {
+    task: Task<State>,
+}
+
+struct State {
+    intercoms: Chan<(PublicKey, GoodbyeMsg)>,
+    observer: Option<DepartureObserver>,
+}
+
+impl Departures {
+    /// Exchange the goodbyes with the peers over the `intercoms`, the ones of the peers are
+    /// passed to the `observer`
+    pub fn new(
+        intercoms: Chan<(PublicKey, GoodbyeMsg)>,
+        observer: Option<DepartureObserver>,
+    ) -> Self {
+        telio_log_info!("Starting departures module");
+        Self {
+            task: Task::start(State {
+                intercoms,
+                observer,
+            }),
+        }
+    }
+
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+
+    /// Tell the `peers` that this node goes away after the `drain_timeout`
+    pub async fn announce(&self, peers: Vec<PublicKey>, drain_timeout: Duration) -> Result<()> {
+        task_exec!(&self.task, async move |s| {
+            for public_key in peers {
+                #[allow(mpsc_blocking_send)]
+                s.intercoms
+                    .tx
+                    .send((public_key, GoodbyeMsg { drain_timeout }))
+                    .await
+                    .unwrap_or_else(|e| {
+                        telio_log_warn!("Failed to say goodbye to {public_key:?}: {e}")
+                    });
+            }
+            Ok(())
+        })
+        .await
+        .map_err(Error::Task)
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "Departures";
+    type Err = ();
+
+    async fn wait_with_update<F>(&mut self, update: F) -> std::result::Result<(), Self::Err>
+    where
+        F: Future<Output = BoxAction<Self, std::result::Result<(), Self::Err>>> + Send,
+    {
+        tokio::select! {
+            Some((public_key, msg)) = self.intercoms.rx.recv() => {
+                telio_log_info!("{public_key:?} is going away in {:?}", msg.drain_timeout);
+                if let Some(observer) = &self.observer {
+                    observer(public_key, msg.drain_timeout);
+                }
+            }
+            update = update => {
+                return update(self).await;
+            }
+            else => {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use tokio::time;
+
+    const PEER: PublicKey = PublicKey([1; 32]);
+
+    #[tokio::test]
+    async fn goodbyes_are_sent_to_the_peers() {
+        let (mut intercoms_them, intercoms_us) = Chan::pipe();
+        let departures = Departures::new(intercoms_us, None);
+
+        departures
+            .announce(vec![PEER, PublicKey([2; 32])], Duration::from_secs(30))
+            .await
+            .unwrap();
+        for public_key in [PEER, PublicKey([2; 32])] {
+            assert_eq!(
+                intercoms_them.rx.recv().await.unwrap(),
+                (
+                    public_key,
+                    GoodbyeMsg {
+                        drain_timeout: Duration::from_secs(30)
+                    }
+                )
+            );
+        }
+
+        departures.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn goodbyes_of_the_peers_are_observed() {
+        let (intercoms_them, intercoms_us) = Chan::pipe();
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let departures = Departures::new(
+            intercoms_us,
+            Some(Arc::new({
+                let observed = observed.clone();
+                move |public_key: PublicKey, drain_timeout: Duration| {
+                    observed.lock().push((public_key, drain_timeout))
+                }
+            })),
+        );
+
+        intercoms_them
+            .tx
+            .send((
+                PEER,
+                GoodbyeMsg {
+                    drain_timeout: Duration::from_secs(5),
+                },
+            ))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(*observed.lock(), vec![(PEER, Duration::from_secs(5))]);
+
+        departures.stop().await;
+    }
+}
@@ -1,4 +1,6 @@
+pub mod custom;
 pub mod local;
+pub mod port_mapping;
 pub mod stun;
 pub mod upnp;
 
@@ -78,6 +80,12 @@ pub enum Error {
     /// Did not find matching endpoint with the IGD subnet
     #[error("No endpoint with matching subnet to IGD")]
     NoMatchingLocalEndpoint,
+    /// Gateway to request port mappings from is unknown
+    #[error("Missing port mapping gateway")]
+    NoPortMappingGateway,
+    /// Gateway refused or failed to handle a port mapping request
+    #[error("Port mapping failed: {0}")]
+    PortMappingError(String),
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone, Enum)]
@@ -85,6 +93,8 @@ pub enum EndpointProviderType {
     LocalInterfaces,
     Stun,
     Upnp,
+    PortMapping,
+    Custom,
 }
 
 impl From<EndpointProviderType> for telio_model::features::EndpointProvider {
@@ -93,6 +103,12 @@ impl From<EndpointProviderType> for telio_model::features::EndpointProvider {
             EndpointProviderType::LocalInterfaces => telio_model::features::EndpointProvider::Local,
             EndpointProviderType::Stun => telio_model::features::EndpointProvider::Stun,
             EndpointProviderType::Upnp => telio_model::features::EndpointProvider::Upnp,
+            EndpointProviderType::PortMapping => {
+                telio_model::features::EndpointProvider::PortMapping
+            }
+            // Custom candidates are public addresses, just like the STUN ones. Reporting them
+            // as such keeps the ping and upgrade messages understandable by older peers.
+            EndpointProviderType::Custom => telio_model::features::EndpointProvider::Stun,
         }
     }
 }
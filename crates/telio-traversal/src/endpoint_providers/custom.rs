@@ -0,0 +1,342 @@
+//! Endpoint provider driven by discovery mechanisms registered by the library user.
+//!
+//! Downstream users implement [EndpointDiscovery] to report public addresses known from
+//! sources libtelio has no access to, e.g. the public IP of a cloud instance read from
+//! its metadata service. The addresses are turned into endpoint candidates which go
+//! through the same cross ping validation as the candidates of the builtin providers.
+
+use crate::ping_pong_handler::PingPongHandler;
+
+use super::{
+    EndpointCandidate, EndpointCandidatesChangeEvent, EndpointProvider, EndpointProviderType,
+    Error, PongEvent,
+};
+use async_trait::async_trait;
+use futures::Future;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use telio_crypto::PublicKey;
+use telio_proto::{Session, WGPort};
+use telio_sockets::External;
+use telio_task::{io::chan, task_exec, BoxAction, Runtime, Task};
+use telio_utils::{interval, telio_log_debug, telio_log_info, telio_log_warn};
+use telio_wg::{DynamicWg, WireGuard};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::time::Interval;
+
+/// Source of public addresses at which this host is reachable
+#[cfg_attr(any(test, feature = "mockall"), mockall::automock)]
+#[async_trait]
+pub trait EndpointDiscovery: Send + Sync + 'static {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Discover the public addresses of this host.
+    ///
+    /// The addresses are expected to forward traffic to this host with ports preserved,
+    /// like a cloud instance's public IP does.
+    async fn discover(&self) -> std::io::Result<Vec<IpAddr>>;
+}
+
+/// Endpoint provider publishing the candidates found by all registered [EndpointDiscovery]
+pub struct CustomEndpointProvider<T: WireGuard = DynamicWg> {
+    task: Task<State<T>>,
+}
+
+pub struct State<T: WireGuard> {
+    endpoint_candidates_change_publisher: Option<chan::Tx<EndpointCandidatesChangeEvent>>,
+    pong_publisher: Option<chan::Tx<PongEvent>>,
+    last_endpoint_candidates_event: Vec<EndpointCandidate>,
+    poll_timer: Interval,
+    wireguard_interface: Arc<T>,
+    udp_socket: External<UdpSocket>,
+    ping_pong_handler: Arc<Mutex<PingPongHandler>>,
+    discoveries: Vec<Arc<dyn EndpointDiscovery>>,
+}
+
+#[async_trait]
+impl<T: WireGuard> EndpointProvider for CustomEndpointProvider<T> {
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    async fn subscribe_for_pong_events(&self, tx: chan::Tx<PongEvent>) {
+        task_exec!(&self.task, async move |s| {
+            s.pong_publisher = Some(tx);
+            Ok(())
+        })
+        .await
+        .unwrap_or_default();
+    }
+
+    async fn subscribe_for_endpoint_candidates_change_events(
+        &self,
+        tx: chan::Tx<EndpointCandidatesChangeEvent>,
+    ) {
+        task_exec!(&self.task, async move |s| {
+            s.endpoint_candidates_change_publisher = Some(tx);
+            Ok(())
+        })
+        .await
+        .unwrap_or_default();
+    }
+
+    async fn trigger_endpoint_candidates_discovery(&self, _force: bool) -> Result<(), Error> {
+        task_exec!(&self.task, async move |s| Ok(s.poll_endpoints().await)).await?
+    }
+
+    async fn handle_endpoint_gone_notification(&self) {}
+
+    async fn send_ping(
+        &self,
+        addr: SocketAddr,
+        session_id: Session,
+        public_key: PublicKey,
+    ) -> Result<(), Error> {
+        task_exec!(&self.task, async move |s| {
+            Ok(s.send_ping(addr, session_id, &public_key).await)
+        })
+        .await?
+    }
+
+    async fn get_current_endpoints(&self) -> Option<Vec<EndpointCandidate>> {
+        task_exec!(&self.task, async move |s| Ok(Some(
+            s.last_endpoint_candidates_event.clone()
+        )))
+        .await
+        .unwrap_or(None)
+    }
+}
+
+impl<T: WireGuard> CustomEndpointProvider<T> {
+    pub fn new(
+        udp_socket: External<UdpSocket>,
+        wireguard_interface: Arc<T>,
+        poll_interval: Duration,
+        ping_pong_handler: Arc<Mutex<PingPongHandler>>,
+        discoveries: Vec<Arc<dyn EndpointDiscovery>>,
+    ) -> Self {
+        telio_log_info!(
+            "Starting custom endpoint provider with {:?}",
+            discoveries.iter().map(|d| d.name()).collect::<Vec<_>>()
+        );
+        Self {
+            task: Task::start(State {
+                endpoint_candidates_change_publisher: None,
+                pong_publisher: None,
+                last_endpoint_candidates_event: vec![],
+                poll_timer: interval(poll_interval),
+                wireguard_interface,
+                udp_socket,
+                ping_pong_handler,
+                discoveries,
+            }),
+        }
+    }
+
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+impl<T: WireGuard> State<T> {
+    async fn get_wg_port(&self) -> Result<u16, Error> {
+        self.wireguard_interface
+            .get_interface()
+            .await
+            .ok()
+            .and_then(|i| i.listen_port)
+            .ok_or(Error::NoWGListenPort)
+    }
+
+    async fn poll_endpoints(&mut self) -> Result<(), Error> {
+        let Some(candidates_publisher) = self.endpoint_candidates_change_publisher.as_ref() else {
+            telio_log_debug!("Skipping custom endpoints poll. No one subscribed for notifications");
+            return Ok(());
+        };
+
+        let wg_port = self.get_wg_port().await?;
+        let udp_port = self.udp_socket.local_addr()?.port();
+
+        // A single failing discovery should not hide the addresses found by the others
+        let mut ips = BTreeSet::new();
+        for discovery in &self.discoveries {
+            match discovery.discover().await {
+                Ok(found) => ips.extend(found.into_iter().filter(IpAddr::is_ipv4)),
+                Err(e) => telio_log_warn!("Endpoint discovery {} failed: {e}", discovery.name()),
+            }
+        }
+
+        let candidates: Vec<_> = ips
+            .into_iter()
+            .map(|ip| EndpointCandidate {
+                wg: SocketAddr::new(ip, wg_port),
+                udp: SocketAddr::new(ip, udp_port),
+            })
+            .collect();
+
+        if self.last_endpoint_candidates_event != candidates {
+            telio_log_debug!("published candidates: {:?}", &candidates);
+            candidates_publisher
+                .send((EndpointProviderType::Custom, candidates.clone()))
+                .await?;
+            self.last_endpoint_candidates_event = candidates;
+        }
+        Ok(())
+    }
+
+    async fn send_ping(
+        &self,
+        addr: SocketAddr,
+        session_id: Session,
+        public_key: &PublicKey,
+    ) -> Result<(), Error> {
+        let wg_port = WGPort(self.get_wg_port().await?);
+        self.ping_pong_handler
+            .lock()
+            .await
+            .send_ping(addr, wg_port, &self.udp_socket, session_id, public_key)
+            .await
+    }
+
+    async fn handle_rx_packet(&self, encrypted_buf: &[u8], addr: &SocketAddr) -> Result<(), Error> {
+        let wg_port = WGPort(self.get_wg_port().await?);
+        self.ping_pong_handler
+            .lock()
+            .await
+            .handle_rx_packet(
+                encrypted_buf,
+                addr,
+                wg_port,
+                &self.udp_socket,
+                &self.pong_publisher,
+                EndpointProviderType::Custom.into(),
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl<T: WireGuard> Runtime for State<T> {
+    const NAME: &'static str = "CustomEndpointProvider";
+    type Err = ();
+
+    async fn wait_with_update<F>(&mut self, update: F) -> std::result::Result<(), Self::Err>
+    where
+        F: Future<Output = BoxAction<Self, std::result::Result<(), Self::Err>>> + Send,
+    {
+        const MAX_SUPPORTED_PACKET_SIZE: usize = 1500;
+        let mut rx_buff = vec![0u8; MAX_SUPPORTED_PACKET_SIZE];
+        tokio::select! {
+            Ok((len, addr)) = self.udp_socket.recv_from(&mut rx_buff) => {
+                let buf = rx_buff.get(..len).ok_or(())?;
+                self.handle_rx_packet(buf, &addr).await.unwrap_or_else(|e| {
+                    telio_log_warn!("Failed to handle packet received on custom endpoint provider {:?}", e);
+                });
+            }
+            _ = self.poll_timer.tick() => {
+                self.poll_endpoints().await.unwrap_or_else(|e| {
+                    telio_log_warn!("Failed to poll custom endpoints {:?}", e);
+                });
+            },
+            update = update => {
+                return update(self).await;
+            }
+            else => {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use telio_crypto::SecretKey;
+    use telio_sockets::{NativeProtector, SocketPool};
+    use telio_task::io::Chan;
+    use telio_wg::{uapi::Interface, MockWireGuard};
+
+    async fn prepare(
+        discoveries: Vec<Arc<dyn EndpointDiscovery>>,
+    ) -> (
+        CustomEndpointProvider<MockWireGuard>,
+        chan::Rx<EndpointCandidatesChangeEvent>,
+        u16,
+    ) {
+        let mut wg_mock = MockWireGuard::new();
+        wg_mock.expect_get_interface().returning(|| {
+            Ok(Interface {
+                listen_port: Some(12345),
+                ..Default::default()
+            })
+        });
+        let socket = SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        )
+        .new_external_udp((Ipv4Addr::LOCALHOST, 0), None)
+        .await
+        .unwrap();
+        let udp_port = socket.local_addr().unwrap().port();
+
+        let provider = CustomEndpointProvider::new(
+            socket,
+            Arc::new(wg_mock),
+            Duration::from_secs(10000),
+            Arc::new(Mutex::new(PingPongHandler::new(SecretKey::gen()))),
+            discoveries,
+        );
+        let candidates = Chan::default();
+        provider
+            .subscribe_for_endpoint_candidates_change_events(candidates.tx)
+            .await;
+        (provider, candidates.rx, udp_port)
+    }
+
+    fn discovery(result: std::io::Result<Vec<IpAddr>>) -> Arc<dyn EndpointDiscovery> {
+        let mut discovery = MockEndpointDiscovery::new();
+        discovery.expect_name().return_const("mock");
+        let result = std::sync::Mutex::new(Some(result));
+        discovery
+            .expect_discover()
+            .returning(move || result.lock().unwrap().take().unwrap_or(Ok(vec![])));
+        Arc::new(discovery)
+    }
+
+    #[tokio::test]
+    async fn discovered_addresses_are_published_as_candidates() {
+        let ip: IpAddr = Ipv4Addr::new(203, 0, 113, 7).into();
+        let (provider, mut candidates_rx, udp_port) = prepare(vec![
+            discovery(Err(std::io::ErrorKind::TimedOut.into())),
+            discovery(Ok(vec![ip])),
+        ])
+        .await;
+
+        provider
+            .trigger_endpoint_candidates_discovery(false)
+            .await
+            .unwrap();
+
+        let (provider_type, candidates) = candidates_rx.recv().await.unwrap();
+        assert_eq!(provider_type, EndpointProviderType::Custom);
+        assert_eq!(
+            candidates,
+            vec![EndpointCandidate {
+                wg: SocketAddr::new(ip, 12345),
+                udp: SocketAddr::new(ip, udp_port),
+            }]
+        );
+        assert_eq!(provider.get_current_endpoints().await, Some(candidates));
+
+        provider.stop().await;
+    }
+}
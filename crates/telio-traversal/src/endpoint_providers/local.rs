@@ -6,12 +6,12 @@ use super::{
 };
 use async_trait::async_trait;
 use futures::Future;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use telio_crypto::PublicKey;
 use telio_network_monitors::local_interfaces::{
-    gather_local_interfaces, GetIfAddrs, SystemGetIfAddrs,
+    gather_local_interfaces_with, GetIfAddrs, SystemGetIfAddrs,
 };
 use telio_proto::{Session, WGPort};
 use telio_sockets::External;
@@ -36,6 +36,8 @@ pub struct State<T: WireGuard, G: GetIfAddrs> {
     poll_timer: Interval,
     wireguard_interface: Arc<T>,
     udp_socket: External<UdpSocket>,
+    /// Socket for pinging IPv6 candidates, IPv6 candidates are gathered only when present
+    udp_socket_v6: Option<External<UdpSocket>>,
     ping_pong_handler: Arc<Mutex<PingPongHandler>>,
     get_if_addr: G,
 }
@@ -100,12 +102,14 @@ impl<T: WireGuard, G: GetIfAddrs> EndpointProvider for LocalInterfacesEndpointPr
 impl<T: WireGuard> LocalInterfacesEndpointProvider<T> {
     pub fn new(
         udp_socket: External<UdpSocket>,
+        udp_socket_v6: Option<External<UdpSocket>>,
         wireguard_interface: Arc<T>,
         poll_interval: Duration,
         ping_pong_handler: Arc<Mutex<PingPongHandler>>,
     ) -> Self {
         LocalInterfacesEndpointProvider::new_with(
             udp_socket,
+            udp_socket_v6,
             wireguard_interface,
             poll_interval,
             ping_pong_handler,
@@ -117,6 +121,7 @@ impl<T: WireGuard> LocalInterfacesEndpointProvider<T> {
 impl<T: WireGuard, G: GetIfAddrs> LocalInterfacesEndpointProvider<T, G> {
     pub fn new_with(
         udp_socket: External<UdpSocket>,
+        udp_socket_v6: Option<External<UdpSocket>>,
         wireguard_interface: Arc<T>,
         poll_interval: Duration,
         ping_pong_handler: Arc<Mutex<PingPongHandler>>,
@@ -132,6 +137,7 @@ impl<T: WireGuard, G: GetIfAddrs> LocalInterfacesEndpointProvider<T, G> {
                 poll_timer,
                 wireguard_interface,
                 udp_socket,
+                udp_socket_v6,
                 ping_pong_handler,
                 get_if_addr,
             }),
@@ -169,14 +175,28 @@ impl<T: WireGuard, G: GetIfAddrs> State<T, G> {
                     return Err(e.into());
                 }
             };
+            let udp_port_v6 = match self.udp_socket_v6.as_ref().map(|s| s.local_addr()) {
+                Some(Ok(addr)) => Some(addr.port()),
+                Some(Err(e)) => {
+                    telio_log_warn!("Skipping IPv6 local interfaces due to failure to retrieve udp socket addr {:?}", e);
+                    None
+                }
+                None => None,
+            };
 
-            let itfs = gather_local_interfaces(&self.get_if_addr)?;
+            let itfs = gather_local_interfaces_with(&self.get_if_addr, udp_port_v6.is_some())?;
 
             let candidates: Vec<_> = itfs
                 .iter()
-                .map(|itf| EndpointCandidate {
-                    wg: SocketAddr::new(itf.addr.ip(), wg_port),
-                    udp: SocketAddr::new(itf.addr.ip(), udp_port),
+                .filter_map(|itf| {
+                    let udp_port = match itf.addr.ip() {
+                        IpAddr::V4(_) => udp_port,
+                        IpAddr::V6(_) => udp_port_v6?,
+                    };
+                    Some(EndpointCandidate {
+                        wg: SocketAddr::new(itf.addr.ip(), wg_port),
+                        udp: SocketAddr::new(itf.addr.ip(), udp_port),
+                    })
                 })
                 .collect();
 
@@ -204,14 +224,23 @@ impl<T: WireGuard, G: GetIfAddrs> State<T, G> {
         public_key: &PublicKey,
     ) -> Result<(), Error> {
         let wg_port = WGPort(self.get_wg_port().await?);
+        let udp_socket = match (addr, &self.udp_socket_v6) {
+            (SocketAddr::V6(_), Some(udp_socket_v6)) => udp_socket_v6,
+            _ => &self.udp_socket,
+        };
         self.ping_pong_handler
             .lock()
             .await
-            .send_ping(addr, wg_port, &self.udp_socket, session_id, public_key)
+            .send_ping(addr, wg_port, udp_socket, session_id, public_key)
             .await
     }
 
-    async fn handle_rx_packet(&self, encrypted_buf: &[u8], addr: &SocketAddr) -> Result<(), Error> {
+    async fn handle_rx_packet(
+        &self,
+        encrypted_buf: &[u8],
+        addr: &SocketAddr,
+        udp_socket: &UdpSocket,
+    ) -> Result<(), Error> {
         let wg_port = WGPort(self.get_wg_port().await?);
         self.ping_pong_handler
             .lock()
@@ -220,7 +249,7 @@ impl<T: WireGuard, G: GetIfAddrs> State<T, G> {
                 encrypted_buf,
                 addr,
                 wg_port,
-                &self.udp_socket,
+                udp_socket,
                 &self.pong_publisher,
                 telio_model::features::EndpointProvider::Local,
             )
@@ -239,14 +268,24 @@ impl<T: WireGuard, G: GetIfAddrs> Runtime for State<T, G> {
     {
         const MAX_SUPPORTED_PACKET_SIZE: usize = 1500;
         let mut rx_buff = vec![0u8; MAX_SUPPORTED_PACKET_SIZE];
+        let mut rx_buff_v6 = vec![0u8; MAX_SUPPORTED_PACKET_SIZE];
         tokio::select! {
             Ok((len, addr)) = self.udp_socket.recv_from(&mut rx_buff) => {
                 let buf = rx_buff.get(..len).ok_or(())?;
-                self.handle_rx_packet(buf, &addr).await.unwrap_or_else(
+                self.handle_rx_packet(buf, &addr, &self.udp_socket).await.unwrap_or_else(
                     |e| {
                         telio_log_warn!("Failed to handle packet received no local interface endpoint provider {:?}", e);
                     });
             }
+            Ok((len, addr)) = recv_from_optional(&self.udp_socket_v6, &mut rx_buff_v6) => {
+                let buf = rx_buff_v6.get(..len).ok_or(())?;
+                if let Some(udp_socket_v6) = &self.udp_socket_v6 {
+                    self.handle_rx_packet(buf, &addr, udp_socket_v6).await.unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Failed to handle IPv6 packet received on local interface endpoint provider {:?}", e);
+                        });
+                }
+            }
             _ = self.poll_timer.tick() => {
                 self.poll_local_endpoints().await.unwrap_or_else(
                     |e| {
@@ -264,6 +303,17 @@ impl<T: WireGuard, G: GetIfAddrs> Runtime for State<T, G> {
     }
 }
 
+/// Receive from the socket if there is one, otherwise never complete
+async fn recv_from_optional(
+    socket: &Option<External<UdpSocket>>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => futures::future::pending().await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -319,6 +369,7 @@ mod tests {
                 .new_external_udp((Ipv4Addr::LOCALHOST, 0), None)
                 .await
                 .unwrap(),
+                udp_socket_v6: None,
                 ping_pong_handler: ping_pong_handler.clone(),
                 get_if_addr: get_if_addrs_mock,
             },
@@ -364,6 +415,7 @@ mod tests {
         let ping_pong_handler = Arc::new(Mutex::new(PingPongHandler::new(secret_key.clone())));
         let local_provider = LocalInterfacesEndpointProvider::new_with(
             provider_socket,
+            None,
             Arc::new(wg_mock),
             Duration::from_secs(10000),
             ping_pong_handler.clone(),
@@ -647,7 +699,9 @@ mod tests {
                 .lock()
                 .await
                 .configure(hashmap! { 456 => sk_copy.public() });
-            state.handle_rx_packet(&encrypted_buf, &provider_addr).await
+            state
+                .handle_rx_packet(&encrypted_buf, &provider_addr, &state.udp_socket)
+                .await
         });
 
         let mut output = vec![0u8; MAX_PACKET_SIZE];
@@ -706,7 +760,9 @@ mod tests {
 
         tokio::spawn(async move {
             ping_pong_handler.lock().await.configure(Default::default());
-            state.handle_rx_packet(&encrypted_buf, &provider_addr).await
+            state
+                .handle_rx_packet(&encrypted_buf, &provider_addr, &state.udp_socket)
+                .await
         });
 
         let mut output = vec![0u8; MAX_PACKET_SIZE];
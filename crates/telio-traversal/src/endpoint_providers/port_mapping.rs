@@ -0,0 +1,984 @@
+//! NAT-PMP (RFC 6886) and PCP (RFC 6887) endpoint provider.
+//!
+//! Many consumer routers have UPnP disabled but still answer NAT-PMP or its successor PCP. The
+//! WireGuard and the proxy port are both mapped on the gateway, the mappings are renewed halfway
+//! through their lifetime and deleted when the provider is stopped.
+
+use crate::endpoint_providers::{
+    EndpointCandidate, EndpointCandidatesChangeEvent, EndpointProvider, EndpointProviderType,
+    Error, PongEvent,
+};
+use crate::ping_pong_handler::PingPongHandler;
+use async_trait::async_trait;
+use futures::Future;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use telio_crypto::PublicKey;
+use telio_model::features::{FeaturePortMapping, PortMappingProtocol};
+use telio_proto::{Session, WGPort};
+use telio_sockets::{External, SocketPool};
+use telio_task::{io::chan::Tx, task_exec, BoxAction, Runtime, Task};
+use telio_utils::{
+    exponential_backoff::{Backoff, ExponentialBackoff, ExponentialBackoffBounds},
+    telio_log_debug, telio_log_info, telio_log_warn, PinnedSleep,
+};
+use telio_wg::{DynamicWg, WireGuard};
+use tokio::{net::UdpSocket, sync::Mutex};
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Port on which gateways listen for NAT-PMP and PCP requests
+const GATEWAY_PORT: u16 = 5351;
+const NAT_PMP_VERSION: u8 = 0;
+const NAT_PMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_OP_MAP_UDP: u8 = 1;
+const PCP_VERSION: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+/// Set in the opcode of every response
+const RESPONSE_BIT: u8 = 0x80;
+const UDP_PROTOCOL: u8 = 17;
+/// PCP messages are never longer than this
+const MAX_RESPONSE_SIZE: usize = 1100;
+/// Wait for the first response, doubled with every retransmission
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: u32 = 4;
+/// Mappings are never renewed more often than this, whatever lifetime the gateway grants
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_SUPPORTED_PACKET_SIZE: usize = 1500;
+const GET_INTERFACE_TIMEOUT_S: Duration = Duration::from_secs(2);
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Port mapped on the gateway
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortMappingLease {
+    /// Local port
+    pub internal: u16,
+    /// Address on the outside of the gateway
+    pub external: SocketAddrV4,
+    /// Time after which the gateway deletes the mapping
+    pub lifetime: Duration,
+}
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait PortMapper: Send + 'static {
+    /// Map the `internal` port on the gateway, or renew its mapping
+    async fn map(&mut self, internal: u16, lifetime: Duration) -> Result<PortMappingLease>;
+    /// Delete the mapping of the `internal` port
+    async fn unmap(&mut self, internal: u16) -> Result<()>;
+}
+
+/// Client of the NAT-PMP and PCP servers on the gateway
+pub struct GatewayClient {
+    /// Requests go to the gateway on the physical interface, outside of the tunnel
+    socket_pool: Arc<SocketPool>,
+    /// Configured gateway, the default route gateway is used otherwise
+    gateway: Option<Ipv4Addr>,
+    port: u16,
+    /// Protocols to try, in order of preference
+    protocols: Vec<PortMappingProtocol>,
+    /// Protocol the gateway answered last time
+    active: Option<PortMappingProtocol>,
+    /// PCP identifies mappings by nonce, renewals and deletes must reuse it
+    nonces: HashMap<u16, [u8; 12]>,
+}
+
+impl GatewayClient {
+    pub fn new(
+        socket_pool: Arc<SocketPool>,
+        gateway: Option<Ipv4Addr>,
+        protocols: Vec<PortMappingProtocol>,
+    ) -> Self {
+        Self {
+            socket_pool,
+            gateway,
+            port: GATEWAY_PORT,
+            protocols,
+            active: None,
+            nonces: HashMap::new(),
+        }
+    }
+
+    fn gateway_addr(&self) -> Result<SocketAddrV4> {
+        self.gateway
+            .or_else(default_gateway)
+            .map(|ip| SocketAddrV4::new(ip, self.port))
+            .ok_or(Error::NoPortMappingGateway)
+    }
+
+    async fn connect(&self, gateway: SocketAddrV4) -> Result<External<UdpSocket>> {
+        let socket = self
+            .socket_pool
+            .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+            .await?;
+        socket.connect(gateway).await?;
+        Ok(socket)
+    }
+
+    async fn map_nat_pmp(
+        &self,
+        gateway: SocketAddrV4,
+        internal: u16,
+        lifetime: Duration,
+    ) -> Result<PortMappingLease> {
+        let socket = self.connect(gateway).await?;
+        let external_ip = transact(
+            &socket,
+            &[NAT_PMP_VERSION, NAT_PMP_OP_EXTERNAL_ADDRESS],
+            parse_nat_pmp_external_address,
+        )
+        .await?;
+        let request = nat_pmp_map_request(internal, internal, lifetime_secs(lifetime));
+        let (external_port, lifetime) = transact(&socket, &request, |response| {
+            parse_nat_pmp_map(response, internal)
+        })
+        .await?;
+        Ok(PortMappingLease {
+            internal,
+            external: SocketAddrV4::new(external_ip, external_port),
+            lifetime,
+        })
+    }
+
+    async fn map_pcp(
+        &mut self,
+        gateway: SocketAddrV4,
+        internal: u16,
+        lifetime: Duration,
+    ) -> Result<PortMappingLease> {
+        let socket = self.connect(gateway).await?;
+        let client = match socket.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return Err(Error::NoPortMappingGateway),
+        };
+        let nonce = *self.nonces.entry(internal).or_insert_with(rand::random);
+        let request = pcp_map_request(client, &nonce, internal, internal, lifetime_secs(lifetime));
+        transact(&socket, &request, |response| {
+            parse_pcp_map(response, &nonce, internal)
+        })
+        .await
+    }
+
+    async fn unmap_pcp(&mut self, gateway: SocketAddrV4, internal: u16) -> Result<()> {
+        let Some(nonce) = self.nonces.remove(&internal) else {
+            return Ok(());
+        };
+        let socket = self.connect(gateway).await?;
+        let client = match socket.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return Err(Error::NoPortMappingGateway),
+        };
+        let request = pcp_map_request(client, &nonce, internal, 0, 0);
+        transact(&socket, &request, |response| {
+            parse_pcp_map(response, &nonce, internal)
+        })
+        .await
+        .map(|_| ())
+    }
+}
+
+#[async_trait]
+impl PortMapper for GatewayClient {
+    async fn map(&mut self, internal: u16, lifetime: Duration) -> Result<PortMappingLease> {
+        let gateway = self.gateway_addr()?;
+        let protocols = match self.active {
+            Some(protocol) => vec![protocol],
+            None => self.protocols.clone(),
+        };
+
+        let mut last_error = Error::PortMappingError("no protocol enabled".to_owned());
+        for protocol in protocols {
+            let result = match protocol {
+                PortMappingProtocol::Pcp => self.map_pcp(gateway, internal, lifetime).await,
+                PortMappingProtocol::NatPmp => self.map_nat_pmp(gateway, internal, lifetime).await,
+            };
+            match result {
+                Ok(lease) => {
+                    if self.active != Some(protocol) {
+                        telio_log_info!("Gateway {gateway} maps ports using {protocol:?}");
+                    }
+                    self.active = Some(protocol);
+                    return Ok(lease);
+                }
+                Err(e) => {
+                    telio_log_debug!("{protocol:?} mapping on {gateway} failed: {e}");
+                    last_error = e;
+                }
+            }
+        }
+
+        self.active = None;
+        Err(last_error)
+    }
+
+    async fn unmap(&mut self, internal: u16) -> Result<()> {
+        let gateway = self.gateway_addr()?;
+        match self.active {
+            Some(PortMappingProtocol::Pcp) => self.unmap_pcp(gateway, internal).await,
+            Some(PortMappingProtocol::NatPmp) => {
+                let request = nat_pmp_map_request(internal, 0, 0);
+                let socket = self.connect(gateway).await?;
+                transact(&socket, &request, |response| {
+                    parse_nat_pmp_map(response, internal)
+                })
+                .await
+                .map(|_| ())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Send `request` until a response accepted by `parse` arrives, retransmitting with growing
+/// timeouts. `parse` returns `None` for packets not answering this request.
+async fn transact<T>(
+    socket: &UdpSocket,
+    request: &[u8],
+    parse: impl Fn(&[u8]) -> Option<Result<T>>,
+) -> Result<T> {
+    let mut buf = vec![0u8; MAX_RESPONSE_SIZE];
+    let mut wait = INITIAL_TIMEOUT;
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(request).await?;
+        let response = tokio::time::timeout(wait, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if let Some(result) = parse(buf.get(..len).unwrap_or_default()) {
+                    return result;
+                }
+            }
+        })
+        .await;
+        match response {
+            Ok(result) => return result,
+            Err(_) => wait *= 2,
+        }
+    }
+    Err(Error::PortMappingError(
+        "gateway did not respond".to_owned(),
+    ))
+}
+
+fn lifetime_secs(lifetime: Duration) -> u32 {
+    u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX)
+}
+
+fn read_u16(buf: &[u8], at: usize) -> Option<u16> {
+    buf.get(at..at + 2)
+        .and_then(|b| b.try_into().ok())
+        .map(u16::from_be_bytes)
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    buf.get(at..at + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_be_bytes)
+}
+
+fn truncated() -> Error {
+    Error::PortMappingError("truncated response".to_owned())
+}
+
+fn nat_pmp_map_request(internal: u16, external: u16, lifetime: u32) -> Vec<u8> {
+    let mut request = vec![NAT_PMP_VERSION, NAT_PMP_OP_MAP_UDP, 0, 0];
+    request.extend_from_slice(&internal.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+/// Checks version, opcode and result code common to NAT-PMP responses
+fn check_nat_pmp_response(response: &[u8], opcode: u8) -> Option<Result<()>> {
+    if response.get(1) != Some(&(opcode | RESPONSE_BIT)) {
+        return None;
+    }
+    if response.first() != Some(&NAT_PMP_VERSION) {
+        return Some(Err(Error::PortMappingError(
+            "unsupported NAT-PMP version".to_owned(),
+        )));
+    }
+    match read_u16(response, 2) {
+        Some(0) => Some(Ok(())),
+        Some(code) => Some(Err(Error::PortMappingError(format!(
+            "NAT-PMP result code {code}"
+        )))),
+        None => Some(Err(truncated())),
+    }
+}
+
+fn parse_nat_pmp_external_address(response: &[u8]) -> Option<Result<Ipv4Addr>> {
+    if let Err(e) = check_nat_pmp_response(response, NAT_PMP_OP_EXTERNAL_ADDRESS)? {
+        return Some(Err(e));
+    }
+    Some(
+        read_u32(response, 8)
+            .map(Ipv4Addr::from)
+            .ok_or_else(truncated),
+    )
+}
+
+/// External port and lifetime of the mapping
+fn parse_nat_pmp_map(response: &[u8], internal: u16) -> Option<Result<(u16, Duration)>> {
+    if let Err(e) = check_nat_pmp_response(response, NAT_PMP_OP_MAP_UDP)? {
+        return Some(Err(e));
+    }
+    if read_u16(response, 8)? != internal {
+        return None;
+    }
+    let external = read_u16(response, 10);
+    let lifetime = read_u32(response, 12);
+    Some(
+        external
+            .zip(lifetime)
+            .map(|(port, lifetime)| (port, Duration::from_secs(lifetime.into())))
+            .ok_or_else(truncated),
+    )
+}
+
+fn pcp_map_request(
+    client: Ipv4Addr,
+    nonce: &[u8; 12],
+    internal: u16,
+    external: u16,
+    lifetime: u32,
+) -> Vec<u8> {
+    let mut request = vec![PCP_VERSION, PCP_OP_MAP, 0, 0];
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client.to_ipv6_mapped().octets());
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[UDP_PROTOCOL, 0, 0, 0]);
+    request.extend_from_slice(&internal.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    // No preference for the external address
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+fn parse_pcp_map(
+    response: &[u8],
+    nonce: &[u8; 12],
+    internal: u16,
+) -> Option<Result<PortMappingLease>> {
+    // Gateways speaking only NAT-PMP answer PCP requests with their own version
+    if response.first() != Some(&PCP_VERSION) {
+        return Some(Err(Error::PortMappingError(
+            "PCP is not supported by the gateway".to_owned(),
+        )));
+    }
+    if response.get(1) != Some(&(PCP_OP_MAP | RESPONSE_BIT)) {
+        return None;
+    }
+    if let Some(code @ 1..) = response.get(3) {
+        return Some(Err(Error::PortMappingError(format!(
+            "PCP result code {code}"
+        ))));
+    }
+    if response.get(24..36) != Some(nonce.as_slice()) || read_u16(response, 40)? != internal {
+        return None;
+    }
+
+    let lifetime = read_u32(response, 4);
+    let external_port = read_u16(response, 42);
+    let external_ip = response
+        .get(44..60)
+        .and_then(|ip| <[u8; 16]>::try_from(ip).ok())
+        .and_then(|ip| std::net::Ipv6Addr::from(ip).to_ipv4_mapped());
+    Some(match (lifetime, external_port, external_ip) {
+        (Some(lifetime), Some(port), Some(ip)) => Ok(PortMappingLease {
+            internal,
+            external: SocketAddrV4::new(ip, port),
+            lifetime: Duration::from_secs(lifetime.into()),
+        }),
+        _ => Err(truncated()),
+    })
+}
+
+/// Gateway of the IPv4 default route
+fn default_gateway() -> Option<Ipv4Addr> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        std::fs::read_to_string("/proc/net/route")
+            .ok()
+            .and_then(|routes| parse_proc_net_route(&routes))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        None
+    }
+}
+
+/// Addresses in /proc/net/route are hex printed in host byte order
+#[cfg(any(target_os = "linux", target_os = "android", test))]
+fn parse_proc_net_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|route| {
+        let mut fields = route.split_whitespace();
+        let destination = fields.nth(1)?;
+        let gateway = u32::from_str_radix(fields.next()?, 16).ok()?;
+        (destination == "00000000" && gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+pub struct PortMappingEndpointProvider<
+    Wg: WireGuard = DynamicWg,
+    M: PortMapper = GatewayClient,
+    E: Backoff = ExponentialBackoff,
+> {
+    task: Task<State<Wg, M, E>>,
+}
+
+impl<Wg: WireGuard> PortMappingEndpointProvider<Wg> {
+    pub fn start(
+        socket_pool: Arc<SocketPool>,
+        udp_socket: External<UdpSocket>,
+        wg: Arc<Wg>,
+        exponential_backoff_bounds: ExponentialBackoffBounds,
+        ping_pong_handler: Arc<Mutex<PingPongHandler>>,
+        features: &FeaturePortMapping,
+    ) -> Result<Self> {
+        let gateway = match features.gateway {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            Some(IpAddr::V6(ip)) => {
+                telio_log_warn!("Ignoring IPv6 port mapping gateway {ip}, only IPv4 is supported");
+                None
+            }
+            None => None,
+        };
+        Ok(Self::start_with(
+            udp_socket,
+            wg,
+            ExponentialBackoff::new(exponential_backoff_bounds)?,
+            ping_pong_handler,
+            GatewayClient::new(socket_pool, gateway, features.protocols.clone()),
+            Duration::from_secs(features.lease_duration_s.into()),
+        ))
+    }
+}
+
+impl<Wg: WireGuard, M: PortMapper, E: Backoff> PortMappingEndpointProvider<Wg, M, E> {
+    pub fn start_with(
+        udp_socket: External<UdpSocket>,
+        wg: Arc<Wg>,
+        exponential_backoff: E,
+        ping_pong_handler: Arc<Mutex<PingPongHandler>>,
+        mapper: M,
+        lease_duration: Duration,
+    ) -> Self {
+        Self {
+            task: Task::start(State {
+                udp_socket: Arc::new(udp_socket),
+                wg,
+                mapper,
+                lease_duration,
+                leases: None,
+                endpoint_candidate: None,
+                pong_events_tx: None,
+                epc_event_tx: None,
+                exponential_backoff,
+                refresh: PinnedSleep::new(Duration::ZERO, ()),
+                is_endpoint_provider_paused: false,
+                rx_buff: vec![0u8; MAX_SUPPORTED_PACKET_SIZE],
+                ping_pong_handler,
+            }),
+        }
+    }
+
+    pub async fn stop(self) {
+        let _ = task_exec!(&self.task, async move |s| {
+            s.delete_mappings().await;
+            Ok(())
+        })
+        .await;
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+#[async_trait]
+impl<Wg: WireGuard, M: PortMapper, E: Backoff> EndpointProvider
+    for PortMappingEndpointProvider<Wg, M, E>
+{
+    fn name(&self) -> &'static str {
+        "port-mapping"
+    }
+
+    async fn subscribe_for_pong_events(&self, tx: Tx<PongEvent>) {
+        task_exec!(&self.task, async move |s| {
+            s.pong_events_tx = Some(tx);
+            Ok(())
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    async fn subscribe_for_endpoint_candidates_change_events(
+        &self,
+        tx: Tx<EndpointCandidatesChangeEvent>,
+    ) {
+        task_exec!(&self.task, async move |s| {
+            s.epc_event_tx = Some(tx);
+            Ok(())
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    async fn trigger_endpoint_candidates_discovery(&self, _force: bool) -> Result<()> {
+        let _ = task_exec!(&self.task, async move |s| {
+            s.send_endpoint_candidate().await;
+            Ok(())
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn handle_endpoint_gone_notification(&self) {
+        task_exec!(&self.task, async move |s| {
+            // Renewing right away checks the mappings and publishes the candidate again
+            s.endpoint_candidate = None;
+            s.refresh = PinnedSleep::new(Duration::ZERO, ());
+            Ok(())
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    async fn send_ping(
+        &self,
+        addr: SocketAddr,
+        session_id: Session,
+        public_key: PublicKey,
+    ) -> Result<()> {
+        task_exec!(&self.task, async move |s| Ok(s
+            .send_ping(addr, session_id, &public_key)
+            .await))
+        .await?
+    }
+
+    async fn get_current_endpoints(&self) -> Option<Vec<EndpointCandidate>> {
+        task_exec!(&self.task, async move |s| Ok(s
+            .endpoint_candidate
+            .clone()
+            .map(|candidate| vec![candidate])))
+        .await
+        .unwrap_or(None)
+    }
+
+    async fn pause(&self) {
+        let _ = task_exec!(&self.task, async move |s| {
+            s.is_endpoint_provider_paused = true;
+            Ok(())
+        })
+        .await;
+    }
+
+    async fn unpause(&self) {
+        let _ = task_exec!(&self.task, async move |s| {
+            s.is_endpoint_provider_paused = false;
+            Ok(())
+        })
+        .await;
+    }
+
+    async fn is_paused(&self) -> bool {
+        task_exec!(&self.task, async move |s| Ok(s.is_endpoint_provider_paused))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+struct State<Wg: WireGuard, M: PortMapper, E: Backoff> {
+    udp_socket: Arc<External<UdpSocket>>,
+    wg: Arc<Wg>,
+    mapper: M,
+    lease_duration: Duration,
+    /// Mappings of the WireGuard and the proxy port
+    leases: Option<(PortMappingLease, PortMappingLease)>,
+    endpoint_candidate: Option<EndpointCandidate>,
+    pong_events_tx: Option<Tx<PongEvent>>,
+    epc_event_tx: Option<Tx<EndpointCandidatesChangeEvent>>,
+    exponential_backoff: E,
+    /// Time to create or renew the mappings
+    refresh: PinnedSleep<()>,
+    is_endpoint_provider_paused: bool,
+    rx_buff: Vec<u8>,
+    ping_pong_handler: Arc<Mutex<PingPongHandler>>,
+}
+
+impl<Wg: WireGuard, M: PortMapper, E: Backoff> State<Wg, M, E> {
+    async fn send_ping(
+        &self,
+        addr: SocketAddr,
+        session_id: Session,
+        public_key: &PublicKey,
+    ) -> Result<()> {
+        let wg_port = self.get_wg_port().ok_or(Error::NoWGListenPort)?;
+        self.ping_pong_handler
+            .lock()
+            .await
+            .send_ping(addr, wg_port, &self.udp_socket, session_id, public_key)
+            .await
+    }
+
+    /// External WireGuard port of the mapping
+    fn get_wg_port(&self) -> Option<WGPort> {
+        self.endpoint_candidate
+            .as_ref()
+            .map(|epc| WGPort(epc.wg.port()))
+    }
+
+    /// Create the mappings or renew them, returning the time after which they are renewed again
+    async fn refresh_mappings(&mut self) -> Result<Duration> {
+        let wg_port = self
+            .wg
+            .wait_for_listen_port(GET_INTERFACE_TIMEOUT_S)
+            .await?;
+        let proxy_port = self.udp_socket.local_addr()?.port();
+
+        if let Some((old_wg, _)) = self.leases {
+            if old_wg.internal != wg_port {
+                telio_log_debug!(
+                    "WireGuard port changed, deleting mapping of {}",
+                    old_wg.internal
+                );
+                let _ = self.mapper.unmap(old_wg.internal).await;
+            }
+        }
+
+        let wg = self.mapper.map(wg_port, self.lease_duration).await?;
+        let proxy = self.mapper.map(proxy_port, self.lease_duration).await?;
+        self.leases = Some((wg, proxy));
+
+        let candidate = EndpointCandidate {
+            wg: SocketAddr::V4(wg.external),
+            udp: SocketAddr::V4(proxy.external),
+        };
+        if self.endpoint_candidate.as_ref() != Some(&candidate) {
+            telio_log_info!("Port mapping endpoint: {:?}", candidate);
+            self.endpoint_candidate = Some(candidate);
+            self.send_endpoint_candidate().await;
+        }
+
+        Ok((wg.lifetime.min(proxy.lifetime) / 2).max(MIN_RENEW_INTERVAL))
+    }
+
+    async fn delete_mappings(&mut self) {
+        if let Some((wg, proxy)) = self.leases.take() {
+            for internal in [wg.internal, proxy.internal] {
+                if let Err(e) = self.mapper.unmap(internal).await {
+                    telio_log_warn!("Failed to delete port mapping of {internal}: {e}");
+                }
+            }
+        }
+    }
+
+    async fn drop_endpoint_candidate(&mut self) {
+        self.leases = None;
+        if self.endpoint_candidate.take().is_some() {
+            if let Some(epc_tx) = &self.epc_event_tx {
+                let _ = epc_tx
+                    .send((EndpointProviderType::PortMapping, vec![]))
+                    .await;
+            }
+        }
+    }
+
+    async fn send_endpoint_candidate(&self) {
+        if let Some(epc) = self.endpoint_candidate.clone() {
+            if let Some(epc_tx) = &self.epc_event_tx {
+                let _ = epc_tx
+                    .send((EndpointProviderType::PortMapping, vec![epc]))
+                    .await;
+            }
+        }
+    }
+
+    async fn handle_ping_rx(&mut self, encrypted_buf: &[u8], addr: &SocketAddr) -> Result<()> {
+        let wg_port = self.get_wg_port().ok_or(Error::NoWGListenPort)?;
+        self.ping_pong_handler
+            .lock()
+            .await
+            .handle_rx_packet(
+                encrypted_buf,
+                addr,
+                wg_port,
+                &self.udp_socket,
+                &self.pong_events_tx,
+                telio_model::features::EndpointProvider::PortMapping,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl<Wg: WireGuard, M: PortMapper, E: Backoff> Runtime for State<Wg, M, E> {
+    const NAME: &'static str = "PortMappingEndpointProvider";
+
+    type Err = ();
+
+    async fn wait_with_update<F>(&mut self, update: F) -> std::result::Result<(), Self::Err>
+    where
+        F: Future<Output = BoxAction<Self, std::result::Result<(), Self::Err>>> + Send,
+    {
+        // Existing mappings are kept alive while paused, but no new ones are created
+        let can_refresh = !self.is_endpoint_provider_paused || self.leases.is_some();
+
+        tokio::select! {
+            Ok((len, addr)) = self.udp_socket.recv_from(&mut self.rx_buff) => {
+                let buff = self.rx_buff.get(..len).unwrap_or_default().to_vec();
+                let _ = self.handle_ping_rx(&buff, &addr).await;
+            }
+            _ = &mut self.refresh, if can_refresh => {
+                let next = match self.refresh_mappings().await {
+                    Ok(renew_after) => {
+                        self.exponential_backoff.reset();
+                        renew_after
+                    }
+                    Err(e) => {
+                        telio_log_warn!("Failed to map ports on the gateway: {}", e);
+                        self.drop_endpoint_candidate().await;
+                        let backoff = self.exponential_backoff.get_backoff();
+                        self.exponential_backoff.next_backoff();
+                        backoff
+                    }
+                };
+                self.refresh = PinnedSleep::new(next, ());
+            }
+            update = update => {
+                return update(self).await;
+            }
+            else => {
+                return Ok(());
+            },
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_crypto::SecretKey;
+    use telio_sockets::{NativeProtector, SocketPool};
+    use telio_task::io::Chan;
+    use telio_wg::MockWireGuard;
+
+    const NONCE: [u8; 12] = [7; 12];
+
+    #[test]
+    fn nat_pmp_messages() {
+        assert_eq!(
+            nat_pmp_map_request(51820, 51821, 3600),
+            [0, 1, 0, 0, 0xca, 0x6c, 0xca, 0x6d, 0, 0, 0x0e, 0x10]
+        );
+
+        let address = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 5];
+        assert_eq!(
+            parse_nat_pmp_external_address(&address).unwrap().unwrap(),
+            Ipv4Addr::new(203, 0, 113, 5)
+        );
+
+        let map = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0xca, 0x6c, 0xca, 0x6d, 0, 0, 0x07, 0x08,
+        ];
+        assert_eq!(
+            parse_nat_pmp_map(&map, 51820).unwrap().unwrap(),
+            (51821, Duration::from_secs(1800))
+        );
+        assert!(parse_nat_pmp_map(&map, 1000).is_none(), "other mapping");
+
+        let refused = [0, 129, 0, 2, 0, 0, 0, 9, 0xca, 0x6c, 0, 0, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_map(&refused, 51820).unwrap().is_err());
+    }
+
+    fn pcp_response(result: u8, nonce: &[u8; 12], internal: u16) -> Vec<u8> {
+        let mut response = vec![PCP_VERSION, PCP_OP_MAP | RESPONSE_BIT, 0, result];
+        response.extend_from_slice(&600u32.to_be_bytes());
+        response.extend_from_slice(&[0; 16]);
+        response.extend_from_slice(nonce);
+        response.extend_from_slice(&[UDP_PROTOCOL, 0, 0, 0]);
+        response.extend_from_slice(&internal.to_be_bytes());
+        response.extend_from_slice(&40000u16.to_be_bytes());
+        response.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 5).to_ipv6_mapped().octets());
+        response
+    }
+
+    #[test]
+    fn pcp_messages() {
+        let request = pcp_map_request(Ipv4Addr::new(192, 168, 1, 10), &NONCE, 51820, 51820, 60);
+        assert_eq!(request.len(), 60);
+        assert_eq!(request.get(..4), Some([2, 1, 0, 0].as_slice()));
+        assert_eq!(request.get(20..24), Some([192, 168, 1, 10].as_slice()));
+        assert_eq!(request.get(24..36), Some(NONCE.as_slice()));
+
+        assert_eq!(
+            parse_pcp_map(&pcp_response(0, &NONCE, 51820), &NONCE, 51820)
+                .unwrap()
+                .unwrap(),
+            PortMappingLease {
+                internal: 51820,
+                external: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), 40000),
+                lifetime: Duration::from_secs(600),
+            }
+        );
+        assert!(parse_pcp_map(&pcp_response(0, &[1; 12], 51820), &NONCE, 51820).is_none());
+        assert!(
+            parse_pcp_map(&pcp_response(8, &NONCE, 51820), &NONCE, 51820)
+                .unwrap()
+                .is_err()
+        );
+
+        let nat_pmp_only = [0, 129, 0, 1, 0, 0, 0, 9];
+        assert!(parse_pcp_map(&nat_pmp_only, &NONCE, 51820)
+            .unwrap()
+            .is_err());
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn default_route_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      nlx0\t0000FE64\t00000000\t0001\t0\t0\t0\t0000FFFF\n\
+                      eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\n";
+        assert_eq!(
+            parse_proc_net_route(routes),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse_proc_net_route("Iface\tDestination\tGateway\n"), None);
+    }
+
+    fn socket_pool() -> Arc<SocketPool> {
+        Arc::new(SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        ))
+    }
+
+    /// Gateway which answers only NAT-PMP, rejecting PCP with its own version
+    async fn nat_pmp_gateway() -> SocketAddrV4 {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = match socket.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_RESPONSE_SIZE];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let response = match &buf[..len] {
+                    [PCP_VERSION, ..] => vec![0, 129, 0, 1, 0, 0, 0, 9],
+                    [0, 0] => vec![0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 5],
+                    [0, 1, 0, 0, internal @ .., _, _, _, _, _, _] => {
+                        let mut response = vec![0, 129, 0, 0, 0, 0, 0, 9];
+                        response.extend_from_slice(internal);
+                        response.extend_from_slice(&[0x9c, 0x40, 0, 0, 0x0e, 0x10]);
+                        response
+                    }
+                    _ => continue,
+                };
+                let _ = socket.send_to(&response, from).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_nat_pmp() {
+        let gateway = nat_pmp_gateway().await;
+        let mut client = GatewayClient::new(
+            socket_pool(),
+            Some(*gateway.ip()),
+            vec![PortMappingProtocol::Pcp, PortMappingProtocol::NatPmp],
+        );
+        client.port = gateway.port();
+
+        let lease = client.map(51820, Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(
+            lease,
+            PortMappingLease {
+                internal: 51820,
+                external: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), 40000),
+                lifetime: Duration::from_secs(3600),
+            }
+        );
+        assert_eq!(client.active, Some(PortMappingProtocol::NatPmp));
+        client.unmap(51820).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_without_enabled_protocols() {
+        let gateway = nat_pmp_gateway().await;
+        let mut client = GatewayClient::new(
+            socket_pool(),
+            Some(*gateway.ip()),
+            vec![PortMappingProtocol::Pcp],
+        );
+        client.port = gateway.port();
+
+        assert!(client.map(51820, Duration::from_secs(60)).await.is_err());
+        assert_eq!(client.active, None);
+    }
+
+    #[tokio::test]
+    async fn provider_publishes_and_deletes_mappings() {
+        let udp_socket = socket_pool()
+            .new_external_udp((Ipv4Addr::LOCALHOST, 0), None)
+            .await
+            .unwrap();
+        let proxy_port = udp_socket.local_addr().unwrap().port();
+
+        let mut wg = MockWireGuard::new();
+        wg.expect_wait_for_listen_port().returning(|_| Ok(51820));
+
+        let mut mapper = MockPortMapper::new();
+        mapper.expect_map().returning(|internal, lifetime| {
+            Ok(PortMappingLease {
+                internal,
+                external: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), internal + 1),
+                lifetime,
+            })
+        });
+        mapper
+            .expect_unmap()
+            .withf(move |internal| *internal == 51820 || *internal == proxy_port)
+            .times(2)
+            .returning(|_| Ok(()));
+
+        let provider = PortMappingEndpointProvider::start_with(
+            udp_socket,
+            Arc::new(wg),
+            ExponentialBackoff::new(ExponentialBackoffBounds {
+                initial: Duration::from_secs(1),
+                maximal: None,
+            })
+            .unwrap(),
+            Arc::new(Mutex::new(PingPongHandler::new(SecretKey::gen()))),
+            mapper,
+            Duration::from_secs(3600),
+        );
+
+        let mut candidates_events = Chan::default();
+        provider
+            .subscribe_for_endpoint_candidates_change_events(candidates_events.tx)
+            .await;
+        provider.handle_endpoint_gone_notification().await;
+
+        let (provider_type, candidates) = candidates_events.rx.recv().await.unwrap();
+        assert_eq!(provider_type, EndpointProviderType::PortMapping);
+        assert_eq!(
+            candidates,
+            vec![EndpointCandidate {
+                wg: SocketAddr::from(([203, 0, 113, 5], 51821)),
+                udp: SocketAddr::from(([203, 0, 113, 5], proxy_port + 1)),
+            }]
+        );
+
+        provider.stop().await;
+    }
+}
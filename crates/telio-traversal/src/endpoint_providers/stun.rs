@@ -972,7 +972,7 @@ impl StunRequest {
 }
 
 /// Stun message encoding/decoding
-mod stun_msg {
+pub(crate) mod stun_msg {
     use std::{io, net::SocketAddr};
 
     use bytecodec::{DecodeExt, EncodeExt};
@@ -1954,6 +1954,7 @@ mod tests {
             async fn add_peer(&self, peer: Peer) -> Result<(), Error>;
             async fn del_peer(&self, key: PublicKey) -> Result<(), Error>;
             async fn drop_connected_sockets(&self) -> Result<(), Error>;
+            async fn trigger_handshakes(&self, peers: Vec<PublicKey>) -> Result<(), Error>;
             async fn time_since_last_rx(&self, public_key: PublicKey) -> Result<Option<Duration>, Error>;
             async fn stop(self);
             async fn reset_existing_connections(&self, exit_pubkey: PublicKey) -> Result<(), Error>;
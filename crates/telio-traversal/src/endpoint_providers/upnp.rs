@@ -747,6 +747,7 @@ mod tests {
             async fn add_peer(&self, peer: Peer) -> Result1<()>;
             async fn del_peer(&self, key: PublicKey) -> Result1<()>;
             async fn drop_connected_sockets(&self) -> Result1<()>;
+            async fn trigger_handshakes(&self, peers: Vec<PublicKey>) -> Result1<()>;
             async fn time_since_last_rx(&self, public_key: PublicKey) -> Result1<Option<Duration>>;
             async fn stop(self);
             async fn reset_existing_connections(&self, exit_pubkey: PublicKey) -> Result1<()>;
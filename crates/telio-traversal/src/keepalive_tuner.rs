@@ -0,0 +1,324 @@
+//! Adaptive persistent keepalive for direct connections.
+//!
+//! NAT bindings expire after staying idle for a NAT specific amount of time. A fixed
+//! keepalive interval is either wasteful behind endpoint-independent NATs, which keep
+//! bindings for minutes, or too slow behind symmetric ones. The tuner instead probes ever
+//! longer intervals while direct peers keep sending us traffic, and falls back to the
+//! last interval that was known to work once a binding is lost. Estimates are remembered
+//! per network, so that switching back to a known network does not start probing anew.
+//! An actively measured binding lifetime, see [crate::nat_binding_probe], replaces the
+//! probing altogether.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    time::Duration,
+};
+
+use telio_model::features::FeatureAdaptiveKeepalive;
+use telio_utils::{telio_log_debug, telio_log_info, Instant};
+
+/// An interval has to keep every direct peer alive for this many keepalive periods
+/// before it is considered to be shorter than the NAT binding lifetime
+const PROBE_PERIODS: u32 = 4;
+
+/// Identifier of the network the device is connected to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NetworkId(u64);
+
+impl NetworkId {
+    /// Derive network identifier from the addresses of the local interfaces
+    pub fn from_local_addresses(addresses: &[IpAddr]) -> Self {
+        let mut addresses = addresses.to_vec();
+        addresses.sort();
+        addresses.dedup();
+
+        let mut hasher = DefaultHasher::new();
+        addresses.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Estimate {
+    /// Interval currently in use
+    interval_s: u32,
+    /// Longest interval which survived a whole probe window
+    confirmed_s: u32,
+    /// Binding was lost at least once, so the interval is no longer increased
+    settled: bool,
+    /// Actively measured binding lifetime the interval is derived from
+    lifetime_s: Option<u32>,
+}
+
+/// Keepalive interval estimator, see the module documentation
+#[derive(Debug)]
+pub struct KeepaliveTuner {
+    config: FeatureAdaptiveKeepalive,
+    min_interval_s: u32,
+    network: NetworkId,
+    estimates: HashMap<NetworkId, Estimate>,
+    probe_started: Option<Instant>,
+}
+
+impl KeepaliveTuner {
+    /// Create a tuner which never goes below `min_interval_s`
+    pub fn new(config: FeatureAdaptiveKeepalive, min_interval_s: u32) -> Self {
+        let min_interval_s = min_interval_s.max(1);
+        Self {
+            config,
+            min_interval_s,
+            network: NetworkId::default(),
+            estimates: HashMap::new(),
+            probe_started: None,
+        }
+    }
+
+    /// Keepalive interval for direct peers on the current network
+    pub fn interval(&self) -> u32 {
+        self.estimate().interval_s
+    }
+
+    /// Network the device is currently connected to
+    pub fn network(&self) -> NetworkId {
+        self.network
+    }
+
+    /// Keepalive interval for the current network, when its binding lifetime was measured
+    pub fn measured_interval(&self) -> Option<u32> {
+        let estimate = self.estimate();
+        estimate.lifetime_s.map(|_| estimate.interval_s)
+    }
+
+    /// Actively measured binding lifetime of the current network
+    pub fn binding_lifetime(&self) -> Option<u32> {
+        self.estimate().lifetime_s
+    }
+
+    /// Use an actively measured binding lifetime of `network`.
+    ///
+    /// The interval is set just under the lifetime and is no longer increased, although lost
+    /// bindings still bring it down. Returns the new interval when it applies to the current
+    /// network and has changed.
+    pub fn set_binding_lifetime(&mut self, network: NetworkId, lifetime_s: u32) -> Option<u32> {
+        let max_interval_s = self.config.max_interval_s.max(self.min_interval_s);
+        let interval_s =
+            (lifetime_s.saturating_mul(9) / 10).clamp(self.min_interval_s, max_interval_s);
+        let previous = self.interval();
+
+        self.estimates.insert(
+            network,
+            Estimate {
+                interval_s,
+                confirmed_s: interval_s,
+                settled: true,
+                lifetime_s: Some(lifetime_s),
+            },
+        );
+        telio_log_info!(
+            "NAT binding lifetime of {network:?} is {lifetime_s}s, keepalive {interval_s}s"
+        );
+
+        (network == self.network && interval_s != previous).then_some(interval_s)
+    }
+
+    /// Switch to a different network, reusing its estimate when it is already known
+    pub fn set_network(&mut self, network: NetworkId) {
+        if self.network != network {
+            telio_log_debug!("Keepalive tuner switched to network {:?}", network);
+            self.network = network;
+            self.probe_started = None;
+        }
+    }
+
+    /// Feed in the time since the last packet was received from each direct peer.
+    ///
+    /// Returns the new interval when it has changed.
+    pub fn observe(
+        &mut self,
+        now: Instant,
+        time_since_last_rx: impl IntoIterator<Item = Duration>,
+    ) -> Option<u32> {
+        let mut time_since_last_rx = time_since_last_rx.into_iter().peekable();
+        if time_since_last_rx.peek().is_none() {
+            // Nothing to learn from without direct peers
+            self.probe_started = None;
+            return None;
+        }
+
+        let max_interval_s = self.config.max_interval_s.max(self.min_interval_s);
+        let min_interval_s = self.min_interval_s;
+        let probe_started = *self.probe_started.get_or_insert(now);
+        let estimate = self.estimate_mut();
+        let interval = Duration::from_secs(estimate.interval_s.into());
+        let previous = estimate.interval_s;
+
+        if time_since_last_rx.any(|since| since > interval * 2) {
+            // Peers did not get through in time, the binding has most likely expired.
+            // Go back to the last interval which was known to work. A peer using a much
+            // longer interval can trigger this too, which only makes the estimate safer.
+            estimate.interval_s = estimate
+                .confirmed_s
+                .min(estimate.interval_s.saturating_sub(1))
+                .max(min_interval_s);
+            estimate.confirmed_s = estimate.interval_s;
+            estimate.settled = true;
+            self.probe_started = None;
+        } else if now.saturating_duration_since(probe_started) >= interval * PROBE_PERIODS {
+            estimate.confirmed_s = estimate.confirmed_s.max(estimate.interval_s);
+            if !estimate.settled {
+                estimate.interval_s = (estimate.interval_s.saturating_mul(3) / 2)
+                    .max(estimate.interval_s + 1)
+                    .min(max_interval_s);
+            }
+            self.probe_started = Some(now);
+        }
+
+        let current = self.estimate().interval_s;
+        (current != previous).then(|| {
+            telio_log_info!("Direct keepalive interval changed {previous}s -> {current}s");
+            current
+        })
+    }
+
+    fn initial_estimate(&self) -> Estimate {
+        Estimate {
+            interval_s: self.min_interval_s,
+            confirmed_s: self.min_interval_s,
+            settled: false,
+            lifetime_s: None,
+        }
+    }
+
+    fn estimate(&self) -> Estimate {
+        self.estimates
+            .get(&self.network)
+            .copied()
+            .unwrap_or_else(|| self.initial_estimate())
+    }
+
+    fn estimate_mut(&mut self) -> &mut Estimate {
+        let initial = self.initial_estimate();
+        self.estimates.entry(self.network).or_insert(initial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEALTHY: Duration = Duration::from_secs(1);
+
+    fn tuner() -> KeepaliveTuner {
+        KeepaliveTuner::new(
+            FeatureAdaptiveKeepalive {
+                max_interval_s: 60,
+                ..Default::default()
+            },
+            5,
+        )
+    }
+
+    /// Run probe windows with healthy peers until the interval stops changing
+    fn probe_until_stable(tuner: &mut KeepaliveTuner, now: &mut Instant) {
+        tuner.observe(*now, [HEALTHY]);
+        for _ in 0..100 {
+            *now += Duration::from_secs((tuner.interval() * PROBE_PERIODS).into());
+            if tuner.observe(*now, [HEALTHY]).is_none() {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn interval_grows_while_peers_are_healthy() {
+        let mut tuner = tuner();
+        let mut now = Instant::now();
+        assert_eq!(tuner.interval(), 5);
+
+        probe_until_stable(&mut tuner, &mut now);
+        assert_eq!(tuner.interval(), 60);
+    }
+
+    #[test]
+    fn interval_backs_off_when_binding_is_lost() {
+        let mut tuner = tuner();
+        let mut now = Instant::now();
+        tuner.observe(now, [HEALTHY]);
+        now += Duration::from_secs(20);
+        assert_eq!(tuner.observe(now, [HEALTHY]), Some(7));
+        now += Duration::from_secs(28);
+        assert_eq!(tuner.observe(now, [HEALTHY]), Some(10));
+
+        // Binding expired somewhere between 7s and 10s
+        assert_eq!(
+            tuner.observe(now, [HEALTHY, Duration::from_secs(25)]),
+            Some(7)
+        );
+
+        // Settled interval is not probed again
+        probe_until_stable(&mut tuner, &mut now);
+        assert_eq!(tuner.interval(), 7);
+    }
+
+    #[test]
+    fn estimates_are_kept_per_network() {
+        let mut tuner = tuner();
+        let mut now = Instant::now();
+        let home = NetworkId::from_local_addresses(&["192.168.1.10".parse().unwrap()]);
+        let office = NetworkId::from_local_addresses(&["10.0.0.10".parse().unwrap()]);
+
+        tuner.set_network(home);
+        probe_until_stable(&mut tuner, &mut now);
+        assert_eq!(tuner.interval(), 60);
+
+        tuner.set_network(office);
+        assert_eq!(tuner.interval(), 5);
+
+        tuner.set_network(home);
+        assert_eq!(tuner.interval(), 60);
+    }
+
+    #[test]
+    fn measured_lifetime_replaces_probing() {
+        let mut tuner = tuner();
+        let mut now = Instant::now();
+        let network = tuner.network();
+        assert_eq!(tuner.measured_interval(), None);
+
+        assert_eq!(tuner.set_binding_lifetime(network, 30), Some(27));
+        assert_eq!(tuner.measured_interval(), Some(27));
+        assert_eq!(tuner.binding_lifetime(), Some(30));
+
+        // Measured interval is not probed further
+        probe_until_stable(&mut tuner, &mut now);
+        assert_eq!(tuner.interval(), 27);
+
+        // But still backs off once a binding is lost
+        assert_eq!(tuner.observe(now, [Duration::from_secs(60)]), Some(26));
+        assert_eq!(tuner.measured_interval(), Some(26));
+    }
+
+    #[test]
+    fn measured_lifetime_of_other_network_is_kept() {
+        let mut tuner = tuner();
+        let office = NetworkId::from_local_addresses(&["10.0.0.10".parse().unwrap()]);
+
+        assert_eq!(tuner.set_binding_lifetime(office, 300), None);
+        assert_eq!(tuner.interval(), 5);
+
+        tuner.set_network(office);
+        assert_eq!(tuner.interval(), 60);
+    }
+
+    #[test]
+    fn network_id_ignores_address_order() {
+        let a: IpAddr = "192.168.1.10".parse().unwrap();
+        let b: IpAddr = "10.0.0.10".parse().unwrap();
+        assert_eq!(
+            NetworkId::from_local_addresses(&[a, b]),
+            NetworkId::from_local_addresses(&[b, a, b])
+        );
+    }
+}
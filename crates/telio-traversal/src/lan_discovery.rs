@@ -0,0 +1,747 @@
+//! Discovery of the meshnet peers on the same LAN.
+//!
+//! Every announce period the node sends each of its meshnet peers an announcement to a multicast
+//! group, holding its meshnet IP and the port of WireGuard. The announcement is sealed for the
+//! peer with [encrypt_request], so only the peer can read it and it proves to be sent by the
+//! node. A peer receiving the announcement learns the WireGuard endpoint of the node on the LAN
+//! from its source address, and the cross ping check takes it as a validated endpoint right away,
+//! without the endpoint exchange over the relay. The session in the announcement is the one the
+//! node sends its upgrade request with, so the peer knows to accept it.
+//!
+//! The source address of a datagram is not authenticated, so the announcement also holds the LAN
+//! IP the node sends it from and a nonce: an announcement arriving from another IP, or arriving
+//! again, is ignored, so it cannot be replayed to point the peer at another endpoint.
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::WireGuardEndpointCandidateChangeEvent;
+use async_trait::async_trait;
+use futures::Future;
+use telio_crypto::{
+    encryption::{decrypt_request, encrypt_request, Error as EncryptionError},
+    PublicKey, SecretKey,
+};
+use telio_model::{
+    config::{Config, PeerBase},
+    features::EndpointProvider,
+};
+use telio_proto::Session;
+use telio_sockets::{External, SocketPool};
+use telio_task::{io::chan, task_exec, BoxAction, Runtime, Task};
+use telio_utils::{interval, telio_log_debug, telio_log_info, telio_log_warn, Instant};
+use telio_wg::{DynamicWg, WireGuard};
+use tokio::{net::UdpSocket, time::Interval};
+
+/// Marks the datagrams of the LAN discovery, version 2
+const MAGIC: &[u8; 4] = b"TLD\x02";
+/// Longest datagram received, the announcements are well below it
+const MAX_DATAGRAM_LEN: usize = 512;
+/// Announcements sent this far off the local clock are ignored
+const MAX_ANNOUNCEMENT_AGE: Duration = Duration::from_secs(60);
+/// How long the local IP of the route to a host is kept before it is looked up again
+const LOCAL_IP_TTL: Duration = Duration::from_secs(60);
+
+/// Possible [LanDiscovery] errors.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// Socket error
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Sealing or opening an announcement failed
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+    /// WireGuard interface error
+    #[error(transparent)]
+    WireGuard(#[from] telio_wg::Error),
+    /// Announcement could not be decoded
+    #[error("Malformed announcement")]
+    MalformedAnnouncement,
+    /// Peer announced an IP which is not its meshnet IP
+    #[error("{0:?} announced a foreign meshnet IP {1}")]
+    ForeignMeshnetIp(PublicKey, IpAddr),
+    /// Announcement was sent too far off the local clock
+    #[error("Stale announcement of {0:?}")]
+    StaleAnnouncement(PublicKey),
+    /// Announcement arrived from another IP than the one it was sent from
+    #[error("Announcement of {0:?} from {1} arrived from {2}")]
+    ForeignSource(PublicKey, IpAddr, IpAddr),
+    /// Announcement arrived before
+    #[error("Replayed announcement of {0:?}")]
+    ReplayedAnnouncement(PublicKey),
+    /// Task encountered an error while running
+    #[error(transparent)]
+    Task(#[from] telio_task::ExecError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Peer announcing itself on the LAN
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LanPeerEvent {
+    pub public_key: PublicKey,
+    /// WireGuard endpoint of the peer on the LAN
+    pub remote_endpoint: SocketAddr,
+    /// Local WireGuard endpoint on the LAN, as reached by the peer
+    pub local_endpoint: SocketAddr,
+    /// Session announced by the peer, the upgrade requests of the peer come with it
+    pub remote_session: Session,
+    /// Session announced to the peer, for the upgrade requests sent to it
+    pub local_session: Session,
+}
+
+impl LanPeerEvent {
+    /// Endpoint of the peer on the LAN, taken as validated since `announced_at`
+    pub fn validated_endpoint(
+        &self,
+        announced_at: Instant,
+    ) -> WireGuardEndpointCandidateChangeEvent {
+        WireGuardEndpointCandidateChangeEvent {
+            public_key: self.public_key,
+            remote_endpoint: (self.remote_endpoint, EndpointProvider::Local),
+            local_endpoint: (self.local_endpoint, EndpointProvider::Local),
+            session: self.local_session,
+            changed_at: announced_at,
+        }
+    }
+}
+
+/// Contents of a sealed announcement
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Announcement {
+    session: Session,
+    /// When the announcement was sent, in seconds since the UNIX epoch
+    sent_at_s: u64,
+    /// Random, so that the announcement is told apart when it arrives again
+    nonce: u64,
+    wg_port: u16,
+    /// IP on the LAN the announcement is sent from
+    lan_ip: IpAddr,
+    meshnet_ip: IpAddr,
+}
+
+impl Announcement {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(60);
+        bytes.extend_from_slice(&self.session.to_be_bytes());
+        bytes.extend_from_slice(&self.sent_at_s.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.wg_port.to_be_bytes());
+        for ip in [self.lan_ip, self.meshnet_ip] {
+            match ip {
+                IpAddr::V4(ip) => {
+                    bytes.push(4);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    bytes.push(16);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+            }
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let session = bytes
+            .get(..8)?
+            .try_into()
+            .ok()
+            .map(Session::from_be_bytes)?;
+        let sent_at_s = bytes.get(8..16)?.try_into().ok().map(u64::from_be_bytes)?;
+        let nonce = bytes.get(16..24)?.try_into().ok().map(u64::from_be_bytes)?;
+        let wg_port = bytes.get(24..26)?.try_into().ok().map(u16::from_be_bytes)?;
+        let (lan_ip, rest) = decode_ip(bytes.get(26..)?)?;
+        let (meshnet_ip, rest) = decode_ip(rest)?;
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Self {
+            session,
+            sent_at_s,
+            nonce,
+            wg_port,
+            lan_ip,
+            meshnet_ip,
+        })
+    }
+}
+
+/// IP prefixed with its length, and the bytes after it
+fn decode_ip(bytes: &[u8]) -> Option<(IpAddr, &[u8])> {
+    let (&len, bytes) = bytes.split_first()?;
+    let (ip, rest) = bytes.split_at_checked(usize::from(len))?;
+    let ip = match len {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some((ip, rest))
+}
+
+/// Meshnet peer announced to
+struct Peer {
+    ip_addresses: Vec<IpAddr>,
+    /// Session announced to the peer
+    session: Session,
+}
+
+pub struct LanDiscovery<T: WireGuard = DynamicWg> {
+    task: Task<State<T>>,
+}
+
+struct State<T: WireGuard> {
+    socket: External<UdpSocket>,
+    /// Where the announcements are sent to, the multicast group
+    group: SocketAddr,
+    socket_pool: Arc<SocketPool>,
+    wireguard_interface: Arc<T>,
+    secret_key: SecretKey,
+    meshnet_ip: Option<IpAddr>,
+    peers: HashMap<PublicKey, Peer>,
+    lan_peer_publisher: chan::Tx<LanPeerEvent>,
+    announce_timer: Interval,
+    recv_buffer: Vec<u8>,
+    /// Nonces of the announcements received, with when they were sent, until they get stale
+    seen_nonces: HashMap<(PublicKey, u64), u64>,
+    /// Local IPs of the routes to the hosts, with when they were looked up
+    local_ips: HashMap<IpAddr, (IpAddr, Instant)>,
+}
+
+impl<T: WireGuard> LanDiscovery<T> {
+    /// Announce the node to the `group` over the `socket` every `announce_interval`, publishing
+    /// the peers announcing themselves to the `lan_peer_publisher`
+    pub fn start(
+        socket: External<UdpSocket>,
+        group: SocketAddr,
+        socket_pool: Arc<SocketPool>,
+        wireguard_interface: Arc<T>,
+        secret_key: SecretKey,
+        announce_interval: Duration,
+        lan_peer_publisher: chan::Tx<LanPeerEvent>,
+    ) -> Self {
+        telio_log_info!("Starting LAN discovery on {group}");
+        Self {
+            task: Task::start(State {
+                socket,
+                group,
+                socket_pool,
+                wireguard_interface,
+                secret_key,
+                meshnet_ip: None,
+                peers: HashMap::new(),
+                lan_peer_publisher,
+                announce_timer: interval(announce_interval),
+                recv_buffer: vec![0; MAX_DATAGRAM_LEN],
+                seen_nonces: HashMap::new(),
+                local_ips: HashMap::new(),
+            }),
+        }
+    }
+
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+
+    /// Announce the node to the peers of the meshnet `config`, and listen to their announcements
+    pub async fn configure(&self, config: Option<Config>) -> Result<()> {
+        task_exec!(&self.task, async move |s| {
+            s.configure(config);
+            Ok(())
+        })
+        .await
+        .map_err(Error::Task)
+    }
+}
+
+impl<T: WireGuard> State<T> {
+    fn configure(&mut self, config: Option<Config>) {
+        let Some(config) = config else {
+            self.meshnet_ip = None;
+            self.peers.clear();
+            return;
+        };
+
+        let ip_addresses = config.this.ip_addresses.unwrap_or_default();
+        self.meshnet_ip = ip_addresses
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| ip_addresses.first())
+            .copied();
+
+        let mut peers = HashMap::new();
+        for peer in config.peers.unwrap_or_default() {
+            let PeerBase {
+                public_key,
+                ip_addresses,
+                ..
+            } = peer.base;
+            // Sessions of the known peers are kept, so their pending upgrades stay valid
+            let session = self
+                .peers
+                .remove(&public_key)
+                .map_or_else(rand::random, |known| known.session);
+            peers.insert(
+                public_key,
+                Peer {
+                    ip_addresses: ip_addresses.unwrap_or_default(),
+                    session,
+                },
+            );
+        }
+        self.peers = peers;
+    }
+
+    async fn wg_port(&self) -> Result<Option<u16>> {
+        Ok(self.wireguard_interface.get_interface().await?.listen_port)
+    }
+
+    async fn announce(&mut self) -> Result<()> {
+        let (Some(meshnet_ip), Some(wg_port)) = (self.meshnet_ip, self.wg_port().await?) else {
+            return Ok(());
+        };
+        let lan_ip = self.local_ip_towards(self.group).await?;
+        let sent_at_s = unix_time_s();
+
+        for (public_key, peer) in &self.peers {
+            let announcement = Announcement {
+                session: peer.session,
+                sent_at_s,
+                nonce: rand::random(),
+                wg_port,
+                lan_ip,
+                meshnet_ip,
+            };
+            let sealed = encrypt_request(
+                &announcement.encode(),
+                &mut rand::thread_rng(),
+                &self.secret_key,
+                public_key,
+            )?;
+            let datagram = [MAGIC.as_slice(), &sealed].concat();
+            self.socket.send_to(&datagram, self.group).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_datagram(&mut self, len: usize, source: SocketAddr) -> Result<()> {
+        let Some(sealed) = self
+            .recv_buffer
+            .get(..len)
+            .and_then(|datagram| datagram.strip_prefix(MAGIC.as_slice()))
+        else {
+            return Ok(());
+        };
+        // Announcements sealed for the other nodes fail to open
+        let Ok((announcement, public_key)) =
+            decrypt_request(sealed, &self.secret_key, |public_key| {
+                self.peers.contains_key(public_key)
+            })
+        else {
+            return Ok(());
+        };
+        let announcement =
+            Announcement::decode(&announcement).ok_or(Error::MalformedAnnouncement)?;
+        let Some(peer) = self.peers.get(&public_key) else {
+            return Ok(());
+        };
+
+        if !peer.ip_addresses.contains(&announcement.meshnet_ip) {
+            return Err(Error::ForeignMeshnetIp(public_key, announcement.meshnet_ip));
+        }
+        let now_s = unix_time_s();
+        if now_s.abs_diff(announcement.sent_at_s) > MAX_ANNOUNCEMENT_AGE.as_secs() {
+            return Err(Error::StaleAnnouncement(public_key));
+        }
+        let source_ip = source.ip().to_canonical();
+        if source_ip != announcement.lan_ip.to_canonical() {
+            return Err(Error::ForeignSource(
+                public_key,
+                announcement.lan_ip,
+                source_ip,
+            ));
+        }
+        let (remote_session, local_session) = (announcement.session, peer.session);
+        self.seen_nonces
+            .retain(|_, sent_at_s| now_s.abs_diff(*sent_at_s) <= MAX_ANNOUNCEMENT_AGE.as_secs());
+        if self
+            .seen_nonces
+            .insert((public_key, announcement.nonce), announcement.sent_at_s)
+            .is_some()
+        {
+            return Err(Error::ReplayedAnnouncement(public_key));
+        }
+        let Some(wg_port) = self.wg_port().await? else {
+            return Ok(());
+        };
+
+        let event = LanPeerEvent {
+            public_key,
+            remote_endpoint: SocketAddr::new(source_ip, announcement.wg_port),
+            local_endpoint: SocketAddr::new(self.local_ip_towards(source).await?, wg_port),
+            remote_session,
+            local_session,
+        };
+        telio_log_debug!("Peer announced on the LAN: {event:?}");
+        // The peer announces itself again, so the event can be dropped when the channel is full
+        if let Err(e) = self.lan_peer_publisher.try_send(event) {
+            telio_log_debug!("Dropping LAN peer event: {e}");
+        }
+        Ok(())
+    }
+
+    /// Local IP of the route to the `host`, without sending anything to it
+    async fn local_ip_towards(&mut self, host: SocketAddr) -> Result<IpAddr> {
+        let now = Instant::now();
+        self.local_ips.retain(|_, (_, looked_up_at)| {
+            now.saturating_duration_since(*looked_up_at) < LOCAL_IP_TTL
+        });
+        if let Some((local_ip, _)) = self.local_ips.get(&host.ip()) {
+            return Ok(*local_ip);
+        }
+
+        let unspecified = match host {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = self
+            .socket_pool
+            .new_external_udp(SocketAddr::new(unspecified, 0), None)
+            .await?;
+        socket.connect(host).await?;
+        let local_ip = socket.local_addr()?.ip().to_canonical();
+        self.local_ips.insert(host.ip(), (local_ip, now));
+        Ok(local_ip)
+    }
+}
+
+fn unix_time_s() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[async_trait]
+impl<T: WireGuard> Runtime for State<T> {
+    const NAME: &'static str = "LanDiscovery";
+    type Err = ();
+
+    async fn wait_with_update<F>(&mut self, update: F) -> std::result::Result<(), Self::Err>
+    where
+        F: Future<Output = BoxAction<Self, std::result::Result<(), Self::Err>>> + Send,
+    {
+        tokio::select! {
+            Ok((len, source)) = self.socket.recv_from(&mut self.recv_buffer) => {
+                self.handle_datagram(len, source)
+                    .await
+                    .unwrap_or_else(|e| {
+                        telio_log_warn!("Failed to handle LAN announcement: {:?}", e);
+                    });
+            }
+            _ = self.announce_timer.tick() => {
+                self.announce()
+                    .await
+                    .unwrap_or_else(|e| {
+                        telio_log_warn!("Failed to announce on the LAN: {:?}", e);
+                    });
+            }
+            update = update => {
+                return update(self).await;
+            }
+            else => {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_model::config::Peer as ConfigPeer;
+    use telio_sockets::NativeProtector;
+    use telio_task::io::Chan;
+    use telio_wg::{uapi::Interface, MockWireGuard};
+    use tokio::time::timeout;
+
+    const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+
+    struct Node {
+        secret_key: SecretKey,
+        meshnet_ip: IpAddr,
+        wg_port: u16,
+    }
+
+    impl Node {
+        fn new(meshnet_ip: [u8; 4], wg_port: u16) -> Self {
+            Self {
+                secret_key: SecretKey::gen(),
+                meshnet_ip: IpAddr::from(meshnet_ip),
+                wg_port,
+            }
+        }
+
+        fn base(&self) -> PeerBase {
+            PeerBase {
+                public_key: self.secret_key.public(),
+                ip_addresses: Some(vec![self.meshnet_ip]),
+                ..Default::default()
+            }
+        }
+
+        fn config(&self, peers: &[PeerBase]) -> Config {
+            Config {
+                this: self.base(),
+                peers: Some(
+                    peers
+                        .iter()
+                        .map(|base| ConfigPeer {
+                            base: base.clone(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }
+        }
+    }
+
+    fn socket_pool() -> Arc<SocketPool> {
+        Arc::new(SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        ))
+    }
+
+    async fn localhost_socket(pool: &SocketPool) -> (External<UdpSocket>, SocketAddr) {
+        let socket = pool
+            .new_external_udp((Ipv4Addr::LOCALHOST, 0), None)
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn wireguard(wg_port: u16) -> Arc<MockWireGuard> {
+        let mut wg = MockWireGuard::new();
+        wg.expect_get_interface().returning(move || {
+            Ok(Interface {
+                listen_port: Some(wg_port),
+                ..Default::default()
+            })
+        });
+        Arc::new(wg)
+    }
+
+    /// Two discoveries sending their announcements straight to each other
+    async fn setup(
+        a: &Node,
+        b: &Node,
+    ) -> (
+        (LanDiscovery<MockWireGuard>, chan::Rx<LanPeerEvent>),
+        (LanDiscovery<MockWireGuard>, chan::Rx<LanPeerEvent>),
+    ) {
+        let pool = socket_pool();
+        let (socket_a, addr_a) = localhost_socket(&pool).await;
+        let (socket_b, addr_b) = localhost_socket(&pool).await;
+
+        let start = |node: &Node, socket, group| {
+            let events = Chan::default();
+            let discovery = LanDiscovery::start(
+                socket,
+                group,
+                pool.clone(),
+                wireguard(node.wg_port),
+                node.secret_key.clone(),
+                ANNOUNCE_INTERVAL,
+                events.tx,
+            );
+            (discovery, events.rx)
+        };
+        (start(a, socket_a, addr_b), start(b, socket_b, addr_a))
+    }
+
+    #[tokio::test]
+    async fn peers_discover_each_other() {
+        let a = Node::new([100, 64, 0, 1], 1111);
+        let b = Node::new([100, 64, 0, 2], 2222);
+        let ((discovery_a, mut events_a), (discovery_b, mut events_b)) = setup(&a, &b).await;
+
+        discovery_a
+            .configure(Some(a.config(&[b.base()])))
+            .await
+            .unwrap();
+        discovery_b
+            .configure(Some(b.config(&[a.base()])))
+            .await
+            .unwrap();
+
+        let a_seen_by_b = timeout(Duration::from_secs(5), events_b.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let b_seen_by_a = timeout(Duration::from_secs(5), events_a.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(a_seen_by_b.public_key, a.secret_key.public());
+        assert_eq!(
+            a_seen_by_b.remote_endpoint,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 1111))
+        );
+        assert_eq!(
+            a_seen_by_b.local_endpoint,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 2222))
+        );
+        assert_eq!(b_seen_by_a.public_key, b.secret_key.public());
+        assert_eq!(
+            b_seen_by_a.remote_endpoint,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 2222))
+        );
+
+        // Each side upgrades with its own session, which the other side was announced
+        assert_eq!(a_seen_by_b.remote_session, b_seen_by_a.local_session);
+        assert_eq!(b_seen_by_a.remote_session, a_seen_by_b.local_session);
+
+        discovery_a.stop().await;
+        discovery_b.stop().await;
+    }
+
+    #[tokio::test]
+    async fn strangers_and_foreign_ips_are_ignored() {
+        let a = Node::new([100, 64, 0, 1], 1111);
+        let b = Node::new([100, 64, 0, 2], 2222);
+        let ((discovery_a, _events_a), (discovery_b, mut events_b)) = setup(&a, &b).await;
+
+        // B does not know A
+        discovery_a
+            .configure(Some(a.config(&[b.base()])))
+            .await
+            .unwrap();
+        discovery_b.configure(Some(b.config(&[]))).await.unwrap();
+        assert!(timeout(ANNOUNCE_INTERVAL * 3, events_b.recv())
+            .await
+            .is_err());
+
+        // B knows A by another meshnet IP
+        let impostor = PeerBase {
+            ip_addresses: Some(vec![IpAddr::from([100, 64, 0, 3])]),
+            ..a.base()
+        };
+        discovery_b
+            .configure(Some(b.config(&[impostor])))
+            .await
+            .unwrap();
+        assert!(timeout(ANNOUNCE_INTERVAL * 3, events_b.recv())
+            .await
+            .is_err());
+
+        discovery_a.stop().await;
+        discovery_b.stop().await;
+    }
+
+    #[tokio::test]
+    async fn relayed_and_replayed_announcements_are_ignored() {
+        let a = Node::new([100, 64, 0, 1], 1111);
+        let b = Node::new([100, 64, 0, 2], 2222);
+        let pool = socket_pool();
+        let (socket_a, _) = localhost_socket(&pool).await;
+        let (socket_b, addr_b) = localhost_socket(&pool).await;
+        // A announces to the test instead of B
+        let capture = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let discovery_a = LanDiscovery::start(
+            socket_a,
+            capture.local_addr().unwrap(),
+            pool.clone(),
+            wireguard(a.wg_port),
+            a.secret_key.clone(),
+            ANNOUNCE_INTERVAL,
+            Chan::default().tx,
+        );
+        let events = Chan::default();
+        let mut events_b = events.rx;
+        let discovery_b = LanDiscovery::start(
+            socket_b,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 9)),
+            pool.clone(),
+            wireguard(b.wg_port),
+            b.secret_key.clone(),
+            Duration::from_secs(3600),
+            events.tx,
+        );
+        discovery_a
+            .configure(Some(a.config(&[b.base()])))
+            .await
+            .unwrap();
+        discovery_b
+            .configure(Some(b.config(&[a.base()])))
+            .await
+            .unwrap();
+
+        let mut announcement = vec![0; MAX_DATAGRAM_LEN];
+        let len = timeout(Duration::from_secs(5), capture.recv(&mut announcement))
+            .await
+            .unwrap()
+            .unwrap();
+        announcement.truncate(len);
+
+        // Relayed from another IP on the LAN
+        let relay = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, 2), 0))
+            .await
+            .unwrap();
+        relay.send_to(&announcement, addr_b).await.unwrap();
+        assert!(timeout(ANNOUNCE_INTERVAL * 3, events_b.recv())
+            .await
+            .is_err());
+
+        // Passed on from the IP it was sent from, then replayed
+        let relay = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        relay.send_to(&announcement, addr_b).await.unwrap();
+        let event = timeout(Duration::from_secs(5), events_b.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.public_key, a.secret_key.public());
+        relay.send_to(&announcement, addr_b).await.unwrap();
+        assert!(timeout(ANNOUNCE_INTERVAL * 3, events_b.recv())
+            .await
+            .is_err());
+
+        discovery_a.stop().await;
+        discovery_b.stop().await;
+    }
+
+    #[test]
+    fn announcements_are_decoded() {
+        for meshnet_ip in [
+            IpAddr::from([100, 64, 0, 1]),
+            IpAddr::from([0xfd74, 0x656c, 0x696f, 0, 0, 0, 0, 1]),
+        ] {
+            let announcement = Announcement {
+                session: 42,
+                sent_at_s: 1_700_000_000,
+                nonce: 7,
+                wg_port: 51820,
+                lan_ip: IpAddr::from([192, 168, 1, 10]),
+                meshnet_ip,
+            };
+            assert_eq!(
+                Announcement::decode(&announcement.encode()),
+                Some(announcement)
+            );
+        }
+        assert_eq!(Announcement::decode(&[0; 20]), None);
+        assert_eq!(Announcement::decode(&[0; 40]), None);
+    }
+}
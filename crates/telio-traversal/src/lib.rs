@@ -1,13 +1,20 @@
 #![deny(unsafe_code)]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+pub mod capability_exchange;
 pub mod connectivity_check;
+pub mod departure;
 pub mod endpoint_providers;
 pub mod endpoint_state;
 pub mod error;
+pub mod keepalive_tuner;
+pub mod lan_discovery;
 pub mod last_rx_time_provider;
+pub mod nat_binding_probe;
+pub mod nat_detection;
 pub mod ping_pong_handler;
 pub mod session_keeper;
+pub mod tcp_punch;
 pub mod upgrade_sync;
 
 pub use connectivity_check::*;
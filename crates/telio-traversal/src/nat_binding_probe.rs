@@ -0,0 +1,356 @@
+//! Active measurement of the NAT UDP binding lifetime.
+//!
+//! A fresh socket asks the STUN server of a relay for its reflexive address, stays silent for
+//! a while and asks again. When the reflexive address is still the same, the binding survived
+//! the silence. Silences are doubled until a binding expires and then bisected, until the
+//! lifetime is known well enough.
+//!
+//! NATs which preserve ports may hand out the same reflexive address for a new binding, which
+//! makes an expired binding look alive. The measurement is therefore only used to seed
+//! [KeepaliveTuner](crate::keepalive_tuner::KeepaliveTuner), which still backs off when
+//! direct peers stop getting through.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use stun_codec::TransactionId;
+use telio_sockets::{External, SocketPool};
+use telio_task::{task_exec, Runtime, RuntimeExt, Task, WaitResponse};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn, PinnedSleep};
+use tokio::net::UdpSocket;
+
+use crate::{endpoint_providers::stun::stun_msg, keepalive_tuner::NetworkId};
+
+/// Time to wait for a STUN response
+const STUN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Search stops once the lifetime is known with this precision
+const RESOLUTION_S: u32 = 5;
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// Search for the binding lifetime over the silences which were probed so far
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LifetimeSearch {
+    min_s: u32,
+    max_s: u32,
+    /// Longest silence the binding survived
+    alive_s: u32,
+    /// Shortest silence after which the binding was gone
+    expired_s: Option<u32>,
+}
+
+impl LifetimeSearch {
+    fn new(min_s: u32, max_s: u32) -> Self {
+        let min_s = min_s.max(1);
+        Self {
+            min_s,
+            max_s: max_s.max(min_s),
+            alive_s: 0,
+            expired_s: None,
+        }
+    }
+
+    /// Silence to probe next, `None` once the search is over
+    fn next(&self) -> Option<u32> {
+        match self.expired_s {
+            None if self.alive_s >= self.max_s => None,
+            None if self.alive_s == 0 => Some(self.min_s),
+            None => Some(self.alive_s.saturating_mul(2).min(self.max_s)),
+            // Even the shortest silence was too long, there is nothing to bisect
+            Some(_) if self.alive_s == 0 => None,
+            Some(expired_s) if expired_s.saturating_sub(self.alive_s) <= RESOLUTION_S => None,
+            Some(expired_s) => Some(self.alive_s + (expired_s - self.alive_s) / 2),
+        }
+    }
+
+    fn record(&mut self, silence_s: u32, survived: bool) {
+        if survived {
+            self.alive_s = self.alive_s.max(silence_s);
+        } else {
+            self.expired_s = Some(self.expired_s.map_or(silence_s, |s| s.min(silence_s)));
+        }
+    }
+
+    /// Longest silence known to be survived, zero when there was none
+    fn lifetime(&self) -> Option<u32> {
+        self.next().is_none().then_some(self.alive_s)
+    }
+}
+
+/// Background measurement of the binding lifetime of a single network
+pub struct NatBindingProbe {
+    task: Task<State>,
+}
+
+impl NatBindingProbe {
+    /// Start measuring the binding lifetime of `network`, using the STUN `server`.
+    ///
+    /// Silences between `min_s` and `max_s` seconds are probed.
+    pub fn start(
+        socket_pool: Arc<SocketPool>,
+        server: SocketAddr,
+        network: NetworkId,
+        min_s: u32,
+        max_s: u32,
+    ) -> Self {
+        telio_log_info!("Measuring NAT binding lifetime of {network:?} via {server}");
+        Self {
+            task: Task::start(State {
+                socket_pool,
+                server,
+                network,
+                search: LifetimeSearch::new(min_s, max_s),
+                phase: Phase::Start,
+                reported: false,
+                timeout: PinnedSleep::new(Duration::ZERO, ()),
+                buf: Box::new([0u8; MAX_PACKET_SIZE]),
+            }),
+        }
+    }
+
+    /// Measured lifetime in seconds, returned only once after the measurement is over
+    pub async fn take_binding_lifetime(&self) -> Option<(NetworkId, u32)> {
+        task_exec!(&self.task, async move |s| {
+            if s.reported {
+                return Ok(None);
+            }
+            s.reported = s.search.lifetime().is_some();
+            Ok(s.search.lifetime().map(|lifetime| (s.network, lifetime)))
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Stop the measurement
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+enum Phase {
+    /// Next probe has to be started
+    Start,
+    /// Waiting for the reflexive address of a fresh binding
+    Binding {
+        socket: External<UdpSocket>,
+        tid: TransactionId,
+        silence_s: u32,
+    },
+    /// Binding is left idle
+    Silent {
+        socket: External<UdpSocket>,
+        reflexive: SocketAddr,
+        silence_s: u32,
+    },
+    /// Waiting for the reflexive address after the silence
+    Checking {
+        socket: External<UdpSocket>,
+        tid: TransactionId,
+        reflexive: SocketAddr,
+        silence_s: u32,
+    },
+    /// Lifetime is known
+    Done,
+    /// STUN server did not respond
+    Failed,
+}
+
+struct State {
+    socket_pool: Arc<SocketPool>,
+    server: SocketAddr,
+    network: NetworkId,
+    search: LifetimeSearch,
+    phase: Phase,
+    /// Lifetime was already handed out
+    reported: bool,
+    /// Deadline of the current phase
+    timeout: PinnedSleep<()>,
+    buf: Box<[u8; MAX_PACKET_SIZE]>,
+}
+
+impl State {
+    async fn send_request(&self, socket: &UdpSocket) -> io::Result<TransactionId> {
+        let (tid, request) =
+            stun_msg::new_request().map_err(|e| io::Error::other(e.to_string()))?;
+        socket.send_to(&request, self.server).await?;
+        Ok(tid)
+    }
+
+    async fn start_probe(&mut self) -> io::Result<()> {
+        let Some(silence_s) = self.search.next() else {
+            telio_log_info!(
+                "NAT binding lifetime of {:?} is at least {}s",
+                self.network,
+                self.search.alive_s
+            );
+            self.phase = Phase::Done;
+            return Ok(());
+        };
+
+        let socket = self
+            .socket_pool
+            .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+            .await?;
+        let tid = self.send_request(&socket).await?;
+        self.timeout = PinnedSleep::new(STUN_TIMEOUT, ());
+        self.phase = Phase::Binding {
+            socket,
+            tid,
+            silence_s,
+        };
+        Ok(())
+    }
+
+    /// Advance the probe with a STUN response, `None` meaning the response timed out
+    async fn advance(&mut self, response: Option<(TransactionId, SocketAddr)>) -> io::Result<()> {
+        match (std::mem::replace(&mut self.phase, Phase::Start), response) {
+            (
+                Phase::Binding {
+                    socket,
+                    tid,
+                    silence_s,
+                },
+                Some((id, reflexive)),
+            ) if id == tid => {
+                telio_log_debug!("Staying silent for {silence_s}s, reflexive address {reflexive}");
+                self.timeout = PinnedSleep::new(Duration::from_secs(silence_s.into()), ());
+                self.phase = Phase::Silent {
+                    socket,
+                    reflexive,
+                    silence_s,
+                };
+            }
+            (
+                Phase::Silent {
+                    socket,
+                    reflexive,
+                    silence_s,
+                },
+                None,
+            ) => {
+                let tid = self.send_request(&socket).await?;
+                self.timeout = PinnedSleep::new(STUN_TIMEOUT, ());
+                self.phase = Phase::Checking {
+                    socket,
+                    tid,
+                    reflexive,
+                    silence_s,
+                };
+            }
+            (
+                Phase::Checking {
+                    tid,
+                    reflexive,
+                    silence_s,
+                    ..
+                },
+                Some((id, current)),
+            ) if id == tid => {
+                let survived = reflexive == current;
+                telio_log_debug!("NAT binding survived {silence_s}s of silence: {survived}");
+                self.search.record(silence_s, survived);
+                self.start_probe().await?;
+            }
+            (Phase::Binding { .. } | Phase::Checking { .. }, None) => {
+                telio_log_warn!("STUN server {} did not respond", self.server);
+                self.phase = Phase::Failed;
+            }
+            // Stale response, keep waiting
+            (phase, _) => self.phase = phase,
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "NatBindingProbe";
+
+    type Err = ();
+
+    async fn wait(&mut self) -> WaitResponse<'_, Self::Err> {
+        let res = match &self.phase {
+            Phase::Done | Phase::Failed => return Self::sleep_forever().await,
+            Phase::Start => self.start_probe().await,
+            Phase::Silent { .. } => {
+                (&mut self.timeout).await;
+                self.advance(None).await
+            }
+            Phase::Binding { socket, .. } | Phase::Checking { socket, .. } => {
+                let response = tokio::select! {
+                    res = socket.recv_from(self.buf.as_mut_slice()) => match res {
+                        Ok((n, from)) if from == self.server => {
+                            match self.buf.get(..n).map(stun_msg::decode_response) {
+                                Some(Ok((reflexive, tid))) => Some((tid, reflexive)),
+                                // Not a STUN response, keep waiting
+                                _ => return Self::next(),
+                            }
+                        }
+                        Ok(_) => return Self::next(),
+                        Err(e) => {
+                            telio_log_warn!("Failed to receive STUN response: {e}");
+                            return Self::next();
+                        }
+                    },
+                    _ = &mut self.timeout => None,
+                };
+                self.advance(response).await
+            }
+        };
+
+        if let Err(e) = res {
+            telio_log_warn!("NAT binding probe failed: {e}");
+            self.phase = Phase::Failed;
+        }
+        Self::next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_search(min_s: u32, max_s: u32, lifetime_s: u32) -> (Option<u32>, Vec<u32>) {
+        let mut search = LifetimeSearch::new(min_s, max_s);
+        let mut probes = Vec::new();
+        while let Some(silence_s) = search.next() {
+            probes.push(silence_s);
+            search.record(silence_s, silence_s < lifetime_s);
+        }
+        (search.lifetime(), probes)
+    }
+
+    #[test]
+    fn search_doubles_then_bisects() {
+        let (lifetime, probes) = run_search(5, 120, 30);
+        assert_eq!(probes, vec![5, 10, 20, 40, 30, 25]);
+        assert_eq!(lifetime, Some(25));
+    }
+
+    #[test]
+    fn search_stops_at_max() {
+        let (lifetime, probes) = run_search(5, 120, 600);
+        assert_eq!(probes, vec![5, 10, 20, 40, 80, 120]);
+        assert_eq!(lifetime, Some(120));
+    }
+
+    #[test]
+    fn search_gives_up_below_min() {
+        let (lifetime, probes) = run_search(5, 120, 3);
+        assert_eq!(probes, vec![5]);
+        assert_eq!(lifetime, Some(0));
+    }
+
+    #[test]
+    fn lifetime_is_unknown_during_search() {
+        let mut search = LifetimeSearch::new(5, 120);
+        search.record(5, true);
+        assert_eq!(search.lifetime(), None);
+    }
+}
@@ -0,0 +1,385 @@
+//! Active classification of the NAT the device is behind.
+//!
+//! Only the STUN servers of the relays are used:
+//! * mapping - reflexive addresses seen by two servers with different IPs are compared,
+//!   a difference means a symmetric NAT,
+//! * filtering - a server is asked to answer from a different IP and port, or just from a
+//!   different port (RFC 5780 CHANGE-REQUEST). Servers which ignore the request leave the
+//!   filtering unknown, such NATs are reported as port restricted cone, as it is the most
+//!   common case and does not promise more than the NAT may allow,
+//! * hairpinning - a second socket sends a request to the reflexive address of the first one.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use nat_detect::NatType;
+use rand::Rng;
+use stun_codec::TransactionId;
+use telio_sockets::SocketPool;
+use telio_utils::{telio_log_debug, telio_log_info};
+use tokio::{net::UdpSocket, time::sleep};
+
+use crate::endpoint_providers::stun::stun_msg;
+
+/// Time to wait for a single STUN response
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Number of times a STUN request is sent before giving up
+const ATTEMPTS: usize = 2;
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// STUN magic cookie
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// RFC 5780 CHANGE-REQUEST attribute and its flags
+const CHANGE_REQUEST: u16 = 0x0003;
+const CHANGE_IP: u32 = 0x4;
+const CHANGE_PORT: u32 = 0x2;
+
+/// Result of the NAT classification
+#[derive(Clone, Debug)]
+pub struct NatReport {
+    /// Type of the NAT
+    pub nat_type: NatType,
+    /// Reflexive address seen by the first STUN server
+    pub public_address: Option<SocketAddr>,
+    /// Whether packets sent to our own public address come back, `None` when not tested
+    pub hairpinning: Option<bool>,
+    /// Measured lifetime of an idle UDP binding, if it is already known
+    pub binding_lifetime_s: Option<u32>,
+}
+
+/// Filtering behaviour of the NAT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Filtering {
+    /// Any host can send to the binding
+    EndpointIndependent,
+    /// Only hosts we have sent to can send to the binding
+    AddressDependent,
+    /// Only host and port pairs we have sent to can send to the binding
+    AddressAndPortDependent,
+    /// Server does not support changing its response address
+    Unknown,
+}
+
+/// Classify the NAT using the given STUN `servers`.
+///
+/// Addresses of the local interfaces in `local_ips` tell whether there is any NAT at all.
+pub async fn detect_nat(
+    socket_pool: &SocketPool,
+    servers: &[SocketAddr],
+    local_ips: &[IpAddr],
+    binding_lifetime_s: Option<u32>,
+) -> io::Result<NatReport> {
+    let socket = socket_pool
+        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+        .await?;
+
+    let mut mappings = Vec::new();
+    for server in servers {
+        if mappings
+            .iter()
+            .any(|(s, _): &(SocketAddr, SocketAddr)| s.ip() == server.ip())
+        {
+            continue;
+        }
+        if let Some((mapped, _)) = binding(&socket, *server, None).await? {
+            mappings.push((*server, mapped));
+            if mappings.len() == 2 {
+                break;
+            }
+        }
+    }
+
+    let Some(&(server, public_address)) = mappings.first() else {
+        return Ok(NatReport {
+            nat_type: NatType::UdpBlocked,
+            public_address: None,
+            hairpinning: None,
+            binding_lifetime_s,
+        });
+    };
+    let second = mappings.get(1).map(|(_, mapped)| *mapped);
+    telio_log_debug!("Reflexive addresses: {public_address:?}, {second:?}");
+
+    let filtering = filtering(&socket, server).await?;
+    let nat_type = classify(
+        local_ips.contains(&public_address.ip()),
+        public_address,
+        second,
+        filtering,
+    );
+
+    let hairpinning = hairpinning(socket_pool, &socket, public_address).await?;
+
+    let report = NatReport {
+        nat_type,
+        public_address: Some(public_address),
+        hairpinning: Some(hairpinning),
+        binding_lifetime_s,
+    };
+    telio_log_info!("Detected NAT: {report:?}, filtering: {filtering:?}");
+    Ok(report)
+}
+
+fn classify(
+    open: bool,
+    first: SocketAddr,
+    second: Option<SocketAddr>,
+    filtering: Filtering,
+) -> NatType {
+    let symmetric_mapping = second.is_some_and(|second| second != first);
+    match (open, symmetric_mapping, filtering) {
+        (_, true, _) => NatType::Symmetric,
+        (true, false, Filtering::EndpointIndependent) => NatType::OpenInternet,
+        (true, false, _) => NatType::SymmetricUdpFirewall,
+        (false, false, Filtering::EndpointIndependent) => NatType::FullCone,
+        (false, false, Filtering::AddressDependent) => NatType::RestrictedCone,
+        (false, false, Filtering::AddressAndPortDependent | Filtering::Unknown) => {
+            NatType::PortRestrictedCone
+        }
+    }
+}
+
+async fn filtering(socket: &UdpSocket, server: SocketAddr) -> io::Result<Filtering> {
+    match binding(socket, server, Some(CHANGE_IP | CHANGE_PORT)).await? {
+        Some((_, from)) if from.ip() != server.ip() => return Ok(Filtering::EndpointIndependent),
+        Some(_) => return Ok(Filtering::Unknown),
+        None => (),
+    }
+    match binding(socket, server, Some(CHANGE_PORT)).await? {
+        Some((_, from)) if from != server => Ok(Filtering::AddressDependent),
+        Some(_) => Ok(Filtering::Unknown),
+        None => Ok(Filtering::AddressAndPortDependent),
+    }
+}
+
+async fn hairpinning(
+    socket_pool: &SocketPool,
+    socket: &UdpSocket,
+    public_address: SocketAddr,
+) -> io::Result<bool> {
+    let sender = socket_pool
+        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+        .await?;
+    let (tid, request) = stun_msg::new_request().map_err(io::Error::other)?;
+    sender.send_to(&request, public_address).await?;
+
+    let deadline = sleep(RESPONSE_TIMEOUT);
+    tokio::pin!(deadline);
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    loop {
+        tokio::select! {
+            _ = &mut deadline => return Ok(false),
+            received = socket.recv_from(&mut buf) => {
+                let (len, _) = received?;
+                if len >= 20 && buf.get(8..20) == Some(tid.as_bytes().as_slice()) {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+/// Run a binding transaction, returns the reflexive address and the source of the response
+async fn binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: Option<u32>,
+) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let (tid, request) = match change {
+        None => stun_msg::new_request().map_err(io::Error::other)?,
+        Some(flags) => change_request(flags),
+    };
+
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let deadline = sleep(RESPONSE_TIMEOUT);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                received = socket.recv_from(&mut buf) => {
+                    let (len, from) = received?;
+                    if let Some(Ok((mapped, response_tid))) =
+                        buf.get(..len).map(stun_msg::decode_response)
+                    {
+                        if response_tid == tid {
+                            return Ok(Some((mapped, from)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Binding request carrying only the CHANGE-REQUEST attribute
+fn change_request(flags: u32) -> (TransactionId, Vec<u8>) {
+    let tid = TransactionId::new(rand::thread_rng().gen::<[u8; 12]>());
+    let mut packet = Vec::with_capacity(28);
+    packet.extend_from_slice(&0x0001u16.to_be_bytes());
+    packet.extend_from_slice(&8u16.to_be_bytes());
+    packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    packet.extend_from_slice(tid.as_bytes());
+    packet.extend_from_slice(&CHANGE_REQUEST.to_be_bytes());
+    packet.extend_from_slice(&4u16.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    (tid, packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::EncodeExt;
+    use std::convert::TryInto;
+    use stun_codec::{
+        rfc5389::{attributes::XorMappedAddress, methods::BINDING, Attribute},
+        Message, MessageClass, MessageEncoder,
+    };
+    use telio_sockets::NativeProtector;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn classification() {
+        let first = addr("198.51.100.1:1000");
+        let other = addr("198.51.100.1:2000");
+        let cases = [
+            (
+                false,
+                Some(other),
+                Filtering::EndpointIndependent,
+                NatType::Symmetric,
+            ),
+            (
+                false,
+                Some(first),
+                Filtering::EndpointIndependent,
+                NatType::FullCone,
+            ),
+            (
+                false,
+                None,
+                Filtering::AddressDependent,
+                NatType::RestrictedCone,
+            ),
+            (
+                false,
+                Some(first),
+                Filtering::AddressAndPortDependent,
+                NatType::PortRestrictedCone,
+            ),
+            (
+                false,
+                Some(first),
+                Filtering::Unknown,
+                NatType::PortRestrictedCone,
+            ),
+            (
+                true,
+                Some(first),
+                Filtering::EndpointIndependent,
+                NatType::OpenInternet,
+            ),
+            (
+                true,
+                Some(first),
+                Filtering::AddressDependent,
+                NatType::SymmetricUdpFirewall,
+            ),
+        ];
+        for (open, second, filtering, expected) in cases {
+            let nat_type = classify(open, first, second, filtering);
+            assert_eq!(
+                format!("{nat_type:?}"),
+                format!("{expected:?}"),
+                "{open} {second:?} {filtering:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn change_request_layout() {
+        let (tid, packet) = change_request(CHANGE_IP | CHANGE_PORT);
+        assert_eq!(packet.get(..4).unwrap(), [0, 1, 0, 8]);
+        assert_eq!(packet.get(4..8).unwrap(), MAGIC_COOKIE.to_be_bytes());
+        assert_eq!(packet.get(8..20).unwrap(), tid.as_bytes());
+        assert_eq!(packet.get(20..).unwrap(), [0, 3, 0, 4, 0, 0, 0, 6]);
+    }
+
+    /// STUN server answering every request from its own socket
+    async fn stun_server(socket: UdpSocket) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            if len < 20 {
+                continue;
+            }
+            let tid = TransactionId::new(buf[8..20].try_into().unwrap());
+            let mut msg = Message::<Attribute>::new(MessageClass::SuccessResponse, BINDING, tid);
+            msg.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(from)));
+            let response = MessageEncoder::new().encode_into_bytes(msg).unwrap();
+            socket.send_to(&response, from).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback_without_nat_is_detected() {
+        let pool = SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        );
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(stun_server(server));
+
+        let report = detect_nat(
+            &pool,
+            &[server_addr],
+            &[Ipv4Addr::LOCALHOST.into()],
+            Some(30),
+        )
+        .await
+        .unwrap();
+
+        // The server ignores CHANGE-REQUEST, so the filtering stays unknown
+        assert!(matches!(report.nat_type, NatType::SymmetricUdpFirewall));
+        assert_eq!(
+            report.public_address.map(|a| a.ip()),
+            Some(Ipv4Addr::LOCALHOST.into())
+        );
+        assert_eq!(report.hairpinning, Some(true));
+        assert_eq!(report.binding_lifetime_s, Some(30));
+    }
+
+    #[tokio::test]
+    async fn unreachable_servers_mean_blocked_udp() {
+        let pool = SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        );
+        // Nobody reads from this socket
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let report = detect_nat(&pool, &[silent.local_addr().unwrap()], &[], None)
+            .await
+            .unwrap();
+        assert!(matches!(report.nat_type, NatType::UdpBlocked));
+        assert_eq!(report.public_address, None);
+        assert_eq!(report.hairpinning, None);
+    }
+}
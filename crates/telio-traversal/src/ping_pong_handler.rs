@@ -3,11 +3,12 @@ use rand::{rngs::StdRng, SeedableRng};
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use telio_crypto::{
     encryption::{decrypt_request, decrypt_response, encrypt_request, encrypt_response},
-    PublicKey, SecretKey,
+    KeyProvider, PublicKey, SecretKey,
 };
 use telio_model::features::EndpointProvider;
 use telio_proto::{
@@ -25,7 +26,7 @@ use crate::endpoint_providers::PongEvent;
 /// keys. When Ponger message is received it will validate against the set of live
 /// sessions.
 pub struct PingPongHandler {
-    secret_key: SecretKey,
+    key_provider: Arc<dyn KeyProvider>,
     known_keys: HashSet<PublicKey>,
     known_sessions: HashMap<Session, PublicKey>,
     rng: Mutex<StdRng>,
@@ -35,7 +36,7 @@ impl PingPongHandler {
     /// Create new instance with our secret key to be used during encryption.
     pub fn new(secret_key: SecretKey) -> Self {
         Self {
-            secret_key,
+            key_provider: Arc::new(secret_key),
             known_keys: Default::default(),
             known_sessions: Default::default(),
             rng: Mutex::new(StdRng::from_entropy()),
@@ -67,7 +68,7 @@ impl PingPongHandler {
         let mut rng = self.rng.lock().await;
 
         let encrypt_transform = |b: &[u8]| {
-            encrypt_request(b, &mut *rng, &self.secret_key, public_key)
+            encrypt_request(b, &mut *rng, &*self.key_provider, public_key)
                 .map_err(|e| CodecError::EncryptionFailed(e.to_string()))
         };
 
@@ -92,7 +93,7 @@ impl PingPongHandler {
         let decrypt_transform = |packet_type, buf: &[u8]| {
             match packet_type {
                 PacketTypeRelayed::Pinger => {
-                    decrypt_request(buf, &self.secret_key, |k| self.known_keys.contains(k))
+                    decrypt_request(buf, &*self.key_provider, |k| self.known_keys.contains(k))
                         .map_err(|e| CodecError::DecryptionFailed(e.to_string()))
                         .map(|(buf, pk)| (buf, Some(pk)))
                 }
@@ -115,7 +116,7 @@ impl PingPongHandler {
                     .ok_or(Error::FailedToBuildPongPacket)?;
                 let mut rng = self.rng.lock().await;
                 let encrypt_transform = |b: &[u8]| {
-                    encrypt_response(b, &mut *rng, &self.secret_key, &remote_pk)
+                    encrypt_response(b, &mut *rng, &*self.key_provider, &remote_pk)
                         .map_err(|e| CodecError::EncryptionFailed(e.to_string()))
                 };
                 let buf = pong.encode_and_encrypt(encrypt_transform)?;
@@ -125,7 +126,7 @@ impl PingPongHandler {
                 if let Some(pong_publisher) = pong_publisher.as_ref() {
                     if let Some(remote_pk) = self.known_sessions.get(&packet.get_session()) {
                         let decrypt_transform = |b: &[u8]| {
-                            decrypt_response(b, &self.secret_key, remote_pk)
+                            decrypt_response(b, &*self.key_provider, remote_pk)
                                 .map_err(|e| CodecError::DecryptionFailed(e.to_string()))
                         };
                         let msg = packet.decrypt(decrypt_transform)?;
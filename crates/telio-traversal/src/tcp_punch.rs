@@ -0,0 +1,489 @@
+//! TCP hole punching, the last attempt at a direct connection before staying on the relay.
+//!
+//! Symmetric NATs assign a new mapping to every destination, so UDP hole punching does not get
+//! through when both peers are behind one. Many of them keep the source port of TCP connections
+//! though. Once the UDP pings time out, the peers exchange the addresses of their [TcpPuncher]
+//! port in CallMeMaybe messages and connect to each other at the same time (TCP simultaneous
+//! open). WireGuard packets are then carried over the connection by a [TcpPunchHop], each one
+//! prefixed with its length.
+//!
+//! Every hop owns a loopback socket, which is used as the endpoint of the peer. The hops exchange
+//! these loopback addresses when the connection opens, so each side can tell the other one which
+//! endpoint to configure in the upgrade request.
+
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use futures::future::select_ok;
+use std::{
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use telio_crypto::PublicKey;
+use telio_sockets::{External, SocketPool};
+use telio_task::{Runtime, RuntimeExt, Task, WaitResponse};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_trace, telio_log_warn};
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpSocket, TcpStream, UdpSocket},
+    time::{sleep, timeout},
+};
+
+/// Time to open the connection, the peer is trying to do the same in parallel.
+///
+/// Together with [HELLO_TIMEOUT] it has to fit into the time the UDP pings are given, so that
+/// the result arrives while the connectivity check session is still pinging.
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Time to exchange the hello frames, once the connection is open
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Delay between connection attempts, rejected ones included
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+const MAX_PACKET_SIZE: usize = u16::MAX as usize;
+
+/// Public key of the sender, followed by the port of its hop
+const HELLO_SIZE: usize = 32 + 2;
+
+type Stream = External<TcpStream>;
+
+/// Opens TCP connections to peers from a single local port
+pub struct TcpPuncher {
+    socket_pool: Arc<SocketPool>,
+    public_key: PublicKey,
+    port: u16,
+    /// Keeps the port reserved between the attempts
+    _reserved: External<TcpSocket>,
+}
+
+impl TcpPuncher {
+    /// Reserve a local port for the connections of the node with `public_key`
+    pub fn new(socket_pool: Arc<SocketPool>, public_key: PublicKey) -> io::Result<Self> {
+        let reserved = bind(&socket_pool, 0)?;
+        let port = reserved.local_addr()?.port();
+        telio_log_info!("TCP hole punching from port {port}");
+        Ok(Self {
+            socket_pool,
+            public_key,
+            port,
+            _reserved: reserved,
+        })
+    }
+
+    /// Local port of all connections
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Addresses to announce to the peers, given the IPs the node is reachable at.
+    ///
+    /// Only IPv4 is supported and the NAT is expected to keep the port.
+    pub fn candidates(&self, ips: impl IntoIterator<Item = IpAddr>) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = ips
+            .into_iter()
+            .filter(|ip| ip.is_ipv4())
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Connect to the node with `public_key` at any of the `remotes`, while it connects to us
+    pub async fn punch(
+        &self,
+        public_key: PublicKey,
+        remotes: Vec<SocketAddr>,
+    ) -> io::Result<TcpPunchHop> {
+        let attempts: Vec<_> = remotes
+            .into_iter()
+            .filter(|remote| remote.is_ipv4())
+            .map(|remote| Box::pin(self.simultaneous_open(remote)))
+            .collect();
+        if attempts.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no IPv4 address to punch",
+            ));
+        }
+
+        let (stream, _) = timeout(PUNCH_TIMEOUT, select_ok(attempts)).await??;
+        stream.set_nodelay(true)?;
+        TcpPunchHop::start(stream, self.public_key, public_key).await
+    }
+
+    /// Keep connecting to `remote` until the connection opens
+    async fn simultaneous_open(&self, remote: SocketAddr) -> io::Result<Stream> {
+        loop {
+            let socket = bind(&self.socket_pool, self.port)?;
+            match socket.connect(remote).await {
+                Ok(stream) => {
+                    telio_log_debug!("TCP hole punched to {remote}");
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    telio_log_trace!("TCP connection to {remote} failed: {e}");
+                    sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+/// External socket bound to `port`, which can be shared by several sockets at once
+fn bind(socket_pool: &SocketPool, port: u16) -> io::Result<External<TcpSocket>> {
+    let socket = socket_pool.new_external_tcp_v4(None)?;
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?;
+    Ok(socket)
+}
+
+/// Relay between the WireGuard socket and a peer reachable over a punched TCP connection
+pub struct TcpPunchHop {
+    task: Task<State>,
+    local_addr: SocketAddr,
+    peer_hop_addr: SocketAddr,
+    closed: Arc<AtomicBool>,
+}
+
+struct State {
+    /// Loopback socket, used as the endpoint of the peer
+    local: UdpSocket,
+    reader: ReadHalf<Stream>,
+    writer: WriteHalf<Stream>,
+    /// Address of the WireGuard socket, learned from the first outgoing packet
+    wg_addr: Option<SocketAddr>,
+    /// Connection was closed, nothing gets through anymore
+    closed: Arc<AtomicBool>,
+    local_buf: Box<[u8; MAX_PACKET_SIZE]>,
+    /// Bytes received from the connection, which do not make up a whole frame yet
+    stream_buf: BytesMut,
+}
+
+impl TcpPunchHop {
+    /// Exchange the hello frames with the peer and start relaying
+    async fn start(
+        stream: Stream,
+        own_public_key: PublicKey,
+        peer_public_key: PublicKey,
+    ) -> io::Result<Self> {
+        let local = SocketPool::new_udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None).await?;
+        let local_addr = local.local_addr()?;
+        let (mut reader, mut writer) = split(stream);
+
+        let mut hello = Vec::with_capacity(HELLO_SIZE);
+        hello.extend_from_slice(&own_public_key.0);
+        hello.extend_from_slice(&local_addr.port().to_be_bytes());
+        writer.write_all(&encode(&hello)).await?;
+        writer.flush().await?;
+
+        let mut stream_buf = BytesMut::with_capacity(MAX_PACKET_SIZE);
+        let hello = timeout(HELLO_TIMEOUT, async {
+            loop {
+                if let Some(frame) = decode(&mut stream_buf) {
+                    return Ok(frame);
+                }
+                if reader.read_buf(&mut stream_buf).await? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+        })
+        .await??;
+        let peer_hop_port = parse_hello(&hello, &peer_public_key)?;
+        let peer_hop_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, peer_hop_port));
+
+        telio_log_info!(
+            "Starting TCP punch hop to {peer_public_key:?} via {local_addr}, peer hop at {peer_hop_addr}"
+        );
+
+        let closed = Arc::new(AtomicBool::new(false));
+        Ok(Self {
+            task: Task::start(State {
+                local,
+                reader,
+                writer,
+                wg_addr: None,
+                closed: closed.clone(),
+                local_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+                stream_buf,
+            }),
+            local_addr,
+            peer_hop_addr,
+            closed,
+        })
+    }
+
+    /// Loopback address which should be used as the endpoint of the peer
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Loopback address of the hop on the peer's side, which the peer should use as our endpoint
+    pub fn peer_hop_addr(&self) -> SocketAddr {
+        self.peer_hop_addr
+    }
+
+    /// Connection was closed by the peer or failed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Stop the relay, closing the connection
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+/// Port of the peer's hop, if the hello comes from the expected peer
+fn parse_hello(hello: &[u8], peer_public_key: &PublicKey) -> io::Result<u16> {
+    let (Some(public_key), Some(port)) = (hello.get(..32), hello.get(32..HELLO_SIZE)) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "TCP punch hello too short",
+        ));
+    };
+    if public_key != peer_public_key.0.as_slice() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "TCP punch hello from unexpected peer",
+        ));
+    }
+    Ok(u16::from_be_bytes([
+        port.first().copied().unwrap_or_default(),
+        port.get(1).copied().unwrap_or_default(),
+    ]))
+}
+
+/// Prefix an outgoing packet with its length
+fn encode(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(2 + packet.len());
+    frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    frame.extend_from_slice(packet);
+    frame
+}
+
+/// Take the next whole frame out of `buf`, `None` when more bytes are needed
+fn decode(buf: &mut BytesMut) -> Option<BytesMut> {
+    let len = buf
+        .get(..2)
+        .and_then(|len| len.try_into().ok())
+        .map(u16::from_be_bytes)?;
+    if buf.len() < 2 + len as usize {
+        return None;
+    }
+    buf.advance(2);
+    Some(buf.split_to(len as usize))
+}
+
+impl State {
+    /// Hand the whole frames received so far over to WireGuard
+    async fn forward_frames(&mut self) {
+        while let Some(packet) = decode(&mut self.stream_buf) {
+            match self.wg_addr {
+                Some(wg_addr) => {
+                    if let Err(e) = self.local.send_to(&packet, wg_addr).await {
+                        telio_log_warn!("Failed to send to WireGuard: {e}");
+                    }
+                }
+                None => {
+                    telio_log_debug!("Dropping TCP punch hop packet, WireGuard address unknown");
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "TcpPunchHop";
+
+    type Err = ();
+
+    async fn wait(&mut self) -> WaitResponse<'_, Self::Err> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Self::sleep_forever().await;
+        }
+
+        tokio::select! {
+            // Outgoing packets from WireGuard towards the peer
+            res = self.local.recv_from(self.local_buf.as_mut_slice()) => {
+                match res {
+                    Ok((n, from)) => {
+                        if self.wg_addr != Some(from) {
+                            telio_log_debug!("TCP punch hop WireGuard address: {from}");
+                            self.wg_addr = Some(from);
+                        }
+                        if let Some(packet) = self.local_buf.get(..n) {
+                            let res = async {
+                                self.writer.write_all(&encode(packet)).await?;
+                                self.writer.flush().await
+                            };
+                            if let Err(e) = res.await {
+                                telio_log_warn!("Failed to send to the TCP punch hop: {e}");
+                            }
+                        }
+                    }
+                    Err(e) => telio_log_warn!("Failed to receive from WireGuard: {e}"),
+                }
+            }
+            // Incoming packets from the peer back to WireGuard
+            res = self.reader.read_buf(&mut self.stream_buf) => {
+                match res {
+                    Ok(0) => {
+                        telio_log_info!("TCP punch hop closed by the peer");
+                        self.closed.store(true, Ordering::Relaxed);
+                    }
+                    Ok(_) => self.forward_frames().await,
+                    Err(e) => {
+                        telio_log_warn!("TCP punch hop closed: {e}");
+                        self.closed.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        Self::next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_crypto::SecretKey;
+    use telio_sockets::NativeProtector;
+    use tokio::net::TcpListener;
+
+    fn socket_pool() -> Arc<SocketPool> {
+        Arc::new(SocketPool::new(
+            NativeProtector::new(
+                #[cfg(target_os = "macos")]
+                false,
+            )
+            .unwrap(),
+        ))
+    }
+
+    #[test]
+    fn frames_roundtrip() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&encode(&[1, 2, 3]));
+        let last = encode(&[4, 5]);
+        buf.extend_from_slice(&last[..3]);
+
+        assert_eq!(decode(&mut buf), Some(BytesMut::from(&[1, 2, 3][..])));
+        assert_eq!(decode(&mut buf), None);
+        buf.extend_from_slice(&last[3..]);
+        assert_eq!(decode(&mut buf), Some(BytesMut::from(&[4, 5][..])));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn hello_from_unexpected_peer_is_rejected() {
+        let expected = SecretKey::gen().public();
+        let mut hello = expected.0.to_vec();
+        hello.extend_from_slice(&51820u16.to_be_bytes());
+        assert_eq!(parse_hello(&hello, &expected).unwrap(), 51820);
+
+        let other = SecretKey::gen().public();
+        assert!(parse_hello(&hello, &other).is_err());
+        assert!(parse_hello(&hello[..33], &expected).is_err());
+    }
+
+    #[tokio::test]
+    async fn candidates_use_the_reserved_port() {
+        let puncher = TcpPuncher::new(socket_pool(), SecretKey::gen().public()).unwrap();
+        let port = puncher.port();
+
+        assert_eq!(
+            puncher.candidates([
+                IpAddr::from([203, 0, 113, 5]),
+                IpAddr::from([192, 168, 0, 2]),
+                IpAddr::from([203, 0, 113, 5]),
+                "2001:db8::1".parse().unwrap(),
+            ]),
+            vec![
+                SocketAddr::from(([192, 168, 0, 2], port)),
+                SocketAddr::from(([203, 0, 113, 5], port)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn punched_connection_relays_wireguard_packets() {
+        let (alpha_key, beta_key) = (SecretKey::gen().public(), SecretKey::gen().public());
+        let alpha = TcpPuncher::new(socket_pool(), alpha_key).unwrap();
+
+        // The peer is a plain listener, which shows up only after the first attempt is rejected
+        let beta_addr = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let beta = tokio::spawn(async move {
+            sleep(RETRY_INTERVAL / 2).await;
+            let listener = TcpListener::bind(beta_addr).await.unwrap();
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut hello = beta_key.0.to_vec();
+            hello.extend_from_slice(&4242u16.to_be_bytes());
+            stream.write_all(&encode(&hello)).await.unwrap();
+
+            let mut buf = BytesMut::new();
+            while buf.len() < 2 + HELLO_SIZE {
+                stream.read_buf(&mut buf).await.unwrap();
+            }
+            let hello = decode(&mut buf).unwrap();
+            (stream, hello)
+        });
+
+        let alpha_hop = alpha.punch(beta_key, vec![beta_addr]).await.unwrap();
+        let (mut beta_stream, alpha_hello) = beta.await.unwrap();
+        assert_eq!(
+            parse_hello(&alpha_hello, &alpha_key).unwrap(),
+            alpha_hop.local_addr().port()
+        );
+        assert_eq!(
+            alpha_hop.peer_hop_addr(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 4242))
+        );
+
+        // Packets from WireGuard are framed onto the connection
+        let alpha_wg = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        alpha_wg
+            .send_to(b"handshake", alpha_hop.local_addr())
+            .await
+            .unwrap();
+        let mut buf = BytesMut::new();
+        let packet = loop {
+            if let Some(packet) = decode(&mut buf) {
+                break packet;
+            }
+            beta_stream.read_buf(&mut buf).await.unwrap();
+        };
+        assert_eq!(&packet[..], b"handshake");
+
+        // And frames from the connection are handed over to WireGuard
+        beta_stream.write_all(&encode(b"response")).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, from) = alpha_wg.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"response");
+        assert_eq!(from, alpha_hop.local_addr());
+
+        drop(beta_stream);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !alpha_hop.is_closed() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        alpha_hop.stop().await;
+    }
+}
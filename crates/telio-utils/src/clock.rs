@@ -0,0 +1,161 @@
+//! Detection of system suspends and wall clock jumps.
+//!
+//! [Instant](crate::Instant) keeps counting while the system is suspended, but the plain
+//! monotonic clock behind runtime timers does not on most platforms, and the wall clock can be
+//! changed at any time. [ClockMonitor] compares those clocks between checks, so that state which
+//! went stale during a long suspend can be refreshed right after the wakeup.
+
+use std::time::{Duration, SystemTime};
+
+/// Clocks drifting apart by less than this between two checks is scheduling noise
+pub const CLOCK_TOLERANCE: Duration = Duration::from_secs(10);
+
+/// Discontinuity of time noticed by [ClockMonitor]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockEvent {
+    /// System was suspended for about the given time
+    Suspended(Duration),
+    /// Wall clock was moved forward by about the given time
+    WallClockForward(Duration),
+    /// Wall clock was moved backward by about the given time
+    WallClockBackward(Duration),
+}
+
+/// Readings of all the clocks at a single point in time
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    /// Counts time spent in suspend
+    boot: boot_time::Instant,
+    /// Stops during suspend
+    monotonic: std::time::Instant,
+    /// Can be changed by the user or time synchronization
+    wall: SystemTime,
+}
+
+impl Sample {
+    fn now() -> Self {
+        #[allow(instant)]
+        Self {
+            boot: boot_time::Instant::now(),
+            monotonic: std::time::Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+}
+
+/// Periodic comparison of the clocks
+#[derive(Debug)]
+pub struct ClockMonitor {
+    last: Sample,
+}
+
+impl Default for ClockMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockMonitor {
+    /// Start monitoring from now
+    pub fn new() -> Self {
+        Self {
+            last: Sample::now(),
+        }
+    }
+
+    /// Discontinuities since the previous check, meant to be called periodically
+    pub fn check(&mut self) -> Vec<ClockEvent> {
+        let now = Sample::now();
+        let last = std::mem::replace(&mut self.last, now);
+
+        let boot = now.boot.saturating_duration_since(last.boot);
+        let monotonic = now.monotonic.saturating_duration_since(last.monotonic);
+        let wall = now.wall.duration_since(last.wall).map_err(|e| e.duration());
+        compare(boot, monotonic, wall)
+    }
+}
+
+/// Compare time elapsed on each of the clocks, `wall` being an error when it went backward
+fn compare(
+    boot: Duration,
+    monotonic: Duration,
+    wall: Result<Duration, Duration>,
+) -> Vec<ClockEvent> {
+    let mut events = Vec::new();
+
+    let suspended = boot.saturating_sub(monotonic);
+    if suspended > CLOCK_TOLERANCE {
+        events.push(ClockEvent::Suspended(suspended));
+    }
+
+    // The wall clock keeps going during suspend, so it is compared with the boot clock
+    match wall {
+        Ok(wall) if wall > boot + CLOCK_TOLERANCE => {
+            events.push(ClockEvent::WallClockForward(wall - boot))
+        }
+        Ok(wall) if wall + CLOCK_TOLERANCE < boot => {
+            events.push(ClockEvent::WallClockBackward(boot - wall))
+        }
+        Ok(_) => (),
+        Err(backward) if backward + boot > CLOCK_TOLERANCE => {
+            events.push(ClockEvent::WallClockBackward(backward + boot))
+        }
+        Err(_) => (),
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn steady_clocks() {
+        assert_eq!(compare(SEC, SEC, Ok(SEC)), vec![]);
+        assert_eq!(
+            compare(5 * SEC, SEC, Ok(6 * SEC)),
+            vec![],
+            "within tolerance"
+        );
+        assert_eq!(ClockMonitor::new().check(), vec![]);
+    }
+
+    #[test]
+    fn suspend_is_detected() {
+        let hours = 3 * 3600 * SEC;
+        assert_eq!(
+            compare(hours + SEC, SEC, Ok(hours + SEC)),
+            vec![ClockEvent::Suspended(hours)]
+        );
+    }
+
+    #[test]
+    fn wall_clock_jumps_are_detected() {
+        assert_eq!(
+            compare(SEC, SEC, Ok(3601 * SEC)),
+            vec![ClockEvent::WallClockForward(3600 * SEC)]
+        );
+        assert_eq!(
+            compare(60 * SEC, 60 * SEC, Ok(SEC)),
+            vec![ClockEvent::WallClockBackward(59 * SEC)]
+        );
+        assert_eq!(
+            compare(SEC, SEC, Err(3600 * SEC)),
+            vec![ClockEvent::WallClockBackward(3601 * SEC)]
+        );
+    }
+
+    #[test]
+    fn suspend_with_wall_clock_jump() {
+        assert_eq!(
+            compare(600 * SEC, SEC, Err(60 * SEC)),
+            vec![
+                ClockEvent::Suspended(599 * SEC),
+                ClockEvent::WallClockBackward(660 * SEC)
+            ]
+        );
+    }
+}
@@ -69,6 +69,18 @@ pub use bytes_and_timestamps::*;
 pub mod instant;
 pub use instant::*;
 
+/// Detection of system suspends and wall clock jumps
+pub mod clock;
+pub use clock::*;
+
+/// CPU time and wakeups consumed by the process
+pub mod process_usage;
+pub use process_usage::*;
+
+/// Pooled packet buffers, passed between the components without copying
+pub mod packet_pool;
+pub use packet_pool::*;
+
 #[cfg(target_os = "linux")]
 /// Default FWMARK value for libtelio on linux
 pub const LIBTELIO_FWMARK: u32 = 11673110;
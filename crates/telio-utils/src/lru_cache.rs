@@ -270,6 +270,12 @@ impl<Key: Clone + Eq + Hash, Value> LruCache<Key, Value> {
         self.map.remove(key).map(|v| v.data)
     }
 
+    /// Removes all key-value pairs from the cache.
+    #[inline(always)]
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
     /// Returns a reference to the value with the given `key`, if present and not expired, without
     /// updating the timestamp.
     pub fn peek<Q>(&self, key: &Q) -> Option<&Value>
@@ -0,0 +1,299 @@
+//! Packet buffers reused instead of allocated for every packet.
+//!
+//! A packet is received straight into a [PooledPacket] taken from a [PacketPool], which is then
+//! passed by value through the channels between the components, so the packet is never copied
+//! on the way. When the last component is done with it, the buffer goes back to its pool.
+//!
+//! The pools carry the packets of starcast and of the DNS interception, which receive and queue
+//! them on their own. The firewall takes none: it is called by the WG adapter with borrowed slices
+//! of the adapter's buffers and verdicts the packets in place, so the packets it filters are not
+//! copied on that path either.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+static REGISTRY: Mutex<Vec<Weak<PoolInner>>> = Mutex::new(Vec::new());
+
+struct PoolInner {
+    name: &'static str,
+    buffer_size: usize,
+    max_free: usize,
+    free: Mutex<Vec<Box<[u8]>>>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    in_use: AtomicU64,
+}
+
+/// Pool of equally sized packet buffers, clones share the buffers
+#[derive(Clone)]
+pub struct PacketPool {
+    inner: Arc<PoolInner>,
+}
+
+/// Counters of a [PacketPool], for tuning its size
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PacketPoolStats {
+    /// Name of the pool
+    pub name: String,
+    /// Size of the buffers of the pool
+    pub buffer_size: usize,
+    /// Buffers allocated since the pool was created
+    pub allocated: u64,
+    /// Buffers taken from the pool instead of allocated
+    pub reused: u64,
+    /// Buffers currently taken
+    pub in_use: u64,
+    /// Buffers waiting in the pool to be taken
+    pub free: u64,
+}
+
+impl PacketPool {
+    /// Create the pool `name` of `buffer_size` long buffers, keeping at most `max_free` of the
+    /// given back buffers for reuse
+    pub fn new(name: &'static str, buffer_size: usize, max_free: usize) -> Self {
+        let inner = Arc::new(PoolInner {
+            name,
+            buffer_size,
+            max_free,
+            free: Mutex::new(Vec::new()),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            in_use: AtomicU64::new(0),
+        });
+
+        let mut registry = REGISTRY.lock();
+        registry.retain(|pool| pool.strong_count() > 0);
+        registry.push(Arc::downgrade(&inner));
+
+        Self { inner }
+    }
+
+    /// Take a buffer of the full `buffer_size` length, its contents are unspecified
+    pub fn take(&self) -> PooledPacket {
+        let reused = self.inner.free.lock().pop();
+        let buf = match reused {
+            Some(buf) => {
+                self.inner.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.inner.allocated.fetch_add(1, Ordering::Relaxed);
+                vec![0u8; self.inner.buffer_size].into_boxed_slice()
+            }
+        };
+        self.inner.in_use.fetch_add(1, Ordering::Relaxed);
+
+        PooledPacket {
+            len: buf.len(),
+            buf,
+            pool: Some(self.inner.clone()),
+        }
+    }
+
+    /// Counters of the pool
+    pub fn stats(&self) -> PacketPoolStats {
+        self.inner.stats()
+    }
+}
+
+impl PoolInner {
+    fn stats(&self) -> PacketPoolStats {
+        PacketPoolStats {
+            name: self.name.to_owned(),
+            buffer_size: self.buffer_size,
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            free: self.free.lock().len() as u64,
+        }
+    }
+
+    fn give_back(&self, buf: Box<[u8]>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        let mut free = self.free.lock();
+        if free.len() < self.max_free {
+            free.push(buf);
+        }
+    }
+}
+
+impl fmt::Debug for PacketPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketPool")
+            .field("name", &self.inner.name)
+            .field("buffer_size", &self.inner.buffer_size)
+            .finish()
+    }
+}
+
+/// Counters of every pool of the process
+pub fn packet_pool_stats() -> Vec<PacketPoolStats> {
+    REGISTRY
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|pool| pool.stats())
+        .collect()
+}
+
+/// Packet buffer taken from a [PacketPool], given back to it when dropped.
+///
+/// Dereferences to the packet, i.e. to the first [PooledPacket::len] bytes of the buffer.
+pub struct PooledPacket {
+    buf: Box<[u8]>,
+    len: usize,
+    pool: Option<Arc<PoolInner>>,
+}
+
+impl PooledPacket {
+    /// Shorten the packet to `len` bytes, e.g. to the number of bytes received into it. Has no
+    /// effect if `len` is greater than the current length.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Make the whole buffer the packet again, e.g. before receiving into it once more
+    pub fn reset(&mut self) {
+        self.len = self.buf.len();
+    }
+
+    /// Size of the underlying buffer
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+impl Deref for PooledPacket {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.get(..self.len).unwrap_or_default()
+    }
+}
+
+impl DerefMut for PooledPacket {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.get_mut(..self.len).unwrap_or_default()
+    }
+}
+
+impl AsRef<[u8]> for PooledPacket {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for PooledPacket {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+/// Packet not belonging to any pool, e.g. one built by hand
+impl From<Vec<u8>> for PooledPacket {
+    fn from(packet: Vec<u8>) -> Self {
+        let buf = packet.into_boxed_slice();
+        Self {
+            len: buf.len(),
+            buf,
+            pool: None,
+        }
+    }
+}
+
+impl PartialEq for PooledPacket {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for PooledPacket {}
+
+impl fmt::Debug for PooledPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for PooledPacket {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = PacketPool::new("test-reuse", 16, 1);
+
+        let mut first = pool.take();
+        assert_eq!(first.len(), 16);
+        first.truncate(4);
+        first.copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(&*first, &[1, 2, 3, 4]);
+
+        let second = pool.take();
+        assert_eq!(
+            pool.stats(),
+            PacketPoolStats {
+                name: "test-reuse".to_owned(),
+                buffer_size: 16,
+                allocated: 2,
+                reused: 0,
+                in_use: 2,
+                free: 0,
+            }
+        );
+
+        // Only one buffer is kept for reuse
+        drop(first);
+        drop(second);
+        assert_eq!(pool.stats().in_use, 0);
+        assert_eq!(pool.stats().free, 1);
+
+        // A reused buffer is of the full length again
+        let third = pool.take();
+        assert_eq!(third.len(), 16);
+        assert_eq!(pool.stats().allocated, 2);
+        assert_eq!(pool.stats().reused, 1);
+        assert_eq!(pool.stats().free, 0);
+    }
+
+    #[test]
+    fn unpooled_packets_compare_by_contents() {
+        let pool = PacketPool::new("test-compare", 3, 1);
+        let mut pooled = pool.take();
+        pooled.copy_from_slice(&[7, 8, 9]);
+
+        assert_eq!(pooled, PooledPacket::from(vec![7, 8, 9]));
+        drop(pooled);
+        assert_eq!(pool.stats().free, 1);
+    }
+
+    #[test]
+    fn stats_of_dropped_pools_are_forgotten() {
+        let pool = PacketPool::new("test-dropped", 8, 1);
+        assert!(packet_pool_stats()
+            .iter()
+            .any(|stats| stats.name == "test-dropped"));
+
+        drop(pool);
+        assert!(!packet_pool_stats()
+            .iter()
+            .any(|stats| stats.name == "test-dropped"));
+    }
+}
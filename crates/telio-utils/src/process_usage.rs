@@ -0,0 +1,119 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Number of times libtelio runtime threads were woken up, see [record_wakeup]
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Count a wakeup of a libtelio runtime thread.
+///
+/// Called whenever a tokio worker thread is unparked, every wakeup prevents the CPU from
+/// staying in a low power state and so contributes to the battery drain.
+pub fn record_wakeup() {
+    WAKEUPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Resources consumed by the current process
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessUsage {
+    /// User and system CPU time of the whole process, `None` where it cannot be measured
+    pub cpu_time: Option<Duration>,
+    /// Wakeups of libtelio runtime threads
+    pub wakeups: u64,
+}
+
+impl ProcessUsage {
+    /// Resources consumed since the process started
+    pub fn current() -> Self {
+        Self {
+            cpu_time: cpu_time(),
+            wakeups: WAKEUPS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resources consumed between `earlier` and `self`
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            cpu_time: self
+                .cpu_time
+                .zip(earlier.cpu_time)
+                .map(|(now, earlier)| now.saturating_sub(earlier)),
+            wakeups: self.wakeups.saturating_sub(earlier.wakeups),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    fn to_duration(time: libc::timeval) -> Duration {
+        Duration::from_secs(time.tv_sec.max(0) as u64)
+            + Duration::from_micros(time.tv_usec.max(0) as u64)
+    }
+
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: getrusage only writes into the provided struct, which is fully initialized
+    // on success
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wakeups_are_counted() {
+        let before = ProcessUsage::current();
+        record_wakeup();
+        record_wakeup();
+        assert!(ProcessUsage::current().since(&before).wakeups >= 2);
+    }
+
+    #[test]
+    fn usage_difference_saturates() {
+        let earlier = ProcessUsage {
+            cpu_time: Some(Duration::from_secs(2)),
+            wakeups: 10,
+        };
+        let later = ProcessUsage {
+            cpu_time: Some(Duration::from_secs(1)),
+            wakeups: 5,
+        };
+        assert_eq!(
+            later.since(&earlier),
+            ProcessUsage {
+                cpu_time: Some(Duration::ZERO),
+                wakeups: 0,
+            }
+        );
+        assert_eq!(
+            later.since(&ProcessUsage::default()).cpu_time,
+            None,
+            "cpu time is unknown when either sample lacks it"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cpu_time_grows_while_busy() {
+        let before = ProcessUsage::current();
+        let start = std::time::Instant::now();
+        let mut x = 0u64;
+        while start.elapsed() < Duration::from_millis(50) {
+            x = std::hint::black_box(x.wrapping_add(1));
+        }
+        let used = ProcessUsage::current().since(&before).cpu_time.unwrap();
+        assert!(used > Duration::ZERO);
+    }
+}
@@ -83,6 +83,7 @@ impl ThreadTracker {
 
     /// Tokio runtime callback
     pub fn on_thread_unpark(&mut self) {
+        crate::record_wakeup();
         self.set_status(ThreadStatus::Unparked);
     }
 }
@@ -7,6 +7,9 @@ mod neptun;
 #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
 mod linux_native_wg;
 
+#[cfg(target_os = "linux")]
+pub(crate) use linux_native_wg::LinuxNativeWg;
+
 #[cfg(any(windows, doc))]
 #[cfg_attr(docsrs, doc(cfg(windows)))]
 mod windows_native_wg;
@@ -28,6 +31,7 @@ use telio_crypto::{KeyDecodeError, PublicKey};
 use telio_sockets::{Protect, SocketPool};
 use thiserror::Error as TError;
 
+use crate::interface_config::InterfaceConfigError;
 use crate::uapi::{self, Cmd, Response};
 use crate::wg::Config;
 
@@ -75,6 +79,12 @@ pub trait Adapter: Send + Sync {
     /// Get luid for adapter interface.
     fn get_adapter_luid(&self) -> u64;
 
+    /// Get the name of the adapter interface, as assigned by the system.
+    /// `None` when the adapter does not know it.
+    async fn get_interface_name(&self) -> Option<String> {
+        None
+    }
+
     /// Send uapi command, and receive response.
     /// Look at [Cross-Platform Userspace Interface](https://www.wireguard.com/xplatform/) for
     /// details.
@@ -177,6 +187,10 @@ pub enum Error {
     /// Endpoint parsing error
     #[error("Failed to parse endpoint address: {0}")]
     EndpointParsingError(#[from] AddrParseError),
+
+    /// Interface configuration error
+    #[error(transparent)]
+    InterfaceConfig(#[from] InterfaceConfigError),
 }
 
 /// Enumeration of types for `Adapter` struct
@@ -272,6 +286,8 @@ pub(crate) async fn start(cfg: Config) -> Result<Box<dyn Adapter>, Error> {
                 cfg.skt_buffer_size,
                 cfg.inter_thread_channel_size,
                 cfg.max_inter_thread_batched_pkts,
+                cfg.worker_threads,
+                cfg.multi_queue_tun,
             )?))
         }
         AdapterType::LinuxNativeWg => {
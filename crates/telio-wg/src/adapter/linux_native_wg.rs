@@ -40,6 +40,11 @@ pub enum Error {
         #[from]
         source: err::LinkDeviceError,
     },
+    #[error("ListDeviceError: {source:?}")]
+    ListDevice {
+        #[from]
+        source: err::ListDeviceError,
+    },
 }
 
 impl LinuxNativeWg {
@@ -47,7 +52,25 @@ impl LinuxNativeWg {
     pub fn start(name: &str) -> Result<Self, AdapterError> {
         let mut rtsocket = RouteSocket::connect().map_err(Error::from)?;
 
-        rtsocket.add_device(name).map_err(Error::from)?;
+        // A kernel interface outlives the process, so after an unclean shutdown
+        // (e.g. gateway reboot or crash) a stale device with the same name may exist.
+        // Adding fails with EEXIST then, but only a WireGuard link may be replaced.
+        if let Err(err) = rtsocket.add_device(name) {
+            let is_stale_wg = rtsocket
+                .list_device_names()
+                .map_err(Error::from)?
+                .iter()
+                .any(|existing| existing == name);
+            if !is_stale_wg {
+                return Err(Error::from(err).into());
+            }
+            telio_log_info!(
+                "LinuxNativeWg: interface {} already exists, recreating it",
+                name
+            );
+            rtsocket.del_device(name).map_err(Error::from)?;
+            rtsocket.add_device(name).map_err(Error::from)?;
+        }
         let wgsocket = WgSocket::connect().map_err(Error::from)?;
 
         Ok(Self {
@@ -80,6 +103,10 @@ impl Adapter for LinuxNativeWg {
         0
     }
 
+    async fn get_interface_name(&self) -> Option<String> {
+        Some(self.ifname.clone())
+    }
+
     async fn stop(&self) {
         let _ = self.rtsocket.lock().await.del_device(&self.ifname);
     }
@@ -39,33 +39,26 @@ impl NepTUN {
         skt_buffer_size: Option<u32>,
         inter_thread_channel_size: Option<u32>,
         max_inter_thread_batched_pkts: Option<u32>,
+        worker_threads: Option<u32>,
+        multi_queue_tun: Option<bool>,
     ) -> Result<Self, AdapterError> {
         use std::os::fd::RawFd;
 
+        #[cfg(not(target_os = "linux"))]
+        let _ = multi_queue_tun;
+
         let config = DeviceConfig {
-            // Apple's NepTUN device runs most efficiently on a single perf-core
-            n_threads: {
-                if cfg!(target_os = "android") {
-                    // A large set of supported android devices
-                    // have 4 "performance" cores
-                    4
-                } else if cfg!(not(any(
-                    target_os = "ios",
-                    target_os = "macos",
-                    target_os = "tvos"
-                ))) {
-                    num_cpus::get()
-                } else {
-                    1
-                }
-            },
+            n_threads: worker_threads
+                .filter(|n| *n > 0)
+                .map(|n| n as usize)
+                .unwrap_or_else(default_worker_threads),
             use_connected_socket: cfg!(not(any(
                 target_os = "ios",
                 target_os = "macos",
                 target_os = "tvos"
             ))),
             #[cfg(target_os = "linux")]
-            use_multi_queue: true,
+            use_multi_queue: multi_queue_tun.unwrap_or(true),
             open_uapi_socket: false,
             protect: socket_pool,
             firewall_process_inbound_callback,
@@ -91,6 +84,24 @@ impl NepTUN {
     }
 }
 
+/// Number of the worker threads when it is not configured
+fn default_worker_threads() -> usize {
+    // Apple's NepTUN device runs most efficiently on a single perf-core
+    if cfg!(target_os = "android") {
+        // A large set of supported android devices
+        // have 4 "performance" cores
+        4
+    } else if cfg!(not(any(
+        target_os = "ios",
+        target_os = "macos",
+        target_os = "tvos"
+    ))) {
+        num_cpus::get()
+    } else {
+        1
+    }
+}
+
 #[async_trait]
 impl Adapter for NepTUN {
     async fn send_uapi_cmd(&self, cmd: &Cmd) -> Result<Response, AdapterError> {
@@ -102,6 +113,12 @@ impl Adapter for NepTUN {
         0
     }
 
+    async fn get_interface_name(&self) -> Option<String> {
+        let dev = self.device.read().await;
+        let dev = dev.device.read();
+        dev.iface().name().ok()
+    }
+
     async fn drop_connected_sockets(&self) {
         self.device.read().await.drop_connected_sockets();
     }
@@ -0,0 +1,86 @@
+//! WireGuard interfaces of the exits steered to by the traffic classes on Linux.
+//!
+//! The tunnel picks the peer of a packet by its destination only, so the traffic classes
+//! selecting their traffic by the source ports or the fwmark are routed to an interface of their
+//! exit instead. The interface is a kernel WireGuard interface with the key of the device and a
+//! single peer, carrying whatever the routing sends its way. It has no addresses, the packets keep
+//! the address of the tunnel.
+
+#![cfg(target_os = "linux")]
+
+use std::collections::BTreeMap;
+
+use telio_crypto::SecretKey;
+use telio_utils::telio_log_info;
+
+use crate::{
+    adapter::{Adapter, Error},
+    interface_config::{self, InterfaceConfig},
+    uapi::{Cmd, Interface, Peer},
+};
+
+/// Interface of a steered exit
+pub struct ClassTunnel {
+    name: String,
+    adapter: Box<dyn Adapter>,
+}
+
+impl ClassTunnel {
+    /// Create the interface called `name` connected to the `exit`. Its encapsulated packets get
+    /// the `fwmark`, so they are routed around the tunnels.
+    pub async fn start(
+        name: &str,
+        private_key: SecretKey,
+        fwmark: u32,
+        exit: Peer,
+    ) -> Result<Self, Error> {
+        interface_config::validate_name(name)?;
+        telio_log_info!("Starting interface {name} of exit {:?}", exit.public_key);
+
+        let adapter = start_adapter(name)?;
+        let tunnel = Self {
+            name: name.to_owned(),
+            adapter,
+        };
+        let interface = Interface {
+            private_key: Some(private_key),
+            listen_port: None,
+            fwmark,
+            peers: BTreeMap::from([(exit.public_key, exit)]),
+        };
+        let response = tunnel
+            .adapter
+            .send_uapi_cmd(&Cmd::Set(interface.into()))
+            .await?;
+        if response.errno != 0 {
+            tunnel.stop().await;
+            return Err(std::io::Error::from_raw_os_error(response.errno).into());
+        }
+        if let Err(e) = interface_config::apply(name, &InterfaceConfig::default()).await {
+            tunnel.stop().await;
+            return Err(e.into());
+        }
+        Ok(tunnel)
+    }
+
+    /// Name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Remove the interface
+    pub async fn stop(self) {
+        telio_log_info!("Stopping interface {}", self.name);
+        self.adapter.stop().await;
+    }
+}
+
+#[cfg(not(any(test, feature = "test-adapter")))]
+fn start_adapter(name: &str) -> Result<Box<dyn Adapter>, Error> {
+    Ok(Box::new(crate::adapter::LinuxNativeWg::start(name)?))
+}
+
+#[cfg(any(test, feature = "test-adapter"))]
+fn start_adapter(_name: &str) -> Result<Box<dyn Adapter>, Error> {
+    Err(Error::UnsupportedAdapter)
+}
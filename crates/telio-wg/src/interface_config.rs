@@ -0,0 +1,373 @@
+//! Parameters of the tunnel network interface which libtelio can apply by itself.
+//!
+//! Historically the interface was configured out-of-band by the platform code once
+//! the device was started. [InterfaceConfig] lets the caller hand the addresses, MTU
+//! and (on Windows) the route metric over to libtelio instead.
+
+use std::collections::HashSet;
+
+use ipnet::IpNet;
+use thiserror::Error as TError;
+
+/// MTU used by NordLynx when nothing else is requested
+pub const DEFAULT_MTU: u32 = 1420;
+
+/// Smallest MTU allowed on an interface carrying only IPv4 addresses
+pub const MIN_MTU_V4: u32 = 576;
+
+/// Smallest MTU allowed on an interface carrying IPv6 addresses
+pub const MIN_MTU_V6: u32 = 1280;
+
+/// Largest MTU which can be set on the interface
+pub const MAX_MTU: u32 = 65535;
+
+#[cfg(not(windows))]
+const MAX_NAME_LEN: usize = 15;
+
+#[cfg(windows)]
+const MAX_NAME_LEN: usize = 128;
+
+/// Addresses, MTU and metric of the tunnel interface
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    /// Addresses assigned to the interface, replacing the ones already present
+    pub addresses: Vec<IpNet>,
+    /// MTU of the interface, left untouched if `None`
+    pub mtu: Option<u32>,
+    /// Route metric of the interface, supported only on Windows
+    pub metric: Option<u32>,
+}
+
+/// Errors of the interface configuration
+#[derive(Debug, TError)]
+pub enum InterfaceConfigError {
+    /// Interface name cannot be used
+    #[error("Invalid interface name: {0:?}")]
+    InvalidName(String),
+    /// MTU is out of the allowed range
+    #[error("Invalid MTU {0}, it must be between {1} and 65535")]
+    InvalidMtu(u32, u32),
+    /// Address cannot be assigned to an interface
+    #[error("Invalid interface address {0}")]
+    InvalidAddress(IpNet),
+    /// The same address was given more than once
+    #[error("Duplicate interface address {0}")]
+    DuplicateAddress(IpNet),
+    /// Metric can be set only on Windows
+    #[error("Interface metric is not supported on this platform")]
+    MetricUnsupported,
+    /// Interface cannot be configured by libtelio on this platform
+    #[error("Interface configuration is not supported on this platform")]
+    Unsupported,
+    /// Adapter does not know the name of its interface, and none was configured
+    #[error("Name of the tunnel interface is unknown")]
+    UnknownName,
+    /// System command configuring the interface failed
+    #[error("Command {0} failed: {1}")]
+    Command(String, String),
+    /// IP helper call configuring the interface failed
+    #[error("Failed to configure interface, error code {0}")]
+    Netio(u32),
+}
+
+impl InterfaceConfig {
+    /// Check the configuration before touching the interface
+    pub fn validate(&self) -> Result<(), InterfaceConfigError> {
+        let mut seen = HashSet::new();
+        for address in &self.addresses {
+            let ip = address.addr();
+            if ip.is_unspecified() || ip.is_multicast() || ip.is_loopback() {
+                return Err(InterfaceConfigError::InvalidAddress(*address));
+            }
+            if !seen.insert(ip) {
+                return Err(InterfaceConfigError::DuplicateAddress(*address));
+            }
+        }
+
+        if let Some(mtu) = self.mtu {
+            self.validate_mtu(mtu)?;
+        }
+
+        if self.metric.is_some() && !cfg!(windows) {
+            return Err(InterfaceConfigError::MetricUnsupported);
+        }
+
+        Ok(())
+    }
+
+    /// Check if `mtu` can be used together with the configured addresses
+    pub fn validate_mtu(&self, mtu: u32) -> Result<(), InterfaceConfigError> {
+        let min = if self.addresses.iter().any(|a| a.addr().is_ipv6()) {
+            MIN_MTU_V6
+        } else {
+            MIN_MTU_V4
+        };
+        if mtu < min || mtu > MAX_MTU {
+            return Err(InterfaceConfigError::InvalidMtu(mtu, min));
+        }
+        Ok(())
+    }
+}
+
+/// Check if `name` can be used as the tunnel interface name
+pub fn validate_name(name: &str) -> Result<(), InterfaceConfigError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| !c.is_control() && !c.is_whitespace() && c != '/' && c != ':');
+    if valid {
+        Ok(())
+    } else {
+        Err(InterfaceConfigError::InvalidName(name.to_owned()))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn execute(bin: &str, args: &[&str]) -> Result<(), InterfaceConfigError> {
+    use telio_utils::telio_log_debug;
+    use tokio::process::Command;
+
+    telio_log_debug!("Executing {bin} {}", args.join(" "));
+    let output = Command::new(bin)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| InterfaceConfigError::Command(bin.to_owned(), e.to_string()))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(InterfaceConfigError::Command(
+            format!("{bin} {}", args.join(" ")),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+/// Apply `cfg` to the interface called `name`
+#[cfg(target_os = "linux")]
+pub(crate) async fn apply(name: &str, cfg: &InterfaceConfig) -> Result<(), InterfaceConfigError> {
+    if let Some(mtu) = cfg.mtu {
+        apply_mtu(name, mtu).await?;
+    }
+
+    execute("ip", &["-4", "addr", "flush", "dev", name]).await?;
+    execute(
+        "ip",
+        &["-6", "addr", "flush", "dev", name, "scope", "global"],
+    )
+    .await?;
+    for address in &cfg.addresses {
+        execute("ip", &["addr", "add", &address.to_string(), "dev", name]).await?;
+    }
+
+    execute("ip", &["link", "set", "dev", name, "up"]).await
+}
+
+/// Change the MTU of the interface called `name`
+#[cfg(target_os = "linux")]
+pub(crate) async fn apply_mtu(name: &str, mtu: u32) -> Result<(), InterfaceConfigError> {
+    execute("ip", &["link", "set", "dev", name, "mtu", &mtu.to_string()]).await
+}
+
+/// Apply `cfg` to the interface called `name`
+#[cfg(target_os = "macos")]
+pub(crate) async fn apply(name: &str, cfg: &InterfaceConfig) -> Result<(), InterfaceConfigError> {
+    if let Some(mtu) = cfg.mtu {
+        apply_mtu(name, mtu).await?;
+    }
+
+    for address in &cfg.addresses {
+        let ip = address.addr().to_string();
+        match address {
+            // utun is a point-to-point interface, so it needs a destination address as well
+            IpNet::V4(_) => execute("ifconfig", &[name, "inet", &address.to_string(), &ip]).await?,
+            IpNet::V6(net) => {
+                execute(
+                    "ifconfig",
+                    &[
+                        name,
+                        "inet6",
+                        &ip,
+                        "prefixlen",
+                        &net.prefix_len().to_string(),
+                        "alias",
+                    ],
+                )
+                .await?
+            }
+        }
+    }
+
+    execute("ifconfig", &[name, "up"]).await
+}
+
+/// Change the MTU of the interface called `name`
+#[cfg(target_os = "macos")]
+pub(crate) async fn apply_mtu(name: &str, mtu: u32) -> Result<(), InterfaceConfigError> {
+    execute("ifconfig", &[name, "mtu", &mtu.to_string()]).await
+}
+
+/// Interface is owned by the app sandbox on mobile platforms
+#[cfg(any(target_os = "android", target_os = "ios", target_os = "tvos"))]
+pub(crate) async fn apply(_name: &str, _cfg: &InterfaceConfig) -> Result<(), InterfaceConfigError> {
+    Err(InterfaceConfigError::Unsupported)
+}
+
+/// Interface is owned by the app sandbox on mobile platforms
+#[cfg(any(target_os = "android", target_os = "ios", target_os = "tvos"))]
+pub(crate) async fn apply_mtu(_name: &str, _mtu: u32) -> Result<(), InterfaceConfigError> {
+    Err(InterfaceConfigError::Unsupported)
+}
+
+/// Apply `cfg` to the interface identified by `luid`
+#[cfg(windows)]
+pub(crate) fn apply(luid: u64, cfg: &InterfaceConfig) -> Result<(), InterfaceConfigError> {
+    use crate::windows::tunnel::winipcfg::luid::InterfaceLuid;
+    use ipnet::{Ipv4Net, Ipv6Net};
+
+    let iface = InterfaceLuid::new(luid);
+    let v4: Vec<Ipv4Net> = cfg
+        .addresses
+        .iter()
+        .filter_map(|a| match a {
+            IpNet::V4(net) => Some(*net),
+            IpNet::V6(_) => None,
+        })
+        .collect();
+    let v6: Vec<Ipv6Net> = cfg
+        .addresses
+        .iter()
+        .filter_map(|a| match a {
+            IpNet::V6(net) => Some(*net),
+            IpNet::V4(_) => None,
+        })
+        .collect();
+
+    iface
+        .set_ipv4_addresses(v4)
+        .map_err(InterfaceConfigError::Netio)?;
+    let has_v6 = !v6.is_empty();
+    iface
+        .set_ipv6_addresses(v6)
+        .map_err(InterfaceConfigError::Netio)?;
+
+    set_ip_interface(luid, cfg.mtu, cfg.metric, has_v6)
+}
+
+/// Change the MTU of the interface identified by `luid`
+#[cfg(windows)]
+pub(crate) fn apply_mtu(luid: u64, mtu: u32, has_v6: bool) -> Result<(), InterfaceConfigError> {
+    set_ip_interface(luid, Some(mtu), None, has_v6)
+}
+
+#[cfg(windows)]
+fn set_ip_interface(
+    luid: u64,
+    mtu: Option<u32>,
+    metric: Option<u32>,
+    has_v6: bool,
+) -> Result<(), InterfaceConfigError> {
+    use crate::windows::tunnel::winipcfg::luid::InterfaceLuid;
+    use winapi::shared::ws2def::{ADDRESS_FAMILY, AF_INET, AF_INET6};
+
+    if mtu.is_none() && metric.is_none() {
+        return Ok(());
+    }
+
+    let iface = InterfaceLuid::new(luid);
+    let mut families = vec![AF_INET as ADDRESS_FAMILY];
+    if has_v6 {
+        families.push(AF_INET6 as ADDRESS_FAMILY);
+    }
+    for family in families {
+        let mut row = iface
+            .get_ip_interface(family)
+            .map_err(InterfaceConfigError::Netio)?;
+        if let Some(mtu) = mtu {
+            row.NlMtu = mtu;
+        }
+        if let Some(metric) = metric {
+            row.UseAutomaticMetric = false as _;
+            row.Metric = metric;
+        }
+        iface
+            .set_ip_interface(&mut row)
+            .map_err(InterfaceConfigError::Netio)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(addresses: &[&str], mtu: Option<u32>) -> InterfaceConfig {
+        InterfaceConfig {
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            mtu,
+            metric: None,
+        }
+    }
+
+    #[test]
+    fn valid_config_passes() {
+        assert!(config(
+            &["100.64.0.1/10", "fd74:656c:696f::1/64"],
+            Some(DEFAULT_MTU)
+        )
+        .validate()
+        .is_ok());
+        assert!(InterfaceConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn bad_addresses_are_rejected() {
+        for address in ["0.0.0.0/0", "127.0.0.1/8", "224.0.0.1/4", "::/0", "::1/128"] {
+            assert!(matches!(
+                config(&[address], None).validate(),
+                Err(InterfaceConfigError::InvalidAddress(_))
+            ));
+        }
+        assert!(matches!(
+            config(&["100.64.0.1/10", "100.64.0.1/32"], None).validate(),
+            Err(InterfaceConfigError::DuplicateAddress(_))
+        ));
+    }
+
+    #[test]
+    fn mtu_range_depends_on_address_family() {
+        assert!(config(&["100.64.0.1/10"], Some(MIN_MTU_V4))
+            .validate()
+            .is_ok());
+        assert!(matches!(
+            config(&["100.64.0.1/10"], Some(MIN_MTU_V4 - 1)).validate(),
+            Err(InterfaceConfigError::InvalidMtu(_, MIN_MTU_V4))
+        ));
+        assert!(matches!(
+            config(&["fd74:656c:696f::1/64"], Some(MIN_MTU_V4)).validate(),
+            Err(InterfaceConfigError::InvalidMtu(_, MIN_MTU_V6))
+        ));
+        assert!(config(&[], Some(MAX_MTU + 1)).validate().is_err());
+    }
+
+    #[test]
+    fn metric_is_windows_only() {
+        let cfg = InterfaceConfig {
+            metric: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(cfg.validate().is_ok(), cfg!(windows));
+    }
+
+    #[test]
+    fn interface_names() {
+        assert!(validate_name("nlx0").is_ok());
+        assert!(validate_name("utun10").is_ok());
+        for name in ["", "has space", "a/b", "a:b", "nul\0"] {
+            assert!(validate_name(name).is_err(), "{name:?}");
+        }
+        assert!(validate_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
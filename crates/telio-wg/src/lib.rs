@@ -6,7 +6,12 @@
 //! Interface between [WireGuard](https://wireguard.com/) and the telio library
 
 pub(crate) mod adapter;
+#[cfg(target_os = "linux")]
+pub mod class_tunnel;
+pub mod interface_config;
 pub(crate) mod link_detection;
+pub mod nested;
+pub mod obfuscation;
 pub(crate) mod wg;
 pub(crate) mod windows;
 
@@ -14,6 +19,7 @@ pub mod uapi;
 
 pub use crate::{
     adapter::{Adapter, AdapterType, Error, FirewallInboundCb, FirewallOutboundCb, Tun},
+    interface_config::{InterfaceConfig, InterfaceConfigError},
     link_detection::LinkDetection,
     wg::*,
 };
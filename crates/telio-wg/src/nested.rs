@@ -0,0 +1,185 @@
+//! Nested tunnel used for chaining exit nodes (multihop).
+//!
+//! The inner hop is configured as a regular WireGuard peer whose endpoint points to a
+//! loopback socket owned by [NestedHop]. Packets received there are already encrypted
+//! for the inner hop and are forwarded using an internal (non-protected) socket, so the
+//! OS routes them back into the tunnel, where they get encrypted again for the entry hop.
+
+use async_trait::async_trait;
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use telio_sockets::SocketPool;
+use telio_task::{Runtime, RuntimeExt, Task, WaitResponse};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn};
+use tokio::net::UdpSocket;
+
+const MAX_PACKET_SIZE: usize = 65535;
+
+/// Relay between the WireGuard socket and the inner hop of an exit chain
+pub struct NestedHop {
+    task: Task<State>,
+    local_addr: SocketAddr,
+}
+
+struct State {
+    /// Loopback socket, used as the endpoint of the inner hop peer
+    local: UdpSocket,
+    /// Socket connected to the inner hop, routed through the tunnel
+    upstream: UdpSocket,
+    /// Address of the WireGuard socket, pinned to the sender of the first outgoing packet
+    wg_addr: Option<SocketAddr>,
+    local_buf: Box<[u8; MAX_PACKET_SIZE]>,
+    upstream_buf: Box<[u8; MAX_PACKET_SIZE]>,
+}
+
+impl NestedHop {
+    /// Start relaying WireGuard packets to the inner `hop` through the tunnel
+    pub async fn start(socket_pool: &SocketPool, hop: SocketAddr) -> io::Result<Self> {
+        let local = SocketPool::new_udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None).await?;
+        let local_addr = local.local_addr()?;
+
+        let bind_addr = if hop.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let upstream = socket_pool.new_internal_udp(bind_addr, None).await?;
+        upstream.connect(hop).await?;
+
+        telio_log_info!("Starting nested hop to {hop} via {local_addr}");
+
+        Ok(Self {
+            task: Task::start(State {
+                local,
+                upstream,
+                wg_addr: None,
+                local_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+                upstream_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+            }),
+            local_addr,
+        })
+    }
+
+    /// Loopback address which should be used as the endpoint of the inner hop peer
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the relay
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "NestedHop";
+
+    type Err = ();
+
+    async fn wait(&mut self) -> WaitResponse<'_, Self::Err> {
+        tokio::select! {
+            // Outgoing packets from WireGuard towards the inner hop
+            res = self.local.recv_from(self.local_buf.as_mut_slice()) => {
+                match res {
+                    Ok((n, from)) if self.wg_addr.unwrap_or(from) == from => {
+                        if self.wg_addr.is_none() {
+                            telio_log_debug!("Nested hop WireGuard address: {from}");
+                            self.wg_addr = Some(from);
+                        }
+                        if let Some(buf) = self.local_buf.get(..n) {
+                            if let Err(e) = self.upstream.send(buf).await {
+                                telio_log_warn!("Failed to send to the nested hop: {e}");
+                            }
+                        }
+                    }
+                    Ok((_, from)) => {
+                        telio_log_debug!("Dropping nested hop packet from unexpected source {from}");
+                    }
+                    Err(e) => telio_log_warn!("Failed to receive from WireGuard: {e}"),
+                }
+            }
+            // Incoming packets from the inner hop back to WireGuard
+            res = self.upstream.recv(self.upstream_buf.as_mut_slice()) => {
+                match (res, self.wg_addr) {
+                    (Ok(n), Some(wg_addr)) => {
+                        if let Some(buf) = self.upstream_buf.get(..n) {
+                            if let Err(e) = self.local.send_to(buf, wg_addr).await {
+                                telio_log_warn!("Failed to send to WireGuard: {e}");
+                            }
+                        }
+                    }
+                    (Ok(_), None) => {
+                        telio_log_debug!("Dropping nested hop packet, WireGuard address unknown");
+                    }
+                    (Err(e), _) => telio_log_warn!("Failed to receive from the nested hop: {e}"),
+                }
+            }
+        }
+
+        Self::next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_sockets::protector::MockProtector;
+
+    #[tokio::test]
+    async fn packets_are_relayed_both_ways() {
+        let mut protect = MockProtector::default();
+        protect.expect_make_internal().returning(|_| Ok(()));
+        let socket_pool = SocketPool::new(protect);
+        let hop = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let wg = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let nested = NestedHop::start(&socket_pool, hop.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        wg.send_to(b"ping", nested.local_addr()).await.unwrap();
+        let (n, relay_addr) = hop.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        hop.send_to(b"pong", relay_addr).await.unwrap();
+        let (n, from) = wg.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        assert_eq!(from, nested.local_addr());
+
+        nested.stop().await;
+    }
+
+    #[tokio::test]
+    async fn packets_from_other_sources_are_dropped() {
+        let mut protect = MockProtector::default();
+        protect.expect_make_internal().returning(|_| Ok(()));
+        let socket_pool = SocketPool::new(protect);
+        let hop = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let wg = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let other = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let nested = NestedHop::start(&socket_pool, hop.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        wg.send_to(b"ping", nested.local_addr()).await.unwrap();
+        let (n, _) = hop.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        other.send_to(b"spoof", nested.local_addr()).await.unwrap();
+        wg.send_to(b"ping2", nested.local_addr()).await.unwrap();
+        let (n, relay_addr) = hop.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping2");
+
+        hop.send_to(b"pong", relay_addr).await.unwrap();
+        let (n, _) = wg.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+
+        nested.stop().await;
+    }
+}
@@ -0,0 +1,386 @@
+//! AmneziaWG compatible obfuscation of WireGuard packets.
+//!
+//! Plain WireGuard is trivial to fingerprint, as every message starts with a well known
+//! message type and handshakes have fixed sizes. The exit node peer is configured with an
+//! endpoint pointing to a loopback socket owned by [ObfuscatedHop], which rewrites message
+//! types to the configured magic headers, pads handshakes with random bytes and sends a
+//! burst of junk packets before every handshake initiation. Packets coming back from the
+//! server are restored before being handed over to WireGuard.
+
+use async_trait::async_trait;
+use rand::Rng;
+use std::{
+    borrow::Cow,
+    convert::TryInto,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use telio_model::features::FeatureObfuscation;
+use telio_sockets::{External, SocketPool};
+use telio_task::{Runtime, RuntimeExt, Task, WaitResponse};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn};
+use tokio::net::UdpSocket;
+
+const MAX_PACKET_SIZE: usize = 65535;
+
+/// Message types of plain WireGuard
+const HANDSHAKE_INIT: u32 = 1;
+const HANDSHAKE_RESPONSE: u32 = 2;
+const COOKIE_REPLY: u32 = 3;
+const TRANSPORT_DATA: u32 = 4;
+
+/// Sizes of WireGuard messages
+const HANDSHAKE_INIT_SIZE: usize = 148;
+const HANDSHAKE_RESPONSE_SIZE: usize = 92;
+const COOKIE_REPLY_SIZE: usize = 64;
+const TRANSPORT_DATA_MIN_SIZE: usize = 32;
+
+/// Relay between the WireGuard socket and an obfuscating exit node
+pub struct ObfuscatedHop {
+    task: Task<State>,
+    local_addr: SocketAddr,
+}
+
+struct State {
+    config: FeatureObfuscation,
+    /// Loopback socket, used as the endpoint of the exit node peer
+    local: UdpSocket,
+    /// Socket connected to the exit node, excluded from the tunnel
+    upstream: External<UdpSocket>,
+    /// Address of the WireGuard socket, pinned to the sender of the first outgoing packet
+    wg_addr: Option<SocketAddr>,
+    local_buf: Box<[u8; MAX_PACKET_SIZE]>,
+    upstream_buf: Box<[u8; MAX_PACKET_SIZE]>,
+}
+
+impl ObfuscatedHop {
+    /// Start relaying obfuscated WireGuard packets to `endpoint`
+    pub async fn start(
+        socket_pool: &SocketPool,
+        endpoint: SocketAddr,
+        config: FeatureObfuscation,
+    ) -> io::Result<Self> {
+        let local = SocketPool::new_udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None).await?;
+        let local_addr = local.local_addr()?;
+
+        let bind_addr = if endpoint.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let upstream = socket_pool.new_external_udp(bind_addr, None).await?;
+        upstream.connect(endpoint).await?;
+
+        telio_log_info!("Starting obfuscated hop to {endpoint} via {local_addr}");
+
+        Ok(Self {
+            task: Task::start(State {
+                config,
+                local,
+                upstream,
+                wg_addr: None,
+                local_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+                upstream_buf: Box::new([0u8; MAX_PACKET_SIZE]),
+            }),
+            local_addr,
+        })
+    }
+
+    /// Loopback address which should be used as the endpoint of the exit node peer
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the relay
+    pub async fn stop(self) {
+        let _ = self.task.stop().await.resume_unwind();
+    }
+}
+
+#[async_trait]
+impl Runtime for State {
+    const NAME: &'static str = "ObfuscatedHop";
+
+    type Err = ();
+
+    async fn wait(&mut self) -> WaitResponse<'_, Self::Err> {
+        tokio::select! {
+            // Outgoing packets from WireGuard towards the exit node
+            res = self.local.recv_from(self.local_buf.as_mut_slice()) => {
+                match res {
+                    Ok((n, from)) if self.wg_addr.unwrap_or(from) == from => {
+                        if self.wg_addr.is_none() {
+                            telio_log_debug!("Obfuscated hop WireGuard address: {from}");
+                            self.wg_addr = Some(from);
+                        }
+                        let config = self.config;
+                        if let Some(packet) = self.local_buf.get_mut(..n) {
+                            let junk = if message_type(packet) == Some(HANDSHAKE_INIT) {
+                                junk_packets(&config)
+                            } else {
+                                Vec::new()
+                            };
+                            for junk_packet in junk {
+                                if let Err(e) = self.upstream.send(&junk_packet).await {
+                                    telio_log_warn!("Failed to send junk packet: {e}");
+                                }
+                            }
+                            match obfuscate(&config, packet) {
+                                Some(packet) => {
+                                    if let Err(e) = self.upstream.send(&packet).await {
+                                        telio_log_warn!("Failed to send to the obfuscated hop: {e}");
+                                    }
+                                }
+                                None => telio_log_debug!("Dropping unknown outgoing packet of {n} bytes"),
+                            }
+                        }
+                    }
+                    Ok((_, from)) => {
+                        telio_log_debug!("Dropping obfuscated hop packet from unexpected source {from}");
+                    }
+                    Err(e) => telio_log_warn!("Failed to receive from WireGuard: {e}"),
+                }
+            }
+            // Incoming packets from the exit node back to WireGuard
+            res = self.upstream.recv(self.upstream_buf.as_mut_slice()) => {
+                match (res, self.wg_addr) {
+                    (Ok(n), Some(wg_addr)) => {
+                        let config = self.config;
+                        let packet = self
+                            .upstream_buf
+                            .get_mut(..n)
+                            .and_then(|packet| deobfuscate(&config, packet));
+                        match packet {
+                            Some(packet) => {
+                                if let Err(e) = self.local.send_to(packet, wg_addr).await {
+                                    telio_log_warn!("Failed to send to WireGuard: {e}");
+                                }
+                            }
+                            // Junk packets and anything else not looking like WireGuard
+                            None => telio_log_debug!("Dropping unknown incoming packet of {n} bytes"),
+                        }
+                    }
+                    (Ok(_), None) => {
+                        telio_log_debug!("Dropping obfuscated hop packet, WireGuard address unknown");
+                    }
+                    (Err(e), _) => telio_log_warn!("Failed to receive from the obfuscated hop: {e}"),
+                }
+            }
+        }
+
+        Self::next()
+    }
+}
+
+fn message_type(packet: &[u8]) -> Option<u32> {
+    packet
+        .get(..4)
+        .and_then(|header| header.try_into().ok())
+        .map(u32::from_le_bytes)
+}
+
+fn set_message_type(packet: &mut [u8], message_type: u32) {
+    if let Some(header) = packet.get_mut(..4) {
+        header.copy_from_slice(&message_type.to_le_bytes());
+    }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill(bytes.as_mut_slice());
+    bytes
+}
+
+/// Junk packets sent ahead of a handshake initiation
+fn junk_packets(config: &FeatureObfuscation) -> Vec<Vec<u8>> {
+    let min = config.junk_packet_min_size as usize;
+    let max = (config.junk_packet_max_size as usize).max(min);
+    (0..config.junk_packet_count)
+        .map(|_| random_bytes(rand::thread_rng().gen_range(min..=max)))
+        .collect()
+}
+
+/// Turn a plain WireGuard message into its obfuscated form.
+///
+/// Handshake messages get padded with random bytes, so they are returned as a new buffer,
+/// while the more frequent cookie replies and transport data are rewritten in place.
+fn obfuscate<'a>(config: &FeatureObfuscation, packet: &'a mut [u8]) -> Option<Cow<'a, [u8]>> {
+    let (junk_size, magic_header) = match (message_type(packet)?, packet.len()) {
+        (HANDSHAKE_INIT, HANDSHAKE_INIT_SIZE) => (
+            config.init_packet_junk_size,
+            config.init_packet_magic_header,
+        ),
+        (HANDSHAKE_RESPONSE, HANDSHAKE_RESPONSE_SIZE) => (
+            config.response_packet_junk_size,
+            config.response_packet_magic_header,
+        ),
+        (COOKIE_REPLY, COOKIE_REPLY_SIZE) => (0, config.cookie_packet_magic_header),
+        (TRANSPORT_DATA, len) if len >= TRANSPORT_DATA_MIN_SIZE => {
+            (0, config.transport_packet_magic_header)
+        }
+        _ => return None,
+    };
+
+    set_message_type(packet, magic_header);
+    if junk_size == 0 {
+        return Some(Cow::Borrowed(packet));
+    }
+
+    let mut obfuscated = random_bytes(junk_size as usize);
+    obfuscated.extend_from_slice(packet);
+    Some(Cow::Owned(obfuscated))
+}
+
+/// Restore a plain WireGuard message from an obfuscated one, `None` for junk
+fn deobfuscate<'a>(config: &FeatureObfuscation, packet: &'a mut [u8]) -> Option<&'a [u8]> {
+    let init_size = config.init_packet_junk_size as usize + HANDSHAKE_INIT_SIZE;
+    let response_size = config.response_packet_junk_size as usize + HANDSHAKE_RESPONSE_SIZE;
+
+    let candidates = [
+        (
+            init_size,
+            config.init_packet_junk_size as usize,
+            config.init_packet_magic_header,
+            HANDSHAKE_INIT,
+        ),
+        (
+            response_size,
+            config.response_packet_junk_size as usize,
+            config.response_packet_magic_header,
+            HANDSHAKE_RESPONSE,
+        ),
+        (
+            COOKIE_REPLY_SIZE,
+            0,
+            config.cookie_packet_magic_header,
+            COOKIE_REPLY,
+        ),
+    ];
+
+    let len = packet.len();
+    let (offset, plain_type) = candidates
+        .iter()
+        .find(|(size, offset, magic_header, _)| {
+            *size == len
+                && message_type(packet.get(*offset..).unwrap_or_default()) == Some(*magic_header)
+        })
+        .map(|(_, offset, _, plain_type)| (*offset, *plain_type))
+        .or_else(|| {
+            (len >= TRANSPORT_DATA_MIN_SIZE
+                && message_type(packet) == Some(config.transport_packet_magic_header))
+            .then_some((0, TRANSPORT_DATA))
+        })?;
+
+    let packet = packet.get_mut(offset..)?;
+    set_message_type(packet, plain_type);
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_sockets::protector::MockProtector;
+
+    fn config() -> FeatureObfuscation {
+        FeatureObfuscation {
+            junk_packet_count: 3,
+            junk_packet_min_size: 10,
+            junk_packet_max_size: 50,
+            init_packet_junk_size: 15,
+            response_packet_junk_size: 18,
+            init_packet_magic_header: 1020325451,
+            response_packet_magic_header: 3288052141,
+            cookie_packet_magic_header: 1766607858,
+            transport_packet_magic_header: 2528465083,
+        }
+    }
+
+    fn message(message_type: u32, size: usize) -> Vec<u8> {
+        let mut packet = vec![0xaa; size];
+        set_message_type(&mut packet, message_type);
+        packet
+    }
+
+    #[test]
+    fn messages_survive_obfuscation() {
+        let config = config();
+        for (plain_type, size, junk_size) in [
+            (HANDSHAKE_INIT, HANDSHAKE_INIT_SIZE, 15),
+            (HANDSHAKE_RESPONSE, HANDSHAKE_RESPONSE_SIZE, 18),
+            (COOKIE_REPLY, COOKIE_REPLY_SIZE, 0),
+            (TRANSPORT_DATA, 1420, 0),
+        ] {
+            let plain = message(plain_type, size);
+            let mut obfuscated = obfuscate(&config, &mut plain.clone()).unwrap().into_owned();
+            assert_eq!(obfuscated.len(), size + junk_size);
+            assert_ne!(message_type(&obfuscated[junk_size..]), Some(plain_type));
+
+            assert_eq!(deobfuscate(&config, &mut obfuscated).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn default_config_keeps_messages_intact() {
+        let config = FeatureObfuscation::default();
+        let plain = message(HANDSHAKE_INIT, HANDSHAKE_INIT_SIZE);
+        let obfuscated = obfuscate(&config, &mut plain.clone()).unwrap().into_owned();
+        assert_eq!(obfuscated, plain);
+    }
+
+    #[test]
+    fn unknown_packets_are_dropped() {
+        let config = config();
+        assert!(obfuscate(&config, &mut message(HANDSHAKE_INIT, 100)).is_none());
+        assert!(obfuscate(&config, &mut message(7, 200)).is_none());
+
+        // Junk coming from the server
+        assert!(deobfuscate(&config, &mut vec![0u8; 40]).is_none());
+        // Plain WireGuard is not accepted once obfuscation is configured
+        assert!(deobfuscate(&config, &mut message(TRANSPORT_DATA, 1420)).is_none());
+    }
+
+    #[test]
+    fn junk_packets_follow_config() {
+        let config = config();
+        let junk = junk_packets(&config);
+        assert_eq!(junk.len(), 3);
+        assert!(junk.iter().all(|packet| (10..=50).contains(&packet.len())));
+    }
+
+    #[tokio::test]
+    async fn handshake_is_preceded_by_junk() {
+        let mut protect = MockProtector::default();
+        protect.expect_make_external().returning(|_| Ok(()));
+        protect.expect_clean().return_const(());
+        let socket_pool = SocketPool::new(protect);
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let wg = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = config();
+
+        let hop = ObfuscatedHop::start(&socket_pool, server.local_addr().unwrap(), config)
+            .await
+            .unwrap();
+
+        let init = message(HANDSHAKE_INIT, HANDSHAKE_INIT_SIZE);
+        wg.send_to(&init, hop.local_addr()).await.unwrap();
+
+        let mut buf = [0u8; 2048];
+        for _ in 0..config.junk_packet_count {
+            let (n, _) = server.recv_from(&mut buf).await.unwrap();
+            assert!((10..=50).contains(&n));
+        }
+        let (n, relay_addr) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(deobfuscate(&config, &mut buf[..n]).unwrap(), init);
+
+        let response = message(HANDSHAKE_RESPONSE, HANDSHAKE_RESPONSE_SIZE);
+        let obfuscated = obfuscate(&config, &mut response.clone())
+            .unwrap()
+            .into_owned();
+        server.send_to(&obfuscated, relay_addr).await.unwrap();
+        let (n, from) = wg.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], response);
+        assert_eq!(from, hop.local_addr());
+
+        hop.stop().await;
+    }
+}
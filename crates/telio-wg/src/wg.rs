@@ -33,6 +33,7 @@ use telio_task::{
 
 use crate::{
     adapter::{self, Adapter, AdapterType, Error, FirewallResetConnsCb, Tun},
+    interface_config::{self, InterfaceConfig},
     link_detection::{self, LinkDetection, LinkDetectionUpdateResult},
     uapi::{self, AnalyticsEvent, Cmd, Event, Interface, Peer, PeerState, Response, UpdateReason},
     FirewallInboundCb, FirewallOutboundCb,
@@ -68,6 +69,9 @@ pub trait WireGuard: Send + Sync + 'static {
     async fn del_peer(&self, key: PublicKey) -> Result<(), Error>;
     /// Disconnect from all peers, implemented only in NepTUN
     async fn drop_connected_sockets(&self) -> Result<(), Error>;
+    /// Make the `peers` send a keepalive right away, which also initiates a handshake when their
+    /// session needs a new one. Peers without an endpoint or persistent keepalive are skipped.
+    async fn trigger_handshakes(&self, peers: Vec<PublicKey>) -> Result<(), Error>;
     /// Retrieve time since last RXed (and accepted) packet
     async fn time_since_last_rx(&self, public_key: PublicKey) -> Result<Option<Duration>, Error>;
     /// Stop adapter
@@ -109,6 +113,10 @@ pub struct Config {
     pub inter_thread_channel_size: Option<u32>,
     /// Configurable socket buffer size, if None doesn't modify default OS set values
     pub max_inter_thread_batched_pkts: Option<u32>,
+    /// Number of the worker threads, if None defaults to the number of CPUs of the platform
+    pub worker_threads: Option<u32>,
+    /// Whether a TUN queue is opened per worker thread on Linux, if None it is
+    pub multi_queue_tun: Option<bool>,
 }
 
 /// Events and analytics transmission channels
@@ -152,6 +160,9 @@ struct State {
     polling_period: Duration,
     polling_period_after_update: Duration,
     last_update: Instant,
+
+    // Interface parameters applied through configure_interface() or set_mtu()
+    interface_config: Option<InterfaceConfig>,
 }
 
 const MAX_UAPI_FAIL_COUNT: i32 = 100;
@@ -228,6 +239,8 @@ impl DynamicWg {
     ///             skt_buffer_size: None,
     ///             inter_thread_channel_size: None,
     ///             max_inter_thread_batched_pkts: None,
+    ///             worker_threads: None,
+    ///             multi_queue_tun: None,
     ///         },
     ///         None,
     ///         Duration::from_millis(1000),
@@ -291,6 +304,7 @@ impl DynamicWg {
                 polling_period,
                 polling_period_after_update,
                 last_update: Instant::now(),
+                interface_config: None,
             }),
         }
     }
@@ -316,6 +330,39 @@ impl DynamicWg {
         task_exec!(&self.task, async move |rt| Ok(rt.set_tun(tun).await)).await??;
         Ok(())
     }
+
+    /// Assign addresses, MTU and metric to the tunnel interface
+    pub async fn configure_interface(&self, config: InterfaceConfig) -> Result<(), Error> {
+        config.validate()?;
+        task_exec!(&self.task, async move |rt| Ok(rt
+            .configure_interface(config)
+            .await))
+        .await??;
+        Ok(())
+    }
+
+    /// Name of the running tunnel interface
+    #[cfg(unix)]
+    pub async fn interface_name(&self) -> Result<String, Error> {
+        task_exec!(&self.task, async move |rt| Ok(rt.interface_name().await)).await?
+    }
+
+    /// Change the MTU of the running tunnel interface
+    pub async fn set_mtu(&self, mtu: u32) -> Result<(), Error> {
+        task_exec!(&self.task, async move |rt| Ok(rt.set_mtu(mtu).await)).await??;
+        Ok(())
+    }
+
+    /// Listen on the `port`, e.g. the one used before a restart
+    pub async fn set_listen_port(&self, port: u16) -> Result<(), Error> {
+        task_exec!(&self.task, async move |s| {
+            let mut ifc = s.interface.clone();
+            ifc.listen_port = Some(port);
+            Ok(s.update(ifc, UpdateReason::Push).await)
+        })
+        .await??;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -329,7 +376,7 @@ impl WireGuard for DynamicWg {
     }
 
     async fn wait_for_listen_port(&self, d: Duration) -> Result<u16, Error> {
-        let start = std::time::SystemTime::now();
+        let start = Instant::now();
         loop {
             if let Some(port) = self.get_interface().await?.listen_port {
                 if port > 0 {
@@ -337,8 +384,7 @@ impl WireGuard for DynamicWg {
                     break Ok(port);
                 }
             }
-            let elapsed_time = start.elapsed()?;
-            if elapsed_time > d {
+            if start.elapsed() > d {
                 break telio_err_with_log!(Error::PortAssignmentTimeoutError);
             }
 
@@ -435,6 +481,59 @@ impl WireGuard for DynamicWg {
         .await?)
     }
 
+    async fn trigger_handshakes(&self, peers: Vec<PublicKey>) -> Result<(), Error> {
+        task_exec!(&self.task, async move |s| {
+            let keepalives: Vec<(PublicKey, u16)> = s
+                .interface
+                .peers
+                .values()
+                .filter(|peer| peers.contains(&peer.public_key) && peer.endpoint.is_some())
+                .filter_map(|peer| match peer.persistent_keepalive_interval {
+                    Some(interval) if interval > 0 => {
+                        Some((peer.public_key, interval.min(u16::MAX as u32) as u16))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if keepalives.is_empty() {
+                return Ok(Ok(()));
+            }
+
+            // WireGuard sends a keepalive right away only when the persistent keepalive is turned
+            // on, so it is turned off and on again, the keepalive initiating the handshake
+            for on in [false, true] {
+                let peers = keepalives
+                    .iter()
+                    .map(|(public_key, interval)| {
+                        let mut set_peer =
+                            set::Peer::from_public_key(public_key.0).update_only(true);
+                        set_peer.persistent_keepalive_interval =
+                            Some(if on { *interval } else { 0 });
+                        set_peer
+                    })
+                    .collect();
+                let response = match s
+                    .uapi_request(&Cmd::Set(set::Device {
+                        private_key: None,
+                        listen_port: None,
+                        fwmark: None,
+                        replace_peers: None,
+                        peers,
+                    }))
+                    .await
+                {
+                    Ok(response) => response,
+                    Err(e) => return Ok(Err(e)),
+                };
+                if response.errno != 0 {
+                    return Ok(Err(std::io::Error::from_raw_os_error(response.errno).into()));
+                }
+            }
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
     async fn time_since_last_rx(&self, public_key: PublicKey) -> Result<Option<Duration>, Error> {
         Ok(task_exec!(&self.task, async move |s| Ok(
             s.time_since_last_rx(public_key)
@@ -487,6 +586,8 @@ impl Config {
             skt_buffer_size: self.skt_buffer_size,
             inter_thread_channel_size: self.inter_thread_channel_size,
             max_inter_thread_batched_pkts: self.max_inter_thread_batched_pkts,
+            worker_threads: self.worker_threads,
+            multi_queue_tun: self.multi_queue_tun,
         })
     }
 }
@@ -499,6 +600,45 @@ struct DiffKeys {
 }
 
 impl State {
+    /// Name of the tunnel interface, as assigned by the system when the adapter knows it
+    #[cfg(unix)]
+    async fn interface_name(&self) -> Result<String, Error> {
+        Ok(self
+            .adapter
+            .get_interface_name()
+            .await
+            .or_else(|| self.cfg.name.clone())
+            .ok_or(interface_config::InterfaceConfigError::UnknownName)?)
+    }
+
+    async fn configure_interface(&mut self, config: InterfaceConfig) -> Result<(), Error> {
+        #[cfg(unix)]
+        interface_config::apply(&self.interface_name().await?, &config).await?;
+        #[cfg(windows)]
+        interface_config::apply(self.adapter.get_adapter_luid(), &config)?;
+
+        self.interface_config = Some(config);
+        Ok(())
+    }
+
+    async fn set_mtu(&mut self, mtu: u32) -> Result<(), Error> {
+        let mut config = self.interface_config.clone().unwrap_or_default();
+        config.validate_mtu(mtu)?;
+
+        #[cfg(unix)]
+        interface_config::apply_mtu(&self.interface_name().await?, mtu).await?;
+        #[cfg(windows)]
+        interface_config::apply_mtu(
+            self.adapter.get_adapter_luid(),
+            mtu,
+            config.addresses.iter().any(|a| a.addr().is_ipv6()),
+        )?;
+
+        config.mtu = Some(mtu);
+        self.interface_config = Some(config);
+        Ok(())
+    }
+
     async fn sync(&mut self) -> Result<(), Error> {
         if let Some(to) = self.uapi_request(&uapi::Cmd::Get).await?.interface {
             let _ = self.update(to, UpdateReason::Pull).await;
@@ -1063,18 +1203,6 @@ pub mod tests {
         }
     }
 
-    impl DynamicWg {
-        pub async fn set_listen_port(&self, port: u16) -> Result<(), Error> {
-            Ok(task_exec!(&self.task, async move |s| {
-                let mut ifc = s.interface.clone();
-                ifc.listen_port = Some(port);
-                let _ = s.update(ifc, UpdateReason::Push).await;
-                Ok(())
-            })
-            .await?)
-        }
-    }
-
     #[cfg(all(unix, test))]
     impl Config {
         fn new() -> std::io::Result<Self> {
@@ -1093,6 +1221,8 @@ pub mod tests {
                 skt_buffer_size: None,
                 inter_thread_channel_size: None,
                 max_inter_thread_batched_pkts: None,
+                worker_threads: None,
+                multi_queue_tun: None,
             })
         }
     }
@@ -1594,6 +1724,75 @@ pub mod tests {
         wg.stop().await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn handshakes_are_triggered_by_turning_the_keepalive_on() {
+        let Env {
+            adapter,
+            wg,
+            event: _event,
+            ..
+        } = setup().await;
+        let pubkey = SecretKey::gen().public();
+        let peer = Peer {
+            public_key: pubkey,
+            endpoint: Some(([192, 0, 2, 1], 51820).into()),
+            persistent_keepalive_interval: Some(25),
+            ..Default::default()
+        };
+        adapter.expect_send_uapi_cmd_generic_call(1).await;
+        wg.add_peer(peer).await.unwrap();
+        adapter.lock().await.checkpoint();
+
+        let keepalive = |interval| {
+            let mut set_peer = set::Peer::from_public_key(pubkey.0).update_only(true);
+            set_peer.persistent_keepalive_interval = Some(interval);
+            Cmd::Set(set::Device {
+                private_key: None,
+                listen_port: None,
+                fwmark: None,
+                replace_peers: None,
+                peers: vec![set_peer],
+            })
+        };
+        let mut sequence = mockall::Sequence::new();
+        for interval in [0, 25] {
+            adapter
+                .lock()
+                .await
+                .expect_send_uapi_cmd()
+                .with(predicate::eq(keepalive(interval)))
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_| {
+                    Ok(Response {
+                        errno: 0,
+                        interface: None,
+                    })
+                });
+        }
+        wg.trigger_handshakes(vec![pubkey]).await.unwrap();
+        adapter.lock().await.checkpoint();
+
+        // Failures are reported
+        adapter
+            .lock()
+            .await
+            .expect_send_uapi_cmd()
+            .with(predicate::eq(keepalive(0)))
+            .times(1)
+            .returning(|_| {
+                Ok(Response {
+                    errno: 19,
+                    interface: None,
+                })
+            });
+        assert!(wg.trigger_handshakes(vec![pubkey]).await.is_err());
+        adapter.lock().await.checkpoint();
+
+        adapter.lock().await.expect_stop().return_once(|| ());
+        wg.stop().await;
+    }
+
     // Check whether updating a peer does not result in a new one being added.
     #[tokio::test(start_paused = true)]
     async fn wg_peer_update() {
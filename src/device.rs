@@ -1,43 +1,114 @@
+mod accounting;
+mod connectivity;
+mod conntrack_persistence;
+mod diagnostics;
+mod exit_failover;
+mod fast_reconnect;
+mod features_update;
+mod flow_collector;
+#[cfg(feature = "health_endpoint")]
+mod health_endpoint;
+#[cfg(all(feature = "strict_privacy", feature = "health_endpoint"))]
+compile_error!("The `strict_privacy` feature excludes the `health_endpoint`");
+#[cfg(feature = "otlp")]
+mod otlp;
+mod routing;
+mod session;
+mod split_tunnel;
+mod startup;
+mod supervision;
 mod wg_controller;
 
+pub use self::connectivity::{ConnectivityRecvError, ConnectivitySubscription};
+pub use self::diagnostics::{AdapterState, DiagnosticsBundle, RecordedEvent, TraversalCandidates};
+pub use self::features_update::FeaturesUpdate;
+
+use self::accounting::TrafficAccounting;
+use self::connectivity::ConnectivityTracker;
+use self::conntrack_persistence::ConntrackStore;
+use self::diagnostics::{redacted_features, EventHistory};
+use self::exit_failover::{exit_node, ExitFailoverState};
+use self::fast_reconnect::FastReconnect;
+#[cfg(target_os = "linux")]
+use self::routing::ClassTunnels;
+use self::routing::RoutingPolicy;
+use self::session::{SavedEndpoint, SavedPostQuantum, SessionSnapshot};
+use self::split_tunnel::SplitTunnelPolicy;
+use self::startup::{Component, StartupSequence};
+use self::supervision::{Restarter, RuntimeSlot};
+use crate::handover::HandoverState;
 use async_trait::async_trait;
-use telio_crypto::{PublicKey, SecretKey};
-use telio_firewall::firewall::{Firewall, StatefullFirewall};
+use telio_crypto::{
+    rotation::{KeyRotation, RotationAction},
+    PublicKey, SecretKey,
+};
+use telio_firewall::approval::InboundDecision;
+use telio_firewall::category::CategoryProvider;
+#[cfg(target_os = "linux")]
+use telio_firewall::exit_node::ExitNodeRules;
+use telio_firewall::firewall::{
+    ConntrackEntry, Firewall, FirewallState, FirewallStats, PacketDescription, StatefullFirewall,
+    Verdict,
+};
+#[cfg(not(feature = "strict_privacy"))]
+use telio_firewall::flow::FlowSink;
+#[cfg(windows)]
+use telio_firewall::wfp::WfpKillswitch;
+#[cfg(target_os = "linux")]
+use telio_firewall::{split_tunnel::SplitTunnelRules, steering::SteeringRules};
+#[cfg(not(feature = "strict_privacy"))]
 use telio_lana::init_lana;
 use telio_network_monitors::{
-    local_interfaces::SystemGetIfAddrs,
+    local_interfaces::{gather_local_interfaces, SystemGetIfAddrs},
     monitor::{LocalInterfacesObserver, NetworkMonitor},
+    path::{current_network_path, NetworkPath, NETWORK_PATH_BROADCAST},
 };
 use telio_pq::PostQuantum;
 use telio_proto::{ConnectionError, Error as EnsError, ErrorNotificationService, HeartbeatMessage};
 use telio_proxy::{Config as ProxyConfig, Io as ProxyIo, Proxy, UdpProxy};
 use telio_relay::{
-    derp::Config as DerpConfig, multiplexer::Multiplexer, DerpKeepaliveConfig, DerpRelay,
-    SortedServers,
+    derp::{
+        health::{order_by_health, RelayHealthMonitor, ServerHealth},
+        Config as DerpConfig,
+    },
+    multiplexer::Multiplexer,
+    stream::StreamHop,
+    DerpKeepaliveConfig, DerpRelay, SortedServers,
 };
 use telio_sockets::{NativeProtector, Protector, SocketPool};
 use telio_starcast::{
+    relay::RelayPolicy,
     starcast_peer::{Config as StarcastPeerConfig, StarcastPeer},
     transport::{Config as StarcastTransportConfig, Transport},
 };
 use telio_task::{
     io::{chan, mc_chan, mc_chan::Tx, Chan, McChan},
+    supervisor::{Supervisor, TaskRegistry, TaskStatus},
     task_exec, BoxAction, Runtime as TaskRuntime, Task,
 };
 
 use telio_traversal::UpgradeSyncTrait;
 use telio_traversal::{
+    capability_exchange::CapabilityExchange,
     connectivity_check,
     cross_ping_check::{CrossPingCheck, CrossPingCheckTrait, Io as CpcIo, UpgradeController},
+    departure::Departures,
     endpoint_providers::{
         self,
+        custom::{CustomEndpointProvider, EndpointDiscovery},
         local::LocalInterfacesEndpointProvider,
+        port_mapping::PortMappingEndpointProvider,
         stun::{StunEndpointProvider, StunServer},
         upnp::UpnpEndpointProvider,
         EndpointProvider,
     },
+    keepalive_tuner::{KeepaliveTuner, NetworkId},
+    lan_discovery::LanDiscovery,
     last_rx_time_provider::{TimeSinceLastRxProvider, WireGuardTimeSinceLastRxProvider},
+    nat_binding_probe::NatBindingProbe,
+    nat_detection::{detect_nat, NatReport},
     ping_pong_handler::PingPongHandler,
+    tcp_punch::TcpPuncher,
     SessionKeeper, UpgradeRequestChangeEvent, UpgradeSync, WireGuardEndpointCandidateChangeEvent,
 };
 
@@ -45,22 +116,29 @@ use telio_traversal::{
 use telio_sockets::native;
 
 use telio_nurse::{
-    aggregator::ConnectivityDataAggregator, config::AggregatorConfig,
-    config::Config as NurseConfig, data::MeshConfigUpdateEvent,
-    MeshnetEntities as NurseMeshnetEntities, Nurse, NurseIo,
+    aggregator::ConnectivityDataAggregator, config::AggregatorConfig, data::MeshConfigUpdateEvent,
+    AnalyticsSink, MeshnetEntities as NurseMeshnetEntities, Nurse,
 };
+#[cfg(not(feature = "strict_privacy"))]
+use telio_nurse::{config::Config as NurseConfig, LanaSink, NurseIo};
 use telio_wg as wg;
+use telio_wg::nested::NestedHop;
+use telio_wg::obfuscation::ObfuscatedHop;
 #[cfg(target_os = "windows")]
 use telio_wg::LinkDetectionObserver;
 
 use thiserror::Error as TError;
 use tokio::{
     runtime::{Builder, Runtime as AsyncRuntime},
-    sync::{broadcast::error::RecvError, Mutex},
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex, RwLockReadGuard,
+    },
+    task::JoinHandle,
     time::Interval,
 };
 
-use telio_dns::{DnsResolver, LocalDnsResolver, Records};
+use telio_dns::{DnsResolver, LocalDnsResolver, Records, UpstreamFailover};
 
 use telio_dns::bind_tun;
 use wg::uapi::{self, PeerState};
@@ -69,7 +147,7 @@ use std::{
     collections::{hash_map::Entry, HashMap, HashSet},
     future::Future,
     io::{self, Error as IoError},
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::{Arc, Once},
     time::Duration,
 };
@@ -80,18 +158,28 @@ use futures::FutureExt;
 use telio_utils::{
     commit_sha,
     exponential_backoff::{self, ExponentialBackoff, ExponentialBackoffBounds},
-    get_ip_stack, interval, telio_log_debug, telio_log_error, telio_log_info, telio_log_warn,
+    get_ip_stack, interval, packet_pool_stats, telio_log_debug, telio_log_error, telio_log_info,
+    telio_log_warn,
     tokio::{Monitor, ThreadTracker},
-    version_tag, Instant,
+    version_tag, ClockEvent, ClockMonitor, Instant, ProcessUsage,
 };
 
 use telio_model::{
-    config::{Config, Peer, PeerBase, Server as DerpServer},
+    config::{
+        Config, ExitFailover, Peer, PeerBase, RelayPinning, RelayPinningFallback, RelayState,
+        Server as DerpServer, SplitTunnel, TrafficClass,
+    },
     constants::{VPN_EXTERNAL_IPV4, VPN_INTERNAL_IPV4},
-    event::{Event, Set},
-    features::{FeaturePersistentKeepalive, Features, PathType},
-    mesh::{ExitNode, LinkState, Node, NodeState},
-    validation::validate_nickname,
+    event::{
+        Connectivity, Event, InboundApproval, KeyRotation as KeyRotationEvent, KeyRotationState,
+        RelayQuality as RelayQualityEvent, RelayRehoming as RelayRehomingEvent, Set,
+    },
+    features::{FeaturePersistentKeepalive, Features, PathType, RestartPolicy, StreamTransport},
+    mesh::{
+        ExitNode, LinkState, Node, NodeState, PeerCapability, PeerConnectivity, PeerQos, PeerStats,
+        PeerTraffic, ResourceUsage,
+    },
+    validation::{validate_meshnet_config, validate_nickname, ValidationReport},
     EndpointMap,
 };
 
@@ -102,6 +190,13 @@ use telio_network_monitors::monitor::PATH_CHANGE_BROADCAST;
 static NETWORK_PATH_MONITOR_START: Once = Once::new();
 static CRYPTO_PROVIDER_INIT: Once = Once::new();
 
+/// Period of checking whether the connections were drained, see [Device::shutdown_graceful]
+const DRAIN_POLL_PERIOD: Duration = Duration::from_millis(200);
+
+/// Time without any traffic after which the connections left are treated as idle rather than
+/// draining, see [Device::shutdown_graceful]
+const DRAIN_IDLE_PERIOD: Duration = Duration::from_secs(2);
+
 pub use wg::{
     uapi::Event as WGEvent, uapi::Interface, AdapterType, DynamicWg, Error as AdapterError,
     FirewallInboundCb, FirewallOutboundCb, LinkDetection, Tun, WireGuard,
@@ -123,6 +218,8 @@ pub enum Error {
     NotStarted,
     #[error("Meshnet not configured.")]
     MeshnetNotConfigured,
+    #[error("Meshnet config has {0} peers, more than the {1} allowed")]
+    TooManyPeers(usize, u32),
     #[error("Adapter is misconfigured: {0}.")]
     AdapterConfig(String),
     #[error("Private key does not match meshnet config's public key.")]
@@ -147,10 +244,34 @@ pub enum Error {
     DnsResolverError(String),
     #[error("DNS module should be disabled when executing this operation")]
     DnsNotDisabled,
+    #[error("Public key does not match the pending key rotation")]
+    InvalidKeyRotation,
+    #[error("Failed to start exit chain: {0}")]
+    ExitChainError(std::io::Error),
+    #[error("WireGuard obfuscation is not enabled in features")]
+    ObfuscationNotEnabled,
+    #[error("Failed to start obfuscated hop: {0}")]
+    ObfuscationError(std::io::Error),
+    #[error("Inbound approval is not enabled in features")]
+    InboundApprovalNotEnabled,
+    #[error("Invalid traffic class: {0}")]
+    InvalidTrafficClass(String),
+    #[error("Invalid split tunnel: {0}")]
+    InvalidSplitTunnel(String),
+    #[error("DNS stub listener address {0} is neither a loopback nor a tunnel address")]
+    InvalidStubListener(IpAddr),
     #[error("Failed to reconnect to DERP server")]
     FailedToReconnect,
     #[error("Failed to recover information about NAT")]
     FailedNatInfoRecover(std::io::Error),
+    #[error("{0:?} cannot start before {1:?} is ready")]
+    StartupOrder(startup::Component, startup::Component),
+    #[error("Device did not start within {0:?}")]
+    StartupTimeout(Duration),
+    #[error("Failed to start the task supervision: {0}")]
+    SupervisionStartError(std::io::Error),
+    #[error("Calls in progress did not return within {0:?} for the restart")]
+    RestartTimeout(Duration),
     #[error("Failed to initialize libmoose: {0}")]
     LibmooseError(#[from] telio_lana::moose::Error),
     #[error("Failed to parse IP network")]
@@ -173,6 +294,12 @@ pub enum Error {
     SessionKeeperError(#[from] telio_traversal::session_keeper::Error),
     #[error("Upgrade sync error: {0}")]
     UpgradeSyncError(#[from] telio_traversal::upgrade_sync::Error),
+    #[error("Capability exchange error: {0}")]
+    CapabilityExchangeError(#[from] telio_traversal::capability_exchange::Error),
+    #[error("Departures error: {0}")]
+    DeparturesError(#[from] telio_traversal::departure::Error),
+    #[error("LAN discovery error: {0}")]
+    LanDiscoveryError(#[from] telio_traversal::lan_discovery::Error),
     #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
     #[error("Socket pool error")]
     SocketPoolError(#[from] telio_sockets::protector::platform::Error),
@@ -198,6 +325,28 @@ pub enum Error {
     EnsFailure(#[from] Box<EnsError>),
     #[error("Exponential backoff error {0}")]
     ExponentialBackoffError(#[from] exponential_backoff::Error),
+    #[error("Session error: {0}")]
+    SessionError(#[from] session::Error),
+    #[cfg(windows)]
+    #[error("Killswitch error: {0}")]
+    KillswitchError(#[from] telio_firewall::wfp::Error),
+    #[cfg(target_os = "linux")]
+    #[error("Exit node rules error: {0}")]
+    ExitNodeRulesError(#[from] telio_firewall::exit_node::Error),
+    #[error("Blocking task failed: {0}")]
+    BlockingTask(#[from] tokio::task::JoinError),
+    #[cfg(target_os = "linux")]
+    #[error("Split tunneling needs the fwmark of the encapsulated packets")]
+    SplitTunnelWithoutFwmark,
+    #[cfg(target_os = "linux")]
+    #[error("Split tunnel rules error: {0}")]
+    SplitTunnelRulesError(telio_firewall::split_tunnel::Error),
+    #[cfg(target_os = "linux")]
+    #[error("Steering traffic classes needs the fwmark of the encapsulated packets")]
+    SteeringWithoutFwmark,
+    #[cfg(target_os = "linux")]
+    #[error("Steering rules error: {0}")]
+    SteeringRulesError(telio_firewall::steering::Error),
 }
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
@@ -213,20 +362,33 @@ pub struct DeviceConfig {
     pub name: Option<String>,
     pub tun: Option<Tun>,
     pub ext_if_filter: Option<Vec<String>>,
+    /// Addresses, MTU and metric applied to the interface once it is created
+    pub interface: Option<wg::InterfaceConfig>,
 }
 
 pub struct Device {
     async_runtime: Option<Box<AsyncRuntime>>,
     event: Tx<Box<Event>>,
-    rt: Option<Task<Runtime>>,
+    rt: RuntimeSlot,
+    /// Tasks of the device, supervised together
+    registry: TaskRegistry,
+    /// Heartbeats of the tasks, enabled by the `supervision` feature
+    supervisor: Option<Supervisor<RestartPolicy>>,
     protect: Option<Arc<dyn Protector>>,
     features: Features,
+    analytics_sinks: Vec<Arc<dyn AnalyticsSink>>,
+    conntrack: ConntrackStore,
+    /// Latest events delivered to the app, for the diagnostics
+    event_history: Arc<parking_lot::Mutex<EventHistory>>,
 }
 
 #[derive(Default)]
 pub struct RequestedDeviceConfig {
     pub private_key: SecretKey,
     pub fwmark: Option<u32>,
+    pub adapter: AdapterType,
+    // Addresses assigned to the interface by libtelio
+    pub addresses: Vec<IpAddr>,
 }
 
 impl RequestedDeviceConfig {
@@ -234,6 +396,12 @@ impl RequestedDeviceConfig {
         Self {
             private_key: device_config.private_key.clone(),
             fwmark: device_config.fwmark,
+            adapter: device_config.adapter.clone(),
+            addresses: device_config
+                .interface
+                .iter()
+                .flat_map(|interface| interface.addresses.iter().map(|net| net.addr()))
+                .collect(),
         }
     }
 }
@@ -252,6 +420,14 @@ pub struct RequestedState {
     // The latest exit node passed by libtelio.connected_to_exit(...)
     pub exit_node: Option<ExitNode>,
 
+    // Inner hop of the exit chain passed by libtelio.set_exit_chain(...), its endpoint
+    // is replaced by the local address of the nested hop relay
+    pub exit_hop: Option<ExitNode>,
+
+    // Local address of the stream hop carrying the exit node traffic while UDP seems to be
+    // blocked, used as the WireGuard endpoint of the exit node instead of its own
+    pub exit_stream_endpoint: Option<SocketAddr>,
+
     // Private key which allows us to recognize if the incoming node event
     // is disconnection from VPN node
     pub last_exit_node: Option<ExitNode>,
@@ -266,6 +442,28 @@ pub struct RequestedState {
 
     // Requested keepalive periods
     pub(crate) keepalive_periods: FeaturePersistentKeepalive,
+
+    // Relay servers passed by libtelio.set_relay_servers(...), used instead of the ones
+    // from the meshnet config
+    pub relay_servers: Option<Vec<DerpServer>>,
+
+    // Restriction of the relay servers passed by libtelio.set_relay_pinning(...)
+    pub relay_pinning: Option<RelayPinning>,
+
+    // Traffic classes passed by libtelio.set_traffic_classes(...), routed to their own exits
+    pub routing_policy: RoutingPolicy,
+
+    // VPN servers passed by libtelio.set_additional_exits(...), connected next to the exit node
+    pub additional_exits: Vec<ExitNode>,
+
+    // Split tunnel passed by libtelio.set_split_tunnel(...), kept off the tunnel
+    pub split_tunnel: SplitTunnelPolicy,
+
+    // Set by libtelio.pause(), keepalives, relay and traversal are stopped until libtelio.resume()
+    pub paused: bool,
+
+    // Endpoint discovery mechanisms registered by libtelio.add_endpoint_discovery(...)
+    pub endpoint_discoveries: Vec<Arc<dyn EndpointDiscovery>>,
 }
 
 pub struct MeshnetEntities {
@@ -286,6 +484,12 @@ pub struct MeshnetEntities {
 
     // Keepalive sender
     session_keeper: Option<Arc<SessionKeeper>>,
+
+    // Exchange of the protocol version and optional features with the peers
+    capability_exchange: Arc<CapabilityExchange>,
+
+    // Goodbyes exchanged with the peers when shutting down
+    departures: Arc<Departures>,
 }
 
 #[derive(Default, Debug)]
@@ -346,7 +550,81 @@ pub struct Entities {
 
     network_monitor: NetworkMonitor,
 
+    // Registered with the network monitor, which holds it weakly only
+    interfaces_change_observer: Option<Arc<dyn LocalInterfacesObserver>>,
+
     error_notification_service: Option<ErrorNotificationService>,
+
+    // WFP based killswitch
+    #[cfg(windows)]
+    killswitch: Option<WfpKillswitch>,
+
+    // Forwarding rules for peers routing through this device
+    #[cfg(target_os = "linux")]
+    exit_node_rules: Option<Arc<ExitNodeRules>>,
+
+    // Marks routing the split tunneled traffic around the tunnel, created on first use
+    #[cfg(target_os = "linux")]
+    split_tunnel_rules: Option<SplitTunnelRules>,
+
+    // Marks and routes steering traffic classes to the interfaces of their exits, created on
+    // first use
+    #[cfg(target_os = "linux")]
+    steering_rules: Option<SteeringRules>,
+
+    // Interfaces of the exits steered to by the traffic classes
+    #[cfg(target_os = "linux")]
+    class_tunnels: ClassTunnels,
+
+    // Relay for the inner hop of the exit chain
+    exit_chain: Option<NestedHop>,
+
+    // Relay obfuscating the traffic of the exit node, set by libtelio.connect_exit_node_obfuscated(...)
+    exit_obfuscation: Option<ObfuscatedHop>,
+
+    // Relay carrying the traffic of the exit node over TLS, while UDP seems to be blocked
+    exit_stream: Option<StreamHop>,
+}
+
+/// Transport used to reach the exit node, moved along `wireguard.stream_transport.fallback_order`
+/// while no handshake completes
+struct ExitTransport {
+    /// Exit node as connected by the integrator, with its real endpoint
+    exit_node: ExitNode,
+    /// Position in the fallback order, `None` for plain UDP
+    position: Option<usize>,
+    /// Since when no handshake completed over the current transport
+    since: Instant,
+    /// Stream hop of the current transport being connected, off the polling of the runtime
+    starting: Option<JoinHandle<io::Result<StreamHop>>>,
+}
+
+impl ExitTransport {
+    fn new(exit_node: ExitNode) -> Self {
+        Self {
+            exit_node,
+            position: None,
+            since: Instant::now(),
+            starting: None,
+        }
+    }
+
+    /// Move on to the next transport, starting over with plain UDP after the last one
+    fn advance(&mut self, order: &[StreamTransport], now: Instant) -> Option<StreamTransport> {
+        self.position = Some(self.position.map_or(0, |position| position + 1))
+            .filter(|position| *position < order.len());
+        self.since = now;
+        self.position
+            .and_then(|position| order.get(position).copied())
+    }
+}
+
+impl Drop for ExitTransport {
+    fn drop(&mut self) {
+        if let Some(starting) = self.starting.take() {
+            starting.abort();
+        }
+    }
 }
 
 impl Entities {
@@ -356,6 +634,13 @@ impl Entities {
             .and_then(|m| m.direct.as_ref().map(|d| &d.cross_ping_check))
     }
 
+    pub fn lan_discovery(&self) -> Option<&LanDiscovery> {
+        self.meshnet
+            .left()
+            .and_then(|m| m.direct.as_ref())
+            .and_then(|d| d.lan_discovery.as_ref())
+    }
+
     pub fn session_keeper(&self) -> Option<&Arc<SessionKeeper>> {
         self.meshnet.left().and_then(|m| m.session_keeper.as_ref())
     }
@@ -394,6 +679,8 @@ pub struct DirectEntities {
     local_interfaces_endpoint_provider: Option<Arc<LocalInterfacesEndpointProvider>>,
     stun_endpoint_provider: Option<Arc<StunEndpointProvider>>,
     upnp_endpoint_provider: Option<Arc<UpnpEndpointProvider>>,
+    port_mapping_endpoint_provider: Option<Arc<PortMappingEndpointProvider>>,
+    custom_endpoint_provider: Option<Arc<CustomEndpointProvider>>,
 
     // dyn EndpointProvider vector for ease of use
     endpoint_providers: Vec<Arc<dyn EndpointProvider>>,
@@ -403,6 +690,9 @@ pub struct DirectEntities {
 
     // Meshnet WG Connection upgrade synchronization
     upgrade_sync: Arc<UpgradeSync>,
+
+    // Discovery of the peers on the same LAN
+    lan_discovery: Option<LanDiscovery>,
 }
 
 pub struct EventListeners {
@@ -413,6 +703,10 @@ pub struct EventListeners {
     stun_server_subscriber: chan::Rx<Option<StunServer>>,
     post_quantum_subscriber: chan::Rx<telio_pq::Event>,
     error_notification_service_subscriber: chan::Rx<(ConnectionError, PublicKey)>,
+    /// Changes of the local interfaces, enabled by `fast_reconnect.follow_interface_changes`
+    interfaces_change_subscriber: chan::Rx<()>,
+    /// Network paths reported by the platform monitor of the app
+    network_path_subscriber: broadcast::Receiver<NetworkPath>,
 }
 
 pub struct EventPublishers {
@@ -487,6 +781,65 @@ struct Runtime {
     /// TODO: This is planned to be refactored into a bit better solution in https://github.com/NordSecurity/libtelio/pull/1021
     last_transmitted_event: HashMap<PublicKey, Node>,
 
+    /// Periodic device key rotation, enabled by the `key_rotation` feature
+    key_rotation: Option<KeyRotation>,
+
+    /// Keepalive tuning for direct peers, enabled by the `wireguard.adaptive_keepalive` feature
+    keepalive_tuner: Option<KeepaliveTuner>,
+
+    /// Measurement of the NAT binding lifetime of the current network, enabled by the
+    /// `wireguard.adaptive_keepalive.probe_binding_lifetime` feature
+    nat_binding_probe: Option<NatBindingProbe>,
+
+    /// Time and resource usage at the start, reported usage is relative to it
+    usage_baseline: (Instant, ProcessUsage),
+
+    /// Transport of the exit node connection, enabled by the `wireguard.stream_transport` feature
+    exit_transport: Option<ExitTransport>,
+
+    /// Exits connected one after another, set by libtelio.set_exit_failover(...)
+    exit_failover: Option<ExitFailoverState>,
+
+    /// Peers awaited after a network change, enabled by the `fast_reconnect` feature
+    fast_reconnect: Option<FastReconnect>,
+
+    /// Last network path reported by the platform monitor of the app
+    network_path: Option<NetworkPath>,
+
+    /// Detection of suspends, after which connections have to be refreshed
+    clock_monitor: ClockMonitor,
+
+    /// Health checks of the relay servers, enabled by the `derp.health_check` feature
+    relay_health: Option<RelayHealthMonitor>,
+
+    /// State the device is restarted with by the supervision, enabled by the `supervision`
+    /// feature
+    restart_state: Option<Arc<parking_lot::Mutex<HandoverState>>>,
+
+    /// Readiness of the components, keeps them coming up in dependency order
+    startup: StartupSequence,
+
+    /// Source of the typed connectivity events
+    connectivity: ConnectivityTracker,
+
+    /// Whether none of the relay servers allowed by the relay pinning is reachable
+    relay_pinning_unsatisfied: bool,
+
+    /// Session state of the previous run not applied yet, set by
+    /// libtelio.start_with_session(...)
+    restored_session: Option<SessionSnapshot>,
+
+    /// Accounting of the traffic exchanged with the peers, enabled by the `accounting` feature
+    accounting: Option<TrafficAccounting>,
+
+    /// Export to an OpenTelemetry collector, enabled by the `otlp` feature
+    #[cfg(feature = "otlp")]
+    otlp: Option<otlp::OtlpTelemetry>,
+
+    /// Local HTTP endpoint probed for the health, enabled by the `health_endpoint` feature
+    #[cfg(feature = "health_endpoint")]
+    health_endpoint: Option<health_endpoint::HealthEndpoint>,
+
     #[cfg(test)]
     /// MockedAdapter (tests)
     test_env: telio_wg::tests::Env,
@@ -513,11 +866,16 @@ impl Device {
 
         telio_log_info!("libtelio is starting up with features : {:?}", features);
 
+        #[cfg(not(feature = "strict_privacy"))]
         if let Some(lana) = &features.lana {
             if init_lana(lana.event_path.clone(), version_tag.to_string(), lana.prod).is_err() {
                 telio_log_error!("Failed to initialize lana")
             }
         }
+        #[cfg(feature = "strict_privacy")]
+        if features.lana.is_some() {
+            telio_log_warn!("Lana is configured, but libtelio is built with strict privacy");
+        }
 
         #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
         NETWORK_PATH_MONITOR_START
@@ -550,16 +908,23 @@ impl Device {
         thread_tracker.start();
 
         let (event_tx, mut event_rx) = tokio::sync::broadcast::channel(256);
+        let event_history = Arc::new(parking_lot::Mutex::new(EventHistory::default()));
 
         let handle = std::thread::Builder::new()
             .name("libtelio-events".to_owned())
-            .spawn(move || loop {
-                match event_rx.blocking_recv() {
-                    Ok(event) => event_cb(event),
-                    Err(RecvError::Lagged(n)) => {
-                        telio_log_warn!("Failed to receive new event, lagged: {n}")
+            .spawn({
+                let event_history = event_history.clone();
+                move || loop {
+                    match event_rx.blocking_recv() {
+                        Ok(event) => {
+                            event_history.lock().record(&event);
+                            event_cb(event)
+                        }
+                        Err(RecvError::Lagged(n)) => {
+                            telio_log_warn!("Failed to receive new event, lagged: {n}")
+                        }
+                        Err(RecvError::Closed) => break,
                     }
-                    Err(RecvError::Closed) => break,
                 }
             });
         if let Err(e) = handle {
@@ -571,354 +936,1089 @@ impl Device {
             features,
             async_runtime: Some(Box::new(art)),
             event: event_tx,
-            rt: None,
+            rt: RuntimeSlot::default(),
+            registry: TaskRegistry::new(),
+            supervisor: None,
             protect,
+            analytics_sinks: Vec::new(),
+            conntrack: ConntrackStore::default(),
+            event_history,
         })
     }
 
     pub fn is_running(&self) -> bool {
-        self.rt.is_some()
+        // Locked only while being stopped or restarted by the supervision
+        self.rt.try_read().map_or(true, |rt| rt.is_some())
+    }
+
+    /// Register a receiver of the analytics collected by nurse, in addition to the built-in
+    /// backend when lana is initialized.
+    ///
+    /// Sinks are bound to the device when it starts, so they have to be registered before.
+    pub fn add_analytics_sink(&mut self, sink: Arc<dyn AnalyticsSink>) -> Result {
+        if self.is_running() {
+            return Err(Error::AlreadyStarted);
+        }
+        self.analytics_sinks.push(sink);
+        Ok(())
+    }
+
+    /// Subscribe to the typed connectivity events.
+    ///
+    /// The same events are delivered to the event callback as well, the subscription stays
+    /// valid across device restarts.
+    pub fn subscribe_connectivity_events(&self) -> ConnectivitySubscription {
+        ConnectivitySubscription::new(self.event.subscribe())
     }
 
     pub fn external_nodes(&self) -> Result<Vec<Node>> {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |s| Ok(s.external_nodes().await)).await?
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .external_nodes()
+                .await))
+            .await?
         })
     }
 
-    pub fn start(&mut self, config: DeviceConfig) -> Result {
-        if self.is_running() {
-            return Err(Error::AlreadyStarted);
-        }
+    /// Get runtime statistics of every WireGuard peer
+    pub fn peer_stats(&self) -> Result<Vec<PeerStats>> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |s| Ok(s.peer_stats().await)).await?
+        })
+    }
 
-        self.rt = Some(self.async_runtime()?.block_on(async {
-            let t = Task::start(
-                Runtime::start(
-                    self.event.clone(),
-                    config,
-                    self.features.clone(),
-                    self.protect.clone(),
-                )
-                .boxed()
-                .await?,
-            );
-            Ok::<Task<Runtime>, Error>(t)
-        })?);
+    /// Get RTT, jitter and loss of every meshnet peer and the exit node, measured by QoS
+    /// analytics. Empty when the analytics are disabled.
+    pub fn qos_report(&self) -> Result<Vec<PeerQos>> {
+        self.async_runtime()?.block_on(async {
+            Ok(task_exec!(&*self.rt().await?, async move |s| Ok(
+                match &s.entities.nurse {
+                    Some(nurse) => nurse.qos_report().await,
+                    None => Vec::new(),
+                }
+            ))
+            .await?)
+        })
+    }
 
-        Ok(())
+    /// Get the current path to every meshnet peer, when it last changed and why the direct path
+    /// was lost, if it was
+    pub fn connectivity_matrix(&self) -> Result<Vec<PeerConnectivity>> {
+        self.async_runtime()?.block_on(async {
+            Ok(task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .connectivity
+                .matrix()))
+            .await?)
+        })
     }
 
-    pub fn stop(&mut self) {
-        if let Some(rt) = self.rt.take() {
-            if let Some(art) = &self.async_runtime {
-                let _ = art.block_on(rt.stop());
-                self.flush_events();
-            }
+    /// Get the health of the internal tasks as of the last supervision check. Empty when the
+    /// supervision is disabled.
+    pub fn task_health(&self) -> Result<Vec<TaskStatus>> {
+        if !self.is_running() {
+            return Err(Error::NotStarted);
         }
+        Ok(self
+            .supervisor
+            .as_ref()
+            .map(Supervisor::report)
+            .unwrap_or_default())
     }
 
-    fn flush_events(&self) {
-        if let Some(timeout) = self.features.flush_events_on_stop_timeout_seconds {
-            let start_time = Instant::now();
-            while !self.event.is_empty() {
-                std::thread::sleep(Duration::from_millis(100));
-                if timeout > 0 && start_time.elapsed().as_secs() >= timeout {
-                    break;
-                }
-            }
-        }
+    /// Collect the adapter state, peer statistics, tracked connections, relay history, latest
+    /// events, endpoint candidates and features, with their secrets redacted, into a single
+    /// bundle for support
+    pub fn collect_diagnostics(&self) -> Result<DiagnosticsBundle> {
+        let (recent_events, relay_history) = self.event_history.lock().snapshot();
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .collect_diagnostics(recent_events, relay_history)
+                .await))
+            .await?
+        })
     }
 
-    /// [Windows only] Retrieve the UUID of the interface
-    ///
-    /// This methods retrieves the UUID of the virtual interface created by device::start() call
-    pub fn get_adapter_luid(&mut self) -> u64 {
-        if let Some(art) = &self.async_runtime {
-            let res: Result<u64> = art.block_on(async {
-                task_exec!(self.rt()?, async move |rt| Ok(rt.get_adapter_luid().await)).await?
-            });
-            res.unwrap_or(0)
-        } else {
-            0
-        }
+    /// Get the firewall rules in effect, i.e. the permissions of the peers
+    pub fn firewall_state(&self) -> Result<FirewallState> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .entities
+                .firewall
+                .effective_state()))
+            .await?
+        })
     }
 
-    pub fn shutdown_art(&mut self) {
-        let _ = self.async_runtime.take();
+    /// Get the connections currently tracked by the firewall
+    pub fn firewall_connections(&self) -> Result<Vec<ConntrackEntry>> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .entities
+                .firewall
+                .export_connections()
+                .entries()))
+            .await?
+        })
     }
 
-    pub fn try_shutdown_art(&mut self, timeout: Duration) -> Result {
-        let art = self.async_runtime.take();
-        match art {
-            Some(art) => {
-                art.shutdown_timeout(timeout);
-                Ok(())
-            }
-            None => Err(Error::NotStarted),
-        }
+    /// Get the number of the packets accepted, dropped and rejected by the firewall
+    pub fn firewall_stats(&self) -> Result<FirewallStats> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .entities
+                .firewall
+                .stats()))
+            .await?
+        })
     }
 
-    /// Configure the private key of the WireGuard interface
-    ///
-    /// This methods sets the private key of the WireGuard interface. Note that it
-    /// is only valid to call this method only on device instance which is currently
-    /// not running. E.g. either before start()'ing or after stop()'ing it.
-    pub fn set_private_key(&self, private_key: &SecretKey) -> Result {
-        let private_key = private_key.clone(); //Going into async context, therefore just copy for lifetimes
+    /// Get CPU time, wakeups and traffic accumulated since the device was started
+    pub fn resource_usage(&self) -> Result<ResourceUsage> {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| {
-                Ok(rt.set_private_key(&private_key).boxed().await)
-            })
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .resource_usage()
+                .await))
             .await?
         })
     }
 
-    /// Set the (u)tun file descriptor to be used by the adapter
-    pub fn set_tun(&self, tun: Tun) -> Result {
+    /// Get the traffic exchanged with each peer during the last minute, hour and day, empty
+    /// unless the `accounting` feature is enabled
+    pub fn traffic_accounting(&self) -> Result<Vec<PeerTraffic>> {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| {
-                let _guard = rt.entities.network_monitor.pause();
-                Ok(rt.set_tun(tun).boxed().await)
-            })
+            task_exec!(&*self.rt().await?, async move |s| Ok(s
+                .accounting
+                .as_ref()
+                .map(|accounting| accounting.traffic(conntrack_persistence::unix_time()))
+                .unwrap_or_default()))
             .await?
         })
     }
 
-    /// Configure the external interface list on adapter.
+    /// Allow only the relay servers in the given regions or with the given hostnames, `None`
+    /// allows any server. When none of the allowed servers is reachable, a
+    /// [Connectivity::NoAllowedRelay] event is published and the fallback of the `pinning` is
+    /// applied until one is reachable again.
     ///
-    /// This methods sets the private key of the WireGuard interface. Note that it
-    /// is only valid to call this method only on device instance which is currently
-    /// not running. E.g. either before start()'ing or after stop()'ing it.
-    pub fn set_ext_if_filter(&self, ext_if_filter: Vec<String>) -> Result {
+    /// [Connectivity::NoAllowedRelay]: telio_model::event::Connectivity::NoAllowedRelay
+    pub fn set_relay_pinning(&self, pinning: Option<RelayPinning>) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| {
-                Ok(rt.set_ext_if_filter(ext_if_filter).boxed().await)
-            })
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_relay_pinning(pinning)
+                .boxed()
+                .await))
             .await?
         })
     }
 
-    /// Retrieves currently configured private key for the interface
-    pub fn get_private_key(&self) -> Result<SecretKey> {
+    /// Route the traffic to the networks of each class through the exit of the class, while the
+    /// rest keeps going through the exit connected with [Device::connect_exit_node]. The exits
+    /// of the classes are either meshnet peers allowing traffic routing, the connected VPN
+    /// server or the additional exits. On Linux classes can also select their traffic by the
+    /// source ports or the fwmark, their exits must be additional exits and get interfaces of
+    /// their own, which needs the fwmark of the encapsulated packets.
+    pub fn set_traffic_classes(&self, classes: Vec<TrafficClass>) -> Result {
+        let policy = RoutingPolicy::new(classes)?;
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt.get_private_key().await)).await?
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_routing_policy(policy)
+                .boxed()
+                .await))
+            .await?
         })
     }
 
-    /// [Linux only] Configure the fwmark used for encapsulated packets
-    #[cfg(any(target_os = "linux", doc))]
-    #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
-    pub fn set_fwmark(&self, fwmark: u32) -> Result {
+    /// Connect the VPN servers of the `exits` next to the exit connected with
+    /// [Device::connect_exit_node], replacing the previous ones. Additional exits carry only
+    /// their own allowed IPs and the traffic classes routed to them, the default route stays with
+    /// the exit node.
+    pub fn set_additional_exits(&self, exits: Vec<ExitNode>) -> Result {
+        if exits.iter().any(|exit| exit.endpoint.is_none()) {
+            return Err(Error::EndpointNotProvided);
+        }
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt
-                .set_fwmark(fwmark)
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_additional_exits(exits)
                 .boxed()
                 .await))
             .await?
         })
     }
 
-    /// Configure meshnet
-    ///
-    /// This method sets the desired meshnet configuration
-    pub fn set_config(&self, config: &Option<Config>) -> Result {
-        let config = config.clone();
+    /// Keep the traffic of the `split_tunnel` off the tunnel, `None` routes all of it through the
+    /// tunnel again. Excluded routes are never routed through the tunnel, while the exits carry
+    /// only the included routes. On Linux the bypassed traffic is marked with the fwmark set by
+    /// [Device::set_fwmark], elsewhere the app has to route it around the tunnel by itself.
+    pub fn set_split_tunnel(&self, split_tunnel: Option<SplitTunnel>) -> Result {
+        let policy = SplitTunnelPolicy::new(split_tunnel)?;
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt
-                .set_config(&config)
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_split_tunnel(policy)
                 .boxed()
                 .await))
             .await?
         })
     }
 
-    /// Notify device about network change event
+    /// Connect the first of the `failover` exits, and the next one whenever the active exit
+    /// degrades, reporting the switch with a [Connectivity::ExitFailover] event. `None` stops
+    /// the failover, leaving the active exit connected.
     ///
-    /// In some cases integrators may have better knowledge of the network state or state changes,
-    /// therefore this method assumes, that a device has migrated from one network to the other,
-    /// and adapts whatever is needed
-    pub fn notify_network_change(&self) -> Result {
+    /// [Connectivity::ExitFailover]: telio_model::event::Connectivity::ExitFailover
+    pub fn set_exit_failover(&self, failover: Option<ExitFailover>) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| {
-                Ok(rt.notify_network_change().await)
-            })
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_exit_failover(failover)
+                .boxed()
+                .await))
             .await?
         })
     }
 
-    pub fn notify_sleep(&self) -> Result {
+    /// Replace the features of the device. Changes which the running components can take are
+    /// applied right away, the ones the meshnet components are started with by restarting them,
+    /// the rest takes effect on the next start. Returns which was which.
+    pub fn update_features(&mut self, features: Features) -> Result<FeaturesUpdate> {
+        let update = FeaturesUpdate::new(&self.features, &features);
+        if update.is_empty() {
+            return Ok(update);
+        }
+        telio_log_info!("Updating features: {:?}", update);
+
+        LOG_CENSOR.set_enabled(features.hide_user_data);
+        hide_thread_id_in_logs(features.hide_thread_id);
+
+        if self.is_running() {
+            let hot = features.clone();
+            self.async_runtime()?.block_on(async {
+                task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                    .apply_features(hot)
+                    .boxed()
+                    .await))
+                .await?
+            })?;
+        }
+        self.features = features;
+        Ok(update)
+    }
+
+    /// Use the given relay servers instead of the ones from the meshnet config, `None` goes back
+    /// to the configured ones. The active relay is reported with a relay event as usual.
+    pub fn set_relay_servers(&self, servers: Option<Vec<DerpServer>>) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt.notify_sleep().await)).await?
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_relay_servers(servers)
+                .boxed()
+                .await))
+            .await?
         })
     }
 
-    pub fn notify_wakeup(&self) -> Result {
+    /// Latency and failed checks of the relay servers, empty unless the `derp.health_check`
+    /// feature is enabled
+    pub fn relay_server_health(&self) -> Result<HashMap<PublicKey, ServerHealth>> {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt.notify_wakeup().await)).await?
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .relay_server_health()
+                .await))
+            .await?
         })
     }
 
-    /// Connect to exit node
-    ///
-    /// Exit node in this case may be the VPN server or another meshnet node. In the former case,
-    /// new node is created and WireGuard tunnel is established to that node. In the latter case
-    /// the specified (matched by public key) meshnet node is "promoted" to be the exit node
-    pub fn connect_exit_node(&self, node: &ExitNode) -> Result {
+    /// Evaluate a hypothetical packet against the current firewall policy without processing it
+    pub fn evaluate_firewall(&self, packet: &PacketDescription) -> Result<Verdict> {
         self.async_runtime()?.block_on(async {
-            let node = node.clone();
-            let _wireguard_interface: Arc<DynamicWg> = task_exec!(self.rt()?, async move |rt| {
-                rt.connect_exit_node(&node).boxed().await?;
-                Ok(rt.entities.wireguard_interface.clone())
-            })
-            .await?;
-
-            Ok(())
+            let packet = packet.clone();
+            Ok(task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .entities
+                .firewall
+                .evaluate(&packet)))
+            .await?)
         })
     }
 
-    /// Connect to exit node with post-quantum secure tunnel
-    ///
-    /// Exit node in this case may only be the VPN server.
-    /// A new node is created and WireGuard post-quantum tunnel is established to that node.
-    /// Meshnet is disallowed when forming a post-quantum tunnel and if it's enabled
-    /// this call will error out.
-    pub fn connect_vpn_post_quantum(&self, node: &ExitNode) -> Result {
+    /// Register the provider of categories for the domains observed in DNS queries, or remove
+    /// it with `None`
+    pub fn set_category_provider(&self, provider: Option<Arc<dyn CategoryProvider>>) -> Result {
         self.async_runtime()?.block_on(async {
-            let node = node.clone();
-            let _wireguard_interface: Arc<DynamicWg> = task_exec!(self.rt()?, async move |rt| {
-                rt.connect_exit_node_pq(&node).boxed().await?;
-                Ok(rt.entities.wireguard_interface.clone())
+            task_exec!(&*self.rt().await?, async move |rt| {
+                rt.entities
+                    .firewall
+                    .domain_classifier()
+                    .set_provider(provider);
+                Ok(())
             })
             .await?;
-
             Ok(())
         })
     }
 
-    /// Disconnect from exit node
-    ///
-    /// Undoes the effects of calling device::connect_exit_node(), matching the node by public key
-    pub fn disconnect_exit_node(&self, node_key: &PublicKey) -> Result {
+    /// Categories of the domains observed in DNS queries, assigned by the registered provider
+    pub fn domain_categories(&self) -> Result<HashMap<String, Vec<String>>> {
         self.async_runtime()?.block_on(async {
-            let node_key = *node_key;
-            task_exec!(self.rt()?, async move |rt| {
-                Ok(rt.disconnect_exit_node(&node_key).boxed().await)
+            Ok(task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .entities
+                .firewall
+                .domain_classifier()
+                .classified_domains()
+                .into_iter()
+                .collect()))
+            .await?)
+        })
+    }
+
+    /// Register a receiver of the flows finished in the firewall, in addition to the collector
+    /// configured in features. Compiled out of the strict privacy builds.
+    #[cfg(not(feature = "strict_privacy"))]
+    pub fn add_flow_sink(&self, sink: Arc<dyn FlowSink>) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| {
+                rt.entities.firewall.flow_exporter().add_sink(sink);
+                Ok(())
             })
+            .await?;
+            Ok(())
+        })
+    }
+
+    /// Route the relay, API and other control connections through the `proxy`, `None` connects
+    /// directly. The relay connection is reopened to use it.
+    pub fn set_proxy(&self, proxy: Option<telio_sockets::Proxy>) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_proxy(proxy)
+                .boxed()
+                .await))
             .await?
         })
     }
 
-    /// Disconnects from any VPN and/or demotes any meshnet node to be a regular meshnet node
-    /// instead of exit node
-    pub fn disconnect_exit_nodes(&self) -> Result {
+    /// Answer the [Event::InboundApproval] about the inbound connections of the `public_key`
+    /// peer. Allowed peers are accepted in addition to the ones permitted by the meshnet config.
+    pub fn decide_inbound_connections(
+        &self,
+        public_key: PublicKey,
+        decision: InboundDecision,
+    ) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| {
-                Ok(rt.disconnect_exit_nodes().boxed().await)
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .entities
+                .firewall
+                .decide_inbound(public_key, decision)
+                .then_some(())
+                .ok_or(Error::InboundApprovalNotEnabled)))
+            .await?
+        })
+    }
+
+    pub fn start(&mut self, config: DeviceConfig) -> Result {
+        if self.is_running() {
+            return Err(Error::AlreadyStarted);
+        }
+
+        if let Some(name) = &config.name {
+            wg::interface_config::validate_name(name)
+                .map_err(|e| Error::AdapterConfig(e.to_string()))?;
+        }
+        if let Some(interface) = &config.interface {
+            interface
+                .validate()
+                .map_err(|e| Error::AdapterConfig(e.to_string()))?;
+        }
+
+        let startup_timeout = self
+            .features
+            .startup_timeout_seconds
+            .map(Duration::from_secs);
+        let restarter = match self.features.supervision {
+            Some(_) => Some(Restarter::new(self, &config)?),
+            None => None,
+        };
+        let restart_state = restarter.as_ref().map(Restarter::state);
+        let task = self
+            .async_runtime()?
+            .block_on(self.registry.clone().scope(async {
+                let mut runtime = Runtime::start(
+                    self.event.clone(),
+                    config,
+                    self.features.clone(),
+                    self.protect.clone(),
+                    self.analytics_sinks.clone(),
+                )
+                .boxed();
+                let mut runtime = match startup_timeout {
+                    // Components ready so far were already reported by the readiness events
+                    Some(bound) => match tokio::time::timeout(bound, &mut runtime).await {
+                        Ok(runtime) => runtime?,
+                        Err(_) => {
+                            // Dropping the start halfway would leak the adapter, the OS rules and
+                            // the tasks started so far, so it is left to finish and rolled back
+                            tokio::spawn(async move {
+                                if let Ok(runtime) = runtime.await {
+                                    telio_log_info!("Rolling back the start past its timeout");
+                                    runtime.stop().await;
+                                }
+                            });
+                            return Err(Error::StartupTimeout(bound));
+                        }
+                    },
+                    None => runtime.await?,
+                };
+                runtime.restart_state = restart_state;
+                Ok::<Task<Runtime>, Error>(Task::start(runtime))
+            }))?;
+        *self.async_runtime()?.block_on(self.rt.write()) = Some(task);
+
+        if let (Some(restarter), Some(supervision)) = (restarter, &self.features.supervision) {
+            match restarter.supervise(supervision) {
+                Ok(supervisor) => self.supervisor = Some(supervisor),
+                Err(e) => {
+                    self.stop();
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(persistence) = &self.features.firewall.conntrack_persistence {
+            if let Some(connections) = self.conntrack.restore(persistence) {
+                self.async_runtime()?.block_on(async {
+                    task_exec!(&*self.rt().await?, async move |rt| {
+                        rt.entities.firewall.import_connections(connections);
+                        Ok(())
+                    })
+                    .await?;
+                    Ok::<(), Error>(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// State for another process taking over this device, see [crate::handover]
+    #[cfg(not(feature = "strict_privacy"))]
+    pub fn export_handover_state(&self) -> Result<HandoverState> {
+        self.async_runtime()?.block_on(async {
+            Ok(
+                task_exec!(&*self.rt().await?, async move |rt| Ok(HandoverState {
+                    private_key: rt.requested_state.device_config.private_key.clone(),
+                    fwmark: rt.requested_state.device_config.fwmark,
+                    meshnet: rt.requested_state.meshnet_config.clone(),
+                    exit_node: rt.requested_state.exit_node.clone(),
+                    connections: rt.entities.firewall.export_connections(),
+                }))
+                .await?,
+            )
+        })
+    }
+
+    /// Start by taking over from another process, `config.tun` being the tun received together
+    /// with the `state`
+    #[cfg(not(feature = "strict_privacy"))]
+    pub fn start_from_handover(
+        &mut self,
+        mut config: DeviceConfig,
+        state: HandoverState,
+    ) -> Result {
+        config.private_key = state.private_key;
+        config.fwmark = state.fwmark.or(config.fwmark);
+        #[cfg(target_os = "linux")]
+        let fwmark = config.fwmark;
+        self.start(config)?;
+
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| {
+                rt.entities.firewall.import_connections(state.connections);
+                #[cfg(target_os = "linux")]
+                if let Some(fwmark) = fwmark {
+                    rt.set_fwmark(fwmark).boxed().await?;
+                }
+                if state.meshnet.is_some() {
+                    rt.set_config(&state.meshnet).boxed().await?;
+                }
+                if let Some(exit_node) = state.exit_node {
+                    rt.connect_exit_node(&exit_node).boxed().await?;
+                }
+                Ok(())
             })
+            .await?;
+            Ok(())
+        })
+    }
+
+    /// Encrypted snapshot of the session state, to be passed to [Device::start_with_session] after
+    /// a restart
+    pub fn export_session(&self) -> Result<Vec<u8>> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .export_session()
+                .await))
             .await?
         })
     }
 
-    fn rt(&self) -> Result<&Task<Runtime>> {
-        self.rt.as_ref().ok_or(Error::NotStarted)
+    /// Start, reconnecting with the session state exported before a restart. A stale snapshot,
+    /// or one exported by another device, is ignored and the device starts from scratch.
+    pub fn start_with_session(&mut self, config: DeviceConfig, session: &[u8]) -> Result {
+        let snapshot = SessionSnapshot::decrypt(session, &config.private_key)
+            .map_err(|e| telio_log_warn!("Starting without the previous session: {e}"))
+            .ok();
+        self.start(config)?;
+
+        if let Some(snapshot) = snapshot {
+            self.async_runtime()?.block_on(async {
+                task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                    .restore_session(snapshot)
+                    .await))
+                .await?
+            })?;
+        }
+        Ok(())
     }
 
-    fn async_runtime(&self) -> Result<&AsyncRuntime> {
-        match self.async_runtime.as_ref() {
-            Some(art) => Ok(art),
+    pub fn stop(&mut self) {
+        // Not to be restarted by the supervision while being stopped
+        if let Some(supervisor) = self.supervisor.take() {
+            supervisor.stop();
+        }
+        let rt = match &self.async_runtime {
+            Some(art) => art.block_on(self.rt.write()).take(),
+            None => None,
+        };
+        if let Some(rt) = rt {
+            if let Some(art) = &self.async_runtime {
+                if let Some(persistence) = &self.features.firewall.conntrack_persistence {
+                    if let Ok(connections) = art.block_on(task_exec!(&rt, async move |rt| Ok(rt
+                        .entities
+                        .firewall
+                        .export_connections())))
+                    {
+                        self.conntrack.save(persistence, connections);
+                    }
+                }
+                let _ = art.block_on(rt.stop());
+                self.flush_events();
+            }
+        }
+    }
+
+    /// Stop the device gracefully: tell the meshnet peers that this node is going away, refuse
+    /// new connections and wait up to the `timeout` for the established ones to finish before
+    /// stopping. Idle connections are not waited for: UDP and idle TCP connections are closed
+    /// only once they time out, so the waiting stops as soon as no traffic was let through for
+    /// a while. The device is stopped even when the draining fails.
+    pub fn shutdown_graceful(&mut self, timeout: Duration) -> Result {
+        let deadline = Instant::now() + timeout;
+        let drained = self.async_runtime().and_then(|art| {
+            art.block_on(async {
+                task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                    .start_draining(timeout)
+                    .await))
+                .await??;
+
+                let (mut drained_packets, mut quiet_since) = (0, Instant::now());
+                loop {
+                    let (active, packets) = task_exec!(&*self.rt().await?, async move |rt| {
+                        let firewall = &rt.entities.firewall;
+                        Ok((firewall.active_connections(), firewall.drained_packets()))
+                    })
+                    .await?;
+                    if active == 0 {
+                        telio_log_info!("Connections drained");
+                        break;
+                    }
+                    if packets != drained_packets {
+                        (drained_packets, quiet_since) = (packets, Instant::now());
+                    } else if quiet_since.elapsed() >= DRAIN_IDLE_PERIOD {
+                        telio_log_info!("{active} connections left idle after draining");
+                        break;
+                    }
+                    if Instant::now() >= deadline {
+                        telio_log_info!("{active} connections left after draining");
+                        break;
+                    }
+                    tokio::time::sleep(DRAIN_POLL_PERIOD).await;
+                }
+                Ok::<(), Error>(())
+            })
+        });
+
+        self.stop();
+        drained
+    }
+
+    fn flush_events(&self) {
+        if let Some(timeout) = self.features.flush_events_on_stop_timeout_seconds {
+            let start_time = Instant::now();
+            while !self.event.is_empty() {
+                std::thread::sleep(Duration::from_millis(100));
+                if timeout > 0 && start_time.elapsed().as_secs() >= timeout {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// [Windows only] Retrieve the UUID of the interface
+    ///
+    /// This methods retrieves the UUID of the virtual interface created by device::start() call
+    pub fn get_adapter_luid(&mut self) -> u64 {
+        if let Some(art) = &self.async_runtime {
+            let res: Result<u64> = art.block_on(async {
+                task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                    .get_adapter_luid()
+                    .await))
+                .await?
+            });
+            res.unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
+    pub fn shutdown_art(&mut self) {
+        let _ = self.async_runtime.take();
+    }
+
+    pub fn try_shutdown_art(&mut self, timeout: Duration) -> Result {
+        let art = self.async_runtime.take();
+        match art {
+            Some(art) => {
+                art.shutdown_timeout(timeout);
+                Ok(())
+            }
             None => Err(Error::NotStarted),
         }
     }
 
-    /// Enables DNS server
+    /// Configure the private key of the WireGuard interface
     ///
-    /// DNS server hosted on a virtual host within device can be used to resolve the domain names
-    /// of the meshnet nodes. If the DNS query sent to this server does not fall under .nord
-    /// top-level-domain, the query is forwarded to one of the `upstream_servers`.
-    pub fn enable_magic_dns(&self, upstream_servers: &[IpAddr]) -> Result {
+    /// This methods sets the private key of the WireGuard interface. Note that it
+    /// is only valid to call this method only on device instance which is currently
+    /// not running. E.g. either before start()'ing or after stop()'ing it.
+    pub fn set_private_key(&self, private_key: &SecretKey) -> Result {
+        let private_key = private_key.clone(); //Going into async context, therefore just copy for lifetimes
         self.async_runtime()?.block_on(async {
-            let upstream_servers = upstream_servers.to_vec();
-            task_exec!(self.rt()?, async move |rt| {
-                Ok(rt.start_dns(&upstream_servers).boxed().await)
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.set_private_key(&private_key).boxed().await)
             })
             .await?
         })
     }
 
-    /// Disables DNS server
+    /// Confirm that the announced key of an ongoing key rotation was registered with the backend
     ///
-    /// Undoes the effects of `device::enable_magic_dns()` call
-    pub fn disable_magic_dns(&self) -> Result {
+    /// The device switches to the new key once the configured grace period passes.
+    pub fn confirm_key_rotation(&self, public_key: &PublicKey) -> Result {
+        let public_key = *public_key;
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt.stop_dns().boxed().await)).await?
+            task_exec!(&*self.rt().await?, async move |rt| Ok(
+                rt.confirm_key_rotation(&public_key)
+            ))
+            .await?
         })
     }
 
-    /// A artificial method causing panics
+    /// Set the (u)tun file descriptor to be used by the adapter
+    pub fn set_tun(&self, tun: Tun) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| {
+                let _guard = rt.entities.network_monitor.pause();
+                Ok(rt.set_tun(tun).boxed().await)
+            })
+            .await?
+        })
+    }
+
+    /// Actively classify the NAT the device is behind, using the STUN servers of the relays.
     ///
-    /// Used only for testing purposes
-    pub fn _panic(&self) -> Result {
+    /// Takes a few seconds, the device keeps working in the meantime.
+    pub fn detect_nat(&self) -> Result<NatReport> {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt._panic().await)).await?
+            let (socket_pool, servers, binding_lifetime_s) =
+                task_exec!(&*self.rt().await?, async move |rt| {
+                    Ok((
+                        rt.entities.socket_pool.clone(),
+                        rt.stun_servers(),
+                        rt.keepalive_tuner
+                            .as_ref()
+                            .and_then(|tuner| tuner.binding_lifetime()),
+                    ))
+                })
+                .await?;
+
+            if servers.is_empty() {
+                return Err(Error::FailedNatInfoRecover(IoError::new(
+                    io::ErrorKind::NotFound,
+                    "No relay with a STUN server",
+                )));
+            }
+
+            detect_nat(
+                &socket_pool,
+                &servers,
+                &local_addresses(),
+                binding_lifetime_s,
+            )
+            .await
+            .map_err(Error::FailedNatInfoRecover)
         })
     }
 
-    /// Retrieves a reference to SocketPool. Use this instead of SocketPool::default() when possible
-    pub fn get_socket_pool(&self) -> Result<Arc<SocketPool>> {
+    /// Register a custom endpoint discovery mechanism for meshnet direct connections.
+    ///
+    /// Its candidates are used from the next time direct connections are set up, so it
+    /// should be registered before meshnet is configured.
+    pub fn add_endpoint_discovery(&self, discovery: Arc<dyn EndpointDiscovery>) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| {
+                telio_log_info!("Registering endpoint discovery: {}", discovery.name());
+                rt.requested_state.endpoint_discoveries.push(discovery);
+                Ok(())
+            })
+            .await?;
+            Ok(())
+        })
+    }
+
+    /// Change the MTU of the tunnel interface while the device is running
+    pub fn set_mtu(&self, mtu: u32) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt.get_socket_pool().await)).await?
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.entities.wireguard_interface.set_mtu(mtu).await)
+            })
+            .await??;
+            Ok(())
         })
     }
 
-    pub fn trigger_analytics_event(&self) -> Result<()> {
+    /// Configure the external interface list on adapter.
+    ///
+    /// This methods sets the private key of the WireGuard interface. Note that it
+    /// is only valid to call this method only on device instance which is currently
+    /// not running. E.g. either before start()'ing or after stop()'ing it.
+    pub fn set_ext_if_filter(&self, ext_if_filter: Vec<String>) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt
-                .trigger_analytics_event()
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.set_ext_if_filter(ext_if_filter).boxed().await)
+            })
+            .await?
+        })
+    }
+
+    /// Retrieves currently configured private key for the interface
+    pub fn get_private_key(&self) -> Result<SecretKey> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .get_private_key()
                 .await))
             .await?
         })
     }
 
-    pub fn trigger_qos_collection(&self) -> Result<()> {
+    /// [Linux only] Configure the fwmark used for encapsulated packets
+    #[cfg(any(target_os = "linux", doc))]
+    #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+    pub fn set_fwmark(&self, fwmark: u32) -> Result {
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(rt
-                .trigger_qos_collection()
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_fwmark(fwmark)
+                .boxed()
                 .await))
             .await?
         })
     }
 
-    pub fn receive_ping(&self) -> Result<String> {
+    /// Configure meshnet
+    ///
+    /// This method sets the desired meshnet configuration
+    pub fn set_config(&self, config: &Option<Config>) -> Result {
+        let config = config.clone();
         self.async_runtime()?.block_on(async {
-            task_exec!(self.rt()?, async move |rt| Ok(
-                Box::pin(rt.receive_ping()).await
-            ))
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .set_config(&config)
+                .boxed()
+                .await))
             .await?
         })
     }
-}
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        if self.features.lana.is_some() {
-            let _ = telio_lana::deinit_lana();
-        }
+    /// Check the meshnet `config` for overlapping IPs, duplicate keys, address families which
+    /// are not enabled and too many peers, without applying it
+    pub fn validate_config(&self, config: &Config) -> ValidationReport {
+        validate_meshnet_config(config, self.features.ipv6, self.features.max_meshnet_peers)
     }
-}
 
-impl RequestedState {
-    // A Convenience function to build a DNS records list from the requested meshnet config
-    // This function does not take into account whether DNS is enabled or not. It simply builds a
-    // list of hostname<->IP pairs out of currently requested meshnet nodes. If meshnet is disabled,
-    // empty list is returned.
-    pub fn collect_dns_records(&self) -> Records {
-        self.meshnet_config
-            .clone()
-            .map_or(Vec::new(), |cfg| {
-                [
-                    cfg.peers.map_or(Vec::new(), |peers| peers),
-                    vec![Peer {
-                        base: cfg.this,
+    /// Notify device about network change event
+    ///
+    /// In some cases integrators may have better knowledge of the network state or state changes,
+    /// therefore this method assumes, that a device has migrated from one network to the other,
+    /// and adapts whatever is needed
+    pub fn notify_network_change(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.notify_network_change().await)
+            })
+            .await?
+        })
+    }
+
+    /// Stop the keepalives, the relay connection, the endpoint discovery and the periodic work of
+    /// the device, while keeping the adapter, the keys and the peers in place. Meant for the
+    /// periods the app is suspended, [Device::resume] brings everything back much faster than
+    /// a stop and start.
+    pub fn pause(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .pause()
+                .boxed()
+                .await))
+            .await?
+        })
+    }
+
+    /// Resume the device paused by [Device::pause]
+    pub fn resume(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .resume()
+                .boxed()
+                .await))
+            .await?
+        })
+    }
+
+    pub fn notify_sleep(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .notify_sleep()
+                .await))
+            .await?
+        })
+    }
+
+    pub fn notify_wakeup(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .notify_wakeup()
+                .await))
+            .await?
+        })
+    }
+
+    /// Connect to exit node
+    ///
+    /// Exit node in this case may be the VPN server or another meshnet node. In the former case,
+    /// new node is created and WireGuard tunnel is established to that node. In the latter case
+    /// the specified (matched by public key) meshnet node is "promoted" to be the exit node
+    pub fn connect_exit_node(&self, node: &ExitNode) -> Result {
+        self.async_runtime()?.block_on(async {
+            let node = node.clone();
+            let _wireguard_interface: Arc<DynamicWg> =
+                task_exec!(&*self.rt().await?, async move |rt| {
+                    rt.connect_exit_node(&node).boxed().await?;
+                    Ok(rt.entities.wireguard_interface.clone())
+                })
+                .await?;
+
+            Ok(())
+        })
+    }
+
+    /// Connect through a chain of two exit nodes
+    ///
+    /// Traffic is encrypted for the `exit` node first and then tunneled through the `entry` node,
+    /// so the `entry` node cannot see the traffic and the `exit` node does not know the device
+    /// address. Both nodes have to be VPN servers with known endpoints.
+    pub fn set_exit_chain(&self, entry: &ExitNode, exit: &ExitNode) -> Result {
+        self.async_runtime()?.block_on(async {
+            let (entry, exit) = (entry.clone(), exit.clone());
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.set_exit_chain(&entry, &exit).boxed().await)
+            })
+            .await?
+        })
+    }
+
+    /// Connect to exit node with obfuscated WireGuard handshakes
+    ///
+    /// Exit node in this case may only be a VPN server supporting AmneziaWG obfuscation, using
+    /// the parameters configured in `wireguard.obfuscation` features.
+    pub fn connect_exit_node_obfuscated(&self, node: &ExitNode) -> Result {
+        self.async_runtime()?.block_on(async {
+            let node = node.clone();
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.connect_exit_node_obfuscated(&node).boxed().await)
+            })
+            .await?
+        })
+    }
+
+    /// Connect to exit node with post-quantum secure tunnel
+    ///
+    /// Exit node in this case may only be the VPN server.
+    /// A new node is created and WireGuard post-quantum tunnel is established to that node.
+    /// Meshnet is disallowed when forming a post-quantum tunnel and if it's enabled
+    /// this call will error out.
+    pub fn connect_vpn_post_quantum(&self, node: &ExitNode) -> Result {
+        self.async_runtime()?.block_on(async {
+            let node = node.clone();
+            let _wireguard_interface: Arc<DynamicWg> =
+                task_exec!(&*self.rt().await?, async move |rt| {
+                    rt.connect_exit_node_pq(&node).boxed().await?;
+                    Ok(rt.entities.wireguard_interface.clone())
+                })
+                .await?;
+
+            Ok(())
+        })
+    }
+
+    /// Disconnect from exit node
+    ///
+    /// Undoes the effects of calling device::connect_exit_node(), matching the node by public key
+    pub fn disconnect_exit_node(&self, node_key: &PublicKey) -> Result {
+        self.async_runtime()?.block_on(async {
+            let node_key = *node_key;
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.disconnect_exit_node(&node_key).boxed().await)
+            })
+            .await?
+        })
+    }
+
+    /// Disconnects from any VPN and/or demotes any meshnet node to be a regular meshnet node
+    /// instead of exit node
+    pub fn disconnect_exit_nodes(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.disconnect_exit_nodes().boxed().await)
+            })
+            .await?
+        })
+    }
+
+    async fn rt(&self) -> Result<RwLockReadGuard<'_, Task<Runtime>>> {
+        RwLockReadGuard::try_map(self.rt.read().await, Option::as_ref)
+            .map_err(|_| Error::NotStarted)
+    }
+
+    fn async_runtime(&self) -> Result<&AsyncRuntime> {
+        match self.async_runtime.as_ref() {
+            Some(art) => Ok(art),
+            None => Err(Error::NotStarted),
+        }
+    }
+
+    /// Enables DNS server
+    ///
+    /// DNS server hosted on a virtual host within device can be used to resolve the domain names
+    /// of the meshnet nodes. If the DNS query sent to this server does not fall under .nord
+    /// top-level-domain, the query is forwarded to one of the `upstream_servers`.
+    pub fn enable_magic_dns(&self, upstream_servers: &[IpAddr]) -> Result {
+        self.async_runtime()?.block_on(async {
+            let upstream_servers = upstream_servers.to_vec();
+            task_exec!(&*self.rt().await?, async move |rt| {
+                Ok(rt.start_dns(&upstream_servers).boxed().await)
+            })
+            .await?
+        })
+    }
+
+    /// Disables DNS server
+    ///
+    /// Undoes the effects of `device::enable_magic_dns()` call
+    pub fn disable_magic_dns(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .stop_dns()
+                .boxed()
+                .await))
+            .await?
+        })
+    }
+
+    /// A artificial method causing panics
+    ///
+    /// Used only for testing purposes
+    pub fn _panic(&self) -> Result {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt._panic().await)).await?
+        })
+    }
+
+    /// Retrieves a reference to SocketPool. Use this instead of SocketPool::default() when possible
+    pub fn get_socket_pool(&self) -> Result<Arc<SocketPool>> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .get_socket_pool()
+                .await))
+            .await?
+        })
+    }
+
+    pub fn trigger_analytics_event(&self) -> Result<()> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .trigger_analytics_event()
+                .await))
+            .await?
+        })
+    }
+
+    pub fn trigger_qos_collection(&self) -> Result<()> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(rt
+                .trigger_qos_collection()
+                .await))
+            .await?
+        })
+    }
+
+    pub fn receive_ping(&self) -> Result<String> {
+        self.async_runtime()?.block_on(async {
+            task_exec!(&*self.rt().await?, async move |rt| Ok(Box::pin(
+                rt.receive_ping()
+            )
+            .await))
+            .await?
+        })
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        if self.features.lana.is_some() {
+            let _ = telio_lana::deinit_lana();
+        }
+    }
+}
+
+// Without IPv6 the meshnet addresses of that family are not routed to the peers, so answering
+// AAAA queries with them would only make dual stack apps try an unreachable address first.
+fn retain_reachable_records(mut records: Records, ipv6: bool) -> Records {
+    if !ipv6 {
+        records.retain(|_, ips| {
+            ips.retain(|ip| ip.is_ipv4());
+            !ips.is_empty()
+        });
+    }
+    records
+}
+
+impl RequestedState {
+    // A Convenience function to build a DNS records list from the requested meshnet config
+    // This function does not take into account whether DNS is enabled or not. It simply builds a
+    // list of hostname<->IP pairs out of currently requested meshnet nodes. If meshnet is disabled,
+    // empty list is returned.
+    pub fn collect_dns_records(&self) -> Records {
+        self.meshnet_config
+            .clone()
+            .map_or(Vec::new(), |cfg| {
+                [
+                    cfg.peers.map_or(Vec::new(), |peers| peers),
+                    vec![Peer {
+                        base: cfg.this,
                         ..Default::default()
                     }],
                 ]
@@ -968,6 +2068,26 @@ impl RequestedState {
 }
 
 impl MeshnetEntities {
+    /// Names of the tasks restarted along with the meshnet
+    const TASKS: &'static [&'static str] = &[
+        "Multiplexer",
+        "DerpRelay",
+        "IngressProxy",
+        "EgressProxy",
+        "LocalInterfacesEndpointProvider",
+        "StunEndpointProvider",
+        "UpnpEndpointProvider",
+        "PortMappingEndpointProvider",
+        "CustomEndpointProvider",
+        "CrossPingCheck",
+        "UpgradeSync",
+        "SessionKeeper",
+        "CapabilityExchange",
+        "Departures",
+        "Starcast",
+        "StarcastTransport",
+    ];
+
     async fn stop(mut self) -> MeshnetEntitiesLastState {
         macro_rules! stop_entity {
             ($entity: expr, $name: expr) => {{
@@ -993,6 +2113,9 @@ impl MeshnetEntities {
         }
 
         if let Some(direct) = self.direct.take() {
+            if let Some(lan_discovery) = direct.lan_discovery {
+                lan_discovery.stop().await;
+            }
             // Arc dependency on CrossPingCheck
             stop_arc_entity!(direct.upgrade_sync, "UpgradeSync");
             // Arc dependency on endpoint providers
@@ -1009,12 +2132,20 @@ impl MeshnetEntities {
             if let Some(upnp) = direct.upnp_endpoint_provider {
                 stop_arc_entity!(upnp, "UpnpEndpointProvider");
             }
+            if let Some(port_mapping) = direct.port_mapping_endpoint_provider {
+                stop_arc_entity!(port_mapping, "PortMappingEndpointProvider");
+            }
+            if let Some(custom) = direct.custom_endpoint_provider {
+                stop_arc_entity!(custom, "CustomEndpointProvider");
+            }
         }
 
         if let Some(sk) = self.session_keeper {
             stop_arc_entity!(sk, "SessionKeeper");
         }
 
+        stop_arc_entity!(self.capability_exchange, "CapabilityExchange");
+        stop_arc_entity!(self.departures, "Departures");
         stop_arc_entity!(self.multiplexer, "Multiplexer");
         stop_arc_entity!(self.derp, "Derp");
 
@@ -1035,11 +2166,41 @@ impl Runtime {
         config: DeviceConfig,
         features: Features,
         protect: Option<Arc<dyn Protector>>,
+        mut analytics_sinks: Vec<Arc<dyn AnalyticsSink>>,
     ) -> Result<Self> {
-        let firewall = Arc::new(StatefullFirewall::new(
-            features.ipv6,
-            features.firewall.clone(),
-        ));
+        let mut startup = StartupSequence::new(libtelio_wide_event_publisher.clone());
+
+        let mut firewall = StatefullFirewall::new(features.ipv6, features.firewall.clone());
+        if features.accounting.is_some() {
+            firewall = firewall.with_packet_counters();
+        }
+        let firewall = Arc::new(firewall);
+        startup.ready(Component::Firewall)?;
+
+        #[cfg(feature = "otlp")]
+        let otlp = features.otlp.as_ref().and_then(|config| {
+            otlp::OtlpTelemetry::start(config)
+                .map_err(|e| telio_log_warn!("Failed to start the OTLP exporter: {e}"))
+                .ok()
+        });
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = &otlp {
+            analytics_sinks.push(otlp.exporter());
+            firewall.flow_exporter().add_sink(otlp.flow_sink());
+        }
+        #[cfg(not(feature = "otlp"))]
+        if features.otlp.is_some() {
+            telio_log_warn!("OTLP export is configured, but libtelio is built without it");
+        }
+
+        let events = libtelio_wide_event_publisher.clone();
+        firewall.set_approval_observer(Some(Arc::new(move |public_key, destination| {
+            let body = InboundApproval {
+                public_key,
+                destination,
+            };
+            let _ = events.send(Box::new(Event::InboundApproval { body }));
+        })));
 
         let firewall_filter_inbound_packets = {
             let fw = firewall.clone();
@@ -1065,6 +2226,17 @@ impl Runtime {
         let firewall_observer_ptr: Arc<dyn LocalInterfacesObserver> = firewall.clone();
         network_monitor.register_local_interfaces_observer(Arc::downgrade(&firewall_observer_ptr));
 
+        let interfaces_change = Chan::new(1);
+        let interfaces_change_observer = features
+            .fast_reconnect
+            .filter(|fast_reconnect| fast_reconnect.follow_interface_changes)
+            .map(|_| {
+                let observer: Arc<dyn LocalInterfacesObserver> =
+                    Arc::new(interfaces_change.tx.clone());
+                network_monitor.register_local_interfaces_observer(Arc::downgrade(&observer));
+                observer
+            });
+
         let socket_pool = Arc::new({
             if let Some(protect) = protect.clone() {
                 SocketPool::new(protect)
@@ -1079,6 +2251,17 @@ impl Runtime {
             socket_pool.set_ext_if_filter(ext_if_filter);
         }
 
+        if let Some(collector) = features
+            .firewall
+            .flow_export
+            .and_then(|flow_export| flow_export.collector)
+        {
+            match flow_collector::IpfixCollector::connect(&socket_pool, collector).await {
+                Ok(sink) => firewall.flow_exporter().add_sink(Arc::new(sink)),
+                Err(e) => telio_log_warn!("Failed to set up flow collector {collector}: {e}"),
+            }
+        }
+
         #[allow(clippy::manual_map)]
         let link_detection = if let Some(ld_config) = features.link_detection {
             #[cfg(target_os = "windows")]
@@ -1123,6 +2306,32 @@ impl Runtime {
 
         let requested_device_config = RequestedDeviceConfig::from(&config);
 
+        // Policy goes in place before the adapter, so no traffic can bypass it. The start fails
+        // rather than running without the killswitch or exit node rules which were asked for.
+        #[cfg(windows)]
+        let killswitch = match features.firewall.killswitch {
+            Some(killswitch) => Some(WfpKillswitch::new(killswitch).map_err(|e| {
+                telio_log_error!("Failed to initialize WFP killswitch: {e}");
+                e
+            })?),
+            None => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        let exit_node_rules = match features.firewall.exit_node_rules {
+            Some(exit_node_rules) => Some(Arc::new(
+                tokio::task::spawn_blocking(move || ExitNodeRules::new(exit_node_rules))
+                    .await?
+                    .map_err(|e| {
+                        telio_log_error!("Failed to initialize exit node rules: {e}");
+                        e
+                    })?,
+            )),
+            None => None,
+        };
+
+        startup.ready(Component::PolicyRules)?;
+
         // tests runtime use wg::MockedAdapter
         cfg_if! {
             if #[cfg(not(test))] {
@@ -1151,6 +2360,8 @@ impl Runtime {
                         skt_buffer_size : Runtime::sanitize_neptun_config(features.wireguard.skt_buffer_size, config.adapter.clone()),
                         inter_thread_channel_size : Runtime::sanitize_neptun_config(features.wireguard.inter_thread_channel_size, config.adapter.clone()),
                         max_inter_thread_batched_pkts : Runtime::sanitize_neptun_config(features.wireguard.max_inter_thread_batched_pkts, config.adapter.clone()),
+                        worker_threads: Runtime::sanitize_neptun_config(features.wireguard.worker_threads, config.adapter.clone()),
+                        multi_queue_tun: Runtime::sanitize_neptun_config(features.wireguard.multi_queue_tun, config.adapter.clone()),
                     },
                     link_detection,
                     Duration::from_millis(features.wireguard.polling.wireguard_polling_period.into()),
@@ -1178,6 +2389,8 @@ impl Runtime {
                             skt_buffer_size: features.wireguard.skt_buffer_size,
                             inter_thread_channel_size: features.wireguard.inter_thread_channel_size,
                             max_inter_thread_batched_pkts: features.wireguard.max_inter_thread_batched_pkts,
+                            worker_threads: features.wireguard.worker_threads,
+                            multi_queue_tun: features.wireguard.multi_queue_tun,
                         }
                     ).await;
 
@@ -1188,6 +2401,12 @@ impl Runtime {
         wireguard_interface
             .set_secret_key(config.private_key.clone())
             .await?;
+        startup.ready(Component::Adapter)?;
+
+        if let Some(interface) = config.interface {
+            wireguard_interface.configure_interface(interface).await?;
+        }
+        startup.ready(Component::AdapterAddresses)?;
 
         #[cfg(windows)]
         {
@@ -1198,19 +2417,34 @@ impl Runtime {
         #[cfg(test)]
         adapter.lock().await.checkpoint();
 
+        #[cfg(not(feature = "strict_privacy"))]
+        if telio_lana::is_lana_initialized() {
+            analytics_sinks.push(Arc::new(LanaSink));
+        }
+
+        // Nothing is collected for the sinks, nor are the nurse pings sent to the peers
+        #[cfg(feature = "strict_privacy")]
+        if !analytics_sinks.is_empty() || features.nurse.is_some() {
+            telio_log_warn!("Analytics are configured, but libtelio is built with strict privacy");
+            analytics_sinks.clear();
+        }
+
         let aggregator = Arc::new(ConnectivityDataAggregator::new(
             AggregatorConfig::new(
                 &features
                     .nurse
                     .clone()
-                    .filter(|_| telio_lana::is_lana_initialized())
+                    .filter(|_| !analytics_sinks.is_empty())
                     .unwrap_or_default(),
             ),
             wireguard_interface.clone(),
             config.private_key.public(),
         ));
 
-        let nurse = if telio_lana::is_lana_initialized() {
+        #[cfg(feature = "strict_privacy")]
+        let nurse = None;
+        #[cfg(not(feature = "strict_privacy"))]
+        let nurse = if !analytics_sinks.is_empty() {
             if let Some(nurse_features) = &features.nurse {
                 let nurse_io = NurseIo {
                     wg_event_channel: &libtelio_wide_event_publisher,
@@ -1218,6 +2452,7 @@ impl Runtime {
                     config_update_channel: config_update_ch.clone(),
                     collection_trigger_channel: collection_trigger_ch.clone(),
                     qos_trigger_channel: qos_trigger_ch.clone(),
+                    analytics_sinks,
                 };
                 let nurse_config = NurseConfig::new(nurse_features);
                 telio_log_debug!(
@@ -1241,7 +2476,7 @@ impl Runtime {
                 None
             }
         } else {
-            telio_log_debug!("lana not initialized");
+            telio_log_debug!("lana not initialized and no analytics sinks registered");
             None
         };
 
@@ -1273,6 +2508,42 @@ impl Runtime {
 
         let polling_interval = interval(Duration::from_secs(5));
 
+        let key_rotation = features.key_rotation.map(|key_rotation| {
+            KeyRotation::new(
+                requested_state.device_config.private_key.public(),
+                Duration::from_secs(key_rotation.interval_s),
+                Duration::from_secs(key_rotation.grace_period_s),
+                Instant::now(),
+            )
+        });
+
+        let keepalive_tuner = features
+            .wireguard
+            .adaptive_keepalive
+            .map(|adaptive_keepalive| {
+                let mut tuner = KeepaliveTuner::new(
+                    adaptive_keepalive,
+                    requested_state.keepalive_periods.direct,
+                );
+                tuner.set_network(current_network_id());
+                tuner
+            });
+
+        let relay_health_check = features.derp.as_ref().and_then(|derp| derp.health_check);
+        #[cfg(not(feature = "strict_privacy"))]
+        let relay_health = relay_health_check
+            .map(|config| RelayHealthMonitor::start(socket_pool.clone(), &config));
+        // The probes reach every relay server in the list, not only the connected one
+        #[cfg(feature = "strict_privacy")]
+        let relay_health: Option<RelayHealthMonitor> = {
+            if relay_health_check.is_some() {
+                telio_log_warn!(
+                    "Relay health checks are configured, but libtelio is built with strict privacy"
+                );
+            }
+            None
+        };
+
         let (error_notification_service, error_notification_service_subscriber) =
             if let Some(error_notification_service) = &features.error_notification_service {
                 telio_log_info!("Will create ENS");
@@ -1287,6 +2558,24 @@ impl Runtime {
                 (None, Chan::new(1).rx)
             };
 
+        let accounting = features
+            .accounting
+            .as_ref()
+            .map(|config| TrafficAccounting::new(config, conntrack_persistence::unix_time()));
+
+        #[cfg(feature = "health_endpoint")]
+        let health_endpoint = match features.health_endpoint.as_ref() {
+            Some(config) => health_endpoint::HealthEndpoint::start(config)
+                .await
+                .map_err(|e| telio_log_warn!("Failed to start the health endpoint: {e}"))
+                .ok(),
+            None => None,
+        };
+        #[cfg(not(feature = "health_endpoint"))]
+        if features.health_endpoint.is_some() {
+            telio_log_warn!("Health endpoint is configured, but libtelio is built without it");
+        }
+
         Ok(Runtime {
             features,
             requested_state,
@@ -1300,7 +2589,21 @@ impl Runtime {
                 aggregator: aggregator.clone(),
                 postquantum_wg,
                 network_monitor,
+                interfaces_change_observer,
                 error_notification_service,
+                #[cfg(windows)]
+                killswitch,
+                #[cfg(target_os = "linux")]
+                exit_node_rules,
+                #[cfg(target_os = "linux")]
+                split_tunnel_rules: None,
+                #[cfg(target_os = "linux")]
+                steering_rules: None,
+                #[cfg(target_os = "linux")]
+                class_tunnels: Default::default(),
+                exit_chain: None,
+                exit_obfuscation: None,
+                exit_stream: None,
             },
             event_listeners: EventListeners {
                 wg_endpoint_publish_event_subscriber: wg_endpoint_publish_events.rx,
@@ -1310,6 +2613,8 @@ impl Runtime {
                 stun_server_subscriber: stun_server_events.rx,
                 post_quantum_subscriber: post_quantum.rx,
                 error_notification_service_subscriber,
+                interfaces_change_subscriber: interfaces_change.rx,
+                network_path_subscriber: NETWORK_PATH_BROADCAST.subscribe(),
             },
             event_publishers: EventPublishers {
                 libtelio_event_publisher: libtelio_wide_event_publisher,
@@ -1323,6 +2628,26 @@ impl Runtime {
             },
             polling_interval,
             last_transmitted_event: Default::default(),
+            key_rotation,
+            keepalive_tuner,
+            nat_binding_probe: None,
+            usage_baseline: (Instant::now(), ProcessUsage::current()),
+            exit_transport: None,
+            exit_failover: None,
+            fast_reconnect: None,
+            network_path: current_network_path(),
+            clock_monitor: ClockMonitor::new(),
+            relay_health,
+            restart_state: None,
+            startup,
+            connectivity: Default::default(),
+            relay_pinning_unsatisfied: false,
+            restored_session: None,
+            accounting,
+            #[cfg(feature = "otlp")]
+            otlp,
+            #[cfg(feature = "health_endpoint")]
+            health_endpoint,
             #[cfg(test)]
             test_env: wg::tests::Env {
                 analytics: analytics_ch,
@@ -1365,6 +2690,27 @@ impl Runtime {
                 .await;
         }
 
+        // Start capability exchange, announcing the optional features enabled locally
+        let capability_exchange = Arc::new(CapabilityExchange::new(
+            multiplexer.get_channel().await?,
+            std::iter::once(PeerCapability::ReplayProtection)
+                .chain(self.features.ipv6.then_some(PeerCapability::Ipv6Candidates)),
+        ));
+
+        // Start departures, the goodbyes of the peers are published as events
+        let events = self.event_publishers.libtelio_event_publisher.clone();
+        let departures = Arc::new(Departures::new(
+            multiplexer.get_channel().await?,
+            Some(Arc::new(move |public_key, drain_timeout: Duration| {
+                let body = Connectivity::PeerLeaving {
+                    public_key,
+                    drain_timeout_ms: drain_timeout.as_millis() as u64,
+                };
+                telio_log_debug!("Connectivity change: {body:?}");
+                let _ = events.send(Box::new(Event::Connectivity { body }));
+            })),
+        ));
+
         let session_keeper = {
             match SessionKeeper::start(
                 self.entities.socket_pool.clone(),
@@ -1399,181 +2745,923 @@ impl Runtime {
                 // Default is all providers
                 match direct.providers.as_ref().map(|p| p.contains(&provider)) {
                     Some(prov) => prov,
-                    None => provider != Upnp,
+                    None => provider != Upnp && provider != PortMapping,
                 }
             };
 
             use telio_model::features::EndpointProvider::*;
 
-            let ping_pong_tracker = Arc::new(Mutex::new(PingPongHandler::new(
-                self.requested_state.device_config.private_key.clone(),
-            )));
-            let mut endpoint_providers: Vec<Arc<dyn EndpointProvider>> = Vec::new();
-            if direct.endpoint_interval_secs == 0 {
-                return Err(Error::PollingPeriodZero);
+            let ping_pong_tracker = Arc::new(Mutex::new(PingPongHandler::new(
+                self.requested_state.device_config.private_key.clone(),
+            )));
+            let mut endpoint_providers: Vec<Arc<dyn EndpointProvider>> = Vec::new();
+            if direct.endpoint_interval_secs == 0 {
+                return Err(Error::PollingPeriodZero);
+            }
+            // Create Local Interface Endpoint Provider
+            let local_interfaces_endpoint_provider = if has_provider(Local) {
+                let udp_socket_v6 = if direct.ipv6_candidates {
+                    self.entities
+                        .socket_pool
+                        .new_external_udp((Ipv6Addr::UNSPECIFIED, 0), None)
+                        .await
+                        .map_err(|e| {
+                            telio_log_warn!("Skipping IPv6 endpoint candidates: {e}");
+                        })
+                        .ok()
+                } else {
+                    None
+                };
+                let ep = Arc::new(LocalInterfacesEndpointProvider::new(
+                    self.entities
+                        .socket_pool
+                        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+                        .await?,
+                    udp_socket_v6,
+                    self.entities.wireguard_interface.clone(),
+                    Duration::from_secs(direct.endpoint_interval_secs),
+                    ping_pong_tracker.clone(),
+                ));
+                endpoint_providers.push(ep.clone());
+                Some(ep)
+            } else {
+                None
+            };
+
+            // Create Stun Endpoint Provider
+            let stun_endpoint_provider = if has_provider(Stun) {
+                let ep = Arc::new(StunEndpointProvider::start(
+                    self.entities.wireguard_interface.clone(),
+                    ExponentialBackoffBounds {
+                        initial: Duration::from_secs(direct.endpoint_interval_secs),
+                        maximal: Some(Duration::from_secs(120)),
+                    },
+                    ping_pong_tracker.clone(),
+                    self.event_publishers.stun_server_publisher.clone(),
+                    self.features
+                        .direct
+                        .clone()
+                        .unwrap_or_default()
+                        .endpoint_providers_optimization
+                        .unwrap_or_default()
+                        .optimize_direct_upgrade_stun,
+                )?);
+                endpoint_providers.push(ep.clone());
+                Some(ep)
+            } else {
+                None
+            };
+
+            // Create Upnp Endpoint Provider
+            let upnp_endpoint_provider = if has_provider(Upnp) {
+                let ep = Arc::new(UpnpEndpointProvider::start(
+                    self.entities
+                        .socket_pool
+                        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+                        .await?,
+                    self.entities.wireguard_interface.clone(),
+                    ExponentialBackoffBounds {
+                        initial: Duration::from_secs(direct.endpoint_interval_secs),
+                        maximal: Some(Duration::from_secs(120)),
+                    },
+                    ping_pong_tracker.clone(),
+                    direct
+                        .endpoint_providers_optimization
+                        .as_ref()
+                        .unwrap_or(&Default::default())
+                        .optimize_direct_upgrade_upnp,
+                    Duration::from_secs(
+                        direct
+                            .upnp_features
+                            .as_ref()
+                            .unwrap_or(&Default::default())
+                            .lease_duration_s as u64,
+                    ),
+                )?);
+                endpoint_providers.push(ep.clone());
+                Some(ep)
+            } else {
+                None
+            };
+
+            // Create NAT-PMP / PCP Endpoint Provider
+            let port_mapping_endpoint_provider = if has_provider(PortMapping) {
+                let ep = Arc::new(PortMappingEndpointProvider::start(
+                    self.entities.socket_pool.clone(),
+                    self.entities
+                        .socket_pool
+                        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+                        .await?,
+                    self.entities.wireguard_interface.clone(),
+                    ExponentialBackoffBounds {
+                        initial: Duration::from_secs(direct.endpoint_interval_secs),
+                        maximal: Some(Duration::from_secs(120)),
+                    },
+                    ping_pong_tracker.clone(),
+                    direct
+                        .port_mapping_features
+                        .as_ref()
+                        .unwrap_or(&Default::default()),
+                )?);
+                endpoint_providers.push(ep.clone());
+                Some(ep)
+            } else {
+                None
+            };
+
+            // Create Endpoint Provider for the registered discovery mechanisms
+            let custom_endpoint_provider = if self.requested_state.endpoint_discoveries.is_empty() {
+                None
+            } else {
+                let ep = Arc::new(CustomEndpointProvider::new(
+                    self.entities
+                        .socket_pool
+                        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+                        .await?,
+                    self.entities.wireguard_interface.clone(),
+                    Duration::from_secs(direct.endpoint_interval_secs),
+                    ping_pong_tracker.clone(),
+                    self.requested_state.endpoint_discoveries.clone(),
+                ));
+                endpoint_providers.push(ep.clone());
+                Some(ep)
+            };
+
+            // Subscribe to endpoint providers' events
+            for endpoint_provider in &endpoint_providers {
+                endpoint_provider
+                    .subscribe_for_endpoint_candidates_change_events(
+                        endpoint_publish_events.tx.clone(),
+                    )
+                    .await;
+                endpoint_provider
+                    .subscribe_for_pong_events(pong_rxed_events.tx.clone())
+                    .await;
+            }
+
+            let last_rx_time_provider: Option<Arc<dyn TimeSinceLastRxProvider>> =
+                if let Some(skip_unresponsive_peers) = &direct.skip_unresponsive_peers {
+                    Some(Arc::new(WireGuardTimeSinceLastRxProvider {
+                        wg: self.entities.wireguard_interface.clone(),
+                        threshold: Duration::from_secs(
+                            skip_unresponsive_peers.no_rx_threshold_secs,
+                        ),
+                    }))
+                } else {
+                    None
+                };
+            let tcp_puncher = if direct.tcp_hole_punching {
+                TcpPuncher::new(
+                    self.entities.socket_pool.clone(),
+                    self.requested_state.device_config.private_key.public(),
+                )
+                .map(Arc::new)
+                .map_err(|e| {
+                    telio_log_warn!("Skipping TCP hole punching: {e}");
+                })
+                .ok()
+            } else {
+                None
+            };
+
+            // Create LAN discovery, a failure to join the multicast group is not fatal
+            let lan_peer_events = Chan::default();
+            let lan_discovery = match &direct.lan_discovery {
+                Some(lan_discovery) => self
+                    .entities
+                    .socket_pool
+                    .new_external_multicast_udp_v4(
+                        lan_discovery.multicast_group,
+                        lan_discovery.port,
+                    )
+                    .map(|socket| {
+                        LanDiscovery::start(
+                            socket,
+                            SocketAddr::from((lan_discovery.multicast_group, lan_discovery.port)),
+                            self.entities.socket_pool.clone(),
+                            self.entities.wireguard_interface.clone(),
+                            self.requested_state.device_config.private_key.clone(),
+                            Duration::from_secs(lan_discovery.announce_interval_s.into()),
+                            lan_peer_events.tx,
+                        )
+                    })
+                    .map_err(|e| {
+                        telio_log_warn!("Skipping LAN discovery: {e}");
+                    })
+                    .ok(),
+                None => None,
+            };
+
+            // Create Cross Ping Check
+            let cross_ping_check = Arc::new(CrossPingCheck::start(
+                CpcIo {
+                    endpoint_change_subscriber: endpoint_publish_events.rx,
+                    pong_rx_subscriber: pong_rxed_events.rx,
+                    wg_endpoint_publisher: self
+                        .event_publishers
+                        .wg_endpoint_publish_event_publisher
+                        .clone(),
+                    intercoms: multiplexer.get_channel().await?,
+                    lan_peer_subscriber: lan_peer_events.rx,
+                },
+                endpoint_providers.clone(),
+                last_rx_time_provider.clone(),
+                Duration::from_secs(2),
+                ping_pong_tracker,
+                Default::default(),
+                tcp_puncher,
+            ));
+
+            // Create WireGuard connection upgrade synchronizer
+            let upgrade_sync = Arc::new(UpgradeSync::new(
+                self.event_publishers
+                    .endpoint_upgrade_event_subscriber
+                    .clone(),
+                multiplexer.get_channel().await?,
+                Duration::from_secs(5),
+                cross_ping_check.clone(),
+                multiplexer.get_channel().await?,
+                self.requested_state.device_config.private_key.public(),
+            )?);
+
+            Some(DirectEntities {
+                local_interfaces_endpoint_provider,
+                stun_endpoint_provider,
+                upnp_endpoint_provider,
+                port_mapping_endpoint_provider,
+                custom_endpoint_provider,
+                endpoint_providers,
+                cross_ping_check,
+                upgrade_sync,
+                lan_discovery,
+            })
+        } else {
+            None
+        };
+
+        let starcast = self.build_starcast().await.unwrap_or_else(|e| {
+            telio_log_error!("Couldn't start starcast: {e:?}");
+            None
+        });
+
+        Ok(MeshnetEntities {
+            multiplexer,
+            derp,
+            proxy,
+            direct,
+            starcast,
+            session_keeper,
+            capability_exchange,
+            departures,
+        })
+    }
+
+    async fn external_nodes(&self) -> Result<Vec<Node>> {
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+        let mut nodes = Vec::new();
+        for peer in wgi.peers.values() {
+            if let Some(node) = self.peer_to_node(peer, None, None).await {
+                nodes.push(node);
+            }
+        }
+        Ok(nodes)
+    }
+
+    async fn peer_stats(&self) -> Result<Vec<PeerStats>> {
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+
+        let endpoint_map = match &self.entities.meshnet {
+            MeshnetState::Entities(meshnet_entities) => meshnet_entities
+                .proxy
+                .get_endpoint_map()
+                .await
+                .unwrap_or_else(|err| {
+                    telio_log_warn!("Failed to get proxy endpoint map: {}", err);
+                    Default::default()
+                }),
+            MeshnetState::LastState(last_state) => last_state.endpoint_map.clone(),
+        };
+
+        let rtts = match &self.entities.nurse {
+            Some(nurse) => nurse.get_last_rtts().await,
+            None => Default::default(),
+        };
+
+        let negotiated = match self.entities.meshnet.left() {
+            Some(meshnet_entities) => meshnet_entities
+                .capability_exchange
+                .get_negotiated()
+                .await
+                .unwrap_or_else(|err| {
+                    telio_log_warn!("Failed to get negotiated capabilities: {}", err);
+                    Default::default()
+                }),
+            None => Default::default(),
+        };
+
+        Ok(wgi
+            .peers
+            .values()
+            .map(|peer| PeerStats {
+                public_key: peer.public_key,
+                rx_bytes: peer.rx_bytes.unwrap_or_default(),
+                tx_bytes: peer.tx_bytes.unwrap_or_default(),
+                time_since_last_handshake_s: peer
+                    .time_since_last_handshake
+                    .map(|age| age.as_secs()),
+                endpoint: peer.endpoint,
+                path: endpoint_map
+                    .get(&peer.public_key)
+                    .and_then(|proxy| peer.endpoint.filter(|actual| proxy.contains(actual)))
+                    .map_or(PathType::Direct, |_| PathType::Relay),
+                rtt_ms: rtts.get(&peer.public_key).map(|rtt| rtt.as_millis() as u64),
+                persistent_keepalive_interval_s: peer.persistent_keepalive_interval,
+                protocol_version: negotiated
+                    .get(&peer.public_key)
+                    .map(|negotiated| negotiated.version),
+                capabilities: negotiated
+                    .get(&peer.public_key)
+                    .map(|negotiated| negotiated.capabilities.iter().copied().collect())
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn collect_diagnostics(
+        &self,
+        recent_events: Vec<RecordedEvent>,
+        relay_history: Vec<RecordedEvent>,
+    ) -> Result<DiagnosticsBundle> {
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+        let adapter = AdapterState {
+            adapter_type: format!("{:?}", self.requested_state.device_config.adapter),
+            listen_port: wgi.listen_port,
+            fwmark: wgi.fwmark,
+            peer_count: wgi.peers.len(),
+        };
+
+        let mut traversal_candidates = Vec::new();
+        if let Some(direct) = self
+            .entities
+            .meshnet
+            .left()
+            .and_then(|meshnet_entities| meshnet_entities.direct.as_ref())
+        {
+            for provider in &direct.endpoint_providers {
+                let candidates = provider.get_current_endpoints().await.unwrap_or_default();
+                traversal_candidates.push(TraversalCandidates {
+                    provider: provider.name().to_owned(),
+                    wg: candidates.iter().map(|candidate| candidate.wg).collect(),
+                    udp: candidates.iter().map(|candidate| candidate.udp).collect(),
+                });
+            }
+        }
+
+        Ok(DiagnosticsBundle {
+            collected_at_ms: diagnostics::unix_time_ms(),
+            version: version_tag().to_owned(),
+            commit_sha: commit_sha().to_owned(),
+            adapter,
+            peers: self.peer_stats().await?,
+            conntrack: self.entities.firewall.export_connections().summary(),
+            shaping: self.entities.firewall.shaping_stats(),
+            relay_history,
+            recent_events,
+            traversal_candidates,
+            packet_pools: packet_pool_stats(),
+            features: redacted_features(&self.features),
+            hide_user_data: self.features.hide_user_data,
+        })
+    }
+
+    async fn export_session(&self) -> Result<Vec<u8>> {
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+        let mut snapshot = SessionSnapshot::new();
+        snapshot.listen_port = wgi.listen_port;
+
+        if let Some(meshnet_entities) = self.entities.meshnet.left() {
+            snapshot.relay = meshnet_entities
+                .derp
+                .get_connected_server()
+                .await
+                .map(|server| server.public_key);
+        }
+        if let Some(cpc) = self.entities.cross_ping_check() {
+            // Only the endpoints the peers are connected over are known to work
+            snapshot.endpoints = cpc
+                .get_validated_endpoints()
+                .await?
+                .into_values()
+                .filter(|endpoint| {
+                    wgi.peers
+                        .get(&endpoint.public_key)
+                        .and_then(|peer| peer.endpoint)
+                        == Some(endpoint.remote_endpoint.0)
+                })
+                .map(SavedEndpoint::from)
+                .collect();
+        }
+        let postquantum = &self.entities.postquantum_wg;
+        if let (Some(peer), Some(keys)) = (postquantum.peer_pubkey(), postquantum.keys()) {
+            snapshot.post_quantum = Some(SavedPostQuantum {
+                peer,
+                pq_shared: keys.pq_shared,
+                wg_secret: keys.wg_secret,
+            });
+        }
+
+        telio_log_info!(
+            "Exporting session with {} direct endpoints",
+            snapshot.endpoints.len()
+        );
+        Ok(snapshot.encrypt(&self.requested_state.device_config.private_key)?)
+    }
+
+    /// Take the listen port of the previous run right away, the rest of the `snapshot` is applied
+    /// once the meshnet and the exit node are set
+    async fn restore_session(&mut self, snapshot: SessionSnapshot) -> Result {
+        telio_log_info!(
+            "Restoring session with {} direct endpoints",
+            snapshot.endpoints.len()
+        );
+        if let Some(port) = snapshot.listen_port {
+            self.entities
+                .wireguard_interface
+                .set_listen_port(port)
+                .await?;
+        }
+        self.restored_session = Some(snapshot);
+        Ok(())
+    }
+
+    async fn resource_usage(&self) -> Result<ResourceUsage> {
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+        let (started, baseline) = &self.usage_baseline;
+        let usage = ProcessUsage::current().since(baseline);
+
+        Ok(ResourceUsage {
+            period_s: started.elapsed().as_secs(),
+            cpu_time_ms: usage.cpu_time.map(|cpu_time| cpu_time.as_millis() as u64),
+            wakeups: usage.wakeups,
+            rx_bytes: wgi.peers.values().filter_map(|peer| peer.rx_bytes).sum(),
+            tx_bytes: wgi.peers.values().filter_map(|peer| peer.tx_bytes).sum(),
+        })
+    }
+
+    async fn set_relay_servers(&mut self, servers: Option<Vec<DerpServer>>) -> Result {
+        self.requested_state.relay_servers = servers;
+        let config = self.requested_state.meshnet_config.clone();
+        if config.is_some() {
+            self.set_config(&config).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_proxy(&mut self, proxy: Option<telio_sockets::Proxy>) -> Result {
+        if self.entities.socket_pool.proxy() == proxy {
+            return Ok(());
+        }
+        telio_log_info!("Control connections proxy: {proxy:?}");
+        self.entities.socket_pool.set_proxy(proxy);
+        if let Some(meshnet_entities) = self.entities.meshnet.left() {
+            meshnet_entities.derp.reconnect().await;
+        }
+        Ok(())
+    }
+
+    async fn set_relay_pinning(&mut self, pinning: Option<RelayPinning>) -> Result {
+        self.requested_state.relay_pinning = pinning;
+        let config = self.requested_state.meshnet_config.clone();
+        if config.is_some() {
+            self.set_config(&config).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_routing_policy(&mut self, policy: RoutingPolicy) -> Result {
+        if self.requested_state.routing_policy == policy {
+            return Ok(());
+        }
+        let previous = std::mem::replace(&mut self.requested_state.routing_policy, policy);
+        let res = self.apply_routing().await;
+        if res.is_err() {
+            self.requested_state.routing_policy = previous;
+            if let Err(e) = self.apply_routing().await {
+                telio_log_warn!("Failed to restore the previous traffic classes: {e}");
+            }
+        }
+        res
+    }
+
+    /// Put the traffic classes and the additional exits of the requested state in effect
+    async fn apply_routing(&mut self) -> Result {
+        wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
+            .boxed()
+            .await?;
+        #[cfg(target_os = "linux")]
+        self.update_steering().await?;
+        Ok(())
+    }
+
+    async fn set_additional_exits(&mut self, exits: Vec<ExitNode>) -> Result {
+        if self.requested_state.additional_exits == exits {
+            return Ok(());
+        }
+        let previous = std::mem::replace(&mut self.requested_state.additional_exits, exits);
+        let res = self.apply_routing().await;
+        if res.is_err() {
+            self.requested_state.additional_exits = previous;
+            if let Err(e) = self.apply_routing().await {
+                telio_log_warn!("Failed to restore the previous additional exits: {e}");
+            }
+        }
+        res
+    }
+
+    async fn set_split_tunnel(&mut self, policy: SplitTunnelPolicy) -> Result {
+        if self.requested_state.split_tunnel == policy {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        self.update_split_tunnel_rules(&policy)?;
+        self.requested_state.split_tunnel = policy;
+        wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
+            .boxed()
+            .await?;
+        Ok(())
+    }
+
+    /// Take over the `features` which can change while running, restarting the meshnet
+    /// components for the ones they are started with
+    async fn apply_features(&mut self, features: Features) -> Result {
+        let restart_meshnet = !FeaturesUpdate::new(&self.features, &features)
+            .restarted
+            .is_empty();
+        self.features = features_update::merge_hot(&self.features, &features);
+
+        let mut keepalive_periods = self.features.wireguard.persistent_keepalive.clone();
+        // Intervals found by the tuner win over the configured ones, as on start
+        if let Some(tuner) = &self.keepalive_tuner {
+            keepalive_periods.direct = tuner.interval();
+            keepalive_periods.vpn = tuner.measured_interval().or(keepalive_periods.vpn);
+        }
+        self.requested_state.keepalive_periods = keepalive_periods;
+        self.entities.firewall.update_rules(&self.features.firewall);
+
+        #[cfg(not(feature = "strict_privacy"))]
+        if let (Some(nurse), Some(nurse_features)) =
+            (self.entities.nurse.as_ref(), self.features.nurse.as_ref())
+        {
+            nurse.set_intervals(NurseConfig::new(nurse_features)).await;
+        }
+
+        if restart_meshnet {
+            telio_log_info!("Restarting meshnet for the updated features");
+            self.restart_meshnet().boxed().await?;
+        }
+
+        wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
+            .boxed()
+            .await?;
+        Ok(())
+    }
+
+    /// Check whether any of the relay servers allowed by the pinning is reachable, servers
+    /// without a health record yet are assumed to be. Reports the changes.
+    async fn update_relay_pinning(&mut self) {
+        let health = self.relay_server_health().await.unwrap_or_default();
+        let max_failures = self
+            .features
+            .derp
+            .as_ref()
+            .and_then(|derp| derp.health_check)
+            .map_or(u32::MAX, |health_check| health_check.max_failures);
+
+        let servers = self.configured_relay_servers();
+        let Some(pinning) = self.requested_state.relay_pinning.as_ref() else {
+            self.relay_pinning_unsatisfied = false;
+            return;
+        };
+        let unsatisfied = !servers.is_empty()
+            && !servers.iter().any(|server| {
+                pinning.allows(server)
+                    && health
+                        .get(&server.public_key)
+                        .is_none_or(|h| h.is_healthy(max_failures))
+            });
+        if unsatisfied == self.relay_pinning_unsatisfied {
+            return;
+        }
+        self.relay_pinning_unsatisfied = unsatisfied;
+
+        if unsatisfied {
+            telio_log_warn!(
+                "No allowed relay server is reachable, falling back to {:?}",
+                pinning.fallback
+            );
+            self.publish_connectivity_event(Connectivity::NoAllowedRelay {
+                fallback: pinning.fallback,
+            });
+        } else {
+            telio_log_info!("An allowed relay server is reachable again");
+        }
+    }
+
+    async fn relay_server_health(&self) -> Result<HashMap<PublicKey, ServerHealth>> {
+        Ok(match self.relay_health.as_ref() {
+            Some(monitor) => monitor.get_health().await,
+            None => HashMap::new(),
+        })
+    }
+
+    /// Relay servers the device may connect to, restricted by the relay pinning unless its
+    /// fallback is in effect
+    fn relay_servers(&self) -> Vec<DerpServer> {
+        let servers = self.configured_relay_servers();
+        match &self.requested_state.relay_pinning {
+            Some(pinning)
+                if !(self.relay_pinning_unsatisfied
+                    && pinning.fallback == RelayPinningFallback::AnyServer) =>
+            {
+                servers
+                    .into_iter()
+                    .filter(|server| pinning.allows(server))
+                    .collect()
+            }
+            _ => servers,
+        }
+    }
+
+    /// Relay servers set with libtelio.set_relay_servers(...), or else the ones from the meshnet config
+    fn configured_relay_servers(&self) -> Vec<DerpServer> {
+        self.requested_state
+            .relay_servers
+            .clone()
+            .or_else(|| {
+                self.requested_state
+                    .meshnet_config
+                    .as_ref()
+                    .and_then(|config| config.derp_servers.clone())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Plaintext STUN servers of the relays
+    fn stun_servers(&self) -> Vec<SocketAddr> {
+        self.relay_servers()
+            .iter()
+            .filter(|server| server.stun_plaintext_port != 0)
+            .map(|server| SocketAddr::new(server.ipv4.into(), server.stun_plaintext_port))
+            .collect()
+    }
+
+    /// Relay servers in the order of connection attempts, the healthy and fastest ones go first
+    /// while their health is monitored
+    async fn sorted_relay_servers(&self) -> SortedServers {
+        let mut servers = self.relay_servers();
+        let health_check = self
+            .features
+            .derp
+            .as_ref()
+            .and_then(|derp| derp.health_check);
+        match (self.relay_health.as_ref(), health_check) {
+            (Some(monitor), Some(health_check)) => {
+                monitor.configure(servers.clone()).await;
+                servers.sort_by_key(|server| server.weight);
+                SortedServers::with_order(order_by_health(
+                    &servers,
+                    &monitor.get_health().await,
+                    health_check.max_failures,
+                ))
             }
-            // Create Local Interface Endpoint Provider
-            let local_interfaces_endpoint_provider = if has_provider(Local) {
-                let ep = Arc::new(LocalInterfacesEndpointProvider::new(
-                    self.entities
-                        .socket_pool
-                        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
-                        .await?,
-                    self.entities.wireguard_interface.clone(),
-                    Duration::from_secs(direct.endpoint_interval_secs),
-                    ping_pong_tracker.clone(),
-                ));
-                endpoint_providers.push(ep.clone());
-                Some(ep)
-            } else {
-                None
-            };
+            _ => SortedServers::new(servers),
+        }
+    }
 
-            // Create Stun Endpoint Provider
-            let stun_endpoint_provider = if has_provider(Stun) {
-                let ep = Arc::new(StunEndpointProvider::start(
-                    self.entities.wireguard_interface.clone(),
-                    ExponentialBackoffBounds {
-                        initial: Duration::from_secs(direct.endpoint_interval_secs),
-                        maximal: Some(Duration::from_secs(120)),
-                    },
-                    ping_pong_tracker.clone(),
-                    self.event_publishers.stun_server_publisher.clone(),
-                    self.features
-                        .direct
-                        .clone()
-                        .unwrap_or_default()
-                        .endpoint_providers_optimization
-                        .unwrap_or_default()
-                        .optimize_direct_upgrade_stun,
-                )?);
-                endpoint_providers.push(ep.clone());
-                Some(ep)
-            } else {
-                None
-            };
+    /// Reorder the relay servers by their latest health, leaving the active one when it became
+    /// unhealthy while another one is fine, or when rehoming found a faster one
+    async fn poll_relay_health(&mut self) -> Result {
+        self.update_relay_pinning().await;
 
-            // Create Upnp Endpoint Provider
-            let upnp_endpoint_provider = if has_provider(Upnp) {
-                let ep = Arc::new(UpnpEndpointProvider::start(
-                    self.entities
-                        .socket_pool
-                        .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
-                        .await?,
-                    self.entities.wireguard_interface.clone(),
-                    ExponentialBackoffBounds {
-                        initial: Duration::from_secs(direct.endpoint_interval_secs),
-                        maximal: Some(Duration::from_secs(120)),
-                    },
-                    ping_pong_tracker.clone(),
-                    direct
-                        .endpoint_providers_optimization
-                        .as_ref()
-                        .unwrap_or(&Default::default())
-                        .optimize_direct_upgrade_upnp,
-                    Duration::from_secs(
-                        direct
-                            .upnp_features
-                            .as_ref()
-                            .unwrap_or(&Default::default())
-                            .lease_duration_s as u64,
-                    ),
-                )?);
-                endpoint_providers.push(ep.clone());
-                Some(ep)
-            } else {
-                None
-            };
+        let (Some(monitor), Some(health_check)) = (
+            self.relay_health.as_ref(),
+            self.features
+                .derp
+                .as_ref()
+                .and_then(|derp| derp.health_check),
+        ) else {
+            return Ok(());
+        };
+        let Some(meshnet_entities) = self.entities.meshnet.left() else {
+            return Ok(());
+        };
 
-            // Subscribe to endpoint providers' events
-            for endpoint_provider in &endpoint_providers {
-                endpoint_provider
-                    .subscribe_for_endpoint_candidates_change_events(
-                        endpoint_publish_events.tx.clone(),
-                    )
-                    .await;
-                endpoint_provider
-                    .subscribe_for_pong_events(pong_rxed_events.tx.clone())
-                    .await;
+        let servers = self.sorted_relay_servers().await;
+        let derp = &meshnet_entities.derp;
+        derp.configure(
+            derp.get_config()
+                .await
+                .map(|config| DerpConfig { servers, ..config }),
+        )
+        .await;
+
+        let health = monitor.get_health().await;
+        let is_healthy = |server: &DerpServer| {
+            health
+                .get(&server.public_key)
+                .is_none_or(|h| h.is_healthy(health_check.max_failures))
+        };
+        let Some(active) = derp.get_connected_server().await else {
+            monitor.set_active_server(None).await;
+            return Ok(());
+        };
+        monitor.set_active_server(Some(active.public_key)).await;
+
+        // Reported from the state polling, so that no extra wakeups are needed
+        if let (
+            Some(period),
+            Some(ServerHealth {
+                latency: Some(rtt),
+                jitter,
+                ..
+            }),
+        ) = (
+            health_check.quality_report_interval_s,
+            health.get(&active.public_key),
+        ) {
+            if self
+                .connectivity
+                .relay_quality_due(Duration::from_secs(period.into()))
+            {
+                let _ = self
+                    .event_publishers
+                    .libtelio_event_publisher
+                    .send(Box::new(Event::RelayQuality {
+                        body: RelayQualityEvent {
+                            hostname: active.hostname.clone(),
+                            rtt_ms: rtt.as_millis() as u64,
+                            jitter_ms: jitter.unwrap_or_default().as_millis() as u64,
+                            reconnects: self.connectivity.relay_reconnects(),
+                        },
+                    }));
             }
+        }
 
-            let last_rx_time_provider: Option<Arc<dyn TimeSinceLastRxProvider>> =
-                if let Some(skip_unresponsive_peers) = &direct.skip_unresponsive_peers {
-                    Some(Arc::new(WireGuardTimeSinceLastRxProvider {
-                        wg: self.entities.wireguard_interface.clone(),
-                        threshold: Duration::from_secs(
-                            skip_unresponsive_peers.no_rx_threshold_secs,
-                        ),
-                    }))
-                } else {
-                    None
-                };
-            // Create Cross Ping Check
-            let cross_ping_check = Arc::new(CrossPingCheck::start(
-                CpcIo {
-                    endpoint_change_subscriber: endpoint_publish_events.rx,
-                    pong_rx_subscriber: pong_rxed_events.rx,
-                    wg_endpoint_publisher: self
-                        .event_publishers
-                        .wg_endpoint_publish_event_publisher
-                        .clone(),
-                    intercoms: multiplexer.get_channel().await?,
+        let relay_servers = self.relay_servers();
+        let alternative = relay_servers
+            .iter()
+            .any(|server| server.public_key != active.public_key && is_healthy(server));
+        if !is_healthy(&active) && alternative {
+            telio_log_info!(
+                "Relay server {} is unhealthy, switching to another one",
+                active.hostname
+            );
+            derp.reconnect().await;
+            return Ok(());
+        }
+
+        let Some(to) = monitor.take_rehome_target().await.and_then(|target| {
+            relay_servers
+                .into_iter()
+                .find(|server| server.public_key == target)
+        }) else {
+            return Ok(());
+        };
+        // The servers are ordered by latency, so the faster one is tried first
+        telio_log_info!("Rehoming relay from {} to {}", active.hostname, to.hostname);
+        derp.reconnect().await;
+
+        let latency_ms = |key: &PublicKey| {
+            health
+                .get(key)
+                .and_then(|h| h.latency)
+                .map_or(0, |latency| latency.as_millis() as u64)
+        };
+        let _ = self
+            .event_publishers
+            .libtelio_event_publisher
+            .send(Box::new(Event::RelayRehoming {
+                body: RelayRehomingEvent {
+                    from_latency_ms: latency_ms(&active.public_key),
+                    to_latency_ms: latency_ms(&to.public_key),
+                    from: active,
+                    to,
                 },
-                endpoint_providers.clone(),
-                last_rx_time_provider.clone(),
-                Duration::from_secs(2),
-                ping_pong_tracker,
-                Default::default(),
-            ));
+            }));
+        Ok(())
+    }
 
-            // Create WireGuard connection upgrade synchronizer
-            let upgrade_sync = Arc::new(UpgradeSync::new(
-                self.event_publishers
-                    .endpoint_upgrade_event_subscriber
-                    .clone(),
-                multiplexer.get_channel().await?,
-                Duration::from_secs(5),
-                cross_ping_check.clone(),
-                multiplexer.get_channel().await?,
-                self.requested_state.device_config.private_key.public(),
-            )?);
+    /// Report the progress of the meshnet formation, `total` being the number of the meshnet
+    /// peers or `None` without meshnet
+    fn update_mesh_readiness(&mut self, total: Option<usize>) {
+        let connected = self.connectivity.connected_links();
+        self.startup.mesh_formed(connected, total);
+    }
 
-            Some(DirectEntities {
-                local_interfaces_endpoint_provider,
-                stun_endpoint_provider,
-                upnp_endpoint_provider,
-                endpoint_providers,
-                cross_ping_check,
-                upgrade_sync,
-            })
-        } else {
-            None
+    /// Tell the meshnet peers that this node goes away after the `timeout` and refuse new
+    /// connections until then
+    async fn start_draining(&self, timeout: Duration) -> Result {
+        self.entities.firewall.set_draining(true);
+
+        let Some(meshnet_entities) = self.entities.meshnet.left() else {
+            return Ok(());
         };
+        let peers = self
+            .requested_state
+            .meshnet_config
+            .as_ref()
+            .and_then(|config| config.peers.as_ref())
+            .map(|peers| peers.iter().map(|peer| peer.base.public_key).collect())
+            .unwrap_or_default();
+        meshnet_entities.departures.announce(peers, timeout).await?;
+        Ok(())
+    }
 
-        let starcast = self.build_starcast().await.unwrap_or_else(|e| {
-            telio_log_error!("Couldn't start starcast: {e:?}");
-            None
-        });
+    /// Add the traffic exchanged with the peers since the previous poll to the accounting
+    async fn poll_accounting(&mut self) -> Result {
+        let Some(accounting) = self.accounting.as_mut() else {
+            return Ok(());
+        };
 
-        Ok(MeshnetEntities {
-            multiplexer,
-            derp,
-            proxy,
-            direct,
-            starcast,
-            session_keeper,
-        })
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+        let exit_node = self
+            .requested_state
+            .exit_node
+            .as_ref()
+            .map(|exit_node| exit_node.public_key);
+        accounting.record(
+            &wgi.peers,
+            self.entities.firewall.take_packet_counts(),
+            exit_node,
+            conntrack_persistence::unix_time(),
+        );
+        Ok(())
     }
 
-    async fn external_nodes(&self) -> Result<Vec<Node>> {
-        let wgi = self.entities.wireguard_interface.get_interface().await?;
-        let mut nodes = Vec::new();
-        for peer in wgi.peers.values() {
-            if let Some(node) = self.peer_to_node(peer, None, None).await {
-                nodes.push(node);
+    /// Publish the state of the device to the health endpoint, proving it still polls
+    #[cfg(feature = "health_endpoint")]
+    async fn publish_health(&self) -> Result {
+        let Some(endpoint) = self.health_endpoint.as_ref() else {
+            return Ok(());
+        };
+
+        let relay = match self.entities.meshnet.left() {
+            Some(meshnet_entities) => {
+                meshnet_entities
+                    .derp
+                    .get_connected_server()
+                    .await
+                    .map(|server| health_endpoint::RelayStatus {
+                        hostname: server.hostname,
+                        public_key: server.public_key,
+                        state: server.conn_state,
+                    })
             }
+            None => None,
+        };
+        endpoint.publish(health_endpoint::HealthStatus {
+            paused: self.requested_state.paused,
+            meshnet: self.requested_state.meshnet_config.is_some(),
+            exit_node: self
+                .requested_state
+                .exit_node
+                .as_ref()
+                .map(|exit_node| exit_node.public_key),
+            relay,
+            peers: self.peer_stats().await?,
+        });
+        Ok(())
+    }
+
+    /// Publish the state the device is restarted with by the supervision
+    fn save_restart_state(&self) {
+        if let Some(state) = &self.restart_state {
+            *state.lock() = HandoverState {
+                private_key: self.requested_state.device_config.private_key.clone(),
+                fwmark: self.requested_state.device_config.fwmark,
+                meshnet: self.requested_state.meshnet_config.clone(),
+                exit_node: self.requested_state.exit_node.clone(),
+                ..Default::default()
+            };
         }
-        Ok(nodes)
+    }
+
+    /// Rebuild the meshnet entities with the current config
+    async fn restart_meshnet(&mut self) -> Result {
+        let config = self.requested_state.meshnet_config.clone();
+        if config.is_none() {
+            return Ok(());
+        }
+        self.set_config(&None).boxed().await?;
+        self.set_config(&config).boxed().await
+    }
+
+    fn publish_connectivity_event(&self, body: Connectivity) {
+        telio_log_debug!("Connectivity change: {body:?}");
+        let _ = self
+            .event_publishers
+            .libtelio_event_publisher
+            .send(Box::new(Event::Connectivity { body }));
     }
 
     async fn build_starcast(&self) -> Result<Option<StarcastEntities>> {
@@ -1582,7 +3670,9 @@ impl Runtime {
         }
 
         let (chan_transport, chan_peer) = Chan::pipe();
-        let virtual_peer = Arc::new(StarcastPeer::start(chan_peer, self.features.ipv6).await?);
+        let policy = RelayPolicy::new(&self.features.starcast.clone().unwrap_or_default());
+        let virtual_peer =
+            Arc::new(StarcastPeer::start(chan_peer, self.features.ipv6, &policy).await?);
 
         let meshnet_ip = self
             .requested_state
@@ -1600,6 +3690,7 @@ impl Runtime {
                 meshnet_ip.to_owned(),
                 self.get_socket_pool().await?,
                 chan_transport,
+                policy,
             )
             .await?,
         );
@@ -1618,6 +3709,7 @@ impl Runtime {
                 peers = self.requested_state.collect_dns_nickname_records();
             }
             peers.extend(self.requested_state.collect_dns_records());
+            let mut peers = retain_reachable_records(peers, self.features.ipv6);
 
             // Insert wildcard for subdomains
             let wildcarded_peers: Records = peers
@@ -1654,6 +3746,19 @@ impl Runtime {
             return Err(Error::DnsNotDisabled);
         }
 
+        self.apply_private_key(private_key).await?;
+
+        if let Some(key_rotation) = self.key_rotation.as_mut() {
+            key_rotation.reset(private_key.public(), Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Propagate the private key to all of the entities
+    ///
+    /// Unlike `set_private_key` this does not require the DNS module to be disabled,
+    /// its peer key is updated during WireGuard consolidation.
+    async fn apply_private_key(&mut self, private_key: &SecretKey) -> Result {
         if let Some(m_entities) = self.entities.meshnet.left() {
             m_entities
                 .derp
@@ -1676,36 +3781,201 @@ impl Runtime {
             .set_local_key(private_key.public())
             .await;
 
-        self.requested_state.device_config.private_key = private_key.clone();
+        self.requested_state.device_config.private_key = private_key.clone();
+
+        if let Some(nurse) = &self.entities.nurse {
+            nurse.set_private_key(private_key.clone()).await;
+        }
+
+        self.apply_routing().await
+    }
+
+    async fn set_tun(&mut self, tun: Tun) -> Result {
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+        let raw_fd = {
+            use std::os::fd::AsRawFd;
+            tun.as_raw_fd()
+        };
+        self.entities.wireguard_interface.set_tun(tun).await?;
+
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+        if let Some(index) = fd_to_if_index(raw_fd) {
+            self.entities.socket_pool.set_tunnel_interface(index);
+        }
+
+        Ok(())
+    }
+
+    async fn get_private_key(&self) -> Result<SecretKey> {
+        Ok(self.requested_state.device_config.private_key.clone())
+    }
+
+    fn confirm_key_rotation(&mut self, public_key: &PublicKey) -> Result {
+        match self.key_rotation.as_mut() {
+            Some(key_rotation) if key_rotation.confirm(public_key, Instant::now()) => Ok(()),
+            _ => Err(Error::InvalidKeyRotation),
+        }
+    }
+
+    async fn poll_key_rotation(&mut self) -> Result {
+        let Some(action) = self
+            .key_rotation
+            .as_mut()
+            .and_then(|key_rotation| key_rotation.poll(Instant::now()))
+        else {
+            return Ok(());
+        };
+
+        let (public_key, state) = match action {
+            RotationAction::Announce(public_key) => {
+                telio_log_info!("Announcing rotated device key: {public_key}");
+                (public_key, KeyRotationState::Announced)
+            }
+            RotationAction::Switch(private_key) => {
+                telio_log_info!("Switching to rotated device key: {}", private_key.public());
+                self.apply_private_key(&private_key).boxed().await?;
+                if let Some(key_rotation) = self.key_rotation.as_mut() {
+                    key_rotation.reset(private_key.public(), Instant::now());
+                }
+                (private_key.public(), KeyRotationState::Switched)
+            }
+        };
+
+        let _ = self
+            .event_publishers
+            .libtelio_event_publisher
+            .send(Box::new(Event::KeyRotation {
+                body: KeyRotationEvent { public_key, state },
+            }));
+        Ok(())
+    }
 
-        if let Some(nurse) = &self.entities.nurse {
-            nurse.set_private_key(private_key.clone()).await;
-        }
+    /// Sequence the control messages relayed to the peers which negotiated the replay protection
+    async fn update_replay_protection(&self) -> Result {
+        let Some(meshnet_entities) = self.entities.meshnet.left() else {
+            return Ok(());
+        };
 
-        wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
-            .boxed()
-            .await?;
+        let peers = meshnet_entities
+            .capability_exchange
+            .get_negotiated()
+            .await?
+            .into_iter()
+            .filter(|(_, negotiated)| {
+                negotiated
+                    .capabilities
+                    .contains(&PeerCapability::ReplayProtection)
+            })
+            .map(|(public_key, _)| public_key)
+            .collect();
+        meshnet_entities
+            .derp
+            .set_replay_protected_peers(peers)
+            .await;
         Ok(())
     }
 
-    async fn set_tun(&mut self, tun: Tun) -> Result {
-        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
-        let raw_fd = {
-            use std::os::fd::AsRawFd;
-            tun.as_raw_fd()
+    async fn tune_keepalive(&mut self) -> Result {
+        self.probe_binding_lifetime().await;
+
+        let Some(tuner) = self.keepalive_tuner.as_mut() else {
+            return Ok(());
+        };
+        let MeshnetState::Entities(meshnet_entities) = &self.entities.meshnet else {
+            return Ok(());
         };
-        self.entities.wireguard_interface.set_tun(tun).await?;
 
-        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
-        if let Some(index) = fd_to_if_index(raw_fd) {
-            self.entities.socket_pool.set_tunnel_interface(index);
+        let proxy_endpoints = meshnet_entities.proxy.get_endpoint_map().await?;
+        let wgi = self.entities.wireguard_interface.get_interface().await?;
+
+        let mut time_since_last_rx = Vec::new();
+        for peer in wgi.peers.values() {
+            let is_meshnet_peer = get_config_peer(
+                self.requested_state.meshnet_config.as_ref(),
+                &peer.public_key,
+            )
+            .is_some();
+            let is_proxying = proxy_endpoints
+                .get(&peer.public_key)
+                .is_some_and(|proxy| peer.endpoint.is_some_and(|ep| proxy.contains(&ep)));
+            if !is_meshnet_peer || is_proxying || peer.state() != NodeState::Connected {
+                continue;
+            }
+
+            if let Some(since) = self
+                .entities
+                .wireguard_interface
+                .time_since_last_rx(peer.public_key)
+                .await?
+            {
+                time_since_last_rx.push(since);
+            }
         }
 
+        if let Some(interval) = tuner.observe(Instant::now(), time_since_last_rx) {
+            self.requested_state.keepalive_periods.direct = interval;
+        }
         Ok(())
     }
 
-    async fn get_private_key(&self) -> Result<SecretKey> {
-        Ok(self.requested_state.device_config.private_key.clone())
+    /// Measure the NAT binding lifetime of the current network when it is not known yet, and
+    /// apply it to both direct and VPN peers once it is
+    async fn probe_binding_lifetime(&mut self) {
+        let Some(config) = self
+            .features
+            .wireguard
+            .adaptive_keepalive
+            .filter(|config| config.probe_binding_lifetime)
+        else {
+            return;
+        };
+        let Some(tuner) = self.keepalive_tuner.as_mut() else {
+            return;
+        };
+
+        // Probing is optional traffic, not worth it when the user pays for or saves the data
+        let saves_data = self
+            .network_path
+            .as_ref()
+            .is_some_and(NetworkPath::saves_data);
+
+        match self.nat_binding_probe.as_ref() {
+            Some(probe) => {
+                if let Some((network, lifetime_s)) = probe.take_binding_lifetime().await {
+                    if let Some(interval) = tuner.set_binding_lifetime(network, lifetime_s) {
+                        self.requested_state.keepalive_periods.direct = interval;
+                    }
+                }
+            }
+            None if tuner.measured_interval().is_none() && !saves_data => {
+                let server = self
+                    .requested_state
+                    .meshnet_config
+                    .as_ref()
+                    .and_then(|config| config.derp_servers.as_ref())
+                    .and_then(|servers| {
+                        servers
+                            .iter()
+                            .find(|server| server.stun_plaintext_port != 0)
+                    })
+                    .map(|server| SocketAddr::new(server.ipv4.into(), server.stun_plaintext_port));
+                if let Some(server) = server {
+                    self.nat_binding_probe = Some(NatBindingProbe::start(
+                        self.entities.socket_pool.clone(),
+                        server,
+                        tuner.network(),
+                        self.features.wireguard.persistent_keepalive.direct,
+                        config.max_interval_s,
+                    ));
+                }
+            }
+            None => (),
+        }
+
+        self.requested_state.keepalive_periods.vpn =
+            tuner
+                .measured_interval()
+                .or(self.features.wireguard.persistent_keepalive.vpn);
     }
 
     async fn get_adapter_luid(&mut self) -> Result<u64> {
@@ -1717,18 +3987,26 @@ impl Runtime {
         self.requested_state.device_config.fwmark = Some(fwmark);
 
         self.entities.socket_pool.set_fwmark(fwmark);
-        wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
-            .boxed()
-            .await?;
-        Ok(())
+        let policy = self.requested_state.split_tunnel.clone();
+        self.update_split_tunnel_rules(&policy)?;
+        self.apply_routing().await
     }
 
     async fn notify_network_change(&mut self) -> Result {
+        self.connectivity.network_changed();
         self.entities
             .wireguard_interface
             .drop_connected_sockets()
             .await?;
 
+        if let Some(tuner) = self.keepalive_tuner.as_mut() {
+            tuner.set_network(current_network_id());
+            self.requested_state.keepalive_periods.direct = tuner.interval();
+        }
+        if let Some(probe) = self.nat_binding_probe.take() {
+            probe.stop().await;
+        }
+
         if let Some(meshnet_entities) = self.entities.meshnet.left() {
             meshnet_entities.proxy.on_network_change().await;
             if let Some(direct) = &meshnet_entities.direct {
@@ -1745,12 +4023,109 @@ impl Runtime {
             meshnet_entities.derp.reconnect().await;
         }
 
+        self.start_fast_reconnect().await?;
+
         #[cfg(target_os = "android")]
         PATH_CHANGE_BROADCAST.send(());
 
         Ok(())
     }
 
+    /// Handshake with the peers connected before the network change right away, instead of
+    /// waiting for their keepalives
+    async fn start_fast_reconnect(&mut self) -> Result {
+        let Some(config) = self.features.fast_reconnect else {
+            return Ok(());
+        };
+        let peers: Vec<PublicKey> = self
+            .entities
+            .wireguard_interface
+            .get_interface()
+            .await?
+            .peers
+            .values()
+            .filter(|peer| peer.is_connected())
+            .map(|peer| peer.public_key)
+            .collect();
+        if peers.is_empty() {
+            return Ok(());
+        }
+
+        telio_log_info!("Reconnecting {} peers after network change", peers.len());
+        self.entities
+            .wireguard_interface
+            .trigger_handshakes(peers.clone())
+            .await?;
+        self.fast_reconnect = Some(FastReconnect::new(
+            peers,
+            Duration::from_millis(config.budget_ms.into()),
+            Instant::now(),
+        ));
+        Ok(())
+    }
+
+    /// Check which of the awaited peers reconnected, retry the handshakes of the others
+    async fn poll_fast_reconnect(&mut self) -> Result {
+        let peers = self
+            .entities
+            .wireguard_interface
+            .get_interface()
+            .await?
+            .peers;
+        let Some(reconnect) = self.fast_reconnect.as_mut() else {
+            return Ok(());
+        };
+        let Some(outcome) = reconnect.update(&peers, Instant::now()) else {
+            let pending = reconnect.pending().to_vec();
+            self.entities
+                .wireguard_interface
+                .trigger_handshakes(pending)
+                .await?;
+            return Ok(());
+        };
+
+        self.fast_reconnect = None;
+        telio_log_info!("Network reconnect finished: {outcome:?}");
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = self.otlp.as_ref() {
+            otlp.network_reconnected(&outcome);
+        }
+        self.publish_connectivity_event(outcome);
+        Ok(())
+    }
+
+    /// React to the network `path` reported by the platform monitor of the app
+    async fn update_network_path(&mut self, path: NetworkPath) -> Result {
+        let previous = self.network_path.replace(path.clone());
+        let flags = |path: &NetworkPath| (path.expensive, path.constrained, path.over_vpn);
+        if previous.as_ref().map(flags) != Some(flags(&path)) {
+            if path.over_vpn {
+                telio_log_warn!("Network path goes through another VPN");
+            }
+            self.publish_connectivity_event(Connectivity::NetworkPathChanged {
+                expensive: path.expensive,
+                constrained: path.constrained,
+                over_vpn: path.over_vpn,
+            });
+        }
+
+        if path.saves_data() {
+            if let Some(probe) = self.nat_binding_probe.take() {
+                probe.stop().await;
+            }
+        }
+        if self.requested_state.paused || !path.satisfied {
+            return Ok(());
+        }
+        // Changes seen by the network monitor as well are handled by the fast reconnect
+        if self.entities.interfaces_change_observer.is_none()
+            && previous.is_some_and(|previous| previous.rebinds(&path))
+        {
+            self.notify_network_change().await?;
+        }
+        Ok(())
+    }
+
     async fn notify_sleep(&mut self) -> Result {
         self.entities
             .aggregator
@@ -1764,6 +4139,107 @@ impl Runtime {
         Ok(())
     }
 
+    async fn pause(&mut self) -> Result {
+        if self.requested_state.paused {
+            return Ok(());
+        }
+        telio_log_info!("Pausing the device");
+        self.requested_state.paused = true;
+        self.fast_reconnect = None;
+        self.notify_sleep().await?;
+        self.set_background_tasks_paused(true).await;
+
+        if let Some(meshnet_entities) = self.entities.meshnet.left() {
+            meshnet_entities.derp.configure(None).await;
+            if let Some(direct) = meshnet_entities.direct.as_ref() {
+                for provider in &direct.endpoint_providers {
+                    provider.pause().await;
+                }
+            }
+        }
+
+        wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
+            .boxed()
+            .await?;
+        Ok(())
+    }
+
+    async fn resume(&mut self) -> Result {
+        if !self.requested_state.paused {
+            return Ok(());
+        }
+        telio_log_info!("Resuming the device");
+        self.requested_state.paused = false;
+        self.notify_wakeup().await?;
+        self.set_background_tasks_paused(false).await;
+
+        // Reconfigures the relay, the consolidation restores keepalives and unpauses the
+        // endpoint providers needed for the upgrades
+        let config = self.requested_state.meshnet_config.clone();
+        if config.is_some() {
+            self.set_config(&config).await?;
+        } else {
+            wg_controller::consolidate_wg_state(
+                &self.requested_state,
+                &self.entities,
+                &self.features,
+            )
+            .boxed()
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Stop or restart the tasks sending traffic on their own: the nurse analytics, the relay
+    /// health checks and the PQ key exchanges
+    async fn set_background_tasks_paused(&self, paused: bool) {
+        if let Some(nurse) = self.entities.nurse.as_ref() {
+            nurse.set_paused(paused).await;
+        }
+        if let Some(relay_health) = self.relay_health.as_ref() {
+            relay_health.set_paused(paused).await;
+        }
+        self.entities.postquantum_wg.set_paused(paused);
+    }
+
+    /// Refresh the state which went stale while the system was suspended, also when the
+    /// integrators did not notify about the wakeup
+    async fn check_clock(&mut self) -> Result {
+        for event in self.clock_monitor.check() {
+            match event {
+                ClockEvent::Suspended(duration) => {
+                    telio_log_info!(
+                        "System was suspended for {duration:?}, refreshing connections"
+                    );
+                    self.notify_wakeup().await?;
+                    self.notify_network_change().await?;
+                }
+                // Timeouts rely on suspend-aware instants, wall clock changes only affect
+                // handshake times reported by WireGuard
+                ClockEvent::WallClockForward(_) | ClockEvent::WallClockBackward(_) => {
+                    telio_log_warn!("Wall clock jump detected: {event:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Addresses of the tunnel interface, assigned by libtelio or the meshnet ones of the device
+    fn tunnel_addresses(&self) -> Vec<IpAddr> {
+        let meshnet = self
+            .requested_state
+            .meshnet_config
+            .as_ref()
+            .and_then(|config| config.this.ip_addresses.as_ref());
+        self.requested_state
+            .device_config
+            .addresses
+            .iter()
+            .chain(meshnet.into_iter().flatten())
+            .copied()
+            .collect()
+    }
+
     async fn start_dns(&mut self, upstream_dns_servers: &[IpAddr]) -> Result {
         self.requested_state.upstream_servers = Some(Vec::from(upstream_dns_servers));
         {
@@ -1788,6 +4264,14 @@ impl Runtime {
                     &upstream_dns_servers
                 );
 
+                // The stub listeners are not exposed to the networks outside of the tunnel
+                let tunnel_addresses = self.tunnel_addresses();
+                if let Some(listener) = self.features.dns.stub_listeners.iter().find(|listener| {
+                    !listener.address.is_loopback() && !tunnel_addresses.contains(&listener.address)
+                }) {
+                    return Err(Error::InvalidStubListener(listener.address));
+                }
+
                 let dns = LocalDnsResolver::new(
                     &public_key,
                     upstream_dns_servers,
@@ -1796,16 +4280,43 @@ impl Runtime {
                 )
                 .await
                 .map_err(Error::DnsResolverError)?;
+                // Domains are observed from the first query answered on
+                let firewall = self.entities.firewall.clone();
+                dns.set_query_observer(Some(Arc::new(move |name: &str| {
+                    firewall.domain_classifier().observe(name);
+                })))
+                .await;
+                let events = self.event_publishers.libtelio_event_publisher.clone();
+                dns.set_upstream_failover(UpstreamFailover {
+                    config: self.features.dns.upstreams.clone(),
+                    observer: Some(Arc::new(move |from, to| {
+                        let body = Connectivity::DnsFailover { from, to };
+                        telio_log_debug!("Connectivity change: {body:?}");
+                        let _ = events.send(Box::new(Event::Connectivity { body }));
+                    })),
+                })
+                .await
+                .map_err(Error::DnsResolverError)?;
                 dns.start().await;
+                if let Err(e) = dns
+                    .start_stub_listeners(&self.features.dns.stub_listeners)
+                    .await
+                {
+                    dns.stop().await;
+                    return Err(Error::DnsResolverError(e));
+                }
                 dns_entity.resolver = Some(dns);
             }
         } // Release locks before controller takes over
 
         self.upsert_dns_peers().await?;
+        self.startup.ready(Component::DnsStub)?;
 
+        // Only now the DNS peer becomes reachable through the tunnel
         wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
             .boxed()
             .await?;
+        self.startup.ready(Component::DnsConfig)?;
 
         Ok(())
     }
@@ -1826,6 +4337,7 @@ impl Runtime {
         if let Some(dns) = self.entities.dns.lock().await.resolver.take() {
             dns.stop().await;
         };
+        self.startup.stopped(Component::DnsStub);
 
         wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
             .boxed()
@@ -1846,10 +4358,20 @@ impl Runtime {
             if should_validate_keys && !keys_match {
                 return Err(Error::BadPublicKey);
             }
+
+            let peers = cfg.peers.as_ref().map_or(0, Vec::len);
+            if let Some(max) = self
+                .features
+                .max_meshnet_peers
+                .filter(|max| peers > *max as usize)
+            {
+                return Err(Error::TooManyPeers(peers, max));
+            }
         }
 
         self.requested_state.old_meshnet_config = self.requested_state.meshnet_config.clone();
         self.requested_state.meshnet_config = config.clone();
+        self.update_relay_pinning().await;
 
         let wg_itf = self.entities.wireguard_interface.get_interface().await?;
         let secret_key = if let Some(secret_key) = wg_itf.private_key {
@@ -1864,6 +4386,8 @@ impl Runtime {
             .iter()
             .map(|p| p.base.public_key)
             .collect();
+        self.connectivity.retain_links(&peers);
+        self.update_mesh_readiness(config.as_ref().map(|_| peers.len()));
 
         // Update for proxy and derp config
         if let Some(config) = config {
@@ -1889,6 +4413,10 @@ impl Runtime {
             };
 
             meshnet_entities.proxy.configure(proxy_config).await?;
+            meshnet_entities
+                .capability_exchange
+                .set_peers(peers.clone())
+                .await?;
 
             if let Some(ref starcast) = meshnet_entities.starcast {
                 let starcast_vpeer_config = StarcastPeerConfig {
@@ -1934,9 +4462,17 @@ impl Runtime {
                     .await?;
             }
 
+            let mut servers = self.sorted_relay_servers().await;
+            if let Some(relay) = self
+                .restored_session
+                .as_mut()
+                .and_then(|session| session.relay.take())
+            {
+                servers.prefer(&relay);
+            }
             let derp_config = DerpConfig {
                 secret_key,
-                servers: SortedServers::new(config.derp_servers.clone().unwrap_or_default()),
+                servers,
                 meshnet_peers: peers,
                 timeout: Duration::from_secs(10), //TODO: make configurable
                 server_keepalives: DerpKeepaliveConfig::from(&self.features.derp),
@@ -1953,10 +4489,19 @@ impl Runtime {
                     .clone()
                     .unwrap_or_default()
                     .use_built_in_root_certificates,
+                transport: self.features.derp.clone().unwrap_or_default().transport,
+                long_poll_after_failures: self
+                    .features
+                    .derp
+                    .as_ref()
+                    .and_then(|derp| derp.long_poll)
+                    .map(|long_poll| long_poll.probe_failures),
             };
 
-            // Update configuration for DERP client
-            meshnet_entities.derp.configure(Some(derp_config)).await;
+            // Update configuration for DERP client, it stays disconnected while paused
+            if !self.requested_state.paused {
+                meshnet_entities.derp.configure(Some(derp_config)).await;
+            }
 
             // Refresh the lists of servers for STUN endpoint provider
             if let Some(direct) = meshnet_entities.direct.as_ref() {
@@ -1972,7 +4517,7 @@ impl Runtime {
 
                     stun_ep
                         .configure(
-                            config.derp_servers.clone().unwrap_or_default(),
+                            self.relay_servers(),
                             use_ipv6,
                             self.get_socket_pool().await?,
                         )
@@ -2008,6 +4553,10 @@ impl Runtime {
             cpc.configure(config.clone()).await?;
         }
 
+        if let Some(lan_discovery) = self.entities.lan_discovery() {
+            lan_discovery.configure(config.clone()).await?;
+        }
+
         // If Disabling meshnet (by calling `set_config()` with `None` as the argument) need to clear exit node
         // so that the controller does not mistake it for a VPN node. See LLT-4266 for more details.
         if self.requested_state.meshnet_config.is_none() {
@@ -2036,40 +4585,291 @@ impl Runtime {
             }
         }
 
-        if let Some(tx) = &self.event_publishers.nurse_config_update_publisher {
-            let event = MeshConfigUpdateEvent::from(config);
-            if tx.send(Box::new(event)).is_err() {
-                telio_log_warn!("Failed to send MeshConfigUpdateEvent to nurse component");
+        #[cfg(target_os = "linux")]
+        self.update_exit_node_rules().await?;
+
+        #[cfg(windows)]
+        self.update_killswitch().await?;
+
+        if let Some(tx) = &self.event_publishers.nurse_config_update_publisher {
+            let event = MeshConfigUpdateEvent::from(config);
+            if tx.send(Box::new(event)).is_err() {
+                telio_log_warn!("Failed to send MeshConfigUpdateEvent to nurse component");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connect ot exit node with post-quantum tunnel
+    async fn connect_exit_node_pq(&mut self, exit_node: &ExitNode) -> Result {
+        if self.requested_state.meshnet_config.is_some() {
+            // Meshnet is enabled and we're trying to set up the QP VPN connection
+            return Err(Error::MeshnetUnavailableWithPQ);
+        }
+
+        self.stop_exit_chain().await;
+        self.stop_exit_obfuscation().await;
+        self.stop_exit_stream().await;
+
+        // This is required to silence the dylint error "error: large future with a size of 2048 bytes"
+        let res = self
+            .connect_exit_node_internal(exit_node, true)
+            .boxed()
+            .await;
+
+        if res.is_err() {
+            // Stop PQ task
+            self.entities.postquantum_wg.stop().await;
+        }
+
+        res
+    }
+
+    async fn connect_exit_node(&mut self, exit_node: &ExitNode) -> Result {
+        self.stop_exit_chain().await;
+        self.stop_exit_obfuscation().await;
+        self.stop_exit_stream().await;
+
+        // Silence the nagger warning
+        Box::pin(self.connect_exit_node_internal(exit_node, false)).await?;
+
+        if self.features.wireguard.stream_transport.is_some() && exit_node.endpoint.is_some() {
+            self.exit_transport = Some(ExitTransport::new(exit_node.clone()));
+        }
+        Ok(())
+    }
+
+    async fn set_exit_chain(&mut self, entry: &ExitNode, exit: &ExitNode) -> Result {
+        let entry_endpoint = entry.endpoint.ok_or(Error::EndpointNotProvided)?;
+        let exit_endpoint = exit.endpoint.ok_or(Error::EndpointNotProvided)?;
+        if entry.public_key == exit.public_key || entry_endpoint == exit_endpoint {
+            return Err(Error::InvalidNode);
+        }
+
+        self.stop_exit_chain().await;
+        self.stop_exit_obfuscation().await;
+        self.stop_exit_stream().await;
+
+        let nested_hop = NestedHop::start(&self.entities.socket_pool, exit_endpoint)
+            .await
+            .map_err(Error::ExitChainError)?;
+        self.requested_state.exit_hop = Some(ExitNode {
+            endpoint: Some(nested_hop.local_addr()),
+            ..exit.clone()
+        });
+        self.entities.exit_chain = Some(nested_hop);
+
+        // Only the traffic of the inner hop is routed through the entry node
+        let entry = ExitNode {
+            allowed_ips: Some(vec![ipnet::IpNet::from(exit_endpoint.ip())]),
+            ..entry.clone()
+        };
+
+        let res = Box::pin(self.connect_exit_node_internal(&entry, false)).await;
+        if res.is_err() {
+            self.stop_exit_chain().await;
+        }
+        res
+    }
+
+    async fn stop_exit_chain(&mut self) {
+        self.requested_state.exit_hop = None;
+        if let Some(nested_hop) = self.entities.exit_chain.take() {
+            nested_hop.stop().await;
+        }
+    }
+
+    async fn connect_exit_node_obfuscated(&mut self, exit_node: &ExitNode) -> Result {
+        let config = self
+            .features
+            .wireguard
+            .obfuscation
+            .ok_or(Error::ObfuscationNotEnabled)?;
+        let endpoint = exit_node.endpoint.ok_or(Error::EndpointNotProvided)?;
+
+        self.stop_exit_chain().await;
+        self.stop_exit_obfuscation().await;
+        self.stop_exit_stream().await;
+
+        let obfuscated_hop = ObfuscatedHop::start(&self.entities.socket_pool, endpoint, config)
+            .await
+            .map_err(Error::ObfuscationError)?;
+        let exit_node = ExitNode {
+            endpoint: Some(obfuscated_hop.local_addr()),
+            ..exit_node.clone()
+        };
+        self.entities.exit_obfuscation = Some(obfuscated_hop);
+
+        let res = Box::pin(self.connect_exit_node_internal(&exit_node, false)).await;
+        if res.is_err() {
+            self.stop_exit_obfuscation().await;
+        }
+        res
+    }
+
+    async fn stop_exit_obfuscation(&mut self) {
+        if let Some(obfuscated_hop) = self.entities.exit_obfuscation.take() {
+            obfuscated_hop.stop().await;
+        }
+    }
+
+    async fn stop_exit_stream(&mut self) {
+        self.exit_transport = None;
+        self.requested_state.exit_stream_endpoint = None;
+        if let Some(stream_hop) = self.entities.exit_stream.take() {
+            stream_hop.stop().await;
+        }
+    }
+
+    /// Move the exit node connection on to the next transport when no handshake completed over
+    /// the current one for `wireguard.stream_transport.handshake_timeout_s`, or when its stream
+    /// hop failed to connect
+    async fn poll_exit_transport(&mut self) -> Result {
+        let Some(config) = self.features.wireguard.stream_transport.as_ref() else {
+            return Ok(());
+        };
+        let Some(exit_transport) = self.exit_transport.as_mut() else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if let Some(starting) = exit_transport.starting.as_mut() {
+            if !starting.is_finished() {
+                return Ok(());
+            }
+            let started = starting.await;
+            exit_transport.starting = None;
+            exit_transport.since = now;
+            match started {
+                Ok(Ok(stream_hop)) => {
+                    // Only the WireGuard endpoint is replaced, the exit node keeps its own
+                    self.requested_state.exit_stream_endpoint = Some(stream_hop.local_addr());
+                    self.entities.exit_stream = Some(stream_hop);
+                    return Ok(());
+                }
+                Ok(Err(e)) => telio_log_warn!("Failed to start the stream hop: {e}"),
+                Err(e) => telio_log_warn!("Failed to start the stream hop: {e}"),
+            }
+        } else {
+            let connected = self
+                .entities
+                .wireguard_interface
+                .get_interface()
+                .await?
+                .peers
+                .get(&exit_transport.exit_node.public_key)
+                .is_some_and(|peer| peer.state() == NodeState::Connected);
+            if connected {
+                exit_transport.since = now;
+                return Ok(());
+            }
+            if now.saturating_duration_since(exit_transport.since)
+                < Duration::from_secs(config.handshake_timeout_s.into())
+            {
+                return Ok(());
+            }
+        }
+
+        let transport = exit_transport.advance(&config.fallback_order, now);
+        let public_key = exit_transport.exit_node.public_key;
+        let endpoint = exit_transport
+            .exit_node
+            .endpoint
+            .ok_or(Error::EndpointNotProvided)?;
+
+        self.requested_state.exit_stream_endpoint = None;
+        if let Some(stream_hop) = self.entities.exit_stream.take() {
+            stream_hop.stop().await;
+        }
+        match transport {
+            None => telio_log_info!("Trying to reach exit node {public_key} over UDP"),
+            Some(transport) => {
+                telio_log_info!("Trying to reach exit node {public_key} over {transport:?}");
+                let socket_pool = self.entities.socket_pool.clone();
+                let stream_endpoint = SocketAddr::new(endpoint.ip(), config.port);
+                let config = config.clone();
+                exit_transport.starting = Some(tokio::spawn(async move {
+                    StreamHop::start(&socket_pool, stream_endpoint, transport, &config).await
+                }));
             }
         }
-
         Ok(())
     }
 
-    /// Connect ot exit node with post-quantum tunnel
-    async fn connect_exit_node_pq(&mut self, exit_node: &ExitNode) -> Result {
-        if self.requested_state.meshnet_config.is_some() {
-            // Meshnet is enabled and we're trying to set up the QP VPN connection
-            return Err(Error::MeshnetUnavailableWithPQ);
+    async fn set_exit_failover(&mut self, failover: Option<ExitFailover>) -> Result {
+        let Some(failover) =
+            failover.and_then(|failover| ExitFailoverState::new(failover, Instant::now()))
+        else {
+            self.exit_failover = None;
+            return Ok(());
+        };
+        if let Some(exit) = failover.active() {
+            self.connect_exit_node(&exit_node(exit)).boxed().await?;
         }
+        self.exit_failover = Some(failover);
+        Ok(())
+    }
 
-        // This is required to silence the dylint error "error: large future with a size of 2048 bytes"
-        let res = self
-            .connect_exit_node_internal(exit_node, true)
-            .boxed()
-            .await;
-
-        if res.is_err() {
-            // Stop PQ task
-            self.entities.postquantum_wg.stop().await;
+    /// Connect the next exit of the failover when the active one degraded
+    async fn poll_exit_failover(&mut self) -> Result {
+        let Some(active) = self
+            .exit_failover
+            .as_ref()
+            .and_then(|failover| failover.active())
+            .map(|exit| exit.public_key)
+        else {
+            return Ok(());
+        };
+        // Exit connected by the app in the meantime is left alone
+        if self
+            .requested_state
+            .exit_node
+            .as_ref()
+            .is_none_or(|exit_node| exit_node.public_key != active)
+        {
+            return Ok(());
         }
 
-        res
-    }
+        let peer = self
+            .entities
+            .wireguard_interface
+            .get_interface()
+            .await?
+            .peers
+            .remove(&active);
+        let qos = match &self.entities.nurse {
+            Some(nurse) => nurse
+                .qos_report()
+                .await
+                .into_iter()
+                .find(|qos| qos.public_key == active),
+            None => None,
+        };
 
-    async fn connect_exit_node(&mut self, exit_node: &ExitNode) -> Result {
-        // Silence the nagger warning
-        Box::pin(self.connect_exit_node_internal(exit_node, false)).await
+        let now = Instant::now();
+        let Some(failover) = self.exit_failover.as_mut() else {
+            return Ok(());
+        };
+        let Some(reason) = failover.degradation(peer.as_ref(), qos.as_ref(), now) else {
+            return Ok(());
+        };
+        let Some(next) = failover.advance(now).map(exit_node) else {
+            telio_log_warn!("Exit {active} degraded ({reason:?}), but there is no other exit");
+            return Ok(());
+        };
+
+        telio_log_warn!(
+            "Exit {active} degraded ({reason:?}), failing over to {}",
+            next.public_key
+        );
+        self.connect_exit_node(&next).boxed().await?;
+        self.publish_connectivity_event(Connectivity::ExitFailover {
+            from: active,
+            to: next.public_key,
+            reason,
+        });
+        Ok(())
     }
 
     async fn connect_exit_node_internal(
@@ -2083,14 +4883,28 @@ impl Runtime {
         self.entities.postquantum_wg.stop().await;
 
         if postquantum {
-            self.entities
-                .postquantum_wg
-                .start(
-                    exit_node.endpoint.ok_or(Error::EndpointNotProvided)?,
-                    self.requested_state.device_config.private_key.clone(),
-                    exit_node.public_key,
-                )
-                .await;
+            let endpoint = exit_node.endpoint.ok_or(Error::EndpointNotProvided)?;
+            let private_key = self.requested_state.device_config.private_key.clone();
+            let restored = self
+                .restored_session
+                .as_mut()
+                .and_then(|session| session.post_quantum.take())
+                .filter(|saved| saved.peer == exit_node.public_key);
+            match restored {
+                Some(saved) => {
+                    telio_log_info!("Resuming post quantum keys of the previous session");
+                    self.entities
+                        .postquantum_wg
+                        .resume(endpoint, private_key, exit_node.public_key, saved.keys())
+                        .await;
+                }
+                None => {
+                    self.entities
+                        .postquantum_wg
+                        .start(endpoint, private_key, exit_node.public_key)
+                        .await;
+                }
+            }
         }
 
         // dns socket for macos should only be bound to tunnel interface when connected to exit,
@@ -2140,6 +4954,9 @@ impl Runtime {
             .boxed()
             .await?;
 
+        #[cfg(windows)]
+        self.update_killswitch().await?;
+
         if let Some(last_exit) = old_exit_node
             .as_ref()
             .or(self.requested_state.last_exit_node.as_ref())
@@ -2154,8 +4971,13 @@ impl Runtime {
     }
 
     async fn disconnect_exit_node(&mut self, node_key: &PublicKey) -> Result {
+        let is_exit_hop = self
+            .requested_state
+            .exit_hop
+            .as_ref()
+            .is_some_and(|exit_hop| &exit_hop.public_key == node_key);
         match self.requested_state.exit_node.as_ref() {
-            Some(exit_node) if &exit_node.public_key == node_key => {
+            Some(exit_node) if &exit_node.public_key == node_key || is_exit_hop => {
                 self.disconnect_exit_nodes().boxed().await
             }
             _ => Err(Error::InvalidNode),
@@ -2163,8 +4985,12 @@ impl Runtime {
     }
 
     async fn disconnect_exit_nodes(&mut self) -> Result {
+        self.exit_failover = None;
         if let Some(exit_node) = self.requested_state.exit_node.take() {
             self.requested_state.last_exit_node = Some(exit_node);
+            self.stop_exit_chain().await;
+            self.stop_exit_obfuscation().await;
+            self.stop_exit_stream().await;
 
             // for macos dns
             bind_tun::set_should_bind(false);
@@ -2187,6 +5013,9 @@ impl Runtime {
             )
             .boxed()
             .await?;
+
+            #[cfg(windows)]
+            self.update_killswitch().await?;
         }
 
         self.entities.postquantum_wg.stop().await;
@@ -2194,6 +5023,147 @@ impl Runtime {
         Ok(())
     }
 
+    /// Engage the killswitch while connected to a VPN server and release it otherwise. The relay
+    /// servers stay reachable outside of the tunnel, as the meshnet is controlled through them.
+    #[cfg(windows)]
+    async fn update_killswitch(&mut self) -> Result {
+        let Some(killswitch) = self.entities.killswitch.as_ref() else {
+            return Ok(());
+        };
+
+        let engaged = match self
+            .requested_state
+            .exit_node
+            .as_ref()
+            .and_then(|exit_node| exit_node.endpoint)
+        {
+            Some(endpoint) => {
+                let relays = self.relay_servers();
+                let control_plane = relays.iter().flat_map(|server| {
+                    IntoIterator::into_iter([
+                        server.relay_port,
+                        server.stun_port,
+                        server.stun_plaintext_port,
+                    ])
+                    .filter(|port| *port != 0)
+                    .map(|port| SocketAddr::new(server.ipv4.into(), port))
+                });
+                let allowed: Vec<SocketAddr> =
+                    std::iter::once(endpoint).chain(control_plane).collect();
+                let luid = self.entities.wireguard_interface.get_adapter_luid().await?;
+                killswitch.enable(luid, &allowed)?;
+                true
+            }
+            None => {
+                killswitch.disable()?;
+                false
+            }
+        };
+
+        if let Some(change) = self.connectivity.killswitch_changed(engaged) {
+            self.publish_connectivity_event(change);
+        }
+
+        Ok(())
+    }
+
+    /// Forward traffic of the meshnet peers which are allowed to route through this device
+    #[cfg(target_os = "linux")]
+    async fn update_exit_node_rules(&self) -> Result {
+        let Some(exit_node_rules) = self.entities.exit_node_rules.clone() else {
+            return Ok(());
+        };
+
+        let sources: Vec<IpAddr> = self
+            .requested_state
+            .meshnet_config
+            .as_ref()
+            .and_then(|config| config.peers.as_ref())
+            .into_iter()
+            .flatten()
+            .filter(|peer| peer.allow_peer_traffic_routing)
+            .flat_map(|peer| peer.ip_addresses.iter().flatten().copied())
+            .collect();
+
+        let tunnel_name = self.entities.wireguard_interface.interface_name().await?;
+        // The rules are installed by running the firewall binaries
+        tokio::task::spawn_blocking(move || exit_node_rules.enable(&tunnel_name, &sources))
+            .await??;
+        Ok(())
+    }
+
+    /// Mark the traffic bypassing the tunnel of the `policy` with the fwmark of the encapsulated
+    /// packets
+    #[cfg(target_os = "linux")]
+    fn update_split_tunnel_rules(&mut self, policy: &SplitTunnelPolicy) -> Result {
+        if policy.is_empty() {
+            if let Some(rules) = self.entities.split_tunnel_rules.as_ref() {
+                rules.disable();
+            }
+            return Ok(());
+        }
+
+        let fwmark = self
+            .requested_state
+            .device_config
+            .fwmark
+            .ok_or(Error::SplitTunnelWithoutFwmark)?;
+        let rules = match self.entities.split_tunnel_rules.take() {
+            Some(rules) => rules,
+            None => SplitTunnelRules::new().map_err(Error::SplitTunnelRulesError)?,
+        };
+        let rules = self.entities.split_tunnel_rules.insert(rules);
+        rules
+            .enable(&policy.bypass(fwmark))
+            .map_err(Error::SplitTunnelRulesError)
+    }
+
+    /// Move the exits of the traffic classes selecting their traffic by the source ports or the
+    /// fwmark to interfaces of their own, and steer the traffic of the classes there
+    #[cfg(target_os = "linux")]
+    async fn update_steering(&mut self) -> Result {
+        let policy = &self.requested_state.routing_policy;
+        if policy.steered().is_empty() {
+            if let Some(rules) = self.entities.steering_rules.as_ref() {
+                rules.disable();
+            }
+            self.entities.class_tunnels.stop().await;
+            return Ok(());
+        }
+
+        let fwmark = self
+            .requested_state
+            .device_config
+            .fwmark
+            .ok_or(Error::SteeringWithoutFwmark)?;
+        let (interfaces, steering) = policy.steering(
+            &self.requested_state.additional_exits,
+            fwmark,
+            self.requested_state.keepalive_periods.vpn,
+            self.features.ipv6,
+        );
+        let rules = match self.entities.steering_rules.take() {
+            Some(rules) => rules,
+            None => SteeringRules::new().map_err(Error::SteeringRulesError)?,
+        };
+        let rules = self.entities.steering_rules.insert(rules);
+
+        let replaced = self
+            .entities
+            .class_tunnels
+            .update(
+                &self.requested_state.device_config.private_key,
+                fwmark,
+                interfaces,
+            )
+            .await?;
+        if replaced {
+            // Routes went away together with the previous interfaces
+            rules.disable();
+        }
+        rules.enable(&steering).map_err(Error::SteeringRulesError)
+    }
+
     #[allow(clippy::panic)]
     async fn _panic(&mut self) -> Result {
         let _ = tokio::spawn(async {
@@ -2223,7 +5193,13 @@ impl Runtime {
             .exit_node
             .as_ref()
             .or(self.requested_state.last_exit_node.as_ref())
-            .filter(|node| node.public_key == peer.public_key);
+            .filter(|node| node.public_key == peer.public_key)
+            .or_else(|| {
+                self.requested_state
+                    .additional_exits
+                    .iter()
+                    .find(|node| node.public_key == peer.public_key)
+            });
 
         let meshnet_peer: Option<&Peer> = get_config_peer(
             self.requested_state.meshnet_config.as_ref(),
@@ -2386,7 +5362,7 @@ impl Runtime {
         }
     }
 
-    fn sanitize_neptun_config(config_param: Option<u32>, adapter: AdapterType) -> Option<u32> {
+    fn sanitize_neptun_config<T>(config_param: Option<T>, adapter: AdapterType) -> Option<T> {
         match config_param {
             Some(b) if adapter == AdapterType::NepTUN => Some(b),
             Some(_) => {
@@ -2444,6 +5420,21 @@ impl TaskRuntime for Runtime {
                             mesh_entities.proxy.mute_peer(public_key, None).await?;
 
                             telio_log_info!("Peer {} was downgraded", public_key);
+                            let change = self.connectivity.path_downgraded(public_key);
+                            self.publish_connectivity_event(change);
+                        }
+
+                        if let (true, false, Some(endpoint)) = (was_proxying, is_proxying, mesh_event.peer.endpoint) {
+                            let change = self.connectivity.path_upgraded(public_key, endpoint);
+                            self.publish_connectivity_event(change);
+                        }
+
+                        // The endpoint of the previous session is restored once the peer is
+                        // proxied, so it is not taken for a failed one
+                        if let (true, Some(direct_entities)) = (is_proxying, mesh_entities.direct.as_ref()) {
+                            if let Some(endpoint) = self.restored_session.as_mut().and_then(|session| session.take_endpoint(&public_key)) {
+                                direct_entities.cross_ping_check.restore_validated_endpoint(endpoint.validated(Instant::now())).await?;
+                            }
                         }
                     }
                 }
@@ -2452,6 +5443,11 @@ impl TaskRuntime for Runtime {
                 telio_log_debug!("Converted peer to node {node:?}");
 
                 if let Some(node) = node {
+                    self.connectivity.link_changed(&node);
+                    let total = self.requested_state.meshnet_config.as_ref()
+                        .map(|config| config.peers.as_ref().map_or(0, Vec::len));
+                    self.update_mesh_readiness(total);
+
                     // Publish WG event to app
                     if !self.is_dublicated_event(&node) && !self.should_supress_disconnected(&node) {
                         telio_log_debug!("Event is being published to libtelio integrators {node:?}");
@@ -2469,6 +5465,18 @@ impl TaskRuntime for Runtime {
 
             Ok(derp_event) = self.event_listeners.derp_event_subscriber.recv() => {
                 telio_log_debug!("Recieved wg_event {derp_event:?}");
+                if let Some(change) = self.connectivity.relay_changed(&derp_event) {
+                    self.publish_connectivity_event(change);
+                }
+                if derp_event.conn_state == RelayState::Connected {
+                    let _ = self.startup.ready(Component::Relay);
+                } else {
+                    self.startup.stopped(Component::Relay);
+                }
+                #[cfg(feature = "otlp")]
+                if let Some(otlp) = self.otlp.as_mut() {
+                    otlp.relay_changed(&derp_event);
+                }
                 let event = Event::builder::<DerpServer>().set(*derp_event).build();
                 if let Some(event) = event {
                 let _ = self.event_publishers.libtelio_event_publisher.send(
@@ -2507,6 +5515,13 @@ impl TaskRuntime for Runtime {
             },
 
             Some(pq_event) = self.event_listeners.post_quantum_subscriber.recv() => {
+                if let telio_pq::Event::RekeyProgress(rekey) = pq_event {
+                    let _ = self.event_publishers.libtelio_event_publisher.send(
+                        Box::new(Event::PqRekey { body: rekey })
+                    );
+                    return Ok(());
+                }
+
                 telio_log_debug!("WG consolidation triggered by PQ event");
 
                 match (&pq_event, &self.requested_state.exit_node, &self.requested_state.last_exit_node) {
@@ -2587,12 +5602,96 @@ impl TaskRuntime for Runtime {
                 }
                 Ok(())
             },
+            Some(_) = self.event_listeners.interfaces_change_subscriber.recv() => {
+                if self.requested_state.paused {
+                    return Ok(());
+                }
+                telio_log_info!("Local interfaces changed, reconnecting");
+                self.notify_network_change()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Network change failure: {:?}. Ignoring", e);
+                        });
+                Ok(())
+            },
+            Ok(path) = self.event_listeners.network_path_subscriber.recv() => {
+                self.update_network_path(path)
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Network path update failure: {:?}. Ignoring", e);
+                        });
+                Ok(())
+            },
+            _ = FastReconnect::next_check(&mut self.fast_reconnect) => {
+                self.poll_fast_reconnect()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Fast reconnect failure: {:?}. Ignoring", e);
+                        });
+                Ok(())
+            },
             _ = self.polling_interval.tick() => {
                 telio_log_debug!("WG consolidation triggered by tick event, total logs dropped: {}", logs_dropped_until_now());
                 let dropped = logs_dropped_since_last_checked();
                 if dropped > 0 {
                     telio_log_warn!("New logs dropped: {dropped}");
                 }
+                if self.requested_state.paused {
+                    return Ok(());
+                }
+                self.check_clock()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Clock check failure: {:?}. Ignoring", e);
+                        });
+                self.tune_keepalive()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Keepalive tuning failure: {:?}. Ignoring", e);
+                        });
+                self.poll_exit_transport()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Exit transport fallback failure: {:?}. Ignoring", e);
+                        });
+                self.poll_exit_failover()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Exit failover failure: {:?}. Ignoring", e);
+                        });
+                self.poll_relay_health()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Relay health check failure: {:?}. Ignoring", e);
+                        });
+                self.update_replay_protection()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Replay protection update failure: {:?}. Ignoring", e);
+                        });
+                self.save_restart_state();
+                self.poll_accounting()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Traffic accounting failure: {:?}. Ignoring", e);
+                        });
+                #[cfg(feature = "health_endpoint")]
+                self.publish_health()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Health endpoint update failure: {:?}. Ignoring", e);
+                        });
                 wg_controller::consolidate_wg_state(&self.requested_state, &self.entities, &self.features)
                     .boxed()
                     .await
@@ -2600,6 +5699,12 @@ impl TaskRuntime for Runtime {
                         |e| {
                             telio_log_warn!("WireGuard controller failure: {:?}. Ignoring", e);
                         });
+                self.poll_key_rotation()
+                    .await
+                    .unwrap_or_else(
+                        |e| {
+                            telio_log_warn!("Key rotation failure: {:?}. Ignoring", e);
+                        });
                 Ok(())
             },
 
@@ -2637,6 +5742,14 @@ impl TaskRuntime for Runtime {
 
         let _ = self.stop_dns().boxed().await;
 
+        if let Some(probe) = self.nat_binding_probe.take() {
+            probe.stop().await;
+        }
+
+        if let Some(monitor) = self.relay_health.take() {
+            monitor.stop().await;
+        }
+
         // Nurse is keeping Arc to Derp, so we need to get rid of it before stopping Derp
         if let Some(nurse) = self.entities.nurse.as_ref() {
             nurse.configure_meshnet(None).await;
@@ -2657,6 +5770,18 @@ impl TaskRuntime for Runtime {
             ens.stop().await;
         }
 
+        if let Some(nested_hop) = self.entities.exit_chain.take() {
+            nested_hop.stop().await;
+        }
+
+        if let Some(obfuscated_hop) = self.entities.exit_obfuscation.take() {
+            obfuscated_hop.stop().await;
+        }
+
+        if let Some(stream_hop) = self.entities.exit_stream.take() {
+            stream_hop.stop().await;
+        }
+
         stop_arc_entity!(self.entities.wireguard_interface, "WireguardInterface");
 
         self.requested_state = Default::default();
@@ -2736,6 +5861,23 @@ fn node_from_exit_node(exit_node: &ExitNode) -> Node {
     }
 }
 
+/// Addresses of the local interfaces
+fn local_addresses() -> Vec<IpAddr> {
+    gather_local_interfaces(&SystemGetIfAddrs)
+        .unwrap_or_else(|e| {
+            telio_log_warn!("Failed to gather local interfaces: {e}");
+            Vec::new()
+        })
+        .iter()
+        .map(|interface| interface.ip())
+        .collect()
+}
+
+/// Identify the current network by the addresses of the local interfaces
+fn current_network_id() -> NetworkId {
+    NetworkId::from_local_addresses(&local_addresses())
+}
+
 /// Find a peer with matching public key in meshnet_config and retrieve the needed
 /// information about it from there
 fn get_config_peer<'a>(config: Option<&'a Config>, public_key: &'a PublicKey) -> Option<&'a Peer> {
@@ -2850,6 +5992,20 @@ mod tests {
         assert_eq!(records["gamma.nord"].clone(), vec![IpAddr::V6(gamma_ipv6)]);
 
         assert_eq!(records.len(), 3);
+
+        let with_ipv6 = retain_reachable_records(records.clone(), true);
+        assert_eq!(with_ipv6, records);
+
+        let without_ipv6 = retain_reachable_records(records, false);
+        assert_eq!(
+            without_ipv6["alpha.nord"].clone(),
+            vec![IpAddr::V4(alpha_ipv4)]
+        );
+        assert_eq!(
+            without_ipv6["beta.nord"].clone(),
+            vec![IpAddr::V4(beta_ipv4)]
+        );
+        assert!(!without_ipv6.contains_key("gamma.nord"));
     }
 
     #[test]
@@ -3033,6 +6189,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3078,6 +6235,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3155,6 +6313,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3243,6 +6402,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3341,6 +6501,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3421,6 +6582,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3513,6 +6675,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3586,6 +6749,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3655,6 +6819,53 @@ mod tests {
         fn set_ext_if_filter(&self, _list: &[String]) {}
     }
 
+    #[cfg(all(not(windows), feature = "strict_privacy"))]
+    #[tokio::test(start_paused = true)]
+    async fn test_strict_privacy_starts_no_analytics_nor_relay_health_checks() {
+        struct CountingSink(Arc<std::sync::atomic::AtomicUsize>);
+
+        impl AnalyticsSink for CountingSink {
+            fn heartbeat(&self, _info: &telio_nurse::data::HeartbeatInfo) {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+
+            fn service_quality(&self, _record: &telio_nurse::ServiceQualityRecord) {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        let (sender, _receiver) = tokio::sync::broadcast::channel(1);
+        let records = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let features = Features {
+            nurse: Some(Default::default()),
+            derp: Some(telio_model::features::FeatureDerp {
+                health_check: Some(Default::default()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let rt = Runtime::start(
+            sender,
+            DeviceConfig {
+                private_key: SecretKey::gen(),
+                ..Default::default()
+            },
+            features,
+            None,
+            vec![Arc::new(CountingSink(records.clone()))],
+        )
+        .await
+        .unwrap();
+
+        assert!(rt.entities.nurse.is_none());
+        assert!(rt.relay_health.is_none());
+        assert!(!telio_lana::is_lana_initialized());
+
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        assert_eq!(records.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
     #[cfg(not(windows))]
     #[tokio::test(start_paused = true)]
     async fn test_set_config_with_failing_session_keeper_start() {
@@ -3684,6 +6895,7 @@ mod tests {
             },
             features,
             Some(protect),
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3744,6 +6956,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3809,6 +7022,7 @@ mod tests {
             },
             Default::default(),
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3854,6 +7068,7 @@ mod tests {
             },
             features,
             None,
+            Vec::new(),
         )
         .await
         .unwrap();
@@ -3921,4 +7136,29 @@ mod tests {
         assert!(Runtime::sanitize_neptun_config(Some(12345), AdapterType::NepTUN).is_some());
         assert!(Runtime::sanitize_neptun_config(Some(12345), AdapterType::LinuxNativeWg).is_none());
     }
+
+    #[test]
+    fn test_exit_transport_fallback_order() {
+        let order = [StreamTransport::Tls, StreamTransport::Websocket];
+        let mut exit_transport = ExitTransport::new(ExitNode::default());
+        let now = Instant::now();
+
+        let tried: Vec<_> = (0..4)
+            .map(|_| exit_transport.advance(&order, now))
+            .collect();
+        assert_eq!(
+            tried,
+            vec![
+                Some(StreamTransport::Tls),
+                Some(StreamTransport::Websocket),
+                None,
+                Some(StreamTransport::Tls),
+            ]
+        );
+        assert_eq!(exit_transport.since, now);
+        assert_eq!(
+            ExitTransport::new(ExitNode::default()).advance(&[], now),
+            None
+        );
+    }
 }
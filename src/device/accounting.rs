@@ -0,0 +1,332 @@
+//! Accounting of the traffic exchanged with the peers, for apps keeping track of data caps.
+//!
+//! On every poll, the byte counters of WireGuard and the packet counters of the firewall are
+//! sampled, and the traffic since the previous sample is added to the buckets of the current
+//! minute and of the current hour. Minute buckets are kept for an hour and hour buckets for a
+//! day, the traffic of the last minute, hour and day is summed up from them. Buckets are aligned
+//! to the wall clock, so that the rollups saved to a file stay meaningful across restarts.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use telio_crypto::PublicKey;
+use telio_firewall::accounting::PacketCount;
+use telio_model::features::FeatureAccounting;
+use telio_model::mesh::{PeerTraffic, TrafficCounters};
+use telio_utils::{telio_log_debug, telio_log_warn};
+use telio_wg::uapi::Peer;
+
+/// Minutes of the last hour, the bucket of the minute an hour ago included
+const KEPT_MINUTES: u64 = 60;
+/// Hours of the last day, the bucket of the hour a day ago included
+const KEPT_HOURS: u64 = 24;
+
+/// Traffic of a peer within a bucket
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct PeerBucket {
+    traffic: TrafficCounters,
+    exit_node: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Bucket {
+    /// Start of the bucket, in minutes or hours since the epoch
+    start: u64,
+    peers: BTreeMap<PublicKey, PeerBucket>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Rollups {
+    /// Minute buckets, oldest first
+    minutes: VecDeque<Bucket>,
+    /// Hour buckets, oldest first
+    hours: VecDeque<Bucket>,
+}
+
+/// Rolling accounting of the traffic exchanged with the peers
+#[derive(Debug, Default)]
+pub struct TrafficAccounting {
+    rollups: Rollups,
+    /// Byte counters of the peers at the previous sample, as received and sent
+    last_bytes: HashMap<PublicKey, (u64, u64)>,
+    path: Option<String>,
+    /// Minute of the latest save of the rollups
+    saved_minute: u64,
+}
+
+impl TrafficAccounting {
+    /// Start the accounting, with the rollups saved to the file of the `config` before
+    pub fn new(config: &FeatureAccounting, now_s: u64) -> Self {
+        let mut rollups = config
+            .path
+            .as_ref()
+            .and_then(|path| {
+                let data = std::fs::read(path).ok()?;
+                serde_json::from_slice(&data)
+                    .map_err(|e| telio_log_warn!("Malformed traffic rollups in {path}: {e}"))
+                    .ok()
+            })
+            .unwrap_or_default();
+        prune(&mut rollups, now_s);
+
+        Self {
+            rollups,
+            last_bytes: HashMap::new(),
+            path: config.path.clone(),
+            saved_minute: now_s / 60,
+        }
+    }
+
+    /// Add the traffic of the `peers` since the previous sample, the `packets` being counted
+    /// since then, and save the rollups once a minute
+    pub fn record(
+        &mut self,
+        peers: &BTreeMap<PublicKey, Peer>,
+        mut packets: telio_firewall::firewall::HashMap<PublicKey, PacketCount>,
+        exit_node: Option<PublicKey>,
+        now_s: u64,
+    ) {
+        let (minute, hour) = (now_s / 60, now_s / 3600);
+        let mut last_bytes = HashMap::with_capacity(peers.len());
+
+        for (public_key, peer) in peers {
+            let bytes = (
+                peer.rx_bytes.unwrap_or_default(),
+                peer.tx_bytes.unwrap_or_default(),
+            );
+            let (last_rx, last_tx) = self.last_bytes.get(public_key).copied().unwrap_or_default();
+            last_bytes.insert(*public_key, bytes);
+
+            let packets = packets.remove(public_key).unwrap_or_default();
+            let traffic = TrafficCounters {
+                rx_bytes: since(bytes.0, last_rx),
+                tx_bytes: since(bytes.1, last_tx),
+                rx_packets: packets.received,
+                tx_packets: packets.sent,
+            };
+            if traffic == TrafficCounters::default() {
+                continue;
+            }
+
+            let exit_node = exit_node == Some(*public_key);
+            for bucket in IntoIterator::into_iter([
+                bucket_at(&mut self.rollups.minutes, minute),
+                bucket_at(&mut self.rollups.hours, hour),
+            ])
+            .flatten()
+            {
+                let peer = bucket.peers.entry(*public_key).or_default();
+                peer.traffic += traffic;
+                peer.exit_node |= exit_node;
+            }
+        }
+        self.last_bytes = last_bytes;
+
+        if minute != self.saved_minute {
+            prune(&mut self.rollups, now_s);
+            self.save();
+            self.saved_minute = minute;
+        }
+    }
+
+    /// Traffic of the peers exchanged with during the last day, ordered by their public keys
+    pub fn traffic(&self, now_s: u64) -> Vec<PeerTraffic> {
+        let (minute, hour) = (now_s / 60, now_s / 3600);
+        let mut traffic: BTreeMap<PublicKey, PeerTraffic> = BTreeMap::new();
+
+        for bucket in &self.rollups.minutes {
+            for (public_key, bucket_peer) in &bucket.peers {
+                let peer = peer_traffic(&mut traffic, public_key);
+                if bucket.start == minute {
+                    peer.last_minute += bucket_peer.traffic;
+                }
+                if bucket.start + KEPT_MINUTES >= minute {
+                    peer.last_hour += bucket_peer.traffic;
+                }
+            }
+        }
+        for bucket in &self.rollups.hours {
+            if bucket.start + KEPT_HOURS < hour {
+                continue;
+            }
+            for (public_key, bucket_peer) in &bucket.peers {
+                let peer = peer_traffic(&mut traffic, public_key);
+                peer.last_day += bucket_peer.traffic;
+                peer.exit_node |= bucket_peer.exit_node;
+            }
+        }
+        traffic.into_values().collect()
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        telio_log_debug!("Saving traffic rollups to {path}");
+        if let Err(e) = serde_json::to_vec(&self.rollups)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(path, data))
+        {
+            telio_log_warn!("Failed to save traffic rollups to {path}: {e}");
+        }
+    }
+}
+
+/// Increase of a counter, which starts from zero again when the peer is added again
+fn since(counter: u64, last: u64) -> u64 {
+    counter.checked_sub(last).unwrap_or(counter)
+}
+
+/// Bucket starting at `start`, or the latest one when the clock went back
+fn bucket_at(buckets: &mut VecDeque<Bucket>, start: u64) -> Option<&mut Bucket> {
+    if buckets.back().is_none_or(|bucket| bucket.start < start) {
+        buckets.push_back(Bucket {
+            start,
+            peers: BTreeMap::new(),
+        });
+    }
+    buckets.back_mut()
+}
+
+fn peer_traffic<'a>(
+    traffic: &'a mut BTreeMap<PublicKey, PeerTraffic>,
+    public_key: &PublicKey,
+) -> &'a mut PeerTraffic {
+    traffic.entry(*public_key).or_insert_with(|| PeerTraffic {
+        public_key: *public_key,
+        ..Default::default()
+    })
+}
+
+fn prune(rollups: &mut Rollups, now_s: u64) {
+    let (minute, hour) = (now_s / 60, now_s / 3600);
+    rollups
+        .minutes
+        .retain(|bucket| bucket.start + KEPT_MINUTES >= minute);
+    rollups
+        .hours
+        .retain(|bucket| bucket.start + KEPT_HOURS >= hour);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: PublicKey = PublicKey([1; 32]);
+    const EXIT: PublicKey = PublicKey([2; 32]);
+
+    fn peers(counters: &[(PublicKey, u64, u64)]) -> BTreeMap<PublicKey, Peer> {
+        counters
+            .iter()
+            .map(|(public_key, rx, tx)| {
+                (
+                    *public_key,
+                    Peer {
+                        public_key: *public_key,
+                        rx_bytes: Some(*rx),
+                        tx_bytes: Some(*tx),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn traffic_is_rolled_up_by_minutes_and_hours() {
+        let start = 1_000 * 3600;
+        let mut accounting = TrafficAccounting::new(&FeatureAccounting::default(), start);
+
+        let mut packets = telio_firewall::firewall::HashMap::default();
+        packets.insert(
+            PEER,
+            PacketCount {
+                received: 2,
+                sent: 1,
+            },
+        );
+        accounting.record(&peers(&[(PEER, 100, 10)]), packets, None, start);
+        accounting.record(
+            &peers(&[(PEER, 150, 10)]),
+            Default::default(),
+            None,
+            start + 30,
+        );
+        // Counters restart when the peer is added again
+        accounting.record(
+            &peers(&[(PEER, 20, 0), (EXIT, 1000, 500)]),
+            Default::default(),
+            Some(EXIT),
+            start + 600,
+        );
+
+        let traffic = accounting.traffic(start + 600);
+        assert_eq!(traffic.len(), 2);
+        assert_eq!(traffic[0].public_key, PEER);
+        assert!(!traffic[0].exit_node);
+        assert_eq!(
+            traffic[0].last_minute,
+            TrafficCounters {
+                rx_bytes: 20,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            traffic[0].last_hour,
+            TrafficCounters {
+                rx_bytes: 170,
+                tx_bytes: 10,
+                rx_packets: 2,
+                tx_packets: 1,
+            }
+        );
+        assert_eq!(traffic[0].last_day, traffic[0].last_hour);
+        assert!(traffic[1].exit_node);
+        assert_eq!(traffic[1].last_day.rx_bytes, 1000);
+
+        // The minute before is no longer the last one
+        let traffic = accounting.traffic(start + 660);
+        assert_eq!(traffic[0].last_minute, TrafficCounters::default());
+        assert_eq!(traffic[0].last_hour.rx_bytes, 170);
+
+        // An hour later only the last day is left
+        let traffic = accounting.traffic(start + 2 * 3600);
+        assert_eq!(traffic[0].last_hour, TrafficCounters::default());
+        assert_eq!(traffic[0].last_day.rx_bytes, 170);
+
+        // And after a day nothing is
+        accounting.record(
+            &BTreeMap::new(),
+            Default::default(),
+            None,
+            start + 26 * 3600,
+        );
+        assert!(accounting.traffic(start + 26 * 3600).is_empty());
+    }
+
+    #[test]
+    fn rollups_are_restored_from_file() {
+        let path = std::env::temp_dir().join(format!("telio-accounting-{}", std::process::id()));
+        let config = FeatureAccounting {
+            path: Some(path.to_string_lossy().into_owned()),
+        };
+        let start = 1_000 * 3600;
+
+        let mut accounting = TrafficAccounting::new(&config, start);
+        accounting.record(&peers(&[(PEER, 100, 10)]), Default::default(), None, start);
+        // Saved once the minute is over
+        accounting.record(
+            &peers(&[(PEER, 100, 10)]),
+            Default::default(),
+            None,
+            start + 60,
+        );
+
+        let restored = TrafficAccounting::new(&config, start + 120);
+        assert_eq!(
+            restored.traffic(start + 120),
+            accounting.traffic(start + 120)
+        );
+        assert_eq!(restored.traffic(start + 120)[0].last_hour.rx_bytes, 100);
+        let _ = std::fs::remove_file(path);
+    }
+}
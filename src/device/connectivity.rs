@@ -0,0 +1,383 @@
+//! Typed connectivity events derived from the state changes observed by the device.
+
+use super::wg_controller::DEFAULT_PEER_UPGRADE_WINDOW;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use telio_crypto::PublicKey;
+use telio_model::config::{RelayState, Server as DerpServer};
+use telio_model::event::{Connectivity, DowngradeReason, Event};
+use telio_model::features::PathType;
+use telio_model::mesh::{ConnectivityPath, Node, NodeState, PeerConnectivity};
+use telio_task::io::mc_chan;
+use telio_utils::Instant;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Remembers just enough of the past to tell why connectivity changed
+#[derive(Default)]
+pub struct ConnectivityTracker {
+    /// Relay server the device is connected to
+    relay: Option<DerpServer>,
+    /// Number of connections made to the relay servers
+    relay_connects: u32,
+    /// When the relay quality was reported the last time
+    last_quality_report: Option<Instant>,
+    /// When the current direct path of each peer was taken into use
+    direct_since: HashMap<PublicKey, Instant>,
+    /// When the local network changed the last time
+    network_changed_at: Option<Instant>,
+    /// Last reported state of the killswitch
+    killswitch_engaged: bool,
+    /// Current connectivity to each of the meshnet peers
+    links: HashMap<PublicKey, PeerConnectivity>,
+}
+
+impl ConnectivityTracker {
+    /// Handle a state change of the relay connection
+    pub fn relay_changed(&mut self, server: &DerpServer) -> Option<Connectivity> {
+        match server.conn_state {
+            RelayState::Connected => {
+                self.relay = Some(server.clone());
+                self.relay_connects = self.relay_connects.saturating_add(1);
+                Some(Connectivity::RelayConnected {
+                    server: server.clone(),
+                })
+            }
+            // Reconnection attempts report disconnects too, only the first one is interesting
+            RelayState::Disconnected | RelayState::Connecting => self
+                .relay
+                .take()
+                .map(|server| Connectivity::RelayDisconnected { server }),
+        }
+    }
+
+    /// Number of reconnections to the relay servers, the first connection is not counted
+    pub fn relay_reconnects(&self) -> u32 {
+        self.relay_connects.saturating_sub(1)
+    }
+
+    /// Whether the relay quality should be reported again, remembers the report if so
+    pub fn relay_quality_due(&mut self, period: Duration) -> bool {
+        let now = Instant::now();
+        if self
+            .last_quality_report
+            .is_some_and(|last| now.duration_since(last) < period)
+        {
+            return false;
+        }
+        self.last_quality_report = Some(now);
+        true
+    }
+
+    /// Handle the peer switching from the relay to a direct endpoint
+    pub fn path_upgraded(&mut self, public_key: PublicKey, endpoint: SocketAddr) -> Connectivity {
+        self.direct_since.insert(public_key, Instant::now());
+        if let Some(link) = self.links.get_mut(&public_key) {
+            link.failure_reason = None;
+        }
+        Connectivity::PathUpgraded {
+            public_key,
+            endpoint,
+        }
+    }
+
+    /// Handle the peer falling back from a direct endpoint to the relay
+    pub fn path_downgraded(&mut self, public_key: PublicKey) -> Connectivity {
+        let direct_since = self.direct_since.remove(&public_key);
+        let reason = match (direct_since, self.network_changed_at) {
+            (Some(since), Some(changed)) if changed >= since => DowngradeReason::EndpointChange,
+            (Some(since), _)
+                if since.elapsed() < Duration::from_secs(DEFAULT_PEER_UPGRADE_WINDOW) =>
+            {
+                DowngradeReason::HolePunchTimeout
+            }
+            _ => DowngradeReason::KeepaliveLoss,
+        };
+        if let Some(link) = self.links.get_mut(&public_key) {
+            link.failure_reason = Some(reason);
+        }
+        Connectivity::PathDowngraded { public_key, reason }
+    }
+
+    /// Handle a state change of a meshnet peer, the VPN servers are not part of the matrix
+    pub fn link_changed(&mut self, node: &Node) {
+        if node.is_vpn {
+            return;
+        }
+        let path = match (node.state, node.path, node.endpoint) {
+            (NodeState::Connected, PathType::Direct, Some(endpoint)) if endpoint.is_ipv6() => {
+                ConnectivityPath::DirectV6
+            }
+            (NodeState::Connected, PathType::Direct, _) => ConnectivityPath::DirectV4,
+            (NodeState::Connected, PathType::Relay, _) => ConnectivityPath::Relayed,
+            _ => ConnectivityPath::Down,
+        };
+
+        let link = self
+            .links
+            .entry(node.public_key)
+            .or_insert_with(|| PeerConnectivity {
+                public_key: node.public_key,
+                path,
+                last_change_s: unix_time(),
+                failure_reason: None,
+            });
+        if link.path != path {
+            link.path = path;
+            link.last_change_s = unix_time();
+        }
+    }
+
+    /// Forget the peers no longer in the meshnet
+    pub fn retain_links(&mut self, peers: &HashSet<PublicKey>) {
+        self.links
+            .retain(|public_key, _| peers.contains(public_key));
+    }
+
+    /// Number of the meshnet peers connected over any path
+    pub fn connected_links(&self) -> usize {
+        self.links
+            .values()
+            .filter(|link| link.path != ConnectivityPath::Down)
+            .count()
+    }
+
+    /// Current connectivity to each of the meshnet peers
+    pub fn matrix(&self) -> Vec<PeerConnectivity> {
+        self.links.values().cloned().collect()
+    }
+
+    /// Handle a change of the local network, which invalidates the direct endpoints
+    pub fn network_changed(&mut self) {
+        self.network_changed_at = Some(Instant::now());
+    }
+
+    /// Handle the killswitch being (re)applied, reports only actual changes
+    pub fn killswitch_changed(&mut self, engaged: bool) -> Option<Connectivity> {
+        if self.killswitch_engaged == engaged {
+            return None;
+        }
+        self.killswitch_engaged = engaged;
+        Some(Connectivity::Killswitch { engaged })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Asynchronous subscription to the connectivity events of the device.
+///
+/// Events are buffered in a bounded queue shared with the other event consumers. A subscriber
+/// falling too far behind does not stall the device, it loses the oldest events instead and
+/// learns how many were lost from [ConnectivityRecvError::Lagged].
+pub struct ConnectivitySubscription {
+    events: mc_chan::Rx<Box<Event>>,
+}
+
+/// Error of [ConnectivitySubscription::recv]
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ConnectivityRecvError {
+    /// The subscriber was too slow, the given number of events was dropped
+    #[error("Connectivity subscriber lagged behind by {0} events")]
+    Lagged(u64),
+    /// The device is gone, no more events will come
+    #[error("Device is gone")]
+    Closed,
+}
+
+impl ConnectivitySubscription {
+    pub(super) fn new(events: mc_chan::Rx<Box<Event>>) -> Self {
+        Self { events }
+    }
+
+    /// Wait for the next connectivity event
+    pub async fn recv(&mut self) -> Result<Connectivity, ConnectivityRecvError> {
+        loop {
+            match self.events.recv().await {
+                Ok(event) => {
+                    if let Event::Connectivity { body } = *event {
+                        return Ok(body);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    return Err(ConnectivityRecvError::Lagged(missed))
+                }
+                Err(RecvError::Closed) => return Err(ConnectivityRecvError::Closed),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_crypto::SecretKey;
+    use telio_model::event::Set;
+
+    #[test]
+    fn relay_disconnect_is_reported_once() {
+        let mut tracker = ConnectivityTracker::default();
+        let mut server = DerpServer {
+            conn_state: RelayState::Connecting,
+            ..Default::default()
+        };
+        assert_eq!(tracker.relay_changed(&server), None);
+
+        server.conn_state = RelayState::Connected;
+        assert!(matches!(
+            tracker.relay_changed(&server),
+            Some(Connectivity::RelayConnected { .. })
+        ));
+
+        server.conn_state = RelayState::Disconnected;
+        assert!(matches!(
+            tracker.relay_changed(&server),
+            Some(Connectivity::RelayDisconnected { .. })
+        ));
+        server.conn_state = RelayState::Connecting;
+        assert_eq!(tracker.relay_changed(&server), None);
+        assert_eq!(tracker.relay_reconnects(), 0);
+
+        server.conn_state = RelayState::Connected;
+        tracker.relay_changed(&server);
+        assert_eq!(tracker.relay_reconnects(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn relay_quality_is_reported_once_per_period() {
+        let mut tracker = ConnectivityTracker::default();
+        let period = Duration::from_secs(60);
+        assert!(tracker.relay_quality_due(period));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(!tracker.relay_quality_due(period));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(tracker.relay_quality_due(period));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn downgrade_reasons() {
+        let mut tracker = ConnectivityTracker::default();
+        let public_key = SecretKey::gen().public();
+        let endpoint = "1.2.3.4:5678".parse().unwrap();
+
+        tracker.path_upgraded(public_key, endpoint);
+        assert_eq!(
+            tracker.path_downgraded(public_key),
+            Connectivity::PathDowngraded {
+                public_key,
+                reason: DowngradeReason::HolePunchTimeout
+            }
+        );
+
+        tracker.path_upgraded(public_key, endpoint);
+        tokio::time::advance(Duration::from_secs(DEFAULT_PEER_UPGRADE_WINDOW + 1)).await;
+        assert_eq!(
+            tracker.path_downgraded(public_key),
+            Connectivity::PathDowngraded {
+                public_key,
+                reason: DowngradeReason::KeepaliveLoss
+            }
+        );
+
+        tracker.path_upgraded(public_key, endpoint);
+        tracker.network_changed();
+        assert_eq!(
+            tracker.path_downgraded(public_key),
+            Connectivity::PathDowngraded {
+                public_key,
+                reason: DowngradeReason::EndpointChange
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn matrix_follows_peer_paths() {
+        let mut tracker = ConnectivityTracker::default();
+        let public_key = SecretKey::gen().public();
+        let mut node = Node {
+            public_key,
+            state: NodeState::Connected,
+            path: PathType::Relay,
+            ..Default::default()
+        };
+        tracker.link_changed(&node);
+        tracker.link_changed(&Node {
+            is_vpn: true,
+            ..Default::default()
+        });
+        assert_eq!(tracker.matrix().len(), 1);
+        assert_eq!(tracker.matrix()[0].path, ConnectivityPath::Relayed);
+
+        node.path = PathType::Direct;
+        node.endpoint = Some("[2001:db8::1]:5678".parse().unwrap());
+        tracker.path_upgraded(public_key, node.endpoint.unwrap());
+        tracker.link_changed(&node);
+        assert_eq!(tracker.matrix()[0].path, ConnectivityPath::DirectV6);
+
+        tracker.path_downgraded(public_key);
+        node.path = PathType::Relay;
+        tracker.link_changed(&node);
+        assert_eq!(
+            tracker.matrix()[0].failure_reason,
+            Some(DowngradeReason::HolePunchTimeout)
+        );
+
+        node.state = NodeState::Disconnected;
+        tracker.link_changed(&node);
+        assert_eq!(tracker.matrix()[0].path, ConnectivityPath::Down);
+
+        tracker.retain_links(&HashSet::new());
+        assert!(tracker.matrix().is_empty());
+    }
+
+    #[test]
+    fn killswitch_changes_are_reported() {
+        let mut tracker = ConnectivityTracker::default();
+        assert_eq!(tracker.killswitch_changed(false), None);
+        assert_eq!(
+            tracker.killswitch_changed(true),
+            Some(Connectivity::Killswitch { engaged: true })
+        );
+        assert_eq!(tracker.killswitch_changed(true), None);
+    }
+
+    #[tokio::test]
+    async fn subscription_skips_other_events_and_reports_lag() {
+        let (tx, rx) = tokio::sync::broadcast::channel(2);
+        let mut subscription = ConnectivitySubscription::new(rx);
+
+        let event = |body| Box::new(Event::builder::<Connectivity>().set(body).build().unwrap());
+        tx.send(Box::new(
+            Event::builder::<DerpServer>()
+                .set(DerpServer::default())
+                .build()
+                .unwrap(),
+        ))
+        .unwrap();
+        tx.send(event(Connectivity::Killswitch { engaged: true }))
+            .unwrap();
+        assert_eq!(
+            subscription.recv().await,
+            Ok(Connectivity::Killswitch { engaged: true })
+        );
+
+        for engaged in [true, false, true] {
+            tx.send(event(Connectivity::Killswitch { engaged }))
+                .unwrap();
+        }
+        assert_eq!(
+            subscription.recv().await,
+            Err(ConnectivityRecvError::Lagged(1))
+        );
+
+        drop(tx);
+        assert_eq!(
+            subscription.recv().await,
+            Ok(Connectivity::Killswitch { engaged: false })
+        );
+    }
+}
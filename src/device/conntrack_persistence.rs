@@ -0,0 +1,122 @@
+//! Persistence of the connections tracked by the firewall across restarts of the device.
+//!
+//! The firewall starts with empty state, so after a restart it treats the packets of the
+//! connections established before as new ones, and resets them when they come from the outside.
+//! Saving the tracked connections on stop and restoring them on the next start keeps e.g. the TCP
+//! connections through an exit node alive, as long as the restart is brief.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use telio_firewall::firewall::ConntrackSnapshot;
+use telio_model::features::FeatureConntrackPersistence;
+use telio_utils::{telio_log_debug, telio_log_warn};
+
+/// Tracked connections saved on stop
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedConnections {
+    /// Wall clock time of saving, in seconds since the epoch, as it has to be comparable across
+    /// processes
+    saved_at: u64,
+    connections: ConntrackSnapshot,
+}
+
+/// Keeps the tracked connections between a stop and the next start, in memory and optionally in
+/// a file
+#[derive(Debug, Default)]
+pub struct ConntrackStore {
+    saved: Option<SavedConnections>,
+}
+
+impl ConntrackStore {
+    /// Remember the `connections` of the device being stopped
+    pub fn save(&mut self, config: &FeatureConntrackPersistence, connections: ConntrackSnapshot) {
+        let saved = SavedConnections {
+            saved_at: unix_time(),
+            connections,
+        };
+        telio_log_debug!("Saving {} tracked connections", saved.connections.len());
+        if let Some(path) = &config.path {
+            if let Err(e) = serde_json::to_vec(&saved)
+                .map_err(std::io::Error::from)
+                .and_then(|data| std::fs::write(path, data))
+            {
+                telio_log_warn!("Failed to save tracked connections to {path}: {e}");
+            }
+        }
+        self.saved = Some(saved);
+    }
+
+    /// Take the connections saved on the previous stop, unless they are too old to be relevant
+    pub fn restore(&mut self, config: &FeatureConntrackPersistence) -> Option<ConntrackSnapshot> {
+        let saved = self.saved.take().or_else(|| Self::load(config));
+        if let Some(path) = &config.path {
+            // Each snapshot is restored at most once
+            let _ = std::fs::remove_file(path);
+        }
+
+        let saved = saved?;
+        let age = Duration::from_secs(unix_time().saturating_sub(saved.saved_at));
+        if age > Duration::from_secs(config.max_age_s.into()) {
+            telio_log_debug!("Discarding tracked connections saved {age:?} ago");
+            return None;
+        }
+        Some(saved.connections)
+    }
+
+    fn load(config: &FeatureConntrackPersistence) -> Option<SavedConnections> {
+        let path = config.path.as_ref()?;
+        let data = std::fs::read(path).ok()?;
+        serde_json::from_slice(&data)
+            .map_err(|e| telio_log_warn!("Malformed tracked connections in {path}: {e}"))
+            .ok()
+    }
+}
+
+pub(super) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_restored_once() {
+        let config = FeatureConntrackPersistence::default();
+        let mut store = ConntrackStore::default();
+        assert!(store.restore(&config).is_none());
+
+        store.save(&config, ConntrackSnapshot::default());
+        assert!(store.restore(&config).is_some());
+        assert!(store.restore(&config).is_none());
+    }
+
+    #[test]
+    fn stale_connections_are_discarded() {
+        let config = FeatureConntrackPersistence::default();
+        let mut store = ConntrackStore {
+            saved: Some(SavedConnections {
+                saved_at: unix_time() - u64::from(config.max_age_s) - 1,
+                connections: ConntrackSnapshot::default(),
+            }),
+        };
+        assert!(store.restore(&config).is_none());
+    }
+
+    #[test]
+    fn connections_are_restored_from_file() {
+        let path = std::env::temp_dir().join(format!("telio-conntrack-{}", std::process::id()));
+        let config = FeatureConntrackPersistence {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        ConntrackStore::default().save(&config, ConntrackSnapshot::default());
+
+        let mut store = ConntrackStore::default();
+        assert!(store.restore(&config).is_some());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,282 @@
+//! Diagnostics bundle gathering the state support needs to look into connectivity issues.
+//!
+//! The device keeps the latest events delivered to the app, and separately the latest relay
+//! events, so that a busy meshnet does not push the relay history out. The rest of the bundle is
+//! collected from the running components when it is asked for. With `hide_user_data` the
+//! addresses in the peers, events and candidates are hidden the same way as in the logs, and the
+//! names of the nodes are left out.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use telio_firewall::firewall::ConntrackSummary;
+use telio_firewall::shaping::BandwidthClassStats;
+use telio_model::event::Event;
+use telio_model::features::Features;
+use telio_model::mesh::PeerStats;
+use telio_utils::PacketPoolStats;
+
+use crate::logging::LOG_CENSOR;
+
+/// Number of the latest events kept
+const RECENT_EVENTS_LEN: usize = 100;
+/// Number of the latest relay events kept
+const RELAY_HISTORY_LEN: usize = 32;
+
+/// Settings which may hold secrets or personal data, as JSON pointers
+const REDACTED_SETTINGS: &[&str] = &[
+    "/error_notification_service/root_certificate_override",
+    "/lana/event_path",
+];
+const REDACTED: &str = "<redacted>";
+
+/// Parts of the bundle which may hold the addresses of the peers
+const USER_DATA: &[&str] = &[
+    "peers",
+    "relay_history",
+    "recent_events",
+    "traversal_candidates",
+];
+
+/// Event delivered to the app
+#[derive(Clone, Debug, Serialize)]
+pub struct RecordedEvent {
+    /// When the event was delivered, in milliseconds since the UNIX epoch
+    pub at_ms: u64,
+    pub event: Event,
+}
+
+/// Latest events delivered to the app
+#[derive(Debug, Default)]
+pub struct EventHistory {
+    events: VecDeque<RecordedEvent>,
+    relay: VecDeque<RecordedEvent>,
+}
+
+impl EventHistory {
+    /// Remember the `event`, forgetting the oldest one when full
+    pub fn record(&mut self, event: &Event) {
+        let recorded = RecordedEvent {
+            at_ms: unix_time_ms(),
+            event: event.clone(),
+        };
+        if matches!(
+            event,
+            Event::Relay { .. } | Event::RelayRehoming { .. } | Event::RelayQuality { .. }
+        ) {
+            push_bounded(&mut self.relay, recorded.clone(), RELAY_HISTORY_LEN);
+        }
+        push_bounded(&mut self.events, recorded, RECENT_EVENTS_LEN);
+    }
+
+    /// Latest events and latest relay events, oldest first
+    pub fn snapshot(&self) -> (Vec<RecordedEvent>, Vec<RecordedEvent>) {
+        (
+            self.events.iter().cloned().collect(),
+            self.relay.iter().cloned().collect(),
+        )
+    }
+}
+
+fn push_bounded(events: &mut VecDeque<RecordedEvent>, event: RecordedEvent, len: usize) {
+    if events.len() >= len {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// State of the WireGuard adapter
+#[derive(Clone, Debug, Serialize)]
+pub struct AdapterState {
+    /// Type of the adapter, e.g. NepTUN
+    pub adapter_type: String,
+    pub listen_port: Option<u16>,
+    pub fwmark: u32,
+    /// Number of the peers configured on the adapter
+    pub peer_count: usize,
+}
+
+/// Endpoint candidates currently discovered by an endpoint provider
+#[derive(Clone, Debug, Serialize)]
+pub struct TraversalCandidates {
+    /// Name of the endpoint provider
+    pub provider: String,
+    /// WireGuard endpoints of the candidates
+    pub wg: Vec<SocketAddr>,
+    /// Endpoints of the candidates pinged by the peers
+    pub udp: Vec<SocketAddr>,
+}
+
+/// Everything support needs to look into connectivity issues, see
+/// [crate::device::Device::collect_diagnostics]
+#[derive(Clone, Debug, Serialize)]
+pub struct DiagnosticsBundle {
+    /// When the bundle was collected, in milliseconds since the UNIX epoch
+    pub collected_at_ms: u64,
+    pub version: String,
+    pub commit_sha: String,
+    pub adapter: AdapterState,
+    pub peers: Vec<PeerStats>,
+    /// Connections tracked by the firewall
+    pub conntrack: ConntrackSummary,
+    /// Traffic of the bandwidth classes, empty without the shaping
+    pub shaping: Vec<BandwidthClassStats>,
+    /// Latest relay events, oldest first
+    pub relay_history: Vec<RecordedEvent>,
+    /// Latest events, oldest first
+    pub recent_events: Vec<RecordedEvent>,
+    /// Endpoint candidates of the meshnet, empty without direct connections
+    pub traversal_candidates: Vec<TraversalCandidates>,
+    /// Packet buffer pools of the running components
+    pub packet_pools: Vec<PacketPoolStats>,
+    /// Features in use, with the secrets redacted
+    pub features: Value,
+    /// Whether the user data is hidden from the serialized bundle
+    #[serde(skip)]
+    pub hide_user_data: bool,
+}
+
+impl DiagnosticsBundle {
+    /// Serialize the bundle to a json string, with the user data hidden when asked to
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        if self.hide_user_data {
+            for part in USER_DATA {
+                if let Some(part) = value.get_mut(part) {
+                    hide_user_data(part);
+                }
+            }
+        }
+        serde_json::to_string(&value)
+    }
+}
+
+/// Hide the addresses in the strings of the `value` like the logs do, and leave out the names
+/// of the nodes in the events
+fn hide_user_data(value: &mut Value) {
+    match value {
+        Value::String(string) => *string = LOG_CENSOR.censor_logs(std::mem::take(string)),
+        Value::Array(values) => values.iter_mut().for_each(hide_user_data),
+        Value::Object(fields) => {
+            if fields.get("type").and_then(Value::as_str) == Some("node") {
+                if let Some(Value::Object(node)) = fields.get_mut("body") {
+                    for name in ["hostname", "nickname"] {
+                        if let Some(name) = node.get_mut(name).filter(|name| !name.is_null()) {
+                            *name = Value::from(REDACTED);
+                        }
+                    }
+                }
+            }
+            fields.values_mut().for_each(hide_user_data);
+        }
+        _ => (),
+    }
+}
+
+/// The `features` as JSON, with the settings which may hold secrets replaced
+pub fn redacted_features(features: &Features) -> Value {
+    let mut value = serde_json::to_value(features).unwrap_or_default();
+    for pointer in REDACTED_SETTINGS {
+        if let Some(setting) = value
+            .pointer_mut(pointer)
+            .filter(|setting| !setting.is_null())
+        {
+            *setting = Value::from(REDACTED);
+        }
+    }
+    value
+}
+
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_model::config::Server as DerpServer;
+    use telio_model::features::{FeatureErrorNotificationService, FeatureLana};
+    use telio_model::mesh::Node;
+
+    #[test]
+    fn relay_history_outlives_other_events() {
+        let mut history = EventHistory::default();
+        let relay = Event::Relay {
+            body: DerpServer::default(),
+        };
+        let error = Event::Error {
+            body: Default::default(),
+        };
+
+        history.record(&relay);
+        for _ in 0..RECENT_EVENTS_LEN {
+            history.record(&error);
+        }
+
+        let (events, relay_history) = history.snapshot();
+        assert_eq!(events.len(), RECENT_EVENTS_LEN);
+        assert!(events
+            .iter()
+            .all(|recorded| matches!(recorded.event, Event::Error { .. })));
+        assert_eq!(relay_history.len(), 1);
+        assert!(matches!(relay_history[0].event, Event::Relay { .. }));
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let features = Features {
+            lana: Some(FeatureLana {
+                event_path: "/home/user/events.db".to_owned(),
+                prod: false,
+            }),
+            error_notification_service: Some(FeatureErrorNotificationService {
+                root_certificate_override: Some(vec![1, 2, 3]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let value = redacted_features(&features);
+        assert_eq!(value["lana"]["event_path"], REDACTED);
+        assert_eq!(
+            value["error_notification_service"]["root_certificate_override"],
+            REDACTED
+        );
+        assert_eq!(value["hide_user_data"], true);
+
+        // Unset settings stay unset
+        let value = redacted_features(&Features::default());
+        assert_eq!(value["lana"], Value::Null);
+    }
+
+    #[test]
+    fn user_data_is_hidden() {
+        let endpoint: SocketAddr = "1.2.3.4:51820".parse().unwrap();
+        let mut value = serde_json::to_value(vec![RecordedEvent {
+            at_ms: 0,
+            event: Event::Node {
+                body: Node {
+                    hostname: Some("peer.nord".to_owned()),
+                    nickname: None,
+                    endpoint: Some(endpoint),
+                    ..Default::default()
+                },
+            },
+        }])
+        .unwrap();
+
+        hide_user_data(&mut value);
+        let node = &value[0]["event"]["body"];
+        assert_eq!(node["hostname"], REDACTED);
+        assert_eq!(node["nickname"], Value::Null);
+        assert_eq!(
+            node["endpoint"],
+            LOG_CENSOR.censor_logs(endpoint.to_string())
+        );
+        assert_eq!(value[0]["event"]["type"], "node");
+    }
+}
@@ -0,0 +1,171 @@
+//! Failover between the exits set with libtelio.set_exit_failover(...).
+//!
+//! The active exit is checked on every polling tick. It is degraded when no handshake completed
+//! with it for `max_handshake_age_s`, or when the QoS analytics measure more ping loss than
+//! `max_ping_loss_percent`. A degraded exit is replaced by the next one in the list, starting over
+//! with the first one after the last. Every exit gets `max_handshake_age_s` to handshake after
+//! being connected.
+
+use std::time::Duration;
+
+use telio_model::{
+    config::{ExitFailover, FailoverExit},
+    event::ExitDegradation,
+    mesh::{ExitNode, PeerQos},
+};
+use telio_utils::Instant;
+use telio_wg::uapi::Peer;
+
+/// Exit failover in progress
+pub(crate) struct ExitFailoverState {
+    config: ExitFailover,
+    /// Position of the active exit
+    position: usize,
+    /// When the active exit was connected
+    since: Instant,
+}
+
+impl ExitFailoverState {
+    /// Failover of the `config` starting with its first exit, none when there is no exit
+    pub(crate) fn new(config: ExitFailover, now: Instant) -> Option<Self> {
+        if config.exits.is_empty() {
+            return None;
+        }
+        Some(Self {
+            config,
+            position: 0,
+            since: now,
+        })
+    }
+
+    /// Exit which should be connected
+    pub(crate) fn active(&self) -> Option<&FailoverExit> {
+        self.config.exits.get(self.position)
+    }
+
+    /// How the active exit degraded, if it did, given its WireGuard `peer` and `qos`
+    pub(crate) fn degradation(
+        &self,
+        peer: Option<&Peer>,
+        qos: Option<&PeerQos>,
+        now: Instant,
+    ) -> Option<ExitDegradation> {
+        let connected_for = now.saturating_duration_since(self.since);
+        let handshake_age = peer
+            .and_then(|peer| peer.time_since_last_handshake)
+            .map_or(connected_for, |age| age.min(connected_for));
+        if handshake_age >= Duration::from_secs(self.config.max_handshake_age_s.into()) {
+            return Some(ExitDegradation::StaleHandshake);
+        }
+
+        let max_loss = self.config.max_ping_loss_percent?;
+        qos.filter(|qos| qos.samples > 0 && qos.loss_percent > max_loss)
+            .map(|_| ExitDegradation::PingLoss)
+    }
+
+    /// Move on to the next exit, returns it unless it is the active one already
+    pub(crate) fn advance(&mut self, now: Instant) -> Option<&FailoverExit> {
+        let previous = self.position;
+        self.position = (self.position + 1) % self.config.exits.len();
+        self.since = now;
+        if self.position == previous {
+            return None;
+        }
+        self.active()
+    }
+}
+
+/// Exit node connecting the failover `exit`
+pub(crate) fn exit_node(exit: &FailoverExit) -> ExitNode {
+    ExitNode {
+        identifier: uuid::Uuid::new_v4().to_string(),
+        public_key: exit.public_key,
+        allowed_ips: exit.allowed_ips.clone(),
+        endpoint: exit.endpoint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_crypto::PublicKey;
+
+    fn exit(byte: u8) -> FailoverExit {
+        FailoverExit {
+            public_key: PublicKey([byte; 32]),
+            allowed_ips: None,
+            endpoint: None,
+        }
+    }
+
+    fn failover(exits: Vec<FailoverExit>, now: Instant) -> ExitFailoverState {
+        ExitFailoverState::new(
+            ExitFailover {
+                exits,
+                max_handshake_age_s: 180,
+                max_ping_loss_percent: Some(30),
+            },
+            now,
+        )
+        .unwrap()
+    }
+
+    fn peer(handshake_age_s: Option<u64>) -> Peer {
+        Peer {
+            time_since_last_handshake: handshake_age_s.map(Duration::from_secs),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exit_degrades_on_stale_handshake_or_ping_loss() {
+        let now = Instant::now();
+        let failover = failover(vec![exit(1), exit(2)], now);
+        let later = now + Duration::from_secs(600);
+
+        // New exit is given time to handshake
+        assert_eq!(failover.degradation(None, None, now), None);
+        assert_eq!(
+            failover.degradation(None, None, later),
+            Some(ExitDegradation::StaleHandshake)
+        );
+        assert_eq!(
+            failover.degradation(Some(&peer(Some(60))), None, later),
+            None
+        );
+        assert_eq!(
+            failover.degradation(Some(&peer(Some(180))), None, later),
+            Some(ExitDegradation::StaleHandshake)
+        );
+
+        let qos = |loss_percent| PeerQos {
+            public_key: PublicKey([1; 32]),
+            loss_percent,
+            samples: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            failover.degradation(Some(&peer(Some(60))), Some(&qos(30)), later),
+            None
+        );
+        assert_eq!(
+            failover.degradation(Some(&peer(Some(60))), Some(&qos(50)), later),
+            Some(ExitDegradation::PingLoss)
+        );
+    }
+
+    #[test]
+    fn exits_are_tried_in_order() {
+        let now = Instant::now();
+        let mut failover = failover(vec![exit(1), exit(2), exit(3)], now);
+
+        assert_eq!(failover.active(), Some(&exit(1)));
+        assert_eq!(failover.advance(now), Some(&exit(2)));
+        assert_eq!(failover.advance(now), Some(&exit(3)));
+        assert_eq!(failover.advance(now), Some(&exit(1)));
+
+        let mut single = self::failover(vec![exit(1)], now);
+        assert_eq!(single.advance(now), None);
+        assert!(ExitFailoverState::new(ExitFailover::default(), now).is_none());
+    }
+}
@@ -0,0 +1,166 @@
+//! Progress of the fast reconnect after the network changed.
+//!
+//! Peers connected before the change are awaited until each of them is heard from over the new
+//! network, or until the budget of `fast_reconnect.budget_ms` is spent. They are checked every
+//! [CHECK_PERIOD], and the handshakes of the ones not heard from yet are retried. The outcome is
+//! reported with a [Connectivity::NetworkReconnect] event.
+//!
+//! [Connectivity::NetworkReconnect]: telio_model::event::Connectivity::NetworkReconnect
+
+use std::{collections::BTreeMap, future::pending, time::Duration};
+
+use telio_crypto::PublicKey;
+use telio_model::event::Connectivity;
+use telio_utils::{interval_after, Instant};
+use telio_wg::uapi::Peer;
+use tokio::time::Interval;
+
+/// Period of the checks of the awaited peers
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Fast reconnect in progress
+pub(crate) struct FastReconnect {
+    started: Instant,
+    budget: Duration,
+    /// Peers connected before the change and not heard from since
+    pending: Vec<PublicKey>,
+    reconnected: u32,
+    /// Time until the last of the reconnected peers was heard from
+    duration: Duration,
+    checks: Interval,
+}
+
+impl FastReconnect {
+    /// Await the `peers` connected when the network changed
+    pub(crate) fn new(peers: Vec<PublicKey>, budget: Duration, now: Instant) -> Self {
+        Self {
+            started: now,
+            budget,
+            pending: peers,
+            reconnected: 0,
+            duration: Duration::ZERO,
+            checks: interval_after(CHECK_PERIOD, CHECK_PERIOD),
+        }
+    }
+
+    /// Wait for the next check of the `reconnect` in progress, forever when there is none
+    pub(crate) async fn next_check(reconnect: &mut Option<Self>) {
+        match reconnect {
+            Some(reconnect) => {
+                reconnect.checks.tick().await;
+            }
+            None => pending().await,
+        }
+    }
+
+    /// Peers not heard from yet
+    pub(crate) fn pending(&self) -> &[PublicKey] {
+        &self.pending
+    }
+
+    /// Take the current state of the WireGuard `peers` into account. Returns the outcome once all
+    /// of the awaited peers were heard from or the budget is spent. Peers removed from the
+    /// interface meanwhile are not awaited anymore.
+    pub(crate) fn update(
+        &mut self,
+        peers: &BTreeMap<PublicKey, Peer>,
+        now: Instant,
+    ) -> Option<Connectivity> {
+        let elapsed = now.saturating_duration_since(self.started);
+        let mut reconnected = 0;
+        let mut duration = self.duration;
+        self.pending.retain(|public_key| {
+            let Some(peer) = peers.get(public_key) else {
+                return false;
+            };
+            match peer.time_since_last_rx {
+                Some(since_rx) if since_rx < elapsed => {
+                    reconnected += 1;
+                    duration = duration.max(elapsed - since_rx);
+                    false
+                }
+                _ => true,
+            }
+        });
+        self.reconnected += reconnected;
+        self.duration = duration;
+
+        if !self.pending.is_empty() && elapsed < self.budget {
+            return None;
+        }
+        let duration = match self.pending.is_empty() {
+            true => self.duration,
+            false => self.budget,
+        };
+        Some(Connectivity::NetworkReconnect {
+            duration_ms: duration.as_millis() as u64,
+            reconnected_peers: self.reconnected,
+            unreachable_peers: self.pending.len() as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_A: PublicKey = PublicKey([1; 32]);
+    const PEER_B: PublicKey = PublicKey([2; 32]);
+
+    fn peers(rx: &[(PublicKey, Option<u64>)]) -> BTreeMap<PublicKey, Peer> {
+        rx.iter()
+            .map(|(public_key, since_rx_ms)| {
+                let peer = Peer {
+                    public_key: *public_key,
+                    time_since_last_rx: since_rx_ms.map(Duration::from_millis),
+                    ..Default::default()
+                };
+                (*public_key, peer)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reconnect_finishes_when_all_peers_are_heard_from() {
+        let now = Instant::now();
+        let mut reconnect = FastReconnect::new(vec![PEER_A, PEER_B], Duration::from_secs(5), now);
+
+        // Traffic from before the change does not count
+        let at = now + Duration::from_millis(500);
+        assert_eq!(
+            reconnect.update(&peers(&[(PEER_A, Some(100)), (PEER_B, Some(900))]), at),
+            None
+        );
+        assert_eq!(reconnect.pending(), &[PEER_B]);
+
+        let at = now + Duration::from_millis(1500);
+        assert_eq!(
+            reconnect.update(&peers(&[(PEER_A, Some(50)), (PEER_B, Some(300))]), at),
+            Some(Connectivity::NetworkReconnect {
+                duration_ms: 1200,
+                reconnected_peers: 2,
+                unreachable_peers: 0,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_is_bounded_by_budget() {
+        let now = Instant::now();
+        let mut reconnect = FastReconnect::new(vec![PEER_A, PEER_B], Duration::from_secs(5), now);
+
+        let at = now + Duration::from_secs(5);
+        assert_eq!(
+            reconnect.update(&peers(&[(PEER_A, Some(4000)), (PEER_B, None)]), at),
+            Some(Connectivity::NetworkReconnect {
+                duration_ms: 5000,
+                reconnected_peers: 1,
+                unreachable_peers: 1,
+            })
+        );
+
+        // Removed peers are not awaited
+        let mut reconnect = FastReconnect::new(vec![PEER_A], Duration::from_secs(5), now);
+        assert!(reconnect.update(&peers(&[]), now).is_some());
+    }
+}
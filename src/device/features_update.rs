@@ -0,0 +1,232 @@
+//! Changes of the features of a running device.
+//!
+//! Features are compared field by field through their JSON form, so that new fields are covered
+//! without listing them here. The fields in [HOT_FEATURES] are applied to the running
+//! components, the ones in [MESHNET_FEATURES] by restarting the meshnet components, every other
+//! change waits for the device to be started again.
+
+use serde_json::Value;
+use telio_model::features::Features;
+
+/// Features applied to the running device in place
+const HOT_FEATURES: &[&str] = &[
+    "wireguard.persistent_keepalive",
+    "firewall.exclude_private_ip_range",
+    "firewall.outgoing_blacklist",
+    "hide_user_data",
+    "hide_thread_id",
+    "flush_events_on_stop_timeout_seconds",
+    "startup_timeout_seconds",
+    "nurse.heartbeat_interval",
+    "nurse.initial_heartbeat_interval",
+    "nurse.qos.rtt_interval",
+];
+
+/// Features read when the meshnet components are started, applied by restarting them
+const MESHNET_FEATURES: &[&str] = &[
+    "direct",
+    "derp.tcp_keepalive",
+    "derp.derp_keepalive",
+    "derp.poll_keepalive",
+    "derp.enable_polling",
+    "derp.use_built_in_root_certificates",
+    "derp.transport",
+    "derp.long_poll",
+];
+
+/// Report of libtelio.update_features(...)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FeaturesUpdate {
+    /// Changed features which are in effect already
+    pub applied: Vec<String>,
+    /// Changed features put in effect by restarting the meshnet components
+    pub restarted: Vec<String>,
+    /// Changed features which take effect once the device is started again
+    pub restart_required: Vec<String>,
+}
+
+impl FeaturesUpdate {
+    /// Dotted paths of the features changed between `old` and `new`, split by whether they can
+    /// be applied in place
+    pub fn new(old: &Features, new: &Features) -> Self {
+        let mut changed = Vec::new();
+        diff(
+            "",
+            &serde_json::to_value(old).unwrap_or_default(),
+            &serde_json::to_value(new).unwrap_or_default(),
+            &mut changed,
+        );
+
+        let (applied, rest): (Vec<_>, Vec<_>) = changed
+            .into_iter()
+            .partition(|path| is_listed(HOT_FEATURES, path));
+        let (restarted, restart_required) = rest
+            .into_iter()
+            .partition(|path| is_listed(MESHNET_FEATURES, path));
+        Self {
+            applied,
+            restarted,
+            restart_required,
+        }
+    }
+
+    /// Whether any of the features changed
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.restarted.is_empty() && self.restart_required.is_empty()
+    }
+}
+
+/// `old` features with only the changes which can be applied without restarting the device
+/// taken over from `new`
+pub(crate) fn merge_hot(old: &Features, new: &Features) -> Features {
+    let mut merged = old.clone();
+    merged.wireguard.persistent_keepalive = new.wireguard.persistent_keepalive.clone();
+    merged.firewall.exclude_private_ip_range = new.firewall.exclude_private_ip_range;
+    merged.firewall.outgoing_blacklist = new.firewall.outgoing_blacklist.clone();
+    merged.hide_user_data = new.hide_user_data;
+    merged.hide_thread_id = new.hide_thread_id;
+    merged.flush_events_on_stop_timeout_seconds = new.flush_events_on_stop_timeout_seconds;
+    merged.startup_timeout_seconds = new.startup_timeout_seconds;
+    if let (Some(merged), Some(new)) = (merged.nurse.as_mut(), new.nurse.as_ref()) {
+        merged.heartbeat_interval = new.heartbeat_interval;
+        merged.initial_heartbeat_interval = new.initial_heartbeat_interval;
+        if let (Some(merged), Some(new)) = (merged.qos.as_mut(), new.qos.as_ref()) {
+            merged.rtt_interval = new.rtt_interval;
+        }
+    }
+
+    merged.direct = new.direct.clone();
+    if let (Some(merged), Some(new)) = (merged.derp.as_mut(), new.derp.as_ref()) {
+        merged.tcp_keepalive = new.tcp_keepalive;
+        merged.derp_keepalive = new.derp_keepalive;
+        merged.poll_keepalive = new.poll_keepalive;
+        merged.enable_polling = new.enable_polling;
+        merged.use_built_in_root_certificates = new.use_built_in_root_certificates;
+        merged.transport = new.transport;
+        merged.long_poll = new.long_poll;
+    }
+    merged
+}
+
+fn is_listed(features: &[&str], path: &str) -> bool {
+    features.iter().any(|hot| {
+        path.strip_prefix(hot)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// Collect the paths of the leaves which differ, a field added or removed counts as a leaf
+fn diff(path: &str, old: &Value, new: &Value, changed: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = match path {
+                    "" => key.clone(),
+                    _ => format!("{path}.{key}"),
+                };
+                diff(
+                    &path,
+                    old.get(key).unwrap_or(&Value::Null),
+                    new.get(key).unwrap_or(&Value::Null),
+                    changed,
+                );
+            }
+        }
+        (old, new) if old != new => changed.push(path.to_owned()),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_model::features::{
+        FeatureDerp, FeatureDirect, FeatureNurse, FeaturePersistentKeepalive, FeatureQoS,
+    };
+
+    #[test]
+    fn changes_are_split_by_hot_reload() {
+        let old = Features::default();
+        let mut new = Features::default();
+        new.wireguard.persistent_keepalive = FeaturePersistentKeepalive {
+            direct: 10,
+            ..Default::default()
+        };
+        new.hide_user_data = !old.hide_user_data;
+        new.nurse = Some(FeatureNurse::default());
+        new.wireguard.polling.wireguard_polling_period = 500;
+        new.direct = Some(FeatureDirect::default());
+
+        let update = FeaturesUpdate::new(&old, &new);
+        assert_eq!(
+            update.applied,
+            vec!["hide_user_data", "wireguard.persistent_keepalive.direct"]
+        );
+        assert_eq!(update.restarted, vec!["direct"]);
+        assert!(update.restart_required.contains(&"nurse".to_owned()));
+        assert!(update
+            .restart_required
+            .contains(&"wireguard.polling.wireguard_polling_period".to_owned()));
+        assert!(FeaturesUpdate::new(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn only_hot_changes_are_merged() {
+        let old = Features::default();
+        let mut new = Features::default();
+        new.startup_timeout_seconds = Some(5);
+        new.ipv6 = !old.ipv6;
+
+        let merged = merge_hot(&old, &new);
+        assert_eq!(merged.startup_timeout_seconds, Some(5));
+        assert_eq!(merged.ipv6, old.ipv6);
+    }
+
+    #[test]
+    fn nurse_intervals_are_hot() {
+        let mut old = Features::default();
+        old.nurse = Some(FeatureNurse {
+            qos: Some(FeatureQoS::default()),
+            ..Default::default()
+        });
+        old.derp = Some(FeatureDerp::default());
+        let mut new = old.clone();
+        if let Some(nurse) = new.nurse.as_mut() {
+            nurse.heartbeat_interval += 1;
+            nurse.enable_relay_conn_data = !nurse.enable_relay_conn_data;
+            if let Some(qos) = nurse.qos.as_mut() {
+                qos.rtt_interval += 1;
+            }
+        }
+        if let Some(derp) = new.derp.as_mut() {
+            derp.tcp_keepalive = Some(7);
+            derp.health_check = Some(Default::default());
+        }
+
+        let update = FeaturesUpdate::new(&old, &new);
+        assert_eq!(
+            update.applied,
+            vec!["nurse.heartbeat_interval", "nurse.qos.rtt_interval"]
+        );
+        assert_eq!(update.restarted, vec!["derp.tcp_keepalive"]);
+        assert_eq!(
+            update.restart_required,
+            vec!["derp.health_check", "nurse.enable_relay_conn_data"]
+        );
+
+        let merged = merge_hot(&old, &new);
+        assert_eq!(
+            merged.nurse.as_ref().map(|n| n.heartbeat_interval),
+            new.nurse.as_ref().map(|n| n.heartbeat_interval)
+        );
+        assert_eq!(
+            merged.nurse.as_ref().map(|n| n.enable_relay_conn_data),
+            old.nurse.as_ref().map(|n| n.enable_relay_conn_data)
+        );
+        assert_eq!(merged.derp.as_ref().and_then(|d| d.tcp_keepalive), Some(7));
+        assert_eq!(merged.derp.as_ref().and_then(|d| d.health_check), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Export of the flows finished in the firewall to the IPFIX collector configured in features.
+//!
+//! The records are sent from a socket of the socket pool, so that they bypass the tunnel like
+//! the other traffic of libtelio itself.
+
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::SystemTime,
+};
+use telio_firewall::flow::{FlowRecord, FlowSink, IpfixEncoder};
+use telio_sockets::{External, SocketPool};
+use telio_utils::telio_log_debug;
+use tokio::net::UdpSocket;
+
+/// [FlowSink] sending every record as an IPFIX message to a collector
+pub(super) struct IpfixCollector {
+    socket: External<UdpSocket>,
+    encoder: IpfixEncoder,
+}
+
+impl IpfixCollector {
+    /// Create a sink sending to the `collector`
+    pub(super) async fn connect(
+        socket_pool: &SocketPool,
+        collector: SocketAddr,
+    ) -> io::Result<Self> {
+        let socket = match collector {
+            SocketAddr::V4(_) => {
+                socket_pool
+                    .new_external_udp((Ipv4Addr::UNSPECIFIED, 0), None)
+                    .await?
+            }
+            SocketAddr::V6(_) => {
+                socket_pool
+                    .new_external_udp((Ipv6Addr::UNSPECIFIED, 0), None)
+                    .await?
+            }
+        };
+        socket.connect(collector).await?;
+        Ok(Self {
+            socket,
+            encoder: IpfixEncoder::default(),
+        })
+    }
+}
+
+impl fmt::Debug for IpfixCollector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpfixCollector")
+            .field("collector", &self.socket.peer_addr().ok())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FlowSink for IpfixCollector {
+    fn export(&self, record: &FlowRecord) {
+        let Some(message) = self.encoder.encode(record, SystemTime::now()) else {
+            return;
+        };
+        // Records are exported on the packet processing path, so they are dropped rather than
+        // waited for when the socket is busy
+        if let Err(e) = self.socket.try_send(&message) {
+            telio_log_debug!("Failed to send flow record to the collector: {e}");
+        }
+    }
+}
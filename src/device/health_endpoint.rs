@@ -0,0 +1,366 @@
+//! Local HTTP endpoint probed for the health of headless deployments.
+//!
+//! Routers and containers run libtelio with nothing linked against the FFI to ask it about its
+//! state, while orchestrators like systemd or Kubernetes probe the services over HTTP. The
+//! endpoint listens on the loopback only and answers:
+//! - `GET /healthz` with `200 OK` while the device keeps polling its state, and with
+//!   `503 Service Unavailable` once it has not for the stall timeout,
+//! - `GET /status` with the [HealthStatus] of the latest poll as JSON.
+//!
+//! The status is published by the polling of the device rather than queried from its runtime, so
+//! that a stalled runtime makes the probes fail instead of hang. Requests naming any other host
+//! than the loopback are refused, so that a web page cannot read the status by rebinding its
+//! domain to the loopback.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use telio_crypto::PublicKey;
+use telio_model::{config::RelayState, features::FeatureHealthEndpoint, mesh::PeerStats};
+use telio_utils::{telio_log_debug, telio_log_info, telio_log_warn, Instant};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+/// Time given to a client to send its request and read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests are expected to be bare probes, anything larger is refused
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Connection to the relay server
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RelayStatus {
+    pub hostname: String,
+    pub public_key: PublicKey,
+    pub state: RelayState,
+}
+
+/// State of the device served on `/status`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HealthStatus {
+    /// The tunnel is kept down while the device is paused
+    pub paused: bool,
+    pub meshnet: bool,
+    /// Public key of the exit node the traffic is routed through
+    pub exit_node: Option<PublicKey>,
+    /// Relay server connected to, none while meshnet is off
+    pub relay: Option<RelayStatus>,
+    pub peers: Vec<PeerStats>,
+}
+
+/// Status of the latest poll, with the time it was published
+struct Published {
+    status: HealthStatus,
+    at: Instant,
+}
+
+/// Health endpoint serving the probes, stopped when dropped
+pub struct HealthEndpoint {
+    published: Arc<Mutex<Option<Published>>>,
+    server: JoinHandle<()>,
+}
+
+impl HealthEndpoint {
+    /// Start listening on the loopback at the port of the `config`
+    pub async fn start(config: &FeatureHealthEndpoint) -> io::Result<Self> {
+        let listener =
+            TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, config.port))).await?;
+        telio_log_info!("Serving the health endpoint on {}", listener.local_addr()?);
+
+        let published = Arc::new(Mutex::new(None));
+        let server = tokio::spawn(serve(
+            listener,
+            published.clone(),
+            Duration::from_secs(config.stall_timeout_s.into()),
+        ));
+        Ok(Self { published, server })
+    }
+
+    /// Replace the served status with the one of the latest poll
+    pub fn publish(&self, status: HealthStatus) {
+        *self.published.lock() = Some(Published {
+            status,
+            at: Instant::now(),
+        });
+    }
+}
+
+impl Drop for HealthEndpoint {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    published: Arc<Mutex<Option<Published>>>,
+    stall_timeout: Duration,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let published = published.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &published, stall_timeout).await {
+                        telio_log_debug!("Failed to answer the health probe from {peer}: {e}");
+                    }
+                });
+            }
+            Err(e) => telio_log_warn!("Failed to accept a health probe: {e}"),
+        }
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    published: &Mutex<Option<Published>>,
+    stall_timeout: Duration,
+) -> io::Result<()> {
+    tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let request = read_request(&mut stream).await?;
+        let (status, body) = respond(
+            &request,
+            published.lock().as_ref(),
+            stall_timeout,
+            Instant::now(),
+        );
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        stream.shutdown().await
+    })
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Read the request up to the end of its headers, the probes having no body
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        match stream.read(&mut buf).await? {
+            0 => break,
+            read => request.extend_from_slice(buf.get(..read).unwrap_or_default()),
+        }
+    }
+    Ok(request)
+}
+
+/// Status line and JSON body answering the `request`
+fn respond(
+    request: &[u8],
+    published: Option<&Published>,
+    stall_timeout: Duration,
+    now: Instant,
+) -> (&'static str, String) {
+    let request_line = request
+        .split(|b| *b == b'\r')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next().and_then(|t| t.split('?').next()));
+
+    if !is_local_host(request) {
+        return ("403 Forbidden", r#"{"error":"forbidden host"}"#.to_owned());
+    }
+    if method != Some("GET") {
+        return (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_owned(),
+        );
+    }
+    let fresh = published.filter(|p| now.saturating_duration_since(p.at) <= stall_timeout);
+    match (path, fresh) {
+        (Some("/healthz"), Some(_)) => ("200 OK", r#"{"status":"ok"}"#.to_owned()),
+        (Some("/healthz"), None) if published.is_none() => (
+            "503 Service Unavailable",
+            r#"{"status":"starting"}"#.to_owned(),
+        ),
+        (Some("/healthz"), None) => (
+            "503 Service Unavailable",
+            r#"{"status":"stalled"}"#.to_owned(),
+        ),
+        (Some("/status"), _) => match published {
+            Some(published) => match serde_json::to_string(&published.status) {
+                Ok(body) => ("200 OK", body),
+                Err(e) => (
+                    "500 Internal Server Error",
+                    serde_json::json!({ "error": e.to_string() }).to_string(),
+                ),
+            },
+            None => (
+                "503 Service Unavailable",
+                r#"{"status":"starting"}"#.to_owned(),
+            ),
+        },
+        _ => ("404 Not Found", r#"{"error":"not found"}"#.to_owned()),
+    }
+}
+
+/// Whether the `Host` header of the `request` names the loopback, with or without a port
+fn is_local_host(request: &[u8]) -> bool {
+    let request = String::from_utf8_lossy(request);
+    let host = request
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|header| header.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim());
+    let Some(host) = host else {
+        return false;
+    };
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    name.eq_ignore_ascii_case("localhost") || name == "127.0.0.1"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn published(at: Instant) -> Published {
+        Published {
+            status: HealthStatus {
+                meshnet: true,
+                relay: Some(RelayStatus {
+                    hostname: "relay.example".to_owned(),
+                    public_key: PublicKey([1; 32]),
+                    state: RelayState::Connected,
+                }),
+                ..Default::default()
+            },
+            at,
+        }
+    }
+
+    #[test]
+    fn liveness_follows_the_polling() {
+        let now = Instant::now();
+        let probe = b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        assert_eq!(
+            respond(probe, None, STALL_TIMEOUT, now).0,
+            "503 Service Unavailable"
+        );
+        assert_eq!(
+            respond(probe, Some(&published(now)), STALL_TIMEOUT, now).0,
+            "200 OK"
+        );
+        assert_eq!(
+            respond(
+                probe,
+                Some(&published(now)),
+                STALL_TIMEOUT,
+                now + STALL_TIMEOUT + Duration::from_secs(1)
+            ),
+            (
+                "503 Service Unavailable",
+                r#"{"status":"stalled"}"#.to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn only_known_paths_are_served() {
+        let now = Instant::now();
+        let published = published(now);
+
+        let (status, body) = respond(
+            b"GET /status?verbose=1 HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n",
+            Some(&published),
+            STALL_TIMEOUT,
+            now,
+        );
+        assert_eq!(status, "200 OK");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["meshnet"], true);
+        assert_eq!(body["relay"]["hostname"], "relay.example");
+        assert_eq!(body["relay"]["state"], "connected");
+
+        assert_eq!(
+            respond(
+                b"GET / HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n",
+                Some(&published),
+                STALL_TIMEOUT,
+                now
+            )
+            .0,
+            "404 Not Found"
+        );
+        assert_eq!(
+            respond(
+                b"POST /healthz HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n",
+                Some(&published),
+                STALL_TIMEOUT,
+                now
+            )
+            .0,
+            "405 Method Not Allowed"
+        );
+    }
+
+    #[test]
+    fn only_loopback_hosts_are_served() {
+        let now = Instant::now();
+        let published = published(now);
+        let status = |request: &[u8]| respond(request, Some(&published), STALL_TIMEOUT, now).0;
+
+        for host in ["localhost", "LOCALHOST:9100", "127.0.0.1", "127.0.0.1:9100"] {
+            let request = format!("GET /status HTTP/1.1\r\nhost: {host}\r\n\r\n");
+            assert_eq!(status(request.as_bytes()), "200 OK", "{host}");
+        }
+        for host in [
+            "attacker.example",
+            "attacker.example:9100",
+            "localhost.attacker.example",
+            "",
+        ] {
+            let request = format!("GET /status HTTP/1.1\r\nHost: {host}\r\n\r\n");
+            assert_eq!(status(request.as_bytes()), "403 Forbidden", "{host}");
+        }
+        assert_eq!(status(b"GET /status HTTP/1.1\r\n\r\n"), "403 Forbidden");
+    }
+
+    #[tokio::test]
+    async fn probe_is_answered_over_the_connection() {
+        let published = Mutex::new(Some(published(Instant::now())));
+        let (mut client, server) = tokio::io::duplex(MAX_REQUEST_SIZE);
+
+        client
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        handle(server, &published, STALL_TIMEOUT).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"status\":\"ok\"}"));
+    }
+}
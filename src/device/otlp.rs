@@ -0,0 +1,150 @@
+//! Export of the device telemetry to an OpenTelemetry collector.
+//!
+//! Nurse heartbeats and QoS records reach the exporter as one of the analytics sinks, finished
+//! firewall flows through [OtlpFlowSink], and the relay connection is reported here as a span per
+//! state it stays in.
+
+use std::{fmt, io, sync::Arc, time::Duration, time::SystemTime};
+use telio_firewall::flow::{FlowRecord, FlowSink};
+use telio_lana::otlp::OtlpExporter;
+use telio_model::{
+    config::{RelayState, Server as DerpServer},
+    event::Connectivity,
+    features::FeatureOtlp,
+};
+
+/// Current relay connection state, with the time it was entered
+struct RelaySpan {
+    hostname: String,
+    state: RelayState,
+    since: SystemTime,
+}
+
+/// Telemetry collected by the device for the OTLP exporter
+pub struct OtlpTelemetry {
+    exporter: Arc<OtlpExporter>,
+    relay: Option<RelaySpan>,
+}
+
+impl OtlpTelemetry {
+    /// Start exporting as configured
+    pub fn start(config: &FeatureOtlp) -> io::Result<Self> {
+        let exporter = OtlpExporter::start(
+            config.collector,
+            config.tls_server_name.clone(),
+            config.service_name.clone(),
+            Duration::from_secs(config.export_interval_s.into()),
+        )?;
+        Ok(Self {
+            exporter: Arc::new(exporter),
+            relay: None,
+        })
+    }
+
+    /// The exporter, to be registered as an analytics sink
+    pub fn exporter(&self) -> Arc<OtlpExporter> {
+        self.exporter.clone()
+    }
+
+    /// Sink of the firewall flows
+    pub fn flow_sink(&self) -> Arc<dyn FlowSink> {
+        Arc::new(OtlpFlowSink(self.exporter.clone()))
+    }
+
+    /// Close the span of the previous relay state, if the state of `server` differs from it
+    pub fn relay_changed(&mut self, server: &DerpServer) {
+        let now = SystemTime::now();
+        if let Some(previous) = &self.relay {
+            if previous.hostname == server.hostname && previous.state == server.conn_state {
+                return;
+            }
+        }
+        if let Some(previous) = self.relay.replace(RelaySpan {
+            hostname: server.hostname.clone(),
+            state: server.conn_state,
+            since: now,
+        }) {
+            self.exporter.span(
+                "telio.relay.state",
+                previous.since,
+                now,
+                vec![
+                    ("server", previous.hostname),
+                    ("state", format!("{:?}", previous.state)),
+                ],
+            );
+        }
+        self.exporter.add(
+            "telio.relay.transitions",
+            "1",
+            1,
+            vec![("state", format!("{:?}", server.conn_state))],
+        );
+    }
+
+    /// Record the `outcome` of a fast reconnect after a network change
+    pub fn network_reconnected(&self, outcome: &Connectivity) {
+        let Connectivity::NetworkReconnect {
+            duration_ms,
+            reconnected_peers,
+            unreachable_peers,
+        } = outcome
+        else {
+            return;
+        };
+        self.exporter.gauge(
+            "telio.network.reconnect_duration",
+            "ms",
+            *duration_ms as f64,
+            vec![],
+        );
+        self.exporter.add(
+            "telio.network.reconnected_peers",
+            "1",
+            (*reconnected_peers).into(),
+            vec![],
+        );
+        self.exporter.add(
+            "telio.network.unreachable_peers",
+            "1",
+            (*unreachable_peers).into(),
+            vec![],
+        );
+    }
+}
+
+/// [FlowSink] counting the flows, packets and bytes passing the firewall
+struct OtlpFlowSink(Arc<OtlpExporter>);
+
+impl fmt::Debug for OtlpFlowSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtlpFlowSink").finish_non_exhaustive()
+    }
+}
+
+impl FlowSink for OtlpFlowSink {
+    fn export(&self, record: &FlowRecord) {
+        let attributes = vec![
+            ("peer", record.peer.to_string()),
+            ("protocol", format!("{:?}", record.protocol)),
+            ("direction", format!("{:?}", record.direction)),
+            ("verdict", format!("{:?}", record.verdict)),
+        ];
+        self.0
+            .add("telio.firewall.flows", "1", 1, attributes.clone());
+        self.0.add(
+            "telio.firewall.packets",
+            "1",
+            record
+                .source_packets
+                .saturating_add(record.destination_packets),
+            attributes.clone(),
+        );
+        self.0.add(
+            "telio.firewall.bytes",
+            "By",
+            record.source_bytes.saturating_add(record.destination_bytes),
+            attributes,
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! Policy table routing traffic classes to different exits.
+//!
+//! Every [TrafficClass] adds its destination networks to the allowed IPs of its exit peer, while
+//! the exit connected with libtelio.connect_to_exit_node(...) keeps the default route. WireGuard
+//! picks the peer with the longest matching prefix, so the traffic of a class goes through its
+//! exit and everything else through the default one.
+//!
+//! Exits of the classes are meshnet peers, the connected VPN server, or the VPN servers connected
+//! next to it with libtelio.set_additional_exits(...), which carry no other traffic.
+//!
+//! Cryptokey routing has no notion of ports or marks, so the classes selecting their traffic by
+//! the source ports or the fwmark are steered on Linux only, and only to the additional exits.
+//! Each of those exits gets its own WireGuard interface, the traffic of its classes is marked and
+//! the mark routes it to that interface instead of the tunnel.
+
+use std::collections::{HashMap, HashSet};
+
+use ipnet::IpNet;
+use telio_crypto::PublicKey;
+use telio_model::{config::TrafficClass, mesh::ExitNode};
+use telio_utils::telio_log_debug;
+use telio_wg::uapi::Peer;
+
+use super::{Error, Result};
+
+/// Mark of the traffic steered to the first of the steered exits and the routing table of its
+/// interface, the next exits count up from it
+#[cfg(target_os = "linux")]
+const STEERED_MARK: u32 = 0xb21e20;
+
+/// Traffic classes passed by libtelio.set_traffic_classes(...)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RoutingPolicy {
+    classes: Vec<TrafficClass>,
+}
+
+impl RoutingPolicy {
+    /// Policy of the `classes`, fails when a class would take over the default route, the
+    /// same network is routed to different exits or the selectors of a class cannot be used
+    pub fn new(classes: Vec<TrafficClass>) -> Result<Self> {
+        let mut exits: HashMap<IpNet, PublicKey> = HashMap::new();
+        for class in &classes {
+            if class.has_selectors() {
+                validate_selectors(class)?;
+                continue;
+            }
+            for network in &class.networks {
+                if network.prefix_len() == 0 {
+                    return Err(Error::InvalidTrafficClass(format!(
+                        "{} routes the default route {network}",
+                        class.name
+                    )));
+                }
+                match exits.insert(network.trunc(), class.exit_public_key) {
+                    Some(exit) if exit != class.exit_public_key => {
+                        return Err(Error::InvalidTrafficClass(format!(
+                            "{} routes {network} to another exit",
+                            class.name
+                        )));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // Steered exits are moved to their own interfaces, leaving the tunnel
+        if let Some(class) = classes.iter().find(|class| {
+            class.has_selectors() && exits.values().any(|exit| *exit == class.exit_public_key)
+        }) {
+            return Err(Error::InvalidTrafficClass(format!(
+                "{} steers to an exit routing destination classes too",
+                class.name
+            )));
+        }
+
+        Ok(Self { classes })
+    }
+
+    /// Classes selecting their traffic by the source ports or the fwmark, grouped by their exits
+    /// in the order of the first class of each exit
+    pub(crate) fn steered(&self) -> Vec<(PublicKey, Vec<&TrafficClass>)> {
+        let mut exits: Vec<(PublicKey, Vec<&TrafficClass>)> = Vec::new();
+        for class in self.classes.iter().filter(|class| class.has_selectors()) {
+            match exits
+                .iter_mut()
+                .find(|(exit, _)| *exit == class.exit_public_key)
+            {
+                Some((_, classes)) => classes.push(class),
+                None => exits.push((class.exit_public_key, vec![class])),
+            }
+        }
+        exits
+    }
+
+    /// Steered exits among the connected additional `exits`, each with the name of its interface
+    /// and the peer of the exit on it, and the steering of the traffic to those interfaces.
+    /// Traffic of the classes of the exits which are not connected follows the default route.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn steering(
+        &self,
+        exits: &[ExitNode],
+        fwmark: u32,
+        persistent_keepalive_interval: Option<u32>,
+        ipv6: bool,
+    ) -> (Vec<(String, Peer)>, telio_firewall::steering::Steering) {
+        use ipnet::{Ipv4Net, Ipv6Net};
+        use telio_firewall::steering::{SteeredClass, SteeredExit, Steering};
+
+        let mut interfaces = Vec::new();
+        let mut steering = Steering {
+            fwmark,
+            ipv6,
+            exits: Vec::new(),
+        };
+        for (exit_public_key, classes) in self.steered() {
+            let Some(exit) = exits.iter().find(|exit| exit.public_key == exit_public_key) else {
+                telio_log_debug!("Steered exit {exit_public_key:?} is not connected");
+                continue;
+            };
+
+            let index = interfaces.len() as u32;
+            let interface = format!("nlxc{index}");
+            let mut allowed_ips: Vec<IpNet> = vec![Ipv4Net::default().into()];
+            if ipv6 {
+                allowed_ips.push(Ipv6Net::default().into());
+            }
+            interfaces.push((
+                interface.clone(),
+                Peer {
+                    allowed_ips,
+                    ..additional_exit_peer(exit, persistent_keepalive_interval, ipv6)
+                },
+            ));
+            steering.exits.push(SteeredExit {
+                interface,
+                mark: STEERED_MARK + index,
+                classes: classes
+                    .into_iter()
+                    .filter_map(|class| {
+                        let networks: Vec<IpNet> = class
+                            .networks
+                            .iter()
+                            .map(IpNet::trunc)
+                            .filter(|network| ipv6 || network.addr().is_ipv4())
+                            .collect();
+                        // A class of the IPv6 networks only must not steer all destinations
+                        if networks.is_empty() && !class.networks.is_empty() {
+                            return None;
+                        }
+                        Some(SteeredClass {
+                            networks,
+                            source_ports: class.source_ports,
+                            fwmark: class.fwmark,
+                        })
+                    })
+                    .collect(),
+            });
+        }
+        (interfaces, steering)
+    }
+
+    /// Add the networks of the classes to the allowed IPs of their exits among the `peers`.
+    /// Classes of the exits which are not connected are skipped, their traffic follows the
+    /// default route until they are.
+    pub(crate) fn apply<'a>(&self, peers: impl IntoIterator<Item = &'a mut Peer>, ipv6: bool) {
+        let mut peers: HashMap<PublicKey, &mut Peer> = peers
+            .into_iter()
+            .map(|peer| (peer.public_key, peer))
+            .collect();
+        let mut routed: HashSet<IpNet> = peers
+            .values()
+            .flat_map(|peer| peer.allowed_ips.iter().copied())
+            .collect();
+
+        for class in self.classes.iter().filter(|class| !class.has_selectors()) {
+            let Some(exit) = peers.get_mut(&class.exit_public_key) else {
+                telio_log_debug!(
+                    "Exit {:?} of traffic class {} is not connected",
+                    class.exit_public_key,
+                    class.name
+                );
+                continue;
+            };
+            for network in &class.networks {
+                let network = network.trunc();
+                if !ipv6 && !network.addr().is_ipv4() {
+                    continue;
+                }
+                // Networks routed already, e.g. to a meshnet peer, stay where they are
+                if routed.insert(network) {
+                    exit.allowed_ips.push(network);
+                } else {
+                    telio_log_debug!(
+                        "{network} of traffic class {} is routed already",
+                        class.name
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Interfaces of the steered exits
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+pub(crate) struct ClassTunnels {
+    /// Private key, fwmark and peers of the interfaces the tunnels were started with
+    started: Option<(telio_crypto::SecretKey, u32, Vec<(String, Peer)>)>,
+    tunnels: Vec<telio_wg::class_tunnel::ClassTunnel>,
+}
+
+#[cfg(target_os = "linux")]
+impl ClassTunnels {
+    /// Start the `interfaces` with their peers, replacing the current ones unless nothing changed.
+    /// Returns whether the interfaces were replaced.
+    pub(crate) async fn update(
+        &mut self,
+        private_key: &telio_crypto::SecretKey,
+        fwmark: u32,
+        interfaces: Vec<(String, Peer)>,
+    ) -> std::result::Result<bool, telio_wg::Error> {
+        use telio_wg::class_tunnel::ClassTunnel;
+
+        let config = (private_key.clone(), fwmark, interfaces);
+        if self.started.as_ref() == Some(&config) {
+            return Ok(false);
+        }
+        self.stop().await;
+
+        let (private_key, fwmark, interfaces) = &config;
+        for (name, peer) in interfaces {
+            match ClassTunnel::start(name, private_key.clone(), *fwmark, peer.clone()).await {
+                Ok(tunnel) => self.tunnels.push(tunnel),
+                Err(e) => {
+                    self.stop().await;
+                    return Err(e);
+                }
+            }
+        }
+        self.started = Some(config);
+        Ok(true)
+    }
+
+    /// Remove all of the interfaces
+    pub(crate) async fn stop(&mut self) {
+        self.started = None;
+        for tunnel in self.tunnels.drain(..) {
+            tunnel.stop().await;
+        }
+    }
+}
+
+/// Check the source ports and the fwmark of the `class`
+fn validate_selectors(class: &TrafficClass) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::InvalidTrafficClass(format!(
+            "{} selects its traffic by the source ports or the fwmark, supported only on Linux",
+            class.name
+        )));
+    }
+    match class.source_ports {
+        Some(ports) if ports.first > ports.last => Err(Error::InvalidTrafficClass(format!(
+            "{} selects the empty source port range {}-{}",
+            class.name, ports.first, ports.last
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// WireGuard peer of the additional `exit`, carrying only its own allowed IPs and the networks of
+/// the classes routed to it
+pub(crate) fn additional_exit_peer(
+    exit: &ExitNode,
+    persistent_keepalive_interval: Option<u32>,
+    ipv6: bool,
+) -> Peer {
+    Peer {
+        public_key: exit.public_key,
+        endpoint: exit.endpoint,
+        persistent_keepalive_interval,
+        allowed_ips: exit
+            .allowed_ips
+            .iter()
+            .flatten()
+            .filter(|network| ipv6 || network.addr().is_ipv4())
+            .copied()
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_model::config::PortRange;
+
+    const EXIT_A: PublicKey = PublicKey([1; 32]);
+    const EXIT_B: PublicKey = PublicKey([2; 32]);
+    const OFFLINE: PublicKey = PublicKey([3; 32]);
+
+    fn class(name: &str, networks: &[&str], exit_public_key: PublicKey) -> TrafficClass {
+        TrafficClass {
+            name: name.to_owned(),
+            networks: networks.iter().map(|n| n.parse().unwrap()).collect(),
+            source_ports: None,
+            fwmark: None,
+            exit_public_key,
+        }
+    }
+
+    fn steered_class(
+        name: &str,
+        networks: &[&str],
+        source_ports: Option<(u16, u16)>,
+        exit_public_key: PublicKey,
+    ) -> TrafficClass {
+        TrafficClass {
+            source_ports: source_ports.map(|(first, last)| PortRange { first, last }),
+            fwmark: source_ports.is_none().then_some(42),
+            ..class(name, networks, exit_public_key)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn exit(public_key: PublicKey) -> ExitNode {
+        ExitNode {
+            identifier: "de".to_owned(),
+            public_key,
+            allowed_ips: None,
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+        }
+    }
+
+    fn peer(public_key: PublicKey, allowed_ips: &[&str]) -> Peer {
+        Peer {
+            public_key,
+            allowed_ips: allowed_ips.iter().map(|n| n.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn allowed_ips(peer: &Peer) -> Vec<String> {
+        peer.allowed_ips.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(RoutingPolicy::new(vec![class("all", &["0.0.0.0/0"], EXIT_A)]).is_err());
+        assert!(RoutingPolicy::new(vec![
+            class("streaming", &["203.0.113.0/24"], EXIT_A),
+            class("work", &["203.0.113.7/24"], EXIT_B),
+        ])
+        .is_err());
+        assert!(RoutingPolicy::new(vec![
+            class("streaming", &["203.0.113.0/24"], EXIT_A),
+            class("video", &["203.0.113.0/24"], EXIT_A),
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn invalid_selectors_are_rejected() {
+        let steered = RoutingPolicy::new(vec![steered_class(
+            "torrents",
+            &["0.0.0.0/0"],
+            Some((6881, 6889)),
+            EXIT_B,
+        )]);
+        assert_eq!(steered.is_ok(), cfg!(target_os = "linux"));
+
+        assert!(RoutingPolicy::new(vec![steered_class(
+            "torrents",
+            &[],
+            Some((6889, 6881)),
+            EXIT_B
+        )])
+        .is_err());
+        assert!(RoutingPolicy::new(vec![
+            class("streaming", &["203.0.113.0/24"], EXIT_B),
+            steered_class("games", &[], None, EXIT_B),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn classes_are_routed_to_their_exits() {
+        let policy = RoutingPolicy::new(vec![
+            class("streaming", &["203.0.113.0/24", "2001:db8::/32"], EXIT_A),
+            class("mesh", &["100.64.0.2/32"], EXIT_A),
+            class("offline", &["198.51.100.0/24"], OFFLINE),
+        ])
+        .unwrap();
+        let mut exit_a = peer(EXIT_A, &["100.64.0.3/32"]);
+        let mut exit_b = peer(EXIT_B, &["0.0.0.0/0"]);
+        let mut meshnet_peer = peer(PublicKey([4; 32]), &["100.64.0.2/32"]);
+
+        policy.apply([&mut exit_a, &mut exit_b, &mut meshnet_peer], false);
+
+        assert_eq!(
+            allowed_ips(&exit_a),
+            vec!["100.64.0.3/32", "203.0.113.0/24"]
+        );
+        assert_eq!(allowed_ips(&exit_b), vec!["0.0.0.0/0"]);
+        assert_eq!(allowed_ips(&meshnet_peer), vec!["100.64.0.2/32"]);
+    }
+
+    #[test]
+    fn additional_exits_carry_only_their_routes() {
+        let exit = ExitNode {
+            identifier: "de".to_owned(),
+            public_key: EXIT_B,
+            allowed_ips: Some(vec![
+                "198.51.100.0/24".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ]),
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+        };
+
+        let peer = additional_exit_peer(&exit, Some(25), false);
+        assert_eq!(peer.public_key, EXIT_B);
+        assert_eq!(peer.endpoint, exit.endpoint);
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+        assert_eq!(allowed_ips(&peer), vec!["198.51.100.0/24"]);
+
+        let unrouted = ExitNode {
+            allowed_ips: None,
+            ..exit
+        };
+        assert!(additional_exit_peer(&unrouted, None, true)
+            .allowed_ips
+            .is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn steered_classes_get_interfaces_of_their_exits() {
+        let policy = RoutingPolicy::new(vec![
+            class("streaming", &["203.0.113.0/24"], EXIT_A),
+            steered_class("torrents", &["198.51.100.0/24"], Some((6881, 6889)), EXIT_B),
+            steered_class("v6 only", &["2001:db8::/32"], Some((80, 80)), EXIT_B),
+            steered_class("games", &[], None, EXIT_B),
+            steered_class("offline", &[], None, OFFLINE),
+        ])
+        .unwrap();
+
+        let mut exit_a = peer(EXIT_A, &["0.0.0.0/0"]);
+        policy.apply([&mut exit_a], false);
+        assert_eq!(allowed_ips(&exit_a), vec!["0.0.0.0/0", "203.0.113.0/24"]);
+
+        let (interfaces, steering) = policy.steering(&[exit(EXIT_B)], 11673110, Some(25), false);
+        assert_eq!(interfaces.len(), 1);
+        let (name, peer) = &interfaces[0];
+        assert_eq!(name, "nlxc0");
+        assert_eq!(peer.public_key, EXIT_B);
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+        assert_eq!(allowed_ips(peer), vec!["0.0.0.0/0"]);
+
+        assert_eq!(steering.fwmark, 11673110);
+        assert_eq!(steering.exits.len(), 1);
+        let steered = &steering.exits[0];
+        assert_eq!(steered.interface, "nlxc0");
+        assert_eq!(steered.mark, STEERED_MARK);
+        assert_eq!(steered.classes.len(), 2);
+        assert_eq!(
+            steered.classes[0].source_ports,
+            Some(PortRange {
+                first: 6881,
+                last: 6889
+            })
+        );
+        assert!(steered.classes[1].networks.is_empty());
+        assert_eq!(steered.classes[1].fwmark, Some(42));
+    }
+}
@@ -0,0 +1,208 @@
+//! Session state kept across restarts of the app.
+//!
+//! Starting from scratch, the device discovers the endpoints of the peers, picks a relay and
+//! handshakes with the post quantum VPN server again, which takes seconds. A [SessionSnapshot]
+//! exported on shutdown keeps what is still valid after a brief restart:
+//! - the WireGuard listen port, taken again so that the NAT mappings to it stay valid,
+//! - the relay connected to, tried first,
+//! - the direct endpoints the peers were connected over, used without validating them again
+//!   unless the peer rejects the upgrade or the connection fails,
+//! - the post quantum keys of the VPN, so that the tunnel is usable before new ones are fetched.
+//!
+//! The snapshot holds secrets, so it is encrypted to the device's own key. Snapshots older than
+//! [MAX_AGE] are discarded, as WireGuard abandons the sessions by then anyway.
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use telio_crypto::encryption::{decrypt_request, encrypt_request};
+use telio_crypto::{PresharedKey, PublicKey, SecretKey};
+use telio_model::features::EndpointProvider;
+use telio_proto::Session;
+use telio_traversal::connectivity_check::WireGuardEndpointCandidateChangeEvent;
+use telio_utils::Instant;
+use thiserror::Error as TError;
+
+use super::conntrack_persistence::unix_time;
+
+/// Age of the snapshot after which its state is no longer valid, the time after which WireGuard
+/// abandons an inactive session
+pub const MAX_AGE: Duration = Duration::from_secs(180);
+
+#[derive(Debug, TError)]
+pub enum Error {
+    #[error("Failed to encrypt or decrypt the session: {0}")]
+    Encryption(#[from] telio_crypto::encryption::Error),
+    #[error("Malformed session: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Session saved {0:?} ago is too old")]
+    Stale(Duration),
+}
+
+/// Direct endpoint a peer was connected over
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedEndpoint {
+    pub public_key: PublicKey,
+    pub remote_endpoint: (SocketAddr, EndpointProvider),
+    pub local_endpoint: (SocketAddr, EndpointProvider),
+    pub session: Session,
+}
+
+impl SavedEndpoint {
+    /// The endpoint as validated at `now`
+    pub fn validated(self, now: Instant) -> WireGuardEndpointCandidateChangeEvent {
+        WireGuardEndpointCandidateChangeEvent {
+            public_key: self.public_key,
+            remote_endpoint: self.remote_endpoint,
+            local_endpoint: self.local_endpoint,
+            session: self.session,
+            changed_at: now,
+        }
+    }
+}
+
+impl From<WireGuardEndpointCandidateChangeEvent> for SavedEndpoint {
+    fn from(endpoint: WireGuardEndpointCandidateChangeEvent) -> Self {
+        Self {
+            public_key: endpoint.public_key,
+            remote_endpoint: endpoint.remote_endpoint,
+            local_endpoint: endpoint.local_endpoint,
+            session: endpoint.session,
+        }
+    }
+}
+
+/// Post quantum keys of the connection to the VPN server
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedPostQuantum {
+    /// Public key of the VPN server
+    pub peer: PublicKey,
+    pub pq_shared: PresharedKey,
+    pub wg_secret: SecretKey,
+}
+
+impl SavedPostQuantum {
+    pub fn keys(self) -> telio_pq::Keys {
+        telio_pq::Keys {
+            pq_shared: self.pq_shared,
+            wg_secret: self.wg_secret,
+        }
+    }
+}
+
+/// Session state exported on shutdown, see [crate::device::Device::export_session]
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    /// Wall clock time of saving, in seconds since the epoch, as it has to be comparable across
+    /// processes
+    saved_at: u64,
+    pub listen_port: Option<u16>,
+    /// Public key of the relay connected to
+    pub relay: Option<PublicKey>,
+    pub endpoints: Vec<SavedEndpoint>,
+    pub post_quantum: Option<SavedPostQuantum>,
+}
+
+impl SessionSnapshot {
+    /// Empty snapshot taken now
+    pub fn new() -> Self {
+        Self {
+            saved_at: unix_time(),
+            ..Default::default()
+        }
+    }
+
+    /// Encrypt the snapshot to the device's `private_key`
+    pub fn encrypt(&self, private_key: &SecretKey) -> Result<Vec<u8>, Error> {
+        let data = serde_json::to_vec(self)?;
+        Ok(encrypt_request(
+            &data,
+            &mut rand::thread_rng(),
+            private_key,
+            &private_key.public(),
+        )?)
+    }
+
+    /// Decrypt the snapshot encrypted to the device's `private_key`, unless it is too old
+    pub fn decrypt(data: &[u8], private_key: &SecretKey) -> Result<Self, Error> {
+        let public_key = private_key.public();
+        let (data, _) = decrypt_request(data, private_key, |sender| *sender == public_key)?;
+        let snapshot: Self = serde_json::from_slice(&data)?;
+
+        let age = Duration::from_secs(unix_time().saturating_sub(snapshot.saved_at));
+        if age > MAX_AGE {
+            return Err(Error::Stale(age));
+        }
+        Ok(snapshot)
+    }
+
+    /// Take the endpoint the peer with the `public_key` was connected over
+    pub fn take_endpoint(&mut self, public_key: &PublicKey) -> Option<SavedEndpoint> {
+        let index = self
+            .endpoints
+            .iter()
+            .position(|endpoint| &endpoint.public_key == public_key)?;
+        Some(self.endpoints.swap_remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> SessionSnapshot {
+        SessionSnapshot {
+            listen_port: Some(51820),
+            relay: Some(PublicKey([1; 32])),
+            endpoints: vec![SavedEndpoint {
+                public_key: PublicKey([2; 32]),
+                remote_endpoint: ("10.0.0.2:51820".parse().unwrap(), EndpointProvider::Stun),
+                local_endpoint: ("10.0.0.1:51820".parse().unwrap(), EndpointProvider::Stun),
+                session: 7,
+            }],
+            post_quantum: Some(SavedPostQuantum {
+                peer: PublicKey([3; 32]),
+                pq_shared: PresharedKey::new([4; 32]),
+                wg_secret: SecretKey::gen(),
+            }),
+            ..SessionSnapshot::new()
+        }
+    }
+
+    #[test]
+    fn snapshot_is_restored_with_the_same_key() {
+        let private_key = SecretKey::gen();
+        let data = snapshot().encrypt(&private_key).unwrap();
+
+        let mut restored = SessionSnapshot::decrypt(&data, &private_key).unwrap();
+        assert_eq!(restored.listen_port, Some(51820));
+        assert_eq!(restored.relay, Some(PublicKey([1; 32])));
+        assert!(restored.take_endpoint(&PublicKey([3; 32])).is_none());
+        assert_eq!(
+            restored.take_endpoint(&PublicKey([2; 32])).unwrap().session,
+            7
+        );
+        assert!(restored.endpoints.is_empty());
+
+        assert!(matches!(
+            SessionSnapshot::decrypt(&data, &SecretKey::gen()),
+            Err(Error::Encryption(_))
+        ));
+    }
+
+    #[test]
+    fn stale_snapshot_is_discarded() {
+        let private_key = SecretKey::gen();
+        let data = SessionSnapshot {
+            saved_at: unix_time() - MAX_AGE.as_secs() - 1,
+            ..snapshot()
+        }
+        .encrypt(&private_key)
+        .unwrap();
+
+        assert!(matches!(
+            SessionSnapshot::decrypt(&data, &private_key),
+            Err(Error::Stale(_))
+        ));
+    }
+}
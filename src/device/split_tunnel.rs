@@ -0,0 +1,269 @@
+//! Split tunneling set with libtelio.set_split_tunnel(...).
+//!
+//! Excluded routes are taken off the allowed IPs of every peer, and the default routes of the
+//! exits are narrowed down to the included routes. Traffic routed into the tunnel regardless has
+//! no peer to go to, so the routing of the OS has to agree. On Linux the bypassed traffic, including
+//! the one of the excluded apps, is marked with the fwmark of the encapsulated packets, which takes
+//! it around the tunnel, and masqueraded so it does not leave with the address of the tunnel.
+//! Elsewhere the app leaves the same routes out of the routes of the tunnel,
+//! and the apps out of the VPN with the platform API.
+
+use std::collections::HashSet;
+
+use ipnet::IpNet;
+use telio_model::config::SplitTunnel;
+use telio_wg::uapi::Peer;
+
+use super::{Error, Result};
+
+/// Split tunnel passed by libtelio.set_split_tunnel(...)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SplitTunnelPolicy {
+    included: Option<Vec<IpNet>>,
+    excluded: Vec<IpNet>,
+    /// UIDs of the excluded apps
+    uids: Vec<u32>,
+}
+
+impl SplitTunnelPolicy {
+    /// Policy of the `split_tunnel`, fails when no route would be included or the excluded apps
+    /// cannot be recognized on this platform
+    pub fn new(split_tunnel: Option<SplitTunnel>) -> Result<Self> {
+        let Some(split_tunnel) = split_tunnel else {
+            return Ok(Self::default());
+        };
+        if split_tunnel
+            .included_routes
+            .as_ref()
+            .is_some_and(Vec::is_empty)
+        {
+            return Err(Error::InvalidSplitTunnel(
+                "no route is included, leave the included routes unset to include all".to_owned(),
+            ));
+        }
+        let uids = split_tunnel
+            .excluded_apps
+            .iter()
+            .map(|app| uid(app))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            included: split_tunnel.included_routes.map(trunc),
+            excluded: trunc(split_tunnel.excluded_routes),
+            uids,
+        })
+    }
+
+    /// Whether all of the traffic goes through the tunnel
+    #[cfg(target_os = "linux")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.included.is_none() && self.excluded.is_empty() && self.uids.is_empty()
+    }
+
+    /// Narrow the allowed IPs of the `peers` down to the traffic kept in the tunnel. Included
+    /// routes routed to another peer already, e.g. by a traffic class, stay where they are.
+    pub(crate) fn apply<'a>(&self, peers: impl IntoIterator<Item = &'a mut Peer>) {
+        let mut peers: Vec<&mut Peer> = peers.into_iter().collect();
+        if let Some(included) = &self.included {
+            let mut routed: HashSet<IpNet> = peers
+                .iter()
+                .flat_map(|peer| peer.allowed_ips.iter().copied())
+                .collect();
+            for peer in peers.iter_mut() {
+                let (defaults, mut allowed_ips): (Vec<IpNet>, Vec<IpNet>) = peer
+                    .allowed_ips
+                    .iter()
+                    .copied()
+                    .partition(|network| network.prefix_len() == 0);
+                for default in defaults {
+                    allowed_ips.extend(
+                        included.iter().filter(|network| {
+                            default.contains(*network) && routed.insert(**network)
+                        }),
+                    );
+                }
+                peer.allowed_ips = allowed_ips;
+            }
+        }
+
+        for peer in peers {
+            peer.allowed_ips = subtract(&peer.allowed_ips, &self.excluded);
+        }
+    }
+
+    /// Traffic to route around the tunnel by marking it with the `fwmark`. The meshnet is never
+    /// routed around, unless it is excluded explicitly.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn bypass(&self, fwmark: u32) -> telio_firewall::split_tunnel::Bypass {
+        use ipnet::{Ipv4Net, Ipv6Net};
+        use telio_model::constants::{IPV4_MESHNET_NETWORK, IPV6_MESHNET_NETWORK};
+
+        let mut routes = match &self.included {
+            Some(included) => {
+                let mut kept = included.clone();
+                kept.extend([IPV4_MESHNET_NETWORK.into(), IPV6_MESHNET_NETWORK.into()]);
+                subtract(
+                    &[Ipv4Net::default().into(), Ipv6Net::default().into()],
+                    &kept,
+                )
+            }
+            None => Vec::new(),
+        };
+        routes.extend(self.excluded.iter().copied());
+
+        telio_firewall::split_tunnel::Bypass {
+            fwmark,
+            routes,
+            uids: self.uids.clone(),
+        }
+    }
+}
+
+/// UID of the excluded `app`, apps are excluded by libtelio only on Linux
+fn uid(app: &str) -> Result<u32> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::InvalidSplitTunnel(format!(
+            "cannot exclude {app}, apps are excluded with the platform API on this platform"
+        )));
+    }
+    app.parse()
+        .map_err(|_| Error::InvalidSplitTunnel(format!("{app} is not a UID")))
+}
+
+fn trunc(networks: Vec<IpNet>) -> Vec<IpNet> {
+    networks.iter().map(IpNet::trunc).collect()
+}
+
+/// Parts of the `networks` outside of the `excluded` ones
+fn subtract(networks: &[IpNet], excluded: &[IpNet]) -> Vec<IpNet> {
+    excluded
+        .iter()
+        .fold(networks.to_vec(), |networks, excluded| {
+            networks
+                .into_iter()
+                .flat_map(|network| subtract_one(network, *excluded))
+                .collect()
+        })
+}
+
+fn subtract_one(network: IpNet, excluded: IpNet) -> Vec<IpNet> {
+    if excluded.contains(&network) {
+        return Vec::new();
+    }
+    if !network.contains(&excluded) {
+        return vec![network];
+    }
+    // Halve the network until the halves are either inside or outside of the excluded one
+    network
+        .subnets(network.prefix_len() + 1)
+        .into_iter()
+        .flatten()
+        .flat_map(|half| subtract_one(half, excluded))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telio_crypto::PublicKey;
+
+    fn networks(networks: &[&str]) -> Vec<IpNet> {
+        networks.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    fn peer(byte: u8, allowed_ips: &[&str]) -> Peer {
+        Peer {
+            public_key: PublicKey([byte; 32]),
+            allowed_ips: networks(allowed_ips),
+            ..Default::default()
+        }
+    }
+
+    fn allowed_ips(peer: &Peer) -> Vec<String> {
+        peer.allowed_ips.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn excluded_networks_are_cut_out() {
+        assert_eq!(
+            subtract(&networks(&["10.0.0.0/8"]), &networks(&["10.128.0.0/9"])),
+            networks(&["10.0.0.0/9"])
+        );
+        assert_eq!(
+            subtract(
+                &networks(&["0.0.0.0/0", "::/0"]),
+                &networks(&["128.0.0.0/2", "0.0.0.0/1"])
+            ),
+            networks(&["192.0.0.0/2", "::/0"])
+        );
+        assert_eq!(
+            subtract(&networks(&["10.0.0.0/24"]), &networks(&["10.0.0.0/8"])),
+            Vec::<IpNet>::new()
+        );
+        assert_eq!(
+            subtract(&networks(&["10.0.0.0/24"]), &networks(&["192.168.0.0/16"])),
+            networks(&["10.0.0.0/24"])
+        );
+    }
+
+    #[test]
+    fn exits_are_narrowed_to_the_tunneled_traffic() {
+        let policy = SplitTunnelPolicy::new(Some(SplitTunnel {
+            included_routes: Some(networks(&["10.0.0.0/8", "203.0.113.0/24"])),
+            excluded_routes: networks(&["10.1.0.0/16", "100.64.0.3/32"]),
+            excluded_apps: Vec::new(),
+        }))
+        .unwrap();
+        let mut exit = peer(1, &["100.64.0.2/32", "0.0.0.0/0"]);
+        let mut class_exit = peer(2, &["203.0.113.0/24"]);
+        let mut meshnet_peer = peer(3, &["100.64.0.3/32", "100.64.0.4/32"]);
+
+        policy.apply([&mut exit, &mut class_exit, &mut meshnet_peer]);
+
+        assert_eq!(allowed_ips(&exit).len(), 9);
+        assert_eq!(allowed_ips(&exit)[..2], ["100.64.0.2/32", "10.0.0.0/16"]);
+        assert!(!allowed_ips(&exit).contains(&"10.1.0.0/16".to_owned()));
+        assert_eq!(allowed_ips(&class_exit), vec!["203.0.113.0/24"]);
+        assert_eq!(allowed_ips(&meshnet_peer), vec!["100.64.0.4/32"]);
+    }
+
+    #[test]
+    fn invalid_split_tunnels_are_rejected() {
+        assert_eq!(SplitTunnelPolicy::new(None).unwrap(), Default::default());
+        assert!(SplitTunnelPolicy::new(Some(SplitTunnel {
+            included_routes: Some(Vec::new()),
+            ..Default::default()
+        }))
+        .is_err());
+
+        let apps = |apps: &[&str]| {
+            SplitTunnelPolicy::new(Some(SplitTunnel {
+                excluded_apps: apps.iter().map(|app| app.to_string()).collect(),
+                ..Default::default()
+            }))
+        };
+        assert!(apps(&["com.bank.app"]).is_err());
+        assert_eq!(apps(&["10123"]).is_ok(), cfg!(target_os = "linux"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bypass_keeps_meshnet_in_tunnel() {
+        let policy = SplitTunnelPolicy::new(Some(SplitTunnel {
+            included_routes: Some(networks(&["128.0.0.0/1"])),
+            excluded_routes: networks(&["10.0.0.0/8"]),
+            excluded_apps: vec!["10123".to_owned()],
+        }))
+        .unwrap();
+
+        let bypass = policy.bypass(11673110);
+        assert_eq!(bypass.fwmark, 11673110);
+        assert_eq!(bypass.uids, vec![10123]);
+        assert!(bypass.routes.contains(&"0.0.0.0/2".parse().unwrap()));
+        assert!(bypass.routes.contains(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!bypass
+            .routes
+            .iter()
+            .any(|route| route.contains(&"100.64.0.2".parse::<std::net::IpAddr>().unwrap())));
+    }
+}
@@ -0,0 +1,244 @@
+//! Ordering of the components brought up by the device.
+//!
+//! Traffic must never flow through a component before the policy guarding it is in place,
+//! e.g. routes to the tunnel must not be installed before the firewall and killswitch rules,
+//! and the OS must not be pointed at the DNS peer before the stub resolver answers. Every
+//! component reports readiness here, which fails if any of its dependencies is not ready yet.
+//!
+//! Every change of the readiness, together with the progress of the meshnet formation, is
+//! published as [Event::Readiness], so the apps can tell how far the device got even when the
+//! start does not complete within the bound of the `startup_timeout_seconds` feature.
+
+use super::{Error, Result};
+use std::collections::BTreeSet;
+use telio_model::event::{Event, Readiness, ReadinessComponent};
+use telio_task::io::mc_chan::Tx;
+use telio_utils::telio_log_debug;
+
+/// Component of the device with a defined place in the startup order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Component {
+    /// Userspace firewall filtering tunnel traffic
+    Firewall,
+    /// OS level policy: WFP killswitch on Windows, exit node rules on Linux
+    PolicyRules,
+    /// Tunnel adapter with its private key set
+    Adapter,
+    /// Addresses and routes of the tunnel interface
+    AdapterAddresses,
+    /// Local DNS resolver with its records and stub listeners
+    DnsStub,
+    /// DNS peer reachable through the tunnel
+    DnsConfig,
+    /// Connection to a relay server
+    Relay,
+}
+
+impl Component {
+    /// Components which have to be ready before this one
+    pub fn dependencies(self) -> &'static [Component] {
+        use Component::*;
+        match self {
+            Firewall => &[],
+            PolicyRules => &[Firewall],
+            Adapter => &[Firewall],
+            AdapterAddresses => &[Adapter, PolicyRules],
+            DnsStub => &[Adapter],
+            DnsConfig => &[DnsStub, Firewall],
+            Relay => &[],
+        }
+    }
+}
+
+impl From<Component> for ReadinessComponent {
+    fn from(component: Component) -> Self {
+        match component {
+            Component::Firewall => ReadinessComponent::Firewall,
+            Component::PolicyRules => ReadinessComponent::PolicyRules,
+            Component::Adapter => ReadinessComponent::Adapter,
+            Component::AdapterAddresses => ReadinessComponent::AdapterAddresses,
+            Component::DnsStub => ReadinessComponent::DnsStub,
+            Component::DnsConfig => ReadinessComponent::DnsConfig,
+            Component::Relay => ReadinessComponent::Relay,
+        }
+    }
+}
+
+/// Readiness of the device components
+#[derive(Debug, Default)]
+pub struct StartupSequence {
+    ready: BTreeSet<Component>,
+    /// Connected meshnet peers in percent of the configured ones
+    mesh_formed_percent: Option<u8>,
+    /// Publisher of the readiness events
+    events: Option<Tx<Box<Event>>>,
+}
+
+impl StartupSequence {
+    /// Sequence publishing its changes to the `events`
+    pub fn new(events: Tx<Box<Event>>) -> Self {
+        Self {
+            events: Some(events),
+            ..Default::default()
+        }
+    }
+
+    /// Mark `component` as ready, fails if any of its dependencies is not ready yet
+    pub fn ready(&mut self, component: Component) -> Result {
+        if let Some(missing) = component
+            .dependencies()
+            .iter()
+            .find(|dependency| !self.ready.contains(dependency))
+        {
+            return Err(Error::StartupOrder(component, *missing));
+        }
+        if self.ready.insert(component) {
+            telio_log_debug!("{component:?} is ready");
+            self.publish();
+        }
+        Ok(())
+    }
+
+    /// Mark `component` and everything depending on it as not ready
+    pub fn stopped(&mut self, component: Component) {
+        if self.stop(component) {
+            self.publish();
+        }
+    }
+
+    fn stop(&mut self, component: Component) -> bool {
+        let mut changed = self.ready.remove(&component);
+        if changed {
+            telio_log_debug!("{component:?} stopped");
+        }
+        let dependents: Vec<_> = self
+            .ready
+            .iter()
+            .copied()
+            .filter(|dependent| dependent.dependencies().contains(&component))
+            .collect();
+        for dependent in dependents {
+            changed |= self.stop(dependent);
+        }
+        changed
+    }
+
+    #[cfg(test)]
+    pub fn is_ready(&self, component: Component) -> bool {
+        self.ready.contains(&component)
+    }
+
+    /// Record that `connected` of the `total` meshnet peers are connected, `total` being
+    /// `None` without meshnet
+    pub fn mesh_formed(&mut self, connected: usize, total: Option<usize>) {
+        let percent = total.map(|total| match total {
+            0 => 100,
+            total => (connected.min(total) * 100 / total) as u8,
+        });
+        if self.mesh_formed_percent != percent {
+            self.mesh_formed_percent = percent;
+            self.publish();
+        }
+    }
+
+    /// Current readiness, as reported by the readiness events
+    pub fn readiness(&self) -> Readiness {
+        Readiness {
+            ready: self.ready.iter().copied().map(Into::into).collect(),
+            mesh_formed_percent: self.mesh_formed_percent,
+        }
+    }
+
+    fn publish(&self) {
+        if let Some(events) = &self.events {
+            let _ = events.send(Box::new(Event::Readiness {
+                body: self.readiness(),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_come_up_in_dependency_order() {
+        let mut sequence = StartupSequence::default();
+        assert!(matches!(
+            sequence.ready(Component::AdapterAddresses),
+            Err(Error::StartupOrder(
+                Component::AdapterAddresses,
+                Component::Adapter
+            ))
+        ));
+        sequence.ready(Component::Firewall).unwrap();
+        sequence.ready(Component::Adapter).unwrap();
+        assert!(matches!(
+            sequence.ready(Component::AdapterAddresses),
+            Err(Error::StartupOrder(
+                Component::AdapterAddresses,
+                Component::PolicyRules
+            ))
+        ));
+        sequence.ready(Component::PolicyRules).unwrap();
+        sequence.ready(Component::AdapterAddresses).unwrap();
+        assert!(sequence.ready(Component::DnsConfig).is_err());
+        sequence.ready(Component::DnsStub).unwrap();
+        sequence.ready(Component::DnsConfig).unwrap();
+    }
+
+    #[test]
+    fn stopping_a_component_stops_its_dependents() {
+        let mut sequence = StartupSequence::default();
+        sequence.ready(Component::Firewall).unwrap();
+        sequence.ready(Component::Adapter).unwrap();
+        sequence.ready(Component::DnsStub).unwrap();
+        sequence.ready(Component::DnsConfig).unwrap();
+
+        sequence.stopped(Component::DnsStub);
+
+        assert!(sequence.is_ready(Component::Adapter));
+        assert!(!sequence.is_ready(Component::DnsStub));
+        assert!(!sequence.is_ready(Component::DnsConfig));
+    }
+
+    #[test]
+    fn changes_are_published() {
+        let events = telio_task::io::McChan::default();
+        let mut rx = events.rx;
+        let mut sequence = StartupSequence::new(events.tx);
+
+        sequence.ready(Component::Firewall).unwrap();
+        sequence.ready(Component::Firewall).unwrap();
+        sequence.mesh_formed(1, Some(3));
+        sequence.mesh_formed(1, Some(3));
+        sequence.stopped(Component::Adapter);
+        sequence.stopped(Component::Firewall);
+
+        let mut published = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            match *event {
+                Event::Readiness { body } => published.push(body),
+                event => panic!("Unexpected event {event:?}"),
+            }
+        }
+        assert_eq!(
+            published,
+            vec![
+                Readiness {
+                    ready: vec![ReadinessComponent::Firewall],
+                    mesh_formed_percent: None,
+                },
+                Readiness {
+                    ready: vec![ReadinessComponent::Firewall],
+                    mesh_formed_percent: Some(33),
+                },
+                Readiness {
+                    ready: vec![],
+                    mesh_formed_percent: Some(33),
+                },
+            ]
+        );
+    }
+}
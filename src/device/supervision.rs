@@ -0,0 +1,244 @@
+//! Application of the restart policies of the task supervision, enabled by the `supervision`
+//! feature.
+//!
+//! The policies are applied on the thread of the [Supervisor], outside of the runtime of the
+//! device, so that a wedged runtime gets restarted as well. The meshnet components are restarted
+//! through the runtime, while a device restart stops the runtime and starts it anew, with the
+//! state it published on its last poll: the private key, fwmark, meshnet config and exit node.
+//! The device restart waits for the calls in progress to return, a failure to restart is
+//! escalated to the app.
+
+use super::{Device, DeviceConfig, Error, MeshnetEntities, Result, Runtime};
+use crate::handover::HandoverState;
+use futures::FutureExt;
+use std::{sync::Arc, time::Duration};
+use telio_model::{
+    event::{Error as ErrorEvent, ErrorCode, ErrorLevel, Event, Set},
+    features::{FeatureSupervision, Features, RestartPolicy},
+};
+use telio_nurse::AnalyticsSink;
+use telio_sockets::Protector;
+use telio_task::{
+    io::mc_chan::Tx,
+    supervisor::{SupervisionAction, Supervisor, SupervisorConfig, TaskRegistry},
+    task_exec, Task,
+};
+use telio_utils::{telio_log_info, telio_log_warn};
+use telio_wg::{AdapterType, InterfaceConfig, Tun};
+use tokio::{runtime::Handle, sync::RwLock};
+
+/// Slot of the runtime of the device, emptied while it is stopped
+pub(super) type RuntimeSlot = Arc<RwLock<Option<Task<Runtime>>>>;
+
+/// Everything needed to start the runtime of the device anew
+pub(super) struct Restarter {
+    rt: RuntimeSlot,
+    /// Async runtime of the device, the restarted tasks run on
+    handle: Handle,
+    registry: TaskRegistry,
+    event: Tx<Box<Event>>,
+    features: Features,
+    protect: Option<Arc<dyn Protector>>,
+    analytics_sinks: Vec<Arc<dyn AnalyticsSink>>,
+    adapter: AdapterType,
+    name: Option<String>,
+    /// Duplicate of the tun of the device config, kept open for the restarted adapter
+    tun: Option<Tun>,
+    ext_if_filter: Option<Vec<String>>,
+    interface: Option<InterfaceConfig>,
+    /// State published by the runtime on every poll
+    state: Arc<parking_lot::Mutex<HandoverState>>,
+}
+
+impl Restarter {
+    /// Keep what the runtime of the `device` is started with in the `config` for the restarts
+    pub fn new(device: &Device, config: &DeviceConfig) -> Result<Self> {
+        let tun = config
+            .tun
+            .as_ref()
+            .map(duplicate)
+            .transpose()
+            .map_err(Error::SupervisionStartError)?;
+        Ok(Self {
+            rt: device.rt.clone(),
+            handle: device.async_runtime()?.handle().clone(),
+            registry: device.registry.clone(),
+            event: device.event.clone(),
+            features: device.features.clone(),
+            protect: device.protect.clone(),
+            analytics_sinks: device.analytics_sinks.clone(),
+            adapter: config.adapter.clone(),
+            name: config.name.clone(),
+            tun,
+            ext_if_filter: config.ext_if_filter.clone(),
+            interface: config.interface.clone(),
+            state: Arc::new(parking_lot::Mutex::new(HandoverState {
+                private_key: config.private_key.clone(),
+                fwmark: config.fwmark,
+                ..Default::default()
+            })),
+        })
+    }
+
+    /// State the runtime has to publish for the restarts
+    pub fn state(&self) -> Arc<parking_lot::Mutex<HandoverState>> {
+        self.state.clone()
+    }
+
+    /// Start supervising the tasks of the device, applying the policies with this restarter
+    pub fn supervise(self, supervision: &FeatureSupervision) -> Result<Supervisor<RestartPolicy>> {
+        let stall_timeout = Duration::from_secs(supervision.stall_timeout_s.max(1).into());
+        let config = SupervisorConfig {
+            check_interval: Duration::from_secs(supervision.check_interval_s.max(1).into()),
+            stall_timeout,
+            default_policy: supervision.default_policy,
+            policies: supervision
+                .policies
+                .iter()
+                .map(|policy| (policy.task.clone(), policy.policy))
+                .collect(),
+        };
+        let registry = self.registry.clone();
+        let restarter = Arc::new(self);
+        Supervisor::start(
+            config,
+            registry,
+            Box::new(move |action| restarter.clone().apply(action, stall_timeout).boxed()),
+        )
+        .map_err(Error::SupervisionStartError)
+    }
+
+    async fn apply(self: Arc<Self>, action: SupervisionAction<RestartPolicy>, bound: Duration) {
+        let msg = format!("Task {} is {:?}", action.task, action.health);
+        match action.policy {
+            RestartPolicy::RestartComponent
+                if MeshnetEntities::TASKS.contains(&action.task.as_str()) =>
+            {
+                telio_log_warn!("{msg}, restarting meshnet");
+                if let Err(e) = self.restart_meshnet(bound).await {
+                    self.publish_error(
+                        ErrorLevel::Critical,
+                        format!("{msg}, failed to restart meshnet: {e}"),
+                    );
+                }
+            }
+            RestartPolicy::RestartDevice => {
+                self.publish_error(ErrorLevel::Critical, format!("{msg}, restarting"));
+                // The tasks of the device run on its own async runtime
+                let restart = self
+                    .handle
+                    .spawn(self.registry.clone().scope(self.clone().restart(bound)));
+                let restarted = match restart.await {
+                    Ok(restarted) => restarted.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match restarted {
+                    Ok(()) => telio_log_info!("Device restarted"),
+                    Err(e) => self.publish_error(
+                        ErrorLevel::Critical,
+                        format!("{msg}, failed to restart: {e}"),
+                    ),
+                }
+            }
+            // Tasks outside of the meshnet cannot be restarted on their own
+            RestartPolicy::RestartComponent | RestartPolicy::Escalate => {
+                self.publish_error(ErrorLevel::Severe, msg);
+            }
+        }
+    }
+
+    /// Rebuild the meshnet entities through the runtime
+    async fn restart_meshnet(&self, bound: Duration) -> Result {
+        tokio::time::timeout(bound, async {
+            let rt = self.rt.read().await;
+            match rt.as_ref() {
+                Some(rt) => task_exec!(rt, async move |rt| Ok(rt.restart_meshnet().await)).await?,
+                None => Ok(()),
+            }
+        })
+        .await
+        .map_err(|_| Error::RestartTimeout(bound))?
+    }
+
+    /// Stop the runtime and start it anew with the state it last published
+    async fn restart(self: Arc<Self>, bound: Duration) -> Result {
+        let mut rt = tokio::time::timeout(bound, self.rt.write())
+            .await
+            .map_err(|_| Error::RestartTimeout(bound))?;
+        // Stopped by the app in the meantime
+        let Some(stalled) = rt.take() else {
+            return Ok(());
+        };
+        if tokio::time::timeout(bound, stalled.stop()).await.is_err() {
+            telio_log_warn!("Runtime did not stop within {bound:?}, starting anew regardless");
+        }
+
+        let state = self.state.lock().clone();
+        let config = DeviceConfig {
+            private_key: state.private_key,
+            adapter: self.adapter.clone(),
+            fwmark: state.fwmark,
+            name: self.name.clone(),
+            tun: self
+                .tun
+                .as_ref()
+                .map(duplicate)
+                .transpose()
+                .map_err(Error::SupervisionStartError)?,
+            ext_if_filter: self.ext_if_filter.clone(),
+            interface: self.interface.clone(),
+        };
+        let mut runtime = Runtime::start(
+            self.event.clone(),
+            config,
+            self.features.clone(),
+            self.protect.clone(),
+            self.analytics_sinks.clone(),
+        )
+        .boxed()
+        .await?;
+        runtime.restart_state = Some(self.state.clone());
+        let restarted = rt.insert(Task::start(runtime));
+
+        let (meshnet, exit_node) = (state.meshnet, state.exit_node);
+        #[cfg(target_os = "linux")]
+        let fwmark = state.fwmark;
+        task_exec!(&*restarted, async move |rt| {
+            #[cfg(target_os = "linux")]
+            if let Some(fwmark) = fwmark {
+                rt.set_fwmark(fwmark).boxed().await?;
+            }
+            if meshnet.is_some() {
+                rt.set_config(&meshnet).boxed().await?;
+            }
+            if let Some(exit_node) = exit_node {
+                rt.connect_exit_node(&exit_node).boxed().await?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    fn publish_error(&self, level: ErrorLevel, msg: String) {
+        telio_log_warn!("{msg}");
+        if let Some(event) = Event::builder::<ErrorEvent>()
+            .set(ErrorCode::Unknown)
+            .set(level)
+            .set(msg)
+            .build()
+        {
+            let _ = self.event.send(Box::new(event));
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn duplicate(tun: &Tun) -> std::io::Result<Tun> {
+    tun.try_clone()
+}
+
+#[cfg(target_os = "windows")]
+fn duplicate(_tun: &Tun) -> std::io::Result<Tun> {
+    Ok(())
+}
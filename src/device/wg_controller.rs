@@ -1,4 +1,4 @@
-use super::{Entities, RequestedState, Result};
+use super::{routing::additional_exit_peer, Entities, RequestedState, Result};
 use futures::FutureExt;
 use ipnet::IpNet;
 use std::collections::{BTreeMap, HashMap, HashSet};
@@ -369,7 +369,7 @@ async fn consolidate_wg_peers<
     }
 
     let ep_control = |ep: Arc<dyn EndpointProvider>| async move {
-        if is_any_peer_eligible_for_upgrade {
+        if is_any_peer_eligible_for_upgrade && !requested_state.paused {
             telio_log_debug!("Unpausing {} provider", ep.name());
             ep.unpause().await;
         } else {
@@ -407,7 +407,12 @@ async fn consolidate_wg_peers<
     let requested_keys: HashSet<&PublicKey> = requested_peers.keys().collect();
     let actual_keys: HashSet<&PublicKey> = actual_peers.keys().collect();
     let delete_keys = &actual_keys - &requested_keys;
-    let insert_keys = &requested_keys - &actual_keys;
+    let insert_keys = throttle_peer_insertions(
+        &requested_keys - &actual_keys,
+        requested_state,
+        &remote_peer_states,
+        features,
+    );
     let update_keys = &requested_keys & &actual_keys;
 
     for key in delete_keys {
@@ -614,6 +619,48 @@ fn check_allowed_ips_correctness(peers: &BTreeMap<PublicKey, RequestedPeer>) ->
         .ok_or_else(|| Error::BadAllowedIps.into())
 }
 
+/// Limit the number of meshnet peers inserted at once, so that applying a large meshmap
+/// does not initiate handshakes with every peer simultaneously. Peers reported as online
+/// by the relay are inserted first, the rest follow in the next consolidation rounds.
+/// Non-meshnet peers (VPN, DNS, etc.) are never throttled.
+fn throttle_peer_insertions<'a>(
+    insert_keys: HashSet<&'a PublicKey>,
+    requested_state: &RequestedState,
+    remote_peer_states: &PeersStatesMap,
+    features: &Features,
+) -> Vec<&'a PublicKey> {
+    let Some(throttling) = features.wireguard.handshake_throttling else {
+        return insert_keys.into_iter().collect();
+    };
+
+    let meshnet_keys: HashSet<PublicKey> =
+        iter_peers(requested_state).map(|p| p.public_key).collect();
+    let (mut meshnet, mut other): (Vec<_>, Vec<_>) = insert_keys
+        .into_iter()
+        .partition(|key| meshnet_keys.contains(*key));
+
+    // Online peers first, order the rest by key for determinism
+    meshnet.sort_by_key(|key| {
+        (
+            !remote_peer_states.get(*key).copied().unwrap_or(false),
+            **key,
+        )
+    });
+
+    // A limit of 0 would defer every meshnet peer forever
+    let limit = throttling.max_handshakes_per_round.max(1) as usize;
+    if meshnet.len() > limit {
+        telio_log_debug!(
+            "Throttling peer insertion, {} peers deferred",
+            meshnet.len() - limit
+        );
+        meshnet.truncate(limit);
+    }
+
+    other.extend(meshnet);
+    other
+}
+
 fn iter_peers(
     requested_state: &RequestedState,
 ) -> impl Iterator<Item = &telio_model::config::Peer> {
@@ -662,7 +709,9 @@ async fn consolidate_firewall<F: Firewall>(
             !iter_peers(requested_state).any(|p| p.public_key == exit_node.public_key);
 
         if is_vpn_exit_node {
-            state.whitelist.vpn_peer = Some(exit_node.public_key);
+            // With an exit chain the traffic is decrypted by the inner hop
+            let vpn_peer = requested_state.exit_hop.as_ref().unwrap_or(exit_node);
+            state.whitelist.vpn_peer = Some(vpn_peer.public_key);
         }
     }
 
@@ -734,7 +783,7 @@ async fn build_requested_peers_list<
         } else {
             // Exit node is a fresh node, therefore - insert create new peer
             let public_key = exit_node.public_key;
-            let endpoint = exit_node.endpoint;
+            let endpoint = requested_state.exit_stream_endpoint.or(exit_node.endpoint);
 
             let mut ip_addresses = vec![VPN_INTERNAL_IPV4.into()];
             if features.ipv6 {
@@ -778,6 +827,82 @@ async fn build_requested_peers_list<
         }
     }
 
+    // Add inner hop of the exit chain, reachable through the nested hop relay
+    if let Some(exit_hop) = &requested_state.exit_hop {
+        let allowed_ips: Vec<IpNet> = exit_hop
+            .allowed_ips
+            .clone()
+            .unwrap_or(vec![
+                IpNet::V4("0.0.0.0/0".parse()?),
+                IpNet::V6("::/0".parse()?),
+            ])
+            .into_iter()
+            .filter(|network| features.ipv6 || network.addr().is_ipv4())
+            .collect();
+
+        let mut ip_addresses = vec![VPN_INTERNAL_IPV4.into()];
+        if features.ipv6 {
+            ip_addresses.push(VPN_INTERNAL_IPV6.into());
+        }
+
+        requested_peers.insert(
+            exit_hop.public_key,
+            RequestedPeer {
+                peer: telio_wg::uapi::Peer {
+                    public_key: exit_hop.public_key,
+                    endpoint: exit_hop.endpoint,
+                    ip_addresses,
+                    persistent_keepalive_interval: requested_state.keepalive_periods.vpn,
+                    allowed_ips,
+                    ..Default::default()
+                },
+                batching_keepalive_interval: None,
+                endpoint: None,
+            },
+        );
+    }
+
+    // Add the VPN servers connected next to the exit node, except for the steered ones having
+    // interfaces of their own
+    let steered: HashSet<PublicKey> = requested_state
+        .routing_policy
+        .steered()
+        .into_iter()
+        .map(|(exit, _)| exit)
+        .collect();
+    for exit in &requested_state.additional_exits {
+        if requested_peers.contains_key(&exit.public_key) || steered.contains(&exit.public_key) {
+            continue;
+        }
+        requested_peers.insert(
+            exit.public_key,
+            RequestedPeer {
+                peer: additional_exit_peer(
+                    exit,
+                    requested_state.keepalive_periods.vpn,
+                    features.ipv6,
+                ),
+                batching_keepalive_interval: None,
+                endpoint: None,
+            },
+        );
+    }
+
+    // Route the traffic classes to their exits
+    requested_state.routing_policy.apply(
+        requested_peers
+            .values_mut()
+            .map(|requested| &mut requested.peer),
+        features.ipv6,
+    );
+
+    // Keep the split tunneled traffic off the tunnel
+    requested_state.split_tunnel.apply(
+        requested_peers
+            .values_mut()
+            .map(|requested| &mut requested.peer),
+    );
+
     // Add DNS peer if enabled
     let dns = dns.lock().await;
     if let (Some(_), Some(resolver)) = (&requested_state.upstream_servers, &dns.resolver) {
@@ -858,6 +983,14 @@ async fn build_requested_peers_list<
         telio_log_debug!("wg-stun peer not configured");
     }
 
+    // Paused device keeps the peers, but lets them go quiet
+    if requested_state.paused {
+        for requested_peer in requested_peers.values_mut() {
+            requested_peer.peer.persistent_keepalive_interval = None;
+            requested_peer.batching_keepalive_interval = None;
+        }
+    }
+
     Ok(requested_peers)
 }
 
@@ -1309,7 +1442,8 @@ mod tests {
     use telio_firewall::firewall::{HashSet as FwHashSet, MockFirewall, Whitelist, FILE_SEND_PORT};
     use telio_model::config::{Config, PeerBase, Server};
     use telio_model::features::{
-        EndpointProvider as ApiEndpointProvider, FeatureBatching, FeatureDns, TtlValue,
+        EndpointProvider as ApiEndpointProvider, FeatureBatching, FeatureDns,
+        FeatureHandshakeThrottling, TtlValue,
     };
     use telio_model::mesh::ExitNode;
     use telio_pq::MockPostQuantum;
@@ -1637,6 +1771,87 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn throttle_peer_insertions_prefers_online_peers() {
+        let offline = SecretKey::gen().public();
+        let online = SecretKey::gen().public();
+        let vpn = SecretKey::gen().public();
+
+        let requested_state = create_requested_state(vec![
+            (offline, vec![], false, false, false, false),
+            (online, vec![], false, false, false, false),
+        ]);
+        let remote_peer_states = PeersStatesMap::from([(online, true), (offline, false)]);
+        let insert_keys = HashSet::from([&offline, &online, &vpn]);
+
+        let mut features = Features::default();
+        assert_eq!(
+            throttle_peer_insertions(
+                insert_keys.clone(),
+                &requested_state,
+                &remote_peer_states,
+                &features
+            )
+            .len(),
+            3
+        );
+
+        features.wireguard.handshake_throttling = Some(FeatureHandshakeThrottling {
+            max_handshakes_per_round: 1,
+        });
+        assert_eq!(
+            throttle_peer_insertions(
+                insert_keys.clone(),
+                &requested_state,
+                &remote_peer_states,
+                &features
+            ),
+            vec![&vpn, &online]
+        );
+
+        features.wireguard.handshake_throttling = Some(FeatureHandshakeThrottling {
+            max_handshakes_per_round: 0,
+        });
+        assert_eq!(
+            throttle_peer_insertions(
+                insert_keys,
+                &requested_state,
+                &remote_peer_states,
+                &features
+            ),
+            vec![&vpn, &online]
+        );
+    }
+
+    #[tokio::test]
+    async fn add_exit_chain_inner_hop_to_firewall() {
+        let mut firewall = MockFirewall::new();
+
+        let pub_key_entry = SecretKey::gen().public();
+        let pub_key_exit = SecretKey::gen().public();
+
+        let mut requested_state = create_requested_state(vec![]);
+
+        requested_state.exit_node = Some(ExitNode {
+            public_key: pub_key_entry,
+            ..Default::default()
+        });
+        requested_state.exit_hop = Some(ExitNode {
+            public_key: pub_key_exit,
+            ..Default::default()
+        });
+
+        firewall
+            .expect_apply_state()
+            .once()
+            .withf(move |state| state.whitelist.vpn_peer == Some(pub_key_exit))
+            .return_const(());
+
+        consolidate_firewall(&requested_state, &firewall, None, None)
+            .await
+            .unwrap();
+    }
+
     #[tokio::test]
     async fn do_not_add_meshnet_exit_node_to_firewall_if_it_does_not_allow_incoming_connections() {
         let mut firewall = MockFirewall::new();
@@ -1825,15 +2040,26 @@ mod tests {
                     nicknames: false,
                     firewall: Default::default(),
                     flush_events_on_stop_timeout_seconds: None,
+                    startup_timeout_seconds: None,
                     post_quantum_vpn: Default::default(),
                     link_detection: None,
                     dns: FeatureDns {
                         exit_dns: None,
                         ttl_value: TtlValue(60),
+                        stub_listeners: Vec::new(),
+                        upstreams: Default::default(),
                     },
                     multicast: false,
+                    starcast: None,
                     batching: None,
                     error_notification_service: None,
+                    key_rotation: None,
+                    otlp: None,
+                    fast_reconnect: None,
+                    supervision: None,
+                    accounting: None,
+                    health_endpoint: None,
+                    max_meshnet_peers: None,
                 },
                 post_quantum: MockPostQuantum::new(),
                 stun_ep_provider,
@@ -2963,6 +3189,82 @@ mod tests {
         f.consolidate_peers().await;
     }
 
+    #[tokio::test]
+    async fn when_paused_vpn_peer_is_added_without_keepalive() {
+        let mut f = Fixture::new();
+
+        let public_key = SecretKey::gen().public();
+        let allowed_ips = vec![IpNet::new(IpAddr::from([7, 6, 5, 4]), 23).unwrap()];
+        let ip_addresses = vec![VPN_INTERNAL_IPV4.into(), VPN_EXTERNAL_IPV4.into()];
+        let endpoint_raw = SocketAddr::from(([192, 168, 0, 1], 13));
+
+        f.requested_state.keepalive_periods.vpn = Some(4321);
+        f.requested_state.exit_node = Some(ExitNode {
+            identifier: "".to_owned(),
+            public_key,
+            allowed_ips: Some(allowed_ips.clone()),
+            endpoint: Some(endpoint_raw),
+        });
+        f.requested_state.paused = true;
+
+        f.when_requested_meshnet_config(vec![]);
+        f.when_proxy_mapping(vec![]);
+        f.when_current_peers(vec![]);
+        f.when_time_since_last_rx(vec![]);
+        f.when_cross_check_validated_endpoints(vec![]);
+        f.when_upgrade_requests(vec![]);
+
+        f.then_add_peer(vec![(
+            public_key,
+            endpoint_raw,
+            None,
+            allowed_ips,
+            ip_addresses,
+        )]);
+
+        f.then_post_quantum_is_checked();
+
+        f.consolidate_peers().await;
+    }
+
+    #[tokio::test]
+    async fn when_vpn_peer_is_reached_over_a_stream_hop() {
+        let mut f = Fixture::new();
+
+        let public_key = SecretKey::gen().public();
+        let allowed_ips = vec![IpNet::new(IpAddr::from([7, 6, 5, 4]), 23).unwrap()];
+        let ip_addresses = vec![VPN_INTERNAL_IPV4.into(), VPN_EXTERNAL_IPV4.into()];
+        let stream_endpoint = SocketAddr::from(([127, 0, 0, 1], 51000));
+
+        f.requested_state.keepalive_periods.vpn = Some(4321);
+        f.requested_state.exit_node = Some(ExitNode {
+            identifier: "".to_owned(),
+            public_key,
+            allowed_ips: Some(allowed_ips.clone()),
+            endpoint: Some(SocketAddr::from(([192, 168, 0, 1], 13))),
+        });
+        f.requested_state.exit_stream_endpoint = Some(stream_endpoint);
+
+        f.when_requested_meshnet_config(vec![]);
+        f.when_proxy_mapping(vec![]);
+        f.when_current_peers(vec![]);
+        f.when_time_since_last_rx(vec![]);
+        f.when_cross_check_validated_endpoints(vec![]);
+        f.when_upgrade_requests(vec![]);
+
+        f.then_add_peer(vec![(
+            public_key,
+            stream_endpoint,
+            Some(4321),
+            allowed_ips,
+            ip_addresses,
+        )]);
+
+        f.then_post_quantum_is_checked();
+
+        f.consolidate_peers().await;
+    }
+
     #[tokio::test]
     async fn when_stun_peer_should_be_added() {
         #[derive(PartialEq)]
@@ -8,14 +8,16 @@ use ffi_helpers::{error_handling, panic as panic_handling};
 use ipnet::IpNet;
 use rand::Rng;
 use telio_crypto::{PublicKey, SecretKey};
-use telio_wg::AdapterType;
+use telio_traversal::nat_detection::NatReport;
+use telio_wg::{AdapterType, InterfaceConfig};
 use tracing::{error, trace};
 
-use telio_sockets::protector::make_external_protector;
+use telio_sockets::{protector::make_external_protector, Protector, Proxy};
 use uuid::Uuid;
 
 use std::{
-    convert::TryInto,
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
     net::{IpAddr, SocketAddr},
     panic::{self, AssertUnwindSafe},
     sync::{Arc, Mutex, Once},
@@ -23,18 +25,30 @@ use std::{
 };
 
 use self::{
-    logging::HIDE_THREAD_ID_IN_LOGS, logging::LOGGER_STOPPER, logging::TIMESTAMPS_IN_LOGS, types::*,
+    logging::HIDE_THREAD_ID_IN_LOGS, logging::LOGGER_STOPPER, logging::LOG_LEVELS,
+    logging::LOG_RING, logging::TIMESTAMPS_IN_LOGS, types::*,
 };
 pub use crate::adapter::{
     CustomAdapter, TelioCustomAdapter, WgCmd, WgDevice, WgInterface, WgPeer, WgResponse,
 };
-use crate::device::{Device, DeviceConfig, Result as DevResult};
+use crate::device::{Device, DeviceConfig, FeaturesUpdate, Result as DevResult};
+use telio_firewall::{
+    approval::InboundDecision,
+    category::CategoryProvider,
+    firewall::{PacketDescription, Verdict},
+};
 use telio_model::{
-    config::{Config, ConfigParseError},
+    config::{
+        Config, ConfigParseError, ExitFailover, RelayPinning, Server, SplitTunnel, TrafficClass,
+    },
     event::*,
     features::Features,
-    mesh::{ExitNode, Node},
+    mesh::{ExitNode, Node, PeerConnectivity, PeerQos, PeerStats, PeerTraffic, ResourceUsage},
+    schema::features_json_schema,
+    validation::{parse_features_strict, ValidationReport},
 };
+use telio_network_monitors::path::NetworkPath;
+use telio_task::supervisor::TaskStatus;
 
 // debug tools
 use telio_utils::{
@@ -97,6 +111,40 @@ pub fn hide_thread_id_in_logs(hide: bool) {
     HIDE_THREAD_ID_IN_LOGS.store(hide, std::sync::atomic::Ordering::Relaxed);
 }
 
+/// Keep up to `bytes_per_component` bytes of the latest logs of every component, e.g. telio_wg
+/// or telio_relay, in memory, to be retrieved with get_buffered_logs or flush_buffered_logs when
+/// a problem is reported. Zero disables the buffer and drops the logs kept.
+pub fn set_log_buffer_size(bytes_per_component: u64) {
+    LOG_RING.set_capacity(usize::try_from(bytes_per_component).unwrap_or(usize::MAX));
+}
+
+/// Get the logs kept in memory, one per line in the order they were logged
+pub fn get_buffered_logs() -> String {
+    LOG_RING.dump()
+}
+
+/// Write the logs kept in memory to the file at `path`, replacing it
+pub fn flush_buffered_logs(path: String) -> FfiResult<()> {
+    LOG_RING
+        .flush_to(std::path::Path::new(&path))
+        .map_err(|err| TelioError::UnknownError {
+            inner: err.to_string(),
+        })
+}
+
+/// Log the module, e.g. `telio_wg` or `telio::device`, and its submodules up to `level`,
+/// overriding the level of the global logger. `None` brings back the level of the global logger.
+pub fn set_module_log_level(module: String, level: Option<TelioLogLevel>) {
+    LOG_LEVELS.set(module, level);
+}
+
+/// Report the network path observed by the platform monitor of the app, NetworkCallback on
+/// Android and NWPathMonitor on iOS. Every update of the monitor can be passed as is, reports
+/// of the same path are ignored. Replaces the calls of notify_network_change.
+pub fn report_network_path(path: NetworkPath) {
+    telio_network_monitors::path::report_network_path(path);
+}
+
 /// Get default recommended adapter type for platform.
 pub fn get_default_adapter() -> TelioAdapterType {
     AdapterType::default().into()
@@ -140,6 +188,25 @@ pub fn deserialize_feature_config(fstr: String) -> FfiResult<Features> {
     }
 }
 
+/// Utility function to create a `Features` object from a json-string, failing on the unknown
+/// settings and on the settings out of their ranges or conflicting with each other
+pub fn deserialize_feature_config_strict(fstr: String) -> FfiResult<Features> {
+    parse_features_strict(&fstr).map_err(|issues| {
+        let inner = issues
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        telio_log_debug!("Invalid feature config: {inner}");
+        TelioError::UnknownError { inner }
+    })
+}
+
+/// Utility function to get the JSON schema of the feature config
+pub fn get_feature_config_schema() -> String {
+    features_json_schema().to_string()
+}
+
 /// Utility function to create a `Config` object from a json-string
 pub fn deserialize_meshnet_config(cfg_str: String) -> FfiResult<Config> {
     match Config::new_from_str(&cfg_str) {
@@ -194,7 +261,7 @@ impl Telio {
     /// - `features`:   JSON string of enabled features
     pub fn new(features: Features, events: Box<dyn TelioEventCb>) -> FfiResult<Self> {
         let serialized_event_fn = format!("{events:?}");
-        let ret = Self::new_common(&features, events, None);
+        let ret = Self::new_common(&features, events, None, None);
         Self::log_entry(features, serialized_event_fn, &ret);
         ret
     }
@@ -210,7 +277,23 @@ impl Telio {
         protect: Box<dyn TelioProtectCb>,
     ) -> FfiResult<Self> {
         let serialized_event_fn = format!("{events:?}");
-        let ret = Self::new_common(&features, events, Some(protect));
+        let ret = Self::new_common(&features, events, Some(protect), None);
+        Self::log_entry(features, serialized_event_fn, &ret);
+        ret
+    }
+
+    /// Create new telio library instance protecting its sockets with the hooks of the app
+    /// # Parameters
+    /// - `events`:     Events callback
+    /// - `features`:   JSON string of enabled features
+    /// - `hooks`:      Protection of the sockets from the tunnel, for platforms without the native one
+    pub fn new_with_protector_hooks(
+        features: Features,
+        events: Box<dyn TelioEventCb>,
+        hooks: Box<dyn TelioProtectorHooks>,
+    ) -> FfiResult<Self> {
+        let serialized_event_fn = format!("{events:?}");
+        let ret = Self::new_common(&features, events, None, Some(hooks));
         Self::log_entry(features, serialized_event_fn, &ret);
         ret
     }
@@ -219,6 +302,7 @@ impl Telio {
         features: &Features,
         events: Box<dyn TelioEventCb>,
         #[allow(unused)] protect_cb: Option<Box<dyn TelioProtectCb>>,
+        protector_hooks: Option<Box<dyn TelioProtectorHooks>>,
     ) -> FfiResult<Self> {
         let events = Arc::new(events);
         let event_dispatcher = move |event: Box<Event>| {
@@ -285,15 +369,23 @@ impl Telio {
             _ => None,
         };
 
+        let protector: Option<Arc<dyn Protector>> = match protector_hooks {
+            #[cfg(unix)]
+            Some(hooks) => Some(telio_sockets::protector::make_custom_protector(Arc::new(
+                FfiProtectorHooks(hooks),
+            ))),
+            #[cfg(not(unix))]
+            Some(_) => {
+                telio_log_warn!("Protector hooks are supported only on unix platforms");
+                None
+            }
+            None => protect.map(make_external_protector),
+        };
+
         catch_ffi_panic(|| {
             let features = features.clone();
             let event_dispatcher = event_dispatcher.clone();
-            let protect = protect.clone();
-            let device = Device::new(
-                features,
-                event_dispatcher,
-                protect.map(make_external_protector),
-            )?;
+            let device = Device::new(features, event_dispatcher, protector.clone())?;
             Ok(Self {
                 inner: Mutex::new(Some(device)),
                 id: rand::thread_rng().gen::<usize>(),
@@ -391,6 +483,7 @@ impl Telio {
                     name: None,
                     tun: None,
                     ext_if_filter: None,
+                    interface: None,
                 })
                 .log_result("Telio::start")
             })
@@ -418,6 +511,7 @@ impl Telio {
                     name: None,
                     tun: None,
                     ext_if_filter: None,
+                    interface: None,
                 })
                 .log_result("Telio::start")
             })
@@ -451,6 +545,7 @@ impl Telio {
                     name: Some(name.clone()),
                     tun: None,
                     ext_if_filter: None,
+                    interface: None,
                 })
                 .log_result("Telio::start_named")
             })
@@ -486,12 +581,125 @@ impl Telio {
                     name: Some(name.clone()),
                     tun: None,
                     ext_if_filter: Some(ext_if_filter.clone()),
+                    interface: None,
                 })
                 .log_result("Telio::start_named_ext_if_filter")
             })
         })
     }
 
+    /// Start telio with specified adapter and let it configure the interface.
+    ///
+    /// Adapter will attempt to open its own tunnel and assign the addresses,
+    /// MTU and metric to it, so that platform code doesn't have to.
+    ///
+    /// # Parameters
+    /// - `private_key`: base64 encoded private_key.
+    /// - `adapter`: Adapter type.
+    /// - `name`: Interface name, platform default is used if not set.
+    /// - `interface`: Interface parameters.
+    ///
+    pub fn start_with_interface_config(
+        &self,
+        private_key: SecretKey,
+        adapter: TelioAdapterType,
+        name: Option<String>,
+        interface: InterfaceConfig,
+    ) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::start entry with instance id: {}. Public key: {:?}. Adapter: {:?}. Name: {:?}. Interface: {:?}",
+            self.id,
+            private_key.public(),
+            &adapter,
+            &name,
+            &interface,
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.start(DeviceConfig {
+                    private_key: private_key.clone(),
+                    adapter: adapter
+                        .try_into()
+                        .map_err(|e| TelioError::UnknownError { inner: e })?,
+                    fwmark: None,
+                    name: name.clone(),
+                    tun: None,
+                    ext_if_filter: None,
+                    interface: Some(interface.clone()),
+                })
+                .log_result("Telio::start_with_interface_config")
+            })
+        })
+    }
+
+    /// Start telio with specified adapter, reconnecting with the session exported before a
+    /// restart.
+    ///
+    /// # Parameters
+    /// - `private_key`: WireGuard private key, the one the session was exported with.
+    /// - `adapter`: Adapter type.
+    /// - `session`: Session from `export_session`, ignored when stale or malformed.
+    ///
+    pub fn start_with_session(
+        &self,
+        private_key: SecretKey,
+        adapter: TelioAdapterType,
+        session: Vec<u8>,
+    ) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::start_with_session entry with instance id: {}. Public key: {:?}. Adapter: {:?}",
+            self.id,
+            private_key.public(),
+            &adapter,
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.start_with_session(
+                    DeviceConfig {
+                        private_key: private_key.clone(),
+                        adapter: adapter
+                            .try_into()
+                            .map_err(|e| TelioError::UnknownError { inner: e })?,
+                        fwmark: None,
+                        name: None,
+                        tun: None,
+                        ext_if_filter: None,
+                        interface: None,
+                    },
+                    &session,
+                )
+                .log_result("Telio::start_with_session")
+            })
+        })
+    }
+
+    /// Change the MTU of the running tunnel interface.
+    ///
+    /// # Parameters
+    /// - `mtu`: New MTU, at least 1280 when IPv6 addresses are assigned.
+    ///
+    pub fn set_mtu(&self, mtu: u32) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_mtu entry with instance id: {}. mtu: {}",
+            self.id,
+            mtu
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| dev.set_mtu(mtu).log_result("Telio::set_mtu"))
+        })
+    }
+
+    /// Classify the NAT the device is behind using the STUN servers of the relays.
+    ///
+    /// Blocks for a few seconds while the probes are sent.
+    ///
+    pub fn detect_nat(&self) -> FfiResult<NatReport> {
+        telio_log_info!("Telio::detect_nat entry with instance id: {}", self.id);
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| dev.detect_nat().log_result("Telio::detect_nat"))
+        })
+    }
+
     /// Set filtered interface list on adapter.
     ///
     /// # Parameters
@@ -554,6 +762,7 @@ impl Telio {
                     name: None,
                     tun,
                     ext_if_filter: None,
+                    interface: None,
                 })
                 .log_result("Telio::start_with_tun")
             })
@@ -571,6 +780,24 @@ impl Telio {
         })
     }
 
+    /// Stop telio device gracefully: tell the meshnet peers that this node is going away, refuse
+    /// new connections and wait up to `timeout_ms` for the established ones to finish.
+    pub fn shutdown_graceful(&self, timeout_ms: u64) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::shutdown_graceful entry with instance id: {}.",
+            self.id,
+        );
+        catch_ffi_panic(|| {
+            self.device_op(false, |dev| {
+                dev.shutdown_graceful(Duration::from_millis(timeout_ms))
+                    .map_err(|err| {
+                        telio_log_error!("Telio::shutdown_graceful: {:?}", err);
+                        err.into()
+                    })
+            })
+        })
+    }
+
     /// get device luid.
     pub fn get_adapter_luid(&self) -> u64 {
         self.device_op(true, |dev| Ok(dev.get_adapter_luid()))
@@ -600,6 +827,25 @@ impl Telio {
         })
     }
 
+    /// Confirms that the key announced by a `KeyRotation` event was registered with the backend.
+    ///
+    /// # Parameters
+    /// - `public_key`: Public key from the `Announced` key rotation event.
+    ///
+    pub fn confirm_key_rotation(&self, public_key: &PublicKey) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::confirm_key_rotation entry with instance id: {}. Public key: {:?}",
+            self.id,
+            public_key
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.confirm_key_rotation(public_key)
+                    .map_err(TelioError::from)
+            })
+        })
+    }
+
     /// Sets the tun interface file descriptor
     ///
     /// This function only does something on NepTUN adapter.
@@ -697,6 +943,18 @@ impl Telio {
         })
     }
 
+    /// Pause keepalives, relay and traversal, keeping the adapter and the peers in place.
+    pub fn pause(&self) -> FfiResult<()> {
+        telio_log_info!("Telio::pause entry with instance id: {}.", self.id);
+        catch_ffi_panic(|| self.device_op(true, |dev| dev.pause().log_result("Telio::pause")))
+    }
+
+    /// Resume after `Telio::pause`.
+    pub fn resume(&self) -> FfiResult<()> {
+        telio_log_info!("Telio::resume entry with instance id: {}.", self.id);
+        catch_ffi_panic(|| self.device_op(true, |dev| dev.resume().log_result("Telio::resume")))
+    }
+
     /// Wrapper for `Telio::connect_to_exit_node_with_id` that doesn't take an identifier
     pub fn connect_to_exit_node(
         &self,
@@ -794,6 +1052,92 @@ impl Telio {
         })
     }
 
+    /// Connects to the VPN exit node with obfuscated WireGuard handshakes
+    ///
+    /// Obfuscation parameters are taken from `wireguard.obfuscation` features and have to
+    /// match the ones of the server. Routing should be set by the user accordingly.
+    ///
+    /// # Parameters
+    /// - `identifier`: String that identifies the exit node, will be generated if null is passed.
+    /// - `public_key`: WireGuard public key for an exit node.
+    /// - `allowed_ips`: List of subnets which will be routed to the exit node.
+    ///   Can be None, same as "0.0.0.0/0".
+    /// - `endpoint`: An endpoint to an exit node. Must contain a port.
+    pub fn connect_to_exit_node_obfuscated(
+        &self,
+        identifier: Option<String>,
+        public_key: PublicKey,
+        allowed_ips: Option<Vec<IpNet>>,
+        endpoint: SocketAddr,
+    ) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::connect_to_exit_node_obfuscated entry with instance id :{}. Identifier: {:?}, Public Key: {:?}. Allowed IP: {:?}. Endpoint: {:?}",
+            self.id,
+            identifier,
+            public_key,
+            allowed_ips,
+            endpoint,
+        );
+        let identifier = identifier.unwrap_or_else(|| Uuid::new_v4().to_string());
+        let node = ExitNode {
+            identifier,
+            public_key,
+            allowed_ips,
+            endpoint: Some(endpoint),
+        };
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.connect_exit_node_obfuscated(&node)
+                    .log_result("Telio::connect_to_exit_node_obfuscated")
+            })
+        })
+    }
+
+    /// Connects through a chain of two VPN exit nodes (multihop)
+    ///
+    /// Traffic is encrypted for the exit node and then tunneled through the entry node.
+    /// Routing should be set by the user accordingly.
+    ///
+    /// # Parameters
+    /// - `entry_public_key`: WireGuard public key of the entry node.
+    /// - `entry_endpoint`: An endpoint of the entry node. Must contain a port.
+    /// - `exit_public_key`: WireGuard public key of the exit node.
+    /// - `exit_endpoint`: An endpoint of the exit node. Must contain a port.
+    pub fn set_exit_chain(
+        &self,
+        entry_public_key: PublicKey,
+        entry_endpoint: SocketAddr,
+        exit_public_key: PublicKey,
+        exit_endpoint: SocketAddr,
+    ) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_exit_chain entry with instance id :{}. Entry: {:?} {:?}. Exit: {:?} {:?}",
+            self.id,
+            entry_public_key,
+            entry_endpoint,
+            exit_public_key,
+            exit_endpoint,
+        );
+        let entry = ExitNode {
+            identifier: Uuid::new_v4().to_string(),
+            public_key: entry_public_key,
+            allowed_ips: None,
+            endpoint: Some(entry_endpoint),
+        };
+        let exit = ExitNode {
+            identifier: Uuid::new_v4().to_string(),
+            public_key: exit_public_key,
+            allowed_ips: None,
+            endpoint: Some(exit_endpoint),
+        };
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_exit_chain(&entry, &exit)
+                    .log_result("Telio::set_exit_chain")
+            })
+        })
+    }
+
     /// Enables magic DNS if it was not enabled yet,
     ///
     /// Routing should be set by the user accordingly.
@@ -882,6 +1226,11 @@ impl Telio {
         })
     }
 
+    /// Check a meshnet config without applying it
+    pub fn validate_meshnet(&self, cfg: Config) -> FfiResult<ValidationReport> {
+        catch_ffi_panic(|| self.device_op(true, |dev| Ok(dev.validate_config(&cfg))))
+    }
+
     /// Disables the meshnet functionality by closing all the connections.
     pub fn set_meshnet_off(&self) -> FfiResult<()> {
         telio_log_info!(
@@ -895,6 +1244,343 @@ impl Telio {
         })
     }
 
+    /// Get runtime statistics of every WireGuard peer
+    pub fn peer_stats(&self) -> FfiResult<Vec<PeerStats>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.peer_stats().map_err(|err| {
+                    telio_log_error!("Telio::peer_stats: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Get the current path to every meshnet peer, when it last changed and why the direct path
+    /// was lost, if it was
+    pub fn connectivity_matrix(&self) -> FfiResult<Vec<PeerConnectivity>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.connectivity_matrix().map_err(|err| {
+                    telio_log_error!("Telio::connectivity_matrix: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Get RTT, jitter and loss of every meshnet peer and the exit node, measured by QoS analytics
+    pub fn qos_report(&self) -> FfiResult<Vec<PeerQos>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.qos_report().map_err(|err| {
+                    telio_log_error!("Telio::qos_report: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Use own relay servers instead of the ones from the meshnet config
+    ///
+    /// # Parameters
+    /// - `servers`: Relay servers to use, `None` goes back to the ones from the meshnet config.
+    pub fn set_relay_servers(&self, servers: Option<Vec<Server>>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_relay_servers entry with instance id: {}. Servers: {:?}",
+            self.id,
+            servers
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_relay_servers(servers)
+                    .log_result("Telio::set_relay_servers")
+            })
+        })
+    }
+
+    /// Allow only some of the relay servers, e.g. for compliance
+    ///
+    /// # Parameters
+    /// - `pinning`: Allowed regions and servers, `None` allows any server.
+    pub fn set_relay_pinning(&self, pinning: Option<RelayPinning>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_relay_pinning entry with instance id: {}. Pinning: {:?}",
+            self.id,
+            pinning
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_relay_pinning(pinning)
+                    .log_result("Telio::set_relay_pinning")
+            })
+        })
+    }
+
+    /// Route the traffic classes through their own exits, the rest of the traffic keeps going
+    /// through the connected exit node
+    ///
+    /// # Parameters
+    /// - `classes`: Selectors of the traffic and their exits, empty routes everything the default
+    ///   way.
+    pub fn set_traffic_classes(&self, classes: Vec<TrafficClass>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_traffic_classes entry with instance id: {}. Classes: {:?}",
+            self.id,
+            classes
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_traffic_classes(classes)
+                    .log_result("Telio::set_traffic_classes")
+            })
+        })
+    }
+
+    /// Connect VPN servers next to the exit node, carrying only their own allowed IPs and the
+    /// traffic classes routed to them
+    ///
+    /// # Parameters
+    /// - `exits`: VPN servers to keep connected, empty disconnects all of them.
+    pub fn set_additional_exits(&self, exits: Vec<ExitNode>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_additional_exits entry with instance id: {}. Exits: {:?}",
+            self.id,
+            exits
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_additional_exits(exits)
+                    .log_result("Telio::set_additional_exits")
+            })
+        })
+    }
+
+    /// Keep some of the traffic off the tunnel
+    ///
+    /// # Parameters
+    /// - `split_tunnel`: Included and excluded routes and the excluded apps, `None` routes all
+    ///   of the traffic through the tunnel.
+    pub fn set_split_tunnel(&self, split_tunnel: Option<SplitTunnel>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_split_tunnel entry with instance id: {}. Split tunnel: {:?}",
+            self.id,
+            split_tunnel
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_split_tunnel(split_tunnel)
+                    .log_result("Telio::set_split_tunnel")
+            })
+        })
+    }
+
+    /// Fail over between exits, connecting the next one whenever the active one degrades
+    ///
+    /// # Parameters
+    /// - `failover`: Exits in the order of preference and their health criteria, `None` stops
+    ///   the failover and keeps the active exit connected.
+    pub fn set_exit_failover(&self, failover: Option<ExitFailover>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_exit_failover entry with instance id: {}. Failover: {:?}",
+            self.id,
+            failover
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_exit_failover(failover.clone())
+                    .log_result("Telio::set_exit_failover")
+            })
+        })
+    }
+
+    /// Replace the features of the instance.
+    ///
+    /// Keepalive periods, firewall rules and logging options are applied to the running device,
+    /// other changes take effect on the next start.
+    ///
+    /// # Parameters
+    /// - `features`: Complete set of the features, as passed to the constructor.
+    ///
+    /// # Returns
+    /// - Features which were applied and the ones which need the device to be started again.
+    pub fn update_features(&self, features: Features) -> FfiResult<FeaturesUpdate> {
+        telio_log_info!(
+            "Telio::update_features entry with instance id: {}. Features: {:?}",
+            self.id,
+            features
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.update_features(features.clone()).map_err(|err| {
+                    telio_log_error!("Telio::update_features: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Route the relay, API and other control connections through a proxy
+    ///
+    /// # Parameters
+    /// - `proxy`: SOCKS5 or HTTP CONNECT proxy, `None` connects directly.
+    pub fn set_proxy(&self, proxy: Option<Proxy>) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_proxy entry with instance id: {}. Proxy: {:?}",
+            self.id,
+            proxy
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_proxy(proxy).log_result("Telio::set_proxy")
+            })
+        })
+    }
+
+    /// Answer the inbound approval event of a peer
+    ///
+    /// # Parameters
+    /// - `public_key`: Public key of the peer.
+    /// - `decision`: Whether the inbound connections of the peer are accepted.
+    pub fn decide_inbound_connections(
+        &self,
+        public_key: PublicKey,
+        decision: InboundDecision,
+    ) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::decide_inbound_connections entry with instance id: {}. Peer: {:?}, decision: {:?}",
+            self.id,
+            public_key,
+            decision
+        );
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.decide_inbound_connections(public_key, decision)
+                    .log_result("Telio::decide_inbound_connections")
+            })
+        })
+    }
+
+    /// Evaluate a hypothetical packet against the current firewall policy
+    ///
+    /// The packet is run through the firewall chain as the first packet of a new connection,
+    /// without being processed.
+    ///
+    /// # Parameters
+    /// - `packet`: Packet to evaluate.
+    pub fn evaluate_firewall(&self, packet: PacketDescription) -> FfiResult<Verdict> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.evaluate_firewall(&packet).map_err(|err| {
+                    telio_log_error!("Telio::evaluate_firewall: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Register the provider of categories for the domains observed in DNS queries
+    ///
+    /// Answers of the provider are cached, so it is asked about each domain only once in a while.
+    ///
+    /// # Parameters
+    /// - `provider`: Source of domain categories, `None` removes the current one.
+    pub fn set_category_provider(
+        &self,
+        provider: Option<Box<dyn TelioCategoryProvider>>,
+    ) -> FfiResult<()> {
+        telio_log_info!(
+            "Telio::set_category_provider entry with instance id: {}. Provider set: {}",
+            self.id,
+            provider.is_some()
+        );
+        let provider = provider
+            .map(|provider| Arc::new(FfiCategoryProvider(provider)) as Arc<dyn CategoryProvider>);
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.set_category_provider(provider)
+                    .log_result("Telio::set_category_provider")
+            })
+        })
+    }
+
+    /// Get categories of the domains observed in DNS queries
+    pub fn domain_categories(&self) -> FfiResult<HashMap<String, Vec<String>>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.domain_categories().map_err(|err| {
+                    telio_log_error!("Telio::domain_categories: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Get CPU time, wakeups and traffic accumulated since libtelio was started
+    pub fn resource_usage(&self) -> FfiResult<ResourceUsage> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.resource_usage().map_err(|err| {
+                    telio_log_error!("Telio::resource_usage: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Get the traffic exchanged with each peer during the last minute, hour and day, empty
+    /// unless the `accounting` feature is enabled
+    pub fn traffic_accounting(&self) -> FfiResult<Vec<PeerTraffic>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.traffic_accounting().map_err(|err| {
+                    telio_log_error!("Telio::traffic_accounting: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Collect the diagnostics bundle for support, serialized to JSON
+    pub fn collect_diagnostics(&self) -> FfiResult<String> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                let bundle = dev.collect_diagnostics().map_err(|err| {
+                    telio_log_error!("Telio::collect_diagnostics: {:?}", err);
+                    TelioError::from(err)
+                })?;
+                bundle.to_json().map_err(|err| TelioError::UnknownError {
+                    inner: err.to_string(),
+                })
+            })
+        })
+    }
+
+    /// Export the session state encrypted to the device key, for `start_with_session` to
+    /// reconnect quickly after a restart
+    pub fn export_session(&self) -> FfiResult<Vec<u8>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.export_session().map_err(|err| {
+                    telio_log_error!("Telio::export_session: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
+    /// Get the health of the internal tasks as of the last supervision check
+    pub fn task_health(&self) -> FfiResult<Vec<TaskStatus>> {
+        catch_ffi_panic(|| {
+            self.device_op(true, |dev| {
+                dev.task_health().map_err(|err| {
+                    telio_log_error!("Telio::task_health: {:?}", err);
+                    err.into()
+                })
+            })
+        })
+    }
+
     pub fn get_status_map(&self) -> Vec<Node> {
         trace!("acquiring dev lock");
         match self.device_op(true, |dev| dev.external_nodes().map_err(|e| e.into())) {
@@ -975,6 +1661,37 @@ impl Telio {
     }
 }
 
+/// Category provider of the app, as seen by the firewall
+#[derive(Debug)]
+struct FfiCategoryProvider(Box<dyn TelioCategoryProvider>);
+
+impl CategoryProvider for FfiCategoryProvider {
+    fn categorize(&self, domain: &str) -> Vec<String> {
+        self.0.categorize(domain.to_owned()).unwrap_or_else(|err| {
+            telio_log_warn!("Category provider failed for {domain}: {err:?}");
+            Vec::new()
+        })
+    }
+}
+
+#[cfg(unix)]
+struct FfiProtectorHooks(Box<dyn TelioProtectorHooks>);
+
+#[cfg(unix)]
+impl telio_sockets::ProtectorHooks for FfiProtectorHooks {
+    fn protect(&self, socket: i32) -> std::io::Result<()> {
+        self.0
+            .protect(socket)
+            .map_err(|err| std::io::Error::other(err.to_string()))
+    }
+
+    fn bind_to_interface(&self, socket: i32, interface: Option<u64>) -> std::io::Result<()> {
+        self.0
+            .bind_to_interface(socket, interface)
+            .map_err(|err| std::io::Error::other(err.to_string()))
+    }
+}
+
 /// cbindgen:ignore
 static PANIC_HOOK: Once = Once::new();
 
@@ -1,7 +1,12 @@
 use std::sync::Arc;
 
 use parking_lot::Mutex;
-use telio_model::features::{FeatureDerp, FeatureLana, FeatureValidateKeys, Features};
+use telio_model::{
+    features::{FeatureDerp, FeatureLana, FeatureValidateKeys, Features},
+    validation::validate_features,
+};
+
+use super::types::{FfiResult, TelioError};
 
 pub struct FeaturesDefaultsBuilder {
     config: Mutex<Features>,
@@ -28,11 +33,20 @@ impl FeaturesDefaultsBuilder {
             derp: None,
             link_detection: None,
             flush_events_on_stop_timeout_seconds: None,
+            startup_timeout_seconds: None,
             multicast: false,
+            starcast: None,
             ipv6: false,
             nicknames: false,
             batching: None,
             error_notification_service: None,
+            key_rotation: None,
+            otlp: None,
+            fast_reconnect: None,
+            supervision: None,
+            accounting: None,
+            health_endpoint: None,
+            max_meshnet_peers: None,
         };
 
         Self {
@@ -45,6 +59,23 @@ impl FeaturesDefaultsBuilder {
         self.config.lock().clone()
     }
 
+    /// Build final config, failing when the settings are out of their ranges or conflict with
+    /// each other
+    pub fn try_build(self: Arc<Self>) -> FfiResult<Features> {
+        let features = self.config.lock().clone();
+        let issues = validate_features(&features);
+        if issues.is_empty() {
+            return Ok(features);
+        }
+        Err(TelioError::UnknownError {
+            inner: issues
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; "),
+        })
+    }
+
     /// Enable lana, this requires input from apps
     pub fn enable_lana(self: Arc<Self>, event_path: String, prod: bool) -> Arc<Self> {
         self.config.lock().lana = Some(FeatureLana { event_path, prod });
@@ -159,6 +190,11 @@ impl FeaturesDefaultsBuilder {
         self
     }
 
+    pub fn set_worker_threads(self: Arc<Self>, worker_threads: u32) -> Arc<Self> {
+        self.config.lock().wireguard.worker_threads = Some(worker_threads);
+        self
+    }
+
     pub fn enable_error_notification_service(self: Arc<Self>) -> Arc<Self> {
         self.config.lock().error_notification_service = Some(Default::default());
         self
@@ -1,5 +1,8 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
     io::{self, ErrorKind},
+    path::Path,
     str::from_utf8,
     sync::{
         atomic::{AtomicBool, AtomicUsize},
@@ -14,9 +17,11 @@ use once_cell::sync::Lazy;
 use telio_utils::{log_censor::LogCensor, Instant};
 use tracing::{level_filters::LevelFilter, Subscriber};
 use tracing_subscriber::{
+    filter::{filter_fn, Targets},
     fmt::{self, FormatEvent, FormatFields, MakeWriter},
-    layer::SubscriberExt,
+    layer::{Layer, SubscriberExt},
     registry::LookupSpan,
+    reload, Registry,
 };
 
 use crate::{TelioLogLevel, TelioLoggerCb};
@@ -30,6 +35,8 @@ static DROPPED_LOGS_LAST_CHECKED_VALUE: AtomicUsize = AtomicUsize::new(0);
 pub static LOGGER_STOPPER: LoggerStopper = LoggerStopper::new();
 pub static TIMESTAMPS_IN_LOGS: AtomicBool = AtomicBool::new(false);
 pub static HIDE_THREAD_ID_IN_LOGS: AtomicBool = AtomicBool::new(true);
+pub static LOG_RING: LogRing = LogRing::new();
+pub static LOG_LEVELS: LogLevels = LogLevels::new();
 
 pub struct LoggerStopper {
     sender: parking_lot::Mutex<Option<SyncSender<LogMessage>>>,
@@ -93,13 +100,226 @@ pub fn build_subscriber(
     let log_sender = start_async_logger(logger, LOG_BUFFER);
     LOGGER_STOPPER.set_sender(log_sender.clone());
     tracing_subscriber::registry()
-        .with(LevelFilter::from_level(log_level.into()))
+        .with(LOG_LEVELS.filter(log_level))
         .with(
             fmt::layer()
                 .event_format(TelioEventFmt)
                 .with_ansi(false)
                 .with_writer(FfiCallback::new(log_sender)),
         )
+        .with(
+            fmt::layer()
+                .event_format(TelioEventFmt)
+                .with_ansi(false)
+                .with_writer(RingSink(&LOG_RING))
+                .with_filter(filter_fn(|_| LOG_RING.is_enabled())),
+        )
+}
+
+/// Log levels of the modules, adjustable while the global logger is set
+pub struct LogLevels {
+    state: parking_lot::Mutex<LogLevelsState>,
+}
+
+struct LogLevelsState {
+    default: LevelFilter,
+    modules: BTreeMap<String, LevelFilter>,
+    handle: Option<reload::Handle<Targets, Registry>>,
+}
+
+impl LogLevelsState {
+    fn targets(&self) -> Targets {
+        Targets::new()
+            .with_default(self.default)
+            .with_targets(self.modules.clone())
+    }
+}
+
+impl LogLevels {
+    const fn new() -> Self {
+        Self {
+            state: parking_lot::Mutex::new(LogLevelsState {
+                default: LevelFilter::TRACE,
+                modules: BTreeMap::new(),
+                handle: None,
+            }),
+        }
+    }
+
+    /// Filter of the global logger logging up to `log_level`, besides the modules with a level
+    /// of their own
+    fn filter(&self, log_level: TelioLogLevel) -> reload::Layer<Targets, Registry> {
+        let mut state = self.state.lock();
+        state.default = LevelFilter::from_level(log_level.into());
+        let (filter, handle) = reload::Layer::new(state.targets());
+        state.handle = Some(handle);
+        filter
+    }
+
+    /// Log the module, e.g. `telio_wg` or `telio::device`, and its submodules up to `level`,
+    /// `None` brings back the level of the global logger
+    pub fn set(&self, module: String, level: Option<TelioLogLevel>) {
+        let mut state = self.state.lock();
+        match level {
+            Some(level) => state
+                .modules
+                .insert(module, LevelFilter::from_level(level.into())),
+            None => state.modules.remove(&module),
+        };
+        let targets = state.targets();
+        if let Some(handle) = &state.handle {
+            if let Err(e) = handle.reload(targets) {
+                eprintln!("Failed to change log levels: {e}");
+            }
+        }
+    }
+}
+
+/// Latest logs of every component kept in memory, to be retrieved when a problem is reported
+pub struct LogRing {
+    state: parking_lot::Mutex<LogRingState>,
+}
+
+struct LogRingState {
+    /// Size of the logs kept per component, in bytes, zero when disabled
+    capacity: usize,
+    components: BTreeMap<String, ComponentLogs>,
+}
+
+#[derive(Default)]
+struct ComponentLogs {
+    entries: VecDeque<LogEntry>,
+    size: usize,
+}
+
+impl ComponentLogs {
+    fn evict(&mut self, capacity: usize) {
+        while self.size > capacity {
+            match self.entries.pop_front() {
+                Some(entry) => self.size -= entry.message.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+struct LogEntry {
+    timestamp: SystemTime,
+    level: TelioLogLevel,
+    message: String,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            state: parking_lot::Mutex::new(LogRingState {
+                capacity: 0,
+                components: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.state.lock().capacity > 0
+    }
+
+    /// Keep up to `capacity` bytes of the latest logs of every component, zero disables the
+    /// buffer and drops the logs kept
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut state = self.state.lock();
+        state.capacity = capacity;
+        if capacity == 0 {
+            state.components.clear();
+        }
+        for logs in state.components.values_mut() {
+            logs.evict(capacity);
+        }
+    }
+
+    fn record(&self, component: &str, level: TelioLogLevel, message: String) {
+        let mut state = self.state.lock();
+        let capacity = state.capacity;
+        if capacity == 0 {
+            return;
+        }
+        let logs = state.components.entry(component.to_owned()).or_default();
+        logs.size += message.len();
+        logs.entries.push_back(LogEntry {
+            timestamp: SystemTime::now(),
+            level,
+            message,
+        });
+        logs.evict(capacity);
+    }
+
+    /// Logs kept, of all the components, one per line in the order they were logged
+    pub fn dump(&self) -> String {
+        let state = self.state.lock();
+        let mut entries: Vec<_> = state
+            .components
+            .values()
+            .flat_map(|logs| logs.entries.iter())
+            .collect();
+        entries.sort_by_key(|entry| entry.timestamp);
+
+        let mut dump = String::new();
+        for entry in entries {
+            let timestamp: time::OffsetDateTime = entry.timestamp.into();
+            let timestamp = timestamp
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_else(|_| timestamp.to_string());
+            dump.push_str(&format!(
+                "{timestamp} {:?} {}\n",
+                entry.level, entry.message
+            ));
+        }
+        dump
+    }
+
+    /// Write the logs kept to the file at `path`, the logs stay in the buffer
+    pub fn flush_to(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.dump())
+    }
+}
+
+struct RingSink(&'static LogRing);
+
+impl MakeWriter<'_> for RingSink {
+    type Writer = RingWriter;
+
+    fn make_writer(&self) -> Self::Writer {
+        unreachable!("`make_writer` should not be called, then `make_writer_for` is implemented")
+    }
+
+    fn make_writer_for(&self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        let target = meta.target();
+        RingWriter {
+            ring: self.0,
+            component: target.split("::").next().unwrap_or(target).to_owned(),
+            level: (*meta.level()).into(),
+        }
+    }
+}
+
+struct RingWriter {
+    ring: &'static LogRing,
+    component: String,
+    level: TelioLogLevel,
+}
+
+impl io::Write for RingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let msg = from_utf8(buf)
+            .map(|msg| msg.trim().to_string())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        self.ring
+            .record(&self.component, self.level, LOG_CENSOR.censor_logs(msg));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 struct TelioEventFmt;
@@ -439,6 +659,29 @@ mod test {
         assert_eq!(LOGS_TO_DROP + 1, logs_dropped_until_now());
     }
 
+    #[test]
+    fn log_ring_keeps_latest_logs_per_component() {
+        let ring = LogRing::new();
+        ring.record("telio_wg", TelioLogLevel::Info, "dropped".to_owned());
+        assert!(ring.dump().is_empty());
+
+        ring.set_capacity(11);
+        ring.record("telio_wg", TelioLogLevel::Info, "first".to_owned());
+        ring.record("telio_relay", TelioLogLevel::Debug, "relay".to_owned());
+        ring.record("telio_wg", TelioLogLevel::Info, "second".to_owned());
+        ring.record("telio_wg", TelioLogLevel::Warning, "third".to_owned());
+
+        let dump = ring.dump();
+        let lines: Vec<_> = dump
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, ["Debug relay", "Info second", "Warning third"]);
+
+        ring.set_capacity(0);
+        assert!(ring.dump().is_empty());
+    }
+
     #[derive(Default, Clone, Debug)]
     struct Log(Arc<Mutex<Vec<(TelioLogLevel, String)>>>);
     impl TelioLoggerCb for Log {
@@ -19,6 +19,15 @@ pub trait TelioProtectCb: Send + Sync + RefUnwindSafe + std::fmt::Debug {
     fn protect(&self, socket_id: i32) -> FfiResult<()>;
 }
 
+pub trait TelioProtectorHooks: Send + Sync + std::fmt::Debug {
+    fn protect(&self, socket_id: i32) -> FfiResult<()>;
+    fn bind_to_interface(&self, socket_id: i32, interface: Option<u64>) -> FfiResult<()>;
+}
+
+pub trait TelioCategoryProvider: Send + Sync + std::fmt::Debug {
+    fn categorize(&self, domain: String) -> FfiResult<Vec<String>>;
+}
+
 pub type FfiResult<T> = Result<T, TelioError>;
 
 #[derive(Debug, thiserror::Error)]
@@ -0,0 +1,230 @@
+//! Handover of a running device to another process.
+//!
+//! Restarting the daemon for an upgrade would normally tear down the adapter and drop every
+//! connection passing through it. Instead, the new process can take over from the old one: the old
+//! process exports its [HandoverState] with [Device::export_handover_state] and sends it together
+//! with the tun file descriptor over a Unix socket with [send]. The new process gets both with
+//! [receive] and starts with [Device::start_from_handover], while the old process stops.
+//!
+//! The state carries the private key, so it is sent only to a process running as the same user,
+//! or as root, which is checked with the credentials of the socket peer.
+//!
+//! The tun interface stays up all the time and the firewall keeps tracking the established
+//! connections. WireGuard sessions cannot be moved, so peers do a single handshake with the new
+//! process, using the endpoints they already know.
+//!
+//! Only the [HandoverState] the supervision restarts the device with is kept in the strict privacy
+//! builds, the state never leaves the process.
+//!
+//! [Device::export_handover_state]: crate::device::Device::export_handover_state
+//! [Device::start_from_handover]: crate::device::Device::start_from_handover
+
+use serde::{Deserialize, Serialize};
+use telio_crypto::SecretKey;
+use telio_firewall::firewall::ConntrackSnapshot;
+use telio_model::{config::Config, mesh::ExitNode};
+
+#[cfg(all(unix, not(feature = "strict_privacy")))]
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+};
+
+/// Everything the successor needs to continue where this instance stops
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HandoverState {
+    /// Private key of the device
+    pub private_key: SecretKey,
+    /// Fwmark used for encapsulated packets
+    pub fwmark: Option<u32>,
+    /// Meshnet configuration as last set
+    pub meshnet: Option<Config>,
+    /// Exit node the device is connected to
+    pub exit_node: Option<ExitNode>,
+    /// Connections tracked by the firewall
+    pub connections: ConntrackSnapshot,
+}
+
+/// Upper bound of the size of the state, the predecessor sending anything larger is not trusted
+#[cfg(not(feature = "strict_privacy"))]
+const MAX_STATE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Handover errors
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Socket failure
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Malformed state
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    /// The state came without a tun file descriptor
+    #[error("Tun file descriptor missing in handover")]
+    MissingTun,
+    /// The announced state is larger than [MAX_STATE_SIZE]
+    #[error("Handover state of {0} bytes is too large")]
+    TooLarge(u64),
+    /// The process on the other end runs as another user
+    #[error("Handover peer runs as uid {0}")]
+    UntrustedPeer(u32),
+}
+
+/// Send the `state` and the `tun` file descriptor to the successor
+#[cfg(all(unix, not(feature = "strict_privacy")))]
+pub fn send(
+    stream: &mut UnixStream,
+    state: &HandoverState,
+    tun: BorrowedFd<'_>,
+) -> Result<(), Error> {
+    use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+
+    check_peer(stream)?;
+    let payload = serde_json::to_vec(state)?;
+    let header = (payload.len() as u64).to_be_bytes();
+
+    // The file descriptor travels with the header, the rest is a plain stream
+    let fds = [tun.as_raw_fd()];
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&header)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .map_err(io::Error::from)?;
+    stream.write_all(header.get(sent..).unwrap_or_default())?;
+    stream.write_all(&payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Receive the state and the tun file descriptor from the predecessor
+#[cfg(all(unix, not(feature = "strict_privacy")))]
+pub fn receive(stream: &mut UnixStream) -> Result<(HandoverState, OwnedFd), Error> {
+    use nix::{
+        cmsg_space,
+        sys::socket::{recvmsg, ControlMessageOwned, MsgFlags},
+    };
+
+    let mut header = [0u8; 8];
+    let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+    let (received, fds) = {
+        let mut iov = [IoSliceMut::new(&mut header)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(io::Error::from)?;
+        let fds: Vec<RawFd> = msg
+            .cmsgs()
+            .map_err(io::Error::from)?
+            .filter_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => Some(fds),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        (msg.bytes, fds)
+    };
+    // Taking the ownership right away, so that nothing leaks on the error paths
+    let mut fds = fds
+        .into_iter()
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+    let tun = fds.next().ok_or(Error::MissingTun)?;
+
+    if received == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    stream.read_exact(header.get_mut(received..).unwrap_or_default())?;
+
+    let len = u64::from_be_bytes(header);
+    if len > MAX_STATE_SIZE {
+        return Err(Error::TooLarge(len));
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((serde_json::from_slice(&payload)?, tun))
+}
+
+/// Fail unless the process on the other end of the `stream` runs as the user of this one, or
+/// as root
+#[cfg(all(unix, not(feature = "strict_privacy")))]
+fn check_peer(stream: &UnixStream) -> Result<(), Error> {
+    use nix::unistd::{geteuid, Uid};
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let uid = {
+        use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+        let credentials = getsockopt(stream, PeerCredentials).map_err(io::Error::from)?;
+        telio_utils::telio_log_debug!("Handing over to pid {}", credentials.pid());
+        Uid::from_raw(credentials.uid())
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let uid = nix::unistd::getpeereid(stream).map_err(io::Error::from)?.0;
+
+    if uid != geteuid() && !uid.is_root() {
+        return Err(Error::UntrustedPeer(uid.as_raw()));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix, not(feature = "strict_privacy")))]
+mod tests {
+    use std::{fs::File, os::fd::AsFd};
+
+    use super::*;
+
+    #[test]
+    fn state_and_tun_are_handed_over() {
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        let tun = File::open("/dev/null").unwrap();
+        let state = HandoverState {
+            private_key: SecretKey::gen(),
+            fwmark: Some(11673110),
+            ..Default::default()
+        };
+
+        send(&mut old, &state, tun.as_fd()).unwrap();
+        let (received, received_tun) = receive(&mut new).unwrap();
+
+        assert_eq!(received.private_key, state.private_key);
+        assert_eq!(received.fwmark, state.fwmark);
+        assert!(received.connections.is_empty());
+        assert_ne!(received_tun.as_raw_fd(), tun.as_raw_fd());
+    }
+
+    #[test]
+    fn oversized_state_is_refused() {
+        use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+
+        let (old, mut new) = UnixStream::pair().unwrap();
+        let tun = File::open("/dev/null").unwrap();
+        let header = (MAX_STATE_SIZE + 1).to_be_bytes();
+        sendmsg::<()>(
+            old.as_raw_fd(),
+            &[IoSlice::new(&header)],
+            &[ControlMessage::ScmRights(&[tun.as_raw_fd()])],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            receive(&mut new),
+            Err(Error::TooLarge(len)) if len == MAX_STATE_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn missing_tun_is_an_error() {
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        old.write_all(&0u64.to_be_bytes()).unwrap();
+        assert!(matches!(receive(&mut new), Err(Error::MissingTun)));
+    }
+}
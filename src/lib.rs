@@ -14,6 +14,9 @@ pub use ffi::types as ffi_types;
 /// cbindgen:ignore
 pub mod device;
 
+/// cbindgen:ignore
+pub mod handover;
+
 /// cbindgen:ignore
 pub use telio_crypto as crypto;
 
@@ -67,18 +70,39 @@ mod uniffi_libtelio {
     use std::net::{IpAddr, SocketAddr};
 
     use super::crypto::{PublicKey, SecretKey};
+    use super::device::FeaturesUpdate;
     use super::*;
 
     use base64::prelude::*;
     use nat_detect::NatType;
+    use telio_firewall::{
+        approval::InboundDecision,
+        firewall::{PacketDescription, PacketDirection, PacketProtocol, Verdict, VerdictAction},
+    };
     use telio_model::config::*;
-    use telio_model::event::{ErrorCode, ErrorLevel, Event};
+    use telio_model::event::{
+        DowngradeReason, ErrorCode, ErrorLevel, Event, ExitDegradation, KeyRotationState,
+        PqRekeyState, ReadinessComponent,
+    };
     use telio_model::features::*;
     use telio_model::mesh::*;
+    use telio_model::validation::{ConfigIssue, ValidationReport};
+    use telio_network_monitors::path::NetworkPath;
+    use telio_sockets::{Proxy, ProxyKind};
+    use telio_task::supervisor::{TaskHealth, TaskStatus};
     use telio_utils::{Hidden, HiddenString};
 
+    type ConnectivityEvent = telio_model::event::Connectivity;
     type ErrorEvent = telio_model::event::Error;
+    type KeyRotationEvent = telio_model::event::KeyRotation;
+    type PqRekeyEvent = telio_model::event::PqRekey;
+    type InboundApprovalEvent = telio_model::event::InboundApproval;
+    type ReadinessEvent = telio_model::event::Readiness;
+    type RelayQualityEvent = telio_model::event::RelayQuality;
+    type RelayRehomingEvent = telio_model::event::RelayRehoming;
     type TelioNode = telio_model::mesh::Node;
+    type TelioInterfaceConfig = telio_wg::InterfaceConfig;
+    type NatReport = telio_traversal::nat_detection::NatReport;
 
     impl From<uniffi::UnexpectedUniFFICallbackError> for TelioError {
         fn from(err: uniffi::UnexpectedUniFFICallbackError) -> Self {